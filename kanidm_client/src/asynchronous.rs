@@ -0,0 +1,472 @@
+// Generated by kanidm_proto::openapi::rust_client, do not edit by hand.
+// See kanidm_proto/src/openapi.rs for how to regenerate this.
+
+use crate::ClientError;
+use futures::{Future, Stream};
+use kanidm_proto::v1::*;
+use reqwest::r#async::{Client, RequestBuilder};
+use serde::de::DeserializeOwned;
+
+pub type ClientFuture<T> = Box<dyn Future<Item = T, Error = ClientError> + Send>;
+
+fn perform<T: DeserializeOwned + Send + 'static>(req: RequestBuilder) -> ClientFuture<T> {
+    Box::new(
+        req.send()
+            .and_then(|response| {
+                let status = response.status();
+                response
+                    .into_body()
+                    .concat2()
+                    .map(move |body| (status, body))
+            })
+            .map_err(ClientError::Transport)
+            .and_then(|(status, body)| match status {
+                reqwest::StatusCode::OK => {
+                    serde_json::from_slice(&body).map_err(|_| ClientError::JsonParse)
+                }
+                unexpect => Err(ClientError::Http(
+                    unexpect,
+                    serde_json::from_slice(&body).ok(),
+                )),
+            }),
+    )
+}
+
+#[derive(Debug, Clone)]
+pub struct AsyncKanidmClient {
+    client: Client,
+    addr: String,
+}
+
+impl AsyncKanidmClient {
+    // The client should have a cookie store, as auth is tracked by the session cookie.
+    pub fn new(client: Client, addr: &str) -> Self {
+        AsyncKanidmClient {
+            client: client,
+            addr: addr.to_string(),
+        }
+    }
+
+    pub fn status(&self) -> ClientFuture<StatusResponse> {
+        let dest = format!("{}/status", self.addr);
+        perform(self.client.get(dest.as_str()))
+    }
+
+    pub fn create(&self, request: &CreateRequest) -> ClientFuture<OperationResponse> {
+        let dest = format!("{}/v1/raw/create", self.addr);
+        perform(self.client.post(dest.as_str()).json(request))
+    }
+
+    pub fn modify(&self, request: &ModifyRequest) -> ClientFuture<OperationResponse> {
+        let dest = format!("{}/v1/raw/modify", self.addr);
+        perform(self.client.post(dest.as_str()).json(request))
+    }
+
+    pub fn delete(&self, request: &DeleteRequest) -> ClientFuture<OperationResponse> {
+        let dest = format!("{}/v1/raw/delete", self.addr);
+        perform(self.client.post(dest.as_str()).json(request))
+    }
+
+    pub fn delete_preview(&self, request: &DeleteRequest) -> ClientFuture<DeletePreviewResponse> {
+        let dest = format!("{}/v1/raw/delete/preview", self.addr);
+        perform(self.client.post(dest.as_str()).json(request))
+    }
+
+    pub fn search(&self, request: &SearchRequest) -> ClientFuture<SearchResponse> {
+        let dest = format!("{}/v1/raw/search", self.addr);
+        perform(self.client.post(dest.as_str()).json(request))
+    }
+
+    pub fn search_query(&self, request: &SearchQueryRequest) -> ClientFuture<SearchResponse> {
+        let dest = format!("{}/v1/raw/search/query", self.addr);
+        perform(self.client.post(dest.as_str()).json(request))
+    }
+
+    pub fn compare(&self, request: &CompareRequest) -> ClientFuture<CompareResponse> {
+        let dest = format!("{}/v1/raw/compare", self.addr);
+        perform(self.client.post(dest.as_str()).json(request))
+    }
+
+    pub fn changes(&self, request: &ChangesRequest) -> ClientFuture<ChangesResponse> {
+        let dest = format!("{}/v1/raw/changes", self.addr);
+        perform(self.client.post(dest.as_str()).json(request))
+    }
+
+    pub fn repl_supply(&self, request: &ReplSupplyRequest) -> ClientFuture<ReplSupplyResponse> {
+        let dest = format!("{}/v1/repl/supply", self.addr);
+        perform(self.client.post(dest.as_str()).json(request))
+    }
+
+    pub fn explain(&self, request: &SearchRequest) -> ClientFuture<SearchExplain> {
+        let dest = format!("{}/v1/raw/explain", self.addr);
+        perform(self.client.post(dest.as_str()).json(request))
+    }
+
+    pub fn auth(&self, request: &AuthRequest) -> ClientFuture<AuthResponse> {
+        let dest = format!("{}/v1/auth", self.addr);
+        perform(self.client.post(dest.as_str()).json(request))
+    }
+
+    pub fn domain_info(&self) -> ClientFuture<DomainInfo> {
+        let dest = format!("{}/v1/domain", self.addr);
+        perform(self.client.get(dest.as_str()))
+    }
+
+    pub fn branding(&self) -> ClientFuture<Branding> {
+        let dest = format!("{}/v1/branding", self.addr);
+        perform(self.client.get(dest.as_str()))
+    }
+
+    pub fn operations_get(&self) -> ClientFuture<OperationsResponse> {
+        let dest = format!("{}/v1/admin/operations", self.addr);
+        perform(self.client.get(dest.as_str()))
+    }
+
+    pub fn operations_cancel(&self, id: &str, request: &()) -> ClientFuture<bool> {
+        let dest = format!("{}/v1/admin/operations/{}/cancel", self.addr, id);
+        perform(self.client.post(dest.as_str()).json(request))
+    }
+
+    pub fn index_stats(&self) -> ClientFuture<Vec<IndexStat>> {
+        let dest = format!("{}/v1/admin/index_stats", self.addr);
+        perform(self.client.get(dest.as_str()))
+    }
+
+    pub fn attr_usage(&self) -> ClientFuture<Vec<AttrUsage>> {
+        let dest = format!("{}/v1/admin/attr_usage", self.addr);
+        perform(self.client.get(dest.as_str()))
+    }
+
+    pub fn attr_usage_id(&self, attr: &str) -> ClientFuture<Vec<AttrUsage>> {
+        let dest = format!("{}/v1/admin/attr_usage/{}", self.addr, attr);
+        perform(self.client.get(dest.as_str()))
+    }
+
+    pub fn slow_queries(&self) -> ClientFuture<Vec<SlowQueryRecord>> {
+        let dest = format!("{}/v1/admin/slow_queries", self.addr);
+        perform(self.client.get(dest.as_str()))
+    }
+
+    pub fn read_only_put(&self, request: &bool) -> ClientFuture<bool> {
+        let dest = format!("{}/v1/admin/read_only", self.addr);
+        perform(self.client.put(dest.as_str()).json(request))
+    }
+
+    pub fn db_pool_stats(&self) -> ClientFuture<DbPoolStats> {
+        let dest = format!("{}/v1/admin/db_pool_stats", self.addr);
+        perform(self.client.get(dest.as_str()))
+    }
+
+    pub fn purge_stats(&self) -> ClientFuture<PurgeStats> {
+        let dest = format!("{}/v1/admin/purge_stats", self.addr);
+        perform(self.client.get(dest.as_str()))
+    }
+
+    pub fn write_stats(&self) -> ClientFuture<Vec<WriteStatsRecord>> {
+        let dest = format!("{}/v1/admin/write_stats", self.addr);
+        perform(self.client.get(dest.as_str()))
+    }
+
+    pub fn access_journal(&self) -> ClientFuture<AccessJournalResponse> {
+        let dest = format!("{}/v1/admin/access_journal", self.addr);
+        perform(self.client.get(dest.as_str()))
+    }
+
+    pub fn verify(&self, request: &()) -> ClientFuture<Vec<ConsistencyError>> {
+        let dest = format!("{}/v1/admin/verify", self.addr);
+        perform(self.client.post(dest.as_str()).json(request))
+    }
+
+    pub fn reindex(&self, request: &()) -> ClientFuture<()> {
+        let dest = format!("{}/v1/admin/reindex", self.addr);
+        perform(self.client.post(dest.as_str()).json(request))
+    }
+
+    pub fn vacuum(&self, request: &()) -> ClientFuture<VacuumResponse> {
+        let dest = format!("{}/v1/admin/vacuum", self.addr);
+        perform(self.client.post(dest.as_str()).json(request))
+    }
+
+    pub fn schema_get(&self) -> ClientFuture<Vec<Entry>> {
+        let dest = format!("{}/v1/schema", self.addr);
+        perform(self.client.get(dest.as_str()))
+    }
+
+    pub fn schema_attributetype_get(&self) -> ClientFuture<Vec<Entry>> {
+        let dest = format!("{}/v1/schema/attributetype", self.addr);
+        perform(self.client.get(dest.as_str()))
+    }
+
+    pub fn schema_attributetype_get_id(&self, id: &str) -> ClientFuture<Option<Entry>> {
+        let dest = format!("{}/v1/schema/attributetype/{}", self.addr, id);
+        perform(self.client.get(dest.as_str()))
+    }
+
+    pub fn schema_classtype_get(&self) -> ClientFuture<Vec<Entry>> {
+        let dest = format!("{}/v1/schema/classtype", self.addr);
+        perform(self.client.get(dest.as_str()))
+    }
+
+    pub fn schema_classtype_get_id(&self, id: &str) -> ClientFuture<Option<Entry>> {
+        let dest = format!("{}/v1/schema/classtype/{}", self.addr, id);
+        perform(self.client.get(dest.as_str()))
+    }
+
+    pub fn whoami(&self) -> ClientFuture<WhoamiResponse> {
+        let dest = format!("{}/v1/self", self.addr);
+        perform(self.client.get(dest.as_str()))
+    }
+
+    pub fn idm_account_set_password(
+        &self,
+        request: &SingleStringRequest,
+    ) -> ClientFuture<OperationResponse> {
+        let dest = format!("{}/v1/self/_credential/primary/set_password", self.addr);
+        perform(self.client.post(dest.as_str()).json(request))
+    }
+
+    pub fn account_get(&self) -> ClientFuture<Vec<Entry>> {
+        let dest = format!("{}/v1/account", self.addr);
+        perform(self.client.get(dest.as_str()))
+    }
+
+    pub fn account_post(&self, request: &Entry) -> ClientFuture<()> {
+        let dest = format!("{}/v1/account", self.addr);
+        perform(self.client.post(dest.as_str()).json(request))
+    }
+
+    pub fn account_id_get(&self, id: &str) -> ClientFuture<Option<Entry>> {
+        let dest = format!("{}/v1/account/{}", self.addr, id);
+        perform(self.client.get(dest.as_str()))
+    }
+
+    pub fn account_id_delete(&self, id: &str) -> ClientFuture<()> {
+        let dest = format!("{}/v1/account/{}", self.addr, id);
+        perform(self.client.delete(dest.as_str()))
+    }
+
+    pub fn account_id_get_attr(&self, id: &str, attr: &str) -> ClientFuture<Option<Vec<String>>> {
+        let dest = format!("{}/v1/account/{}/_attr/{}", self.addr, id, attr);
+        perform(self.client.get(dest.as_str()))
+    }
+
+    pub fn account_id_post_attr(
+        &self,
+        id: &str,
+        attr: &str,
+        request: &Vec<String>,
+    ) -> ClientFuture<()> {
+        let dest = format!("{}/v1/account/{}/_attr/{}", self.addr, id, attr);
+        perform(self.client.post(dest.as_str()).json(request))
+    }
+
+    pub fn account_id_put_attr(
+        &self,
+        id: &str,
+        attr: &str,
+        request: &Vec<String>,
+    ) -> ClientFuture<()> {
+        let dest = format!("{}/v1/account/{}/_attr/{}", self.addr, id, attr);
+        perform(self.client.put(dest.as_str()).json(request))
+    }
+
+    pub fn account_id_delete_attr(&self, id: &str, attr: &str) -> ClientFuture<()> {
+        let dest = format!("{}/v1/account/{}/_attr/{}", self.addr, id, attr);
+        perform(self.client.delete(dest.as_str()))
+    }
+
+    pub fn account_put_id_credential_primary(
+        &self,
+        id: &str,
+        request: &SetAuthCredential,
+    ) -> ClientFuture<Option<String>> {
+        let dest = format!("{}/v1/account/{}/_credential/primary", self.addr, id);
+        perform(self.client.put(dest.as_str()).json(request))
+    }
+
+    pub fn account_get_id_radius(&self, id: &str) -> ClientFuture<Option<String>> {
+        let dest = format!("{}/v1/account/{}/_radius", self.addr, id);
+        perform(self.client.get(dest.as_str()))
+    }
+
+    pub fn account_post_id_radius_regenerate(
+        &self,
+        id: &str,
+        request: &(),
+    ) -> ClientFuture<String> {
+        let dest = format!("{}/v1/account/{}/_radius", self.addr, id);
+        perform(self.client.post(dest.as_str()).json(request))
+    }
+
+    pub fn account_delete_id_radius(&self, id: &str) -> ClientFuture<()> {
+        let dest = format!("{}/v1/account/{}/_radius", self.addr, id);
+        perform(self.client.delete(dest.as_str()))
+    }
+
+    pub fn account_get_id_radius_token(&self, id: &str) -> ClientFuture<RadiusAuthToken> {
+        let dest = format!("{}/v1/account/{}/_radius/_token", self.addr, id);
+        perform(self.client.get(dest.as_str()))
+    }
+
+    pub fn account_post_id_unix(&self, id: &str, request: &AccountUnixExtend) -> ClientFuture<()> {
+        let dest = format!("{}/v1/account/{}/_unix", self.addr, id);
+        perform(self.client.post(dest.as_str()).json(request))
+    }
+
+    pub fn account_get_id_unix_token(&self, id: &str) -> ClientFuture<UnixUserToken> {
+        let dest = format!("{}/v1/account/{}/_unix/_token", self.addr, id);
+        perform(self.client.get(dest.as_str()))
+    }
+
+    pub fn recycle_bin_get(&self) -> ClientFuture<Vec<Entry>> {
+        let dest = format!("{}/v1/recycle_bin", self.addr);
+        perform(self.client.get(dest.as_str()))
+    }
+
+    pub fn recycle_bin_id_get(&self, id: &str) -> ClientFuture<Option<Entry>> {
+        let dest = format!("{}/v1/recycle_bin/{}", self.addr, id);
+        perform(self.client.get(dest.as_str()))
+    }
+
+    pub fn recycle_bin_id_post_revive(&self, id: &str) -> ClientFuture<()> {
+        let dest = format!("{}/v1/recycle_bin/{}/_revive", self.addr, id);
+        perform(self.client.post(dest.as_str()))
+    }
+
+    pub fn group_get(&self) -> ClientFuture<Vec<Entry>> {
+        let dest = format!("{}/v1/group", self.addr);
+        perform(self.client.get(dest.as_str()))
+    }
+
+    pub fn group_post(&self, request: &Entry) -> ClientFuture<()> {
+        let dest = format!("{}/v1/group", self.addr);
+        perform(self.client.post(dest.as_str()).json(request))
+    }
+
+    pub fn group_id_get(&self, id: &str) -> ClientFuture<Option<Entry>> {
+        let dest = format!("{}/v1/group/{}", self.addr, id);
+        perform(self.client.get(dest.as_str()))
+    }
+
+    pub fn group_id_delete(&self, id: &str) -> ClientFuture<()> {
+        let dest = format!("{}/v1/group/{}", self.addr, id);
+        perform(self.client.delete(dest.as_str()))
+    }
+
+    pub fn group_id_post_members(
+        &self,
+        id: &str,
+        request: &GroupMembersRequest,
+    ) -> ClientFuture<GroupMembersResponse> {
+        let dest = format!("{}/v1/group/{}/_members", self.addr, id);
+        perform(self.client.post(dest.as_str()).json(request))
+    }
+
+    pub fn group_id_post_request(
+        &self,
+        id: &str,
+        request: &MembershipRequest,
+    ) -> ClientFuture<String> {
+        let dest = format!("{}/v1/group/{}/_request", self.addr, id);
+        perform(self.client.post(dest.as_str()).json(request))
+    }
+
+    pub fn group_id_get_attr(&self, id: &str, attr: &str) -> ClientFuture<Option<Vec<String>>> {
+        let dest = format!("{}/v1/group/{}/_attr/{}", self.addr, id, attr);
+        perform(self.client.get(dest.as_str()))
+    }
+
+    pub fn group_id_post_attr(
+        &self,
+        id: &str,
+        attr: &str,
+        request: &Vec<String>,
+    ) -> ClientFuture<()> {
+        let dest = format!("{}/v1/group/{}/_attr/{}", self.addr, id, attr);
+        perform(self.client.post(dest.as_str()).json(request))
+    }
+
+    pub fn group_id_put_attr(
+        &self,
+        id: &str,
+        attr: &str,
+        request: &Vec<String>,
+    ) -> ClientFuture<()> {
+        let dest = format!("{}/v1/group/{}/_attr/{}", self.addr, id, attr);
+        perform(self.client.put(dest.as_str()).json(request))
+    }
+
+    pub fn group_id_delete_attr(&self, id: &str, attr: &str) -> ClientFuture<()> {
+        let dest = format!("{}/v1/group/{}/_attr/{}", self.addr, id, attr);
+        perform(self.client.delete(dest.as_str()))
+    }
+
+    pub fn group_post_id_unix(&self, id: &str, request: &GroupUnixExtend) -> ClientFuture<()> {
+        let dest = format!("{}/v1/group/{}/_unix", self.addr, id);
+        perform(self.client.post(dest.as_str()).json(request))
+    }
+
+    pub fn group_get_id_unix_token(&self, id: &str) -> ClientFuture<UnixGroupToken> {
+        let dest = format!("{}/v1/group/{}/_unix/_token", self.addr, id);
+        perform(self.client.get(dest.as_str()))
+    }
+
+    pub fn savedsearch_get(&self) -> ClientFuture<Vec<Entry>> {
+        let dest = format!("{}/v1/savedsearch", self.addr);
+        perform(self.client.get(dest.as_str()))
+    }
+
+    pub fn savedsearch_post(&self, request: &Entry) -> ClientFuture<()> {
+        let dest = format!("{}/v1/savedsearch", self.addr);
+        perform(self.client.post(dest.as_str()).json(request))
+    }
+
+    pub fn savedsearch_id_get(&self, id: &str) -> ClientFuture<Option<Entry>> {
+        let dest = format!("{}/v1/savedsearch/{}", self.addr, id);
+        perform(self.client.get(dest.as_str()))
+    }
+
+    pub fn savedsearch_id_delete(&self, id: &str) -> ClientFuture<()> {
+        let dest = format!("{}/v1/savedsearch/{}", self.addr, id);
+        perform(self.client.delete(dest.as_str()))
+    }
+
+    pub fn savedsearch_id_put_attr(
+        &self,
+        id: &str,
+        attr: &str,
+        request: &Vec<String>,
+    ) -> ClientFuture<()> {
+        let dest = format!("{}/v1/savedsearch/{}/_attr/{}", self.addr, id, attr);
+        perform(self.client.put(dest.as_str()).json(request))
+    }
+
+    pub fn savedsearch_id_post_search(
+        &self,
+        id: &str,
+        request: &SavedSearchRequest,
+    ) -> ClientFuture<SearchResponse> {
+        let dest = format!("{}/v1/savedsearch/{}/_search", self.addr, id);
+        perform(self.client.post(dest.as_str()).json(request))
+    }
+
+    pub fn savedsearch_id_get_report(&self, id: &str) -> ClientFuture<Vec<ReportRecord>> {
+        let dest = format!("{}/v1/savedsearch/{}/_report", self.addr, id);
+        perform(self.client.get(dest.as_str()))
+    }
+
+    pub fn membershiprequest_get(&self) -> ClientFuture<Vec<MembershipRequestRecord>> {
+        let dest = format!("{}/v1/membershiprequest", self.addr);
+        perform(self.client.get(dest.as_str()))
+    }
+
+    pub fn membershiprequest_id_post_approve(&self, id: &str, request: &()) -> ClientFuture<()> {
+        let dest = format!("{}/v1/membershiprequest/{}/_approve", self.addr, id);
+        perform(self.client.post(dest.as_str()).json(request))
+    }
+
+    pub fn membershiprequest_id_post_reject(&self, id: &str, request: &()) -> ClientFuture<()> {
+        let dest = format!("{}/v1/membershiprequest/{}/_reject", self.addr, id);
+        perform(self.client.post(dest.as_str()).json(request))
+    }
+}
@@ -9,16 +9,25 @@ use serde::de::DeserializeOwned;
 use serde::Serialize;
 use std::collections::BTreeMap;
 use std::fs::File;
-use std::io::Read;
+use std::io::{BufRead, BufReader, Lines, Read};
 
 use kanidm_proto::v1::{
-    AuthCredential, AuthRequest, AuthResponse, AuthState, AuthStep, CreateRequest, DeleteRequest,
-    Entry, Filter, ModifyList, ModifyRequest, OperationError, OperationResponse, RadiusAuthToken,
-    SearchRequest, SearchResponse, SetAuthCredential, SingleStringRequest, UserAuthToken,
-    WhoamiResponse,
+    AccessJournalResponse, AccountUnixExtend, AttrUsage, AuthCredential, AuthRequest, AuthResponse,
+    AuthState, AuthStep, Branding, ChangesRequest, ChangesResponse, CompareRequest,
+    CompareResponse, ConsistencyError, CreateRequest, DbPoolStats, DeletePreviewResponse,
+    DeleteRequest, DomainInfo, Entry, Filter, GroupMembersRequest, GroupMembersResponse,
+    GroupUnixExtend, IndexStat, MembershipAction, MembershipRequest, MembershipRequestRecord,
+    ModifyList, ModifyRequest, OperationError, OperationResponse, OperationsResponse,
+    PersistentSearchNotice, PersistentSearchRequest, PurgeStats, RadiusAuthToken,
+    ReplSupplyRequest, ReplSupplyResponse, ReportRecord, SavedSearchRequest, SearchExplain,
+    SearchQueryRequest, SearchRequest, SearchResponse, SetAuthCredential, SingleStringRequest,
+    SlowQueryRecord, UnixGroupToken, UnixUserToken, UserAuthToken, VacuumResponse, WhoamiResponse,
+    WriteStatsRecord,
 };
 use serde_json;
 
+pub mod asynchronous;
+
 #[derive(Debug)]
 pub enum ClientError {
     Unauthorized,
@@ -29,6 +38,33 @@ pub enum ClientError {
     EmptyResponse,
 }
 
+// The notices of a persistent search, as they arrive. The search ends when
+// this is dropped, or the connection closes.
+pub struct PersistentSearch {
+    lines: Lines<BufReader<reqwest::Response>>,
+}
+
+impl Iterator for PersistentSearch {
+    type Item = Result<PersistentSearchNotice, ClientError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let line = match self.lines.next()? {
+                Ok(line) => line,
+                Err(e) => {
+                    debug!("persistent search closed -> {:?}", e);
+                    return None;
+                }
+            };
+            // Empty lines only keep the connection alive.
+            if line.is_empty() {
+                continue;
+            }
+            return Some(serde_json::from_str(line.as_str()).map_err(|_| ClientError::JsonParse));
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct KanidmClient {
     client: reqwest::Client,
@@ -243,11 +279,188 @@ impl KanidmClient {
 
     // search
     pub fn search(&self, filter: Filter) -> Result<Vec<Entry>, ClientError> {
-        let sr = SearchRequest { filter: filter };
+        let sr = SearchRequest::new(filter);
         let r: Result<SearchResponse, _> = self.perform_post_request("/v1/raw/search", sr);
         r.map(|v| v.entries)
     }
 
+    // As search, in the order of attr's values.
+    pub fn search_sorted(
+        &self,
+        filter: Filter,
+        attr: &str,
+        descending: bool,
+    ) -> Result<Vec<Entry>, ClientError> {
+        let sr = SearchRequest::new(filter).sorted_by(attr, descending);
+        let r: Result<SearchResponse, _> = self.perform_post_request("/v1/raw/search", sr);
+        r.map(|v| v.entries)
+    }
+
+    // Only the named attributes of each entry.
+    pub fn search_attrs(&self, filter: Filter, attrs: &[&str]) -> Result<Vec<Entry>, ClientError> {
+        self.search_request(SearchRequest::new(filter).with_attrs(attrs))
+    }
+
+    // For when the above helpers don't say enough, IE sorted and projected.
+    pub fn search_request(&self, sr: SearchRequest) -> Result<Vec<Entry>, ClientError> {
+        let r: Result<SearchResponse, _> = self.perform_post_request("/v1/raw/search", sr);
+        r.map(|v| v.entries)
+    }
+
+    // A page of at most page_size entries. Pass the next_cursor of the
+    // response with the same filter for the next page, until it is None.
+    pub fn search_paged(
+        &self,
+        filter: Filter,
+        page_size: u32,
+        cursor: Option<String>,
+    ) -> Result<SearchResponse, ClientError> {
+        let sr = SearchRequest::new_paged(filter, page_size, cursor);
+        self.perform_post_request("/v1/raw/search", sr)
+    }
+
+    // Be sent each change to the entries filter matches, beginning with
+    // those it matches now.
+    pub fn psearch(&self, filter: Filter) -> Result<PersistentSearch, ClientError> {
+        let dest = format!("{}/v1/raw/psearch", self.addr);
+        let req_string = serde_json::to_string(&PersistentSearchRequest::new(filter)).unwrap();
+
+        let mut response = self
+            .client
+            .post(dest.as_str())
+            .body(req_string)
+            .send()
+            .map_err(|e| ClientError::Transport(e))?;
+
+        match response.status() {
+            reqwest::StatusCode::OK => {}
+            unexpect => return Err(ClientError::Http(unexpect, response.json().ok())),
+        }
+
+        Ok(PersistentSearch {
+            lines: BufReader::new(response).lines(),
+        })
+    }
+
+    // The filter as text, IE (&(class=person)(name=*will*)).
+    pub fn search_query(&self, query: &str) -> Result<Vec<Entry>, ClientError> {
+        let sr = SearchQueryRequest::new(query.to_string());
+        let r: Result<SearchResponse, _> = self.perform_post_request("/v1/raw/search/query", sr);
+        r.map(|v| v.entries)
+    }
+
+    pub fn compare(&self, target: &str, attr: &str, value: &str) -> Result<bool, ClientError> {
+        let cr = CompareRequest::new(target.to_string(), attr.to_string(), value.to_string());
+        let r: Result<CompareResponse, _> = self.perform_post_request("/v1/raw/compare", cr);
+        r.map(|v| v.result)
+    }
+
+    // Pass the cookie from the previous response to get only what changed
+    // since, or None to start from the beginning.
+    pub fn changes(&self, cookie: Option<String>) -> Result<ChangesResponse, ClientError> {
+        let cr = ChangesRequest::new(cookie);
+        self.perform_post_request("/v1/raw/changes", cr)
+    }
+
+    // Requires membership of system_admins. This is how kanidmd replicas pull
+    // from each other.
+    pub fn repl_supply(&self, cookie: Option<String>) -> Result<ReplSupplyResponse, ClientError> {
+        let rr = ReplSupplyRequest::new(cookie);
+        self.perform_post_request("/v1/repl/supply", rr)
+    }
+
+    // Anyone may ask, authenticated or not.
+    pub fn get_domain_info(&self) -> Result<DomainInfo, ClientError> {
+        self.perform_get_request("/v1/domain")
+    }
+
+    pub fn get_branding(&self) -> Result<Branding, ClientError> {
+        self.perform_get_request("/v1/branding")
+    }
+
+    // Requires membership of system_admins.
+    pub fn list_operations(&self) -> Result<OperationsResponse, ClientError> {
+        self.perform_get_request("/v1/admin/operations")
+    }
+
+    pub fn cancel_operation(&self, id: u64) -> Result<bool, ClientError> {
+        self.perform_post_request(format!("/v1/admin/operations/{}/cancel", id).as_str(), ())
+    }
+
+    // Requires membership of system_admins.
+    pub fn index_stats(&self) -> Result<Vec<IndexStat>, ClientError> {
+        self.perform_get_request("/v1/admin/index_stats")
+    }
+
+    // Requires membership of system_admins. How many entries hold attr, or
+    // each attribute in schema if None.
+    pub fn attr_usage(&self, attr: Option<&str>) -> Result<Vec<AttrUsage>, ClientError> {
+        match attr {
+            Some(a) => self.perform_get_request(format!("/v1/admin/attr_usage/{}", a).as_str()),
+            None => self.perform_get_request("/v1/admin/attr_usage"),
+        }
+    }
+
+    // Requires membership of system_admins. The most recent first.
+    pub fn slow_queries(&self) -> Result<Vec<SlowQueryRecord>, ClientError> {
+        self.perform_get_request("/v1/admin/slow_queries")
+    }
+
+    // Requires membership of system_admins. While read only, anything that
+    // writes fails with OperationError::ReadOnly, and reads carry on.
+    pub fn set_read_only(&self, read_only: bool) -> Result<bool, ClientError> {
+        self.perform_put_request("/v1/admin/read_only", read_only)
+    }
+
+    // Requires membership of system_admins.
+    pub fn db_pool_stats(&self) -> Result<DbPoolStats, ClientError> {
+        self.perform_get_request("/v1/admin/db_pool_stats")
+    }
+
+    // Requires membership of system_admins. What the scheduled purges of
+    // recycled entries and tombstones have reaped since the server started.
+    pub fn purge_stats(&self) -> Result<PurgeStats, ClientError> {
+        self.perform_get_request("/v1/admin/purge_stats")
+    }
+
+    // Requires membership of system_admins. What the recent write txns wrote,
+    // the most recent first.
+    pub fn write_stats(&self) -> Result<Vec<WriteStatsRecord>, ClientError> {
+        self.perform_get_request("/v1/admin/write_stats")
+    }
+
+    // Requires membership of system_admins.
+    pub fn access_journal(&self) -> Result<AccessJournalResponse, ClientError> {
+        self.perform_get_request("/v1/admin/access_journal")
+    }
+
+    // Requires membership of system_admins. Any consistency errors found.
+    pub fn verify(&self) -> Result<Vec<ConsistencyError>, ClientError> {
+        self.perform_post_request("/v1/admin/verify", ())
+    }
+
+    // Requires membership of system_admins.
+    pub fn reindex(&self) -> Result<(), ClientError> {
+        self.perform_post_request("/v1/admin/reindex", ())
+    }
+
+    // Requires membership of system_admins.
+    pub fn vacuum(&self) -> Result<VacuumResponse, ClientError> {
+        self.perform_post_request("/v1/admin/vacuum", ())
+    }
+
+    // What deleting the entries matching filter would break, without deleting them.
+    pub fn delete_preview(&self, filter: Filter) -> Result<DeletePreviewResponse, ClientError> {
+        let dr = DeleteRequest { filter: filter };
+        self.perform_post_request("/v1/raw/delete/preview", dr)
+    }
+
+    // Requires membership of system_admins.
+    pub fn explain(&self, filter: Filter) -> Result<SearchExplain, ClientError> {
+        let sr = SearchRequest::new(filter);
+        self.perform_post_request("/v1/raw/explain", sr)
+    }
+
     // create
     pub fn create(&self, entries: Vec<Entry>) -> Result<(), ClientError> {
         let c = CreateRequest { entries: entries };
@@ -317,12 +530,91 @@ impl KanidmClient {
         self.perform_post_request(format!("/v1/group/{}/_attr/member", id).as_str(), m)
     }
 
+    // Add and remove many members in a single write, rather than one per member.
+    pub fn idm_group_update_members(
+        &self,
+        id: &str,
+        add: Vec<&str>,
+        remove: Vec<&str>,
+    ) -> Result<GroupMembersResponse, ClientError> {
+        let gmr = GroupMembersRequest {
+            add: add.iter().map(|v| v.to_string()).collect(),
+            remove: remove.iter().map(|v| v.to_string()).collect(),
+        };
+        self.perform_post_request(format!("/v1/group/{}/_members", id).as_str(), gmr)
+    }
+
     /*
     pub fn idm_group_remove_member(&self, id: &str, member: &str) -> Result<(), ClientError> {
         unimplemented!();
     }
     */
 
+    pub fn idm_group_set_approvers(
+        &self,
+        id: &str,
+        approvers: Vec<&str>,
+    ) -> Result<(), ClientError> {
+        let m: Vec<_> = approvers.iter().map(|v| v.to_string()).collect();
+        self.perform_put_request(
+            format!("/v1/group/{}/_attr/membership_approver", id).as_str(),
+            m,
+        )
+    }
+
+    // Ask the group's approvers to add or remove member. Returns the request id.
+    pub fn idm_group_request_membership(
+        &self,
+        id: &str,
+        member: &str,
+        action: MembershipAction,
+    ) -> Result<String, ClientError> {
+        let mr = MembershipRequest::new(member.to_string(), action);
+        self.perform_post_request(format!("/v1/group/{}/_request", id).as_str(), mr)
+    }
+
+    pub fn idm_membership_requests(&self) -> Result<Vec<MembershipRequestRecord>, ClientError> {
+        self.perform_get_request("/v1/membershiprequest")
+    }
+
+    pub fn idm_membership_request_approve(&self, id: &str) -> Result<(), ClientError> {
+        self.perform_post_request(
+            format!("/v1/membershiprequest/{}/_approve", id).as_str(),
+            (),
+        )
+    }
+
+    pub fn idm_membership_request_reject(&self, id: &str) -> Result<(), ClientError> {
+        self.perform_post_request(format!("/v1/membershiprequest/{}/_reject", id).as_str(), ())
+    }
+
+    // The vlan RADIUS puts the group's members on.
+    pub fn idm_group_set_radius_vlan(&self, id: &str, vlan: u32) -> Result<(), ClientError> {
+        self.perform_put_request(
+            format!("/v1/group/{}/_attr/radius_vlan", id).as_str(),
+            vec![vlan.to_string()],
+        )
+    }
+
+    pub fn idm_group_purge_radius_vlan(&self, id: &str) -> Result<(), ClientError> {
+        self.perform_delete_request(format!("/v1/group/{}/_attr/radius_vlan", id).as_str())
+    }
+
+    pub fn idm_group_unix_token_get(&self, id: &str) -> Result<UnixGroupToken, ClientError> {
+        self.perform_get_request(format!("/v1/group/{}/_unix/_token", id).as_str())
+    }
+
+    pub fn idm_group_unix_extend(
+        &self,
+        id: &str,
+        gidnumber: Option<u32>,
+    ) -> Result<(), ClientError> {
+        let gx = GroupUnixExtend {
+            gidnumber: gidnumber,
+        };
+        self.perform_post_request(format!("/v1/group/{}/_unix", id).as_str(), gx)
+    }
+
     pub fn idm_group_purge_members(&self, id: &str) -> Result<(), ClientError> {
         self.perform_delete_request(format!("/v1/group/{}/_attr/member", id).as_str())
     }
@@ -341,6 +633,61 @@ impl KanidmClient {
         self.perform_post_request(format!("/v1/group").as_str(), new_group)
     }
 
+    // ==== saved searches
+    pub fn idm_savedsearch_list(&self) -> Result<Vec<Entry>, ClientError> {
+        self.perform_get_request("/v1/savedsearch")
+    }
+
+    pub fn idm_savedsearch_get(&self, id: &str) -> Result<Option<Entry>, ClientError> {
+        self.perform_get_request(format!("/v1/savedsearch/{}", id).as_str())
+    }
+
+    // Values of the filter may hold ${param} placeholders, given when it's run.
+    pub fn idm_savedsearch_create(&self, name: &str, filter: &Filter) -> Result<(), ClientError> {
+        let filter = serde_json::to_string(filter).unwrap();
+        let mut new_search = Entry {
+            attrs: BTreeMap::new(),
+        };
+        new_search
+            .attrs
+            .insert("name".to_string(), vec![name.to_string()]);
+        new_search
+            .attrs
+            .insert("savedsearch_filter".to_string(), vec![filter]);
+        self.perform_post_request("/v1/savedsearch", new_search)
+    }
+
+    pub fn idm_savedsearch_delete(&self, id: &str) -> Result<(), ClientError> {
+        self.perform_delete_request(format!("/v1/savedsearch/{}", id).as_str())
+    }
+
+    pub fn idm_savedsearch_run(
+        &self,
+        id: &str,
+        params: BTreeMap<String, String>,
+    ) -> Result<Vec<Entry>, ClientError> {
+        let sr = SavedSearchRequest::new(params);
+        let r: Result<SearchResponse, _> =
+            self.perform_post_request(format!("/v1/savedsearch/{}/_search", id).as_str(), sr);
+        r.map(|v| v.entries)
+    }
+
+    // Run the saved search as a report every interval seconds.
+    pub fn idm_savedsearch_set_report_interval(
+        &self,
+        id: &str,
+        interval: u64,
+    ) -> Result<(), ClientError> {
+        self.perform_put_request(
+            format!("/v1/savedsearch/{}/_attr/report_interval", id).as_str(),
+            vec![interval.to_string()],
+        )
+    }
+
+    pub fn idm_savedsearch_reports(&self, id: &str) -> Result<Vec<ReportRecord>, ClientError> {
+        self.perform_get_request(format!("/v1/savedsearch/{}/_report", id).as_str())
+    }
+
     // ==== accounts
     pub fn idm_account_list(&self) -> Result<Vec<Entry>, ClientError> {
         self.perform_get_request("/v1/account")
@@ -426,6 +773,24 @@ impl KanidmClient {
         self.perform_get_request(format!("/v1/account/{}/_radius/_token", id).as_str())
     }
 
+    // The id may also be a uid, as nss looks accounts up by number.
+    pub fn idm_account_unix_token_get(&self, id: &str) -> Result<UnixUserToken, ClientError> {
+        self.perform_get_request(format!("/v1/account/{}/_unix/_token", id).as_str())
+    }
+
+    pub fn idm_account_unix_extend(
+        &self,
+        id: &str,
+        gidnumber: Option<u32>,
+        shell: Option<&str>,
+    ) -> Result<(), ClientError> {
+        let ux = AccountUnixExtend {
+            gidnumber: gidnumber,
+            shell: shell.map(|s| s.to_string()),
+        };
+        self.perform_post_request(format!("/v1/account/{}/_unix", id).as_str(), ux)
+    }
+
     // ==== schema
     pub fn idm_schema_list(&self) -> Result<Vec<Entry>, ClientError> {
         self.perform_get_request("/v1/schema")
@@ -446,4 +811,19 @@ impl KanidmClient {
     pub fn idm_schema_classtype_get(&self, id: &str) -> Result<Option<Entry>, ClientError> {
         self.perform_get_request(format!("/v1/schema/classtype/{}", id).as_str())
     }
+
+    // ==== recycle bin
+    // Deleted entries you can see, until they're made tombstones.
+    pub fn recycle_bin_list(&self) -> Result<Vec<Entry>, ClientError> {
+        self.perform_get_request("/v1/recycle_bin")
+    }
+
+    pub fn recycle_bin_get(&self, id: &str) -> Result<Option<Entry>, ClientError> {
+        self.perform_get_request(format!("/v1/recycle_bin/{}", id).as_str())
+    }
+
+    // Back to live, and back in the groups it was in.
+    pub fn recycle_bin_revive(&self, id: &str) -> Result<(), ClientError> {
+        self.perform_post_request(format!("/v1/recycle_bin/{}/_revive", id).as_str(), ())
+    }
 }
@@ -15,7 +15,7 @@ use kanidm_client::KanidmClient;
 
 use kanidm::config::{Configuration, IntegrationTestConfig};
 use kanidm::core::create_server_core;
-use kanidm_proto::v1::{Entry, Filter, Modify, ModifyList};
+use kanidm_proto::v1::{Entry, Filter, Modify, ModifyList, PersistentSearchNotice, SearchRequest};
 
 extern crate reqwest;
 
@@ -107,6 +107,153 @@ fn test_server_create() {
     });
 }
 
+#[test]
+fn test_server_read_only() {
+    run_test(|rsclient: KanidmClient| {
+        let e: Entry = serde_json::from_str(
+            r#"{
+            "attrs": {
+                "class": ["account"],
+                "name": ["testperson"],
+                "displayname": ["testperson"]
+            }
+        }"#,
+        )
+        .unwrap();
+
+        // Only a system admin may change it.
+        assert!(rsclient.set_read_only(true).is_err());
+
+        let a_res = rsclient.auth_simple_password("admin", ADMIN_TEST_PASSWORD);
+        assert!(a_res.is_ok());
+
+        assert_eq!(rsclient.set_read_only(true).unwrap(), true);
+        assert!(rsclient.create(vec![e.clone()]).is_err());
+        // Reads carry on.
+        assert!(rsclient.whoami().is_ok());
+
+        assert_eq!(rsclient.set_read_only(false).unwrap(), false);
+        assert!(rsclient.create(vec![e]).is_ok());
+    });
+}
+
+#[test]
+fn test_server_search_sorted() {
+    run_test(|rsclient: KanidmClient| {
+        let a_res = rsclient.auth_simple_password("admin", ADMIN_TEST_PASSWORD);
+        assert!(a_res.is_ok());
+
+        let filter = Filter::Pres("name".to_string());
+        let names = |desc: bool| -> Vec<String> {
+            rsclient
+                .search_sorted(filter.clone(), "name", desc)
+                .unwrap()
+                .iter()
+                .map(|e| e.attrs.get("name").unwrap()[0].clone())
+                .collect()
+        };
+        let asc = names(false);
+        let mut expect = asc.clone();
+        expect.sort();
+        assert_eq!(asc, expect);
+        expect.reverse();
+        assert_eq!(names(true), expect);
+
+        // Only attributes the schema knows may be sorted on.
+        assert!(rsclient
+            .search_sorted(filter, "not_an_attribute", false)
+            .is_err());
+    });
+}
+
+#[test]
+fn test_server_search_attrs() {
+    run_test(|rsclient: KanidmClient| {
+        let a_res = rsclient.auth_simple_password("admin", ADMIN_TEST_PASSWORD);
+        assert!(a_res.is_ok());
+
+        let filter = Filter::Eq("name".to_string(), "admin".to_string());
+        let r = rsclient.search_attrs(filter.clone(), &["name"]).unwrap();
+        assert!(r.len() == 1);
+        let attrs: Vec<&String> = r[0].attrs.keys().collect();
+        assert_eq!(attrs, vec!["name"]);
+
+        // The attribute sorted by comes back too.
+        let sr = SearchRequest::new(filter.clone())
+            .sorted_by("displayname", false)
+            .with_attrs(&["name"]);
+        let r = rsclient.search_request(sr).unwrap();
+        assert!(r[0].attrs.contains_key("displayname"));
+        assert!(!r[0].attrs.contains_key("class"));
+
+        assert!(rsclient.search_attrs(filter.clone(), &[]).is_err());
+        assert!(rsclient
+            .search_attrs(filter, &["not_an_attribute"])
+            .is_err());
+    });
+}
+
+#[test]
+fn test_server_psearch() {
+    run_test(|rsclient: KanidmClient| {
+        let a_res = rsclient.auth_simple_password("admin", ADMIN_TEST_PASSWORD);
+        assert!(a_res.is_ok());
+
+        let filter = Filter::Eq("name".to_string(), "psearch_group".to_string());
+        let mut ps = rsclient.psearch(filter).unwrap();
+        rsclient.idm_group_create("psearch_group").unwrap();
+        match ps.next() {
+            Some(Ok(PersistentSearchNotice::Add(e))) => {
+                assert_eq!(
+                    e.attrs.get("name"),
+                    Some(&vec!["psearch_group".to_string()])
+                )
+            }
+            n => panic!("unexpected notice {:?}", n),
+        }
+
+        // Anything after that is about the same group, until it's deleted.
+        rsclient.idm_group_delete("psearch_group").unwrap();
+        loop {
+            match ps.next() {
+                Some(Ok(PersistentSearchNotice::Modify(_))) => {}
+                Some(Ok(PersistentSearchNotice::Delete(_))) => break,
+                n => panic!("unexpected notice {:?}", n),
+            }
+        }
+    });
+}
+
+#[test]
+fn test_server_search_paged() {
+    run_test(|rsclient: KanidmClient| {
+        let a_res = rsclient.auth_simple_password("admin", ADMIN_TEST_PASSWORD);
+        assert!(a_res.is_ok());
+
+        let filter = Filter::Pres("class".to_string());
+        let all = rsclient.search(filter.clone()).unwrap();
+        assert!(all.len() > 3);
+
+        let mut paged = Vec::new();
+        let mut cursor = None;
+        loop {
+            let r = rsclient.search_paged(filter.clone(), 3, cursor).unwrap();
+            assert!(r.entries.len() <= 3);
+            paged.extend(r.entries);
+            cursor = r.next_cursor;
+            if cursor.is_none() {
+                break;
+            }
+        }
+        assert_eq!(paged.len(), all.len());
+
+        // A cursor that was never handed out.
+        assert!(rsclient
+            .search_paged(filter, 3, Some("not a cursor".to_string()))
+            .is_err());
+    });
+}
+
 #[test]
 fn test_server_modify() {
     run_test(|rsclient: KanidmClient| {
@@ -153,6 +300,96 @@ fn test_server_whoami_anonymous() {
     });
 }
 
+#[test]
+fn test_server_domain_info() {
+    run_test(|rsclient: KanidmClient| {
+        // Available without authenticating, and stable between calls.
+        let d1 = rsclient.get_domain_info().unwrap();
+        let d2 = rsclient.get_domain_info().unwrap();
+        assert!(d1 == d2);
+        assert!(d1.generation >= 1);
+    });
+}
+
+#[test]
+fn test_server_branding() {
+    run_test(|rsclient: KanidmClient| {
+        // Public, so anyone can draw a login page.
+        let b = rsclient.get_branding().unwrap();
+        assert_eq!(b.display_name, "Kanidm");
+        assert!(b.login_banner.is_none());
+
+        let res = rsclient.auth_simple_password("admin", ADMIN_TEST_PASSWORD);
+        assert!(res.is_ok());
+        // Only idm_admins may change it, which admin isn't in by default.
+        let f = Filter::Eq("name".to_string(), "idm_admins".to_string());
+        let m = ModifyList::new_list(vec![Modify::Present(
+            "member".to_string(),
+            "system_admins".to_string(),
+        )]);
+        assert!(rsclient.modify(f, m).is_ok());
+
+        let f = Filter::Eq("class".to_string(), "domain_branding".to_string());
+        let m = ModifyList::new_list(vec![Modify::Present(
+            "login_banner".to_string(),
+            "Authorised use only".to_string(),
+        )]);
+        assert!(rsclient.modify(f, m).is_ok());
+
+        let b = rsclient.get_branding().unwrap();
+        assert_eq!(b.login_banner, Some("Authorised use only".to_string()));
+    });
+}
+
+#[test]
+fn test_server_rest_recycle_bin() {
+    run_test(|rsclient: KanidmClient| {
+        let res = rsclient.auth_simple_password("admin", ADMIN_TEST_PASSWORD);
+        assert!(res.is_ok());
+
+        // The recycle bin is for system_admins, which admin is in.
+        rsclient.idm_group_create("recycle_group").unwrap();
+        rsclient.idm_group_delete("recycle_group").unwrap();
+        assert!(rsclient.idm_group_get("recycle_group").unwrap().is_none());
+
+        let rc = rsclient.recycle_bin_get("recycle_group").unwrap();
+        assert!(rc.is_some());
+        assert!(rsclient
+            .recycle_bin_list()
+            .unwrap()
+            .iter()
+            .any(|e| e.attrs.get("name") == Some(&vec!["recycle_group".to_string()])));
+
+        rsclient.recycle_bin_revive("recycle_group").unwrap();
+        assert!(rsclient.idm_group_get("recycle_group").unwrap().is_some());
+        assert!(rsclient.recycle_bin_get("recycle_group").unwrap().is_none());
+    });
+}
+
+#[test]
+fn test_server_repl_supply() {
+    run_test(|rsclient: KanidmClient| {
+        // Partners must authenticate as a system admin.
+        assert!(rsclient.repl_supply(None).is_err());
+        let res = rsclient.auth_simple_password("admin", ADMIN_TEST_PASSWORD);
+        assert!(res.is_ok());
+
+        // From the start, we get everything, with a CID on every attribute.
+        let all = rsclient.repl_supply(None).unwrap();
+        assert!(!all.entries.is_empty());
+        assert!(all.entries.iter().all(|e| !e.cids.is_empty()));
+
+        // And from where that left us, only what changed since.
+        rsclient.idm_group_create("repl_group").unwrap();
+        let since = rsclient.repl_supply(Some(all.cookie.clone())).unwrap();
+        assert!(since.entries.len() >= 1);
+        assert!(since.entries.len() < all.entries.len());
+
+        let after = rsclient.repl_supply(Some(since.cookie)).unwrap();
+        assert!(after.entries.is_empty());
+    });
+}
+
 #[test]
 fn test_server_whoami_admin_simple_password() {
     run_test(|rsclient: KanidmClient| {
@@ -472,6 +709,47 @@ fn test_server_rest_account_lifecycle() {
     });
 }
 
+#[test]
+fn test_server_rest_posix_lifecycle() {
+    run_test(|rsclient: KanidmClient| {
+        let res = rsclient.auth_simple_password("admin", ADMIN_TEST_PASSWORD);
+        assert!(res.is_ok());
+        // Extending to posix is for idm admins.
+        rsclient
+            .idm_group_add_members("idm_admins", vec!["admin"])
+            .unwrap();
+
+        rsclient.idm_group_create("posix_group").unwrap();
+        rsclient
+            .idm_group_add_members("posix_group", vec!["admin"])
+            .unwrap();
+        rsclient
+            .idm_group_unix_extend("posix_group", Some(2001))
+            .unwrap();
+        // Not posix yet.
+        assert!(rsclient.idm_account_unix_token_get("admin").is_err());
+        rsclient
+            .idm_account_unix_extend("admin", None, Some("/bin/zsh"))
+            .unwrap();
+
+        let r_tok = rsclient.idm_account_unix_token_get("admin").unwrap();
+        assert!(r_tok.name == "admin");
+        assert!(r_tok.gidnumber >= 65536);
+        assert!(r_tok.shell == Some("/bin/zsh".to_string()));
+        let gids: Vec<u32> = r_tok.groups.iter().map(|g| g.gidnumber).collect();
+        assert!(gids == vec![r_tok.gidnumber, 2001]);
+
+        // And the same account by its uid.
+        let r_tok2 = rsclient
+            .idm_account_unix_token_get(r_tok.gidnumber.to_string().as_str())
+            .unwrap();
+        assert!(r_tok2 == r_tok);
+
+        let g_tok = rsclient.idm_group_unix_token_get("2001").unwrap();
+        assert!(g_tok.name == "posix_group");
+    });
+}
+
 // Test the self version of the radius path.
 
 // Test hitting all auth-required endpoints and assert they give unauthorized.
@@ -0,0 +1,14 @@
+// Prints the generated openapi spec or async client, see src/openapi.rs.
+
+use kanidm_proto::openapi::{rust_client, spec};
+
+fn main() {
+    match std::env::args().nth(1).as_ref().map(|s| s.as_str()) {
+        Some("spec") => println!(
+            "{}",
+            serde_json::to_string_pretty(&spec()).expect("Failed to serialise spec")
+        ),
+        Some("client") => print!("{}", rust_client()),
+        _ => eprintln!("usage: openapi spec|client"),
+    }
+}
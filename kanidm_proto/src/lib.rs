@@ -4,4 +4,7 @@
 #[macro_use]
 extern crate serde_derive;
 
+#[cfg(feature = "openapi")]
+pub mod openapi;
+pub mod query;
 pub mod v1;
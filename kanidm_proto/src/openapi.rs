@@ -0,0 +1,691 @@
+// The v1 http api as data, so that an openapi spec and a typed async client can
+// be generated from the same types the server and kanidm_client use rather than
+// being maintained by hand. The generated outputs are checked in (openapi.json
+// in this crate, and kanidm_client's asynchronous.rs), and the tests below fail
+// when they drift from what would be generated now. To regenerate them:
+//
+//   cargo run -p kanidm_proto --features openapi --example openapi -- spec > kanidm_proto/openapi.json
+//   cargo run -p kanidm_proto --features openapi --example openapi -- client > kanidm_client/src/asynchronous.rs
+//
+// When you add a route to the server, add it to endpoints() too.
+
+use crate::v1::*;
+use schemars::gen::{SchemaGenerator, SchemaSettings};
+use schemars::schema::Schema;
+use schemars::JsonSchema;
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+
+// A type as it's named in rust, and how to describe it in the spec.
+pub struct TypeRef {
+    pub name: &'static str,
+    pub schema: fn(&mut SchemaGenerator) -> Schema,
+}
+
+impl TypeRef {
+    fn of<T: JsonSchema>(name: &'static str) -> Self {
+        TypeRef {
+            name: name,
+            schema: SchemaGenerator::subschema_for::<T>,
+        }
+    }
+}
+
+pub struct Endpoint {
+    pub method: &'static str,
+    pub path: &'static str,
+    // Matches the handler name in the server, and is the client fn name.
+    pub operation: &'static str,
+    pub request: Option<TypeRef>,
+    pub response: TypeRef,
+}
+
+impl Endpoint {
+    // The {name} segments of the path, in order.
+    pub fn path_params(&self) -> Vec<&'static str> {
+        self.path
+            .split('/')
+            .filter(|s| s.starts_with('{') && s.ends_with('}'))
+            .map(|s| &s[1..s.len() - 1])
+            .collect()
+    }
+}
+
+macro_rules! endpoint {
+    ($method:expr, $path:expr, $op:expr, $resp:ty) => {
+        Endpoint {
+            method: $method,
+            path: $path,
+            operation: $op,
+            request: None,
+            response: TypeRef::of::<$resp>(stringify!($resp)),
+        }
+    };
+    ($method:expr, $path:expr, $op:expr, $req:ty, $resp:ty) => {
+        Endpoint {
+            method: $method,
+            path: $path,
+            operation: $op,
+            request: Some(TypeRef::of::<$req>(stringify!($req))),
+            response: TypeRef::of::<$resp>(stringify!($resp)),
+        }
+    };
+}
+
+// Routes the server answers with do_nothing aren't listed until they do something.
+// Nor is /v1/raw/psearch, whose response is a stream of PersistentSearchNotice,
+// one to a line, rather than a single document.
+pub fn endpoints() -> Vec<Endpoint> {
+    vec![
+        endpoint!("GET", "/status", "status", StatusResponse),
+        endpoint!(
+            "POST",
+            "/v1/raw/create",
+            "create",
+            CreateRequest,
+            OperationResponse
+        ),
+        endpoint!(
+            "POST",
+            "/v1/raw/modify",
+            "modify",
+            ModifyRequest,
+            OperationResponse
+        ),
+        endpoint!(
+            "POST",
+            "/v1/raw/delete",
+            "delete",
+            DeleteRequest,
+            OperationResponse
+        ),
+        endpoint!(
+            "POST",
+            "/v1/raw/delete/preview",
+            "delete_preview",
+            DeleteRequest,
+            DeletePreviewResponse
+        ),
+        endpoint!(
+            "POST",
+            "/v1/raw/search",
+            "search",
+            SearchRequest,
+            SearchResponse
+        ),
+        endpoint!(
+            "POST",
+            "/v1/raw/search/query",
+            "search_query",
+            SearchQueryRequest,
+            SearchResponse
+        ),
+        endpoint!(
+            "POST",
+            "/v1/raw/compare",
+            "compare",
+            CompareRequest,
+            CompareResponse
+        ),
+        endpoint!(
+            "POST",
+            "/v1/raw/changes",
+            "changes",
+            ChangesRequest,
+            ChangesResponse
+        ),
+        endpoint!(
+            "POST",
+            "/v1/repl/supply",
+            "repl_supply",
+            ReplSupplyRequest,
+            ReplSupplyResponse
+        ),
+        endpoint!(
+            "POST",
+            "/v1/raw/explain",
+            "explain",
+            SearchRequest,
+            SearchExplain
+        ),
+        endpoint!("POST", "/v1/auth", "auth", AuthRequest, AuthResponse),
+        endpoint!("GET", "/v1/domain", "domain_info", DomainInfo),
+        endpoint!("GET", "/v1/branding", "branding", Branding),
+        endpoint!(
+            "GET",
+            "/v1/admin/operations",
+            "operations_get",
+            OperationsResponse
+        ),
+        endpoint!(
+            "POST",
+            "/v1/admin/operations/{id}/cancel",
+            "operations_cancel",
+            (),
+            bool
+        ),
+        endpoint!(
+            "GET",
+            "/v1/admin/index_stats",
+            "index_stats",
+            Vec<IndexStat>
+        ),
+        endpoint!("GET", "/v1/admin/attr_usage", "attr_usage", Vec<AttrUsage>),
+        endpoint!(
+            "GET",
+            "/v1/admin/attr_usage/{attr}",
+            "attr_usage_id",
+            Vec<AttrUsage>
+        ),
+        endpoint!(
+            "GET",
+            "/v1/admin/slow_queries",
+            "slow_queries",
+            Vec<SlowQueryRecord>
+        ),
+        endpoint!("PUT", "/v1/admin/read_only", "read_only_put", bool, bool),
+        endpoint!(
+            "GET",
+            "/v1/admin/db_pool_stats",
+            "db_pool_stats",
+            DbPoolStats
+        ),
+        endpoint!("GET", "/v1/admin/purge_stats", "purge_stats", PurgeStats),
+        endpoint!(
+            "GET",
+            "/v1/admin/write_stats",
+            "write_stats",
+            Vec<WriteStatsRecord>
+        ),
+        endpoint!(
+            "GET",
+            "/v1/admin/access_journal",
+            "access_journal",
+            AccessJournalResponse
+        ),
+        endpoint!(
+            "POST",
+            "/v1/admin/verify",
+            "verify",
+            (),
+            Vec<ConsistencyError>
+        ),
+        endpoint!("POST", "/v1/admin/reindex", "reindex", (), ()),
+        endpoint!("POST", "/v1/admin/vacuum", "vacuum", (), VacuumResponse),
+        endpoint!("GET", "/v1/schema", "schema_get", Vec<Entry>),
+        endpoint!(
+            "GET",
+            "/v1/schema/attributetype",
+            "schema_attributetype_get",
+            Vec<Entry>
+        ),
+        endpoint!(
+            "GET",
+            "/v1/schema/attributetype/{id}",
+            "schema_attributetype_get_id",
+            Option<Entry>
+        ),
+        endpoint!(
+            "GET",
+            "/v1/schema/classtype",
+            "schema_classtype_get",
+            Vec<Entry>
+        ),
+        endpoint!(
+            "GET",
+            "/v1/schema/classtype/{id}",
+            "schema_classtype_get_id",
+            Option<Entry>
+        ),
+        endpoint!("GET", "/v1/self", "whoami", WhoamiResponse),
+        endpoint!(
+            "POST",
+            "/v1/self/_credential/primary/set_password",
+            "idm_account_set_password",
+            SingleStringRequest,
+            OperationResponse
+        ),
+        endpoint!("GET", "/v1/account", "account_get", Vec<Entry>),
+        endpoint!("POST", "/v1/account", "account_post", Entry, ()),
+        endpoint!("GET", "/v1/account/{id}", "account_id_get", Option<Entry>),
+        endpoint!("DELETE", "/v1/account/{id}", "account_id_delete", ()),
+        endpoint!(
+            "GET",
+            "/v1/account/{id}/_attr/{attr}",
+            "account_id_get_attr",
+            Option<Vec<String>>
+        ),
+        endpoint!(
+            "POST",
+            "/v1/account/{id}/_attr/{attr}",
+            "account_id_post_attr",
+            Vec<String>,
+            ()
+        ),
+        endpoint!(
+            "PUT",
+            "/v1/account/{id}/_attr/{attr}",
+            "account_id_put_attr",
+            Vec<String>,
+            ()
+        ),
+        endpoint!(
+            "DELETE",
+            "/v1/account/{id}/_attr/{attr}",
+            "account_id_delete_attr",
+            ()
+        ),
+        endpoint!(
+            "PUT",
+            "/v1/account/{id}/_credential/primary",
+            "account_put_id_credential_primary",
+            SetAuthCredential,
+            Option<String>
+        ),
+        endpoint!(
+            "GET",
+            "/v1/account/{id}/_radius",
+            "account_get_id_radius",
+            Option<String>
+        ),
+        endpoint!(
+            "POST",
+            "/v1/account/{id}/_radius",
+            "account_post_id_radius_regenerate",
+            (),
+            String
+        ),
+        endpoint!(
+            "DELETE",
+            "/v1/account/{id}/_radius",
+            "account_delete_id_radius",
+            ()
+        ),
+        endpoint!(
+            "GET",
+            "/v1/account/{id}/_radius/_token",
+            "account_get_id_radius_token",
+            RadiusAuthToken
+        ),
+        endpoint!(
+            "POST",
+            "/v1/account/{id}/_unix",
+            "account_post_id_unix",
+            AccountUnixExtend,
+            ()
+        ),
+        endpoint!(
+            "GET",
+            "/v1/account/{id}/_unix/_token",
+            "account_get_id_unix_token",
+            UnixUserToken
+        ),
+        endpoint!("GET", "/v1/recycle_bin", "recycle_bin_get", Vec<Entry>),
+        endpoint!(
+            "GET",
+            "/v1/recycle_bin/{id}",
+            "recycle_bin_id_get",
+            Option<Entry>
+        ),
+        endpoint!(
+            "POST",
+            "/v1/recycle_bin/{id}/_revive",
+            "recycle_bin_id_post_revive",
+            ()
+        ),
+        endpoint!("GET", "/v1/group", "group_get", Vec<Entry>),
+        endpoint!("POST", "/v1/group", "group_post", Entry, ()),
+        endpoint!("GET", "/v1/group/{id}", "group_id_get", Option<Entry>),
+        endpoint!("DELETE", "/v1/group/{id}", "group_id_delete", ()),
+        endpoint!(
+            "POST",
+            "/v1/group/{id}/_members",
+            "group_id_post_members",
+            GroupMembersRequest,
+            GroupMembersResponse
+        ),
+        endpoint!(
+            "POST",
+            "/v1/group/{id}/_request",
+            "group_id_post_request",
+            MembershipRequest,
+            String
+        ),
+        endpoint!(
+            "GET",
+            "/v1/group/{id}/_attr/{attr}",
+            "group_id_get_attr",
+            Option<Vec<String>>
+        ),
+        endpoint!(
+            "POST",
+            "/v1/group/{id}/_attr/{attr}",
+            "group_id_post_attr",
+            Vec<String>,
+            ()
+        ),
+        endpoint!(
+            "PUT",
+            "/v1/group/{id}/_attr/{attr}",
+            "group_id_put_attr",
+            Vec<String>,
+            ()
+        ),
+        endpoint!(
+            "DELETE",
+            "/v1/group/{id}/_attr/{attr}",
+            "group_id_delete_attr",
+            ()
+        ),
+        endpoint!(
+            "POST",
+            "/v1/group/{id}/_unix",
+            "group_post_id_unix",
+            GroupUnixExtend,
+            ()
+        ),
+        endpoint!(
+            "GET",
+            "/v1/group/{id}/_unix/_token",
+            "group_get_id_unix_token",
+            UnixGroupToken
+        ),
+        endpoint!("GET", "/v1/savedsearch", "savedsearch_get", Vec<Entry>),
+        endpoint!("POST", "/v1/savedsearch", "savedsearch_post", Entry, ()),
+        endpoint!(
+            "GET",
+            "/v1/savedsearch/{id}",
+            "savedsearch_id_get",
+            Option<Entry>
+        ),
+        endpoint!(
+            "DELETE",
+            "/v1/savedsearch/{id}",
+            "savedsearch_id_delete",
+            ()
+        ),
+        endpoint!(
+            "PUT",
+            "/v1/savedsearch/{id}/_attr/{attr}",
+            "savedsearch_id_put_attr",
+            Vec<String>,
+            ()
+        ),
+        endpoint!(
+            "POST",
+            "/v1/savedsearch/{id}/_search",
+            "savedsearch_id_post_search",
+            SavedSearchRequest,
+            SearchResponse
+        ),
+        endpoint!(
+            "GET",
+            "/v1/savedsearch/{id}/_report",
+            "savedsearch_id_get_report",
+            Vec<ReportRecord>
+        ),
+        endpoint!(
+            "GET",
+            "/v1/membershiprequest",
+            "membershiprequest_get",
+            Vec<MembershipRequestRecord>
+        ),
+        endpoint!(
+            "POST",
+            "/v1/membershiprequest/{id}/_approve",
+            "membershiprequest_id_post_approve",
+            (),
+            ()
+        ),
+        endpoint!(
+            "POST",
+            "/v1/membershiprequest/{id}/_reject",
+            "membershiprequest_id_post_reject",
+            (),
+            ()
+        ),
+    ]
+}
+
+fn json_content(schema: Schema) -> Value {
+    json!({
+        "application/json": {
+            "schema": schema
+        }
+    })
+}
+
+// An openapi 3 document of endpoints(), with every v1 type they mention in
+// components/schemas.
+pub fn spec() -> Value {
+    let mut gen = SchemaSettings::openapi3().into_generator();
+    let mut paths: BTreeMap<&str, Map<String, Value>> = BTreeMap::new();
+
+    for ep in endpoints() {
+        let mut op = Map::new();
+        op.insert("operationId".to_string(), json!(ep.operation));
+
+        let params: Vec<Value> = ep
+            .path_params()
+            .into_iter()
+            .map(|p| {
+                json!({
+                    "name": p,
+                    "in": "path",
+                    "required": true,
+                    "schema": { "type": "string" }
+                })
+            })
+            .collect();
+        if !params.is_empty() {
+            op.insert("parameters".to_string(), Value::Array(params));
+        }
+
+        if let Some(req) = &ep.request {
+            op.insert(
+                "requestBody".to_string(),
+                json!({
+                    "required": true,
+                    "content": json_content((req.schema)(&mut gen))
+                }),
+            );
+        }
+
+        // Errors come back as an OperationError where the server could say why.
+        op.insert(
+            "responses".to_string(),
+            json!({
+                "200": {
+                    "description": "Success",
+                    "content": json_content((ep.response.schema)(&mut gen))
+                },
+                "default": {
+                    "description": "Failure",
+                    "content": json_content(gen.subschema_for::<OperationError>())
+                }
+            }),
+        );
+
+        paths
+            .entry(ep.path)
+            .or_default()
+            .insert(ep.method.to_lowercase(), Value::Object(op));
+    }
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "kanidm",
+            "version": env!("CARGO_PKG_VERSION")
+        },
+        "paths": paths,
+        "components": {
+            "schemas": gen.definitions()
+        }
+    })
+}
+
+// rustfmt's default max_width.
+const MAX_WIDTH: usize = 100;
+
+// Rust source for an async client of endpoints(), built on reqwest's async
+// client. It's a module of kanidm_client, so it shares ClientError.
+pub fn rust_client() -> String {
+    let mut src = String::new();
+    src.push_str(
+        "// Generated by kanidm_proto::openapi::rust_client, do not edit by hand.
+// See kanidm_proto/src/openapi.rs for how to regenerate this.
+
+use crate::ClientError;
+use futures::{Future, Stream};
+use kanidm_proto::v1::*;
+use reqwest::r#async::{Client, RequestBuilder};
+use serde::de::DeserializeOwned;
+
+pub type ClientFuture<T> = Box<dyn Future<Item = T, Error = ClientError> + Send>;
+
+fn perform<T: DeserializeOwned + Send + 'static>(req: RequestBuilder) -> ClientFuture<T> {
+    Box::new(
+        req.send()
+            .and_then(|response| {
+                let status = response.status();
+                response
+                    .into_body()
+                    .concat2()
+                    .map(move |body| (status, body))
+            })
+            .map_err(ClientError::Transport)
+            .and_then(|(status, body)| match status {
+                reqwest::StatusCode::OK => {
+                    serde_json::from_slice(&body).map_err(|_| ClientError::JsonParse)
+                }
+                unexpect => Err(ClientError::Http(
+                    unexpect,
+                    serde_json::from_slice(&body).ok(),
+                )),
+            }),
+    )
+}
+
+#[derive(Debug, Clone)]
+pub struct AsyncKanidmClient {
+    client: Client,
+    addr: String,
+}
+
+impl AsyncKanidmClient {
+    // The client should have a cookie store, as auth is tracked by the session cookie.
+    pub fn new(client: Client, addr: &str) -> Self {
+        AsyncKanidmClient {
+            client: client,
+            addr: addr.to_string(),
+        }
+    }
+",
+    );
+
+    for ep in endpoints() {
+        let params = ep.path_params();
+        let mut args: Vec<String> = params.iter().map(|p| format!("{}: &str", p)).collect();
+        if let Some(req) = &ep.request {
+            args.push(format!("request: &{}", req.name));
+        }
+
+        let mut dest_args = String::new();
+        let mut path = ep.path.to_string();
+        for p in params.iter() {
+            path = path.replace(format!("{{{}}}", p).as_str(), "{}");
+            dest_args.push_str(format!(", {}", p).as_str());
+        }
+
+        // Wrapped as rustfmt would, so formatting the client doesn't change it.
+        src.push_str("\n");
+        let sig = format!(
+            "    pub fn {}(&self{}) -> ClientFuture<{}> {{\n",
+            ep.operation,
+            args.iter().map(|a| format!(", {}", a)).collect::<String>(),
+            ep.response.name
+        );
+        if sig.len() <= MAX_WIDTH + 1 {
+            src.push_str(sig.as_str());
+        } else {
+            src.push_str(format!("    pub fn {}(\n        &self,\n", ep.operation).as_str());
+            for a in args.iter() {
+                src.push_str(format!("        {},\n", a).as_str());
+            }
+            src.push_str(format!("    ) -> ClientFuture<{}> {{\n", ep.response.name).as_str());
+        }
+
+        let dest = format!(
+            "        let dest = format!(\"{{}}{}\", self.addr{});\n",
+            path, dest_args
+        );
+        if dest.len() <= MAX_WIDTH + 1 {
+            src.push_str(dest.as_str());
+        } else {
+            src.push_str(
+                format!(
+                    "        let dest = format!(\n            \"{{}}{}\",\n            self.addr{}\n        );\n",
+                    path, dest_args
+                )
+                .as_str(),
+            );
+        }
+        let body = if ep.request.is_some() {
+            ".json(request)"
+        } else {
+            ""
+        };
+        src.push_str(
+            format!(
+                "        perform(self.client.{}(dest.as_str()){})\n",
+                ep.method.to_lowercase(),
+                body
+            )
+            .as_str(),
+        );
+        src.push_str("    }\n");
+    }
+
+    src.push_str("}\n");
+    src
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::openapi::{endpoints, rust_client, spec};
+    use std::collections::BTreeSet;
+
+    #[test]
+    fn test_openapi_endpoints_unique() {
+        let mut ops = BTreeSet::new();
+        let mut routes = BTreeSet::new();
+        for ep in endpoints() {
+            assert!(ops.insert(ep.operation), "duplicate {}", ep.operation);
+            assert!(
+                routes.insert((ep.method, ep.path)),
+                "duplicate {} {}",
+                ep.method,
+                ep.path
+            );
+        }
+    }
+
+    #[test]
+    fn test_openapi_spec_in_sync() {
+        let current: serde_json::Value =
+            serde_json::from_str(include_str!("../openapi.json")).expect("invalid openapi.json");
+        assert!(
+            current == spec(),
+            "kanidm_proto/openapi.json is out of date, see openapi.rs to regenerate it"
+        );
+    }
+
+    #[test]
+    fn test_openapi_client_in_sync() {
+        assert!(
+            include_str!("../../kanidm_client/src/asynchronous.rs") == rust_client(),
+            "kanidm_client/src/asynchronous.rs is out of date, see openapi.rs to regenerate it"
+        );
+    }
+}
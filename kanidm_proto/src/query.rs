@@ -0,0 +1,345 @@
+// A textual filter, written as LDAP filters are (RFC4515), so that admins
+// don't have to write the json tree by hand. It's also the form the access
+// log shows filters in, so a filter can be copied from there.
+//
+//   (attr=value)    Eq          (attr=*)        Pres
+//   (attr=*value*)  Sub         (attr~=value)   Fuzzy
+//   (attr>=value)   Ge          (attr<=value)   Le
+//   (attr=~regex)   Regex       (self)          Self
+//   (&(..)(..))     And         (|(..)(..))     Or
+//   (!(..))         AndNot
+//
+// In values ( ) * and \ are escaped as \28 \29 \2a \5c, or by a backslash in
+// front of them. A regex is taken as written up to the ) closing the term, so
+// its parentheses only need to balance. A single term may leave off its
+// parentheses, IE name=william.
+
+use crate::v1::Filter;
+use std::fmt;
+
+// Where the text stopped making sense, in chars from the start.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueryError {
+    pub pos: usize,
+    pub msg: String,
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at position {}", self.msg, self.pos)
+    }
+}
+
+// The offset in the value and position in the text of each unescaped *.
+type Stars = Vec<(usize, usize)>;
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn err<T>(&self, pos: usize, msg: &str) -> Result<T, QueryError> {
+        Err(QueryError {
+            pos: pos,
+            msg: msg.to_string(),
+        })
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).cloned()
+    }
+
+    fn peek_at(&self, n: usize) -> Option<char> {
+        self.chars.get(self.pos + n).cloned()
+    }
+
+    fn skip_ws(&mut self) {
+        while self.peek().map(|c| c.is_whitespace()).unwrap_or(false) {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), QueryError> {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            self.err(self.pos, format!("expected '{}'", c).as_str())
+        }
+    }
+
+    fn filter(&mut self) -> Result<Filter, QueryError> {
+        self.expect('(')?;
+        let f = match self.peek() {
+            Some('&') => {
+                self.pos += 1;
+                Filter::And(self.list()?)
+            }
+            Some('|') => {
+                self.pos += 1;
+                Filter::Or(self.list()?)
+            }
+            Some('!') => {
+                self.pos += 1;
+                self.skip_ws();
+                let f = self.filter()?;
+                self.skip_ws();
+                Filter::AndNot(Box::new(f))
+            }
+            _ => self.term()?,
+        };
+        self.expect(')')?;
+        Ok(f)
+    }
+
+    fn list(&mut self) -> Result<Vec<Filter>, QueryError> {
+        let mut l = Vec::new();
+        self.skip_ws();
+        while self.peek() == Some('(') {
+            l.push(self.filter()?);
+            self.skip_ws();
+        }
+        if l.is_empty() {
+            self.err(self.pos, "expected a filter")
+        } else {
+            Ok(l)
+        }
+    }
+
+    fn term(&mut self) -> Result<Filter, QueryError> {
+        let start = self.pos;
+        while self
+            .peek()
+            .map(|c| c.is_alphanumeric() || c == '_' || c == '-' || c == '.')
+            .unwrap_or(false)
+        {
+            self.pos += 1;
+        }
+        if self.pos == start {
+            return self.err(self.pos, "expected an attribute name");
+        }
+        let attr: String = self.chars[start..self.pos].iter().collect();
+        if attr == "self" && (self.peek() == Some(')') || self.peek().is_none()) {
+            return Ok(Filter::SelfUUID);
+        }
+
+        let op_pos = self.pos;
+        let op = match (self.peek(), self.peek_at(1)) {
+            (Some('~'), Some('=')) => "~=",
+            (Some('>'), Some('=')) => ">=",
+            (Some('<'), Some('=')) => "<=",
+            (Some('='), Some('~')) => "=~",
+            (Some('='), _) => "=",
+            _ => return self.err(op_pos, "expected one of = ~= >= <= =~"),
+        };
+        self.pos += op.len();
+
+        if op == "=~" {
+            let re = self.regex()?;
+            if re.is_empty() {
+                return self.err(self.pos, "expected a value");
+            }
+            return Ok(Filter::Regex(attr, re));
+        }
+
+        let value_pos = self.pos;
+        let (v, stars) = self.value()?;
+        if v.is_empty() {
+            return self.err(self.pos, "expected a value");
+        }
+        let last = v.len() - 1;
+        match (op, stars.as_slice()) {
+            (_, []) => {}
+            ("=", [(0, _)]) if last == 0 => return Ok(Filter::Pres(attr)),
+            ("=", [(0, _), (l, _)]) if *l == last && last > 1 => {
+                return match String::from_utf8(v[1..last].to_vec()) {
+                    Ok(s) => Ok(Filter::Sub(attr, s)),
+                    Err(_) => self.err(value_pos, "value is not valid utf8"),
+                };
+            }
+            ("=", _) => {
+                let (_, p) = stars
+                    .iter()
+                    .find(|(i, _)| *i != 0 && *i != last)
+                    .unwrap_or(&stars[0]);
+                return self.err(*p, "only (attr=*value*) substrings are supported");
+            }
+            (_, _) => return self.err(stars[0].1, "* is only allowed with ="),
+        };
+        let v = match String::from_utf8(v) {
+            Ok(v) => v,
+            Err(_) => return self.err(value_pos, "value is not valid utf8"),
+        };
+        Ok(match op {
+            "~=" => Filter::Fuzzy(attr, v),
+            ">=" => Filter::Ge(attr, v),
+            "<=" => Filter::Le(attr, v),
+            _ => Filter::Eq(attr, v),
+        })
+    }
+
+    // The value as bytes, as hex escapes may make up a multibyte char.
+    fn value(&mut self) -> Result<(Vec<u8>, Stars), QueryError> {
+        let mut v = Vec::new();
+        let mut stars = Vec::new();
+        loop {
+            match self.peek() {
+                None | Some(')') => break,
+                Some('(') => return self.err(self.pos, "( in a value must be escaped"),
+                Some('*') => {
+                    stars.push((v.len(), self.pos));
+                    v.push(b'*');
+                    self.pos += 1;
+                }
+                Some('\\') => {
+                    let hex: String = self.chars[self.pos + 1..].iter().take(2).collect();
+                    let is_hex = hex.len() == 2 && hex.chars().all(|c| c.is_ascii_hexdigit());
+                    match (self.peek_at(1), u8::from_str_radix(hex.as_str(), 16)) {
+                        (_, Ok(b)) if is_hex => {
+                            v.push(b);
+                            self.pos += 3;
+                        }
+                        (Some(c), _) if c == '(' || c == ')' || c == '*' || c == '\\' => {
+                            v.push(c as u8);
+                            self.pos += 2;
+                        }
+                        _ => return self.err(self.pos, "invalid escape"),
+                    }
+                }
+                Some(c) => {
+                    let mut buf = [0; 4];
+                    v.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                    self.pos += 1;
+                }
+            }
+        }
+        Ok((v, stars))
+    }
+
+    fn regex(&mut self) -> Result<String, QueryError> {
+        let start = self.pos;
+        let mut depth = 0;
+        loop {
+            match self.peek() {
+                None => break,
+                Some('\\') => self.pos += 1,
+                Some('(') => depth += 1,
+                Some(')') if depth == 0 => break,
+                Some(')') => depth -= 1,
+                Some(_) => {}
+            }
+            self.pos += 1;
+        }
+        if self.pos > self.chars.len() {
+            return self.err(self.chars.len(), "regex ends with an escape");
+        }
+        Ok(self.chars[start..self.pos].iter().collect())
+    }
+}
+
+pub fn parse_filter(s: &str) -> Result<Filter, QueryError> {
+    let mut p = Parser {
+        chars: s.chars().collect(),
+        pos: 0,
+    };
+    p.skip_ws();
+    let f = if p.peek() == Some('(') {
+        p.filter()?
+    } else {
+        p.term()?
+    };
+    p.skip_ws();
+    match p.peek() {
+        None => Ok(f),
+        Some(_) => p.err(p.pos, "unexpected text after the filter"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_filter, QueryError};
+    use crate::v1::Filter;
+
+    fn s(v: &str) -> String {
+        v.to_string()
+    }
+
+    fn err(pos: usize, msg: &str) -> Result<Filter, QueryError> {
+        Err(QueryError {
+            pos: pos,
+            msg: s(msg),
+        })
+    }
+
+    #[test]
+    fn test_query_parse() {
+        assert_eq!(
+            parse_filter("name=william"),
+            Ok(Filter::Eq(s("name"), s("william")))
+        );
+        assert_eq!(
+            parse_filter(
+                "(& (class=person) (|(name=*will*)(displayname~=jon smyth)) (!(memberof=*)))"
+            ),
+            Ok(Filter::And(vec![
+                Filter::Eq(s("class"), s("person")),
+                Filter::Or(vec![
+                    Filter::Sub(s("name"), s("will")),
+                    Filter::Fuzzy(s("displayname"), s("jon smyth")),
+                ]),
+                Filter::AndNot(Box::new(Filter::Pres(s("memberof")))),
+            ]))
+        );
+        assert_eq!(
+            parse_filter("(|(gidnumber>=1000)(gidnumber<=10)(self))"),
+            Ok(Filter::Or(vec![
+                Filter::Ge(s("gidnumber"), s("1000")),
+                Filter::Le(s("gidnumber"), s("10")),
+                Filter::SelfUUID,
+            ]))
+        );
+        // Escapes, including a multibyte char as hex.
+        assert_eq!(
+            parse_filter(r"(description=\28a\2a\29 \\ \c3\bc)"),
+            Ok(Filter::Eq(s("description"), s("(a*) \\ ü")))
+        );
+        // A regex is as written, with balanced parentheses.
+        assert_eq!(
+            parse_filter(r"(name=~^(will|claire)\d+$)"),
+            Ok(Filter::Regex(s("name"), s(r"^(will|claire)\d+$")))
+        );
+    }
+
+    #[test]
+    fn test_query_parse_errors() {
+        assert_eq!(parse_filter("(name=william"), err(13, "expected ')'"));
+        assert_eq!(parse_filter("(&)"), err(2, "expected a filter"));
+        assert_eq!(
+            parse_filter("(=william)"),
+            err(1, "expected an attribute name")
+        );
+        assert_eq!(
+            parse_filter("(name!william)"),
+            err(5, "expected one of = ~= >= <= =~")
+        );
+        assert_eq!(parse_filter("(name=)"), err(6, "expected a value"));
+        assert_eq!(
+            parse_filter("(name=wi*am)"),
+            err(8, "only (attr=*value*) substrings are supported")
+        );
+        assert_eq!(
+            parse_filter("(name>=*)"),
+            err(7, "* is only allowed with =")
+        );
+        assert_eq!(
+            parse_filter("(name=a(b)"),
+            err(7, "( in a value must be escaped")
+        );
+        assert_eq!(parse_filter(r"(name=\zz)"), err(6, "invalid escape"));
+        assert_eq!(
+            parse_filter("(name=a))"),
+            err(8, "unexpected text after the filter")
+        );
+    }
+}
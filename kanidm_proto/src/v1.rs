@@ -2,11 +2,15 @@ use std::collections::BTreeMap;
 use std::fmt;
 use uuid::Uuid;
 
+#[cfg(feature = "openapi")]
+use schemars::JsonSchema;
+
 // These proto implementations are here because they have public definitions
 
 /* ===== errors ===== */
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
 pub enum SchemaError {
     NotImplemented,
     InvalidClass,
@@ -18,6 +22,7 @@ pub enum SchemaError {
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
 pub enum PluginError {
     AttrUnique(String),
     Base(String),
@@ -25,6 +30,7 @@ pub enum PluginError {
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
 pub enum OperationError {
     EmptyRequest,
     Backend,
@@ -57,9 +63,39 @@ pub enum OperationError {
     InvalidSessionState,
     SystemProtectedObject,
     SystemProtectedAttribute,
+    ResourceLimit,
+    // Seconds until the request may be retried.
+    RateLimited(u64),
+    DatabaseLocked,
+    // An admin cancelled the operation while it was running.
+    Cancelled,
+    // A bug, the server log has the detail.
+    Panicked,
+    // The database is too busy to start a transaction, try again shortly.
+    Busy,
+    // The database can't be reached at all.
+    Unavailable,
+    // An admin has put the server in read only mode, try again later.
+    ReadOnly,
+    // This server is a read replica, so writes must be sent to its primary
+    // at this url instead.
+    ReadReplica(String),
+    // A filter query that didn't parse - where it went wrong, and why.
+    InvalidFilterQuery(usize, String),
+    // A saved search was run without a value for this parameter.
+    SavedSearchParamMissing(String),
+    // The change is sensitive, and the session must authenticate again first.
+    ElevationRequired,
+    // The paged search cursor has expired, or isn't for this search - it must
+    // be started again.
+    InvalidSearchCursor,
+    // An OAuth2 request was refused, with the error code from rfc6749 that
+    // the relying party is sent.
+    Oauth2(String),
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
 pub enum ConsistencyError {
     Unknown,
     // Class, Attribute
@@ -72,6 +108,15 @@ pub enum ConsistencyError {
     MemberOfInvalid(u64),
     InvalidAttributeType(String),
     DuplicateUniqueAttribute(String),
+    CorruptedEntry(u64),
+    // Attr, index type, key, id
+    IndexMissingId(String, String, String, u64),
+    IndexExtraId(String, String, String, u64),
+    IndexDanglingId(String, String, String, u64),
+    // Attr, index type
+    IndexUnreadable(String, String),
+    // What sqlite's integrity check reported.
+    SqliteIntegrityFailure(String),
 }
 
 /* ===== higher level types ===== */
@@ -81,12 +126,14 @@ pub enum ConsistencyError {
 // entry/ava/filter types. These related deeply to schema.
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
 pub struct Group {
     pub name: String,
     pub uuid: String,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
 pub struct Claim {
     pub name: String,
     pub uuid: String,
@@ -96,6 +143,7 @@ pub struct Claim {
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
 pub struct Application {
     pub name: String,
     pub uuid: String,
@@ -113,6 +161,7 @@ pub struct Application {
 // It's likely that this must have a relationship to the server's user structure
 // and to the Entry so that filters or access controls can be applied.
 #[derive(Debug, Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
 pub struct UserAuthToken {
     // When this data should be considered invalid. Interpretation
     // may depend on the client application.
@@ -123,16 +172,27 @@ pub struct UserAuthToken {
     pub application: Option<Application>,
     pub groups: Vec<Group>,
     pub claims: Vec<Claim>,
+    // Until when, in seconds since the epoch, this session may make sensitive
+    // changes. Authenticating again extends it.
+    #[serde(default)]
+    pub elevated_until: Option<u64>,
     // Should we allow supplemental ava's to be added on request?
 }
 
+impl UserAuthToken {
+    pub fn is_elevated(&self, now: u64) -> bool {
+        self.elevated_until.map(|t| now < t).unwrap_or(false)
+    }
+}
+
 impl fmt::Display for UserAuthToken {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         writeln!(f, "name: {}", self.name)?;
         writeln!(f, "display: {}", self.displayname)?;
         writeln!(f, "uuid: {}", self.uuid)?;
         writeln!(f, "groups: {:?}", self.groups)?;
-        writeln!(f, "claims: {:?}", self.claims)
+        writeln!(f, "claims: {:?}", self.claims)?;
+        writeln!(f, "elevated until: {:?}", self.elevated_until)
     }
 }
 
@@ -142,12 +202,17 @@ impl fmt::Display for UserAuthToken {
 // This is similar to uat, but omits claims (they have no role in radius), and adds
 // the radius secret field.
 #[derive(Debug, Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
 pub struct RadiusAuthToken {
     pub name: String,
     pub displayname: String,
     pub uuid: String,
     pub secret: String,
     pub groups: Vec<Group>,
+    // From the radius_vlan of their groups, None to leave it to the RADIUS
+    // server's own configuration.
+    #[serde(default)]
+    pub vlan: Option<u32>,
 }
 
 impl fmt::Display for RadiusAuthToken {
@@ -156,7 +221,52 @@ impl fmt::Display for RadiusAuthToken {
         writeln!(f, "display: {}", self.displayname)?;
         writeln!(f, "uuid: {}", self.uuid)?;
         writeln!(f, "secret: {}", self.secret)?;
-        writeln!(f, "groups: {:?}", self.groups)
+        writeln!(f, "groups: {:?}", self.groups)?;
+        writeln!(f, "vlan: {:?}", self.vlan)
+    }
+}
+
+// What a unix client needs to resolve and log in a posix account. The uid is
+// the same as the gidnumber, as each account is its own user private group.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
+pub struct UnixGroupToken {
+    pub name: String,
+    pub uuid: String,
+    pub gidnumber: u32,
+}
+
+impl fmt::Display for UnixGroupToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "name: {}", self.name)?;
+        writeln!(f, "uuid: {}", self.uuid)?;
+        writeln!(f, "gidnumber: {}", self.gidnumber)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
+pub struct UnixUserToken {
+    pub name: String,
+    pub displayname: String,
+    pub uuid: String,
+    pub gidnumber: u32,
+    pub shell: Option<String>,
+    pub home_directory: Option<String>,
+    // The user private group is always first.
+    pub groups: Vec<UnixGroupToken>,
+}
+
+impl fmt::Display for UnixUserToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "name: {}", self.name)?;
+        writeln!(f, "display: {}", self.displayname)?;
+        writeln!(f, "uuid: {}", self.uuid)?;
+        writeln!(f, "gidnumber: {}", self.gidnumber)?;
+        writeln!(f, "shell: {:?}", self.shell)?;
+        writeln!(f, "home_directory: {:?}", self.home_directory)?;
+        let names: Vec<&str> = self.groups.iter().map(|g| g.name.as_str()).collect();
+        writeln!(f, "groups: {:?}", names)
     }
 }
 
@@ -168,15 +278,24 @@ impl fmt::Display for RadiusAuthToken {
 //
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
 pub struct Entry {
     pub attrs: BTreeMap<String, Vec<String>>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, Ord, PartialOrd, Eq, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
 pub enum Filter {
     // This is attr - value
     Eq(String, String),
     Sub(String, String),
+    // Some value of attr has a word sounding like each word of the value.
+    Fuzzy(String, String),
+    // attr >= value and attr <= value, compared as the values' index keys.
+    Ge(String, String),
+    Le(String, String),
+    // attr matches the regex, which the server limits in size and nesting.
+    Regex(String, String),
     Pres(String),
     Or(Vec<Filter>),
     And(Vec<Filter>),
@@ -186,6 +305,7 @@ pub enum Filter {
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
 pub enum Modify {
     Present(String, String),
     Removed(String, String),
@@ -193,6 +313,7 @@ pub enum Modify {
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
 pub struct ModifyList {
     pub mods: Vec<Modify>,
 }
@@ -204,6 +325,7 @@ impl ModifyList {
 }
 
 #[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
 pub struct OperationResponse {}
 
 impl OperationResponse {
@@ -213,28 +335,516 @@ impl OperationResponse {
 }
 
 #[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
 pub struct SearchRequest {
     pub filter: Filter,
+    // Return at most this many entries, with a cursor to ask for the next
+    // page by. Unpaged if not set.
+    #[serde(default)]
+    pub page_size: Option<u32>,
+    // The next_cursor of the previous page, sent with the same filter.
+    #[serde(default)]
+    pub cursor: Option<String>,
+    // Otherwise the entries come back in no order to rely on.
+    #[serde(default)]
+    pub sort: Option<SearchSort>,
+    // Only return these attributes of each entry, rather than all that may be
+    // read. The attribute sorted by is always returned.
+    #[serde(default)]
+    pub attrs: Option<Vec<String>>,
+}
+
+// Entries without the attribute come last, in either direction.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
+pub struct SearchSort {
+    pub attr: String,
+    #[serde(default)]
+    pub descending: bool,
 }
 
 impl SearchRequest {
     pub fn new(filter: Filter) -> Self {
-        SearchRequest { filter: filter }
+        SearchRequest {
+            filter: filter,
+            page_size: None,
+            cursor: None,
+            sort: None,
+            attrs: None,
+        }
+    }
+
+    pub fn new_paged(filter: Filter, page_size: u32, cursor: Option<String>) -> Self {
+        SearchRequest {
+            filter: filter,
+            page_size: Some(page_size),
+            cursor: cursor,
+            sort: None,
+            attrs: None,
+        }
+    }
+
+    pub fn sorted_by(mut self, attr: &str, descending: bool) -> Self {
+        self.sort = Some(SearchSort {
+            attr: attr.to_string(),
+            descending: descending,
+        });
+        self
+    }
+
+    pub fn with_attrs(mut self, attrs: &[&str]) -> Self {
+        self.attrs = Some(attrs.iter().map(|a| a.to_string()).collect());
+        self
+    }
+}
+
+// As SearchRequest, with the filter written as text. See query.rs.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
+pub struct SearchQueryRequest {
+    pub query: String,
+}
+
+impl SearchQueryRequest {
+    pub fn new(query: String) -> Self {
+        SearchQueryRequest { query: query }
+    }
+}
+
+// Run the saved search named in the path, with these values for the ${param}
+// placeholders in its filter.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
+pub struct SavedSearchRequest {
+    pub params: BTreeMap<String, String>,
+}
+
+impl SavedSearchRequest {
+    pub fn new(params: BTreeMap<String, String>) -> Self {
+        SavedSearchRequest { params: params }
     }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
 pub struct SearchResponse {
     pub entries: Vec<Entry>,
+    // Only for paged searches, when there are more pages.
+    #[serde(default)]
+    pub next_cursor: Option<String>,
 }
 
 impl SearchResponse {
     pub fn new(entries: Vec<Entry>) -> Self {
-        SearchResponse { entries: entries }
+        SearchResponse {
+            entries: entries,
+            next_cursor: None,
+        }
     }
 }
 
+// Assert that the entry named by target (a uuid or name) holds this value
+// of attr. Like an ldap compare, this answers true or false without
+// returning the entry.
 #[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
+pub struct CompareRequest {
+    pub target: String,
+    pub attr: String,
+    pub value: String,
+}
+
+impl CompareRequest {
+    pub fn new(target: String, attr: String, value: String) -> Self {
+        CompareRequest {
+            target: target,
+            attr: attr,
+            value: value,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
+pub struct CompareResponse {
+    pub result: bool,
+}
+
+impl CompareResponse {
+    pub fn new(result: bool) -> Self {
+        CompareResponse { result: result }
+    }
+}
+
+// Poll for what changed since an earlier poll. The cookie is opaque - send
+// back the one from the last response, or none to fetch everything.
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
+pub struct ChangesRequest {
+    pub cookie: Option<String>,
+}
+
+impl ChangesRequest {
+    pub fn new(cookie: Option<String>) -> Self {
+        ChangesRequest { cookie: cookie }
+    }
+}
+
+// Entries that were added or changed, and the uuids of entries that were
+// deleted, since the request's cookie.
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
+pub struct ChangesResponse {
+    pub entries: Vec<Entry>,
+    pub deleted: Vec<String>,
+    pub cookie: String,
+}
+
+impl ChangesResponse {
+    pub fn new(entries: Vec<Entry>, deleted: Vec<String>, cookie: String) -> Self {
+        ChangesResponse {
+            entries: entries,
+            deleted: deleted,
+            cookie: cookie,
+        }
+    }
+}
+
+// What a replication partner asks for - every entry changed since its cookie,
+// with nothing hidden by access controls.
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
+pub struct ReplSupplyRequest {
+    pub cookie: Option<String>,
+}
+
+impl ReplSupplyRequest {
+    pub fn new(cookie: Option<String>) -> Self {
+        ReplSupplyRequest { cookie: cookie }
+    }
+}
+
+// An entry as the supplier stores it, which only another kanidmd can make
+// sense of, and the change id (CID) of each of its attributes. An attribute
+// with a CID but no values was last changed by removing them.
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
+pub struct ReplEntry {
+    pub entry: String,
+    pub cids: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
+pub struct ReplSupplyResponse {
+    pub entries: Vec<ReplEntry>,
+    pub cookie: String,
+}
+
+impl ReplSupplyResponse {
+    pub fn new(entries: Vec<ReplEntry>, cookie: String) -> Self {
+        ReplSupplyResponse {
+            entries: entries,
+            cookie: cookie,
+        }
+    }
+}
+
+// Rather than polling, be sent each change to the entries filter matches as
+// it happens.
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
+pub struct PersistentSearchRequest {
+    pub filter: Filter,
+}
+
+impl PersistentSearchRequest {
+    pub fn new(filter: Filter) -> Self {
+        PersistentSearchRequest { filter: filter }
+    }
+}
+
+// What a persistent search is sent, one to a line. It begins with an Add for
+// each entry the filter matches, then one of these for each change after.
+// A change may be sent twice around when the search begins.
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
+pub enum PersistentSearchNotice {
+    // The entry has come to match, IE was created.
+    Add(Entry),
+    // The entry still matches, but has changed.
+    Modify(Entry),
+    // The entry with this uuid no longer matches, was deleted, or may no
+    // longer be seen.
+    Delete(String),
+    // The search has ended, and won't be sent anything more.
+    End(OperationError),
+}
+
+// An operation the server is running right now, for the admin listing.
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
+pub struct ActiveOperation {
+    pub id: u64,
+    pub operation: String,
+    pub identity: String,
+    pub elapsed_ms: u64,
+    // What the backend was last doing, IE resolving indexes or testing the filter.
+    pub phase: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
+pub struct IdentityOperationCount {
+    pub identity: String,
+    pub count: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
+pub struct OperationsResponse {
+    pub active: Vec<ActiveOperation>,
+    // How many operations each identity has run over the last few minutes.
+    pub recent: Vec<IdentityOperationCount>,
+}
+
+impl OperationsResponse {
+    pub fn new(active: Vec<ActiveOperation>, recent: Vec<IdentityOperationCount>) -> Self {
+        OperationsResponse {
+            active: active,
+            recent: recent,
+        }
+    }
+}
+
+// How the backend was able to resolve a filter term to a set of candidate ids.
+// Partial and allids mean the candidates must be tested against the filter.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
+pub enum ExplainIdl {
+    Indexed(usize),
+    Partial(usize),
+    AllIds,
+}
+
+// Why resolving the terms of an and or an or stopped before reaching them all.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
+pub enum ExplainShortcut {
+    // A term of the or was unindexed, so the whole or is.
+    OrAllIds,
+    // The candidates of the and fell below the filter test threshold, and
+    // the entry test is left to do the rest.
+    AndThreshold,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
+pub struct ExplainTerm {
+    // Depth in the filter tree, 0 is the root term.
+    pub depth: usize,
+    pub term: String,
+    pub idl: ExplainIdl,
+    pub shortcut: Option<ExplainShortcut>,
+    // The terms left unresolved by the shortcut.
+    pub skipped: usize,
+}
+
+// The terms are in the order the backend resolved them, so this shows the
+// order the terms of an and were chosen in, and where resolution stopped.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
+pub struct SearchExplain {
+    // The filter after resolution and optimisation.
+    pub filter: String,
+    pub terms: Vec<ExplainTerm>,
+    pub result: ExplainIdl,
+    pub threshold: usize,
+    // Entries loaded from the database, then those that matched.
+    pub candidates: usize,
+    pub filter_test: bool,
+    pub matched: usize,
+}
+
+// What the server has recorded about an index. An attribute that is searched
+// on without an index is listed too, with no keys, so that the allids count
+// shows what is worth indexing.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
+pub struct IndexStat {
+    pub attr: String,
+    pub itype: String,
+    pub keys: u64,
+    // Of the number of ids per key.
+    pub avg_idl: f64,
+    pub max_idl: u64,
+    // Searches that had to test every entry for want of this index.
+    pub allids: u64,
+}
+
+// How many entries hold an attribute, for deciding what in schema is still
+// used. entries is None when the attribute has no presence index to count it.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
+pub struct AttrUsage {
+    pub attr: String,
+    pub entries: Option<u64>,
+}
+
+// A read of an entry marked access_journal. identity is the uuid of who read
+// it, and hash chains this record to the one before it.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
+pub struct AccessRecord {
+    pub id: u64,
+    pub time: String,
+    pub uuid: String,
+    pub identity: String,
+    pub operation: String,
+    pub hash: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
+pub struct AccessJournalResponse {
+    pub records: Vec<AccessRecord>,
+    // The id of the first record whose hash doesn't follow from those before
+    // it, if the journal has been tampered with.
+    pub broken_at: Option<u64>,
+}
+
+// A search that had to test every entry, or was slow. duration_ms is the
+// time spent in the backend, and candidates the entries it had to load.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
+pub struct SlowQueryRecord {
+    pub id: u64,
+    pub time: String,
+    pub filter: String,
+    pub duration_ms: u64,
+    pub candidates: u64,
+    pub results: u64,
+    pub allids: bool,
+}
+
+// How many of something happened, and for how long in total and at most.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
+pub struct TxnStats {
+    pub count: u64,
+    pub ms_total: u64,
+    pub ms_max: u64,
+}
+
+// The db connection pool as it is now, and since the server started. waits
+// is the time spent waiting for a connection, and exhausted how often none
+// could be had. Only write txns commit or roll back.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
+pub struct DbPoolStats {
+    pub max_size: u32,
+    pub connections: u32,
+    pub idle_connections: u32,
+    pub waits: TxnStats,
+    pub exhausted: u64,
+    pub read_txns: TxnStats,
+    pub write_txns: TxnStats,
+    pub commits: u64,
+    pub rollbacks: u64,
+}
+
+// What a write txn wrote, for spotting write amplification. idx_slots is
+// how many idls it rewrote, and idx_bytes their size, against the entries
+// and bytes written to id2entry.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
+pub struct WriteStatsRecord {
+    pub id: u64,
+    pub time: String,
+    pub entries: u64,
+    pub id2entry_bytes: u64,
+    pub idx_slots: u64,
+    pub idx_bytes: u64,
+    pub changelog_records: u64,
+}
+
+// What one of the scheduled purges has reaped since we started. pending is
+// set if its last run reached the batch limit, and left the rest for the next.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
+pub struct PurgeRunStats {
+    pub runs: u64,
+    pub reaped: u64,
+    pub pending: bool,
+    pub last_run: Option<String>,
+}
+
+// Recycled entries are made tombstones, and tombstones are deleted.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
+pub struct PurgeStats {
+    pub recycled: PurgeRunStats,
+    pub tombstones: PurgeRunStats,
+}
+
+// The size of the database file before and after a vacuum, in bytes.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
+pub struct VacuumResponse {
+    pub before: u64,
+    pub after: u64,
+}
+
+// One run of a scheduled report. allids is set if the saved search wasn't
+// fully indexed, so the report had to test every entry.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
+pub struct ReportRecord {
+    pub id: u64,
+    pub time: String,
+    pub count: u64,
+    pub allids: bool,
+}
+
+// What /status reports. online is whether the server can service requests,
+// and warnings lists anything that will stop it doing so before long, such as
+// the database nearing its size limit.
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
+pub struct StatusResponse {
+    pub online: bool,
+    // Writes are refused until an admin turns this off.
+    pub read_only: bool,
+    // Set if we're a read replica, to where writes are referred.
+    pub primary: Option<String>,
+    pub warnings: Vec<String>,
+}
+
+// The identity of the domain this server belongs to. The uuid is stable for
+// the life of the domain, and generation goes up whenever any of this
+// changes, so anything holding a copy can tell it's stale. created is in
+// seconds since the epoch.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
+pub struct DomainInfo {
+    pub uuid: String,
+    pub name: String,
+    pub generation: u64,
+    pub created: u64,
+}
+
+// How the domain presents itself, for drawing the login page before anyone
+// has authenticated. logo is a url or a path on the server.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
+pub struct Branding {
+    pub display_name: String,
+    pub logo: Option<String>,
+    pub login_banner: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
 pub struct CreateRequest {
     pub entries: Vec<Entry>,
 }
@@ -246,6 +856,7 @@ impl CreateRequest {
 }
 
 #[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
 pub struct DeleteRequest {
     pub filter: Filter,
 }
@@ -256,7 +867,95 @@ impl DeleteRequest {
     }
 }
 
+// An entry that refers to one being deleted, and by which attribute. The
+// reference is removed when the delete goes ahead.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
+pub struct ReferenceImpact {
+    pub uuid: String,
+    pub attr: String,
+}
+
+// What deleting an entry would break. Only what the caller can see is
+// included, so an empty report doesn't promise the delete is harmless.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
+pub struct DeleteImpact {
+    pub uuid: String,
+    pub name: Option<String>,
+    // Every entry that refers to this one.
+    pub references: Vec<ReferenceImpact>,
+    // The groups this is a direct member of.
+    pub memberships_lost: Vec<String>,
+    // Access profiles whose receiver or target scope names this.
+    pub access_profiles: Vec<String>,
+    // Authentications in progress for this account, which will fail.
+    pub auth_sessions: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
+pub struct DeletePreviewResponse {
+    pub entries: Vec<DeleteImpact>,
+}
+
+// Members to add to and remove from a group in one write. Each is a uuid or
+// name, and members already in the state asked for are skipped.
 #[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
+pub struct GroupMembersRequest {
+    pub add: Vec<String>,
+    pub remove: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
+pub struct GroupMembersResponse {
+    pub added: usize,
+    pub removed: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
+pub enum MembershipAction {
+    Add,
+    Remove,
+}
+
+// Ask for a member, given as a uuid or name, to be added to or removed from a
+// group. It's applied once one of the group's approvers agrees.
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
+pub struct MembershipRequest {
+    pub member: String,
+    pub action: MembershipAction,
+}
+
+impl MembershipRequest {
+    pub fn new(member: String, action: MembershipAction) -> Self {
+        MembershipRequest {
+            member: member,
+            action: action,
+        }
+    }
+}
+
+// A membership request as its requester and approvers see it. The group,
+// member and identities are names where they have one, else uuids.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
+pub struct MembershipRequestRecord {
+    pub uuid: String,
+    pub group: String,
+    pub member: String,
+    pub action: MembershipAction,
+    pub state: String,
+    pub requester: String,
+    pub approver: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
 pub struct ModifyRequest {
     // Probably needs a modlist?
     pub filter: Filter,
@@ -285,6 +984,7 @@ impl ModifyRequest {
 // On loginSuccess, we send a cookie, and that allows the token to be
 // generated. The cookie can be shared between servers.
 #[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
 pub enum AuthCredential {
     Anonymous,
     Password(String),
@@ -292,6 +992,7 @@ pub enum AuthCredential {
 }
 
 #[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
 pub enum AuthStep {
     // name, application id?
     Init(String, Option<String>),
@@ -307,6 +1008,7 @@ pub enum AuthStep {
 
 // Request auth for identity X with roles Y?
 #[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
 pub struct AuthRequest {
     pub step: AuthStep,
 }
@@ -314,6 +1016,7 @@ pub struct AuthRequest {
 // Respond with the list of auth types and nonce, etc.
 // It can also contain a denied, or success.
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
 pub enum AuthAllowed {
     Anonymous,
     Password,
@@ -321,6 +1024,7 @@ pub enum AuthAllowed {
 }
 
 #[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
 pub enum AuthState {
     // Everything is good, your cookie has been issued, and a token is set here
     // for the client to view.
@@ -332,13 +1036,16 @@ pub enum AuthState {
 }
 
 #[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
 pub struct AuthResponse {
+    #[cfg_attr(feature = "openapi", schemars(with = "String"))]
     pub sessionid: Uuid,
     pub state: AuthState,
 }
 
 // Types needed for setting credentials
 #[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
 pub enum SetAuthCredential {
     Password(String),
     GeneratePassword,
@@ -383,6 +1090,7 @@ impl WhoamiRequest {
 }
 
 #[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
 pub struct WhoamiResponse {
     // Should we just embed the entry? Or destructure it?
     pub youare: Entry,
@@ -400,6 +1108,7 @@ impl WhoamiResponse {
 
 // Simple string value provision.
 #[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
 pub struct SingleStringRequest {
     pub value: String,
 }
@@ -411,6 +1120,116 @@ impl SingleStringRequest {
 }
 // Use OperationResponse here ...
 
+// OAuth2 and OpenID Connect, for web applications registered as relying
+// parties. These are named as rfc6749, rfc7636 and OpenID Connect Core name
+// them, as they're what the relying party's library sends and expects.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
+pub struct Oauth2AuthoriseRequest {
+    pub response_type: String,
+    pub client_id: String,
+    pub redirect_uri: String,
+    // Space separated.
+    #[serde(default)]
+    pub scope: Option<String>,
+    #[serde(default)]
+    pub state: Option<String>,
+    #[serde(default)]
+    pub nonce: Option<String>,
+    // PKCE is required, and only S256.
+    #[serde(default)]
+    pub code_challenge: Option<String>,
+    #[serde(default)]
+    pub code_challenge_method: Option<String>,
+}
+
+// The client may authenticate with basic auth instead of the last two.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
+pub struct Oauth2TokenRequest {
+    pub grant_type: String,
+    pub code: String,
+    pub redirect_uri: String,
+    #[serde(default)]
+    pub code_verifier: Option<String>,
+    #[serde(default)]
+    pub client_id: Option<String>,
+    #[serde(default)]
+    pub client_secret: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
+pub struct Oauth2TokenResponse {
+    pub access_token: String,
+    pub token_type: String,
+    // Seconds.
+    pub expires_in: u64,
+    pub scope: String,
+    // Only if the openid scope was granted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id_token: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
+pub struct Oauth2ErrorResponse {
+    pub error: String,
+}
+
+// What's at .well-known/openid-configuration, for each relying party.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
+pub struct OidcDiscoveryResponse {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub jwks_uri: String,
+    pub scopes_supported: Vec<String>,
+    pub response_types_supported: Vec<String>,
+    pub grant_types_supported: Vec<String>,
+    pub subject_types_supported: Vec<String>,
+    pub id_token_signing_alg_values_supported: Vec<String>,
+    pub token_endpoint_auth_methods_supported: Vec<String>,
+    pub code_challenge_methods_supported: Vec<String>,
+    pub claims_supported: Vec<String>,
+}
+
+// A relying party's public key, to check our signatures on its tokens with.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
+pub struct Jwk {
+    pub kty: String,
+    pub crv: String,
+    pub alg: String,
+    #[serde(rename = "use")]
+    pub use_: String,
+    pub kid: String,
+    pub x: String,
+    pub y: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
+pub struct JwkSet {
+    pub keys: Vec<Jwk>,
+}
+
+// Turning an existing account or group into a posix one. A gidnumber is
+// generated from the uuid if one isn't given.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
+pub struct AccountUnixExtend {
+    pub gidnumber: Option<u32>,
+    pub shell: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "openapi", derive(JsonSchema))]
+pub struct GroupUnixExtend {
+    pub gidnumber: Option<u32>,
+}
+
 #[cfg(test)]
 mod tests {
     use crate::v1::Filter as ProtoFilter;
@@ -2,6 +2,7 @@ import sys
 import requests
 import logging
 import os
+from functools import reduce
 
 MAJOR, MINOR, _, _, _ = sys.version_info
 
@@ -101,8 +102,12 @@ def authorize(args):
     if req_sat is not True:
         return radiusd.RLM_MODULE_NOTFOUND
 
-    # look up them in config for group vlan if possible.
-    uservlan = reduce(check_vlan, tok["groups"], 0)
+    # The vlan set on their groups in kanidm wins, otherwise look them up in
+    # config for group vlan if possible.
+    if tok.get("vlan") is not None:
+        uservlan = tok["vlan"]
+    else:
+        uservlan = reduce(check_vlan, tok["groups"], 0)
     print("selected vlan %s" % uservlan)
     # Convert the tok groups to groups.
     name = tok["name"]
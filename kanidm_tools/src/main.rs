@@ -1,6 +1,7 @@
 extern crate structopt;
 use kanidm_client::KanidmClient;
-use kanidm_proto::v1::{Entry, Filter, Modify, ModifyList};
+use kanidm_proto::query::parse_filter;
+use kanidm_proto::v1::{Entry, Filter, MembershipAction, Modify, ModifyList, SearchRequest};
 use serde::de::DeserializeOwned;
 use std::path::PathBuf;
 use structopt::StructOpt;
@@ -74,16 +75,48 @@ struct ModifyOpt {
     file: Option<PathBuf>,
 }
 
+#[derive(Debug, StructOpt)]
+struct CompareOpt {
+    #[structopt()]
+    target: String,
+    #[structopt()]
+    attr: String,
+    #[structopt()]
+    value: String,
+    #[structopt(flatten)]
+    commonopts: CommonOpt,
+}
+
+#[derive(Debug, StructOpt)]
+struct SearchOpt {
+    #[structopt()]
+    filter: String,
+    // Sort the results by this attribute.
+    #[structopt(long = "sort")]
+    sort: Option<String>,
+    #[structopt(long = "desc")]
+    desc: bool,
+    // Only show these attributes, comma separated.
+    #[structopt(long = "attrs")]
+    attrs: Option<String>,
+    #[structopt(flatten)]
+    commonopts: CommonOpt,
+}
+
 #[derive(Debug, StructOpt)]
 enum RawOpt {
     #[structopt(name = "search")]
-    Search(FilterOpt),
+    Search(SearchOpt),
     #[structopt(name = "create")]
     Create(CreateOpt),
     #[structopt(name = "modify")]
     Modify(ModifyOpt),
     #[structopt(name = "delete")]
     Delete(FilterOpt),
+    #[structopt(name = "compare")]
+    Compare(CompareOpt),
+    #[structopt(name = "explain")]
+    Explain(FilterOpt),
 }
 
 #[derive(Debug, StructOpt)]
@@ -138,12 +171,35 @@ enum AccountRadius {
     Delete(AccountNamedOpt),
 }
 
+#[derive(Debug, StructOpt)]
+struct AccountPosixOpt {
+    #[structopt(flatten)]
+    aopts: AccountCommonOpt,
+    // Generated from the account's uuid if not given.
+    #[structopt(long = "gidnumber")]
+    gidnumber: Option<u32>,
+    #[structopt(long = "shell")]
+    shell: Option<String>,
+    #[structopt(flatten)]
+    copt: CommonOpt,
+}
+
+#[derive(Debug, StructOpt)]
+enum AccountPosix {
+    #[structopt(name = "show")]
+    Show(AccountNamedOpt),
+    #[structopt(name = "set")]
+    Set(AccountPosixOpt),
+}
+
 #[derive(Debug, StructOpt)]
 enum AccountOpt {
     #[structopt(name = "credential")]
     Credential(AccountCredential),
     #[structopt(name = "radius")]
     Radius(AccountRadius),
+    #[structopt(name = "posix")]
+    Posix(AccountPosix),
     #[structopt(name = "list")]
     List(CommonOpt),
     #[structopt(name = "get")]
@@ -172,6 +228,52 @@ struct GroupNamedMembers {
     copt: CommonOpt,
 }
 
+#[derive(Debug, StructOpt)]
+struct GroupNamedMember {
+    #[structopt()]
+    name: String,
+    #[structopt()]
+    member: String,
+    #[structopt(flatten)]
+    copt: CommonOpt,
+}
+
+#[derive(Debug, StructOpt)]
+struct GroupRadiusVlan {
+    #[structopt()]
+    name: String,
+    #[structopt()]
+    vlan: u32,
+    #[structopt(flatten)]
+    copt: CommonOpt,
+}
+
+#[derive(Debug, StructOpt)]
+struct GroupPosixOpt {
+    #[structopt()]
+    name: String,
+    #[structopt(long = "gidnumber")]
+    gidnumber: Option<u32>,
+    #[structopt(flatten)]
+    copt: CommonOpt,
+}
+
+#[derive(Debug, StructOpt)]
+enum GroupPosix {
+    #[structopt(name = "show")]
+    Show(GroupNamed),
+    #[structopt(name = "set")]
+    Set(GroupPosixOpt),
+}
+
+#[derive(Debug, StructOpt)]
+struct MembershipRequestId {
+    #[structopt()]
+    id: String,
+    #[structopt(flatten)]
+    copt: CommonOpt,
+}
+
 #[derive(Debug, StructOpt)]
 enum GroupOpt {
     #[structopt(name = "list")]
@@ -188,6 +290,92 @@ enum GroupOpt {
     PurgeMembers(GroupNamed),
     #[structopt(name = "add_members")]
     AddMembers(GroupNamedMembers),
+    #[structopt(name = "set_approvers")]
+    SetApprovers(GroupNamedMembers),
+    #[structopt(name = "set_radius_vlan")]
+    SetRadiusVlan(GroupRadiusVlan),
+    #[structopt(name = "purge_radius_vlan")]
+    PurgeRadiusVlan(GroupNamed),
+    #[structopt(name = "posix")]
+    Posix(GroupPosix),
+    #[structopt(name = "request_add")]
+    RequestAdd(GroupNamedMember),
+    #[structopt(name = "request_remove")]
+    RequestRemove(GroupNamedMember),
+    #[structopt(name = "list_requests")]
+    ListRequests(CommonOpt),
+    #[structopt(name = "approve")]
+    Approve(MembershipRequestId),
+    #[structopt(name = "reject")]
+    Reject(MembershipRequestId),
+}
+
+#[derive(Debug, StructOpt)]
+struct SavedSearchCreate {
+    #[structopt()]
+    name: String,
+    // Values may hold ${param} placeholders, IE (memberof=${group}).
+    #[structopt()]
+    filter: String,
+    #[structopt(flatten)]
+    copt: CommonOpt,
+}
+
+#[derive(Debug, StructOpt)]
+struct SavedSearchRun {
+    #[structopt()]
+    name: String,
+    // As param=value.
+    #[structopt()]
+    params: Vec<String>,
+    #[structopt(flatten)]
+    copt: CommonOpt,
+}
+
+#[derive(Debug, StructOpt)]
+struct SavedSearchSchedule {
+    #[structopt()]
+    name: String,
+    // In seconds.
+    #[structopt()]
+    interval: u64,
+    #[structopt(flatten)]
+    copt: CommonOpt,
+}
+
+#[derive(Debug, StructOpt)]
+enum SavedSearchOpt {
+    #[structopt(name = "list")]
+    List(CommonOpt),
+    #[structopt(name = "create")]
+    Create(SavedSearchCreate),
+    #[structopt(name = "delete")]
+    Delete(GroupNamed),
+    #[structopt(name = "run")]
+    Run(SavedSearchRun),
+    #[structopt(name = "schedule")]
+    Schedule(SavedSearchSchedule),
+    #[structopt(name = "report")]
+    Report(GroupNamed),
+}
+
+#[derive(Debug, StructOpt)]
+struct RecycleBinNamed {
+    // The uuid or name of the deleted entry.
+    #[structopt()]
+    id: String,
+    #[structopt(flatten)]
+    copt: CommonOpt,
+}
+
+#[derive(Debug, StructOpt)]
+enum RecycleBinOpt {
+    #[structopt(name = "list")]
+    List(CommonOpt),
+    #[structopt(name = "get")]
+    Get(RecycleBinNamed),
+    #[structopt(name = "revive")]
+    Revive(RecycleBinNamed),
 }
 
 #[derive(Debug, StructOpt)]
@@ -208,6 +396,10 @@ enum ClientOpt {
     Account(AccountOpt),
     #[structopt(name = "group")]
     Group(GroupOpt),
+    #[structopt(name = "savedsearch")]
+    SavedSearch(SavedSearchOpt),
+    #[structopt(name = "recycle_bin")]
+    RecycleBin(RecycleBinOpt),
 }
 
 impl ClientOpt {
@@ -218,6 +410,8 @@ impl ClientOpt {
                 RawOpt::Create(copt) => copt.commonopts.debug,
                 RawOpt::Modify(mopt) => mopt.commonopts.debug,
                 RawOpt::Delete(dopt) => dopt.commonopts.debug,
+                RawOpt::Compare(copt) => copt.commonopts.debug,
+                RawOpt::Explain(eopt) => eopt.commonopts.debug,
             },
             ClientOpt::CSelf(csopt) => match csopt {
                 SelfOpt::Whoami(copt) => copt.debug,
@@ -233,6 +427,10 @@ impl ClientOpt {
                     AccountRadius::Generate(aro) => aro.copt.debug,
                     AccountRadius::Delete(aro) => aro.copt.debug,
                 },
+                AccountOpt::Posix(apopt) => match apopt {
+                    AccountPosix::Show(apo) => apo.copt.debug,
+                    AccountPosix::Set(apo) => apo.copt.debug,
+                },
                 AccountOpt::List(copt) => copt.debug,
                 AccountOpt::Get(aopt) => aopt.copt.debug,
                 AccountOpt::Delete(aopt) => aopt.copt.debug,
@@ -246,6 +444,31 @@ impl ClientOpt {
                 GroupOpt::AddMembers(gcopt) => gcopt.copt.debug,
                 GroupOpt::SetMembers(gcopt) => gcopt.copt.debug,
                 GroupOpt::PurgeMembers(gcopt) => gcopt.copt.debug,
+                GroupOpt::SetApprovers(gcopt) => gcopt.copt.debug,
+                GroupOpt::SetRadiusVlan(gcopt) => gcopt.copt.debug,
+                GroupOpt::PurgeRadiusVlan(gcopt) => gcopt.copt.debug,
+                GroupOpt::Posix(gpopt) => match gpopt {
+                    GroupPosix::Show(gpo) => gpo.copt.debug,
+                    GroupPosix::Set(gpo) => gpo.copt.debug,
+                },
+                GroupOpt::RequestAdd(gcopt) => gcopt.copt.debug,
+                GroupOpt::RequestRemove(gcopt) => gcopt.copt.debug,
+                GroupOpt::ListRequests(copt) => copt.debug,
+                GroupOpt::Approve(gcopt) => gcopt.copt.debug,
+                GroupOpt::Reject(gcopt) => gcopt.copt.debug,
+            },
+            ClientOpt::SavedSearch(sopt) => match sopt {
+                SavedSearchOpt::List(copt) => copt.debug,
+                SavedSearchOpt::Create(scopt) => scopt.copt.debug,
+                SavedSearchOpt::Delete(scopt) => scopt.copt.debug,
+                SavedSearchOpt::Run(scopt) => scopt.copt.debug,
+                SavedSearchOpt::Schedule(scopt) => scopt.copt.debug,
+                SavedSearchOpt::Report(scopt) => scopt.copt.debug,
+            },
+            ClientOpt::RecycleBin(ropt) => match ropt {
+                RecycleBinOpt::List(copt) => copt.debug,
+                RecycleBinOpt::Get(ropt) => ropt.copt.debug,
+                RecycleBinOpt::Revive(ropt) => ropt.copt.debug,
             },
         }
     }
@@ -259,6 +482,21 @@ fn read_file<T: DeserializeOwned, P: AsRef<Path>>(path: P) -> Result<T, Box<dyn
     Ok(t)
 }
 
+// Filters may be given as json, or as text, IE (&(class=person)(name=*will*)).
+fn filter_arg(s: &str) -> Filter {
+    if s.starts_with('{') || s.starts_with('"') {
+        return serde_json::from_str(s).unwrap();
+    }
+    match parse_filter(s) {
+        Ok(f) => f,
+        Err(e) => {
+            eprintln!("{}", s);
+            eprintln!("{}^ {}", " ".repeat(e.pos), e.msg);
+            std::process::exit(1);
+        }
+    }
+}
+
 fn main() {
     let opt = ClientOpt::from_args();
 
@@ -274,13 +512,52 @@ fn main() {
             RawOpt::Search(sopt) => {
                 let client = sopt.commonopts.to_client();
 
-                let filter: Filter = serde_json::from_str(sopt.filter.as_str()).unwrap();
-                let rset = client.search(filter).unwrap();
+                let filter = filter_arg(sopt.filter.as_str());
+                let mut sr = SearchRequest::new(filter);
+                if let Some(attr) = &sopt.sort {
+                    sr = sr.sorted_by(attr.as_str(), sopt.desc);
+                }
+                if let Some(attrs) = &sopt.attrs {
+                    let attrs: Vec<&str> = attrs.split(',').map(|a| a.trim()).collect();
+                    sr = sr.with_attrs(attrs.as_slice());
+                }
+                let rset = client.search_request(sr).unwrap();
 
                 rset.iter().for_each(|e| {
                     println!("{:?}", e);
                 });
             }
+            RawOpt::Compare(copt) => {
+                let client = copt.commonopts.to_client();
+                let r = client
+                    .compare(
+                        copt.target.as_str(),
+                        copt.attr.as_str(),
+                        copt.value.as_str(),
+                    )
+                    .unwrap();
+                println!("{}", r);
+            }
+            RawOpt::Explain(eopt) => {
+                let client = eopt.commonopts.to_client();
+
+                let filter = filter_arg(eopt.filter.as_str());
+                let ex = client.explain(filter).unwrap();
+
+                println!("filter: {}", ex.filter);
+                ex.terms.iter().for_each(|t| {
+                    print!("{}{} -> {:?}", "  ".repeat(t.depth), t.term, t.idl);
+                    match &t.shortcut {
+                        Some(s) => println!(", {:?} skipped {}", s, t.skipped),
+                        None => println!(),
+                    }
+                });
+                println!("result: {:?} (threshold {})", ex.result, ex.threshold);
+                println!(
+                    "candidates: {}, filter test: {}, matched: {}",
+                    ex.candidates, ex.filter_test, ex.matched
+                );
+            }
             RawOpt::Create(copt) => {
                 let client = copt.commonopts.to_client();
                 // Read the file?
@@ -300,7 +577,7 @@ fn main() {
                 // Read the file?
                 match mopt.file {
                     Some(p) => {
-                        let filter: Filter = serde_json::from_str(mopt.filter.as_str()).unwrap();
+                        let filter = filter_arg(mopt.filter.as_str());
                         let r_list: Vec<Modify> = read_file(p).unwrap();
                         let modlist = ModifyList::new_list(r_list);
                         client.modify(filter, modlist).unwrap()
@@ -312,7 +589,7 @@ fn main() {
             }
             RawOpt::Delete(dopt) => {
                 let client = dopt.commonopts.to_client();
-                let filter: Filter = serde_json::from_str(dopt.filter.as_str()).unwrap();
+                let filter = filter_arg(dopt.filter.as_str());
                 client.delete(filter).unwrap();
             }
         },
@@ -397,6 +674,25 @@ fn main() {
                         .unwrap();
                 }
             }, // end AccountOpt::Radius
+            AccountOpt::Posix(apopt) => match apopt {
+                AccountPosix::Show(aopt) => {
+                    let client = aopt.copt.to_client();
+                    let token = client
+                        .idm_account_unix_token_get(aopt.aopts.account_id.as_str())
+                        .unwrap();
+                    println!("{}", token);
+                }
+                AccountPosix::Set(aopt) => {
+                    let client = aopt.copt.to_client();
+                    client
+                        .idm_account_unix_extend(
+                            aopt.aopts.account_id.as_str(),
+                            aopt.gidnumber,
+                            aopt.shell.as_deref(),
+                        )
+                        .unwrap();
+                }
+            }, // end AccountOpt::Posix
             AccountOpt::List(copt) => {
                 let client = copt.to_client();
                 let r = client.idm_account_list().unwrap();
@@ -475,6 +771,166 @@ fn main() {
                     .idm_group_set_members(gcopt.name.as_str(), new_members)
                     .unwrap();
             }
+            GroupOpt::SetApprovers(gcopt) => {
+                let client = gcopt.copt.to_client();
+                let approvers: Vec<&str> = gcopt.members.iter().map(|s| s.as_str()).collect();
+
+                client
+                    .idm_group_set_approvers(gcopt.name.as_str(), approvers)
+                    .unwrap();
+            }
+            GroupOpt::SetRadiusVlan(gcopt) => {
+                let client = gcopt.copt.to_client();
+                client
+                    .idm_group_set_radius_vlan(gcopt.name.as_str(), gcopt.vlan)
+                    .unwrap();
+            }
+            GroupOpt::PurgeRadiusVlan(gcopt) => {
+                let client = gcopt.copt.to_client();
+                client
+                    .idm_group_purge_radius_vlan(gcopt.name.as_str())
+                    .unwrap();
+            }
+            GroupOpt::Posix(gpopt) => match gpopt {
+                GroupPosix::Show(gcopt) => {
+                    let client = gcopt.copt.to_client();
+                    let token = client
+                        .idm_group_unix_token_get(gcopt.name.as_str())
+                        .unwrap();
+                    println!("{}", token);
+                }
+                GroupPosix::Set(gcopt) => {
+                    let client = gcopt.copt.to_client();
+                    client
+                        .idm_group_unix_extend(gcopt.name.as_str(), gcopt.gidnumber)
+                        .unwrap();
+                }
+            },
+            GroupOpt::RequestAdd(gcopt) => {
+                let client = gcopt.copt.to_client();
+                let id = client
+                    .idm_group_request_membership(
+                        gcopt.name.as_str(),
+                        gcopt.member.as_str(),
+                        MembershipAction::Add,
+                    )
+                    .unwrap();
+                println!("{}", id);
+            }
+            GroupOpt::RequestRemove(gcopt) => {
+                let client = gcopt.copt.to_client();
+                let id = client
+                    .idm_group_request_membership(
+                        gcopt.name.as_str(),
+                        gcopt.member.as_str(),
+                        MembershipAction::Remove,
+                    )
+                    .unwrap();
+                println!("{}", id);
+            }
+            GroupOpt::ListRequests(copt) => {
+                let client = copt.to_client();
+                let r = client.idm_membership_requests().unwrap();
+                for mr in r {
+                    println!(
+                        "{} {:?} {} in {} (by {})",
+                        mr.uuid, mr.action, mr.member, mr.group, mr.requester
+                    );
+                }
+            }
+            GroupOpt::Approve(gcopt) => {
+                let client = gcopt.copt.to_client();
+                client
+                    .idm_membership_request_approve(gcopt.id.as_str())
+                    .unwrap();
+            }
+            GroupOpt::Reject(gcopt) => {
+                let client = gcopt.copt.to_client();
+                client
+                    .idm_membership_request_reject(gcopt.id.as_str())
+                    .unwrap();
+            }
         }, // end Group
+        ClientOpt::SavedSearch(sopt) => match sopt {
+            SavedSearchOpt::List(copt) => {
+                let client = copt.to_client();
+                let r = client.idm_savedsearch_list().unwrap();
+                for e in r {
+                    println!("{:?}", e);
+                }
+            }
+            SavedSearchOpt::Create(scopt) => {
+                let client = scopt.copt.to_client();
+                let filter = filter_arg(scopt.filter.as_str());
+                client
+                    .idm_savedsearch_create(scopt.name.as_str(), &filter)
+                    .unwrap();
+            }
+            SavedSearchOpt::Delete(scopt) => {
+                let client = scopt.copt.to_client();
+                client.idm_savedsearch_delete(scopt.name.as_str()).unwrap();
+            }
+            SavedSearchOpt::Run(scopt) => {
+                let client = scopt.copt.to_client();
+                let params: BTreeMap<String, String> = scopt
+                    .params
+                    .iter()
+                    .map(|p| {
+                        let mut kv = p.splitn(2, '=');
+                        match (kv.next(), kv.next()) {
+                            (Some(k), Some(v)) => (k.to_string(), v.to_string()),
+                            _ => {
+                                eprintln!("{} is not param=value", p);
+                                std::process::exit(1);
+                            }
+                        }
+                    })
+                    .collect();
+                let rset = client
+                    .idm_savedsearch_run(scopt.name.as_str(), params)
+                    .unwrap();
+                rset.iter().for_each(|e| {
+                    println!("{:?}", e);
+                });
+            }
+            SavedSearchOpt::Schedule(scopt) => {
+                let client = scopt.copt.to_client();
+                client
+                    .idm_savedsearch_set_report_interval(scopt.name.as_str(), scopt.interval)
+                    .unwrap();
+            }
+            SavedSearchOpt::Report(scopt) => {
+                let client = scopt.copt.to_client();
+                let reports = client.idm_savedsearch_reports(scopt.name.as_str()).unwrap();
+                for r in reports {
+                    println!(
+                        "{} {}{}",
+                        r.time,
+                        r.count,
+                        if r.allids { " (unindexed)" } else { "" }
+                    );
+                }
+            }
+        }, // end SavedSearch
+        ClientOpt::RecycleBin(ropt) => match ropt {
+            RecycleBinOpt::List(copt) => {
+                let client = copt.to_client();
+                let r = client.recycle_bin_list().unwrap();
+                for e in r {
+                    println!("{:?}", e);
+                }
+            }
+            RecycleBinOpt::Get(ropt) => {
+                let client = ropt.copt.to_client();
+                match client.recycle_bin_get(ropt.id.as_str()).unwrap() {
+                    Some(e) => println!("{:?}", e),
+                    None => eprintln!("{} is not in the recycle bin", ropt.id),
+                }
+            }
+            RecycleBinOpt::Revive(ropt) => {
+                let client = ropt.copt.to_client();
+                client.recycle_bin_revive(ropt.id.as_str()).unwrap();
+            }
+        }, // end RecycleBin
     }
 }
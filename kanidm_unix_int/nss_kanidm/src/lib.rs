@@ -0,0 +1,397 @@
+// The glibc nss module, installed as libnss_kanidm.so.2 and enabled with
+// "passwd: files kanidm" and "group: files kanidm" in /etc/nsswitch.conf.
+// Everything is asked of kanidm_unixd, so this never talks to the network or
+// keeps state beyond an enumeration in progress.
+#![deny(warnings)]
+// These are only called by glibc, with the pointers the nss interface promises.
+#![allow(clippy::missing_safety_doc)]
+
+use kanidm_unix_common::client::call_daemon_blocking;
+use kanidm_unix_common::constants::DEFAULT_SOCK_PATH;
+use kanidm_unix_common::unix_proto::{ClientRequest, ClientResponse, NssGroup, NssUser};
+
+use libc::{c_char, c_int, gid_t, group, passwd, size_t, uid_t};
+use std::ffi::CStr;
+use std::mem;
+use std::ptr;
+use std::sync::Mutex;
+
+// enum nss_status from nss.h
+const NSS_STATUS_TRYAGAIN: c_int = -2;
+const NSS_STATUS_UNAVAIL: c_int = -1;
+const NSS_STATUS_NOTFOUND: c_int = 0;
+const NSS_STATUS_SUCCESS: c_int = 1;
+
+// What setpwent and setgrent fetched, handed out by the get*ent_r calls.
+static PW_ENT: Mutex<Vec<NssUser>> = Mutex::new(Vec::new());
+static GR_ENT: Mutex<Vec<NssGroup>> = Mutex::new(Vec::new());
+
+fn call(req: ClientRequest) -> Option<ClientResponse> {
+    call_daemon_blocking(DEFAULT_SOCK_PATH, &req).ok()
+}
+
+// Lays strings and pointer arrays out in the buffer the caller gave us, which
+// is where everything the returned struct points to must live.
+struct NssBuffer {
+    buf: *mut c_char,
+    len: usize,
+    off: usize,
+}
+
+impl NssBuffer {
+    fn add_str(&mut self, s: &str) -> Option<*mut c_char> {
+        let b = s.as_bytes();
+        if b.contains(&0) || self.off + b.len() + 1 > self.len {
+            return None;
+        }
+        unsafe {
+            let dst = self.buf.add(self.off);
+            ptr::copy_nonoverlapping(b.as_ptr() as *const c_char, dst, b.len());
+            *dst.add(b.len()) = 0;
+            self.off += b.len() + 1;
+            Some(dst)
+        }
+    }
+
+    // A null terminated array of the strings, as gr_mem is.
+    fn add_str_array(&mut self, v: &[String]) -> Option<*mut *mut c_char> {
+        let align = mem::align_of::<*mut c_char>();
+        let start = (self.buf as usize + self.off).div_ceil(align) * align - self.buf as usize;
+        let size = (v.len() + 1) * mem::size_of::<*mut c_char>();
+        if start + size > self.len {
+            return None;
+        }
+        self.off = start + size;
+        let arr = unsafe { self.buf.add(start) as *mut *mut c_char };
+        for (i, s) in v.iter().enumerate() {
+            let p = self.add_str(s.as_str())?;
+            unsafe { *arr.add(i) = p };
+        }
+        unsafe { *arr.add(v.len()) = ptr::null_mut() };
+        Some(arr)
+    }
+}
+
+unsafe fn fill_passwd(
+    nu: &NssUser,
+    result: *mut passwd,
+    buf: *mut c_char,
+    buflen: size_t,
+    errnop: *mut c_int,
+) -> c_int {
+    let mut b = NssBuffer {
+        buf: buf,
+        len: buflen,
+        off: 0,
+    };
+    let filled = (|| {
+        let r = &mut *result;
+        r.pw_name = b.add_str(nu.name.as_str())?;
+        // The password is never given out, pam checks it.
+        r.pw_passwd = b.add_str("x")?;
+        r.pw_uid = nu.gid as uid_t;
+        r.pw_gid = nu.gid as gid_t;
+        r.pw_gecos = b.add_str(nu.gecos.as_str())?;
+        r.pw_dir = b.add_str(nu.homedir.as_str())?;
+        r.pw_shell = b.add_str(nu.shell.as_str())?;
+        Some(())
+    })();
+    match filled {
+        Some(()) => NSS_STATUS_SUCCESS,
+        None => {
+            // Too small, glibc tries again with a bigger buffer.
+            *errnop = libc::ERANGE;
+            NSS_STATUS_TRYAGAIN
+        }
+    }
+}
+
+unsafe fn fill_group(
+    ng: &NssGroup,
+    result: *mut group,
+    buf: *mut c_char,
+    buflen: size_t,
+    errnop: *mut c_int,
+) -> c_int {
+    let mut b = NssBuffer {
+        buf: buf,
+        len: buflen,
+        off: 0,
+    };
+    let filled = (|| {
+        let r = &mut *result;
+        r.gr_name = b.add_str(ng.name.as_str())?;
+        r.gr_passwd = b.add_str("x")?;
+        r.gr_gid = ng.gid as gid_t;
+        r.gr_mem = b.add_str_array(&ng.members)?;
+        Some(())
+    })();
+    match filled {
+        Some(()) => NSS_STATUS_SUCCESS,
+        None => {
+            *errnop = libc::ERANGE;
+            NSS_STATUS_TRYAGAIN
+        }
+    }
+}
+
+unsafe fn passwd_response(
+    resp: Option<ClientResponse>,
+    result: *mut passwd,
+    buf: *mut c_char,
+    buflen: size_t,
+    errnop: *mut c_int,
+) -> c_int {
+    match resp {
+        Some(ClientResponse::NssAccount(Some(nu))) => fill_passwd(&nu, result, buf, buflen, errnop),
+        Some(ClientResponse::NssAccount(None)) => NSS_STATUS_NOTFOUND,
+        _ => NSS_STATUS_UNAVAIL,
+    }
+}
+
+unsafe fn group_response(
+    resp: Option<ClientResponse>,
+    result: *mut group,
+    buf: *mut c_char,
+    buflen: size_t,
+    errnop: *mut c_int,
+) -> c_int {
+    match resp {
+        Some(ClientResponse::NssGroup(Some(ng))) => fill_group(&ng, result, buf, buflen, errnop),
+        Some(ClientResponse::NssGroup(None)) => NSS_STATUS_NOTFOUND,
+        _ => NSS_STATUS_UNAVAIL,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn _nss_kanidm_getpwnam_r(
+    name: *const c_char,
+    result: *mut passwd,
+    buf: *mut c_char,
+    buflen: size_t,
+    errnop: *mut c_int,
+) -> c_int {
+    let name = match CStr::from_ptr(name).to_str() {
+        Ok(n) => n.to_string(),
+        Err(_) => return NSS_STATUS_NOTFOUND,
+    };
+    passwd_response(
+        call(ClientRequest::NssAccountByName(name)),
+        result,
+        buf,
+        buflen,
+        errnop,
+    )
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn _nss_kanidm_getpwuid_r(
+    uid: uid_t,
+    result: *mut passwd,
+    buf: *mut c_char,
+    buflen: size_t,
+    errnop: *mut c_int,
+) -> c_int {
+    passwd_response(
+        call(ClientRequest::NssAccountByUid(uid)),
+        result,
+        buf,
+        buflen,
+        errnop,
+    )
+}
+
+#[no_mangle]
+pub extern "C" fn _nss_kanidm_setpwent() -> c_int {
+    let users = match call(ClientRequest::NssAccounts) {
+        Some(ClientResponse::NssAccounts(mut v)) => {
+            // Handed out from the end.
+            v.reverse();
+            v
+        }
+        _ => return NSS_STATUS_UNAVAIL,
+    };
+    match PW_ENT.lock() {
+        Ok(mut ent) => {
+            *ent = users;
+            NSS_STATUS_SUCCESS
+        }
+        Err(_) => NSS_STATUS_UNAVAIL,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn _nss_kanidm_getpwent_r(
+    result: *mut passwd,
+    buf: *mut c_char,
+    buflen: size_t,
+    errnop: *mut c_int,
+) -> c_int {
+    let mut ent = match PW_ENT.lock() {
+        Ok(e) => e,
+        Err(_) => return NSS_STATUS_UNAVAIL,
+    };
+    let status = match ent.last() {
+        Some(nu) => fill_passwd(nu, result, buf, buflen, errnop),
+        None => return NSS_STATUS_NOTFOUND,
+    };
+    // Kept on a short buffer, for the retry.
+    if status == NSS_STATUS_SUCCESS {
+        ent.pop();
+    }
+    status
+}
+
+#[no_mangle]
+pub extern "C" fn _nss_kanidm_endpwent() -> c_int {
+    if let Ok(mut ent) = PW_ENT.lock() {
+        ent.clear();
+    }
+    NSS_STATUS_SUCCESS
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn _nss_kanidm_getgrnam_r(
+    name: *const c_char,
+    result: *mut group,
+    buf: *mut c_char,
+    buflen: size_t,
+    errnop: *mut c_int,
+) -> c_int {
+    let name = match CStr::from_ptr(name).to_str() {
+        Ok(n) => n.to_string(),
+        Err(_) => return NSS_STATUS_NOTFOUND,
+    };
+    group_response(
+        call(ClientRequest::NssGroupByName(name)),
+        result,
+        buf,
+        buflen,
+        errnop,
+    )
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn _nss_kanidm_getgrgid_r(
+    gid: gid_t,
+    result: *mut group,
+    buf: *mut c_char,
+    buflen: size_t,
+    errnop: *mut c_int,
+) -> c_int {
+    group_response(
+        call(ClientRequest::NssGroupByGid(gid)),
+        result,
+        buf,
+        buflen,
+        errnop,
+    )
+}
+
+#[no_mangle]
+pub extern "C" fn _nss_kanidm_setgrent() -> c_int {
+    let groups = match call(ClientRequest::NssGroups) {
+        Some(ClientResponse::NssGroups(mut v)) => {
+            v.reverse();
+            v
+        }
+        _ => return NSS_STATUS_UNAVAIL,
+    };
+    match GR_ENT.lock() {
+        Ok(mut ent) => {
+            *ent = groups;
+            NSS_STATUS_SUCCESS
+        }
+        Err(_) => NSS_STATUS_UNAVAIL,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn _nss_kanidm_getgrent_r(
+    result: *mut group,
+    buf: *mut c_char,
+    buflen: size_t,
+    errnop: *mut c_int,
+) -> c_int {
+    let mut ent = match GR_ENT.lock() {
+        Ok(e) => e,
+        Err(_) => return NSS_STATUS_UNAVAIL,
+    };
+    let status = match ent.last() {
+        Some(ng) => fill_group(ng, result, buf, buflen, errnop),
+        None => return NSS_STATUS_NOTFOUND,
+    };
+    if status == NSS_STATUS_SUCCESS {
+        ent.pop();
+    }
+    status
+}
+
+#[no_mangle]
+pub extern "C" fn _nss_kanidm_endgrent() -> c_int {
+    if let Ok(mut ent) = GR_ENT.lock() {
+        ent.clear();
+    }
+    NSS_STATUS_SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fill_group, fill_passwd, NSS_STATUS_SUCCESS, NSS_STATUS_TRYAGAIN};
+    use kanidm_unix_common::unix_proto::{NssGroup, NssUser};
+    use libc::{c_char, c_int, group, passwd};
+    use std::ffi::CStr;
+    use std::mem;
+
+    #[test]
+    fn test_nss_fill_passwd() {
+        let nu = NssUser {
+            name: "testuser".to_string(),
+            gid: 70000,
+            gecos: "Test User".to_string(),
+            homedir: "/home/testuser".to_string(),
+            shell: "/bin/sh".to_string(),
+        };
+        let mut pw: passwd = unsafe { mem::zeroed() };
+        let mut errno: c_int = 0;
+
+        let mut small = vec![0 as c_char; 16];
+        let r = unsafe { fill_passwd(&nu, &mut pw, small.as_mut_ptr(), small.len(), &mut errno) };
+        assert_eq!(r, NSS_STATUS_TRYAGAIN);
+        assert_eq!(errno, libc::ERANGE);
+
+        let mut buf = vec![0 as c_char; 256];
+        let r = unsafe { fill_passwd(&nu, &mut pw, buf.as_mut_ptr(), buf.len(), &mut errno) };
+        assert_eq!(r, NSS_STATUS_SUCCESS);
+        assert_eq!(pw.pw_uid, 70000);
+        assert_eq!(
+            unsafe { CStr::from_ptr(pw.pw_name) }.to_str(),
+            Ok("testuser")
+        );
+        assert_eq!(
+            unsafe { CStr::from_ptr(pw.pw_shell) }.to_str(),
+            Ok("/bin/sh")
+        );
+    }
+
+    #[test]
+    fn test_nss_fill_group() {
+        let ng = NssGroup {
+            name: "posix_group".to_string(),
+            gid: 2001,
+            members: vec!["a".to_string(), "b".to_string()],
+        };
+        let mut gr: group = unsafe { mem::zeroed() };
+        let mut errno: c_int = 0;
+        let mut buf = vec![0 as c_char; 256];
+        let r = unsafe { fill_group(&ng, &mut gr, buf.as_mut_ptr(), buf.len(), &mut errno) };
+        assert_eq!(r, NSS_STATUS_SUCCESS);
+        assert_eq!(gr.gr_gid, 2001);
+        let members: Vec<&str> = (0..)
+            .map(|i| unsafe { *gr.gr_mem.add(i) })
+            .take_while(|p| !p.is_null())
+            .map(|p| unsafe { CStr::from_ptr(p) }.to_str().unwrap())
+            .collect();
+        assert_eq!(members, vec!["a", "b"]);
+    }
+}
@@ -0,0 +1,191 @@
+// The pam module, installed as pam_kanidm.so. Passwords are checked by
+// kanidm_unixd, which asks the server, or its cache when the server can't be
+// reached. Accounts it doesn't know are left for other modules unless told
+// otherwise, for example:
+//
+//   auth     sufficient  pam_kanidm.so ignore_unknown_user
+//   account  sufficient  pam_kanidm.so ignore_unknown_user
+#![deny(warnings)]
+// These are only called by libpam, with the handle and arguments it promises.
+#![allow(clippy::missing_safety_doc)]
+
+use kanidm_unix_common::client::call_daemon_blocking;
+use kanidm_unix_common::constants::DEFAULT_SOCK_PATH;
+use kanidm_unix_common::unix_proto::{ClientRequest, ClientResponse};
+
+use libc::{c_char, c_int, c_void};
+use std::ffi::CStr;
+use std::ptr;
+
+type PamHandle = c_void;
+
+// From security/_pam_types.h
+const PAM_SUCCESS: c_int = 0;
+const PAM_SERVICE_ERR: c_int = 3;
+const PAM_SYSTEM_ERR: c_int = 4;
+const PAM_PERM_DENIED: c_int = 6;
+const PAM_AUTH_ERR: c_int = 7;
+const PAM_AUTHINFO_UNAVAIL: c_int = 9;
+const PAM_USER_UNKNOWN: c_int = 10;
+const PAM_IGNORE: c_int = 25;
+
+const PAM_AUTHTOK: c_int = 6;
+
+// Resolved from libpam when the module is loaded.
+extern "C" {
+    fn pam_get_user(pamh: *mut PamHandle, user: *mut *const c_char, prompt: *const c_char)
+        -> c_int;
+    fn pam_get_authtok(
+        pamh: *mut PamHandle,
+        item: c_int,
+        authtok: *mut *const c_char,
+        prompt: *const c_char,
+    ) -> c_int;
+}
+
+#[derive(Debug, Default)]
+struct Options {
+    ignore_unknown_user: bool,
+}
+
+unsafe fn parse_options(argc: c_int, argv: *const *const c_char) -> Options {
+    let mut opts = Options::default();
+    if argv.is_null() {
+        return opts;
+    }
+    for i in 0..argc as usize {
+        let arg = *argv.add(i);
+        if arg.is_null() {
+            continue;
+        }
+        if let Ok("ignore_unknown_user") = CStr::from_ptr(arg).to_str() {
+            opts.ignore_unknown_user = true;
+        }
+    }
+    opts
+}
+
+unsafe fn get_user(pamh: *mut PamHandle) -> Result<String, c_int> {
+    let mut user: *const c_char = ptr::null();
+    let r = pam_get_user(pamh, &mut user, ptr::null());
+    if r != PAM_SUCCESS {
+        return Err(r);
+    }
+    if user.is_null() {
+        return Err(PAM_SERVICE_ERR);
+    }
+    CStr::from_ptr(user)
+        .to_str()
+        .map(|s| s.to_string())
+        .map_err(|_| PAM_USER_UNKNOWN)
+}
+
+// What the daemon said, as pam understands it.
+fn status_to_pam(resp: Option<ClientResponse>, denied: c_int, opts: &Options) -> c_int {
+    match resp {
+        Some(ClientResponse::PamStatus(Some(true))) => PAM_SUCCESS,
+        Some(ClientResponse::PamStatus(Some(false))) => denied,
+        Some(ClientResponse::PamStatus(None)) => {
+            if opts.ignore_unknown_user {
+                PAM_IGNORE
+            } else {
+                PAM_USER_UNKNOWN
+            }
+        }
+        Some(_) => PAM_SYSTEM_ERR,
+        // The daemon isn't running.
+        None => PAM_AUTHINFO_UNAVAIL,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn pam_sm_authenticate(
+    pamh: *mut PamHandle,
+    _flags: c_int,
+    argc: c_int,
+    argv: *const *const c_char,
+) -> c_int {
+    let opts = parse_options(argc, argv);
+    let user = match get_user(pamh) {
+        Ok(u) => u,
+        Err(e) => return e,
+    };
+
+    let mut authtok: *const c_char = ptr::null();
+    let r = pam_get_authtok(pamh, PAM_AUTHTOK, &mut authtok, ptr::null());
+    if r != PAM_SUCCESS {
+        return r;
+    }
+    if authtok.is_null() {
+        return PAM_AUTH_ERR;
+    }
+    let password = match CStr::from_ptr(authtok).to_str() {
+        Ok(p) => p.to_string(),
+        Err(_) => return PAM_AUTH_ERR,
+    };
+
+    let resp = call_daemon_blocking(
+        DEFAULT_SOCK_PATH,
+        &ClientRequest::PamAuthenticate(user, password),
+    )
+    .ok();
+    status_to_pam(resp, PAM_AUTH_ERR, &opts)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn pam_sm_acct_mgmt(
+    pamh: *mut PamHandle,
+    _flags: c_int,
+    argc: c_int,
+    argv: *const *const c_char,
+) -> c_int {
+    let opts = parse_options(argc, argv);
+    let user = match get_user(pamh) {
+        Ok(u) => u,
+        Err(e) => return e,
+    };
+    let resp =
+        call_daemon_blocking(DEFAULT_SOCK_PATH, &ClientRequest::PamAccountAllowed(user)).ok();
+    status_to_pam(resp, PAM_PERM_DENIED, &opts)
+}
+
+#[no_mangle]
+pub extern "C" fn pam_sm_setcred(
+    _pamh: *mut PamHandle,
+    _flags: c_int,
+    _argc: c_int,
+    _argv: *const *const c_char,
+) -> c_int {
+    PAM_SUCCESS
+}
+
+#[no_mangle]
+pub extern "C" fn pam_sm_open_session(
+    _pamh: *mut PamHandle,
+    _flags: c_int,
+    _argc: c_int,
+    _argv: *const *const c_char,
+) -> c_int {
+    PAM_SUCCESS
+}
+
+#[no_mangle]
+pub extern "C" fn pam_sm_close_session(
+    _pamh: *mut PamHandle,
+    _flags: c_int,
+    _argc: c_int,
+    _argv: *const *const c_char,
+) -> c_int {
+    PAM_SUCCESS
+}
+
+// Password changes are made with the kanidm tool, against the server.
+#[no_mangle]
+pub extern "C" fn pam_sm_chauthtok(
+    _pamh: *mut PamHandle,
+    _flags: c_int,
+    _argc: c_int,
+    _argv: *const *const c_char,
+) -> c_int {
+    PAM_IGNORE
+}
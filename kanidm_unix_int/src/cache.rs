@@ -0,0 +1,354 @@
+// What kanidm_unixd remembers between asking the server. Entries are keyed by
+// uuid, so a rename on the server replaces the old name rather than leaving it
+// resolvable. Entries past their expiry are only used while the server can't
+// be reached, and are never removed for age alone - that's what lets someone
+// log in to a laptop that's been off the network for a week.
+//
+// The password is only ever kept as a salted pbkdf2 hash, taken after the
+// server accepted it, so that an offline login checks against the last
+// password that really worked.
+use crate::unix_proto::{NssGroup, NssUser};
+use kanidm_proto::v1::{UnixGroupToken, UnixUserToken};
+use openssl::hash::MessageDigest;
+use openssl::pkcs5::pbkdf2_hmac;
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+const PBKDF2_SALT_LEN: usize = 24;
+const PBKDF2_KEY_LEN: usize = 32;
+const PBKDF2_ITER: usize = 10000;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CachedPassword {
+    iter: usize,
+    salt: Vec<u8>,
+    hash: Vec<u8>,
+}
+
+impl CachedPassword {
+    pub fn new(cleartext: &str) -> Option<Self> {
+        let mut salt = vec![0; PBKDF2_SALT_LEN];
+        openssl::rand::rand_bytes(&mut salt).ok()?;
+        let hash = Self::derive(cleartext, &salt, PBKDF2_ITER)?;
+        Some(CachedPassword {
+            iter: PBKDF2_ITER,
+            salt: salt,
+            hash: hash,
+        })
+    }
+
+    fn derive(cleartext: &str, salt: &[u8], iter: usize) -> Option<Vec<u8>> {
+        let mut key = vec![0; PBKDF2_KEY_LEN];
+        pbkdf2_hmac(
+            cleartext.as_bytes(),
+            salt,
+            iter,
+            MessageDigest::sha256(),
+            &mut key,
+        )
+        .ok()?;
+        Some(key)
+    }
+
+    pub fn verify(&self, cleartext: &str) -> bool {
+        match Self::derive(cleartext, &self.salt, self.iter) {
+            Some(key) => key.len() == self.hash.len() && openssl::memcmp::eq(&key, &self.hash),
+            None => false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CachedUser {
+    pub token: UnixUserToken,
+    pub expiry: u64,
+    pub password: Option<CachedPassword>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CachedGroup {
+    pub token: UnixGroupToken,
+    pub expiry: u64,
+}
+
+// How nss asks for something.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Id {
+    Name(String),
+    Gid(u32),
+}
+
+impl Id {
+    // The form the server accepts in a url, see unix_target_uuid.
+    pub fn to_server_id(&self) -> String {
+        match self {
+            Id::Name(n) => n.clone(),
+            Id::Gid(g) => g.to_string(),
+        }
+    }
+
+    fn matches(&self, name: &str, gid: u32) -> bool {
+        match self {
+            Id::Name(n) => n == name,
+            Id::Gid(g) => *g == gid,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct CacheState {
+    users: BTreeMap<String, CachedUser>,
+    groups: BTreeMap<String, CachedGroup>,
+}
+
+impl CacheState {
+    // A missing cache is the normal first start, and a damaged one is thrown
+    // away rather than keeping the daemon from starting.
+    pub fn load<P: AsRef<Path>>(path: P) -> Self {
+        let mut contents = String::new();
+        match File::open(path.as_ref()).and_then(|mut f| f.read_to_string(&mut contents)) {
+            Ok(_) => serde_json::from_str(contents.as_str()).unwrap_or_else(|e| {
+                error!("Discarding unreadable cache -> {:?}", e);
+                CacheState::default()
+            }),
+            Err(e) => {
+                debug!("No cache loaded -> {:?}", e);
+                CacheState::default()
+            }
+        }
+    }
+
+    // Written aside and renamed over, so a crash part way leaves the old
+    // cache rather than half of a new one. Only root may read it, as it
+    // holds the password hashes.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), io::Error> {
+        let path = path.as_ref();
+        let tmp = path.with_extension("tmp");
+        let data =
+            serde_json::to_vec(self).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        {
+            let mut f = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .mode(0o600)
+                .open(&tmp)?;
+            f.write_all(&data)?;
+            f.sync_all()?;
+        }
+        fs::rename(&tmp, path)
+    }
+
+    pub fn get_user(&self, id: &Id) -> Option<&CachedUser> {
+        self.users
+            .values()
+            .find(|u| id.matches(u.token.name.as_str(), u.token.gidnumber))
+    }
+
+    pub fn get_group(&self, id: &Id) -> Option<&CachedGroup> {
+        self.groups
+            .values()
+            .find(|g| id.matches(g.token.name.as_str(), g.token.gidnumber))
+    }
+
+    pub fn users(&self) -> impl Iterator<Item = &CachedUser> {
+        self.users.values()
+    }
+
+    pub fn groups(&self) -> impl Iterator<Item = &CachedGroup> {
+        self.groups.values()
+    }
+
+    // The user's groups come along with it, which is how the user private
+    // group and group members are known without asking for each.
+    pub fn update_user(&mut self, token: UnixUserToken, expiry: u64) {
+        for g in token.groups.iter() {
+            self.update_group(g.clone(), expiry);
+        }
+        let password = self.users.remove(&token.uuid).and_then(|u| u.password);
+        self.users.insert(
+            token.uuid.clone(),
+            CachedUser {
+                token: token,
+                expiry: expiry,
+                password: password,
+            },
+        );
+    }
+
+    pub fn update_group(&mut self, token: UnixGroupToken, expiry: u64) {
+        self.groups.insert(
+            token.uuid.clone(),
+            CachedGroup {
+                token: token,
+                expiry: expiry,
+            },
+        );
+    }
+
+    // When the server says the account is gone, or no longer posix, it must
+    // not keep working offline.
+    pub fn remove_user(&mut self, id: &Id) -> bool {
+        let uuid = match self.get_user(id) {
+            Some(u) => u.token.uuid.clone(),
+            None => return false,
+        };
+        self.users.remove(&uuid);
+        self.groups.remove(&uuid);
+        true
+    }
+
+    pub fn remove_group(&mut self, id: &Id) -> bool {
+        let uuid = match self.get_group(id) {
+            Some(g) => g.token.uuid.clone(),
+            None => return false,
+        };
+        self.groups.remove(&uuid).is_some()
+    }
+
+    pub fn set_password(&mut self, name: &str, cred: Option<CachedPassword>) {
+        if let Some(u) = self
+            .users
+            .values_mut()
+            .find(|u| u.token.name.as_str() == name)
+        {
+            u.password = cred;
+        }
+    }
+
+    // Everything is asked for again on next use, but kept in case the
+    // server can't be reached.
+    pub fn invalidate(&mut self) {
+        self.users.values_mut().for_each(|u| u.expiry = 0);
+        self.groups.values_mut().for_each(|g| g.expiry = 0);
+    }
+
+    pub fn group_members(&self, group_uuid: &str) -> Vec<String> {
+        self.users
+            .values()
+            .filter(|u| u.token.groups.iter().any(|g| g.uuid == group_uuid))
+            .map(|u| u.token.name.clone())
+            .collect()
+    }
+
+    pub fn to_nss_user(&self, u: &CachedUser, home_prefix: &str, default_shell: &str) -> NssUser {
+        let t = &u.token;
+        NssUser {
+            name: t.name.clone(),
+            gid: t.gidnumber,
+            gecos: t.displayname.clone(),
+            homedir: t
+                .home_directory
+                .clone()
+                .unwrap_or_else(|| format!("{}{}", home_prefix, t.name)),
+            shell: t.shell.clone().unwrap_or_else(|| default_shell.to_string()),
+        }
+    }
+
+    pub fn to_nss_group(&self, g: &CachedGroup) -> NssGroup {
+        NssGroup {
+            name: g.token.name.clone(),
+            gid: g.token.gidnumber,
+            members: self.group_members(g.token.uuid.as_str()),
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::{CacheState, CachedPassword, Id};
+    use kanidm_proto::v1::{UnixGroupToken, UnixUserToken};
+
+    pub(crate) fn test_token(name: &str, uuid: &str, gid: u32) -> UnixUserToken {
+        UnixUserToken {
+            name: name.to_string(),
+            displayname: name.to_string(),
+            uuid: uuid.to_string(),
+            gidnumber: gid,
+            shell: None,
+            home_directory: None,
+            groups: vec![
+                UnixGroupToken {
+                    name: name.to_string(),
+                    uuid: uuid.to_string(),
+                    gidnumber: gid,
+                },
+                UnixGroupToken {
+                    name: "posix_group".to_string(),
+                    uuid: "01609135-a1c4-43d5-966b-a28227644445".to_string(),
+                    gidnumber: 2001,
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn test_cache_password() {
+        let c = CachedPassword::new("password").unwrap();
+        assert!(c.verify("password"));
+        assert!(!c.verify("passwore"));
+        assert!(!c.verify(""));
+    }
+
+    #[test]
+    fn test_cache_user_lookup() {
+        let mut cache = CacheState::default();
+        cache.update_user(
+            test_token("testuser", "cc8e95b4-c24f-4d68-ba54-8bed76f63930", 70000),
+            100,
+        );
+
+        assert!(cache.get_user(&Id::Name("testuser".to_string())).is_some());
+        assert!(cache.get_user(&Id::Gid(70000)).is_some());
+        assert!(cache.get_user(&Id::Gid(2001)).is_none());
+        // Its user private group, and the posix group it's in.
+        assert!(cache.get_group(&Id::Name("testuser".to_string())).is_some());
+        let g = cache.get_group(&Id::Gid(2001)).unwrap();
+        assert_eq!(cache.to_nss_group(g).members, vec!["testuser".to_string()]);
+
+        let nu = cache.to_nss_user(
+            cache.get_user(&Id::Gid(70000)).unwrap(),
+            "/home/",
+            "/bin/sh",
+        );
+        assert_eq!(nu.homedir, "/home/testuser");
+        assert_eq!(nu.shell, "/bin/sh");
+    }
+
+    #[test]
+    fn test_cache_rename_keeps_password() {
+        let mut cache = CacheState::default();
+        let uuid = "cc8e95b4-c24f-4d68-ba54-8bed76f63930";
+        cache.update_user(test_token("testuser", uuid, 70000), 100);
+        cache.set_password("testuser", CachedPassword::new("password"));
+
+        cache.update_user(test_token("renamed", uuid, 70000), 200);
+        assert!(cache.get_user(&Id::Name("testuser".to_string())).is_none());
+        let u = cache.get_user(&Id::Name("renamed".to_string())).unwrap();
+        assert!(u.password.as_ref().unwrap().verify("password"));
+
+        assert!(cache.remove_user(&Id::Name("renamed".to_string())));
+        assert!(cache.get_user(&Id::Gid(70000)).is_none());
+        assert!(cache.get_group(&Id::Gid(70000)).is_none());
+    }
+
+    #[test]
+    fn test_cache_save_load() {
+        let path = std::env::temp_dir().join(format!("kanidm_unixd_test_{}", std::process::id()));
+        let mut cache = CacheState::default();
+        cache.update_user(
+            test_token("testuser", "cc8e95b4-c24f-4d68-ba54-8bed76f63930", 70000),
+            100,
+        );
+        cache.save(&path).expect("Failed to save cache");
+
+        let loaded = CacheState::load(&path);
+        let _ = std::fs::remove_file(&path);
+        let u = loaded.get_user(&Id::Gid(70000)).unwrap();
+        assert_eq!(u.token.name, "testuser");
+        assert_eq!(u.expiry, 100);
+    }
+}
@@ -0,0 +1,25 @@
+use crate::constants::DEFAULT_CONN_TIMEOUT;
+use crate::unix_proto::{ClientRequest, ClientResponse};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::Shutdown;
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+// Ask kanidm_unixd one thing, and wait for the answer. This is what the nss
+// and pam modules use, so it must not need anything set up beforehand.
+pub fn call_daemon_blocking(path: &str, req: &ClientRequest) -> Result<ClientResponse, io::Error> {
+    let timeout = Some(Duration::from_secs(DEFAULT_CONN_TIMEOUT));
+    let mut stream = UnixStream::connect(path)?;
+    stream.set_read_timeout(timeout)?;
+    stream.set_write_timeout(timeout)?;
+
+    let mut data =
+        serde_json::to_vec(req).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    data.push(b'\n');
+    stream.write_all(&data)?;
+    stream.shutdown(Shutdown::Write)?;
+
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    serde_json::from_str(line.as_str()).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
@@ -0,0 +1,12 @@
+pub const DEFAULT_CONFIG_PATH: &str = "/etc/kanidm/unixd";
+pub const DEFAULT_SOCK_PATH: &str = "/var/run/kanidm-unixd/sock";
+pub const DEFAULT_CACHE_PATH: &str = "/var/cache/kanidm-unixd/cache.json";
+// How long, in seconds, a cached account or group is trusted before asking
+// the server again. Past this they're still served if the server is down.
+pub const DEFAULT_CACHE_TIMEOUT: u64 = 300;
+// Used when an account has no homedirectory of its own.
+pub const DEFAULT_HOME_PREFIX: &str = "/home/";
+pub const DEFAULT_SHELL: &str = "/bin/sh";
+// How long the nss and pam modules wait on the daemon. Authentication may
+// go to the server, so this is generous.
+pub const DEFAULT_CONN_TIMEOUT: u64 = 30;
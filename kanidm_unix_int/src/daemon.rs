@@ -0,0 +1,146 @@
+// kanidm_unixd - resolves posix accounts and groups, and checks passwords, for
+// the nss and pam modules on this machine. It must run as root, as its cache
+// holds password hashes.
+//
+//   kanidm_unixd [-d] [-c /etc/kanidm/unixd]
+#![deny(warnings)]
+
+#[macro_use]
+extern crate log;
+
+use kanidm_client::KanidmClient;
+use kanidm_unix_common::constants::DEFAULT_CONFIG_PATH;
+use kanidm_unix_common::provider::KanidmProvider;
+use kanidm_unix_common::resolver::Resolver;
+use kanidm_unix_common::unix_config::KanidmUnixdConfig;
+use kanidm_unix_common::unix_proto::{ClientRequest, ClientResponse};
+
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+type SharedResolver = Arc<Mutex<Resolver<KanidmProvider>>>;
+
+fn current_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn handle_client(stream: UnixStream, resolver: SharedResolver) {
+    let mut line = String::new();
+    if let Err(e) = BufReader::new(&stream).read_line(&mut line) {
+        error!("Failed to read request -> {:?}", e);
+        return;
+    }
+
+    let resp = match serde_json::from_str::<ClientRequest>(line.as_str()) {
+        Ok(req) => {
+            // The password must never reach the log.
+            match &req {
+                ClientRequest::PamAuthenticate(name, _) => debug!("pam authenticate {}", name),
+                r => debug!("{:?}", r),
+            };
+            // One request at a time, so the cache is only ever written by
+            // one of them. Lookups are quick enough that this is fine.
+            match resolver.lock() {
+                Ok(mut r) => r.handle_request(req, current_time()),
+                Err(_) => ClientResponse::Error,
+            }
+        }
+        Err(e) => {
+            error!("Invalid request -> {:?}", e);
+            ClientResponse::Error
+        }
+    };
+
+    let mut data = match serde_json::to_vec(&resp) {
+        Ok(d) => d,
+        Err(e) => {
+            error!("Failed to serialise response -> {:?}", e);
+            return;
+        }
+    };
+    data.push(b'\n');
+    if let Err(e) = (&stream).write_all(&data) {
+        error!("Failed to write response -> {:?}", e);
+    }
+}
+
+fn main() {
+    let mut debug = false;
+    let mut config_path = DEFAULT_CONFIG_PATH.to_string();
+    let mut args = std::env::args().skip(1);
+    while let Some(a) = args.next() {
+        match a.as_str() {
+            "-d" => debug = true,
+            "-c" => match args.next() {
+                Some(p) => config_path = p,
+                None => {
+                    eprintln!("-c needs a path");
+                    std::process::exit(1);
+                }
+            },
+            _ => {
+                eprintln!("usage: kanidm_unixd [-d] [-c config]");
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if debug {
+        ::std::env::set_var("RUST_LOG", "kanidm_unixd=debug,kanidm_unix_common=debug");
+    } else {
+        ::std::env::set_var("RUST_LOG", "kanidm_unixd=info,kanidm_unix_common=info");
+    }
+    env_logger::init();
+
+    let config = match KanidmUnixdConfig::read_options_from_optional_config(&config_path) {
+        Ok(c) => c,
+        Err(e) => {
+            error!("Failed to read config {} -> {:?}", config_path, e);
+            std::process::exit(1);
+        }
+    };
+
+    // Anonymous sign in happens on first use, so that starting before the
+    // network is up doesn't fail.
+    let client = KanidmClient::new(config.addr.as_str(), config.ca_path.as_deref());
+    let resolver: SharedResolver = Arc::new(Mutex::new(Resolver::new(
+        KanidmProvider::new(client),
+        &config,
+    )));
+
+    let sock_path = Path::new(config.sock_path.as_str());
+    // Left over from a previous run.
+    let _ = fs::remove_file(sock_path);
+    let listener = match UnixListener::bind(sock_path) {
+        Ok(l) => l,
+        Err(e) => {
+            error!("Failed to bind {} -> {:?}", config.sock_path, e);
+            std::process::exit(1);
+        }
+    };
+    // Every process on the machine resolves names through nss.
+    if let Err(e) = fs::set_permissions(sock_path, fs::Permissions::from_mode(0o777)) {
+        error!("Failed to set socket permissions -> {:?}", e);
+        std::process::exit(1);
+    }
+
+    info!("kanidm_unixd listening on {}", config.sock_path);
+    for stream in listener.incoming() {
+        match stream {
+            Ok(s) => {
+                let r = resolver.clone();
+                thread::spawn(move || handle_client(s, r));
+            }
+            Err(e) => error!("Failed to accept connection -> {:?}", e),
+        }
+    }
+}
@@ -0,0 +1,20 @@
+// Shared by the kanidm_unixd caching daemon and the nss and pam modules that
+// talk to it. The modules are loaded into every process on the machine, so
+// they do as little as possible: they ask the daemon over a unix socket, and
+// the daemon is what talks to the kanidm server, caches what it learnt, and
+// keeps answering from that cache while the server can't be reached.
+#![deny(warnings)]
+#![warn(unused_extern_crates)]
+
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate log;
+
+pub mod cache;
+pub mod client;
+pub mod constants;
+pub mod provider;
+pub mod resolver;
+pub mod unix_config;
+pub mod unix_proto;
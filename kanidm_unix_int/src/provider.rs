@@ -0,0 +1,88 @@
+// Where the resolver gets its answers when the cache can't give them. This is
+// a trait so the resolver's offline behaviour can be tested without a server.
+use kanidm_client::{ClientError, KanidmClient};
+use kanidm_proto::v1::{UnixGroupToken, UnixUserToken};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum IdProviderError {
+    // The server couldn't be reached, so the cache should answer instead.
+    Offline,
+}
+
+pub trait IdProvider {
+    // Ok(None) means the server answered, and there is no such posix account.
+    fn get_user_token(&self, id: &str) -> Result<Option<UnixUserToken>, IdProviderError>;
+
+    fn get_group_token(&self, id: &str) -> Result<Option<UnixGroupToken>, IdProviderError>;
+
+    fn authenticate(&self, name: &str, password: &str) -> Result<bool, IdProviderError>;
+}
+
+// The daemon reads as anonymous, which the unix read access control allows.
+// Logins are checked with a session of their own, so they never change who
+// the daemon is.
+pub struct KanidmProvider {
+    client: KanidmClient,
+}
+
+impl KanidmProvider {
+    pub fn new(client: KanidmClient) -> Self {
+        KanidmProvider { client: client }
+    }
+
+    // Our anonymous session may have expired, so one failure to authorise is
+    // followed by signing in again and a retry.
+    fn with_session<T, F>(&self, f: F) -> Result<Option<T>, IdProviderError>
+    where
+        F: Fn(&KanidmClient) -> Result<T, ClientError>,
+    {
+        match f(&self.client) {
+            Ok(t) => Ok(Some(t)),
+            Err(ClientError::Transport(e)) => {
+                debug!("kanidm server unreachable -> {:?}", e);
+                Err(IdProviderError::Offline)
+            }
+            Err(ClientError::Http(s, _)) if s.as_u16() == 401 => {
+                match self.client.auth_anonymous() {
+                    Ok(_) => {}
+                    Err(ClientError::Transport(_)) => return Err(IdProviderError::Offline),
+                    Err(e) => {
+                        error!("Failed to authenticate as anonymous -> {:?}", e);
+                        return Ok(None);
+                    }
+                };
+                match f(&self.client) {
+                    Ok(t) => Ok(Some(t)),
+                    Err(ClientError::Transport(_)) => Err(IdProviderError::Offline),
+                    Err(_) => Ok(None),
+                }
+            }
+            Err(e) => {
+                debug!("kanidm server refused -> {:?}", e);
+                Ok(None)
+            }
+        }
+    }
+}
+
+impl IdProvider for KanidmProvider {
+    fn get_user_token(&self, id: &str) -> Result<Option<UnixUserToken>, IdProviderError> {
+        self.with_session(|c| c.idm_account_unix_token_get(id))
+    }
+
+    fn get_group_token(&self, id: &str) -> Result<Option<UnixGroupToken>, IdProviderError> {
+        self.with_session(|c| c.idm_group_unix_token_get(id))
+    }
+
+    fn authenticate(&self, name: &str, password: &str) -> Result<bool, IdProviderError> {
+        let session = self.client.new_session();
+        match session.auth_simple_password(name, password) {
+            Ok(_) => Ok(true),
+            Err(ClientError::Transport(_)) => Err(IdProviderError::Offline),
+            Err(e) => {
+                debug!("kanidm server denied authentication -> {:?}", e);
+                Ok(false)
+            }
+        }
+    }
+}
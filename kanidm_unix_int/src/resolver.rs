@@ -0,0 +1,341 @@
+// Answers the nss and pam modules. A fresh cache entry is used as is, anything
+// else is asked of the server, and if the server can't be reached whatever
+// the cache has is used regardless of age.
+use crate::cache::{CacheState, CachedPassword, Id};
+use crate::provider::{IdProvider, IdProviderError};
+use crate::unix_config::KanidmUnixdConfig;
+use crate::unix_proto::{ClientRequest, ClientResponse, NssGroup, NssUser};
+
+pub struct Resolver<P: IdProvider> {
+    provider: P,
+    cache: CacheState,
+    // None keeps the cache only in memory.
+    cache_path: Option<String>,
+    cache_timeout: u64,
+    home_prefix: String,
+    default_shell: String,
+    pam_allowed_login_groups: Vec<String>,
+}
+
+impl<P: IdProvider> Resolver<P> {
+    pub fn new(provider: P, config: &KanidmUnixdConfig) -> Self {
+        Resolver {
+            provider: provider,
+            cache: CacheState::load(config.cache_path.as_str()),
+            cache_path: Some(config.cache_path.clone()),
+            cache_timeout: config.cache_timeout,
+            home_prefix: config.home_prefix.clone(),
+            default_shell: config.default_shell.clone(),
+            pam_allowed_login_groups: config.pam_allowed_login_groups.clone(),
+        }
+    }
+
+    fn save(&self) {
+        if let Some(path) = self.cache_path.as_ref() {
+            if let Err(e) = self.cache.save(path) {
+                error!("Failed to save cache -> {:?}", e);
+            }
+        }
+    }
+
+    fn refresh_user(&mut self, id: &Id, ct: u64) {
+        if let Some(u) = self.cache.get_user(id) {
+            if u.expiry > ct {
+                return;
+            }
+        }
+        match self.provider.get_user_token(id.to_server_id().as_str()) {
+            Ok(Some(token)) => {
+                self.cache.update_user(token, ct + self.cache_timeout);
+                self.save();
+            }
+            Ok(None) => {
+                if self.cache.remove_user(id) {
+                    self.save();
+                }
+            }
+            Err(IdProviderError::Offline) => {
+                debug!("Offline, using cached account {:?}", id);
+            }
+        }
+    }
+
+    fn refresh_group(&mut self, id: &Id, ct: u64) {
+        if let Some(g) = self.cache.get_group(id) {
+            if g.expiry > ct {
+                return;
+            }
+        }
+        match self.provider.get_group_token(id.to_server_id().as_str()) {
+            Ok(Some(token)) => {
+                self.cache.update_group(token, ct + self.cache_timeout);
+                self.save();
+            }
+            Ok(None) => {
+                if self.cache.remove_group(id) {
+                    self.save();
+                }
+            }
+            Err(IdProviderError::Offline) => {
+                debug!("Offline, using cached group {:?}", id);
+            }
+        }
+    }
+
+    pub fn get_nssaccount(&mut self, id: Id, ct: u64) -> Option<NssUser> {
+        self.refresh_user(&id, ct);
+        self.cache.get_user(&id).map(|u| {
+            self.cache
+                .to_nss_user(u, self.home_prefix.as_str(), self.default_shell.as_str())
+        })
+    }
+
+    pub fn get_nssgroup(&mut self, id: Id, ct: u64) -> Option<NssGroup> {
+        self.refresh_group(&id, ct);
+        self.cache
+            .get_group(&id)
+            .map(|g| self.cache.to_nss_group(g))
+    }
+
+    // There's no listing posix accounts on the server, so enumeration is of
+    // what has been seen here before.
+    pub fn get_nssaccounts(&self) -> Vec<NssUser> {
+        self.cache
+            .users()
+            .map(|u| {
+                self.cache
+                    .to_nss_user(u, self.home_prefix.as_str(), self.default_shell.as_str())
+            })
+            .collect()
+    }
+
+    pub fn get_nssgroups(&self) -> Vec<NssGroup> {
+        self.cache
+            .groups()
+            .map(|g| self.cache.to_nss_group(g))
+            .collect()
+    }
+
+    pub fn pam_account_allowed(&mut self, name: &str, ct: u64) -> Option<bool> {
+        let id = Id::Name(name.to_string());
+        self.refresh_user(&id, ct);
+        let u = self.cache.get_user(&id)?;
+        if self.pam_allowed_login_groups.is_empty() {
+            return Some(true);
+        }
+        Some(u.token.groups.iter().any(|g| {
+            self.pam_allowed_login_groups
+                .iter()
+                .any(|a| a == &g.name || a == &g.uuid)
+        }))
+    }
+
+    pub fn pam_authenticate(&mut self, name: &str, password: &str, ct: u64) -> Option<bool> {
+        let id = Id::Name(name.to_string());
+        self.refresh_user(&id, ct);
+        // Only posix accounts may log in, everyone else is for another
+        // pam module to decide.
+        self.cache.get_user(&id)?;
+
+        match self.provider.authenticate(name, password) {
+            Ok(true) => {
+                self.cache.set_password(name, CachedPassword::new(password));
+                self.save();
+                Some(true)
+            }
+            Ok(false) => {
+                // The server is the authority, so what was cached is no
+                // longer the right password.
+                self.cache.set_password(name, None);
+                self.save();
+                Some(false)
+            }
+            Err(IdProviderError::Offline) => {
+                debug!("Offline, checking cached password for {}", name);
+                let ok = self
+                    .cache
+                    .get_user(&id)
+                    .and_then(|u| u.password.as_ref())
+                    .map(|c| c.verify(password))
+                    .unwrap_or(false);
+                Some(ok)
+            }
+        }
+    }
+
+    pub fn invalidate(&mut self) {
+        self.cache.invalidate();
+        self.save();
+    }
+
+    pub fn handle_request(&mut self, req: ClientRequest, ct: u64) -> ClientResponse {
+        match req {
+            ClientRequest::NssAccounts => ClientResponse::NssAccounts(self.get_nssaccounts()),
+            ClientRequest::NssAccountByUid(gid) => {
+                ClientResponse::NssAccount(self.get_nssaccount(Id::Gid(gid), ct))
+            }
+            ClientRequest::NssAccountByName(name) => {
+                ClientResponse::NssAccount(self.get_nssaccount(Id::Name(name), ct))
+            }
+            ClientRequest::NssGroups => ClientResponse::NssGroups(self.get_nssgroups()),
+            ClientRequest::NssGroupByGid(gid) => {
+                ClientResponse::NssGroup(self.get_nssgroup(Id::Gid(gid), ct))
+            }
+            ClientRequest::NssGroupByName(name) => {
+                ClientResponse::NssGroup(self.get_nssgroup(Id::Name(name), ct))
+            }
+            ClientRequest::PamAuthenticate(name, password) => ClientResponse::PamStatus(
+                self.pam_authenticate(name.as_str(), password.as_str(), ct),
+            ),
+            ClientRequest::PamAccountAllowed(name) => {
+                ClientResponse::PamStatus(self.pam_account_allowed(name.as_str(), ct))
+            }
+            ClientRequest::InvalidateCache => {
+                self.invalidate();
+                ClientResponse::Ok
+            }
+            ClientRequest::Status => ClientResponse::Ok,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Resolver;
+    use crate::cache::tests::test_token;
+    use crate::cache::{CacheState, Id};
+    use crate::provider::{IdProvider, IdProviderError};
+    use kanidm_proto::v1::{UnixGroupToken, UnixUserToken};
+    use std::cell::Cell;
+
+    static TEST_UUID: &str = "cc8e95b4-c24f-4d68-ba54-8bed76f63930";
+
+    // A server with one posix account, that can be unplugged.
+    struct TestProvider {
+        online: Cell<bool>,
+        exists: Cell<bool>,
+        lookups: Cell<usize>,
+    }
+
+    impl IdProvider for TestProvider {
+        fn get_user_token(&self, id: &str) -> Result<Option<UnixUserToken>, IdProviderError> {
+            if !self.online.get() {
+                return Err(IdProviderError::Offline);
+            }
+            self.lookups.set(self.lookups.get() + 1);
+            if self.exists.get() && (id == "testuser" || id == "70000") {
+                Ok(Some(test_token("testuser", TEST_UUID, 70000)))
+            } else {
+                Ok(None)
+            }
+        }
+
+        fn get_group_token(&self, _id: &str) -> Result<Option<UnixGroupToken>, IdProviderError> {
+            if !self.online.get() {
+                return Err(IdProviderError::Offline);
+            }
+            Ok(None)
+        }
+
+        fn authenticate(&self, name: &str, password: &str) -> Result<bool, IdProviderError> {
+            if !self.online.get() {
+                return Err(IdProviderError::Offline);
+            }
+            Ok(name == "testuser" && password == "password")
+        }
+    }
+
+    fn test_resolver() -> Resolver<TestProvider> {
+        Resolver {
+            provider: TestProvider {
+                online: Cell::new(true),
+                exists: Cell::new(true),
+                lookups: Cell::new(0),
+            },
+            cache: CacheState::default(),
+            cache_path: None,
+            cache_timeout: 300,
+            home_prefix: "/home/".to_string(),
+            default_shell: "/bin/sh".to_string(),
+            pam_allowed_login_groups: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_resolver_cache_timeout() {
+        let mut r = test_resolver();
+        assert!(r.get_nssaccount(Id::Gid(70000), 0).is_some());
+        assert!(r
+            .get_nssaccount(Id::Name("testuser".to_string()), 100)
+            .is_some());
+        assert_eq!(r.provider.lookups.get(), 1);
+        // Expired, so the server is asked again.
+        assert!(r.get_nssaccount(Id::Gid(70000), 300).is_some());
+        assert_eq!(r.provider.lookups.get(), 2);
+        assert!(r
+            .get_nssaccount(Id::Name("nobody".to_string()), 300)
+            .is_none());
+    }
+
+    #[test]
+    fn test_resolver_offline() {
+        let mut r = test_resolver();
+        // Nothing cached, so offline knows nothing.
+        r.provider.online.set(false);
+        assert!(r.get_nssaccount(Id::Gid(70000), 0).is_none());
+        assert_eq!(r.pam_authenticate("testuser", "password", 0), None);
+
+        r.provider.online.set(true);
+        assert_eq!(r.pam_authenticate("testuser", "password", 0), Some(true));
+
+        // Long after it expired, the cache still answers.
+        r.provider.online.set(false);
+        let nu = r.get_nssaccount(Id::Gid(70000), 10000).unwrap();
+        assert_eq!(nu.name, "testuser");
+        assert_eq!(
+            r.get_nssgroup(Id::Gid(2001), 10000).unwrap().members,
+            vec!["testuser"]
+        );
+        assert_eq!(
+            r.pam_authenticate("testuser", "password", 10000),
+            Some(true)
+        );
+        assert_eq!(r.pam_authenticate("testuser", "wrong", 10000), Some(false));
+    }
+
+    #[test]
+    fn test_resolver_password_changed() {
+        let mut r = test_resolver();
+        assert_eq!(r.pam_authenticate("testuser", "password", 0), Some(true));
+        // The server refusing it forgets the cached password, so it can't be
+        // used offline later.
+        assert_eq!(r.pam_authenticate("testuser", "other", 0), Some(false));
+        r.provider.online.set(false);
+        assert_eq!(
+            r.pam_authenticate("testuser", "password", 10000),
+            Some(false)
+        );
+    }
+
+    #[test]
+    fn test_resolver_account_removed() {
+        let mut r = test_resolver();
+        assert_eq!(r.pam_authenticate("testuser", "password", 0), Some(true));
+        r.provider.exists.set(false);
+        // Once the server says it's gone, it's gone offline too.
+        assert!(r.get_nssaccount(Id::Gid(70000), 10000).is_none());
+        r.provider.online.set(false);
+        assert_eq!(r.pam_authenticate("testuser", "password", 20000), None);
+    }
+
+    #[test]
+    fn test_resolver_allowed_groups() {
+        let mut r = test_resolver();
+        assert_eq!(r.pam_account_allowed("testuser", 0), Some(true));
+        r.pam_allowed_login_groups = vec!["posix_group".to_string()];
+        assert_eq!(r.pam_account_allowed("testuser", 0), Some(true));
+        r.pam_allowed_login_groups = vec!["other_group".to_string()];
+        assert_eq!(r.pam_account_allowed("testuser", 0), Some(false));
+        assert_eq!(r.pam_account_allowed("nobody", 0), None);
+    }
+}
@@ -0,0 +1,84 @@
+use crate::constants::{
+    DEFAULT_CACHE_PATH, DEFAULT_CACHE_TIMEOUT, DEFAULT_HOME_PREFIX, DEFAULT_SHELL,
+    DEFAULT_SOCK_PATH,
+};
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+// kanidm_unixd's configuration, as json, IE:
+//
+//   {
+//       "addr": "https://idm.example.com:8443",
+//       "ca_path": "/etc/kanidm/ca.pem",
+//       "pam_allowed_login_groups": ["posix_login"]
+//   }
+//
+// Everything but addr has a default.
+#[derive(Deserialize, Debug, Clone)]
+pub struct KanidmUnixdConfig {
+    pub addr: String,
+    #[serde(default)]
+    pub ca_path: Option<String>,
+    #[serde(default = "default_sock_path")]
+    pub sock_path: String,
+    #[serde(default = "default_cache_path")]
+    pub cache_path: String,
+    #[serde(default = "default_cache_timeout")]
+    pub cache_timeout: u64,
+    #[serde(default = "default_home_prefix")]
+    pub home_prefix: String,
+    #[serde(default = "default_shell")]
+    pub default_shell: String,
+    // If not empty, pam only lets in members of these groups, by name.
+    #[serde(default)]
+    pub pam_allowed_login_groups: Vec<String>,
+}
+
+fn default_sock_path() -> String {
+    DEFAULT_SOCK_PATH.to_string()
+}
+
+fn default_cache_path() -> String {
+    DEFAULT_CACHE_PATH.to_string()
+}
+
+fn default_cache_timeout() -> u64 {
+    DEFAULT_CACHE_TIMEOUT
+}
+
+fn default_home_prefix() -> String {
+    DEFAULT_HOME_PREFIX.to_string()
+}
+
+fn default_shell() -> String {
+    DEFAULT_SHELL.to_string()
+}
+
+impl KanidmUnixdConfig {
+    pub fn read_options_from_optional_config<P: AsRef<Path>>(
+        config_path: P,
+    ) -> Result<Self, io::Error> {
+        let mut f = File::open(config_path)?;
+        let mut contents = String::new();
+        f.read_to_string(&mut contents)?;
+        serde_json::from_str(contents.as_str())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::KanidmUnixdConfig;
+    use crate::constants::{DEFAULT_CACHE_TIMEOUT, DEFAULT_SOCK_PATH};
+
+    #[test]
+    fn test_unix_config_defaults() {
+        let c: KanidmUnixdConfig =
+            serde_json::from_str(r#"{"addr": "https://localhost:8443"}"#).unwrap();
+        assert_eq!(c.sock_path, DEFAULT_SOCK_PATH);
+        assert_eq!(c.cache_timeout, DEFAULT_CACHE_TIMEOUT);
+        assert!(c.ca_path.is_none());
+        assert!(c.pam_allowed_login_groups.is_empty());
+    }
+}
@@ -0,0 +1,49 @@
+// What the nss and pam modules ask kanidm_unixd, and its answers. Each is one
+// line of json on the socket, and there is one request per connection.
+
+// An account as nss presents it. The uid is always the gid, as each account
+// is its own user private group.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct NssUser {
+    pub name: String,
+    pub gid: u32,
+    pub gecos: String,
+    pub homedir: String,
+    pub shell: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct NssGroup {
+    pub name: String,
+    pub gid: u32,
+    // Only the accounts the cache knows of, as the server's group token
+    // doesn't carry the members.
+    pub members: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum ClientRequest {
+    NssAccounts,
+    NssAccountByUid(u32),
+    NssAccountByName(String),
+    NssGroups,
+    NssGroupByGid(u32),
+    NssGroupByName(String),
+    PamAuthenticate(String, String),
+    PamAccountAllowed(String),
+    InvalidateCache,
+    Status,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum ClientResponse {
+    NssAccounts(Vec<NssUser>),
+    NssAccount(Option<NssUser>),
+    NssGroups(Vec<NssGroup>),
+    NssGroup(Option<NssGroup>),
+    // None when the account isn't known to kanidm at all, so pam can move
+    // on to the next module.
+    PamStatus(Option<bool>),
+    Ok,
+    Error,
+}
@@ -36,6 +36,31 @@ lazy_static! {
     static ref CLASS_ACD: PartialValue = PartialValue::new_class("access_control_delete");
     static ref CLASS_ACM: PartialValue = PartialValue::new_class("access_control_modify");
     static ref CLASS_ACP: PartialValue = PartialValue::new_class("access_control_profile");
+    static ref CLASS_ANONYMOUS_POLICY: PartialValue = PartialValue::new_class("anonymous_policy");
+}
+
+// The attributes of allowed_attrs that attr_allow leaves, if there is one.
+fn restrict_attrs<'a>(
+    allowed_attrs: BTreeSet<&'a str>,
+    attr_allow: Option<&BTreeSet<String>>,
+) -> BTreeSet<&'a str> {
+    match attr_allow {
+        Some(attr_allow) => allowed_attrs
+            .into_iter()
+            .filter(|a| attr_allow.contains(*a))
+            .collect(),
+        None => allowed_attrs,
+    }
+}
+
+// Credentials, and the access controls themselves, can only be written by a
+// session that authenticated recently, whatever the acps allow. That way a
+// stolen session cookie can't be used to take over an account, or to grant
+// itself more.
+static ELEVATED_ATTRS: [&str; 3] = ["primary_credential", "radius_secret", "ssh_publickey"];
+
+fn requires_elevation(attr: &str) -> bool {
+    ELEVATED_ATTRS.contains(&attr) || attr.starts_with("acp_")
 }
 
 // =========================================================================
@@ -337,6 +362,44 @@ impl AccessControlProfile {
     }
 }
 
+// What anonymous may search, whatever the acps allow. This is read from the
+// anonymous_policy entry, and reloaded with the acps when it changes.
+#[derive(Debug, Clone)]
+pub struct AnonymousPolicy {
+    allow_search: bool,
+    // If set, anonymous may only filter on and read these.
+    attr_allow: Option<BTreeSet<String>>,
+}
+
+impl AnonymousPolicy {
+    // Until the policy entry exists, anonymous has what the acps allow.
+    pub fn new() -> Self {
+        AnonymousPolicy {
+            allow_search: true,
+            attr_allow: None,
+        }
+    }
+
+    pub fn try_from(
+        audit: &mut AuditScope,
+        value: &Entry<EntryValid, EntryCommitted>,
+    ) -> Result<Self, OperationError> {
+        if !value.attribute_value_pres("class", &CLASS_ANONYMOUS_POLICY) {
+            audit_log!(audit, "class anonymous_policy not present.");
+            return Err(OperationError::InvalidACPState(
+                "Missing anonymous_policy".to_string(),
+            ));
+        }
+
+        Ok(AnonymousPolicy {
+            allow_search: value
+                .get_ava_single_bool("anonymous_allow_search")
+                .unwrap_or(true),
+            attr_allow: value.get_ava_set_string("anonymous_attr_allow"),
+        })
+    }
+}
+
 // =========================================================================
 // ACP transactions and management for server bits.
 // =========================================================================
@@ -348,6 +411,7 @@ pub struct AccessControlsInner {
     acps_create: BTreeMap<Uuid, AccessControlCreate>,
     acps_modify: BTreeMap<Uuid, AccessControlModify>,
     acps_delete: BTreeMap<Uuid, AccessControlDelete>,
+    anonymous: AnonymousPolicy,
 }
 
 impl AccessControlsInner {
@@ -357,6 +421,7 @@ impl AccessControlsInner {
             acps_create: BTreeMap::new(),
             acps_modify: BTreeMap::new(),
             acps_delete: BTreeMap::new(),
+            anonymous: AnonymousPolicy::new(),
         }
     }
 }
@@ -390,6 +455,17 @@ pub trait AccessControlsTransaction {
         // Some useful references we'll use for the remainder of the operation
         let state = self.get_inner();
 
+        // Anonymous may be further restricted by the anonymous policy.
+        let anon_attr_allow = if se.event.is_anonymous() {
+            if !state.anonymous.allow_search {
+                audit_log!(audit, "anonymous search is disabled by policy");
+                return Err(OperationError::AccessDenied);
+            }
+            state.anonymous.attr_allow.as_ref()
+        } else {
+            None
+        };
+
         // First get the set of acps that apply to this receiver
         let related_acp: Vec<&AccessControlSearch> = state
             .acps_search
@@ -484,6 +560,7 @@ pub trait AccessControlsTransaction {
                     })
                     .flatten()
                     .collect();
+                let allowed_attrs = restrict_attrs(allowed_attrs, anon_attr_allow);
 
                 audit_log!(audit, "-- for entry         --> {:?}", e.get_uuid());
                 audit_log!(audit, "allowed attributes   --> {:?}", allowed_attrs);
@@ -542,6 +619,17 @@ pub trait AccessControlsTransaction {
         // Some useful references we'll use for the remainder of the operation
         let state = self.get_inner();
 
+        let anon_attr_allow = if se.event.is_anonymous() {
+            if !state.anonymous.allow_search {
+                // search_filter_entries has refused this already.
+                audit_log!(audit, "anonymous search is disabled by policy");
+                return Ok(Vec::new());
+            }
+            state.anonymous.attr_allow.as_ref()
+        } else {
+            None
+        };
+
         // Get the relevant acps for this receiver.
         let related_acp: Vec<&AccessControlSearch> = state
             .acps_search
@@ -641,6 +729,7 @@ pub trait AccessControlsTransaction {
                     })
                     .flatten()
                     .collect();
+                let allowed_attrs = restrict_attrs(allowed_attrs, anon_attr_allow);
 
                 // Remove all others that are present on the entry.
                 audit_log!(audit, "-- for entry         --> {:?}", e.get_uuid());
@@ -785,6 +874,17 @@ pub trait AccessControlsTransaction {
         audit_log!(audit, "Requested remove set: {:?}", requested_rem);
         audit_log!(audit, "Requested class set: {:?}", requested_classes);
 
+        if !me.event.elevated {
+            if let Some(a) = requested_pres
+                .iter()
+                .chain(requested_rem.iter())
+                .find(|a| requires_elevation(a))
+            {
+                audit_log!(audit, "Modifying {} requires an elevated session", a);
+                return Err(OperationError::ElevationRequired);
+            }
+        }
+
         let r = entries.iter().fold(true, |acc, e| {
             if acc == false {
                 false
@@ -906,6 +1006,17 @@ pub trait AccessControlsTransaction {
 
         audit_log!(audit, "Related acc -> {:?}", related_acp);
 
+        if !ce.event.elevated {
+            if let Some(a) = entries
+                .iter()
+                .flat_map(|e| e.get_ava_names())
+                .find(|a| requires_elevation(a))
+            {
+                audit_log!(audit, "Creating {} requires an elevated session", a);
+                return Err(OperationError::ElevationRequired);
+            }
+        }
+
         // For each entry
         let r = entries.iter().fold(true, |acc, e| {
             if acc == false {
@@ -1175,6 +1286,11 @@ impl<'a> AccessControlsWriteTransaction<'a> {
         Ok(())
     }
 
+    pub fn update_anonymous(&mut self, policy: AnonymousPolicy) -> Result<(), OperationError> {
+        self.get_inner_mut().anonymous = policy;
+        Ok(())
+    }
+
     pub fn commit(self) -> Result<(), OperationError> {
         self.inner.commit();
         Ok(())
@@ -1240,6 +1356,7 @@ mod tests {
     // use crate::proto_v1::Filter as ProtoFilter;
     use crate::constants::{JSON_ADMIN_V1, JSON_ANONYMOUS_V1, JSON_TESTPERSON1, JSON_TESTPERSON2};
     use crate::value::{PartialValue, Value};
+    use kanidm_proto::v1::OperationError;
 
     macro_rules! acp_from_entry_err {
         (
@@ -1283,7 +1400,7 @@ mod tests {
             // really protects us *a lot* here, but it's nice to have defence and
             // layers of validation.
 
-            let qs_write = qs.write();
+            let qs_write = qs.write().expect("Failed to begin txn");
 
             acp_from_entry_err!(
                 audit,
@@ -1359,7 +1476,7 @@ mod tests {
     #[test]
     fn test_access_acp_delete_parser() {
         run_test!(|qs: &QueryServer, audit: &mut AuditScope| {
-            let qs_write = qs.write();
+            let qs_write = qs.write().expect("Failed to begin txn");
 
             acp_from_entry_err!(
                 audit,
@@ -1409,7 +1526,7 @@ mod tests {
     fn test_access_acp_search_parser() {
         run_test!(|qs: &QueryServer, audit: &mut AuditScope| {
             // Test that parsing search access controls works.
-            let qs_write = qs.write();
+            let qs_write = qs.write().expect("Failed to begin txn");
 
             // Missing class acp
             acp_from_entry_err!(
@@ -1508,7 +1625,7 @@ mod tests {
     fn test_access_acp_modify_parser() {
         run_test!(|qs: &QueryServer, audit: &mut AuditScope| {
             // Test that parsing modify access controls works.
-            let qs_write = qs.write();
+            let qs_write = qs.write().expect("Failed to begin txn");
 
             acp_from_entry_err!(
                 audit,
@@ -1585,7 +1702,7 @@ mod tests {
     fn test_access_acp_create_parser() {
         run_test!(|qs: &QueryServer, audit: &mut AuditScope| {
             // Test that parsing create access controls works.
-            let qs_write = qs.write();
+            let qs_write = qs.write().expect("Failed to begin txn");
 
             acp_from_entry_err!(
                 audit,
@@ -1663,7 +1780,7 @@ mod tests {
             // given a single &str, we can evaluate all types from a single record.
             // This is valid, and could exist, IE a rule to allow create, search and modify
             // over a single scope.
-            let qs_write = qs.write();
+            let qs_write = qs.write().expect("Failed to begin txn");
 
             let e: &str = r#"{
                     "valid": null,
@@ -1950,6 +2067,50 @@ mod tests {
         }};
     }
 
+    #[test]
+    fn test_access_modify_elevation() {
+        let e1: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(JSON_TESTPERSON1);
+        let ev1 = unsafe { e1.to_valid_committed() };
+        let r_set = vec![ev1.clone()];
+
+        let mut me_cred = unsafe {
+            ModifyEvent::new_impersonate_entry_ser(
+                JSON_ADMIN_V1,
+                filter_all!(f_eq("name", PartialValue::new_iutf8s("testperson1"))),
+                modlist!([m_purge("ssh_publickey")]),
+            )
+        };
+        let acp_allow = unsafe {
+            AccessControlModify::from_raw(
+                "test_modify_allow",
+                "87bfe9b8-7600-431e-a492-1dde64bbc455",
+                filter_valid!(f_eq("name", PartialValue::new_iutf8s("admin"))),
+                filter_valid!(f_eq("name", PartialValue::new_iutf8s("testperson1"))),
+                "ssh_publickey",
+                "ssh_publickey",
+                "",
+            )
+        };
+
+        let ac = AccessControls::new();
+        let mut acw = ac.write();
+        acw.update_modify(vec![acp_allow])
+            .expect("Failed to update");
+        let acw = acw;
+        let mut audit = AuditScope::new("test_acp_modify");
+
+        // The acp allows it, but the session has to have authenticated recently.
+        assert_eq!(
+            acw.modify_allow_operation(&mut audit, &me_cred, &r_set),
+            Err(OperationError::ElevationRequired)
+        );
+        me_cred.event.elevated = true;
+        assert_eq!(
+            acw.modify_allow_operation(&mut audit, &me_cred, &r_set),
+            Ok(true)
+        );
+    }
+
     #[test]
     fn test_access_enforce_modify() {
         let e1: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(JSON_TESTPERSON1);
@@ -2,21 +2,39 @@ use std::sync::Arc;
 
 use crate::audit::AuditScope;
 
-use crate::async_log::EventLog;
-use crate::event::{AuthEvent, SearchEvent, SearchResult, WhoamiResult};
-use crate::idm::event::RadiusAuthTokenEvent;
-use kanidm_proto::v1::{OperationError, RadiusAuthToken};
+use crate::async_log::{AccessLogEvent, EventLog};
+use crate::be::BackendTransaction;
+use crate::event::{
+    AuthEvent, ChangesEvent, CompareEvent, Event, OnlineBackupEvent, ReplSupplyEvent, SearchEvent,
+    SearchResult, WhoamiResult,
+};
+use crate::idm::event::{RadiusAuthTokenEvent, UnixGroupTokenEvent, UnixUserTokenEvent};
+use kanidm_proto::v1::{
+    AccessJournalResponse, AttrUsage, Branding, ConsistencyError, DbPoolStats,
+    DeletePreviewResponse, DeleteRequest, DomainInfo, IndexStat, JwkSet, MembershipRequestRecord,
+    Oauth2AuthoriseRequest, Oauth2TokenRequest, Oauth2TokenResponse, OidcDiscoveryResponse,
+    OperationError, OperationsResponse, PurgeStats, RadiusAuthToken, ReportRecord, SlowQueryRecord,
+    StatusResponse, UnixGroupToken, UnixUserToken, WriteStatsRecord,
+};
 
 use crate::filter::{Filter, FilterInvalid};
 use crate::idm::server::IdmServer;
-use crate::server::{QueryServer, QueryServerTransaction};
+use crate::optrack::OpTracker;
+use crate::priority::{OpPriority, OpScheduler};
+use crate::server::{QueryServer, QueryServerReadTransaction, QueryServerTransaction};
+use crate::value::PartialValue;
 
+use kanidm_proto::query::parse_filter;
 use kanidm_proto::v1::Entry as ProtoEntry;
 use kanidm_proto::v1::{
-    AuthRequest, AuthResponse, SearchRequest, SearchResponse, UserAuthToken, WhoamiResponse,
+    AuthRequest, AuthResponse, AuthState, ChangesRequest, ChangesResponse, CompareRequest,
+    CompareResponse, ReplSupplyRequest, ReplSupplyResponse, SavedSearchRequest, SearchExplain,
+    SearchQueryRequest, SearchRequest, SearchResponse, UserAuthToken, WhoamiResponse,
 };
 
 use actix::prelude::*;
+use std::fs;
+use std::path::Path;
 use std::time::SystemTime;
 use uuid::Uuid;
 
@@ -75,6 +93,148 @@ impl Message for SearchMessage {
     type Result = Result<SearchResponse, OperationError>;
 }
 
+pub struct SearchQueryMessage {
+    pub uat: Option<UserAuthToken>,
+    pub req: SearchQueryRequest,
+}
+
+impl SearchQueryMessage {
+    pub fn new(uat: Option<UserAuthToken>, req: SearchQueryRequest) -> Self {
+        SearchQueryMessage { uat: uat, req: req }
+    }
+}
+
+impl Message for SearchQueryMessage {
+    type Result = Result<SearchResponse, OperationError>;
+}
+
+pub struct SavedSearchMessage {
+    pub uat: Option<UserAuthToken>,
+    pub name: String,
+    pub req: SavedSearchRequest,
+}
+
+impl SavedSearchMessage {
+    pub fn new(uat: Option<UserAuthToken>, name: String, req: SavedSearchRequest) -> Self {
+        SavedSearchMessage {
+            uat: uat,
+            name: name,
+            req: req,
+        }
+    }
+}
+
+impl Message for SavedSearchMessage {
+    type Result = Result<SearchResponse, OperationError>;
+}
+
+pub struct ReportsMessage {
+    pub uat: Option<UserAuthToken>,
+    pub name: String,
+}
+
+impl ReportsMessage {
+    pub fn new(uat: Option<UserAuthToken>, name: String) -> Self {
+        ReportsMessage {
+            uat: uat,
+            name: name,
+        }
+    }
+}
+
+impl Message for ReportsMessage {
+    type Result = Result<Vec<ReportRecord>, OperationError>;
+}
+
+pub struct MembershipRequestsMessage {
+    pub uat: Option<UserAuthToken>,
+}
+
+impl MembershipRequestsMessage {
+    pub fn new(uat: Option<UserAuthToken>) -> Self {
+        MembershipRequestsMessage { uat: uat }
+    }
+}
+
+impl Message for MembershipRequestsMessage {
+    type Result = Result<Vec<MembershipRequestRecord>, OperationError>;
+}
+
+pub struct CompareMessage {
+    pub uat: Option<UserAuthToken>,
+    pub req: CompareRequest,
+}
+
+impl CompareMessage {
+    pub fn new(uat: Option<UserAuthToken>, req: CompareRequest) -> Self {
+        CompareMessage { uat: uat, req: req }
+    }
+}
+
+impl Message for CompareMessage {
+    type Result = Result<CompareResponse, OperationError>;
+}
+
+pub struct ChangesMessage {
+    pub uat: Option<UserAuthToken>,
+    pub req: ChangesRequest,
+}
+
+impl ChangesMessage {
+    pub fn new(uat: Option<UserAuthToken>, req: ChangesRequest) -> Self {
+        ChangesMessage { uat: uat, req: req }
+    }
+}
+
+impl Message for ChangesMessage {
+    type Result = Result<ChangesResponse, OperationError>;
+}
+
+pub struct ReplSupplyMessage {
+    pub uat: Option<UserAuthToken>,
+    pub req: ReplSupplyRequest,
+}
+
+impl ReplSupplyMessage {
+    pub fn new(uat: Option<UserAuthToken>, req: ReplSupplyRequest) -> Self {
+        ReplSupplyMessage { uat: uat, req: req }
+    }
+}
+
+impl Message for ReplSupplyMessage {
+    type Result = Result<ReplSupplyResponse, OperationError>;
+}
+
+pub struct ExplainMessage {
+    pub uat: Option<UserAuthToken>,
+    pub req: SearchRequest,
+}
+
+impl ExplainMessage {
+    pub fn new(uat: Option<UserAuthToken>, req: SearchRequest) -> Self {
+        ExplainMessage { uat: uat, req: req }
+    }
+}
+
+impl Message for ExplainMessage {
+    type Result = Result<SearchExplain, OperationError>;
+}
+
+pub struct DeletePreviewMessage {
+    pub uat: Option<UserAuthToken>,
+    pub req: DeleteRequest,
+}
+
+impl DeletePreviewMessage {
+    pub fn new(uat: Option<UserAuthToken>, req: DeleteRequest) -> Self {
+        DeletePreviewMessage { uat: uat, req: req }
+    }
+}
+
+impl Message for DeletePreviewMessage {
+    type Result = Result<DeletePreviewResponse, OperationError>;
+}
+
 pub struct InternalSearchMessage {
     pub uat: Option<UserAuthToken>,
     pub filter: Filter<FilterInvalid>,
@@ -85,6 +245,16 @@ impl Message for InternalSearchMessage {
     type Result = Result<Vec<ProtoEntry>, OperationError>;
 }
 
+// The recycle bin, IE deleted entries that can still be revived.
+pub struct InternalSearchRecycledMessage {
+    pub uat: Option<UserAuthToken>,
+    pub filter: Filter<FilterInvalid>,
+}
+
+impl Message for InternalSearchRecycledMessage {
+    type Result = Result<Vec<ProtoEntry>, OperationError>;
+}
+
 pub struct InternalRadiusReadMessage {
     pub uat: Option<UserAuthToken>,
     pub uuid_or_name: String,
@@ -103,12 +273,197 @@ impl Message for InternalRadiusTokenReadMessage {
     type Result = Result<RadiusAuthToken, OperationError>;
 }
 
+// The id may also be a gidnumber, as that's what nss asks for by number.
+pub struct InternalUnixUserTokenReadMessage {
+    pub uat: Option<UserAuthToken>,
+    pub uuid_or_name: String,
+}
+
+impl Message for InternalUnixUserTokenReadMessage {
+    type Result = Result<UnixUserToken, OperationError>;
+}
+
+pub struct InternalUnixGroupTokenReadMessage {
+    pub uat: Option<UserAuthToken>,
+    pub uuid_or_name: String,
+}
+
+impl Message for InternalUnixGroupTokenReadMessage {
+    type Result = Result<UnixGroupToken, OperationError>;
+}
+
+pub struct StatusMessage;
+
+impl Message for StatusMessage {
+    type Result = Result<StatusResponse, OperationError>;
+}
+
+// Unauthenticated, as with status.
+pub struct DomainInfoMessage;
+
+impl Message for DomainInfoMessage {
+    type Result = Result<DomainInfo, OperationError>;
+}
+
+// Unauthenticated, as the login page needs it.
+pub struct BrandingMessage;
+
+impl Message for BrandingMessage {
+    type Result = Result<Branding, OperationError>;
+}
+
+// Ok is the uri to redirect the browser to.
+pub struct Oauth2AuthoriseMessage {
+    pub uat: UserAuthToken,
+    pub req: Oauth2AuthoriseRequest,
+}
+
+impl Message for Oauth2AuthoriseMessage {
+    type Result = Result<String, OperationError>;
+}
+
+// Unauthenticated, the client authenticates with its secret, from basic auth
+// if it used it, and origin is where the issuer lives.
+pub struct Oauth2TokenMessage {
+    pub req: Oauth2TokenRequest,
+    pub basic: Option<(String, String)>,
+    pub origin: String,
+}
+
+impl Message for Oauth2TokenMessage {
+    type Result = Result<Oauth2TokenResponse, OperationError>;
+}
+
+// Unauthenticated, as relying parties need these to begin.
+pub struct Oauth2DiscoveryMessage {
+    pub client_id: String,
+    pub origin: String,
+}
+
+impl Message for Oauth2DiscoveryMessage {
+    type Result = Result<OidcDiscoveryResponse, OperationError>;
+}
+
+pub struct Oauth2JwksMessage {
+    pub client_id: String,
+}
+
+impl Message for Oauth2JwksMessage {
+    type Result = Result<JwkSet, OperationError>;
+}
+
+pub struct IndexStatsMessage {
+    pub uat: Option<UserAuthToken>,
+}
+
+impl Message for IndexStatsMessage {
+    type Result = Result<Vec<IndexStat>, OperationError>;
+}
+
+// Every attribute in schema if attr is None.
+pub struct AttrUsageMessage {
+    pub uat: Option<UserAuthToken>,
+    pub attr: Option<String>,
+}
+
+impl Message for AttrUsageMessage {
+    type Result = Result<Vec<AttrUsage>, OperationError>;
+}
+
+pub struct SlowQueriesMessage {
+    pub uat: Option<UserAuthToken>,
+}
+
+impl Message for SlowQueriesMessage {
+    type Result = Result<Vec<SlowQueryRecord>, OperationError>;
+}
+
+// This goes to the read workers, so it isn't queued behind the writes it's
+// meant to stop.
+pub struct ReadOnlyMessage {
+    pub uat: Option<UserAuthToken>,
+    pub read_only: bool,
+}
+
+impl ReadOnlyMessage {
+    pub fn new(uat: Option<UserAuthToken>, read_only: bool) -> Self {
+        ReadOnlyMessage {
+            uat: uat,
+            read_only: read_only,
+        }
+    }
+}
+
+impl Message for ReadOnlyMessage {
+    type Result = Result<bool, OperationError>;
+}
+
+pub struct DbPoolStatsMessage {
+    pub uat: Option<UserAuthToken>,
+}
+
+impl Message for DbPoolStatsMessage {
+    type Result = Result<DbPoolStats, OperationError>;
+}
+
+pub struct PurgeStatsMessage {
+    pub uat: Option<UserAuthToken>,
+}
+
+impl Message for PurgeStatsMessage {
+    type Result = Result<PurgeStats, OperationError>;
+}
+
+pub struct WriteStatsMessage {
+    pub uat: Option<UserAuthToken>,
+}
+
+impl Message for WriteStatsMessage {
+    type Result = Result<Vec<WriteStatsRecord>, OperationError>;
+}
+
+pub struct AccessJournalMessage {
+    pub uat: Option<UserAuthToken>,
+}
+
+impl Message for AccessJournalMessage {
+    type Result = Result<AccessJournalResponse, OperationError>;
+}
+
+pub struct OperationsMessage {
+    pub uat: Option<UserAuthToken>,
+}
+
+impl Message for OperationsMessage {
+    type Result = Result<OperationsResponse, OperationError>;
+}
+
+pub struct OperationCancelMessage {
+    pub uat: Option<UserAuthToken>,
+    pub id: u64,
+}
+
+impl Message for OperationCancelMessage {
+    type Result = Result<bool, OperationError>;
+}
+
+// As the offline verify, but against a read txn, so the server keeps running.
+pub struct VerifyMessage {
+    pub uat: Option<UserAuthToken>,
+}
+
+impl Message for VerifyMessage {
+    type Result = Result<Vec<ConsistencyError>, OperationError>;
+}
+
 // ===========================================================
 
 pub struct QueryServerReadV1 {
     log: actix::Addr<EventLog>,
     qs: QueryServer,
     idms: Arc<IdmServer>,
+    sched: OpScheduler,
+    ops: OpTracker,
 }
 
 impl Actor for QueryServerReadV1 {
@@ -120,12 +475,20 @@ impl Actor for QueryServerReadV1 {
 }
 
 impl QueryServerReadV1 {
-    pub fn new(log: actix::Addr<EventLog>, qs: QueryServer, idms: Arc<IdmServer>) -> Self {
+    pub fn new(
+        log: actix::Addr<EventLog>,
+        qs: QueryServer,
+        idms: Arc<IdmServer>,
+        sched: OpScheduler,
+        ops: OpTracker,
+    ) -> Self {
         log_event!(log, "Starting query server v1 worker ...");
         QueryServerReadV1 {
             log: log,
             qs: qs,
             idms: idms,
+            sched: sched,
+            ops: ops,
         }
     }
 
@@ -133,10 +496,18 @@ impl QueryServerReadV1 {
         log: actix::Addr<EventLog>,
         query_server: QueryServer,
         idms: Arc<IdmServer>,
+        sched: OpScheduler,
+        ops: OpTracker,
         threads: usize,
     ) -> actix::Addr<QueryServerReadV1> {
         SyncArbiter::start(threads, move || {
-            QueryServerReadV1::new(log.clone(), query_server.clone(), idms.clone())
+            QueryServerReadV1::new(
+                log.clone(),
+                query_server.clone(),
+                idms.clone(),
+                sched.clone(),
+                ops.clone(),
+            )
         })
     }
 }
@@ -150,30 +521,299 @@ impl Handler<SearchMessage> for QueryServerReadV1 {
     type Result = Result<SearchResponse, OperationError>;
 
     fn handle(&mut self, msg: SearchMessage, _: &mut Self::Context) -> Self::Result {
+        let _ticket = self.sched.acquire(OpPriority::Admin);
         let mut audit = AuditScope::new("search");
-        let res = audit_segment!(&mut audit, || {
+        let mut access = AccessLogEvent::new("search", &msg.uat);
+        let _op = self.ops.begin("search", &msg.uat);
+        // Taken before the message is made into an event.
+        let page = msg
+            .req
+            .page_size
+            .map(|page_size| (page_size as usize, msg.req.cursor.clone()));
+        let res = isolated_segment!(&mut audit, || {
             // Begin a read
-            let qs_read = self.qs.read();
+            let qs_read = self.qs.read()?;
+
+            // Make an event from the request
+            let srch = match SearchEvent::from_message(&mut audit, msg, &qs_read) {
+                Ok(s) => s,
+                Err(e) => {
+                    audit_log!(audit, "Failed to begin search: {:?}", e);
+                    return Err(e);
+                }
+            };
+
+            audit_log!(audit, "Begin event {:?}", srch);
+            access.set_filter(srch.filter_orig.to_proto());
+
+            let res = match &page {
+                Some((page_size, cursor)) => qs_read.search_page_ext(
+                    &mut audit,
+                    &srch,
+                    *page_size,
+                    cursor.as_ref().map(|c| c.as_str()),
+                ),
+                None => qs_read
+                    .search_ext(&mut audit, &srch)
+                    .map(|entries| (entries, None)),
+            };
+            match res {
+                Ok((entries, next_cursor)) => {
+                    access.set_result_count(entries.len());
+                    SearchResult::new(&mut audit, &qs_read, entries)
+                        .map(|ok_sr| ok_sr.with_next_cursor(next_cursor).response())
+                }
+                Err(e) => Err(e),
+            }
+        });
+        // At the end of the event we send it for logging.
+        audit.set_access(access.complete(&res));
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<CompareMessage> for QueryServerReadV1 {
+    type Result = Result<CompareResponse, OperationError>;
+
+    fn handle(&mut self, msg: CompareMessage, _: &mut Self::Context) -> Self::Result {
+        let _ticket = self.sched.acquire(OpPriority::Interactive);
+        let mut audit = AuditScope::new("compare");
+        let mut access = AccessLogEvent::new("compare", &msg.uat);
+        let _op = self.ops.begin("compare", &msg.uat);
+        let res = isolated_segment!(&mut audit, || {
+            let qs_read = self.qs.read()?;
+
+            let ce = match CompareEvent::from_message(&mut audit, msg, &qs_read) {
+                Ok(c) => c,
+                Err(e) => {
+                    audit_log!(audit, "Failed to begin compare: {:?}", e);
+                    return Err(e);
+                }
+            };
+
+            audit_log!(audit, "Begin event {:?}", ce);
+            access.set_filter(ce.filter_orig.to_proto());
+
+            qs_read
+                .compare(&mut audit, &ce)
+                .map(|r| CompareResponse::new(r))
+        });
+        audit.set_access(access.complete(&res));
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<ChangesMessage> for QueryServerReadV1 {
+    type Result = Result<ChangesResponse, OperationError>;
+
+    fn handle(&mut self, msg: ChangesMessage, _: &mut Self::Context) -> Self::Result {
+        // Pollers are background sync, so shouldn't hold up interactive work.
+        let _ticket = self.sched.acquire(OpPriority::Bulk);
+        let mut audit = AuditScope::new("changes");
+        let mut access = AccessLogEvent::new("changes", &msg.uat);
+        let _op = self.ops.begin("changes", &msg.uat);
+        let res = isolated_segment!(&mut audit, || {
+            let qs_read = self.qs.read()?;
+
+            let ce = match ChangesEvent::from_message(&mut audit, msg, &qs_read) {
+                Ok(c) => c,
+                Err(e) => {
+                    audit_log!(audit, "Failed to begin changes: {:?}", e);
+                    return Err(e);
+                }
+            };
+
+            audit_log!(audit, "Begin event {:?}", ce);
+
+            let (entries, deleted, marker) = qs_read.changes(&mut audit, &ce)?;
+            access.set_result_count(entries.len());
+            SearchResult::new(&mut audit, &qs_read, entries)
+                .map(|sr| ChangesResponse::new(sr.to_proto_array(), deleted, marker.to_string()))
+        });
+        audit.set_access(access.complete(&res));
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<ReplSupplyMessage> for QueryServerReadV1 {
+    type Result = Result<ReplSupplyResponse, OperationError>;
+
+    fn handle(&mut self, msg: ReplSupplyMessage, _: &mut Self::Context) -> Self::Result {
+        // Partners pull in the background, as sync pollers do.
+        let _ticket = self.sched.acquire(OpPriority::Bulk);
+        let mut audit = AuditScope::new("repl_supply");
+        let mut access = AccessLogEvent::new("repl_supply", &msg.uat);
+        let _op = self.ops.begin("repl_supply", &msg.uat);
+        let res = isolated_segment!(&mut audit, || {
+            let qs_read = self.qs.read()?;
+
+            let re = match ReplSupplyEvent::from_message(&mut audit, msg, &qs_read) {
+                Ok(r) => r,
+                Err(e) => {
+                    audit_log!(audit, "Failed to begin replication supply: {:?}", e);
+                    return Err(e);
+                }
+            };
+
+            audit_log!(audit, "Begin event {:?}", re);
+
+            let (entries, marker) = qs_read.repl_supply(&mut audit, &re)?;
+            access.set_result_count(entries.len());
+            Ok(ReplSupplyResponse::new(entries, marker.to_string()))
+        });
+        audit.set_access(access.complete(&res));
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<SearchQueryMessage> for QueryServerReadV1 {
+    type Result = Result<SearchResponse, OperationError>;
+
+    fn handle(&mut self, msg: SearchQueryMessage, ctx: &mut Self::Context) -> Self::Result {
+        // Once parsed this is any other search.
+        let filter = parse_filter(msg.req.query.as_str())
+            .map_err(|e| OperationError::InvalidFilterQuery(e.pos, e.msg))?;
+        Handler::<SearchMessage>::handle(
+            self,
+            SearchMessage::new(msg.uat, SearchRequest::new(filter)),
+            ctx,
+        )
+    }
+}
+
+impl Handler<SavedSearchMessage> for QueryServerReadV1 {
+    type Result = Result<SearchResponse, OperationError>;
+
+    fn handle(&mut self, msg: SavedSearchMessage, ctx: &mut Self::Context) -> Self::Result {
+        // Once the filter is found, this is any other search.
+        let mut audit = AuditScope::new("saved_search");
+        let res = isolated_segment!(&mut audit, || {
+            let qs_read = self.qs.read()?;
+            let event = Event::from_ro_uat(&mut audit, &qs_read, msg.uat.clone())?;
+            qs_read.saved_search_filter(&mut audit, &event, msg.name.as_str(), &msg.req.params)
+        });
+        self.log.do_send(audit);
+        Handler::<SearchMessage>::handle(
+            self,
+            SearchMessage::new(msg.uat, SearchRequest::new(res?)),
+            ctx,
+        )
+    }
+}
+
+// Every run we keep of a report is returned, newest first.
+const REPORT_LIMIT: usize = 1000;
+
+impl Handler<ReportsMessage> for QueryServerReadV1 {
+    type Result = Result<Vec<ReportRecord>, OperationError>;
+
+    fn handle(&mut self, msg: ReportsMessage, _: &mut Self::Context) -> Self::Result {
+        let mut audit = AuditScope::new("reports");
+        let res = isolated_segment!(&mut audit, || {
+            let qs_read = self.qs.read()?;
+            let event = Event::from_ro_uat(&mut audit, &qs_read, msg.uat)?;
+            qs_read.reports(&mut audit, &event, msg.name.as_str(), REPORT_LIMIT)
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<MembershipRequestsMessage> for QueryServerReadV1 {
+    type Result = Result<Vec<MembershipRequestRecord>, OperationError>;
+
+    fn handle(&mut self, msg: MembershipRequestsMessage, _: &mut Self::Context) -> Self::Result {
+        let mut audit = AuditScope::new("membership_requests");
+        let res = isolated_segment!(&mut audit, || {
+            let qs_read = self.qs.read()?;
+            let event = Event::from_ro_uat(&mut audit, &qs_read, msg.uat)?;
+            qs_read.membership_requests(&mut audit, &event)
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<ExplainMessage> for QueryServerReadV1 {
+    type Result = Result<SearchExplain, OperationError>;
+
+    fn handle(&mut self, msg: ExplainMessage, _: &mut Self::Context) -> Self::Result {
+        let _ticket = self.sched.acquire(OpPriority::Admin);
+        let mut audit = AuditScope::new("explain");
+        let mut access = AccessLogEvent::new("explain", &msg.uat);
+        let _op = self.ops.begin("explain", &msg.uat);
+        let res = isolated_segment!(&mut audit, || {
+            let qs_read = self.qs.read()?;
+
+            // An explain is just a search we don't return the entries of.
+            let srch = match SearchEvent::from_message(
+                &mut audit,
+                SearchMessage::new(msg.uat, msg.req),
+                &qs_read,
+            ) {
+                Ok(s) => s,
+                Err(e) => {
+                    audit_log!(audit, "Failed to begin explain: {:?}", e);
+                    return Err(e);
+                }
+            };
+
+            audit_log!(audit, "Begin event {:?}", srch);
+            access.set_filter(srch.filter_orig.to_proto());
+
+            qs_read.explain(&mut audit, &srch).map(|ex| {
+                access.set_result_count(ex.matched);
+                ex
+            })
+        });
+        audit.set_access(access.complete(&res));
+        self.log.do_send(audit);
+        res
+    }
+}
 
-            // Make an event from the request
-            let srch = match SearchEvent::from_message(&mut audit, msg, &qs_read) {
+impl Handler<DeletePreviewMessage> for QueryServerReadV1 {
+    type Result = Result<DeletePreviewResponse, OperationError>;
+
+    fn handle(&mut self, msg: DeletePreviewMessage, _: &mut Self::Context) -> Self::Result {
+        let _ticket = self.sched.acquire(OpPriority::Admin);
+        let mut audit = AuditScope::new("delete_preview");
+        let mut access = AccessLogEvent::new("delete_preview", &msg.uat);
+        let _op = self.ops.begin("delete_preview", &msg.uat);
+        let res = isolated_segment!(&mut audit, || {
+            let qs_read = self.qs.read()?;
+
+            // The entries a delete would act on are those a search with the
+            // same filter finds.
+            let srch = match SearchEvent::from_message(
+                &mut audit,
+                SearchMessage::new(msg.uat, SearchRequest::new(msg.req.filter)),
+                &qs_read,
+            ) {
                 Ok(s) => s,
                 Err(e) => {
-                    audit_log!(audit, "Failed to begin search: {:?}", e);
+                    audit_log!(audit, "Failed to begin delete preview: {:?}", e);
                     return Err(e);
                 }
             };
 
             audit_log!(audit, "Begin event {:?}", srch);
+            access.set_filter(srch.filter_orig.to_proto());
 
-            match qs_read.search_ext(&mut audit, &srch) {
-                Ok(entries) => {
-                    SearchResult::new(&mut audit, &qs_read, entries).map(|ok_sr| ok_sr.response())
+            let mut entries = qs_read.delete_preview(&mut audit, &srch)?;
+            access.set_result_count(entries.len());
+            entries.iter_mut().for_each(|di| {
+                if let Ok(u) = Uuid::parse_str(di.uuid.as_str()) {
+                    di.auth_sessions = self.idms.count_auth_sessions(&u);
                 }
-                Err(e) => Err(e),
-            }
+            });
+            Ok(DeletePreviewResponse { entries: entries })
         });
-        // At the end of the event we send it for logging.
+        audit.set_access(access.complete(&res));
         self.log.do_send(audit);
         res
     }
@@ -183,12 +823,15 @@ impl Handler<AuthMessage> for QueryServerReadV1 {
     type Result = Result<AuthResponse, OperationError>;
 
     fn handle(&mut self, msg: AuthMessage, _: &mut Self::Context) -> Self::Result {
+        let _ticket = self.sched.acquire(OpPriority::Interactive);
         // This is probably the first function that really implements logic
         // "on top" of the db server concept. In this case we check if
         // the credentials provided is sufficient to say if someone is
         // "authenticated" or not.
         let mut audit = AuditScope::new("auth");
-        let res = audit_segment!(&mut audit, || {
+        let mut access = AccessLogEvent::new("auth", &None);
+        let _op = self.ops.begin("auth", &None);
+        let res = isolated_segment!(&mut audit, || {
             audit_log!(audit, "Begin auth event {:?}", msg);
 
             // Destructure it.
@@ -218,7 +861,16 @@ impl Handler<AuthMessage> for QueryServerReadV1 {
             // Build the result.
             r.map(|r| r.response())
         });
+        // Until now we couldn't know who this was.
+        if let Ok(AuthResponse {
+            state: AuthState::Success(uat),
+            ..
+        }) = &res
+        {
+            access.set_who(uat);
+        }
         // At the end of the event we send it for logging.
+        audit.set_access(access.complete(&res));
         self.log.do_send(audit);
         res
     }
@@ -228,11 +880,12 @@ impl Handler<WhoamiMessage> for QueryServerReadV1 {
     type Result = Result<WhoamiResponse, OperationError>;
 
     fn handle(&mut self, msg: WhoamiMessage, _: &mut Self::Context) -> Self::Result {
+        let _ticket = self.sched.acquire(OpPriority::Interactive);
         let mut audit = AuditScope::new("whoami");
-        let res = audit_segment!(&mut audit, || {
+        let res = isolated_segment!(&mut audit, || {
             // TODO #62: Move this to IdmServer!!!
             // Begin a read
-            let qs_read = self.qs.read();
+            let qs_read = self.qs.read()?;
 
             // Make an event from the whoami request. This will process the event and
             // generate a selfuuid search.
@@ -283,9 +936,12 @@ impl Handler<InternalSearchMessage> for QueryServerReadV1 {
     type Result = Result<Vec<ProtoEntry>, OperationError>;
 
     fn handle(&mut self, msg: InternalSearchMessage, _: &mut Self::Context) -> Self::Result {
+        let _ticket = self.sched.acquire(OpPriority::Admin);
         let mut audit = AuditScope::new("internal_search_message");
-        let res = audit_segment!(&mut audit, || {
-            let qs_read = self.qs.read();
+        let mut access = AccessLogEvent::new("search", &msg.uat);
+        let _op = self.ops.begin("search", &msg.uat);
+        let res = isolated_segment!(&mut audit, || {
+            let qs_read = self.qs.read()?;
 
             // Make an event from the request
             let srch = match SearchEvent::from_internal_message(&mut audit, msg, &qs_read) {
@@ -297,13 +953,60 @@ impl Handler<InternalSearchMessage> for QueryServerReadV1 {
             };
 
             audit_log!(audit, "Begin event {:?}", srch);
+            access.set_filter(srch.filter_orig.to_proto());
+
+            match qs_read.search_ext(&mut audit, &srch) {
+                Ok(entries) => {
+                    access.set_result_count(entries.len());
+                    SearchResult::new(&mut audit, &qs_read, entries)
+                        .map(|ok_sr| ok_sr.to_proto_array())
+                }
+                Err(e) => Err(e),
+            }
+        });
+        audit.set_access(access.complete(&res));
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<InternalSearchRecycledMessage> for QueryServerReadV1 {
+    type Result = Result<Vec<ProtoEntry>, OperationError>;
+
+    fn handle(
+        &mut self,
+        msg: InternalSearchRecycledMessage,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        let _ticket = self.sched.acquire(OpPriority::Admin);
+        let mut audit = AuditScope::new("internal_search_recycled_message");
+        let mut access = AccessLogEvent::new("search_recycled", &msg.uat);
+        let _op = self.ops.begin("search_recycled", &msg.uat);
+        let res = isolated_segment!(&mut audit, || {
+            let qs_read = self.qs.read()?;
+
+            let srch = match SearchEvent::from_rec_parts(&mut audit, msg.uat, msg.filter, &qs_read)
+            {
+                Ok(s) => s,
+                Err(e) => {
+                    audit_log!(audit, "Failed to begin recycled search: {:?}", e);
+                    return Err(e);
+                }
+            };
+
+            audit_log!(audit, "Begin event {:?}", srch);
+            access.set_filter(srch.filter_orig.to_proto());
 
             match qs_read.search_ext(&mut audit, &srch) {
-                Ok(entries) => SearchResult::new(&mut audit, &qs_read, entries)
-                    .map(|ok_sr| ok_sr.to_proto_array()),
+                Ok(entries) => {
+                    access.set_result_count(entries.len());
+                    SearchResult::new(&mut audit, &qs_read, entries)
+                        .map(|ok_sr| ok_sr.to_proto_array())
+                }
                 Err(e) => Err(e),
             }
         });
+        audit.set_access(access.complete(&res));
         self.log.do_send(audit);
         res
     }
@@ -313,9 +1016,10 @@ impl Handler<InternalRadiusReadMessage> for QueryServerReadV1 {
     type Result = Result<Option<String>, OperationError>;
 
     fn handle(&mut self, msg: InternalRadiusReadMessage, _: &mut Self::Context) -> Self::Result {
+        let _ticket = self.sched.acquire(OpPriority::Admin);
         let mut audit = AuditScope::new("internal_radius_read_message");
-        let res = audit_segment!(&mut audit, || {
-            let qs_read = self.qs.read();
+        let res = isolated_segment!(&mut audit, || {
+            let qs_read = self.qs.read()?;
 
             let target_uuid = match Uuid::parse_str(msg.uuid_or_name.as_str()) {
                 Ok(u) => u,
@@ -371,9 +1075,10 @@ impl Handler<InternalRadiusTokenReadMessage> for QueryServerReadV1 {
         msg: InternalRadiusTokenReadMessage,
         _: &mut Self::Context,
     ) -> Self::Result {
+        let _ticket = self.sched.acquire(OpPriority::Interactive);
         let mut audit = AuditScope::new("internal_radius_token_read_message");
-        let res = audit_segment!(&mut audit, || {
-            let idm_read = self.idms.proxy_read();
+        let res = isolated_segment!(&mut audit, || {
+            let idm_read = self.idms.proxy_read()?;
 
             let target_uuid = match Uuid::parse_str(msg.uuid_or_name.as_str()) {
                 Ok(u) => u,
@@ -408,3 +1113,524 @@ impl Handler<InternalRadiusTokenReadMessage> for QueryServerReadV1 {
         res
     }
 }
+
+impl Handler<InternalUnixUserTokenReadMessage> for QueryServerReadV1 {
+    type Result = Result<UnixUserToken, OperationError>;
+
+    fn handle(
+        &mut self,
+        msg: InternalUnixUserTokenReadMessage,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        let _ticket = self.sched.acquire(OpPriority::Interactive);
+        let mut audit = AuditScope::new("internal_unix_user_token_read_message");
+        let res = isolated_segment!(&mut audit, || {
+            let idm_read = self.idms.proxy_read()?;
+
+            let target_uuid =
+                unix_target_uuid(&mut audit, &idm_read.qs_read, msg.uuid_or_name.as_str())?;
+
+            // Make an event from the request
+            let uute = match UnixUserTokenEvent::from_parts(
+                &mut audit,
+                &idm_read.qs_read,
+                msg.uat,
+                target_uuid,
+            ) {
+                Ok(s) => s,
+                Err(e) => {
+                    audit_log!(audit, "Failed to begin search: {:?}", e);
+                    return Err(e);
+                }
+            };
+
+            audit_log!(audit, "Begin event {:?}", uute);
+
+            idm_read.get_unixusertoken(&mut audit, &uute)
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<InternalUnixGroupTokenReadMessage> for QueryServerReadV1 {
+    type Result = Result<UnixGroupToken, OperationError>;
+
+    fn handle(
+        &mut self,
+        msg: InternalUnixGroupTokenReadMessage,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        let _ticket = self.sched.acquire(OpPriority::Interactive);
+        let mut audit = AuditScope::new("internal_unix_group_token_read_message");
+        let res = isolated_segment!(&mut audit, || {
+            let idm_read = self.idms.proxy_read()?;
+
+            let target_uuid =
+                unix_target_uuid(&mut audit, &idm_read.qs_read, msg.uuid_or_name.as_str())?;
+
+            let ugte = match UnixGroupTokenEvent::from_parts(
+                &mut audit,
+                &idm_read.qs_read,
+                msg.uat,
+                target_uuid,
+            ) {
+                Ok(s) => s,
+                Err(e) => {
+                    audit_log!(audit, "Failed to begin search: {:?}", e);
+                    return Err(e);
+                }
+            };
+
+            audit_log!(audit, "Begin event {:?}", ugte);
+
+            idm_read.get_unixgrouptoken(&mut audit, &ugte)
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<StatusMessage> for QueryServerReadV1 {
+    type Result = Result<StatusResponse, OperationError>;
+
+    fn handle(&mut self, _msg: StatusMessage, _: &mut Self::Context) -> Self::Result {
+        let _ticket = self.sched.acquire(OpPriority::Interactive);
+        let mut audit = AuditScope::new("status_message");
+        let res = isolated_segment!(&mut audit, || {
+            // If we can open a read and see the system info entry, the backend is
+            // up and the schema loaded, which is all status needs to report.
+            let qs_read = self.qs.read()?;
+            let online = qs_read.internal_exists(
+                &mut audit,
+                filter!(f_eq("class", PartialValue::new_class("system_info"))),
+            )?;
+            let warnings = qs_read.get_be_txn().db_warnings(&mut audit)?;
+            Ok(StatusResponse {
+                online: online,
+                read_only: self.qs.is_read_only(),
+                primary: self.qs.get_primary(),
+                warnings: warnings,
+            })
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<DomainInfoMessage> for QueryServerReadV1 {
+    type Result = Result<DomainInfo, OperationError>;
+
+    fn handle(&mut self, _msg: DomainInfoMessage, _: &mut Self::Context) -> Self::Result {
+        let _ticket = self.sched.acquire(OpPriority::Interactive);
+        let mut audit = AuditScope::new("domain_info");
+        let res = isolated_segment!(&mut audit, || {
+            let qs_read = self.qs.read()?;
+            qs_read.get_be_txn().get_domain_info().map(|d| d.to_proto())
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<BrandingMessage> for QueryServerReadV1 {
+    type Result = Result<Branding, OperationError>;
+
+    fn handle(&mut self, _msg: BrandingMessage, _: &mut Self::Context) -> Self::Result {
+        let _ticket = self.sched.acquire(OpPriority::Interactive);
+        let mut audit = AuditScope::new("branding");
+        let res = isolated_segment!(&mut audit, || {
+            let qs_read = self.qs.read()?;
+            qs_read.branding(&mut audit)
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<Oauth2AuthoriseMessage> for QueryServerReadV1 {
+    type Result = Result<String, OperationError>;
+
+    fn handle(&mut self, msg: Oauth2AuthoriseMessage, _: &mut Self::Context) -> Self::Result {
+        let _ticket = self.sched.acquire(OpPriority::Interactive);
+        let mut audit = AuditScope::new("oauth2_authorise");
+        let access = AccessLogEvent::new("oauth2_authorise", &Some(msg.uat.clone()));
+        let res = isolated_segment!(&mut audit, || {
+            let mut idm_write = self.idms.write();
+            let ct = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .expect("Clock failure!");
+            idm_write
+                .oauth2_authorise(&mut audit, &msg.uat, &msg.req, ct)
+                .and_then(|r| idm_write.commit().map(|_| r))
+        });
+        audit.set_access(access.complete(&res));
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<Oauth2TokenMessage> for QueryServerReadV1 {
+    type Result = Result<Oauth2TokenResponse, OperationError>;
+
+    fn handle(&mut self, msg: Oauth2TokenMessage, _: &mut Self::Context) -> Self::Result {
+        let _ticket = self.sched.acquire(OpPriority::Interactive);
+        let mut audit = AuditScope::new("oauth2_token");
+        let access = AccessLogEvent::new("oauth2_token", &None);
+        let res = isolated_segment!(&mut audit, || {
+            let mut idm_write = self.idms.write();
+            let ct = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .expect("Clock failure!");
+            // The code is spent whatever happens, so commit either way.
+            let r = idm_write.oauth2_token_exchange(
+                &mut audit,
+                &msg.req,
+                msg.basic,
+                msg.origin.as_str(),
+                ct,
+            );
+            idm_write.commit().and_then(|_| r)
+        });
+        audit.set_access(access.complete(&res));
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<Oauth2DiscoveryMessage> for QueryServerReadV1 {
+    type Result = Result<OidcDiscoveryResponse, OperationError>;
+
+    fn handle(&mut self, msg: Oauth2DiscoveryMessage, _: &mut Self::Context) -> Self::Result {
+        let _ticket = self.sched.acquire(OpPriority::Interactive);
+        let mut audit = AuditScope::new("oauth2_discovery");
+        let res = isolated_segment!(&mut audit, || {
+            let idm_read = self.idms.proxy_read()?;
+            idm_read.oauth2_discovery(&mut audit, msg.client_id.as_str(), msg.origin.as_str())
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<Oauth2JwksMessage> for QueryServerReadV1 {
+    type Result = Result<JwkSet, OperationError>;
+
+    fn handle(&mut self, msg: Oauth2JwksMessage, _: &mut Self::Context) -> Self::Result {
+        let _ticket = self.sched.acquire(OpPriority::Interactive);
+        let mut audit = AuditScope::new("oauth2_jwks");
+        let res = isolated_segment!(&mut audit, || {
+            let idm_read = self.idms.proxy_read()?;
+            idm_read.oauth2_jwks(&mut audit, msg.client_id.as_str())
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<IndexStatsMessage> for QueryServerReadV1 {
+    type Result = Result<Vec<IndexStat>, OperationError>;
+
+    fn handle(&mut self, msg: IndexStatsMessage, _: &mut Self::Context) -> Self::Result {
+        let _ticket = self.sched.acquire(OpPriority::Admin);
+        let mut audit = AuditScope::new("index_stats");
+        let res = isolated_segment!(&mut audit, || {
+            let qs_read = self.qs.read()?;
+            check_system_admin(&mut audit, &qs_read, msg.uat)?;
+            qs_read.get_be_txn().index_stats(&mut audit)
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<AttrUsageMessage> for QueryServerReadV1 {
+    type Result = Result<Vec<AttrUsage>, OperationError>;
+
+    fn handle(&mut self, msg: AttrUsageMessage, _: &mut Self::Context) -> Self::Result {
+        let _ticket = self.sched.acquire(OpPriority::Admin);
+        let mut audit = AuditScope::new("attr_usage");
+        let res = isolated_segment!(&mut audit, || {
+            let qs_read = self.qs.read()?;
+            check_system_admin(&mut audit, &qs_read, msg.uat)?;
+            qs_read.attr_usage(&mut audit, msg.attr.as_ref().map(|a| a.as_str()))
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+// The most recent slow queries are what matter, and more than this is too
+// many to read through anyway.
+const SLOW_QUERY_REPORT_LIMIT: usize = 1000;
+
+impl Handler<SlowQueriesMessage> for QueryServerReadV1 {
+    type Result = Result<Vec<SlowQueryRecord>, OperationError>;
+
+    fn handle(&mut self, msg: SlowQueriesMessage, _: &mut Self::Context) -> Self::Result {
+        let _ticket = self.sched.acquire(OpPriority::Admin);
+        let mut audit = AuditScope::new("slow_queries");
+        let res = isolated_segment!(&mut audit, || {
+            let qs_read = self.qs.read()?;
+            check_system_admin(&mut audit, &qs_read, msg.uat)?;
+            qs_read
+                .get_be_txn()
+                .slow_queries(&mut audit, SLOW_QUERY_REPORT_LIMIT)
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<ReadOnlyMessage> for QueryServerReadV1 {
+    type Result = Result<bool, OperationError>;
+
+    fn handle(&mut self, msg: ReadOnlyMessage, _: &mut Self::Context) -> Self::Result {
+        let mut audit = AuditScope::new("read_only");
+        let res = audit_segment!(&mut audit, || {
+            let qs_read = self.qs.read()?;
+            let who = msg.uat.as_ref().map(|u| u.name.clone()).unwrap_or_default();
+            check_system_admin(&mut audit, &qs_read, msg.uat)?;
+            audit_log!(audit, "read only set to {} by {}", msg.read_only, who);
+            info!("read only set to {} by {}", msg.read_only, who);
+            self.qs.set_read_only(msg.read_only);
+            Ok(msg.read_only)
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<DbPoolStatsMessage> for QueryServerReadV1 {
+    type Result = Result<DbPoolStats, OperationError>;
+
+    // No ticket, as this is most wanted when the server is busy. Checking the
+    // caller does need a connection, which is back in the pool before the
+    // stats are read.
+    fn handle(&mut self, msg: DbPoolStatsMessage, _: &mut Self::Context) -> Self::Result {
+        let mut audit = AuditScope::new("db_pool_stats");
+        let res = audit_segment!(&mut audit, || {
+            {
+                let qs_read = self.qs.read()?;
+                check_system_admin(&mut audit, &qs_read, msg.uat)?;
+            }
+            Ok(self.qs.pool_stats())
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<PurgeStatsMessage> for QueryServerReadV1 {
+    type Result = Result<PurgeStats, OperationError>;
+
+    fn handle(&mut self, msg: PurgeStatsMessage, _: &mut Self::Context) -> Self::Result {
+        let mut audit = AuditScope::new("purge_stats");
+        let res = audit_segment!(&mut audit, || {
+            let qs_read = self.qs.read()?;
+            check_system_admin(&mut audit, &qs_read, msg.uat)?;
+            Ok(self.qs.purge_metrics().to_proto())
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<WriteStatsMessage> for QueryServerReadV1 {
+    type Result = Result<Vec<WriteStatsRecord>, OperationError>;
+
+    fn handle(&mut self, msg: WriteStatsMessage, _: &mut Self::Context) -> Self::Result {
+        let _ticket = self.sched.acquire(OpPriority::Admin);
+        let mut audit = AuditScope::new("write_stats");
+        let res = isolated_segment!(&mut audit, || {
+            let qs_read = self.qs.read()?;
+            check_system_admin(&mut audit, &qs_read, msg.uat)?;
+            Ok(qs_read.get_be_txn().write_stats())
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<AccessJournalMessage> for QueryServerReadV1 {
+    type Result = Result<AccessJournalResponse, OperationError>;
+
+    fn handle(&mut self, msg: AccessJournalMessage, _: &mut Self::Context) -> Self::Result {
+        let _ticket = self.sched.acquire(OpPriority::Admin);
+        let mut audit = AuditScope::new("access_journal");
+        let res = isolated_segment!(&mut audit, || {
+            let qs_read = self.qs.read()?;
+            check_system_admin(&mut audit, &qs_read, msg.uat)?;
+            qs_read.get_be_txn().access_journal(&mut audit)
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+// Listing and cancelling take no ticket, so they aren't queued behind the
+// operations they are meant to show or stop.
+impl Handler<OperationsMessage> for QueryServerReadV1 {
+    type Result = Result<OperationsResponse, OperationError>;
+
+    fn handle(&mut self, msg: OperationsMessage, _: &mut Self::Context) -> Self::Result {
+        let mut audit = AuditScope::new("operations");
+        let res = audit_segment!(&mut audit, || {
+            let qs_read = self.qs.read()?;
+            check_system_admin(&mut audit, &qs_read, msg.uat)?;
+            Ok(OperationsResponse::new(
+                self.ops.list_active(),
+                self.ops.list_recent(),
+            ))
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<OperationCancelMessage> for QueryServerReadV1 {
+    type Result = Result<bool, OperationError>;
+
+    fn handle(&mut self, msg: OperationCancelMessage, _: &mut Self::Context) -> Self::Result {
+        let mut audit = AuditScope::new("operation_cancel");
+        let res = audit_segment!(&mut audit, || {
+            let qs_read = self.qs.read()?;
+            check_system_admin(&mut audit, &qs_read, msg.uat)?;
+            if self.ops.cancel(msg.id) {
+                audit_log!(audit, "cancelled operation {}", msg.id);
+                Ok(true)
+            } else {
+                Err(OperationError::NoMatchingEntries)
+            }
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<VerifyMessage> for QueryServerReadV1 {
+    type Result = Result<Vec<ConsistencyError>, OperationError>;
+
+    fn handle(&mut self, msg: VerifyMessage, _: &mut Self::Context) -> Self::Result {
+        let _ticket = self.sched.acquire(OpPriority::Maintenance);
+        let mut audit = AuditScope::new("verify");
+        let _op = self.ops.begin("verify", &msg.uat);
+        let res = isolated_segment!(&mut audit, || {
+            let qs_read = self.qs.read()?;
+            check_system_admin(&mut audit, &qs_read, msg.uat)?;
+            let errs: Vec<_> = qs_read
+                .verify(&mut audit)
+                .into_iter()
+                .filter_map(|r| r.err())
+                .collect();
+            audit_log!(audit, "verify found {} errors", errs.len());
+            Ok(errs)
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+// The server administration messages are checked against the caller's live
+// entry by the query server.
+fn check_system_admin(
+    audit: &mut AuditScope,
+    qs_read: &QueryServerReadTransaction,
+    uat: Option<UserAuthToken>,
+) -> Result<(), OperationError> {
+    let event = Event::from_ro_uat(audit, qs_read, uat)?;
+    qs_read.require_system_admin(audit, &event)
+}
+
+// Resolve a unix client's id, which is a uuid, a gidnumber or a name in that
+// order. Accounts share their number with their user private group, so a
+// gidnumber is also how a uid is looked up.
+fn unix_target_uuid(
+    audit: &mut AuditScope,
+    qs: &QueryServerReadTransaction,
+    id: &str,
+) -> Result<Uuid, OperationError> {
+    if let Ok(u) = Uuid::parse_str(id) {
+        return Ok(u);
+    }
+    if let Ok(gid) = id.parse::<u32>() {
+        let f = filter!(f_eq("gidnumber", PartialValue::new_uint32(gid)));
+        let mut r = qs.internal_search(audit, f)?;
+        return match r.pop() {
+            Some(e) if r.is_empty() => Ok(*e.get_uuid()),
+            _ => Err(OperationError::NoMatchingEntries),
+        };
+    }
+    qs.name_to_uuid(audit, id).map_err(|e| {
+        audit_log!(audit, "Error resolving id to target");
+        e
+    })
+}
+
+// Scheduled backups are named for when they were taken, so sorting the names
+// orders them oldest first.
+const ONLINE_BACKUP_PREFIX: &str = "backup-";
+const ONLINE_BACKUP_SUFFIX: &str = ".json";
+
+fn prune_online_backups(
+    audit: &mut AuditScope,
+    dir: &Path,
+    versions: usize,
+) -> Result<(), OperationError> {
+    let mut backups: Vec<_> = try_audit!(
+        audit,
+        fs::read_dir(dir),
+        "Failed to read backup dir -> {:?}",
+        OperationError::FsError
+    )
+    .filter_map(|e| e.ok())
+    .filter_map(|e| e.file_name().into_string().ok())
+    .filter(|n| n.starts_with(ONLINE_BACKUP_PREFIX) && n.ends_with(ONLINE_BACKUP_SUFFIX))
+    .collect();
+    backups.sort();
+
+    let excess = backups.len().saturating_sub(versions);
+    backups.iter().take(excess).try_for_each(|n| {
+        audit_log!(audit, "Removing old backup {}", n);
+        fs::remove_file(dir.join(n)).map_err(|e| {
+            audit_log!(audit, "Failed to remove old backup -> {:?}", e);
+            OperationError::FsError
+        })
+    })
+}
+
+impl Handler<OnlineBackupEvent> for QueryServerReadV1 {
+    type Result = ();
+
+    fn handle(&mut self, msg: OnlineBackupEvent, _: &mut Self::Context) -> Self::Result {
+        let _ticket = self.sched.acquire(OpPriority::Maintenance);
+        let mut audit = AuditScope::new("online backup");
+        let res = isolated_segment!(&mut audit, || {
+            let dir = Path::new(msg.path.as_str());
+            let name = match time::now_utc().strftime("%Y%m%dT%H%M%SZ") {
+                Ok(ts) => format!("{}{}{}", ONLINE_BACKUP_PREFIX, ts, ONLINE_BACKUP_SUFFIX),
+                Err(_) => return Err(OperationError::InvalidState),
+            };
+            // Write under a temporary name, so that a backup which fails part
+            // way is never mistaken for a complete one, nor counted when pruning.
+            let tmp = dir.join(format!(".{}.tmp", name));
+            let tmp_str = tmp.to_str().ok_or(OperationError::FsError)?;
+
+            let qs_read = self.qs.read()?;
+            qs_read.get_be_txn().backup(&mut audit, tmp_str)?;
+            try_audit!(
+                audit,
+                fs::rename(&tmp, dir.join(&name)),
+                "Failed to rename backup -> {:?}",
+                OperationError::FsError
+            );
+            audit_log!(audit, "Wrote backup {}", name);
+
+            prune_online_backups(&mut audit, dir, msg.versions)
+        });
+        if res.is_err() {
+            error!("Online backup failed -> {:?}", res);
+        }
+        self.log.do_send(audit);
+    }
+}
@@ -1,23 +1,31 @@
 use crate::audit::AuditScope;
 use std::sync::Arc;
 
-use crate::async_log::EventLog;
+use crate::async_log::{AccessLogEvent, EventLog};
+use crate::constants::{ATTR_MIGRATE_BATCH, PURGE_BATCH};
 use crate::event::{
-    CreateEvent, DeleteEvent, ModifyEvent, PurgeRecycledEvent, PurgeTombstoneEvent,
+    CreateEvent, DbAnalyzeEvent, DbHeartbeatEvent, DeleteEvent, Event, MigrateAliasesEvent,
+    ModifyEvent, PurgeRecycledEvent, PurgeTombstoneEvent, ReplApplyEvent, ReviveRecycledEvent,
+    RunReportsEvent,
 };
 use crate::idm::event::{GeneratePasswordEvent, PasswordChangeEvent, RegenerateRadiusSecretEvent};
+use crate::repl::MergeStats;
 use kanidm_proto::v1::OperationError;
 
 use crate::filter::{Filter, FilterInvalid};
 use crate::idm::server::IdmServer;
-use crate::server::{QueryServer, QueryServerTransaction};
+use crate::priority::{OpPriority, OpScheduler, WriteScheduler, BULK_CREATE_THRESHOLD};
+use crate::server::{QueryServer, QueryServerTransaction, QueryServerWriteTransaction};
+use crate::value::PartialValue;
+use std::collections::BTreeSet;
 
 use kanidm_proto::v1::Entry as ProtoEntry;
 use kanidm_proto::v1::Modify as ProtoModify;
 use kanidm_proto::v1::ModifyList as ProtoModifyList;
 use kanidm_proto::v1::{
-    CreateRequest, DeleteRequest, ModifyRequest, OperationResponse, SetAuthCredential,
-    SingleStringRequest, UserAuthToken,
+    AccountUnixExtend, CreateRequest, DeleteRequest, GroupMembersRequest, GroupMembersResponse,
+    GroupUnixExtend, MembershipRequest, ModifyRequest, OperationResponse, SetAuthCredential,
+    SingleStringRequest, UserAuthToken, VacuumResponse,
 };
 
 use actix::prelude::*;
@@ -69,6 +77,15 @@ impl Message for InternalDeleteMessage {
     type Result = Result<(), OperationError>;
 }
 
+pub struct ReviveRecycledMessage {
+    pub uat: Option<UserAuthToken>,
+    pub filter: Filter<FilterInvalid>,
+}
+
+impl Message for ReviveRecycledMessage {
+    type Result = Result<(), OperationError>;
+}
+
 pub struct ModifyMessage {
     pub uat: Option<UserAuthToken>,
     pub req: ModifyRequest,
@@ -184,10 +201,104 @@ impl Message for SetAttributeMessage {
     type Result = Result<(), OperationError>;
 }
 
+// Adds the posix class and attributes to an existing account. Leaving the
+// gidnumber out has one generated, see plugins/gidnumber.rs.
+pub struct IdmAccountUnixExtendMessage {
+    pub uat: Option<UserAuthToken>,
+    pub uuid_or_name: String,
+    pub gidnumber: Option<u32>,
+    pub shell: Option<String>,
+}
+
+impl IdmAccountUnixExtendMessage {
+    pub fn new(uat: Option<UserAuthToken>, uuid_or_name: String, ux: AccountUnixExtend) -> Self {
+        let AccountUnixExtend { gidnumber, shell } = ux;
+        IdmAccountUnixExtendMessage {
+            uat: uat,
+            uuid_or_name: uuid_or_name,
+            gidnumber: gidnumber,
+            shell: shell,
+        }
+    }
+}
+
+impl Message for IdmAccountUnixExtendMessage {
+    type Result = Result<(), OperationError>;
+}
+
+pub struct IdmGroupUnixExtendMessage {
+    pub uat: Option<UserAuthToken>,
+    pub uuid_or_name: String,
+    pub gidnumber: Option<u32>,
+}
+
+impl IdmGroupUnixExtendMessage {
+    pub fn new(uat: Option<UserAuthToken>, uuid_or_name: String, gx: GroupUnixExtend) -> Self {
+        IdmGroupUnixExtendMessage {
+            uat: uat,
+            uuid_or_name: uuid_or_name,
+            gidnumber: gx.gidnumber,
+        }
+    }
+}
+
+impl Message for IdmGroupUnixExtendMessage {
+    type Result = Result<(), OperationError>;
+}
+
+pub struct GroupMembersMessage {
+    pub uat: Option<UserAuthToken>,
+    pub uuid_or_name: String,
+    pub req: GroupMembersRequest,
+}
+
+impl Message for GroupMembersMessage {
+    type Result = Result<GroupMembersResponse, OperationError>;
+}
+
+pub struct MembershipRequestMessage {
+    pub uat: Option<UserAuthToken>,
+    pub uuid_or_name: String,
+    pub req: MembershipRequest,
+}
+
+impl Message for MembershipRequestMessage {
+    type Result = Result<String, OperationError>;
+}
+
+pub struct MembershipDecisionMessage {
+    pub uat: Option<UserAuthToken>,
+    pub id: String,
+    pub approve: bool,
+}
+
+impl Message for MembershipDecisionMessage {
+    type Result = Result<(), OperationError>;
+}
+
+// The maintenance the cli does offline, for a server that's running.
+pub struct ReindexMessage {
+    pub uat: Option<UserAuthToken>,
+}
+
+impl Message for ReindexMessage {
+    type Result = Result<(), OperationError>;
+}
+
+pub struct VacuumMessage {
+    pub uat: Option<UserAuthToken>,
+}
+
+impl Message for VacuumMessage {
+    type Result = Result<VacuumResponse, OperationError>;
+}
+
 pub struct QueryServerWriteV1 {
     log: actix::Addr<EventLog>,
     qs: QueryServer,
     idms: Arc<IdmServer>,
+    sched: WriteScheduler,
+    ops: OpTracker,
 }
 
 impl Actor for QueryServerWriteV1 {
@@ -201,12 +312,20 @@ impl Actor for QueryServerWriteV1 {
 }
 
 impl QueryServerWriteV1 {
-    pub fn new(log: actix::Addr<EventLog>, qs: QueryServer, idms: Arc<IdmServer>) -> Self {
+    pub fn new(
+        log: actix::Addr<EventLog>,
+        qs: QueryServer,
+        idms: Arc<IdmServer>,
+        sched: WriteScheduler,
+        ops: OpTracker,
+    ) -> Self {
         log_event!(log, "Starting query server v1 worker ...");
         QueryServerWriteV1 {
             log: log,
             qs: qs,
             idms: idms,
+            sched: sched,
+            ops: ops,
         }
     }
 
@@ -214,12 +333,36 @@ impl QueryServerWriteV1 {
         log: actix::Addr<EventLog>,
         query_server: QueryServer,
         idms: Arc<IdmServer>,
+        sched: OpScheduler,
+        ops: OpTracker,
+        threads: usize,
     ) -> actix::Addr<QueryServerWriteV1> {
-        SyncArbiter::start(1, move || {
-            QueryServerWriteV1::new(log.clone(), query_server.clone(), idms.clone())
+        let sched = WriteScheduler::new(sched);
+        SyncArbiter::start(threads, move || {
+            QueryServerWriteV1::new(
+                log.clone(),
+                query_server.clone(),
+                idms.clone(),
+                sched.clone(),
+                ops.clone(),
+            )
         })
     }
 
+    fn resolve_id(
+        audit: &mut AuditScope,
+        qs_write: &QueryServerWriteTransaction,
+        uuid_or_name: &str,
+    ) -> Result<Uuid, OperationError> {
+        match Uuid::parse_str(uuid_or_name) {
+            Ok(u) => Ok(u),
+            Err(_) => qs_write.name_to_uuid(audit, uuid_or_name).map_err(|e| {
+                audit_log!(audit, "Error resolving id {:?} to target", uuid_or_name);
+                e
+            }),
+        }
+    }
+
     fn modify_from_parts(
         &mut self,
         audit: &mut AuditScope,
@@ -227,8 +370,9 @@ impl QueryServerWriteV1 {
         uuid_or_name: String,
         proto_ml: ProtoModifyList,
         filter: Filter<FilterInvalid>,
+        access: &mut AccessLogEvent,
     ) -> Result<(), OperationError> {
-        let mut qs_write = self.qs.write();
+        let mut qs_write = self.qs.write()?;
 
         let target_uuid = match Uuid::parse_str(uuid_or_name.as_str()) {
             Ok(u) => u,
@@ -250,6 +394,7 @@ impl QueryServerWriteV1 {
             };
 
         audit_log!(audit, "Begin modify event {:?}", mdf);
+        access.set_filter(mdf.filter_orig.to_proto());
 
         qs_write
             .modify(audit, &mdf)
@@ -261,9 +406,19 @@ impl Handler<CreateMessage> for QueryServerWriteV1 {
     type Result = Result<OperationResponse, OperationError>;
 
     fn handle(&mut self, msg: CreateMessage, _: &mut Self::Context) -> Self::Result {
+        let _ticket = self
+            .sched
+            .acquire(if msg.req.entries.len() > BULK_CREATE_THRESHOLD {
+                OpPriority::Bulk
+            } else {
+                OpPriority::Admin
+            });
         let mut audit = AuditScope::new("create");
-        let res = audit_segment!(&mut audit, || {
-            let mut qs_write = self.qs.write();
+        let mut access = AccessLogEvent::new("create", &msg.uat);
+        let _op = self.ops.begin("create", &msg.uat);
+        access.set_result_count(msg.req.entries.len());
+        let res = isolated_segment!(&mut audit, || {
+            let mut qs_write = self.qs.write()?;
 
             let crt = match CreateEvent::from_message(&mut audit, msg, &qs_write) {
                 Ok(c) => c,
@@ -280,6 +435,7 @@ impl Handler<CreateMessage> for QueryServerWriteV1 {
                 .and_then(|_| qs_write.commit(&mut audit).map(|_| OperationResponse {}))
         });
         // At the end of the event we send it for logging.
+        audit.set_access(access.complete(&res));
         self.log.do_send(audit);
         res
     }
@@ -289,9 +445,12 @@ impl Handler<ModifyMessage> for QueryServerWriteV1 {
     type Result = Result<OperationResponse, OperationError>;
 
     fn handle(&mut self, msg: ModifyMessage, _: &mut Self::Context) -> Self::Result {
+        let _ticket = self.sched.acquire(OpPriority::Admin);
         let mut audit = AuditScope::new("modify");
-        let res = audit_segment!(&mut audit, || {
-            let mut qs_write = self.qs.write();
+        let mut access = AccessLogEvent::new("modify", &msg.uat);
+        let _op = self.ops.begin("modify", &msg.uat);
+        let res = isolated_segment!(&mut audit, || {
+            let mut qs_write = self.qs.write()?;
             let mdf = match ModifyEvent::from_message(&mut audit, msg, &qs_write) {
                 Ok(m) => m,
                 Err(e) => {
@@ -301,11 +460,13 @@ impl Handler<ModifyMessage> for QueryServerWriteV1 {
             };
 
             audit_log!(audit, "Begin modify event {:?}", mdf);
+            access.set_filter(mdf.filter_orig.to_proto());
 
             qs_write
                 .modify(&mut audit, &mdf)
                 .and_then(|_| qs_write.commit(&mut audit).map(|_| OperationResponse {}))
         });
+        audit.set_access(access.complete(&res));
         self.log.do_send(audit);
         res
     }
@@ -315,9 +476,12 @@ impl Handler<DeleteMessage> for QueryServerWriteV1 {
     type Result = Result<OperationResponse, OperationError>;
 
     fn handle(&mut self, msg: DeleteMessage, _: &mut Self::Context) -> Self::Result {
+        let _ticket = self.sched.acquire(OpPriority::Admin);
         let mut audit = AuditScope::new("delete");
-        let res = audit_segment!(&mut audit, || {
-            let mut qs_write = self.qs.write();
+        let mut access = AccessLogEvent::new("delete", &msg.uat);
+        let _op = self.ops.begin("delete", &msg.uat);
+        let res = isolated_segment!(&mut audit, || {
+            let mut qs_write = self.qs.write()?;
 
             let del = match DeleteEvent::from_message(&mut audit, msg, &qs_write) {
                 Ok(d) => d,
@@ -328,11 +492,13 @@ impl Handler<DeleteMessage> for QueryServerWriteV1 {
             };
 
             audit_log!(audit, "Begin delete event {:?}", del);
+            access.set_filter(del.filter_orig.to_proto());
 
             qs_write
                 .delete(&mut audit, &del)
                 .and_then(|_| qs_write.commit(&mut audit).map(|_| OperationResponse {}))
         });
+        audit.set_access(access.complete(&res));
         self.log.do_send(audit);
         res
     }
@@ -342,9 +508,12 @@ impl Handler<InternalDeleteMessage> for QueryServerWriteV1 {
     type Result = Result<(), OperationError>;
 
     fn handle(&mut self, msg: InternalDeleteMessage, _: &mut Self::Context) -> Self::Result {
+        let _ticket = self.sched.acquire(OpPriority::Admin);
         let mut audit = AuditScope::new("delete");
-        let res = audit_segment!(&mut audit, || {
-            let mut qs_write = self.qs.write();
+        let mut access = AccessLogEvent::new("delete", &msg.uat);
+        let _op = self.ops.begin("delete", &msg.uat);
+        let res = isolated_segment!(&mut audit, || {
+            let mut qs_write = self.qs.write()?;
 
             let del = match DeleteEvent::from_parts(&mut audit, msg.uat, msg.filter, &qs_write) {
                 Ok(d) => d,
@@ -355,11 +524,46 @@ impl Handler<InternalDeleteMessage> for QueryServerWriteV1 {
             };
 
             audit_log!(audit, "Begin delete event {:?}", del);
+            access.set_filter(del.filter_orig.to_proto());
 
             qs_write
                 .delete(&mut audit, &del)
                 .and_then(|_| qs_write.commit(&mut audit).map(|_| ()))
         });
+        audit.set_access(access.complete(&res));
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<ReviveRecycledMessage> for QueryServerWriteV1 {
+    type Result = Result<(), OperationError>;
+
+    fn handle(&mut self, msg: ReviveRecycledMessage, _: &mut Self::Context) -> Self::Result {
+        let _ticket = self.sched.acquire(OpPriority::Admin);
+        let mut audit = AuditScope::new("revive_recycled");
+        let mut access = AccessLogEvent::new("revive_recycled", &msg.uat);
+        let _op = self.ops.begin("revive_recycled", &msg.uat);
+        let res = isolated_segment!(&mut audit, || {
+            let mut qs_write = self.qs.write()?;
+
+            let rre =
+                match ReviveRecycledEvent::from_parts(&mut audit, msg.uat, msg.filter, &qs_write) {
+                    Ok(r) => r,
+                    Err(e) => {
+                        audit_log!(audit, "Failed to begin revive: {:?}", e);
+                        return Err(e);
+                    }
+                };
+
+            audit_log!(audit, "Begin revive event {:?}", rre);
+            access.set_filter(rre.filter.to_proto());
+
+            qs_write
+                .revive_recycled(&mut audit, &rre)
+                .and_then(|_| qs_write.commit(&mut audit).map(|_| ()))
+        });
+        audit.set_access(access.complete(&res));
         self.log.do_send(audit);
         res
     }
@@ -370,9 +574,10 @@ impl Handler<InternalCredentialSetMessage> for QueryServerWriteV1 {
     type Result = Result<Option<String>, OperationError>;
 
     fn handle(&mut self, msg: InternalCredentialSetMessage, _: &mut Self::Context) -> Self::Result {
+        let _ticket = self.sched.acquire(OpPriority::Admin);
         let mut audit = AuditScope::new("internal_credential_set_message");
-        let res = audit_segment!(&mut audit, || {
-            let mut idms_prox_write = self.idms.proxy_write();
+        let res = isolated_segment!(&mut audit, || {
+            let mut idms_prox_write = self.idms.proxy_write()?;
 
             // given the uuid_or_name, determine the target uuid.
             // We can either do this by trying to parse the name or by creating a filter
@@ -445,9 +650,10 @@ impl Handler<IdmAccountSetPasswordMessage> for QueryServerWriteV1 {
     type Result = Result<OperationResponse, OperationError>;
 
     fn handle(&mut self, msg: IdmAccountSetPasswordMessage, _: &mut Self::Context) -> Self::Result {
+        let _ticket = self.sched.acquire(OpPriority::Interactive);
         let mut audit = AuditScope::new("idm_account_set_password");
-        let res = audit_segment!(&mut audit, || {
-            let mut idms_prox_write = self.idms.proxy_write();
+        let res = isolated_segment!(&mut audit, || {
+            let mut idms_prox_write = self.idms.proxy_write()?;
 
             let pce = PasswordChangeEvent::from_idm_account_set_password(
                 &mut audit,
@@ -477,9 +683,10 @@ impl Handler<InternalRegenerateRadiusMessage> for QueryServerWriteV1 {
         msg: InternalRegenerateRadiusMessage,
         _: &mut Self::Context,
     ) -> Self::Result {
+        let _ticket = self.sched.acquire(OpPriority::Admin);
         let mut audit = AuditScope::new("idm_account_regenerate_radius");
-        let res = audit_segment!(&mut audit, || {
-            let mut idms_prox_write = self.idms.proxy_write();
+        let res = isolated_segment!(&mut audit, || {
+            let mut idms_prox_write = self.idms.proxy_write()?;
 
             let target_uuid = match Uuid::parse_str(msg.uuid_or_name.as_str()) {
                 Ok(u) => u,
@@ -520,9 +727,12 @@ impl Handler<PurgeAttributeMessage> for QueryServerWriteV1 {
     type Result = Result<(), OperationError>;
 
     fn handle(&mut self, msg: PurgeAttributeMessage, _: &mut Self::Context) -> Self::Result {
+        let _ticket = self.sched.acquire(OpPriority::Admin);
         let mut audit = AuditScope::new("purge_attribute");
-        let res = audit_segment!(&mut audit, || {
-            let mut qs_write = self.qs.write();
+        let mut access = AccessLogEvent::new("modify", &msg.uat);
+        let _op = self.ops.begin("modify", &msg.uat);
+        let res = isolated_segment!(&mut audit, || {
+            let mut qs_write = self.qs.write()?;
             let target_uuid = match Uuid::parse_str(msg.uuid_or_name.as_str()) {
                 Ok(u) => u,
                 Err(_) => qs_write
@@ -549,11 +759,13 @@ impl Handler<PurgeAttributeMessage> for QueryServerWriteV1 {
             };
 
             audit_log!(audit, "Begin modify event {:?}", mdf);
+            access.set_filter(mdf.filter_orig.to_proto());
 
             qs_write
                 .modify(&mut audit, &mdf)
                 .and_then(|_| qs_write.commit(&mut audit).map(|_| ()))
         });
+        audit.set_access(access.complete(&res));
         self.log.do_send(audit);
         res
     }
@@ -563,8 +775,11 @@ impl Handler<AppendAttributeMessage> for QueryServerWriteV1 {
     type Result = Result<(), OperationError>;
 
     fn handle(&mut self, msg: AppendAttributeMessage, _: &mut Self::Context) -> Self::Result {
+        let _ticket = self.sched.acquire(OpPriority::Admin);
         let mut audit = AuditScope::new("append_attribute");
-        let res = audit_segment!(&mut audit, || {
+        let mut access = AccessLogEvent::new("modify", &msg.uat);
+        let _op = self.ops.begin("modify", &msg.uat);
+        let res = isolated_segment!(&mut audit, || {
             let AppendAttributeMessage {
                 uat,
                 uuid_or_name,
@@ -580,8 +795,9 @@ impl Handler<AppendAttributeMessage> for QueryServerWriteV1 {
                     .map(|v| ProtoModify::Present(attr.clone(), v))
                     .collect(),
             );
-            self.modify_from_parts(&mut audit, uat, uuid_or_name, proto_ml, filter)
+            self.modify_from_parts(&mut audit, uat, uuid_or_name, proto_ml, filter, &mut access)
         });
+        audit.set_access(access.complete(&res));
         self.log.do_send(audit);
         res
     }
@@ -591,8 +807,11 @@ impl Handler<SetAttributeMessage> for QueryServerWriteV1 {
     type Result = Result<(), OperationError>;
 
     fn handle(&mut self, msg: SetAttributeMessage, _: &mut Self::Context) -> Self::Result {
+        let _ticket = self.sched.acquire(OpPriority::Admin);
         let mut audit = AuditScope::new("set_attribute");
-        let res = audit_segment!(&mut audit, || {
+        let mut access = AccessLogEvent::new("modify", &msg.uat);
+        let _op = self.ops.begin("modify", &msg.uat);
+        let res = isolated_segment!(&mut audit, || {
             let SetAttributeMessage {
                 uat,
                 uuid_or_name,
@@ -611,8 +830,245 @@ impl Handler<SetAttributeMessage> for QueryServerWriteV1 {
                     )
                     .collect(),
             );
-            self.modify_from_parts(&mut audit, uat, uuid_or_name, proto_ml, filter)
+            self.modify_from_parts(&mut audit, uat, uuid_or_name, proto_ml, filter, &mut access)
+        });
+        audit.set_access(access.complete(&res));
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<IdmAccountUnixExtendMessage> for QueryServerWriteV1 {
+    type Result = Result<(), OperationError>;
+
+    fn handle(&mut self, msg: IdmAccountUnixExtendMessage, _: &mut Self::Context) -> Self::Result {
+        let _ticket = self.sched.acquire(OpPriority::Admin);
+        let mut audit = AuditScope::new("idm_account_unix_extend");
+        let mut access = AccessLogEvent::new("modify", &msg.uat);
+        let _op = self.ops.begin("modify", &msg.uat);
+        let res = isolated_segment!(&mut audit, || {
+            let IdmAccountUnixExtendMessage {
+                uat,
+                uuid_or_name,
+                gidnumber,
+                shell,
+            } = msg;
+            // The class is added with present, so extending twice just
+            // replaces the values given.
+            let mut mods = vec![ProtoModify::Present(
+                "class".to_string(),
+                "posixaccount".to_string(),
+            )];
+            if let Some(gid) = gidnumber {
+                mods.push(ProtoModify::Purged("gidnumber".to_string()));
+                mods.push(ProtoModify::Present(
+                    "gidnumber".to_string(),
+                    gid.to_string(),
+                ));
+            }
+            if let Some(s) = shell {
+                mods.push(ProtoModify::Purged("loginshell".to_string()));
+                mods.push(ProtoModify::Present("loginshell".to_string(), s));
+            }
+            let proto_ml = ProtoModifyList::new_list(mods);
+            let filter = filter_all!(f_eq("class", PartialValue::new_class("account")));
+            self.modify_from_parts(&mut audit, uat, uuid_or_name, proto_ml, filter, &mut access)
+        });
+        audit.set_access(access.complete(&res));
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<IdmGroupUnixExtendMessage> for QueryServerWriteV1 {
+    type Result = Result<(), OperationError>;
+
+    fn handle(&mut self, msg: IdmGroupUnixExtendMessage, _: &mut Self::Context) -> Self::Result {
+        let _ticket = self.sched.acquire(OpPriority::Admin);
+        let mut audit = AuditScope::new("idm_group_unix_extend");
+        let mut access = AccessLogEvent::new("modify", &msg.uat);
+        let _op = self.ops.begin("modify", &msg.uat);
+        let res = isolated_segment!(&mut audit, || {
+            let IdmGroupUnixExtendMessage {
+                uat,
+                uuid_or_name,
+                gidnumber,
+            } = msg;
+            let mut mods = vec![ProtoModify::Present(
+                "class".to_string(),
+                "posixgroup".to_string(),
+            )];
+            if let Some(gid) = gidnumber {
+                mods.push(ProtoModify::Purged("gidnumber".to_string()));
+                mods.push(ProtoModify::Present(
+                    "gidnumber".to_string(),
+                    gid.to_string(),
+                ));
+            }
+            let proto_ml = ProtoModifyList::new_list(mods);
+            let filter = filter_all!(f_eq("class", PartialValue::new_class("group")));
+            self.modify_from_parts(&mut audit, uat, uuid_or_name, proto_ml, filter, &mut access)
         });
+        audit.set_access(access.complete(&res));
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<GroupMembersMessage> for QueryServerWriteV1 {
+    type Result = Result<GroupMembersResponse, OperationError>;
+
+    fn handle(&mut self, msg: GroupMembersMessage, _: &mut Self::Context) -> Self::Result {
+        let count = msg.req.add.len() + msg.req.remove.len();
+        let _ticket = self.sched.acquire(if count > BULK_CREATE_THRESHOLD {
+            OpPriority::Bulk
+        } else {
+            OpPriority::Admin
+        });
+        let mut audit = AuditScope::new("group_members");
+        let mut access = AccessLogEvent::new("modify", &msg.uat);
+        let _op = self.ops.begin("modify", &msg.uat);
+        let res = isolated_segment!(&mut audit, || {
+            let mut qs_write = self.qs.write()?;
+            let GroupMembersMessage {
+                uat,
+                uuid_or_name,
+                req,
+            } = msg;
+
+            let group = Self::resolve_id(&mut audit, &qs_write, uuid_or_name.as_str())?;
+            let add = req
+                .add
+                .iter()
+                .map(|id| Self::resolve_id(&mut audit, &qs_write, id.as_str()))
+                .collect::<Result<BTreeSet<_>, _>>()?;
+            let remove = req
+                .remove
+                .iter()
+                .map(|id| Self::resolve_id(&mut audit, &qs_write, id.as_str()))
+                .collect::<Result<BTreeSet<_>, _>>()?;
+            let event = Event::from_rw_uat(&mut audit, &qs_write, uat)?;
+
+            let (added, removed) =
+                qs_write.modify_members(&mut audit, &event, &group, &add, &remove)?;
+            access.set_result_count(added + removed);
+            qs_write.commit(&mut audit).map(|_| GroupMembersResponse {
+                added: added,
+                removed: removed,
+            })
+        });
+        audit.set_access(access.complete(&res));
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<MembershipRequestMessage> for QueryServerWriteV1 {
+    type Result = Result<String, OperationError>;
+
+    fn handle(&mut self, msg: MembershipRequestMessage, _: &mut Self::Context) -> Self::Result {
+        let _ticket = self.sched.acquire(OpPriority::Admin);
+        let mut audit = AuditScope::new("membership_request");
+        let mut access = AccessLogEvent::new("create", &msg.uat);
+        let _op = self.ops.begin("create", &msg.uat);
+        let res = isolated_segment!(&mut audit, || {
+            let mut qs_write = self.qs.write()?;
+            let MembershipRequestMessage {
+                uat,
+                uuid_or_name,
+                req,
+            } = msg;
+
+            let group = Self::resolve_id(&mut audit, &qs_write, uuid_or_name.as_str())?;
+            let member = Self::resolve_id(&mut audit, &qs_write, req.member.as_str())?;
+            let event = Event::from_rw_uat(&mut audit, &qs_write, uat)?;
+
+            let request =
+                qs_write.request_membership(&mut audit, &event, &group, &member, req.action)?;
+            access.set_result_count(1);
+            qs_write
+                .commit(&mut audit)
+                .map(|_| request.to_hyphenated_ref().to_string())
+        });
+        audit.set_access(access.complete(&res));
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<MembershipDecisionMessage> for QueryServerWriteV1 {
+    type Result = Result<(), OperationError>;
+
+    fn handle(&mut self, msg: MembershipDecisionMessage, _: &mut Self::Context) -> Self::Result {
+        let _ticket = self.sched.acquire(OpPriority::Admin);
+        let mut audit = AuditScope::new("membership_decision");
+        let mut access = AccessLogEvent::new("modify", &msg.uat);
+        let _op = self.ops.begin("modify", &msg.uat);
+        let res = isolated_segment!(&mut audit, || {
+            let mut qs_write = self.qs.write()?;
+            let request = Uuid::parse_str(msg.id.as_str()).map_err(|_| {
+                audit_log!(audit, "Invalid membership request id {:?}", msg.id);
+                OperationError::InvalidRequestState
+            })?;
+            let event = Event::from_rw_uat(&mut audit, &qs_write, msg.uat)?;
+
+            qs_write.decide_membership_request(&mut audit, &event, &request, msg.approve)?;
+            access.set_result_count(1);
+            qs_write.commit(&mut audit)
+        });
+        audit.set_access(access.complete(&res));
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<ReindexMessage> for QueryServerWriteV1 {
+    type Result = Result<(), OperationError>;
+
+    fn handle(&mut self, msg: ReindexMessage, _: &mut Self::Context) -> Self::Result {
+        let _ticket = self.sched.acquire(OpPriority::Maintenance);
+        let mut audit = AuditScope::new("reindex");
+        let _op = self.ops.begin("reindex", &msg.uat);
+        let res = isolated_segment!(&mut audit, || {
+            let qs_write = self.qs.write()?;
+            let event = Event::from_rw_uat(&mut audit, &qs_write, msg.uat)?;
+            qs_write.require_system_admin(&mut audit, &event)?;
+            qs_write
+                .reindex(&mut audit, false)
+                .and_then(|_| qs_write.commit(&mut audit))
+        });
+        if res.is_ok() {
+            info!("Online reindex complete");
+        }
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<VacuumMessage> for QueryServerWriteV1 {
+    type Result = Result<VacuumResponse, OperationError>;
+
+    fn handle(&mut self, msg: VacuumMessage, _: &mut Self::Context) -> Self::Result {
+        let _ticket = self.sched.acquire(OpPriority::Maintenance);
+        let mut audit = AuditScope::new("vacuum");
+        let _op = self.ops.begin("vacuum", &msg.uat);
+        let res = isolated_segment!(&mut audit, || {
+            // Checked with a read txn, as vacuum must begin its own write.
+            {
+                let qs_read = self.qs.read()?;
+                let event = Event::from_ro_uat(&mut audit, &qs_read, msg.uat)?;
+                qs_read.require_system_admin(&mut audit, &event)?;
+            }
+            self.qs
+                .vacuum(&mut audit)
+                .map(|(before, after)| VacuumResponse {
+                    before: before,
+                    after: after,
+                })
+        });
+        if let Ok(v) = &res {
+            info!("Online vacuum complete, {} -> {} bytes", v.before, v.after);
+        }
         self.log.do_send(audit);
         res
     }
@@ -624,16 +1080,32 @@ impl Handler<PurgeTombstoneEvent> for QueryServerWriteV1 {
     type Result = ();
 
     fn handle(&mut self, msg: PurgeTombstoneEvent, _: &mut Self::Context) -> Self::Result {
+        let _ticket = self.sched.acquire(OpPriority::Maintenance);
         let mut audit = AuditScope::new("purge tombstones");
-        let res = audit_segment!(&mut audit, || {
+        let res = isolated_segment!(&mut audit, || {
             audit_log!(audit, "Begin purge tombstone event {:?}", msg);
-            let qs_write = self.qs.write();
-
-            let res = qs_write
-                .purge_tombstones(&mut audit)
-                .and_then(|_| qs_write.commit(&mut audit));
+            // Tombstones aren't replicated away, so even a read replica
+            // purges its own.
+            let now = time::now_utc().to_timespec().sec;
+            let res = self.qs.write_replicated().and_then(|qs_write| {
+                qs_write
+                    .purge_tombstones(&mut audit, now, PURGE_BATCH)
+                    .and_then(|r| qs_write.commit(&mut audit).map(|_| r))
+            });
             audit_log!(audit, "Purge tombstones result: {:?}", res);
-            res.expect("Invalid Server State");
+            match res {
+                // The next interval will try again.
+                Err(OperationError::Busy) | Err(OperationError::Unavailable) => {
+                    error!(
+                        "Unable to purge tombstones, database unavailable -> {:?}",
+                        res
+                    )
+                }
+                res => {
+                    let (reaped, pending) = res.expect("Invalid Server State");
+                    self.qs.purge_metrics().tombstones(reaped, pending, now);
+                }
+            }
         });
         // At the end of the event we send it for logging.
         self.log.do_send(audit);
@@ -645,19 +1117,166 @@ impl Handler<PurgeRecycledEvent> for QueryServerWriteV1 {
     type Result = ();
 
     fn handle(&mut self, msg: PurgeRecycledEvent, _: &mut Self::Context) -> Self::Result {
+        let _ticket = self.sched.acquire(OpPriority::Maintenance);
         let mut audit = AuditScope::new("purge recycled");
-        let res = audit_segment!(&mut audit, || {
+        let res = isolated_segment!(&mut audit, || {
             audit_log!(audit, "Begin purge recycled event {:?}", msg);
-            let qs_write = self.qs.write();
-
-            let res = qs_write
-                .purge_recycled(&mut audit)
-                .and_then(|_| qs_write.commit(&mut audit));
+            let now = time::now_utc().to_timespec().sec;
+            let res = self.qs.write().and_then(|qs_write| {
+                qs_write
+                    .purge_recycled(&mut audit, now, PURGE_BATCH)
+                    .and_then(|r| qs_write.commit(&mut audit).map(|_| r))
+            });
             audit_log!(audit, "Purge recycled result: {:?}", res);
-            res.expect("Invalid Server State");
+            match res {
+                // The next interval will try again.
+                Err(OperationError::Busy) | Err(OperationError::Unavailable) => {
+                    error!(
+                        "Unable to purge recycled, database unavailable -> {:?}",
+                        res
+                    )
+                }
+                // Our primary purges them, and we get the tombstones.
+                Err(OperationError::ReadReplica(_)) => {}
+                res => {
+                    let (reaped, pending) = res.expect("Invalid Server State");
+                    self.qs.purge_metrics().recycled(reaped, pending, now);
+                }
+            }
         });
         // At the end of the event we send it for logging.
         self.log.do_send(audit);
         res
     }
 }
+
+impl Handler<ReplApplyEvent> for QueryServerWriteV1 {
+    type Result = Result<MergeStats, OperationError>;
+
+    fn handle(&mut self, msg: ReplApplyEvent, _: &mut Self::Context) -> Self::Result {
+        let _ticket = self.sched.acquire(OpPriority::Bulk);
+        let mut audit = AuditScope::new("repl_apply");
+        let res = isolated_segment!(&mut audit, || {
+            audit_log!(
+                audit,
+                "Begin replication from {}, {} entries",
+                msg.partner,
+                msg.entries.len()
+            );
+            let mut qs_write = self.qs.write_replicated()?;
+            qs_write
+                .repl_apply(&mut audit, msg.entries.as_slice())
+                .and_then(|stats| qs_write.commit(&mut audit).map(|_| stats))
+        });
+        audit_log!(audit, "Replication result: {:?}", res);
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<DbAnalyzeEvent> for QueryServerWriteV1 {
+    type Result = ();
+
+    fn handle(&mut self, _msg: DbAnalyzeEvent, _: &mut Self::Context) -> Self::Result {
+        // This holds the write lock until it's done, so it only runs if
+        // nothing else is. If we're busy, it waits for the next interval.
+        let _ticket = match self.sched.try_acquire_idle(OpPriority::Maintenance) {
+            Some(t) => t,
+            None => {
+                debug!("Server busy, skipping database analyze");
+                return;
+            }
+        };
+        let mut audit = AuditScope::new("db analyze");
+        let res = self.qs.analyze(&mut audit);
+        match res {
+            Ok(n) => info!("Database analyze complete, counted {} indexes", n),
+            Err(e) => error!("Database analyze failed -> {:?}", e),
+        }
+        self.log.do_send(audit);
+    }
+}
+
+impl Handler<RunReportsEvent> for QueryServerWriteV1 {
+    type Result = ();
+
+    fn handle(&mut self, msg: RunReportsEvent, _: &mut Self::Context) -> Self::Result {
+        let _ticket = self.sched.acquire(OpPriority::Maintenance);
+        let mut audit = AuditScope::new("run reports");
+        let res = isolated_segment!(&mut audit, || {
+            audit_log!(audit, "Begin run reports event {:?}", msg);
+            let now = time::now_utc().to_timespec().sec;
+            self.qs.write().and_then(|qs_write| {
+                qs_write
+                    .run_reports(&mut audit, now)
+                    .and_then(|_| qs_write.commit(&mut audit))
+            })
+        });
+        // Unlike the purges a failed report isn't fatal, it's just retried
+        // at the next interval. A read replica leaves them to its primary.
+        match res {
+            Ok(_) | Err(OperationError::ReadReplica(_)) => {}
+            Err(e) => error!("Unable to run reports -> {:?}", e),
+        }
+        self.log.do_send(audit);
+    }
+}
+
+impl Handler<MigrateAliasesEvent> for QueryServerWriteV1 {
+    type Result = ();
+
+    fn handle(&mut self, _msg: MigrateAliasesEvent, _: &mut Self::Context) -> Self::Result {
+        // Nearly always there is nothing to do, so don't take a write txn
+        // to find that out.
+        if !self.qs.alias_migrations_pending() {
+            return;
+        }
+        let _ticket = self.sched.acquire(OpPriority::Maintenance);
+        let mut audit = AuditScope::new("migrate aliases");
+        let res = isolated_segment!(&mut audit, || {
+            self.qs.write().and_then(|mut qs_write| {
+                qs_write
+                    .migrate_aliases(&mut audit, ATTR_MIGRATE_BATCH)
+                    .and_then(|n| qs_write.commit(&mut audit).map(|_| n))
+            })
+        });
+        // Whatever is left is picked up at the next interval.
+        match res {
+            Ok(n) => debug!("Migrated {} entries off attribute aliases", n),
+            // Our primary migrates them, and we get the result.
+            Err(OperationError::ReadReplica(_)) => {}
+            Err(e) => error!("Unable to migrate attribute aliases -> {:?}", e),
+        }
+        self.log.do_send(audit);
+    }
+}
+
+impl Handler<DbHeartbeatEvent> for QueryServerWriteV1 {
+    type Result = ();
+
+    fn handle(&mut self, _msg: DbHeartbeatEvent, _: &mut Self::Context) -> Self::Result {
+        let _ticket = self.sched.acquire(OpPriority::Maintenance);
+        let mut audit = AuditScope::new("db heartbeat");
+        let res = self.qs.heartbeat(&mut audit);
+        if res.is_err() {
+            // This has already been reported loudly, keep the detail too.
+            error!("Database heartbeat failed -> {:?}", res);
+        }
+        // Keep the index usage for warming up on the next start. Losing a
+        // round of this to a busy database is fine.
+        let res = self.qs.flush_idx_usage(&mut audit);
+        if res.is_err() {
+            debug!("Index usage flush failed -> {:?}", res);
+        }
+        let res = self.qs.flush_slow_queries(&mut audit);
+        if res.is_err() {
+            debug!("Slow query flush failed -> {:?}", res);
+        }
+        // Unlike usage, journal records are kept and retried until written.
+        let res = self.qs.flush_access_journal(&mut audit);
+        if res.is_err() {
+            error!("Access journal flush failed -> {:?}", res);
+        }
+        self.log.do_send(audit);
+    }
+}
@@ -1,6 +1,14 @@
 use actix::prelude::*;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
 
 use crate::audit::AuditScope;
+use crate::config::LogRedaction;
+use crate::event::uat_is_elevated;
+use kanidm_proto::v1::Filter as ProtoFilter;
+use kanidm_proto::v1::{OperationError, UserAuthToken};
 
 // Helper for internal logging.
 // Should only be used at startup/shutdown
@@ -25,11 +33,45 @@ macro_rules! log_event {
 // so that we don't msg unless it's the correct level?
 // Do we need config in the log macro?
 
-pub fn start() -> actix::Addr<EventLog> {
-    SyncArbiter::start(1, move || EventLog {})
+pub fn start(redaction: LogRedaction, access_log: Option<PathBuf>) -> actix::Addr<EventLog> {
+    // Opened once here, so a bad path is reported at startup rather than on
+    // the first operation.
+    let access_file = match access_log {
+        Some(p) => match OpenOptions::new().create(true).append(true).open(&p) {
+            Ok(f) => Some(f),
+            Err(e) => {
+                error!("Failed to open access log {:?} -> {:?}", p, e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+    SyncArbiter::start(1, move || EventLog {
+        redaction: redaction.clone(),
+        access_file: access_file.as_ref().and_then(|f| f.try_clone().ok()),
+    })
 }
 
-pub struct EventLog {}
+pub struct EventLog {
+    // How values in access log filters are shown.
+    redaction: LogRedaction,
+    // Where access lines go. Without one they go to the server log.
+    access_file: Option<File>,
+}
+
+impl EventLog {
+    fn write_access(&mut self, event: &AccessLogEvent) {
+        let line = event.to_log_string(&self.redaction);
+        match self.access_file.as_mut() {
+            Some(f) => {
+                if let Err(e) = writeln!(f, "{}", line) {
+                    error!("Failed to write access log -> {:?}", e);
+                }
+            }
+            None => info!("access: {}", line),
+        }
+    }
+}
 
 impl Actor for EventLog {
     type Context = SyncContext<Self>;
@@ -65,11 +107,129 @@ impl Handler<LogEvent> for EventLog {
 impl Handler<AuditScope> for EventLog {
     type Result = ();
 
-    fn handle(&mut self, event: AuditScope, _: &mut SyncContext<Self>) -> Self::Result {
+    fn handle(&mut self, mut event: AuditScope, _: &mut SyncContext<Self>) -> Self::Result {
+        if let Some(access) = event.take_access() {
+            self.write_access(&access);
+        }
         debug!("audit: {}", event);
     }
 }
 
+// The access log is a single summary line per operation - who did it, what it
+// was, how many results, and how it went. Filters can contain sensitive values
+// (think searching by a persons name) so values are redacted when written, while
+// attribute names are kept so that the query shape is still useful.
+//
+// It travels with the operation's audit scope, so one message to the log
+// actor carries both.
+#[derive(Debug)]
+pub struct AccessLogEvent {
+    who: String,
+    operation: &'static str,
+    filter: Option<ProtoFilter>,
+    result_count: Option<usize>,
+    start: Instant,
+    duration: Option<Duration>,
+    outcome: Option<String>,
+}
+
+impl AccessLogEvent {
+    pub fn new(operation: &'static str, uat: &Option<UserAuthToken>) -> Self {
+        AccessLogEvent {
+            who: match uat {
+                Some(uat) => Self::describe(uat),
+                None => "unauthenticated".to_string(),
+            },
+            operation: operation,
+            filter: None,
+            result_count: None,
+            start: Instant::now(),
+            duration: None,
+            outcome: None,
+        }
+    }
+
+    fn describe(uat: &UserAuthToken) -> String {
+        if uat_is_elevated(uat) {
+            format!("{} ({}) elevated", uat.name, uat.uuid)
+        } else {
+            format!("{} ({})", uat.name, uat.uuid)
+        }
+    }
+
+    // For auth, where who it was is only known once it succeeds.
+    pub fn set_who(&mut self, uat: &UserAuthToken) {
+        self.who = Self::describe(uat);
+    }
+
+    pub fn set_filter(&mut self, f: ProtoFilter) {
+        self.filter = Some(f);
+    }
+
+    pub fn set_result_count(&mut self, c: usize) {
+        self.result_count = Some(c);
+    }
+
+    // Mark the operation as done, and take the outcome from the result.
+    pub fn complete<T>(mut self, res: &Result<T, OperationError>) -> Self {
+        self.duration = Some(self.start.elapsed());
+        self.outcome = res.as_ref().err().map(|e| format!("{:?}", e));
+        self
+    }
+
+    fn to_log_string(&self, redaction: &LogRedaction) -> String {
+        let filter = match &self.filter {
+            Some(f) => redact_filter(f, redaction),
+            None => "-".to_string(),
+        };
+        let count = match self.result_count {
+            Some(c) => c.to_string(),
+            None => "-".to_string(),
+        };
+        let outcome = match &self.outcome {
+            Some(e) => e.as_str(),
+            None => "success",
+        };
+        format!(
+            "who={} op={} filter={} results={} duration={:?} outcome={}",
+            self.who,
+            self.operation,
+            filter,
+            count,
+            self.duration.unwrap_or_else(|| self.start.elapsed()),
+            outcome
+        )
+    }
+}
+
+pub fn redact_filter(f: &ProtoFilter, redaction: &LogRedaction) -> String {
+    match f {
+        ProtoFilter::Eq(a, v) => format!("({}={})", a, redaction.redact(v)),
+        ProtoFilter::Sub(a, v) => format!("({}=*{}*)", a, redaction.redact(v)),
+        ProtoFilter::Fuzzy(a, v) => format!("({}~={})", a, redaction.redact(v)),
+        ProtoFilter::Ge(a, v) => format!("({}>={})", a, redaction.redact(v)),
+        ProtoFilter::Le(a, v) => format!("({}<={})", a, redaction.redact(v)),
+        ProtoFilter::Regex(a, v) => format!("({}=~{})", a, redaction.redact(v)),
+        ProtoFilter::Pres(a) => format!("({}=*)", a),
+        ProtoFilter::Or(l) => format!(
+            "(|{})",
+            l.iter()
+                .map(|f| redact_filter(f, redaction))
+                .collect::<Vec<_>>()
+                .concat()
+        ),
+        ProtoFilter::And(l) => format!(
+            "(&{})",
+            l.iter()
+                .map(|f| redact_filter(f, redaction))
+                .collect::<Vec<_>>()
+                .concat()
+        ),
+        ProtoFilter::AndNot(f) => format!("(!{})", redact_filter(f, redaction)),
+        ProtoFilter::SelfUUID => "(self)".to_string(),
+    }
+}
+
 /*
 impl Handler<Event> for EventLog {
     type Result = ();
@@ -79,3 +239,61 @@ impl Handler<Event> for EventLog {
     }
 }
 */
+
+#[cfg(test)]
+mod tests {
+    use crate::async_log::{redact_filter, AccessLogEvent};
+    use crate::audit::AuditScope;
+    use crate::config::LogRedaction;
+    use kanidm_proto::v1::Filter as ProtoFilter;
+    use kanidm_proto::v1::{OperationError, UserAuthToken};
+
+    #[test]
+    fn test_access_log_redact_filter() {
+        let f = ProtoFilter::And(vec![
+            ProtoFilter::Eq("name".to_string(), "claire".to_string()),
+            ProtoFilter::AndNot(Box::new(ProtoFilter::Pres("class".to_string()))),
+        ]);
+
+        assert_eq!(
+            redact_filter(&f, &LogRedaction::Clear),
+            "(&(name=claire)(!(class=*)))"
+        );
+        assert_eq!(
+            redact_filter(&f, &LogRedaction::Truncate(2)),
+            "(&(name=cl...)(!(class=*)))"
+        );
+        let hashed = redact_filter(&f, &LogRedaction::Hash);
+        assert!(!hashed.contains("claire"));
+        assert!(hashed.starts_with("(&(name=#"));
+        // The same value must always hash the same so queries can be correlated.
+        assert_eq!(hashed, redact_filter(&f, &LogRedaction::Hash));
+    }
+
+    #[test]
+    fn test_access_log_auth_identity() {
+        let mut access = AccessLogEvent::new("auth", &None);
+        let line = access.to_log_string(&LogRedaction::Hash);
+        assert!(line.starts_with("who=unauthenticated op=auth"));
+
+        access.set_who(&UserAuthToken {
+            name: "admin".to_string(),
+            displayname: "admin".to_string(),
+            uuid: "00000000-0000-0000-0000-000000000000".to_string(),
+            application: None,
+            groups: Vec::new(),
+            claims: Vec::new(),
+            elevated_until: None,
+        });
+
+        // It goes to the log actor inside the audit scope.
+        let mut audit = AuditScope::new("auth");
+        let r: Result<(), OperationError> = Ok(());
+        audit.set_access(access.complete(&r));
+        let access = audit.take_access().expect("No access event");
+        let line = access.to_log_string(&LogRedaction::Hash);
+        assert!(line.starts_with("who=admin (00000000-0000-0000-0000-000000000000) op=auth"));
+        assert!(line.ends_with("outcome=success"));
+        assert!(audit.take_access().is_none());
+    }
+}
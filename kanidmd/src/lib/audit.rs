@@ -1,5 +1,7 @@
 use actix::prelude::*;
 use std::fmt;
+
+use crate::async_log::AccessLogEvent;
 use std::time::Duration;
 use std::time::SystemTime;
 
@@ -56,6 +58,24 @@ macro_rules! audit_segment {
     }};
 }
 
+// As audit_segment, but a panic in fun is caught rather than unwinding out of
+// the worker. The panic and its backtrace go to the audit log, and the result
+// is FromPanic::from_panic, IE Err(OperationError::Panicked).
+macro_rules! isolated_segment {
+    ($au:expr, $fun:expr) => {{
+        use crate::isolate::{catch_panic, FromPanic};
+
+        audit_segment!($au, || match catch_panic($fun) {
+            Ok(r) => r,
+            Err(p) => {
+                audit_log!($au, "operation panicked -> {}", p);
+                error!("An operation panicked, the audit log has the backtrace");
+                FromPanic::from_panic()
+            }
+        })
+    }};
+}
+
 macro_rules! try_audit {
     ($audit:ident, $result:expr, $logFormat:expr, $errorType:expr) => {
         match $result {
@@ -110,6 +130,10 @@ pub struct AuditScope {
     name: String,
     duration: Option<Duration>,
     events: Vec<AuditEvent>,
+    // The operation's access log line, written by the log actor alongside
+    // the scope.
+    #[serde(skip)]
+    access: Option<AccessLogEvent>,
 }
 
 // Allow us to be sent to the log subsystem
@@ -136,6 +160,7 @@ impl AuditScope {
             name: String::from(name),
             duration: None,
             events: Vec::new(),
+            access: None,
         }
     }
 
@@ -152,6 +177,14 @@ impl AuditScope {
         self.events.push(AuditEvent::Scope(scope))
     }
 
+    pub fn set_access(&mut self, access: AccessLogEvent) {
+        self.access = Some(access);
+    }
+
+    pub fn take_access(&mut self) -> Option<AccessLogEvent> {
+        self.access.take()
+    }
+
     pub fn log_event(&mut self, data: String) {
         let t_now = SystemTime::now();
         let datetime: DateTime<Utc> = t_now.into();
@@ -174,5 +207,4 @@ mod tests {
         let d = serde_json::to_string_pretty(&au).expect("Json serialise failure");
         println!("{}", d);
     }
-
 }
@@ -0,0 +1,239 @@
+// A capture of what the backend writes over a window of time, so that a bug
+// we can't otherwise reproduce - index corruption being the usual one - can
+// be replayed by a developer against a scratch database, deterministically
+// and as often as needed. See `kanidmd replay`.
+//
+// The capture is json lines of CaptureRecord. The first is a snapshot of
+// every entry, taken as the first write txn of the window begins, so the
+// capture stands alone. Each record after is one committed write txn, with
+// the entries it wrote and the ids it deleted, by id, so the replay puts them
+// exactly where they were.
+//
+// Values are anonymised: each string is replaced by a keyed hash of itself
+// of the same length, under a key that is never written down. Equal values
+// stay equal, so the indexes see the same shape of keys, but what they were
+// can't be recovered from the capture. uuids and references are the structure
+// rather than the content, so are kept, as are class names so that the
+// entries still make some sense to whoever reads them.
+
+use crate::be::dbentry::{DbEntry, DbEntryVers};
+use crate::be::dbvalue::{DbPasswordV1, DbValueTaggedStringV1, DbValueV1};
+use openssl::sha::Sha256;
+use rand::prelude::*;
+use serde_json;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+// Stands in for every json filter, as the real one holds values.
+const CAPTURE_JSON_FILTER: &str = "{\"Pres\":\"class\"}";
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum CaptureOp {
+    // The entries written, by id. A write to an id that exists replaces it.
+    Write(Vec<(u64, DbEntry)>),
+    Delete(Vec<u64>),
+    Reindex,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CaptureRecord {
+    // 0 is the snapshot, and the txns are numbered from 1 after it.
+    pub txn: u64,
+    // The indexes kept from this txn on, as (attr, idx str). Only given when
+    // they changed from the record before.
+    pub idxmeta: Option<Vec<(String, String)>>,
+    pub ops: Vec<CaptureOp>,
+}
+
+struct CaptureState {
+    // None once the window has passed.
+    writer: Option<BufWriter<File>>,
+    until: Instant,
+    txn: u64,
+    idxmeta: Option<Vec<(String, String)>>,
+}
+
+#[derive(Clone)]
+pub struct Capture {
+    key: [u8; 32],
+    inner: Arc<Mutex<CaptureState>>,
+}
+
+impl Capture {
+    pub fn new(path: &str, window: Duration) -> io::Result<Self> {
+        let writer = BufWriter::new(File::create(path)?);
+        let mut key = [0; 32];
+        StdRng::from_entropy().fill(&mut key);
+        Ok(Capture {
+            key: key,
+            inner: Arc::new(Mutex::new(CaptureState {
+                writer: Some(writer),
+                until: Instant::now() + window,
+                txn: 0,
+                idxmeta: None,
+            })),
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, CaptureState> {
+        self.inner
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    // Whether a write txn beginning now should be captured.
+    pub fn is_active(&self) -> bool {
+        let inner = self.lock();
+        inner.writer.is_some() && Instant::now() < inner.until
+    }
+
+    // Whether the snapshot is yet to be taken.
+    pub fn needs_snapshot(&self) -> bool {
+        let inner = self.lock();
+        inner.writer.is_some() && inner.idxmeta.is_none()
+    }
+
+    // Write a txn's record, and close the capture once the window has passed.
+    // The snapshot is the record written with txn 0.
+    pub fn record(&self, idxmeta: Vec<(String, String)>, ops: Vec<CaptureOp>) -> io::Result<()> {
+        let mut inner = self.lock();
+        let inner = &mut *inner;
+        let writer = match inner.writer.as_mut() {
+            Some(w) => w,
+            None => return Ok(()),
+        };
+        let snapshot = inner.idxmeta.is_none();
+        let record = CaptureRecord {
+            txn: if snapshot { 0 } else { inner.txn + 1 },
+            idxmeta: if inner.idxmeta.as_ref() == Some(&idxmeta) {
+                None
+            } else {
+                Some(idxmeta.clone())
+            },
+            ops: ops,
+        };
+        serde_json::to_writer(&mut *writer, &record)?;
+        writer.write_all(b"\n")?;
+        // A capture is most wanted after a crash, so don't hold on to it.
+        writer.flush()?;
+        inner.txn = record.txn;
+        inner.idxmeta = Some(idxmeta);
+        if Instant::now() >= inner.until {
+            info!("backend capture finished after {} txns", inner.txn);
+            inner.writer = None;
+        }
+        Ok(())
+    }
+
+    // Give up on the capture, as it can't be completed.
+    pub fn abandon(&self) {
+        self.lock().writer = None;
+    }
+
+    fn anon_str(&self, s: &str) -> String {
+        let len = s.chars().count();
+        let mut out = String::with_capacity(len);
+        let mut round: u32 = 0;
+        while out.len() < len {
+            let mut h = Sha256::new();
+            h.update(&self.key);
+            h.update(&round.to_le_bytes());
+            h.update(s.as_bytes());
+            out.extend(h.finish().iter().map(|b| format!("{:02x}", b)));
+            round += 1;
+        }
+        out.truncate(len);
+        out
+    }
+
+    fn anon_value(&self, v: DbValueV1) -> DbValueV1 {
+        match v {
+            DbValueV1::U8(s) => DbValueV1::U8(self.anon_str(s.as_str())),
+            DbValueV1::I8(s) => DbValueV1::I8(self.anon_str(s.as_str())),
+            DbValueV1::RU(s) => DbValueV1::RU(self.anon_str(s.as_str())),
+            DbValueV1::JF(_) => DbValueV1::JF(CAPTURE_JSON_FILTER.to_string()),
+            DbValueV1::SK(ts) => DbValueV1::SK(DbValueTaggedStringV1 {
+                t: self.anon_str(ts.t.as_str()),
+                d: self.anon_str(ts.d.as_str()),
+            }),
+            DbValueV1::CR(mut cr) => {
+                cr.d.password = cr.d.password.map(|pw| match pw {
+                    DbPasswordV1::PBKDF2(cost, salt, hash) => {
+                        DbPasswordV1::PBKDF2(cost, vec![0; salt.len()], vec![0; hash.len()])
+                    }
+                });
+                DbValueV1::CR(cr)
+            }
+            v => v,
+        }
+    }
+
+    pub fn anonymise(&self, dbe: DbEntry) -> DbEntry {
+        let v1 = match dbe.ent {
+            DbEntryVers::V1(mut v1) => {
+                for (attr, vs) in v1.attrs.iter_mut() {
+                    if attr == "class" {
+                        continue;
+                    }
+                    let anon: Vec<_> = vs.drain(..).map(|v| self.anon_value(v)).collect();
+                    *vs = anon;
+                }
+                v1
+            }
+        };
+        DbEntry {
+            ent: DbEntryVers::V1(v1),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Capture;
+    use crate::be::dbentry::{DbEntry, DbEntryV1, DbEntryVers};
+    use crate::be::dbvalue::DbValueV1;
+    use std::collections::BTreeMap;
+    use std::time::Duration;
+    use uuid::Uuid;
+
+    fn attr(dbe: &DbEntry, a: &str) -> String {
+        match &dbe.ent {
+            DbEntryVers::V1(v1) => format!("{:?}", v1.attrs.get(a)),
+        }
+    }
+
+    #[test]
+    fn test_capture_anonymise() {
+        let path = "/tmp/kanidm_test_capture_anonymise.json";
+        let capture = Capture::new(path, Duration::from_secs(60)).expect("capture failed");
+        let u = Uuid::new_v4();
+        let mk = |name: &str| {
+            let mut attrs = BTreeMap::new();
+            attrs.insert(
+                "class".to_string(),
+                vec![DbValueV1::I8("person".to_string())],
+            );
+            attrs.insert("name".to_string(), vec![DbValueV1::I8(name.to_string())]);
+            attrs.insert("uuid".to_string(), vec![DbValueV1::UU(u)]);
+            DbEntry {
+                ent: DbEntryVers::V1(DbEntryV1 { attrs: attrs }),
+            }
+        };
+
+        let a = capture.anonymise(mk("william"));
+        let b = capture.anonymise(mk("william"));
+        let c = capture.anonymise(mk("claire"));
+        // The same value is always replaced the same way, and keeps its
+        // length, but isn't itself.
+        assert_eq!(attr(&a, "name"), attr(&b, "name"));
+        assert!(attr(&a, "name") != attr(&c, "name"));
+        assert!(!attr(&a, "name").contains("william"));
+        assert_eq!(attr(&a, "name").len(), attr(&mk("william"), "name").len());
+        // Structure is kept.
+        assert_eq!(attr(&a, "class"), attr(&mk("william"), "class"));
+        assert_eq!(attr(&a, "uuid"), attr(&mk("william"), "uuid"));
+        let _ = std::fs::remove_file(path);
+    }
+}
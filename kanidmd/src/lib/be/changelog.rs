@@ -0,0 +1,113 @@
+// Every committed create, modify and delete, with who made it, written to the
+// changelog table in the same transaction as the change itself. The records
+// are chained in the same way as the access journal, so that after an
+// incident it can be shown that the log wasn't edited after the fact.
+//
+// The chain alone can be rebuilt by anyone with write access to the database,
+// so if a changelog key is configured each hash is also signed with it
+// (HMAC-SHA256). Once a signed record is seen every later one must be signed,
+// else stripping the signatures along with an edit would go unnoticed.
+
+use crate::be::journal::JournalRecord;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+
+pub fn sign(key: &[u8; 32], hash: &str) -> Result<String, ()> {
+    let pkey = PKey::hmac(key).map_err(|_| ())?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &pkey).map_err(|_| ())?;
+    signer.update(hash.as_bytes()).map_err(|_| ())?;
+    let sig = signer.sign_to_vec().map_err(|_| ())?;
+    let hs: Vec<String> = sig.iter().map(|b| format!("{:02x}", b)).collect();
+    Ok(hs.concat())
+}
+
+// As read back in order, the id, the record, its hash and its signature if
+// it has one.
+pub type ChangelogRow = (i64, JournalRecord, String, Option<String>);
+
+// The id of the first record whose hash doesn't follow from the one before it,
+// or whose signature can't be verified with key.
+pub fn first_invalid(changelog: &[ChangelogRow], key: Option<&[u8; 32]>) -> Option<i64> {
+    let mut prev = "";
+    let mut signed = false;
+    for (id, rec, hash, sig) in changelog {
+        if rec.chain_hash(prev) != *hash {
+            return Some(*id);
+        }
+        match (key, sig) {
+            (Some(key), Some(sig)) => {
+                if sign(key, hash.as_str()).ok().as_ref() != Some(sig) {
+                    return Some(*id);
+                }
+                signed = true;
+            }
+            (Some(_), None) => {
+                if signed {
+                    return Some(*id);
+                }
+            }
+            // Without the key only the chain can be checked.
+            (None, _) => {}
+        }
+        prev = hash.as_str();
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{first_invalid, sign, ChangelogRow};
+    use crate::be::journal::JournalRecord;
+
+    fn changelog(key: Option<&[u8; 32]>, n: usize) -> Vec<ChangelogRow> {
+        let mut prev = String::new();
+        (1..=n as i64)
+            .map(|id| {
+                let rec = JournalRecord {
+                    time: "20200101000000Z".to_string(),
+                    uuid: format!("{}", id),
+                    identity: "admin".to_string(),
+                    operation: "modify".to_string(),
+                };
+                prev = rec.chain_hash(prev.as_str());
+                let sig = key.map(|k| sign(k, prev.as_str()).unwrap());
+                (id, rec, prev.clone(), sig)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_be_changelog_signed() {
+        let key = [3; 32];
+        let mut rows = changelog(Some(&key), 3);
+        assert_eq!(first_invalid(rows.as_slice(), Some(&key)), None);
+        assert_eq!(first_invalid(rows.as_slice(), None), None);
+        assert_eq!(first_invalid(rows.as_slice(), Some(&[4; 32])), Some(1));
+
+        // Rebuilding the chain after an edit still fails the signatures.
+        let mut prev = String::new();
+        rows[1].1.identity = "x".to_string();
+        rows.iter_mut().for_each(|(_, rec, hash, _)| {
+            *hash = rec.chain_hash(prev.as_str());
+            prev = hash.clone();
+        });
+        assert_eq!(first_invalid(rows.as_slice(), None), None);
+        assert_eq!(first_invalid(rows.as_slice(), Some(&key)), Some(2));
+
+        // As does removing the signatures from there on.
+        rows[1].3 = None;
+        rows[2].3 = None;
+        assert_eq!(first_invalid(rows.as_slice(), Some(&key)), Some(2));
+    }
+
+    #[test]
+    fn test_be_changelog_unsigned() {
+        let key = [3; 32];
+        // Records from before a key was configured are accepted.
+        let mut rows = changelog(None, 2);
+        assert_eq!(first_invalid(rows.as_slice(), Some(&key)), None);
+        rows.remove(0);
+        assert_eq!(first_invalid(rows.as_slice(), Some(&key)), Some(2));
+    }
+}
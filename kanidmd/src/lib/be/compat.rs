@@ -0,0 +1,27 @@
+// Restore has to keep accepting backups taken by every earlier release. How an
+// entry is represented is versioned by DbEntryVers, and when a new version is
+// added, the step bringing the one before it up to date belongs here, so that
+// restore and everything after it only deal with the latest. Entries read
+// from id2entry come through here too, by DbEntry::from_blob, so a database
+// written by an earlier release needs no migration before it can be used.
+//
+// The backups earlier releases wrote are kept in be/fixtures, and
+// test_be_restore_fixtures restores each of them:
+//
+// * backup_pretty.json - one pretty printed array, before backups were streamed.
+// * backup_streamed.json - streamed from id2entry an entry at a time.
+// * backup_streamed.json.gz, backup_streamed.json.zst - compressed.
+// * backup_ndjson.json - newline delimited, as import tools tend to write.
+// * backup_incremental.json - an incremental backup following on from the above.
+//
+// A release that changes any of these must add a fixture of its own, not
+// replace the old one.
+
+use crate::be::dbentry::{DbEntry, DbEntryVers};
+
+pub fn upgrade_dbentry(dbe: DbEntry) -> Result<DbEntry, ()> {
+    match dbe.ent {
+        // The current version, nothing to do.
+        DbEntryVers::V1(_) => Ok(dbe),
+    }
+}
@@ -0,0 +1,176 @@
+// The candidates of paged searches, so that each page comes from the set the
+// search first resolved to, rather than the ids shifting under the client as
+// entries are added and removed between pages. Cursors are only kept in
+// memory, and are dropped once the last page is read or they go unused for
+// CURSOR_TTL.
+
+use crate::be::sort::SortSpec;
+use crate::filter::{Filter, FilterValidResolved};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+pub const CURSOR_TTL: Duration = Duration::from_secs(300);
+// Past this the oldest is dropped to make room, so clients that never finish
+// their searches can't grow this without bound.
+pub const CURSOR_MAX: usize = 1024;
+
+struct SearchCursor {
+    // In the order they are returned.
+    ids: Arc<Vec<u64>>,
+    // A cursor may only be continued by who began it, with the same filter
+    // and sort.
+    owner: Option<Uuid>,
+    filter: Filter<FilterValidResolved>,
+    sort: Option<SortSpec>,
+    expires: Instant,
+}
+
+#[derive(Clone)]
+pub struct SearchCursors {
+    inner: Arc<Mutex<BTreeMap<Uuid, SearchCursor>>>,
+}
+
+// What a client is handed, IE <cursor uuid>:<offset>. The offset is in the
+// token rather than the cursor, so repeating a request after a lost response
+// returns the same page again.
+pub fn cursor_token(id: &Uuid, offset: usize) -> String {
+    format!("{}:{}", id.to_hyphenated_ref(), offset)
+}
+
+pub fn parse_cursor_token(token: &str) -> Option<(Uuid, usize)> {
+    let mut parts = token.splitn(2, ':');
+    let id = parts.next().and_then(|s| Uuid::parse_str(s).ok())?;
+    let offset = parts.next().and_then(|s| s.parse().ok())?;
+    Some((id, offset))
+}
+
+impl SearchCursors {
+    pub fn new() -> Self {
+        SearchCursors {
+            inner: Arc::new(Mutex::new(BTreeMap::new())),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, BTreeMap<Uuid, SearchCursor>> {
+        // At worst a client has to start its search again.
+        self.inner
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn insert(
+        &self,
+        ids: Arc<Vec<u64>>,
+        owner: Option<&Uuid>,
+        filter: &Filter<FilterValidResolved>,
+        sort: Option<&SortSpec>,
+    ) -> Uuid {
+        let now = Instant::now();
+        let mut inner = self.lock();
+        inner.retain(|_, c| c.expires > now);
+        while inner.len() >= CURSOR_MAX {
+            let oldest = inner
+                .iter()
+                .min_by_key(|(_, c)| c.expires)
+                .map(|(id, _)| *id);
+            match oldest {
+                Some(id) => inner.remove(&id),
+                None => break,
+            };
+        }
+        let id = Uuid::new_v4();
+        inner.insert(
+            id,
+            SearchCursor {
+                ids: ids,
+                owner: owner.cloned(),
+                filter: filter.clone(),
+                sort: sort.cloned(),
+                expires: now + CURSOR_TTL,
+            },
+        );
+        id
+    }
+
+    // The candidates of the cursor, if it exists and was begun by owner with
+    // filter and sort. Each use extends its life.
+    pub fn get(
+        &self,
+        id: &Uuid,
+        owner: Option<&Uuid>,
+        filter: &Filter<FilterValidResolved>,
+        sort: Option<&SortSpec>,
+    ) -> Option<Arc<Vec<u64>>> {
+        let now = Instant::now();
+        let mut inner = self.lock();
+        match inner.get_mut(id) {
+            Some(c)
+                if c.expires > now
+                    && c.owner.as_ref() == owner
+                    && c.filter == *filter
+                    && c.sort.as_ref() == sort =>
+            {
+                c.expires = now + CURSOR_TTL;
+                Some(c.ids.clone())
+            }
+            _ => None,
+        }
+    }
+
+    pub fn remove(&self, id: &Uuid) {
+        self.lock().remove(id);
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{cursor_token, parse_cursor_token, SearchCursors, CURSOR_MAX};
+    use crate::be::sort::SortSpec;
+    use std::sync::Arc;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_be_search_cursors() {
+        let f_a = unsafe { filter_resolved!(f_pres("name")) };
+        let f_b = unsafe { filter_resolved!(f_pres("uuid")) };
+        let owner = Uuid::new_v4();
+        let cursors = SearchCursors::new();
+
+        let id = cursors.insert(Arc::new(vec![1, 2, 3]), Some(&owner), &f_a, None);
+        assert_eq!(
+            cursors
+                .get(&id, Some(&owner), &f_a, None)
+                .map(|ids| ids.to_vec()),
+            Some(vec![1, 2, 3])
+        );
+        // Not for anyone else, or for another search.
+        assert!(cursors.get(&id, None, &f_a, None).is_none());
+        assert!(cursors
+            .get(&id, Some(&Uuid::new_v4()), &f_a, None)
+            .is_none());
+        assert!(cursors.get(&id, Some(&owner), &f_b, None).is_none());
+        let sort = SortSpec::new("name", false);
+        assert!(cursors.get(&id, Some(&owner), &f_a, Some(&sort)).is_none());
+
+        cursors.remove(&id);
+        assert!(cursors.get(&id, Some(&owner), &f_a, None).is_none());
+
+        let first = cursors.insert(Arc::new(Vec::new()), None, &f_a, None);
+        for _ in 0..CURSOR_MAX {
+            cursors.insert(Arc::new(Vec::new()), None, &f_a, None);
+        }
+        assert_eq!(cursors.len(), CURSOR_MAX);
+        assert!(cursors.get(&first, None, &f_a, None).is_none());
+
+        let token = cursor_token(&id, 20);
+        assert_eq!(parse_cursor_token(token.as_str()), Some((id, 20)));
+        assert_eq!(parse_cursor_token("20"), None);
+        assert_eq!(parse_cursor_token("not a cursor:1"), None);
+    }
+}
@@ -1,5 +1,30 @@
+use crate::be::compat;
 use crate::be::dbvalue::DbValueV1;
+use kanidm_proto::v1::OperationError;
+use openssl::sha::sha256;
+use serde_cbor;
 use std::collections::BTreeMap;
+use zstd;
+
+// Every id2entry blob starts with a two byte header, DBENTRY_MAGIC and then
+// the format the rest is in. No cbor item starts with 0xff (it's the break
+// code), so blobs written before there was a header are told apart, and read
+// as DBENTRY_FORMAT_LEGACY.
+const DBENTRY_MAGIC: u8 = 0xff;
+// Bare cbor of a DbEntry, with no header.
+pub const DBENTRY_FORMAT_LEGACY: u8 = 0;
+// Cbor of a DbEntry.
+pub const DBENTRY_FORMAT_CBOR: u8 = 1;
+// Cbor of a DbEntry, zstd compressed. Worth it for large entries, such as
+// groups with many members.
+pub const DBENTRY_FORMAT_CBOR_ZSTD: u8 = 2;
+// The zstd level entries are compressed at. Entries are written far more
+// often than backups, so this favours speed.
+const DBENTRY_ZSTD_LEVEL: i32 = 3;
+// Values larger than this are kept out of the entry, in id2blob, so that a
+// modify of some other attribute doesn't rewrite them. Photos and
+// certificates are the usual suspects.
+pub const DBENTRY_BLOB_THRESHOLD: usize = 4096;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct DbEntryV1 {
@@ -19,3 +44,142 @@ pub enum DbEntryVers {
 pub struct DbEntry {
     pub ent: DbEntryVers,
 }
+
+impl DbEntry {
+    // Every format but DBENTRY_FORMAT_LEGACY can be written.
+    pub fn to_blob(&self, format: u8) -> Result<Vec<u8>, OperationError> {
+        let cbor = serde_cbor::to_vec(self).map_err(|_| OperationError::SerdeCborError)?;
+        let body = match format {
+            DBENTRY_FORMAT_CBOR => cbor,
+            DBENTRY_FORMAT_CBOR_ZSTD => {
+                zstd::stream::encode_all(cbor.as_slice(), DBENTRY_ZSTD_LEVEL)
+                    .map_err(|_| OperationError::SerdeCborError)?
+            }
+            _ => return Err(OperationError::InvalidDBState),
+        };
+        let mut data = Vec::with_capacity(body.len() + 2);
+        data.push(DBENTRY_MAGIC);
+        data.push(format);
+        data.extend(body);
+        Ok(data)
+    }
+
+    // The entry in data, and the format it was stored in so the caller can
+    // tell if it's due a rewrite. However it was stored, the entry is brought
+    // up to date before it's returned.
+    //
+    // A new format needs an arm in decode_blob, and a new DbEntryVers a step
+    // in compat::upgrade_dbentry. Neither should ever be removed, as there
+    // is no telling what's still on disk.
+    pub fn from_blob(data: &[u8]) -> Result<(Self, u8), OperationError> {
+        let (format, body) = match data.split_first() {
+            Some((&DBENTRY_MAGIC, rest)) => match rest.split_first() {
+                Some((&format, body)) => (format, body),
+                None => return Err(OperationError::SerdeCborError),
+            },
+            _ => (DBENTRY_FORMAT_LEGACY, data),
+        };
+        let dbe = decode_blob(format, body)?;
+        compat::upgrade_dbentry(dbe)
+            .map(|dbe| (dbe, format))
+            .map_err(|_| OperationError::SerdeCborError)
+    }
+
+    // Move the values over DBENTRY_BLOB_THRESHOLD out through put, which is
+    // given the hex sha256 of the value's cbor and the cbor, and refer to them
+    // by that hash instead. Also returns the hashes the entry now refers to,
+    // so the caller knows which blobs are in use.
+    pub fn externalise<F>(self, mut put: F) -> Result<(Self, Vec<String>), OperationError>
+    where
+        F: FnMut(&str, &[u8]) -> Result<(), OperationError>,
+    {
+        let mut v1 = match self.ent {
+            DbEntryVers::V1(v1) => v1,
+        };
+        let mut refs = Vec::new();
+        for vs in v1.attrs.values_mut() {
+            for v in vs.iter_mut() {
+                if let DbValueV1::XB(h) = v {
+                    refs.push(h.clone());
+                    continue;
+                }
+                if !is_large(v) {
+                    continue;
+                }
+                let data = serde_cbor::to_vec(v).map_err(|_| OperationError::SerdeCborError)?;
+                let hash: String = sha256(data.as_slice())
+                    .iter()
+                    .map(|b| format!("{:02x}", b))
+                    .collect();
+                put(hash.as_str(), data.as_slice())?;
+                refs.push(hash.clone());
+                *v = DbValueV1::XB(hash);
+            }
+        }
+        Ok((
+            DbEntry {
+                ent: DbEntryVers::V1(v1),
+            },
+            refs,
+        ))
+    }
+
+    // The reverse of externalise, with get giving the cbor of a hash.
+    pub fn internalise<F>(self, get: F) -> Result<Self, OperationError>
+    where
+        F: Fn(&str) -> Result<Option<Vec<u8>>, OperationError>,
+    {
+        let mut v1 = match self.ent {
+            DbEntryVers::V1(v1) => v1,
+        };
+        for vs in v1.attrs.values_mut() {
+            for v in vs.iter_mut() {
+                let data = match v {
+                    DbValueV1::XB(h) => get(h.as_str())?.ok_or(OperationError::InvalidDBState)?,
+                    _ => continue,
+                };
+                *v = serde_cbor::from_slice(data.as_slice())
+                    .map_err(|_| OperationError::SerdeCborError)?;
+            }
+        }
+        Ok(DbEntry {
+            ent: DbEntryVers::V1(v1),
+        })
+    }
+}
+
+// Only the types that can hold arbitrary strings grow large enough.
+fn is_large(v: &DbValueV1) -> bool {
+    match v {
+        DbValueV1::U8(s) | DbValueV1::I8(s) | DbValueV1::JF(s) | DbValueV1::RU(s) => {
+            s.len() > DBENTRY_BLOB_THRESHOLD
+        }
+        DbValueV1::SK(ts) => ts.d.len() > DBENTRY_BLOB_THRESHOLD,
+        _ => false,
+    }
+}
+
+fn decode_blob(format: u8, body: &[u8]) -> Result<DbEntry, OperationError> {
+    match format {
+        DBENTRY_FORMAT_LEGACY | DBENTRY_FORMAT_CBOR => {
+            serde_cbor::from_slice(body).map_err(|_| OperationError::SerdeCborError)
+        }
+        DBENTRY_FORMAT_CBOR_ZSTD => zstd::stream::decode_all(body)
+            .map_err(|_| OperationError::SerdeCborError)
+            .and_then(|cbor| {
+                serde_cbor::from_slice(cbor.as_slice()).map_err(|_| OperationError::SerdeCborError)
+            }),
+        // Written by a newer release than this one.
+        _ => Err(OperationError::InvalidDBState),
+    }
+}
+
+// An incremental backup - the entries written and the uuids of the entries
+// deleted after the change sequence number since, up to marker.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DbIncremental {
+    pub since: i64,
+    pub marker: i64,
+    pub removed: Vec<String>,
+    pub entries: Vec<DbEntry>,
+}
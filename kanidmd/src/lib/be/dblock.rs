@@ -0,0 +1,232 @@
+// Guards against two kanidmd processes opening the same database. There are
+// two layers here - an OS advisory lock on a file next to the database, which
+// is reliable on local filesystems, and an owner row in the database itself
+// that the owning process heartbeats. The owner row catches the cases where
+// advisory locks silently don't work (IE some network filesystems).
+
+use fs2::FileExt;
+use std::fs::{File, OpenOptions};
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+use crate::audit::AuditScope;
+use crate::be::idlayer::{IdLayer, IdLayerWriteTransaction};
+use crate::be::BackendIdLayer;
+use crate::constants::DB_OWNER_STALE;
+use kanidm_proto::v1::OperationError;
+
+pub struct DbLock {
+    // Held only so the advisory lock lives as long as we do.
+    _file: Option<File>,
+    idlayer: BackendIdLayer,
+    instance: Uuid,
+}
+
+fn now_secs() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+impl DbLock {
+    // This must be called before the database is opened, so that we never
+    // touch the wal of a database another process is using.
+    pub fn lock_file(audit: &mut AuditScope, path: &str) -> Result<Option<File>, OperationError> {
+        if path == "" {
+            // In memory, there is nothing to share.
+            return Ok(None);
+        }
+        let lock_path = format!("{}.lock", path);
+        let file = try_audit!(
+            audit,
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .open(&lock_path),
+            "Unable to open lock file {:?}",
+            OperationError::FsError
+        );
+        match file.try_lock_exclusive() {
+            Ok(_) => Ok(Some(file)),
+            Err(e) => {
+                audit_log!(audit, "Unable to lock {} -> {:?}", lock_path, e);
+                error!(
+                    "The database {} is locked by another process - is kanidmd already running?",
+                    path
+                );
+                Err(OperationError::DatabaseLocked)
+            }
+        }
+    }
+
+    pub fn claim(
+        audit: &mut AuditScope,
+        file: Option<File>,
+        idlayer: BackendIdLayer,
+    ) -> Result<Self, OperationError> {
+        let lock = DbLock {
+            _file: file,
+            idlayer: idlayer,
+            instance: Uuid::new_v4(),
+        };
+        lock.claim_at(audit, now_secs())?;
+        Ok(lock)
+    }
+
+    fn claim_at(&self, audit: &mut AuditScope, now: i64) -> Result<(), OperationError> {
+        let idl_write = self.idlayer.write()?;
+        match idl_write.get_db_owner()? {
+            Some((pid, instance, heartbeat)) if now - heartbeat < DB_OWNER_STALE as i64 => {
+                audit_log!(
+                    audit,
+                    "Database owned by pid {} instance {}, last heartbeat {}s ago",
+                    pid,
+                    instance,
+                    now - heartbeat
+                );
+                error!(
+                    "The database is in use by pid {} which was alive {}s ago - refusing to start",
+                    pid,
+                    now - heartbeat
+                );
+                return Err(OperationError::DatabaseLocked);
+            }
+            Some((pid, instance, _)) => {
+                audit_log!(
+                    audit,
+                    "Taking over stale claim from pid {} instance {}",
+                    pid,
+                    instance
+                );
+            }
+            None => {}
+        }
+        idl_write.write_db_owner(
+            std::process::id() as i64,
+            &self.instance.to_hyphenated().to_string(),
+            now,
+        )?;
+        idl_write.commit(audit)
+    }
+
+    pub fn heartbeat(&self, audit: &mut AuditScope) -> Result<(), OperationError> {
+        self.heartbeat_at(audit, now_secs())
+    }
+
+    fn heartbeat_at(&self, audit: &mut AuditScope, now: i64) -> Result<(), OperationError> {
+        let instance = self.instance.to_hyphenated().to_string();
+        let idl_write = self.idlayer.write()?;
+        match idl_write.get_db_owner()? {
+            Some((_, ref owner, _)) if owner == &instance => {}
+            owner => {
+                // Someone else has taken over - we can't know what they have
+                // changed, so the operator has to intervene.
+                audit_log!(audit, "Database ownership lost -> {:?}", owner);
+                error!("Database ownership has been taken by another process! Stop this server immediately.");
+                return Err(OperationError::DatabaseLocked);
+            }
+        }
+        idl_write.write_db_owner(std::process::id() as i64, &instance, now)?;
+        idl_write.commit(audit)
+    }
+}
+
+impl Drop for DbLock {
+    fn drop(&mut self) {
+        // Release our claim so the next process doesn't have to wait out the
+        // stale timeout. If the db is busy we leave it, the claim will expire.
+        let mut audit = AuditScope::new("db_lock_release");
+        let instance = self.instance.to_hyphenated().to_string();
+        let r = match self.idlayer.try_write() {
+            Some(idl_write) => idl_write
+                .delete_db_owner(&instance)
+                .and_then(|_| idl_write.commit(&mut audit)),
+            None => Ok(()),
+        };
+        if r.is_err() {
+            debug!("Unable to release database claim -> {:?}", r);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::audit::AuditScope;
+    use crate::be::dblock::{now_secs, DbLock};
+    use crate::be::idlayer::{IdLayer, IdLayerWriteTransaction};
+    use crate::be::BackendIdLayer;
+    use crate::config::DbPragmas;
+    use crate::constants::DB_OWNER_STALE;
+    use kanidm_proto::v1::OperationError;
+
+    fn setup_idlayer(audit: &mut AuditScope) -> BackendIdLayer {
+        let idlayer = BackendIdLayer::new(audit, "", 1, None, DbPragmas::default())
+            .expect("Failed to setup idlayer");
+        let idl_write = idlayer.write().expect("Failed to begin txn");
+        idl_write
+            .setup(audit)
+            .and_then(|_| idl_write.commit(audit))
+            .expect("Failed to setup db");
+        idlayer
+    }
+
+    #[test]
+    fn test_be_dblock_owner() {
+        let mut audit = AuditScope::new("run_test");
+        let idlayer = setup_idlayer(&mut audit);
+
+        let lock_a = DbLock::claim(&mut audit, None, idlayer.clone()).expect("claim failed");
+        // A second process can't claim while we are alive.
+        assert_eq!(
+            DbLock::claim(&mut audit, None, idlayer.clone()).err(),
+            Some(OperationError::DatabaseLocked)
+        );
+        assert!(lock_a.heartbeat(&mut audit).is_ok());
+
+        // Releasing lets the next process in straight away.
+        drop(lock_a);
+        let lock_b = DbLock::claim(&mut audit, None, idlayer.clone()).expect("claim failed");
+        assert!(lock_b.heartbeat(&mut audit).is_ok());
+        println!("{}", audit);
+    }
+
+    #[test]
+    fn test_be_dblock_stale() {
+        let mut audit = AuditScope::new("run_test");
+        let idlayer = setup_idlayer(&mut audit);
+
+        let lock_a = DbLock::claim(&mut audit, None, idlayer.clone()).expect("claim failed");
+        let lock_b = DbLock {
+            _file: None,
+            idlayer: idlayer.clone(),
+            instance: uuid::Uuid::new_v4(),
+        };
+        let later = now_secs() + DB_OWNER_STALE as i64 + 1;
+        // a has gone quiet, so b can take over, and a is told it lost it.
+        assert!(lock_b.claim_at(&mut audit, later).is_ok());
+        assert_eq!(
+            lock_a.heartbeat_at(&mut audit, later),
+            Err(OperationError::DatabaseLocked)
+        );
+        assert!(lock_b.heartbeat_at(&mut audit, later).is_ok());
+    }
+
+    #[test]
+    fn test_be_dblock_file() {
+        let mut audit = AuditScope::new("run_test");
+        let path = std::env::temp_dir().join(format!("kanidm_dblock_{}", uuid::Uuid::new_v4()));
+        let path = path.to_str().expect("invalid temp path").to_string();
+
+        let f1 = DbLock::lock_file(&mut audit, &path).expect("lock failed");
+        assert!(f1.is_some());
+        assert_eq!(
+            DbLock::lock_file(&mut audit, &path).err(),
+            Some(OperationError::DatabaseLocked)
+        );
+        drop(f1);
+        assert!(DbLock::lock_file(&mut audit, &path).is_ok());
+        let _ = std::fs::remove_file(format!("{}.lock", path));
+    }
+}
@@ -1,8 +1,12 @@
 use uuid::Uuid;
 
+// Integers here are fixed width, never usize, so a database or backup from a
+// 64 bit host has the same meaning on a 32 bit one. serde writes a usize as a
+// u64 in any case, so this is the same encoding as before.
+
 #[derive(Serialize, Deserialize, Debug)]
 pub enum DbPasswordV1 {
-    PBKDF2(usize, Vec<u8>, Vec<u8>),
+    PBKDF2(u64, Vec<u8>, Vec<u8>),
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -30,11 +34,15 @@ pub enum DbValueV1 {
     I8(String),
     UU(Uuid),
     BO(bool),
-    SY(usize),
-    IN(usize),
+    SY(u64),
+    IN(u64),
     RF(Uuid),
     JF(String),
     CR(DbValueCredV1),
     RU(String),
     SK(DbValueTaggedStringV1),
+    UI(u32),
+    // A large value kept in id2blob, by the hash of its cbor. These only exist
+    // on disk, see DbEntry::externalise.
+    XB(String),
 }
@@ -0,0 +1,115 @@
+// The identity of the domain this database belongs to. This replaces the
+// bare four byte server id we used to keep in db_sid, so that replication and
+// anything issuing tokens can refer to the domain by a uuid that outlives
+// restarts, copies and renames of the domain.
+
+use crate::utils::SID;
+use kanidm_proto::v1::DomainInfo as ProtoDomainInfo;
+use rand::prelude::*;
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DomainInfo {
+    pub uuid: Uuid,
+    pub name: String,
+    // Goes up by one whenever any of this changes.
+    pub generation: u64,
+    // Seconds since the epoch.
+    pub created: u64,
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl DomainInfo {
+    pub fn new(name: &str) -> Self {
+        DomainInfo {
+            uuid: Uuid::new_v4(),
+            name: name.to_string(),
+            generation: 1,
+            created: now_secs(),
+        }
+    }
+
+    // For a database that only had a server id. The uuid starts with it, so
+    // the server id we hand out stays the same.
+    pub fn from_sid(sid: &SID, name: &str) -> Self {
+        let mut bytes = [0; 16];
+        StdRng::from_entropy().fill(&mut bytes);
+        bytes[..4].copy_from_slice(sid);
+        DomainInfo {
+            // As with new_v4, so it looks like any other uuid we made.
+            uuid: uuid::Builder::from_bytes(bytes)
+                .set_variant(uuid::Variant::RFC4122)
+                .set_version(uuid::Version::Random)
+                .build(),
+            name: name.to_string(),
+            generation: 1,
+            created: now_secs(),
+        }
+    }
+
+    // A new identity for the same domain, IE for a copy that will run
+    // alongside the original.
+    pub fn reset(&self) -> Self {
+        DomainInfo {
+            uuid: Uuid::new_v4(),
+            name: self.name.clone(),
+            generation: self.generation + 1,
+            created: now_secs(),
+        }
+    }
+
+    pub fn rename(&self, name: &str) -> Self {
+        DomainInfo {
+            uuid: self.uuid,
+            name: name.to_string(),
+            generation: self.generation + 1,
+            created: self.created,
+        }
+    }
+
+    // The server id in the uuids we generate, IE uuid_from_now.
+    pub fn sid(&self) -> SID {
+        let mut sid = [0; 4];
+        sid.copy_from_slice(&self.uuid.as_bytes()[..4]);
+        sid
+    }
+
+    pub fn to_proto(&self) -> ProtoDomainInfo {
+        ProtoDomainInfo {
+            uuid: self.uuid.to_hyphenated_ref().to_string(),
+            name: self.name.clone(),
+            generation: self.generation,
+            created: self.created,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DomainInfo;
+
+    #[test]
+    fn test_domain_info_sid() {
+        let sid = [1, 2, 3, 4];
+        let d = DomainInfo::from_sid(&sid, "example.com");
+        assert_eq!(d.sid(), sid);
+        assert_eq!(d.uuid.get_version_num(), 4);
+
+        let r = d.reset();
+        assert!(r.uuid != d.uuid);
+        assert_eq!(r.generation, d.generation + 1);
+        assert_eq!(r.name, d.name);
+
+        let n = d.rename("example.net");
+        assert_eq!(n.uuid, d.uuid);
+        assert_eq!(n.sid(), sid);
+        assert_eq!(n.generation, d.generation + 1);
+    }
+}
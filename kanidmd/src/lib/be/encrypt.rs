@@ -0,0 +1,308 @@
+// Backups may be encrypted so that copies kept off host don't expose
+// credential material. The stream is cut into chunks which are each sealed
+// with AES-256-GCM, so neither backup nor restore have to hold the whole file
+// in memory. Each chunk's nonce is derived from its sequence number, and the
+// chunk header (including a flag marking the last chunk) is authenticated, so
+// chunks can't be reordered or dropped, nor the stream truncated, without the
+// restore failing.
+//
+// The file is laid out as:
+//   magic | kdf | salt | nonce | (last flag | len | ciphertext | tag)*
+
+use crate::config::BackupKey;
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::pkcs5::pbkdf2_hmac;
+use openssl::rand::rand_bytes;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use std::cmp;
+use std::io::{self, Read, Write};
+
+pub const ENCRYPT_MAGIC: [u8; 8] = *b"KBAKENC1";
+
+const CHUNK_SIZE: usize = 65536;
+const CHUNK_HEADER_LEN: usize = 5;
+const TAG_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const SALT_LEN: usize = 16;
+const PBKDF2_ITERATIONS: usize = 100_000;
+
+// How the key was derived, so restore can tell the operator they gave the
+// wrong kind of key rather than just failing to decrypt.
+const KDF_NONE: u8 = 0;
+const KDF_PBKDF2: u8 = 1;
+
+fn ssl_err(e: ErrorStack) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e)
+}
+
+fn kdf_for(key: &BackupKey) -> u8 {
+    match key {
+        BackupKey::Key(_) => KDF_NONE,
+        BackupKey::Passphrase(_) => KDF_PBKDF2,
+    }
+}
+
+fn derive_key(key: &BackupKey, kdf: u8, salt: &[u8]) -> io::Result<[u8; 32]> {
+    match (key, kdf) {
+        (BackupKey::Key(k), KDF_NONE) => Ok(*k),
+        (BackupKey::Passphrase(p), KDF_PBKDF2) => {
+            let mut dkey = [0; 32];
+            pbkdf2_hmac(
+                p.as_bytes(),
+                salt,
+                PBKDF2_ITERATIONS,
+                MessageDigest::sha256(),
+                &mut dkey,
+            )
+            .map_err(ssl_err)?;
+            Ok(dkey)
+        }
+        (_, KDF_NONE) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "backup was encrypted with a key file, not a passphrase",
+        )),
+        (_, KDF_PBKDF2) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "backup was encrypted with a passphrase, not a key file",
+        )),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "backup uses an unknown key derivation",
+        )),
+    }
+}
+
+fn chunk_nonce(base: &[u8; NONCE_LEN], counter: u64) -> [u8; NONCE_LEN] {
+    let mut nonce = *base;
+    nonce[NONCE_LEN - 8..]
+        .iter_mut()
+        .zip(counter.to_be_bytes().iter())
+        .for_each(|(n, c)| *n ^= c);
+    nonce
+}
+
+pub struct EncryptWriter<W: Write> {
+    inner: W,
+    key: [u8; 32],
+    nonce: [u8; NONCE_LEN],
+    counter: u64,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> EncryptWriter<W> {
+    pub fn new(mut inner: W, key: &BackupKey) -> io::Result<Self> {
+        let mut salt = [0; SALT_LEN];
+        let mut nonce = [0; NONCE_LEN];
+        rand_bytes(&mut salt)
+            .and_then(|_| rand_bytes(&mut nonce))
+            .map_err(ssl_err)?;
+        let kdf = kdf_for(key);
+        let dkey = derive_key(key, kdf, &salt)?;
+
+        inner.write_all(&ENCRYPT_MAGIC)?;
+        inner.write_all(&[kdf])?;
+        inner.write_all(&salt)?;
+        inner.write_all(&nonce)?;
+
+        Ok(EncryptWriter {
+            inner: inner,
+            key: dkey,
+            nonce: nonce,
+            counter: 0,
+            buf: Vec::with_capacity(CHUNK_SIZE),
+        })
+    }
+
+    fn seal(&mut self, last: bool) -> io::Result<()> {
+        let mut header = [0; CHUNK_HEADER_LEN];
+        header[0] = last as u8;
+        header[1..].copy_from_slice(&(self.buf.len() as u32).to_be_bytes());
+
+        let mut tag = [0; TAG_LEN];
+        let ct = encrypt_aead(
+            Cipher::aes_256_gcm(),
+            &self.key,
+            Some(&chunk_nonce(&self.nonce, self.counter)),
+            &header,
+            &self.buf,
+            &mut tag,
+        )
+        .map_err(ssl_err)?;
+
+        self.inner.write_all(&header)?;
+        self.inner.write_all(&ct)?;
+        self.inner.write_all(&tag)?;
+        self.counter += 1;
+        self.buf.clear();
+        Ok(())
+    }
+
+    // This must be called once everything is written, as it seals the last
+    // chunk. Without it restore will reject the backup as truncated.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.seal(true)?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for EncryptWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let n = cmp::min(data.len(), CHUNK_SIZE - self.buf.len());
+        self.buf.extend_from_slice(&data[..n]);
+        if self.buf.len() == CHUNK_SIZE {
+            self.seal(false)?;
+        }
+        Ok(n)
+    }
+
+    // Only whole chunks are ever written, so this can't flush a partial one.
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+pub struct DecryptReader<R: Read> {
+    inner: R,
+    key: [u8; 32],
+    nonce: [u8; NONCE_LEN],
+    counter: u64,
+    buf: Vec<u8>,
+    pos: usize,
+    done: bool,
+}
+
+impl<R: Read> DecryptReader<R> {
+    pub fn new(mut inner: R, key: &BackupKey) -> io::Result<Self> {
+        let mut magic = [0; 8];
+        let mut kdf = [0; 1];
+        let mut salt = [0; SALT_LEN];
+        let mut nonce = [0; NONCE_LEN];
+        inner.read_exact(&mut magic)?;
+        if magic != ENCRYPT_MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not an encrypted backup",
+            ));
+        }
+        inner.read_exact(&mut kdf)?;
+        inner.read_exact(&mut salt)?;
+        inner.read_exact(&mut nonce)?;
+        let dkey = derive_key(key, kdf[0], &salt)?;
+
+        Ok(DecryptReader {
+            inner: inner,
+            key: dkey,
+            nonce: nonce,
+            counter: 0,
+            buf: Vec::new(),
+            pos: 0,
+            done: false,
+        })
+    }
+
+    fn open(&mut self) -> io::Result<()> {
+        let truncated = |e: io::Error| match e.kind() {
+            io::ErrorKind::UnexpectedEof => {
+                io::Error::new(io::ErrorKind::InvalidData, "encrypted backup is truncated")
+            }
+            _ => e,
+        };
+
+        let mut header = [0; CHUNK_HEADER_LEN];
+        self.inner.read_exact(&mut header).map_err(truncated)?;
+        let mut len = [0; 4];
+        len.copy_from_slice(&header[1..]);
+        let len = u32::from_be_bytes(len) as usize;
+        if len > CHUNK_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "encrypted backup chunk is too large",
+            ));
+        }
+
+        let mut ct = vec![0; len];
+        let mut tag = [0; TAG_LEN];
+        self.inner.read_exact(&mut ct).map_err(truncated)?;
+        self.inner.read_exact(&mut tag).map_err(truncated)?;
+
+        self.buf = decrypt_aead(
+            Cipher::aes_256_gcm(),
+            &self.key,
+            Some(&chunk_nonce(&self.nonce, self.counter)),
+            &header,
+            &ct,
+            &tag,
+        )
+        .map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "backup failed to decrypt - the key is wrong or the file is damaged",
+            )
+        })?;
+        self.pos = 0;
+        self.counter += 1;
+        self.done = header[0] == 1;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for DecryptReader<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.buf.len() {
+            if self.done {
+                return Ok(0);
+            }
+            self.open()?;
+        }
+        let n = cmp::min(out.len(), self.buf.len() - self.pos);
+        out[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DecryptReader, EncryptWriter, CHUNK_SIZE};
+    use crate::config::BackupKey;
+    use std::io::{Read, Write};
+
+    fn encrypt(key: &BackupKey, data: &[u8]) -> Vec<u8> {
+        let mut enc = EncryptWriter::new(Vec::new(), key).expect("encrypt setup failed");
+        enc.write_all(data).expect("write failed");
+        enc.finish().expect("finish failed")
+    }
+
+    fn decrypt(key: &BackupKey, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        let mut dec = DecryptReader::new(data, key)?;
+        let mut out = Vec::new();
+        dec.read_to_end(&mut out).map(|_| out)
+    }
+
+    #[test]
+    fn test_be_encrypt_roundtrip() {
+        // More than one chunk, so the chunk sequencing is exercised.
+        let data: Vec<u8> = (0..(CHUNK_SIZE * 2 + 100)).map(|i| i as u8).collect();
+
+        let key = BackupKey::Key([7; 32]);
+        let ct = encrypt(&key, &data);
+        assert_eq!(decrypt(&key, &ct).expect("decrypt failed"), data);
+
+        let pass = BackupKey::Passphrase("correct horse".to_string());
+        let ct_pass = encrypt(&pass, &data);
+        assert_eq!(decrypt(&pass, &ct_pass).expect("decrypt failed"), data);
+
+        // The wrong key, or the wrong kind of key, is refused.
+        assert!(decrypt(&BackupKey::Key([8; 32]), &ct).is_err());
+        assert!(decrypt(&pass, &ct).is_err());
+        assert!(decrypt(&BackupKey::Passphrase("wrong".to_string()), &ct_pass).is_err());
+
+        // As is a truncated or damaged backup.
+        assert!(decrypt(&key, &ct[..ct.len() - 1]).is_err());
+        assert!(decrypt(&key, &ct[..CHUNK_SIZE + 100]).is_err());
+        let mut damaged = ct.clone();
+        damaged[100] ^= 1;
+        assert!(decrypt(&key, &damaged).is_err());
+    }
+}
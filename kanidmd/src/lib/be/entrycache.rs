@@ -0,0 +1,179 @@
+// Deserialised entries by id, shared by the read txns so that hot entries
+// don't have their cbor decoded on every search.
+//
+// As with the filter cache, each read txn only uses and adds entries for the
+// change marker of its snapshot, and a newer marker replaces what's held. A
+// write txn's changes are its own until it commits, when the ids it wrote or
+// deleted are dropped and the cache moves to the new marker. Write txns
+// don't read from the cache, as they must see their own changes.
+//
+// Eviction is least recently used. Only entries loaded by id are added, so an
+// allids scan can't push out the hot set.
+
+use crate::entry::{Entry, EntryCommitted, EntryValid};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+pub const ENTRY_CACHE_SIZE: usize = 4096;
+
+type CachedEntry = Entry<EntryValid, EntryCommitted>;
+
+struct EntryCacheInner {
+    marker: i64,
+    // Each id with its entry and when it was last used, and the reverse, so
+    // the least recently used is first.
+    entries: BTreeMap<u64, (CachedEntry, u64)>,
+    lru: BTreeMap<u64, u64>,
+    tick: u64,
+}
+
+impl EntryCacheInner {
+    fn clear(&mut self, marker: i64) {
+        self.marker = marker;
+        self.entries.clear();
+        self.lru.clear();
+    }
+
+    fn remove(&mut self, id: u64) {
+        if let Some((_, tick)) = self.entries.remove(&id) {
+            self.lru.remove(&tick);
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct EntryCache {
+    size: usize,
+    inner: Arc<Mutex<EntryCacheInner>>,
+}
+
+impl EntryCache {
+    pub fn new(size: usize) -> Self {
+        EntryCache {
+            size: size,
+            inner: Arc::new(Mutex::new(EntryCacheInner {
+                marker: 0,
+                entries: BTreeMap::new(),
+                lru: BTreeMap::new(),
+                tick: 0,
+            })),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, EntryCacheInner> {
+        // The worst a panic can leave is a stale lru order.
+        self.inner
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn get(&self, marker: i64, id: u64) -> Option<CachedEntry> {
+        let mut inner = self.lock();
+        if inner.marker != marker {
+            return None;
+        }
+        inner.tick += 1;
+        let tick = inner.tick;
+        let (e, old_tick) = match inner.entries.get_mut(&id) {
+            Some(ce) => {
+                let old_tick = ce.1;
+                ce.1 = tick;
+                (ce.0.clone(), old_tick)
+            }
+            None => return None,
+        };
+        inner.lru.remove(&old_tick);
+        inner.lru.insert(tick, id);
+        Some(e)
+    }
+
+    pub fn insert(&self, marker: i64, e: &CachedEntry) {
+        if self.size == 0 {
+            return;
+        }
+        let mut inner = self.lock();
+        if marker < inner.marker {
+            return;
+        }
+        if marker > inner.marker {
+            inner.clear(marker);
+        }
+        let id = e.get_id();
+        inner.remove(id);
+        inner.tick += 1;
+        let tick = inner.tick;
+        inner.entries.insert(id, (e.clone(), tick));
+        inner.lru.insert(tick, id);
+        while inner.entries.len() > self.size {
+            let (oldest, oldest_id) = match inner.lru.iter().next() {
+                Some((t, i)) => (*t, *i),
+                None => break,
+            };
+            inner.lru.remove(&oldest);
+            inner.entries.remove(&oldest_id);
+        }
+    }
+
+    // A write txn that began at from has committed as to. If we've moved on
+    // from from, or it doesn't know what it changed, we can't say what's still
+    // valid so everything goes.
+    pub fn commit(&self, from: i64, to: i64, dirty: Option<&BTreeSet<u64>>) {
+        let mut inner = self.lock();
+        match dirty {
+            Some(dirty) if inner.marker == from => {
+                dirty.iter().for_each(|id| inner.remove(*id));
+                inner.marker = to;
+            }
+            _ => inner.clear(to),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::EntryCache;
+    use crate::entry::{Entry, EntryCommitted, EntryInvalid, EntryNew, EntryValid};
+    use crate::value::Value;
+    use std::collections::BTreeSet;
+    use std::iter::FromIterator;
+    use uuid::Uuid;
+
+    fn mk(id: u64) -> Entry<EntryValid, EntryCommitted> {
+        let mut e: Entry<EntryInvalid, EntryNew> = Entry::new();
+        e.add_ava("uuid", &Value::new_uuid(Uuid::new_v4()));
+        unsafe { e.to_valid_new() }.to_valid_committed_id(id)
+    }
+
+    #[test]
+    fn test_be_entry_cache() {
+        let ec = EntryCache::new(2);
+        ec.insert(1, &mk(1));
+        ec.insert(1, &mk(2));
+        assert_eq!(ec.get(1, 1).map(|e| e.get_id()), Some(1));
+        // 2 is now the least recently used, so it's evicted.
+        ec.insert(1, &mk(3));
+        assert!(ec.get(1, 1).is_some());
+        assert!(ec.get(1, 2).is_none());
+        assert!(ec.get(1, 3).is_some());
+
+        // An older snapshot can neither read nor add entries.
+        assert!(ec.get(0, 1).is_none());
+        ec.insert(0, &mk(2));
+        assert!(ec.get(1, 2).is_none());
+
+        // A commit from the current marker only drops what it changed.
+        ec.commit(1, 2, Some(&BTreeSet::from_iter(vec![3])));
+        assert!(ec.get(2, 1).is_some());
+        assert!(ec.get(2, 3).is_none());
+
+        // One from an older marker drops everything.
+        ec.commit(1, 3, Some(&BTreeSet::new()));
+        assert!(ec.get(3, 1).is_none());
+
+        // As does a newer snapshot inserting.
+        ec.insert(3, &mk(1));
+        ec.insert(4, &mk(2));
+        assert!(ec.get(4, 1).is_none());
+        assert!(ec.get(4, 2).is_some());
+    }
+}
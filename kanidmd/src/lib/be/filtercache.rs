@@ -0,0 +1,164 @@
+// The idls of resolved filters, shared by the read txns so that hot repeated
+// searches (IE group membership checks) skip filter2idl and the index fetches.
+//
+// Each result is tagged with the change marker of the snapshot it was
+// resolved in. A read txn only uses and adds results for its own marker, so an
+// older txn racing a commit can't hand its results to newer ones. Write txns
+// never use the cache, as they see their own changes, and their commit clears
+// it, which covers index changes that don't move the marker.
+//
+// Only misses resolve the filter, so only misses count toward index usage.
+
+use crate::be::IDL;
+use crate::filter::FilterResolved;
+use openssl::sha;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+pub const FILTER_CACHE_SIZE: usize = 2048;
+
+type FilterKey = [u8; 32];
+
+struct FilterCacheInner {
+    marker: i64,
+    // Each key with its idl and when it was last used, and the reverse, so
+    // the least recently used is first.
+    entries: BTreeMap<FilterKey, (IDL, u64)>,
+    lru: BTreeMap<u64, FilterKey>,
+    tick: u64,
+}
+
+#[derive(Clone)]
+pub struct FilterCache {
+    size: usize,
+    inner: Arc<Mutex<FilterCacheInner>>,
+}
+
+impl FilterCache {
+    pub fn new(size: usize) -> Self {
+        FilterCache {
+            size: size,
+            inner: Arc::new(Mutex::new(FilterCacheInner {
+                marker: 0,
+                entries: BTreeMap::new(),
+                lru: BTreeMap::new(),
+                tick: 0,
+            })),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, FilterCacheInner> {
+        // The worst a panic can leave is a stale lru order.
+        self.inner
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    // The threshold changes which shortcuts are taken, and so the idl.
+    pub fn key(filt: &FilterResolved, thres: usize) -> FilterKey {
+        sha::sha256(format!("{}:{:?}", thres, filt).as_bytes())
+    }
+
+    pub fn get(&self, marker: i64, key: &FilterKey) -> Option<IDL> {
+        let mut inner = self.lock();
+        if inner.marker != marker {
+            return None;
+        }
+        inner.tick += 1;
+        let tick = inner.tick;
+        let (idl, old_tick) = match inner.entries.get_mut(key) {
+            Some(e) => {
+                let old_tick = e.1;
+                e.1 = tick;
+                (e.0.clone(), old_tick)
+            }
+            None => return None,
+        };
+        inner.lru.remove(&old_tick);
+        inner.lru.insert(tick, *key);
+        Some(idl)
+    }
+
+    pub fn insert(&self, marker: i64, key: FilterKey, idl: &IDL) {
+        if self.size == 0 {
+            return;
+        }
+        let mut inner = self.lock();
+        if marker < inner.marker {
+            return;
+        }
+        if marker > inner.marker {
+            inner.marker = marker;
+            inner.entries.clear();
+            inner.lru.clear();
+        }
+        inner.tick += 1;
+        let tick = inner.tick;
+        if let Some((_, old_tick)) = inner.entries.insert(key, (idl.clone(), tick)) {
+            inner.lru.remove(&old_tick);
+        }
+        inner.lru.insert(tick, key);
+        while inner.entries.len() > self.size {
+            let oldest = match inner.lru.keys().next() {
+                Some(t) => *t,
+                None => break,
+            };
+            if let Some(k) = inner.lru.remove(&oldest) {
+                inner.entries.remove(&k);
+            }
+        }
+    }
+
+    pub fn invalidate(&self) {
+        let mut inner = self.lock();
+        inner.entries.clear();
+        inner.lru.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FilterCache;
+    use crate::be::IDL;
+    use idlset::IDLBitRange;
+    use std::iter::FromIterator;
+
+    fn indexed(ids: &[u64]) -> IDL {
+        IDL::Indexed(IDLBitRange::from_iter(ids.iter().cloned()))
+    }
+
+    fn is_cached(fc: &FilterCache, marker: i64, key: u8) -> bool {
+        fc.get(marker, &[key; 32]).is_some()
+    }
+
+    #[test]
+    fn test_be_filter_cache() {
+        let fc = FilterCache::new(2);
+        fc.insert(1, [1; 32], &indexed(&[1]));
+        fc.insert(1, [2; 32], &indexed(&[2]));
+        match fc.get(1, &[1; 32]) {
+            Some(IDL::Indexed(idl)) => assert_eq!(idl, IDLBitRange::from_iter(vec![1])),
+            _ => panic!(""),
+        }
+        // 2 is now the least recently used, so it's evicted.
+        fc.insert(1, [3; 32], &indexed(&[3]));
+        assert!(is_cached(&fc, 1, 1));
+        assert!(!is_cached(&fc, 1, 2));
+        assert!(is_cached(&fc, 1, 3));
+
+        // An older snapshot can neither read nor add results.
+        assert!(!is_cached(&fc, 0, 1));
+        fc.insert(0, [4; 32], &indexed(&[4]));
+        assert!(!is_cached(&fc, 0, 4));
+        assert!(!is_cached(&fc, 1, 4));
+
+        // A newer one replaces them.
+        fc.insert(2, [4; 32], &indexed(&[4]));
+        assert!(!is_cached(&fc, 1, 1));
+        assert!(!is_cached(&fc, 2, 1));
+        assert!(is_cached(&fc, 2, 4));
+
+        fc.invalidate();
+        assert!(!is_cached(&fc, 2, 4));
+    }
+}
@@ -1,53 +1,215 @@
 use crate::audit::AuditScope;
+use crate::be::changelog::{self, ChangelogRow};
+use crate::be::domain::DomainInfo;
+use crate::be::idlayer::{IdLayer, IdLayerTransaction, IdLayerWriteTransaction};
+use crate::be::journal::JournalRecord;
+use crate::be::poolmetrics::PoolMetrics;
+use crate::be::slowlog::SlowQuery;
+use crate::be::usage::IdxSlot;
 use crate::be::{IdEntry, IDL};
-use crate::utils::SID;
+use crate::config::DbPragmas;
+use crate::repl::cid::Cid;
 use crate::value::IndexType;
 use idlset::IDLBitRange;
-use kanidm_proto::v1::OperationError;
+use kanidm_proto::v1::{DbPoolStats, IndexStat, OperationError, ReportRecord, SlowQueryRecord};
 use r2d2::Pool;
 use r2d2_sqlite::SqliteConnectionManager;
-use rusqlite::types::ToSql;
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+use rusqlite::ErrorCode;
 use rusqlite::OptionalExtension;
 use rusqlite::NO_PARAMS;
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
 use std::convert::TryFrom;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
 
-// use uuid::Uuid;
+use uuid::Uuid;
 
 static DBV_ID2ENTRY: &'static str = "id2entry";
 static DBV_INDEXV: &'static str = "indexv";
+// The last change sequence number handed out, and the one the last backup was
+// taken at. These aren't versions, but db_version is where we keep counters.
+static DBV_CSN: &'static str = "csn";
+static DBV_BACKUP_CSN: &'static str = "backupcsn";
+// Not a version either, but how id2entry blobs are written from now on.
+static DBV_ENTRY_FORMAT: &'static str = "entryformat";
+// The most slow queries kept, older ones are removed as new ones are written.
+const SLOW_QUERY_KEEP: i64 = 10000;
+// The most runs kept of each report.
+const REPORT_KEEP: i64 = 1000;
+// The most ids we put in a single id2entry IN query.
+const IDL_QUERY_CHUNK: usize = 8192;
+// Up to this many ids are read one at a time with a cached statement, rather
+// than preparing an IN query that will never be used again. Most searches
+// resolve to a single entry.
+const IDL_POINT_LOOKUP_MAX: usize = 8;
+// Prepared statements kept by each connection. There are three per idx
+// table on the hot path, so this is well above sqlite's default.
+const STMT_CACHE_CAPACITY: usize = 256;
+
+// The statements for an idx table, formatted once for the life of the server
+// rather than on each use. Each connection keeps its own prepared copy of
+// these in its statement cache, keyed by the text.
+struct IdxStmts {
+    get: String,
+    put: String,
+    del: String,
+}
+
+lazy_static! {
+    static ref IDX_STMTS: RwLock<BTreeMap<(String, IndexType), Arc<IdxStmts>>> =
+        RwLock::new(BTreeMap::new());
+}
+
+fn idx_stmts(attr: &str, itype: &IndexType) -> Arc<IdxStmts> {
+    let k = (attr.to_string(), itype.clone());
+    if let Some(s) = IDX_STMTS
+        .read()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .get(&k)
+    {
+        return s.clone();
+    }
+    let table = format!("idx_{}_{}", itype.as_idx_str(), attr);
+    let s = Arc::new(IdxStmts {
+        get: format!("SELECT idl FROM {} WHERE key = :key", table),
+        put: format!(
+            "INSERT OR REPLACE INTO {} (key, idl) VALUES(:key, :idl)",
+            table
+        ),
+        del: format!("DELETE FROM {} WHERE key = :key", table),
+    });
+    IDX_STMTS
+        .write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .entry(k)
+        .or_insert(s)
+        .clone()
+}
+
+// Entry ids are stored as 8 byte big endian blobs, which sqlite orders as we
+// order the ids, so the whole u64 range can be used rather than just what
+// fits in sqlites i64 integers. This is the only place ids are converted.
+struct IdKey(u64);
+
+impl ToSql for IdKey {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.0.to_be_bytes().to_vec()))
+    }
+}
+
+impl FromSql for IdKey {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        let blob = value.as_blob()?;
+        let mut key = [0; 8];
+        if blob.len() != key.len() {
+            return Err(FromSqlError::InvalidType);
+        }
+        key.copy_from_slice(blob);
+        Ok(IdKey(u64::from_be_bytes(key)))
+    }
+}
+
+impl IdKey {
+    // As a literal, for the id lists we write into statements.
+    fn to_literal(&self) -> String {
+        format!("X'{:016X}'", self.0)
+    }
+}
 
 #[derive(Clone)]
 pub struct IdlSqlite {
     pool: Pool<SqliteConnectionManager>,
+    metrics: PoolMetrics,
 }
 
 pub struct IdlSqliteReadTransaction {
     committed: bool,
     conn: r2d2::PooledConnection<SqliteConnectionManager>,
+    metrics: PoolMetrics,
+    // When we began, for how long the txn was held.
+    start: Instant,
 }
 
 pub struct IdlSqliteWriteTransaction {
     committed: bool,
     conn: r2d2::PooledConnection<SqliteConnectionManager>,
+    metrics: PoolMetrics,
+    start: Instant,
+    // Every entry written in this txn gets the same csn, allocated on the
+    // first write. 0 means none has been allocated yet.
+    csn: Cell<i64>,
+    // The csn as it was when each open savepoint was taken, as rolling back
+    // to one may undo its allocation.
+    savepoints: RefCell<Vec<i64>>,
 }
 
+// The sqlite specific parts of a transaction. Everything the backend needs
+// goes through the IdLayer traits, implemented below in terms of these.
 pub trait IdlSqliteTransaction {
     fn get_conn(&self) -> &r2d2::PooledConnection<r2d2_sqlite::SqliteConnectionManager>;
 
+    fn exists_idx(
+        &self,
+        audit: &mut AuditScope,
+        attr: &String,
+        itype: &IndexType,
+    ) -> Result<bool, OperationError> {
+        let tname = format!("idx_{}_{}", itype.as_idx_str(), attr);
+        let mut stmt = try_audit!(
+            audit,
+            self.get_conn()
+                .prepare_cached("SELECT COUNT(name) from sqlite_master where name = :tname"),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        let i: Option<i64> = try_audit!(
+            audit,
+            stmt.query_row_named(&[(":tname", &tname as &dyn ToSql)], |row| row.get(0)),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+
+        if i.unwrap_or(0) == 0 {
+            Ok(false)
+        } else {
+            Ok(true)
+        }
+    }
+
+    fn get_db_version_key(&self, key: &str) -> i64 {
+        match self.get_conn().query_row_named(
+            "SELECT version FROM db_version WHERE id = :id",
+            &[(":id", &key)],
+            |row| row.get(0),
+        ) {
+            Ok(e) => e,
+            Err(_) => {
+                // The value is missing, default to 0.
+                0
+            }
+        }
+    }
+}
+
+impl<T: IdlSqliteTransaction> IdLayerTransaction for T {
     fn get_identry(&self, au: &mut AuditScope, idl: &IDL) -> Result<Vec<IdEntry>, OperationError> {
         // is the idl allids?
         match idl {
             IDL::ALLIDS => {
                 let mut stmt = try_audit!(
                     au,
-                    self.get_conn().prepare("SELECT id, data FROM id2entry"),
+                    self.get_conn()
+                        .prepare_cached("SELECT id, data FROM id2entry"),
                     "SQLite Error {:?}",
                     OperationError::SQLiteError
                 );
                 let id2entry_iter = try_audit!(
                     au,
                     stmt.query_map(NO_PARAMS, |row| Ok(IdEntry {
-                        id: row.get(0)?,
+                        id: row.get::<_, IdKey>(0)?.0,
                         data: row.get(1)?,
                     })),
                     "SQLite Error {:?}",
@@ -63,74 +225,210 @@ pub trait IdlSqliteTransaction {
                     .collect()
             }
             IDL::Partial(idli) | IDL::Indexed(idli) => {
-                let mut stmt = try_audit!(
-                    au,
-                    self.get_conn()
-                        .prepare("SELECT id, data FROM id2entry WHERE id = :idl"),
-                    "SQLite Error {:?}",
-                    OperationError::SQLiteError
-                );
-
-                // TODO: I have no idea how to make this an iterator chain ... so what
-                // I have now is probably really bad :(
-                let mut results = Vec::new();
-
-                for id in idli {
-                    let iid = i64::try_from(id).map_err(|_| OperationError::InvalidEntryID)?;
-                    let id2entry_iter = stmt
-                        .query_map(&[&iid], |row| {
-                            Ok(IdEntry {
-                                id: row.get(0)?,
-                                data: row.get(1)?,
+                let ids: Vec<IdKey> = idli.into_iter().map(IdKey).collect();
+
+                if ids.len() <= IDL_POINT_LOOKUP_MAX {
+                    let mut stmt = try_audit!(
+                        au,
+                        self.get_conn()
+                            .prepare_cached("SELECT id, data FROM id2entry WHERE id = :id"),
+                        "SQLite Error {:?}",
+                        OperationError::SQLiteError
+                    );
+                    let mut results = Vec::with_capacity(ids.len());
+                    for id in ids.iter() {
+                        let ide = try_audit!(
+                            au,
+                            stmt.query_row_named(&[(":id", id as &dyn ToSql)], |row| {
+                                Ok(IdEntry {
+                                    id: row.get::<_, IdKey>(0)?.0,
+                                    data: row.get(1)?,
+                                })
                             })
-                        })
-                        .map_err(|e| {
-                            audit_log!(au, "SQLite Error {:?}", e);
+                            .optional(),
+                            "SQLite Error {:?}",
                             OperationError::SQLiteError
-                        })?;
+                        );
+                        results.extend(ide);
+                    }
+                    return Ok(results);
+                }
 
-                    let r: Result<Vec<_>, _> = id2entry_iter
-                        .map(|v| {
-                            v.map_err(|e| {
-                                audit_log!(au, "SQLite Error {:?}", e);
-                                OperationError::SQLiteError
-                            })
-                        })
-                        .collect();
-                    let mut r = r?;
-                    results.append(&mut r);
+                // The ids are keys we control, so it's safe to write them
+                // into the statement. This avoids sqlites bound parameter limit,
+                // and we only chunk to keep the statement text to a sane size.
+                let mut results = Vec::with_capacity(ids.len());
+                for chunk in ids.chunks(IDL_QUERY_CHUNK) {
+                    let id_list: Vec<String> = chunk.iter().map(|id| id.to_literal()).collect();
+                    let query = format!(
+                        "SELECT id, data FROM id2entry WHERE id IN ({})",
+                        id_list.join(",")
+                    );
+                    let mut stmt = try_audit!(
+                        au,
+                        self.get_conn().prepare(query.as_str()),
+                        "SQLite Error {:?}",
+                        OperationError::SQLiteError
+                    );
+                    let id2entry_iter = try_audit!(
+                        au,
+                        stmt.query_map(NO_PARAMS, |row| Ok(IdEntry {
+                            id: row.get::<_, IdKey>(0)?.0,
+                            data: row.get(1)?,
+                        })),
+                        "SQLite Error {:?}",
+                        OperationError::SQLiteError
+                    );
+                    for v in id2entry_iter {
+                        results.push(v.map_err(|e| {
+                            audit_log!(au, "SQLite Error {:?}", e);
+                            OperationError::SQLiteError
+                        })?);
+                    }
                 }
                 Ok(results)
             }
         }
     }
 
-    fn exists_idx(
+    fn get_identry_batch(
         &self,
-        audit: &mut AuditScope,
-        attr: &String,
-        itype: &IndexType,
-    ) -> Result<bool, OperationError> {
-        let tname = format!("idx_{}_{}", itype.as_idx_str(), attr);
+        au: &mut AuditScope,
+        after: u64,
+        limit: usize,
+    ) -> Result<Vec<IdEntry>, OperationError> {
+        let limit = try_audit!(
+            au,
+            i64::try_from(limit),
+            "Invalid batch limit {:?}",
+            OperationError::InvalidState
+        );
         let mut stmt = try_audit!(
-            audit,
+            au,
+            self.get_conn().prepare_cached(
+                "SELECT id, data FROM id2entry WHERE id > :after ORDER BY id ASC LIMIT :limit"
+            ),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        let id2entry_iter = try_audit!(
+            au,
+            stmt.query_map_named(
+                &[
+                    (":after", &IdKey(after) as &dyn ToSql),
+                    (":limit", &limit as &dyn ToSql),
+                ],
+                |row| Ok(IdEntry {
+                    id: row.get::<_, IdKey>(0)?.0,
+                    data: row.get(1)?,
+                })
+            ),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        id2entry_iter
+            .map(|v| {
+                v.map_err(|e| {
+                    audit_log!(au, "SQLite Error {:?}", e);
+                    OperationError::SQLiteError
+                })
+            })
+            .collect()
+    }
+
+    fn get_identry_changed(
+        &self,
+        au: &mut AuditScope,
+        since: i64,
+        after: u64,
+        limit: usize,
+    ) -> Result<Vec<IdEntry>, OperationError> {
+        let limit = try_audit!(
+            au,
+            i64::try_from(limit),
+            "Invalid batch limit {:?}",
+            OperationError::InvalidState
+        );
+        let mut stmt = try_audit!(
+            au,
+            self.get_conn().prepare(
+                "SELECT id, data FROM id2entry WHERE csn > :since AND id > :after ORDER BY id ASC LIMIT :limit"
+            ),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        let id2entry_iter = try_audit!(
+            au,
+            stmt.query_map_named(
+                &[
+                    (":since", &since as &dyn ToSql),
+                    (":after", &IdKey(after) as &dyn ToSql),
+                    (":limit", &limit as &dyn ToSql),
+                ],
+                |row| Ok(IdEntry {
+                    id: row.get::<_, IdKey>(0)?.0,
+                    data: row.get(1)?,
+                })
+            ),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        id2entry_iter
+            .map(|v| {
+                v.map_err(|e| {
+                    audit_log!(au, "SQLite Error {:?}", e);
+                    OperationError::SQLiteError
+                })
+            })
+            .collect()
+    }
+
+    fn get_removed_since(
+        &self,
+        au: &mut AuditScope,
+        since: i64,
+    ) -> Result<Vec<String>, OperationError> {
+        let mut stmt = try_audit!(
+            au,
             self.get_conn()
-                .prepare("SELECT COUNT(name) from sqlite_master where name = :tname"),
+                .prepare("SELECT uuid FROM id2entry_removed WHERE csn > :since"),
             "SQLite Error {:?}",
             OperationError::SQLiteError
         );
-        let i: Option<i64> = try_audit!(
-            audit,
-            stmt.query_row_named(&[(":tname", &tname as &dyn ToSql)], |row| row.get(0)),
+        let uuid_iter = try_audit!(
+            au,
+            stmt.query_map_named(&[(":since", &since as &dyn ToSql)], |row| row.get(0)),
             "SQLite Error {:?}",
             OperationError::SQLiteError
         );
+        uuid_iter
+            .map(|v| {
+                v.map_err(|e| {
+                    audit_log!(au, "SQLite Error {:?}", e);
+                    OperationError::SQLiteError
+                })
+            })
+            .collect()
+    }
 
-        if i.unwrap_or(0) == 0 {
-            Ok(false)
-        } else {
-            Ok(true)
-        }
+    fn get_change_marker(&self) -> i64 {
+        self.get_db_version_key(DBV_CSN)
+    }
+
+    fn get_backup_marker(&self) -> i64 {
+        self.get_db_version_key(DBV_BACKUP_CSN)
+    }
+
+    fn get_db_entry_format(&self) -> i64 {
+        self.get_db_version_key(DBV_ENTRY_FORMAT)
+    }
+
+    fn get_id2entry_count(&self) -> Result<i64, OperationError> {
+        self.get_conn()
+            .query_row("SELECT COUNT(id) FROM id2entry", NO_PARAMS, |row| {
+                row.get(0)
+            })
+            .map_err(|_| OperationError::SQLiteError)
     }
 
     fn get_idl(
@@ -145,21 +443,16 @@ pub trait IdlSqliteTransaction {
             return Ok(None);
         }
         // The table exists - lets now get the actual index itself.
-
-        let query = format!(
-            "SELECT idl FROM idx_{}_{} WHERE key = :idx_key",
-            itype.as_idx_str(),
-            attr
-        );
+        let stmts = idx_stmts(attr, itype);
         let mut stmt = try_audit!(
             audit,
-            self.get_conn().prepare(query.as_str()),
+            self.get_conn().prepare_cached(stmts.get.as_str()),
             "SQLite Error {:?}",
             OperationError::SQLiteError
         );
         let idl_raw: Option<Vec<u8>> = try_audit!(
             audit,
-            stmt.query_row_named(&[(":idx_key", idx_key)], |row| row.get(0))
+            stmt.query_row_named(&[(":key", idx_key)], |row| row.get(0))
                 // We don't mind if it doesn't exist
                 .optional(),
             "SQLite Error {:?}",
@@ -178,79 +471,893 @@ pub trait IdlSqliteTransaction {
         Ok(Some(idl))
     }
 
-    /*
-    fn get_name2uuid(&self, name: &str) -> Result<Uuid, OperationError> {
-        unimplemented!();
+    fn get_name2uuid(
+        &self,
+        audit: &mut AuditScope,
+        name: &str,
+    ) -> Result<Option<Uuid>, OperationError> {
+        let mut stmt = try_audit!(
+            audit,
+            self.get_conn()
+                .prepare_cached("SELECT uuid FROM idx_name2uuid WHERE name = :name"),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        let uuid_raw: Option<String> = try_audit!(
+            audit,
+            stmt.query_row_named(&[(":name", &name)], |row| row.get(0))
+                .optional(),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        match uuid_raw {
+            Some(u) => Uuid::parse_str(u.as_str())
+                .map(Some)
+                .map_err(|_| OperationError::InvalidDBState),
+            None => Ok(None),
+        }
     }
 
-    fn get_uuid2name(&self, uuid: &Uuid) -> Result<String, OperationError> {
-        unimplemented!();
+    fn get_uuid2name(
+        &self,
+        audit: &mut AuditScope,
+        uuid: &Uuid,
+    ) -> Result<Option<String>, OperationError> {
+        let mut stmt = try_audit!(
+            audit,
+            self.get_conn()
+                .prepare("SELECT name FROM idx_uuid2name WHERE uuid = :uuid"),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        let name: Option<String> = try_audit!(
+            audit,
+            stmt.query_row_named(&[(":uuid", &uuid.to_hyphenated_ref().to_string())], |row| {
+                row.get(0)
+            })
+            .optional(),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        Ok(name)
     }
-    */
 
-    fn get_db_sid(&self) -> Result<Option<SID>, OperationError> {
-        // Try to get a value.
-        self.get_conn()
-            .query_row_named("SELECT data FROM db_sid WHERE id = 1", &[], |row| {
+    fn get_uuid2spn(
+        &self,
+        audit: &mut AuditScope,
+        uuid: &Uuid,
+    ) -> Result<Option<String>, OperationError> {
+        let mut stmt = try_audit!(
+            audit,
+            self.get_conn()
+                .prepare("SELECT spn FROM idx_uuid2spn WHERE uuid = :uuid"),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        let spn: Option<String> = try_audit!(
+            audit,
+            stmt.query_row_named(&[(":uuid", &uuid.to_hyphenated_ref().to_string())], |row| {
                 row.get(0)
             })
-            .optional()
-            .map(|e_opt| {
-                // If we have a row, we try to make it a sid
-                e_opt.map(|e| {
-                    let y: Vec<u8> = e;
-                    assert!(y.len() == 4);
-                    let mut sid: [u8; 4] = [0; 4];
-                    for i in 0..4 {
-                        sid[i] = y[i];
-                    }
-                    sid
-                })
-                // If no sid, we return none.
-            })
-            .map_err(|_| OperationError::SQLiteError)
+            .optional(),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        Ok(spn)
     }
-}
 
-impl IdlSqliteTransaction for IdlSqliteReadTransaction {
-    fn get_conn(&self) -> &r2d2::PooledConnection<r2d2_sqlite::SqliteConnectionManager> {
-        &self.conn
-    }
-}
+    fn get_idx_keys(
+        &self,
+        audit: &mut AuditScope,
+        attr: &String,
+        itype: &IndexType,
+    ) -> Result<Vec<(String, IDLBitRange)>, OperationError> {
+        let query = format!("SELECT key, idl FROM idx_{}_{}", itype.as_idx_str(), attr);
+        let mut stmt = try_audit!(
+            audit,
+            self.get_conn().prepare(query.as_str()),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        let idx_iter = try_audit!(
+            audit,
+            stmt.query_map(NO_PARAMS, |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, Vec<u8>>(1)?))
+            }),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
 
-impl Drop for IdlSqliteReadTransaction {
-    // Abort - so far this has proven reliable to use drop here.
-    fn drop(self: &mut Self) {
-        if !self.committed {
-            debug!("Aborting BE RO txn");
-            self.conn
-                .execute("ROLLBACK TRANSACTION", NO_PARAMS)
-                // We can't do this without expect.
-                // We may need to change how we do transactions to not rely on drop if
-                // it becomes and issue :(
-                .expect("Unable to rollback transaction! Can not proceed!!!");
-        }
+        idx_iter
+            .map(|v| {
+                let (key, raw) = v.map_err(|e| {
+                    audit_log!(audit, "SQLite Error {:?}", e);
+                    OperationError::SQLiteError
+                })?;
+                let idl = serde_cbor::from_slice(raw.as_slice())
+                    .map_err(|_| OperationError::SerdeCborError)?;
+                Ok((key, idl))
+            })
+            .collect()
     }
-}
 
-impl IdlSqliteReadTransaction {
-    pub fn new(conn: r2d2::PooledConnection<SqliteConnectionManager>) -> Self {
-        // Start the transaction
-        debug!("Starting BE RO txn ...");
-        // I'm happy for this to be an expect, because this is a huge failure
-        // of the server ... but if it happens a lot we should consider making
-        // this a Result<>
-        //
-        // There is no way to flag this is an RO operation.
-        conn.execute("BEGIN TRANSACTION", NO_PARAMS)
-            .expect("Unable to begin transaction!");
-        IdlSqliteReadTransaction {
-            committed: false,
-            conn: conn,
+    fn get_idx_range(
+        &self,
+        audit: &mut AuditScope,
+        attr: &String,
+        itype: &IndexType,
+        lower: Option<&String>,
+        upper: Option<&String>,
+    ) -> Result<Option<Vec<(String, IDLBitRange)>>, OperationError> {
+        if self.exists_idx(audit, attr, itype)? == false {
+            audit_log!(audit, "Index {:?} {:?} not found", itype, attr);
+            return Ok(None);
         }
-    }
-}
-
+        // Keys are TEXT, which sqlite compares bytewise, the same as a rust
+        // String, so an entry's values sort here as they do in the filter test.
+        let mut bounds: Vec<(&str, &dyn ToSql)> = Vec::new();
+        let mut clauses = Vec::new();
+        if let Some(l) = lower {
+            clauses.push("key >= :lower");
+            bounds.push((":lower", l));
+        }
+        if let Some(u) = upper {
+            clauses.push("key <= :upper");
+            bounds.push((":upper", u));
+        }
+        let query = if clauses.is_empty() {
+            format!("SELECT key, idl FROM idx_{}_{}", itype.as_idx_str(), attr)
+        } else {
+            format!(
+                "SELECT key, idl FROM idx_{}_{} WHERE {}",
+                itype.as_idx_str(),
+                attr,
+                clauses.join(" AND ")
+            )
+        };
+        let mut stmt = try_audit!(
+            audit,
+            self.get_conn().prepare(query.as_str()),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        let idx_iter = try_audit!(
+            audit,
+            stmt.query_map_named(bounds.as_slice(), |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, Vec<u8>>(1)?))
+            }),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+
+        idx_iter
+            .map(|v| {
+                let (key, raw) = v.map_err(|e| {
+                    audit_log!(audit, "SQLite Error {:?}", e);
+                    OperationError::SQLiteError
+                })?;
+                let idl = serde_cbor::from_slice(raw.as_slice())
+                    .map_err(|_| OperationError::SerdeCborError)?;
+                Ok((key, idl))
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map(Some)
+    }
+
+    fn list_idxs(&self, audit: &mut AuditScope) -> Result<Vec<String>, OperationError> {
+        let mut stmt = try_audit!(
+            audit,
+            self.get_conn()
+                .prepare("SELECT name from sqlite_master where type='table' and name LIKE 'idx_%'"),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        let idx_table_iter = try_audit!(
+            audit,
+            stmt.query_map(NO_PARAMS, |row| row.get(0)),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+
+        let r: Result<_, _> = idx_table_iter
+            .map(|v| {
+                v.map_err(|e| {
+                    audit_log!(audit, "SQLite Error {:?}", e);
+                    OperationError::SQLiteError
+                })
+            })
+            .collect();
+
+        r
+    }
+
+    fn count_idx_keys(
+        &self,
+        audit: &mut AuditScope,
+        attr: &String,
+        itype: &IndexType,
+    ) -> Result<i64, OperationError> {
+        let query = format!("SELECT COUNT(key) FROM idx_{}_{}", itype.as_idx_str(), attr);
+        self.get_conn()
+            .query_row(query.as_str(), NO_PARAMS, |row| row.get(0))
+            .map_err(|e| {
+                audit_log!(audit, "SQLite Error {:?}", e);
+                OperationError::SQLiteError
+            })
+    }
+
+    fn get_idx_stats(&self, audit: &mut AuditScope) -> Result<Vec<IndexStat>, OperationError> {
+        let mut stmt = try_audit!(
+            audit,
+            self.get_conn().prepare(
+                "SELECT attr, itype, keys, idl_total, idl_max, allids FROM index_stats ORDER BY attr, itype"
+            ),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        let stats_iter = try_audit!(
+            audit,
+            stmt.query_map(NO_PARAMS, |row| {
+                let keys: i64 = row.get(2)?;
+                let idl_total: i64 = row.get(3)?;
+                Ok(IndexStat {
+                    attr: row.get(0)?,
+                    itype: row.get(1)?,
+                    keys: u64::try_from(keys).unwrap_or(0),
+                    avg_idl: if keys > 0 {
+                        idl_total as f64 / keys as f64
+                    } else {
+                        0.0
+                    },
+                    max_idl: u64::try_from(row.get::<_, i64>(4)?).unwrap_or(0),
+                    allids: u64::try_from(row.get::<_, i64>(5)?).unwrap_or(0),
+                })
+            }),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+
+        stats_iter
+            .map(|v| {
+                v.map_err(|e| {
+                    audit_log!(audit, "SQLite Error {:?}", e);
+                    OperationError::SQLiteError
+                })
+            })
+            .collect()
+    }
+
+    fn get_db_size(&self, audit: &mut AuditScope) -> Result<(u64, u64), OperationError> {
+        let mut vals = Vec::with_capacity(3);
+        for pragma in &["page_size", "page_count", "max_page_count"] {
+            let v: i64 = try_audit!(
+                audit,
+                self.get_conn().query_row(
+                    format!("PRAGMA {}", pragma).as_str(),
+                    NO_PARAMS,
+                    |row| row.get(0)
+                ),
+                "SQLite Error {:?}",
+                OperationError::SQLiteError
+            );
+            vals.push(u64::try_from(v).unwrap_or(0));
+        }
+        Ok((vals[0] * vals[1], vals[0] * vals[2]))
+    }
+
+    // quick_check doesn't compare sqlite's indexes to their tables, so is
+    // about as fast as reading the file. Both give a single "ok" row when
+    // there is nothing wrong.
+    fn integrity_check(
+        &self,
+        audit: &mut AuditScope,
+        quick: bool,
+    ) -> Result<Vec<String>, OperationError> {
+        let pragma = if quick {
+            "PRAGMA quick_check"
+        } else {
+            "PRAGMA integrity_check"
+        };
+        let mut stmt = try_audit!(
+            audit,
+            self.get_conn().prepare(pragma),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        let rows: Result<Vec<String>, _> = try_audit!(
+            audit,
+            stmt.query_map(NO_PARAMS, |row| row.get(0)),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        )
+        .collect();
+        let rows = try_audit!(
+            audit,
+            rows,
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        Ok(rows.into_iter().filter(|r| r != "ok").collect())
+    }
+
+    fn get_db_domain(&self) -> Result<Option<DomainInfo>, OperationError> {
+        let row: Option<(String, String, i64, i64)> = self
+            .get_conn()
+            .query_row_named(
+                "SELECT uuid, name, generation, created FROM db_domain WHERE id = 1",
+                &[],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            )
+            .optional()
+            .map_err(|_| OperationError::SQLiteError)?;
+        match row {
+            Some((uuid, name, generation, created)) => Ok(Some(DomainInfo {
+                uuid: Uuid::parse_str(uuid.as_str()).map_err(|_| OperationError::InvalidDBState)?,
+                name: name,
+                generation: generation as u64,
+                created: created as u64,
+            })),
+            None => Ok(None),
+        }
+    }
+
+    fn get_blob(&self, hash: &str) -> Result<Option<Vec<u8>>, OperationError> {
+        self.get_conn()
+            .query_row_named(
+                "SELECT data FROM id2blob WHERE hash = :hash",
+                &[(":hash", &hash as &dyn ToSql)],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(|_| OperationError::SQLiteError)
+    }
+
+    fn get_idx_estimate(
+        &self,
+        audit: &mut AuditScope,
+        attr: &String,
+        itype: &IndexType,
+    ) -> Result<Option<u64>, OperationError> {
+        let stats: Option<(i64, i64)> =
+            try_audit!(
+            audit,
+            self.get_conn()
+                .query_row_named(
+                    "SELECT keys, idl_total FROM index_stats WHERE attr = :attr AND itype = :itype",
+                    &[(":attr", attr as &dyn ToSql), (":itype", &itype.as_idx_str())],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )
+                .optional(),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        Ok(match stats {
+            Some((keys, _)) if keys <= 0 => Some(0),
+            // Keys were counted by analyze, but the idls haven't been
+            // since the stats were added, so all we know is it isn't empty.
+            Some((_, total)) if total <= 0 => None,
+            Some((keys, total)) => u64::try_from((total + keys - 1) / keys).ok(),
+            None => None,
+        })
+    }
+
+    fn get_idx_usage(
+        &self,
+        audit: &mut AuditScope,
+        limit: usize,
+    ) -> Result<Vec<IdxSlot>, OperationError> {
+        let limit = i64::try_from(limit).map_err(|_| OperationError::InvalidRequestState)?;
+        let mut stmt = try_audit!(
+            audit,
+            self.get_conn().prepare(
+                "SELECT attr, itype, key FROM index_usage ORDER BY hits DESC LIMIT :limit"
+            ),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        let usage_iter = try_audit!(
+            audit,
+            stmt.query_map_named(&[(":limit", &limit)], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                ))
+            }),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+
+        let mut usage = Vec::new();
+        for v in usage_iter {
+            let (attr, itype, key) = v.map_err(|e| {
+                audit_log!(audit, "SQLite Error {:?}", e);
+                OperationError::SQLiteError
+            })?;
+            // An index type we no longer have can't be warmed.
+            match IndexType::from_idx_str(itype.as_str()) {
+                Some(itype) => usage.push((attr, itype, key)),
+                None => audit_log!(audit, "Ignoring usage of unknown index type {}", itype),
+            }
+        }
+        Ok(usage)
+    }
+
+    fn get_access_journal(
+        &self,
+        audit: &mut AuditScope,
+    ) -> Result<Vec<(i64, JournalRecord, String)>, OperationError> {
+        let mut stmt = try_audit!(
+            audit,
+            self.get_conn().prepare(
+                "SELECT id, time, uuid, identity, operation, hash FROM access_journal ORDER BY id ASC"
+            ),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        let journal_iter = try_audit!(
+            audit,
+            stmt.query_map(NO_PARAMS, |row| {
+                Ok((
+                    row.get(0)?,
+                    JournalRecord {
+                        time: row.get(1)?,
+                        uuid: row.get(2)?,
+                        identity: row.get(3)?,
+                        operation: row.get(4)?,
+                    },
+                    row.get(5)?,
+                ))
+            }),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        let journal: Result<Vec<_>, _> = journal_iter.collect();
+        Ok(try_audit!(
+            audit,
+            journal,
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        ))
+    }
+
+    fn get_changelog(&self, audit: &mut AuditScope) -> Result<Vec<ChangelogRow>, OperationError> {
+        let mut stmt = try_audit!(
+            audit,
+            self.get_conn().prepare(
+                "SELECT id, time, uuid, identity, operation, hash, signature FROM changelog ORDER BY id ASC"
+            ),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        let changelog_iter = try_audit!(
+            audit,
+            stmt.query_map(NO_PARAMS, |row| {
+                Ok((
+                    row.get(0)?,
+                    JournalRecord {
+                        time: row.get(1)?,
+                        uuid: row.get(2)?,
+                        identity: row.get(3)?,
+                        operation: row.get(4)?,
+                    },
+                    row.get(5)?,
+                    row.get(6)?,
+                ))
+            }),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        let changelog: Result<Vec<_>, _> = changelog_iter.collect();
+        Ok(try_audit!(
+            audit,
+            changelog,
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        ))
+    }
+
+    fn get_slow_queries(
+        &self,
+        audit: &mut AuditScope,
+        limit: usize,
+    ) -> Result<Vec<SlowQueryRecord>, OperationError> {
+        let limit = i64::try_from(limit).map_err(|_| OperationError::InvalidRequestState)?;
+        let mut stmt = try_audit!(
+            audit,
+            self.get_conn().prepare(
+                "SELECT id, time, filter, duration_ms, candidates, results, allids FROM slow_queries ORDER BY id DESC LIMIT :limit"
+            ),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        let query_iter = try_audit!(
+            audit,
+            stmt.query_map_named(&[(":limit", &limit)], |row| {
+                Ok(SlowQueryRecord {
+                    id: row.get::<_, i64>(0)? as u64,
+                    time: row.get(1)?,
+                    filter: row.get(2)?,
+                    duration_ms: row.get::<_, i64>(3)? as u64,
+                    candidates: row.get::<_, i64>(4)? as u64,
+                    results: row.get::<_, i64>(5)? as u64,
+                    allids: row.get(6)?,
+                })
+            }),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        let queries: Result<Vec<_>, _> = query_iter.collect();
+        Ok(try_audit!(
+            audit,
+            queries,
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        ))
+    }
+
+    fn get_reports(
+        &self,
+        audit: &mut AuditScope,
+        report: &str,
+        limit: usize,
+    ) -> Result<Vec<ReportRecord>, OperationError> {
+        let limit = i64::try_from(limit).map_err(|_| OperationError::InvalidRequestState)?;
+        let mut stmt = try_audit!(
+            audit,
+            self.get_conn().prepare(
+                "SELECT id, time, count, allids FROM report_history WHERE report = :report ORDER BY id DESC LIMIT :limit"
+            ),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        let report_iter = try_audit!(
+            audit,
+            stmt.query_map_named(
+                &[(":report", &report as &dyn ToSql), (":limit", &limit)],
+                |row| {
+                    Ok(ReportRecord {
+                        id: row.get::<_, i64>(0)? as u64,
+                        time: row.get(1)?,
+                        count: row.get::<_, i64>(2)? as u64,
+                        allids: row.get(3)?,
+                    })
+                }
+            ),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        let reports: Result<Vec<_>, _> = report_iter.collect();
+        Ok(try_audit!(
+            audit,
+            reports,
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        ))
+    }
+
+    fn get_report_last_run(
+        &self,
+        audit: &mut AuditScope,
+        report: &str,
+    ) -> Result<Option<i64>, OperationError> {
+        Ok(try_audit!(
+            audit,
+            self.get_conn().query_row_named(
+                "SELECT MAX(epoch) FROM report_history WHERE report = :report",
+                &[(":report", &report)],
+                |row| row.get(0)
+            ),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        ))
+    }
+
+    fn get_changestate(
+        &self,
+        audit: &mut AuditScope,
+        uuid: &Uuid,
+    ) -> Result<BTreeMap<String, Cid>, OperationError> {
+        let mut stmt = try_audit!(
+            audit,
+            self.get_conn()
+                .prepare_cached("SELECT attr, cid FROM changestate WHERE uuid = :uuid"),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        let uuid = uuid.to_hyphenated_ref().to_string();
+        let rows = try_audit!(
+            audit,
+            stmt.query_map_named(&[(":uuid", &uuid)], |row| Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?
+            ))),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        let mut changestate = BTreeMap::new();
+        for row in rows {
+            let (attr, cid) =
+                try_audit!(audit, row, "SQLite Error {:?}", OperationError::SQLiteError);
+            let cid = try_audit!(
+                audit,
+                cid.parse::<Cid>(),
+                "Invalid cid {:?}",
+                OperationError::InvalidDBState
+            );
+            changestate.insert(attr, cid);
+        }
+        Ok(changestate)
+    }
+
+    fn get_max_cid(&self, audit: &mut AuditScope) -> Result<Option<Cid>, OperationError> {
+        let cid: Option<String> = try_audit!(
+            audit,
+            self.get_conn()
+                .query_row("SELECT MAX(cid) FROM changestate", NO_PARAMS, |row| row
+                    .get(0)),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        match cid {
+            Some(cid) => Ok(Some(try_audit!(
+                audit,
+                cid.parse::<Cid>(),
+                "Invalid cid {:?}",
+                OperationError::InvalidDBState
+            ))),
+            None => Ok(None),
+        }
+    }
+}
+
+// The most this process may write to a file, if limited (ulimit -f). Writing
+// past it raises SIGXFSZ, which kills the server outright.
+#[cfg(unix)]
+fn file_size_limit() -> Option<u64> {
+    let mut rlim = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // This only writes to rlim.
+    if unsafe { libc::getrlimit(libc::RLIMIT_FSIZE, &mut rlim) } != 0
+        || rlim.rlim_cur == libc::RLIM_INFINITY
+    {
+        None
+    } else {
+        Some(rlim.rlim_cur as u64)
+    }
+}
+
+#[cfg(not(unix))]
+fn file_size_limit() -> Option<u64> {
+    None
+}
+
+// SQLCipher is given the raw key as a blob literal, so it's used as is rather
+// than derived from a passphrase.
+fn key_pragma(key: &[u8; 32]) -> String {
+    let hex: String = key.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("PRAGMA key = \"x'{}'\";", hex)
+}
+
+// Plain sqlite ignores the key pragma, and a wrong key only shows once a page
+// is read, so both are checked before the pool is built. The pool would
+// otherwise keep retrying the connections until it timed out.
+fn check_db_key(audit: &mut AuditScope, path: &str, key: &[u8; 32]) -> Result<(), OperationError> {
+    let conn = if path == "" {
+        rusqlite::Connection::open_in_memory()
+    } else {
+        rusqlite::Connection::open(path)
+    };
+    let conn = try_audit!(
+        audit,
+        conn,
+        "sqlite error {:?}",
+        OperationError::SQLiteError
+    );
+    try_audit!(
+        audit,
+        conn.execute_batch(key_pragma(key).as_str()),
+        "sqlite error {:?}",
+        OperationError::SQLiteError
+    );
+    match conn.query_row("PRAGMA cipher_version", NO_PARAMS, |row| {
+        row.get::<_, String>(0)
+    }) {
+        Ok(v) => audit_log!(audit, "database encrypted with sqlcipher {}", v),
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            error!("A database key was given, but this build has no sqlcipher support");
+            return Err(OperationError::InvalidState);
+        }
+        Err(e) => {
+            audit_log!(audit, "sqlite error {:?}", e);
+            return Err(OperationError::SQLiteError);
+        }
+    }
+    conn.query_row("SELECT count(*) FROM sqlite_master", NO_PARAMS, |row| {
+        row.get::<_, i64>(0)
+    })
+    .map(|_| ())
+    .map_err(|e| {
+        error!(
+            "Unable to read the database with this key - is it the right key, and was it created encrypted? -> {:?}",
+            e
+        );
+        OperationError::InvalidDBState
+    })
+}
+
+// The configured tuning as statements. These were checked when the config
+// was read, so are safe to format in.
+fn tuning_pragmas(pragmas: &DbPragmas) -> String {
+    let mut stmts = String::new();
+    if let Some(s) = pragmas.synchronous.as_ref() {
+        stmts.push_str(format!("PRAGMA synchronous = {};", s).as_str());
+    }
+    if let Some(c) = pragmas.cache_size {
+        stmts.push_str(format!("PRAGMA cache_size = {};", c).as_str());
+    }
+    if let Some(m) = pragmas.mmap_size {
+        if !cfg!(target_pointer_width = "32") {
+            stmts.push_str(format!("PRAGMA mmap_size = {};", m).as_str());
+        }
+    }
+    if let Some(w) = pragmas.wal_autocheckpoint {
+        stmts.push_str(format!("PRAGMA wal_autocheckpoint = {};", w).as_str());
+    }
+    if let Some(b) = pragmas.busy_timeout {
+        stmts.push_str(format!("PRAGMA busy_timeout = {};", b).as_str());
+    }
+    stmts
+}
+
+// Pragmas only last as long as the connection, so these are applied to each
+// one as the pool opens it.
+fn init_conn(
+    conn: &mut rusqlite::Connection,
+    fsize_limit: Option<u64>,
+    db_key: Option<[u8; 32]>,
+    pragmas: &DbPragmas,
+) -> Result<(), rusqlite::Error> {
+    // This must come before anything reads the database.
+    if let Some(key) = db_key.as_ref() {
+        conn.execute_batch(key_pragma(key).as_str())?;
+    }
+    if cfg!(target_pointer_width = "32") {
+        // A 32 bit address space can't hold a map of a large database, and a
+        // failed map surfaces as an unhelpful IO error. Only ever read().
+        conn.execute_batch("PRAGMA mmap_size = 0;")?;
+    }
+    conn.execute_batch(tuning_pragmas(pragmas).as_str())?;
+    conn.set_prepared_statement_cache_capacity(STMT_CACHE_CAPACITY);
+    if let Some(limit) = fsize_limit {
+        // Keep sqlite under the limit, so that it reports the database full
+        // rather than the process being killed part way through a write.
+        let page_size: i64 = conn.query_row("PRAGMA page_size", NO_PARAMS, |row| row.get(0))?;
+        conn.execute_batch(
+            format!(
+                "PRAGMA max_page_count = {};",
+                limit / page_size.max(1) as u64
+            )
+            .as_str(),
+        )?;
+    }
+    Ok(())
+}
+
+// A busy database, or a connection that failed to open, is usually a moment
+// away from being fine again, so these are retried a few times, backing off,
+// before the caller is told.
+const TXN_BEGIN_RETRIES: u32 = 4;
+const TXN_BEGIN_BACKOFF_MS: u64 = 50;
+
+fn is_busy(e: &rusqlite::Error) -> bool {
+    match e {
+        rusqlite::Error::SqliteFailure(f, _) => {
+            f.code == ErrorCode::DatabaseBusy || f.code == ErrorCode::DatabaseLocked
+        }
+        _ => false,
+    }
+}
+
+fn backoff(attempt: u32) {
+    thread::sleep(Duration::from_millis(
+        TXN_BEGIN_BACKOFF_MS * u64::from(attempt + 1),
+    ));
+}
+
+fn begin(conn: &rusqlite::Connection) -> Result<(), OperationError> {
+    let mut attempt = 0;
+    loop {
+        // A rollback that failed in drop leaves the connection inside the old
+        // txn, and it went back to the pool like that. Finish it off here.
+        if !conn.is_autocommit() {
+            error!("Connection returned to pool mid transaction, rolling back");
+            if let Err(e) = conn.execute("ROLLBACK TRANSACTION", NO_PARAMS) {
+                error!("Unable to rollback stale transaction -> {:?}", e);
+            }
+        }
+        match conn.execute("BEGIN TRANSACTION", NO_PARAMS) {
+            Ok(_) => return Ok(()),
+            Err(ref e) if is_busy(e) && attempt < TXN_BEGIN_RETRIES => {
+                debug!("Database busy on begin, retrying -> {:?}", e);
+                backoff(attempt);
+                attempt += 1;
+            }
+            Err(ref e) if is_busy(e) => {
+                error!("Database busy, unable to begin transaction -> {:?}", e);
+                return Err(OperationError::Busy);
+            }
+            Err(e) => {
+                error!("Unable to begin transaction -> {:?}", e);
+                return Err(OperationError::Unavailable);
+            }
+        }
+    }
+}
+
+// This is called from drop, so it can't fail. If it doesn't work, the next
+// begin on this connection will try again.
+fn rollback(conn: &rusqlite::Connection) {
+    if let Err(e) = conn.execute("ROLLBACK TRANSACTION", NO_PARAMS) {
+        error!("Unable to rollback transaction -> {:?}", e);
+    }
+}
+
+// Shared by write_db_domain and the migration that creates the record.
+fn write_db_domain(conn: &rusqlite::Connection, domain: &DomainInfo) -> rusqlite::Result<()> {
+    conn.execute_named(
+        "INSERT OR REPLACE INTO db_domain (id, uuid, name, generation, created)
+        VALUES(1, :uuid, :name, :generation, :created)",
+        &[
+            (
+                ":uuid",
+                &domain.uuid.to_hyphenated_ref().to_string() as &dyn ToSql,
+            ),
+            (":name", &domain.name as &dyn ToSql),
+            (":generation", &(domain.generation as i64) as &dyn ToSql),
+            (":created", &(domain.created as i64) as &dyn ToSql),
+        ],
+    )
+    .map(|_| ())
+}
+
+impl IdlSqliteTransaction for IdlSqliteReadTransaction {
+    fn get_conn(&self) -> &r2d2::PooledConnection<r2d2_sqlite::SqliteConnectionManager> {
+        &self.conn
+    }
+}
+
+impl Drop for IdlSqliteReadTransaction {
+    // Abort - so far this has proven reliable to use drop here.
+    fn drop(self: &mut Self) {
+        if !self.committed {
+            debug!("Aborting BE RO txn");
+            rollback(&self.conn);
+        }
+        self.metrics.read_txn_end(self.start.elapsed());
+    }
+}
+
+impl IdlSqliteReadTransaction {
+    pub fn new(
+        conn: r2d2::PooledConnection<SqliteConnectionManager>,
+        metrics: PoolMetrics,
+    ) -> Result<Self, OperationError> {
+        // Start the transaction
+        debug!("Starting BE RO txn ...");
+        // There is no way to flag this is an RO operation.
+        begin(&conn)?;
+        Ok(IdlSqliteReadTransaction {
+            committed: false,
+            conn: conn,
+            metrics: metrics,
+            start: Instant::now(),
+        })
+    }
+}
+
 impl IdlSqliteTransaction for IdlSqliteWriteTransaction {
     fn get_conn(&self) -> &r2d2::PooledConnection<r2d2_sqlite::SqliteConnectionManager> {
         &self.conn
@@ -262,124 +1369,610 @@ impl Drop for IdlSqliteWriteTransaction {
     fn drop(self: &mut Self) {
         if !self.committed {
             debug!("Aborting BE WR txn");
+            rollback(&self.conn);
+        }
+        self.metrics
+            .write_txn_end(self.start.elapsed(), self.committed);
+    }
+}
+
+impl IdlSqliteWriteTransaction {
+    pub fn new(
+        conn: r2d2::PooledConnection<SqliteConnectionManager>,
+        metrics: PoolMetrics,
+    ) -> Result<Self, OperationError> {
+        // Start the transaction
+        debug!("Starting BE WR txn ...");
+        begin(&conn)?;
+        Ok(IdlSqliteWriteTransaction {
+            committed: false,
+            conn: conn,
+            metrics: metrics,
+            start: Instant::now(),
+            csn: Cell::new(0),
+            savepoints: RefCell::new(Vec::new()),
+        })
+    }
+}
+
+impl IdLayerWriteTransaction for IdlSqliteWriteTransaction {
+    fn commit(mut self, audit: &mut AuditScope) -> Result<(), OperationError> {
+        audit_log!(audit, "Commiting BE txn");
+        assert!(!self.committed);
+        self.committed = true;
+
+        // A commit that is busy leaves the txn open, and the connection goes
+        // back to the pool like that, so begin will roll it back.
+        self.conn
+            .execute("COMMIT TRANSACTION", NO_PARAMS)
+            .map(|_| ())
+            .map_err(|e| {
+                audit_log!(audit, "SQLite Error {:?}", e);
+                if is_busy(&e) {
+                    OperationError::Busy
+                } else {
+                    OperationError::BackendEngine
+                }
+            })
+    }
+
+    fn vacuum(mut self, audit: &mut AuditScope) -> Result<u64, OperationError> {
+        // VACUUM can't run in a txn, and takes its own write lock, so ours
+        // is committed first. The checkpoint is what shrinks the file when
+        // we are in wal mode.
+        assert!(!self.committed);
+        self.committed = true;
+        try_audit!(
+            audit,
+            self.conn.execute_batch("COMMIT TRANSACTION;"),
+            "sqlite error {:?}",
+            OperationError::BackendEngine
+        );
+        audit_log!(audit, "Vacuuming BE");
+        try_audit!(
+            audit,
+            self.conn
+                .execute_batch("VACUUM; PRAGMA wal_checkpoint(TRUNCATE);"),
+            "sqlite error {:?}",
+            OperationError::SQLiteError
+        );
+        self.get_db_size(audit).map(|(size, _)| size)
+    }
+
+    fn savepoint(&self, au: &mut AuditScope) -> Result<usize, OperationError> {
+        let mut savepoints = self.savepoints.borrow_mut();
+        let depth = savepoints.len();
+        try_audit!(
+            au,
+            self.conn
+                .execute_batch(format!("SAVEPOINT sp_{};", depth).as_str()),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        savepoints.push(self.csn.get());
+        Ok(depth)
+    }
+
+    fn release_savepoint(&self, au: &mut AuditScope, depth: usize) -> Result<(), OperationError> {
+        let mut savepoints = self.savepoints.borrow_mut();
+        if depth >= savepoints.len() {
+            audit_log!(au, "No savepoint at depth {}", depth);
+            return Err(OperationError::InvalidState);
+        }
+        try_audit!(
+            au,
+            self.conn
+                .execute_batch(format!("RELEASE SAVEPOINT sp_{};", depth).as_str()),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        savepoints.truncate(depth);
+        Ok(())
+    }
+
+    fn rollback_to_savepoint(
+        &self,
+        au: &mut AuditScope,
+        depth: usize,
+    ) -> Result<(), OperationError> {
+        let mut savepoints = self.savepoints.borrow_mut();
+        if depth >= savepoints.len() {
+            audit_log!(au, "No savepoint at depth {}", depth);
+            return Err(OperationError::InvalidState);
+        }
+        // ROLLBACK TO leaves the savepoint open, so it's released after.
+        try_audit!(
+            au,
+            self.conn.execute_batch(
+                format!(
+                    "ROLLBACK TO SAVEPOINT sp_{}; RELEASE SAVEPOINT sp_{};",
+                    depth, depth
+                )
+                .as_str()
+            ),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        self.csn.set(savepoints[depth]);
+        savepoints.truncate(depth);
+        Ok(())
+    }
+
+    fn get_id2entry_max_id(&self) -> Result<u64, OperationError> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT MAX(id) as id_max FROM id2entry")
+            .map_err(|_| OperationError::SQLiteError)?;
+        // This exists checks for if any rows WERE returned
+        // that way we know to shortcut or not.
+        let v = stmt
+            .exists(NO_PARAMS)
+            .map_err(|_| OperationError::SQLiteError)?;
+
+        Ok(if v {
+            // We have some rows, let get max!
+            let i: Option<IdKey> = stmt
+                .query_row(NO_PARAMS, |row| row.get(0))
+                .map_err(|_| OperationError::SQLiteError)?;
+            i.map(|k| k.0).unwrap_or(0)
+        } else {
+            // No rows are present, return a 0.
+            0
+        })
+    }
+
+    fn write_identries(
+        &self,
+        au: &mut AuditScope,
+        entries: Vec<IdEntry>,
+    ) -> Result<(), OperationError> {
+        let csn = self.get_txn_csn(au)?;
+        let mut stmt = try_audit!(
+            au,
+            self.conn.prepare_cached(
+                "INSERT OR REPLACE INTO id2entry (id, data, csn) VALUES(:id, :data, :csn)"
+            ),
+            "RusqliteError: {:?}",
+            OperationError::SQLiteError
+        );
+
+        try_audit!(
+            au,
+            entries.iter().try_for_each(|ser_ent| {
+                stmt.execute_named(&[
+                    (":id", &IdKey(ser_ent.id) as &dyn ToSql),
+                    (":data", &ser_ent.data as &dyn ToSql),
+                    (":csn", &csn as &dyn ToSql),
+                ])
+                // remove the updated usize
+                .map(|_| ())
+            }),
+            "RusqliteError: {:?}",
+            OperationError::SQLiteError
+        );
+        Ok(())
+    }
+
+    fn delete_identry(&self, au: &mut AuditScope, idl: Vec<u64>) -> Result<(), OperationError> {
+        let mut stmt = try_audit!(
+            au,
+            self.conn
+                .prepare_cached("DELETE FROM id2entry WHERE id = :id"),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+
+        let mut ref_stmt = try_audit!(
+            au,
+            self.conn
+                .prepare_cached("DELETE FROM id2blob_ref WHERE id = :id"),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+
+        idl.into_iter().map(IdKey).try_for_each(|id| {
+            stmt.execute(&[&id])
+                .and_then(|_| ref_stmt.execute(&[&id]))
+                .map(|_| ())
+                .map_err(|_| OperationError::SQLiteError)
+        })
+    }
+
+    fn write_blob(
+        &self,
+        au: &mut AuditScope,
+        hash: &str,
+        data: &[u8],
+    ) -> Result<(), OperationError> {
+        try_audit!(
+            au,
+            self.conn.execute_named(
+                "INSERT OR IGNORE INTO id2blob (hash, data) VALUES(:hash, :data)",
+                &[
+                    (":hash", &hash as &dyn ToSql),
+                    (":data", &data as &dyn ToSql)
+                ],
+            ),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        Ok(())
+    }
+
+    fn write_blob_refs(
+        &self,
+        au: &mut AuditScope,
+        id: u64,
+        hashes: &[String],
+    ) -> Result<(), OperationError> {
+        try_audit!(
+            au,
+            self.conn.execute_named(
+                "DELETE FROM id2blob_ref WHERE id = :id",
+                &[(":id", &IdKey(id) as &dyn ToSql)],
+            ),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        if hashes.is_empty() {
+            return Ok(());
+        }
+        let mut stmt = try_audit!(
+            au,
+            self.conn
+                .prepare("INSERT OR IGNORE INTO id2blob_ref (id, hash) VALUES(:id, :hash)"),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        try_audit!(
+            au,
+            hashes.iter().try_for_each(|hash| {
+                stmt.execute_named(&[
+                    (":id", &IdKey(id) as &dyn ToSql),
+                    (":hash", hash as &dyn ToSql),
+                ])
+                .map(|_| ())
+            }),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        Ok(())
+    }
+
+    fn purge_blobs(&self, au: &mut AuditScope) -> Result<usize, OperationError> {
+        let purged = try_audit!(
+            au,
+            self.conn.execute(
+                "DELETE FROM id2blob WHERE hash NOT IN (SELECT hash FROM id2blob_ref)",
+                NO_PARAMS,
+            ),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        if purged > 0 {
+            audit_log!(au, "purged {} unreferenced blobs", purged);
+        }
+        Ok(purged)
+    }
+
+    fn write_removed(&self, au: &mut AuditScope, uuids: Vec<String>) -> Result<(), OperationError> {
+        let csn = self.get_txn_csn(au)?;
+        let mut stmt = try_audit!(
+            au,
+            self.conn
+                .prepare("INSERT OR REPLACE INTO id2entry_removed (uuid, csn) VALUES(:uuid, :csn)"),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        try_audit!(
+            au,
+            uuids.iter().try_for_each(|u| {
+                stmt.execute_named(&[(":uuid", u as &dyn ToSql), (":csn", &csn as &dyn ToSql)])
+                    .map(|_| ())
+            }),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        Ok(())
+    }
+
+    fn set_backup_marker(&self, csn: i64) -> Result<(), OperationError> {
+        self.set_db_version_key(DBV_BACKUP_CSN, csn).map_err(|e| {
+            debug!("sqlite error {:?}", e);
+            OperationError::SQLiteError
+        })
+    }
+
+    fn write_idx_usage(
+        &self,
+        audit: &mut AuditScope,
+        usage: &BTreeMap<IdxSlot, i64>,
+    ) -> Result<(), OperationError> {
+        // Our sqlite may predate upsert, so update, and insert if there was
+        // nothing to update.
+        let mut update_stmt = try_audit!(
+            audit,
+            self.conn.prepare(
+                "UPDATE index_usage SET hits = hits + :hits WHERE attr = :attr AND itype = :itype AND key = :key"
+            ),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        let mut insert_stmt = try_audit!(
+            audit,
+            self.conn.prepare(
+                "INSERT INTO index_usage (attr, itype, key, hits) VALUES(:attr, :itype, :key, :hits)"
+            ),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        try_audit!(
+            audit,
+            usage.iter().try_for_each(|((attr, itype, key), hits)| {
+                let params: [(&str, &dyn ToSql); 4] = [
+                    (":attr", attr),
+                    (":itype", &itype.as_idx_str()),
+                    (":key", key),
+                    (":hits", hits),
+                ];
+                match update_stmt.execute_named(&params)? {
+                    0 => insert_stmt.execute_named(&params).map(|_| ()),
+                    _ => Ok(()),
+                }
+            }),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        Ok(())
+    }
+
+    fn write_access_journal(
+        &self,
+        audit: &mut AuditScope,
+        records: &[JournalRecord],
+    ) -> Result<(), OperationError> {
+        let last: Option<String> = try_audit!(
+            audit,
             self.conn
-                .execute("ROLLBACK TRANSACTION", NO_PARAMS)
-                .expect("Unable to rollback transaction! Can not proceed!!!");
+                .query_row(
+                    "SELECT hash FROM access_journal ORDER BY id DESC LIMIT 1",
+                    NO_PARAMS,
+                    |row| row.get(0),
+                )
+                .optional(),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        let mut stmt = try_audit!(
+            audit,
+            self.conn.prepare(
+                "INSERT INTO access_journal (time, uuid, identity, operation, hash) VALUES(:time, :uuid, :identity, :operation, :hash)"
+            ),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        let mut prev = last.unwrap_or_default();
+        for rec in records {
+            let hash = rec.chain_hash(prev.as_str());
+            try_audit!(
+                audit,
+                stmt.execute_named(&[
+                    (":time", &rec.time),
+                    (":uuid", &rec.uuid),
+                    (":identity", &rec.identity),
+                    (":operation", &rec.operation),
+                    (":hash", &hash),
+                ]),
+                "SQLite Error {:?}",
+                OperationError::SQLiteError
+            );
+            prev = hash;
         }
+        Ok(())
     }
-}
 
-impl IdlSqliteWriteTransaction {
-    pub fn new(conn: r2d2::PooledConnection<SqliteConnectionManager>) -> Self {
-        // Start the transaction
-        debug!("Starting BE WR txn ...");
-        conn.execute("BEGIN TRANSACTION", NO_PARAMS)
-            .expect("Unable to begin transaction!");
-        IdlSqliteWriteTransaction {
-            committed: false,
-            conn: conn,
+    fn write_changestate(
+        &self,
+        audit: &mut AuditScope,
+        rows: &[(Uuid, String, Cid)],
+    ) -> Result<(), OperationError> {
+        let mut stmt = try_audit!(
+            audit,
+            self.conn.prepare_cached(
+                "INSERT OR REPLACE INTO changestate (uuid, attr, cid) VALUES(:uuid, :attr, :cid)"
+            ),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        for (uuid, attr, cid) in rows {
+            try_audit!(
+                audit,
+                stmt.execute_named(&[
+                    (":uuid", &uuid.to_hyphenated_ref().to_string()),
+                    (":attr", attr),
+                    (":cid", &cid.to_string()),
+                ]),
+                "SQLite Error {:?}",
+                OperationError::SQLiteError
+            );
         }
+        Ok(())
     }
 
-    pub fn commit(mut self, audit: &mut AuditScope) -> Result<(), OperationError> {
-        audit_log!(audit, "Commiting BE txn");
-        assert!(!self.committed);
-        self.committed = true;
-
-        self.conn
-            .execute("COMMIT TRANSACTION", NO_PARAMS)
-            .map(|_| ())
-            .map_err(|e| {
-                println!("{:?}", e);
-                OperationError::BackendEngine
-            })
+    fn delete_changestate(
+        &self,
+        audit: &mut AuditScope,
+        uuids: &[Uuid],
+    ) -> Result<(), OperationError> {
+        let mut stmt = try_audit!(
+            audit,
+            self.conn
+                .prepare_cached("DELETE FROM changestate WHERE uuid = :uuid"),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        for uuid in uuids {
+            try_audit!(
+                audit,
+                stmt.execute_named(&[(":uuid", &uuid.to_hyphenated_ref().to_string())]),
+                "SQLite Error {:?}",
+                OperationError::SQLiteError
+            );
+        }
+        Ok(())
     }
 
-    pub fn get_id2entry_max_id(&self) -> Result<i64, OperationError> {
-        let mut stmt = self
-            .conn
-            .prepare("SELECT MAX(id) as id_max FROM id2entry")
-            .map_err(|_| OperationError::SQLiteError)?;
-        // This exists checks for if any rows WERE returned
-        // that way we know to shortcut or not.
-        let v = stmt
-            .exists(NO_PARAMS)
-            .map_err(|_| OperationError::SQLiteError)?;
-
-        Ok(if v {
-            // We have some rows, let get max!
-            let i: Option<i64> = stmt
-                .query_row(NO_PARAMS, |row| row.get(0))
-                .map_err(|_| OperationError::SQLiteError)?;
-            i.unwrap_or(0)
-        } else {
-            // No rows are present, return a 0.
-            0
-        })
+    fn write_changelog(
+        &self,
+        audit: &mut AuditScope,
+        records: &[JournalRecord],
+        key: Option<&[u8; 32]>,
+    ) -> Result<(), OperationError> {
+        let last: Option<String> = try_audit!(
+            audit,
+            self.conn
+                .query_row(
+                    "SELECT hash FROM changelog ORDER BY id DESC LIMIT 1",
+                    NO_PARAMS,
+                    |row| row.get(0),
+                )
+                .optional(),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        let mut stmt = try_audit!(
+            audit,
+            self.conn.prepare(
+                "INSERT INTO changelog (time, uuid, identity, operation, hash, signature) VALUES(:time, :uuid, :identity, :operation, :hash, :signature)"
+            ),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        let mut prev = last.unwrap_or_default();
+        for rec in records {
+            let hash = rec.chain_hash(prev.as_str());
+            let signature = match key {
+                Some(key) => Some(try_audit!(
+                    audit,
+                    changelog::sign(key, hash.as_str()),
+                    "Changelog signing failed {:?}",
+                    OperationError::BackendEngine
+                )),
+                None => None,
+            };
+            try_audit!(
+                audit,
+                stmt.execute_named(&[
+                    (":time", &rec.time),
+                    (":uuid", &rec.uuid),
+                    (":identity", &rec.identity),
+                    (":operation", &rec.operation),
+                    (":hash", &hash),
+                    (":signature", &signature),
+                ]),
+                "SQLite Error {:?}",
+                OperationError::SQLiteError
+            );
+            prev = hash;
+        }
+        Ok(())
     }
 
-    pub fn write_identries(
+    fn write_slow_queries(
         &self,
-        au: &mut AuditScope,
-        entries: Vec<IdEntry>,
+        audit: &mut AuditScope,
+        queries: &[SlowQuery],
     ) -> Result<(), OperationError> {
         let mut stmt = try_audit!(
-            au,
-            self.conn
-                .prepare("INSERT OR REPLACE INTO id2entry (id, data) VALUES(:id, :data)"),
-            "RusqliteError: {:?}",
+            audit,
+            self.conn.prepare(
+                "INSERT INTO slow_queries (time, filter, duration_ms, candidates, results, allids) VALUES(:time, :filter, :duration_ms, :candidates, :results, :allids)"
+            ),
+            "SQLite Error {:?}",
             OperationError::SQLiteError
         );
-
         try_audit!(
-            au,
-            entries.iter().try_for_each(|ser_ent| {
-                stmt.execute_named(&[(":id", &ser_ent.id), (":data", &ser_ent.data)])
-                    // remove the updated usize
-                    .map(|_| ())
+            audit,
+            queries.iter().try_for_each(|q| {
+                stmt.execute_named(&[
+                    (":time", &q.time),
+                    (":filter", &q.filter),
+                    (":duration_ms", &(q.duration_ms as i64)),
+                    (":candidates", &(q.candidates as i64)),
+                    (":results", &(q.results as i64)),
+                    (":allids", &q.allids),
+                ])
+                .map(|_| ())
             }),
-            "RusqliteError: {:?}",
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        try_audit!(
+            audit,
+            self.conn.execute_named(
+                "DELETE FROM slow_queries WHERE id <= (SELECT MAX(id) FROM slow_queries) - :keep",
+                &[(":keep", &SLOW_QUERY_KEEP)],
+            ),
+            "SQLite Error {:?}",
             OperationError::SQLiteError
         );
         Ok(())
     }
 
-    pub fn delete_identry(&self, au: &mut AuditScope, idl: Vec<i64>) -> Result<(), OperationError> {
-        let mut stmt = try_audit!(
-            au,
-            self.conn.prepare("DELETE FROM id2entry WHERE id = :id"),
+    fn write_report(
+        &self,
+        audit: &mut AuditScope,
+        report: &str,
+        epoch: i64,
+        time: &str,
+        count: u64,
+        allids: bool,
+    ) -> Result<(), OperationError> {
+        try_audit!(
+            audit,
+            self.conn.execute_named(
+                "INSERT INTO report_history (report, time, epoch, count, allids) VALUES(:report, :time, :epoch, :count, :allids)",
+                &[
+                    (":report", &report as &dyn ToSql),
+                    (":time", &time),
+                    (":epoch", &epoch),
+                    (":count", &(count as i64)),
+                    (":allids", &allids),
+                ],
+            ),
             "SQLite Error {:?}",
             OperationError::SQLiteError
         );
-
-        idl.iter().try_for_each(|id| {
-            stmt.execute(&[&id])
-                .map(|_| ())
-                .map_err(|_| OperationError::SQLiteError)
-        })
+        try_audit!(
+            audit,
+            self.conn.execute_named(
+                "DELETE FROM report_history WHERE report = :report AND id NOT IN (SELECT id FROM report_history WHERE report = :report ORDER BY id DESC LIMIT :keep)",
+                &[(":report", &report as &dyn ToSql), (":keep", &REPORT_KEEP)],
+            ),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        Ok(())
     }
 
-    pub fn write_idl(
+    fn write_idl(
         &self,
         audit: &mut AuditScope,
         attr: &String,
         itype: &IndexType,
         idx_key: &String,
         idl: &IDLBitRange,
-    ) -> Result<(), OperationError> {
+    ) -> Result<usize, OperationError> {
+        let stmts = idx_stmts(attr, itype);
+        let old_len = self.get_idl_len(audit, stmts.get.as_str(), idx_key)?;
         if idl.len() == 0 {
             audit_log!(audit, "purging idl -> {:?}", idl);
             // delete it
             // Delete this idx_key from the table.
-            let query = format!(
-                "DELETE FROM idx_{}_{} WHERE key = :key",
-                itype.as_idx_str(),
-                attr
-            );
-
             self.conn
-                .prepare(query.as_str())
+                .prepare_cached(stmts.del.as_str())
                 .and_then(|mut stmt| stmt.execute_named(&[(":key", &idx_key)]))
+                .map(|_| 0)
                 .map_err(|e| {
                     audit_log!(audit, "SQLite Error {:?}", e);
                     OperationError::SQLiteError
@@ -393,25 +1986,215 @@ impl IdlSqliteWriteTransaction {
             })?;
 
             // update or create it.
-            let query = format!(
-                "INSERT OR REPLACE INTO idx_{}_{} (key, idl) VALUES(:key, :idl)",
-                itype.as_idx_str(),
-                attr
-            );
-
             self.conn
-                .prepare(query.as_str())
+                .prepare_cached(stmts.put.as_str())
                 .and_then(|mut stmt| stmt.execute_named(&[(":key", &idx_key), (":idl", &idl_raw)]))
+                .map(|_| idl_raw.len())
                 .map_err(|e| {
                     audit_log!(audit, "SQLite Error {:?}", e);
                     OperationError::SQLiteError
                 })
         }
-        // Get rid of the sqlite rows usize
-        .map(|_| ())
+        .and_then(|written| {
+            self.update_idx_stats(audit, attr, itype, old_len, idl.len() as i64)
+                .map(|_| written)
+        })
+    }
+
+    fn write_idx_cardinality(
+        &self,
+        audit: &mut AuditScope,
+        attr: &String,
+        itype: &IndexType,
+        keys: i64,
+    ) -> Result<(), OperationError> {
+        self.touch_idx_stats(audit, attr, itype)?;
+        self.conn
+            .execute_named(
+                "UPDATE index_stats SET keys = :keys WHERE attr = :attr AND itype = :itype",
+                &[
+                    (":attr", attr as &dyn ToSql),
+                    (":itype", &itype.as_idx_str()),
+                    (":keys", &keys),
+                ],
+            )
+            .map(|_| ())
+            .map_err(|e| {
+                audit_log!(audit, "SQLite Error {:?}", e);
+                OperationError::SQLiteError
+            })
+    }
+
+    fn write_idx_allids(
+        &self,
+        audit: &mut AuditScope,
+        allids: &BTreeMap<(String, IndexType), i64>,
+    ) -> Result<(), OperationError> {
+        allids.iter().try_for_each(|((attr, itype), n)| {
+            self.touch_idx_stats(audit, attr, itype)?;
+            self.conn
+                .execute_named(
+                    "UPDATE index_stats SET allids = allids + :n WHERE attr = :attr AND itype = :itype",
+                    &[(":attr", attr as &dyn ToSql), (":itype", &itype.as_idx_str()), (":n", n)],
+                )
+                .map(|_| ())
+                .map_err(|e| {
+                    audit_log!(audit, "SQLite Error {:?}", e);
+                    OperationError::SQLiteError
+                })
+        })
+    }
+
+    // This rebuilds sqlite_stat1, which the query planner uses to choose
+    // between indexes on id2entry and our own tables.
+    fn analyze(&self, audit: &mut AuditScope) -> Result<(), OperationError> {
+        try_audit!(
+            audit,
+            self.conn.execute("ANALYZE", NO_PARAMS),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        Ok(())
+    }
+
+    fn write_name2uuid_add(
+        &self,
+        audit: &mut AuditScope,
+        name: &str,
+        uuid: &Uuid,
+    ) -> Result<(), OperationError> {
+        self.conn
+            .execute_named(
+                "INSERT OR REPLACE INTO idx_name2uuid (name, uuid) VALUES(:name, :uuid)",
+                &[
+                    (":name", &name as &dyn ToSql),
+                    (":uuid", &uuid.to_hyphenated_ref().to_string()),
+                ],
+            )
+            .map(|_| ())
+            .map_err(|e| {
+                audit_log!(audit, "SQLite Error {:?}", e);
+                OperationError::SQLiteError
+            })
+    }
+
+    fn write_name2uuid_rem(
+        &self,
+        audit: &mut AuditScope,
+        name: &str,
+        uuid: &Uuid,
+    ) -> Result<(), OperationError> {
+        self.conn
+            .execute_named(
+                "DELETE FROM idx_name2uuid WHERE name = :name AND uuid = :uuid",
+                &[
+                    (":name", &name as &dyn ToSql),
+                    (":uuid", &uuid.to_hyphenated_ref().to_string()),
+                ],
+            )
+            .map(|_| ())
+            .map_err(|e| {
+                audit_log!(audit, "SQLite Error {:?}", e);
+                OperationError::SQLiteError
+            })
+    }
+
+    fn write_uuid2name_add(
+        &self,
+        audit: &mut AuditScope,
+        uuid: &Uuid,
+        name: &str,
+    ) -> Result<(), OperationError> {
+        self.conn
+            .execute_named(
+                "INSERT OR REPLACE INTO idx_uuid2name (uuid, name) VALUES(:uuid, :name)",
+                &[
+                    (":uuid", &uuid.to_hyphenated_ref().to_string() as &dyn ToSql),
+                    (":name", &name),
+                ],
+            )
+            .map(|_| ())
+            .map_err(|e| {
+                audit_log!(audit, "SQLite Error {:?}", e);
+                OperationError::SQLiteError
+            })
+    }
+
+    fn write_uuid2name_rem(
+        &self,
+        audit: &mut AuditScope,
+        uuid: &Uuid,
+    ) -> Result<(), OperationError> {
+        self.conn
+            .execute_named(
+                "DELETE FROM idx_uuid2name WHERE uuid = :uuid",
+                &[(":uuid", &uuid.to_hyphenated_ref().to_string())],
+            )
+            .map(|_| ())
+            .map_err(|e| {
+                audit_log!(audit, "SQLite Error {:?}", e);
+                OperationError::SQLiteError
+            })
+    }
+
+    fn write_uuid2spn_add(
+        &self,
+        audit: &mut AuditScope,
+        uuid: &Uuid,
+        spn: &str,
+    ) -> Result<(), OperationError> {
+        self.conn
+            .execute_named(
+                "INSERT OR REPLACE INTO idx_uuid2spn (uuid, spn) VALUES(:uuid, :spn)",
+                &[
+                    (":uuid", &uuid.to_hyphenated_ref().to_string() as &dyn ToSql),
+                    (":spn", &spn),
+                ],
+            )
+            .map(|_| ())
+            .map_err(|e| {
+                audit_log!(audit, "SQLite Error {:?}", e);
+                OperationError::SQLiteError
+            })
+    }
+
+    fn write_uuid2spn_rem(
+        &self,
+        audit: &mut AuditScope,
+        uuid: &Uuid,
+    ) -> Result<(), OperationError> {
+        self.conn
+            .execute_named(
+                "DELETE FROM idx_uuid2spn WHERE uuid = :uuid",
+                &[(":uuid", &uuid.to_hyphenated_ref().to_string())],
+            )
+            .map(|_| ())
+            .map_err(|e| {
+                audit_log!(audit, "SQLite Error {:?}", e);
+                OperationError::SQLiteError
+            })
+    }
+
+    fn rebuild_uuid2spn(&self, audit: &mut AuditScope, domain: &str) -> Result<(), OperationError> {
+        try_audit!(
+            audit,
+            self.conn.execute("DELETE FROM idx_uuid2spn", NO_PARAMS),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        try_audit!(
+            audit,
+            self.conn.execute_named(
+                "INSERT INTO idx_uuid2spn (uuid, spn) SELECT uuid, name || '@' || :domain FROM idx_uuid2name",
+                &[(":domain", &domain)],
+            ),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        Ok(())
     }
 
-    pub fn create_name2uuid(&self, audit: &mut AuditScope) -> Result<(), OperationError> {
+    fn create_name2uuid(&self, audit: &mut AuditScope) -> Result<(), OperationError> {
         try_audit!(
             audit,
             self.conn.execute(
@@ -424,7 +2207,7 @@ impl IdlSqliteWriteTransaction {
         Ok(())
     }
 
-    pub fn create_uuid2name(&self, audit: &mut AuditScope) -> Result<(), OperationError> {
+    fn create_uuid2name(&self, audit: &mut AuditScope) -> Result<(), OperationError> {
         try_audit!(
             audit,
             self.conn.execute(
@@ -437,7 +2220,20 @@ impl IdlSqliteWriteTransaction {
         Ok(())
     }
 
-    pub fn create_idx(
+    fn create_uuid2spn(&self, audit: &mut AuditScope) -> Result<(), OperationError> {
+        try_audit!(
+            audit,
+            self.conn.execute(
+                "CREATE TABLE IF NOT EXISTS idx_uuid2spn (uuid TEXT PRIMARY KEY, spn TEXT)",
+                NO_PARAMS
+            ),
+            "sqlite error {:?}",
+            OperationError::SQLiteError
+        );
+        Ok(())
+    }
+
+    fn create_idx(
         &self,
         audit: &mut AuditScope,
         attr: &String,
@@ -463,38 +2259,26 @@ impl IdlSqliteWriteTransaction {
         Ok(())
     }
 
-    pub fn list_idxs(&self, audit: &mut AuditScope) -> Result<Vec<String>, OperationError> {
-        let mut stmt = try_audit!(
-            audit,
-            self.get_conn()
-                .prepare("SELECT name from sqlite_master where type='table' and name LIKE 'idx_%'"),
-            "SQLite Error {:?}",
-            OperationError::SQLiteError
-        );
-        let idx_table_iter = try_audit!(
-            audit,
-            stmt.query_map(NO_PARAMS, |row| row.get(0)),
-            "SQLite Error {:?}",
-            OperationError::SQLiteError
-        );
-
-        let r: Result<_, _> = idx_table_iter
-            .map(|v| {
-                v.map_err(|e| {
-                    audit_log!(audit, "SQLite Error {:?}", e);
-                    OperationError::SQLiteError
-                })
-            })
-            .collect();
-
-        r
-    }
-
-    pub unsafe fn purge_idxs(&self, audit: &mut AuditScope) -> Result<(), OperationError> {
+    unsafe fn purge_idxs(&self, audit: &mut AuditScope) -> Result<(), OperationError> {
         let idx_table_list = self.list_idxs(audit)?;
 
         idx_table_list.iter().try_for_each(|idx_table| {
             audit_log!(audit, "removing idx_table -> {:?}", idx_table);
+            // These are created by setup rather than from idxmeta, so they
+            // are emptied instead, and are always there to be written.
+            if idx_table == "idx_name2uuid"
+                || idx_table == "idx_uuid2name"
+                || idx_table == "idx_uuid2spn"
+            {
+                return self
+                    .conn
+                    .execute(format!("DELETE FROM {}", idx_table).as_str(), NO_PARAMS)
+                    .map(|_| ())
+                    .map_err(|e| {
+                        audit_log!(audit, "sqlite error {:?}", e);
+                        OperationError::SQLiteError
+                    });
+            }
             self.conn
                 .prepare(format!("DROP TABLE {}", idx_table).as_str())
                 .and_then(|mut stmt| stmt.query(NO_PARAMS).map(|_| ()))
@@ -502,74 +2286,189 @@ impl IdlSqliteWriteTransaction {
                     audit_log!(audit, "sqlite error {:?}", e);
                     OperationError::SQLiteError
                 })
-        })
+        })?;
+        // The allids counts are about searches, not what the index held, so
+        // they are kept.
+        try_audit!(
+            audit,
+            self.conn.execute(
+                "UPDATE index_stats SET keys = 0, idl_total = 0, idl_max = 0",
+                NO_PARAMS
+            ),
+            "sqlite error {:?}",
+            OperationError::SQLiteError
+        );
+        Ok(())
+    }
+
+    unsafe fn purge_idx(
+        &self,
+        audit: &mut AuditScope,
+        attr: &String,
+        itype: &IndexType,
+    ) -> Result<(), OperationError> {
+        let purge_stmt = format!("DELETE FROM idx_{}_{}", itype.as_idx_str(), attr);
+        audit_log!(audit, "Purging index -> {}", purge_stmt);
+        try_audit!(
+            audit,
+            self.conn.execute(purge_stmt.as_str(), NO_PARAMS),
+            "sqlite error {:?}",
+            OperationError::SQLiteError
+        );
+        try_audit!(
+            audit,
+            self.conn.execute_named(
+                "UPDATE index_stats SET keys = 0, idl_total = 0, idl_max = 0 WHERE attr = :attr AND itype = :itype",
+                &[(":attr", attr as &dyn ToSql), (":itype", &itype.as_idx_str())],
+            ),
+            "sqlite error {:?}",
+            OperationError::SQLiteError
+        );
+        Ok(())
+    }
+
+    fn drop_idx(
+        &self,
+        audit: &mut AuditScope,
+        attr: &String,
+        itype: &IndexType,
+    ) -> Result<(), OperationError> {
+        let drop_stmt = format!("DROP TABLE IF EXISTS idx_{}_{}", itype.as_idx_str(), attr);
+        audit_log!(audit, "Dropping index -> {}", drop_stmt);
+        try_audit!(
+            audit,
+            self.conn.execute(drop_stmt.as_str(), NO_PARAMS),
+            "sqlite error {:?}",
+            OperationError::SQLiteError
+        );
+        try_audit!(
+            audit,
+            self.conn.execute_named(
+                "DELETE FROM index_stats WHERE attr = :attr AND itype = :itype",
+                &[
+                    (":attr", attr as &dyn ToSql),
+                    (":itype", &itype.as_idx_str())
+                ],
+            ),
+            "sqlite error {:?}",
+            OperationError::SQLiteError
+        );
+        Ok(())
     }
 
-    pub unsafe fn purge_id2entry(&self, audit: &mut AuditScope) -> Result<(), OperationError> {
+    unsafe fn purge_id2entry(&self, audit: &mut AuditScope) -> Result<(), OperationError> {
         try_audit!(
             audit,
             self.conn.execute("DELETE FROM id2entry", NO_PARAMS),
             "rustqlite error {:?}",
             OperationError::SQLiteError
         );
+        // Nothing that was removed before can be relevant to what replaces it.
+        try_audit!(
+            audit,
+            self.conn.execute("DELETE FROM id2entry_removed", NO_PARAMS),
+            "rustqlite error {:?}",
+            OperationError::SQLiteError
+        );
+        // Nor are the CIDs of what was here. What replaces it has none, so a
+        // partner's copy of any entry is taken over it.
+        try_audit!(
+            audit,
+            self.conn.execute("DELETE FROM changestate", NO_PARAMS),
+            "rustqlite error {:?}",
+            OperationError::SQLiteError
+        );
+        try_audit!(
+            audit,
+            self.conn.execute("DELETE FROM id2blob_ref", NO_PARAMS),
+            "rustqlite error {:?}",
+            OperationError::SQLiteError
+        );
+        try_audit!(
+            audit,
+            self.conn.execute("DELETE FROM id2blob", NO_PARAMS),
+            "rustqlite error {:?}",
+            OperationError::SQLiteError
+        );
         Ok(())
     }
 
-    pub fn write_db_sid(&self, nsid: &SID) -> Result<(), OperationError> {
-        let mut data = Vec::new();
-        data.extend_from_slice(nsid);
+    fn write_db_domain(&self, domain: &DomainInfo) -> Result<(), OperationError> {
+        write_db_domain(&self.conn, domain).map_err(|e| {
+            debug!("rusqlite error {:?}", e);
+            OperationError::SQLiteError
+        })
+    }
 
+    // Returns the pid, instance uuid and last heartbeat of the process that
+    // currently claims this database, if any.
+    fn get_db_owner(&self) -> Result<Option<(i64, String, i64)>, OperationError> {
         self.conn
-            .execute_named(
-                "INSERT OR REPLACE INTO db_sid (id, data) VALUES(:id, :sid)",
-                &[(":id", &1), (":sid", &data)],
+            .query_row_named(
+                "SELECT pid, instance, heartbeat FROM db_owner WHERE id = 1",
+                &[],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
             )
-            .map(|_| ())
+            .optional()
             .map_err(|e| {
                 debug!("rusqlite error {:?}", e);
-
                 OperationError::SQLiteError
             })
     }
 
-    // ===== inner helpers =====
-    // Some of these are not self due to use in new()
-    fn get_db_version_key(&self, key: &str) -> i64 {
-        match self.conn.query_row_named(
-            "SELECT version FROM db_version WHERE id = :id",
-            &[(":id", &key)],
-            |row| row.get(0),
-        ) {
-            Ok(e) => e,
-            Err(_) => {
-                // The value is missing, default to 0.
-                0
-            }
-        }
+    fn write_db_owner(
+        &self,
+        pid: i64,
+        instance: &str,
+        heartbeat: i64,
+    ) -> Result<(), OperationError> {
+        self.conn
+            .execute_named(
+                "INSERT OR REPLACE INTO db_owner (id, pid, instance, heartbeat) VALUES(:id, :pid, :instance, :heartbeat)",
+                &[(":id", &1), (":pid", &pid), (":instance", &instance), (":heartbeat", &heartbeat)],
+            )
+            .map(|_| ())
+            .map_err(|e| {
+                debug!("rusqlite error {:?}", e);
+                OperationError::SQLiteError
+            })
     }
 
-    fn set_db_version_key(&self, key: &str, v: i64) -> Result<(), rusqlite::Error> {
+    fn delete_db_owner(&self, instance: &str) -> Result<(), OperationError> {
         self.conn
             .execute_named(
-                "INSERT OR REPLACE INTO db_version (id, version) VALUES(:id, :dbv_id2entry)",
-                &[(":id", &key), (":dbv_id2entry", &v)],
+                "DELETE FROM db_owner WHERE instance = :instance",
+                &[(":instance", &instance)],
             )
             .map(|_| ())
+            .map_err(|e| {
+                debug!("rusqlite error {:?}", e);
+                OperationError::SQLiteError
+            })
     }
 
-    pub(crate) fn get_db_index_version(&self) -> i64 {
+    fn get_db_index_version(&self) -> i64 {
         self.get_db_version_key(DBV_INDEXV)
     }
 
-    pub(crate) fn set_db_index_version(&self, v: i64) -> Result<(), OperationError> {
+    fn set_db_index_version(&self, v: i64) -> Result<(), OperationError> {
         self.set_db_version_key(DBV_INDEXV, v).map_err(|e| {
             debug!("sqlite error {:?}", e);
             OperationError::SQLiteError
         })
     }
 
-    pub fn setup(&self, audit: &mut AuditScope) -> Result<(), OperationError> {
-        // Enable WAL mode, which is just faster and better.
+    fn set_db_entry_format(&self, f: i64) -> Result<(), OperationError> {
+        self.set_db_version_key(DBV_ENTRY_FORMAT, f).map_err(|e| {
+            debug!("sqlite error {:?}", e);
+            OperationError::SQLiteError
+        })
+    }
+
+    fn setup(&self, audit: &mut AuditScope) -> Result<(), OperationError> {
+        // Enable WAL mode, which is just faster and better. This sticks to the
+        // db file, unlike the DbPragmas tuning, which init_conn applies to
+        // every connection.
         //
         // We have to use stmt + prepare because execute can't handle
         // the "wal" row on result when this works!
@@ -622,9 +2521,163 @@ impl IdlSqliteWriteTransaction {
             try_audit!(
                 audit,
                 self.conn.execute(
-                    "CREATE TABLE IF NOT EXISTS id2entry (
-                        id INTEGER PRIMARY KEY ASC,
-                        data BLOB NOT NULL
+                    "CREATE TABLE IF NOT EXISTS id2entry (
+                        id INTEGER PRIMARY KEY ASC,
+                        data BLOB NOT NULL
+                    )
+                    ",
+                    NO_PARAMS,
+                ),
+                "sqlite error {:?}",
+                OperationError::SQLiteError
+            );
+            try_audit!(
+                audit,
+                self.conn.execute(
+                    "CREATE TABLE IF NOT EXISTS db_sid (
+                        id INTEGER PRIMARY KEY ASC,
+                        data BLOB NOT NULL
+                    )
+                    ",
+                    NO_PARAMS,
+                ),
+                "sqlite error {:?}",
+                OperationError::SQLiteError
+            );
+            dbv_id2entry = 1;
+            audit_log!(audit, "dbv_id2entry migrated -> {}", dbv_id2entry);
+        }
+        //   * if v1 -> add the owner table.
+        if dbv_id2entry == 1 {
+            try_audit!(
+                audit,
+                self.conn.execute(
+                    "CREATE TABLE IF NOT EXISTS db_owner (
+                        id INTEGER PRIMARY KEY ASC,
+                        pid INTEGER NOT NULL,
+                        instance TEXT NOT NULL,
+                        heartbeat INTEGER NOT NULL
+                    )
+                    ",
+                    NO_PARAMS,
+                ),
+                "sqlite error {:?}",
+                OperationError::SQLiteError
+            );
+            dbv_id2entry = 2;
+            audit_log!(audit, "dbv_id2entry migrated -> {}", dbv_id2entry);
+        }
+        //   * if v2 -> add change sequence numbers, so we can tell what has
+        //     changed since a backup.
+        if dbv_id2entry == 2 {
+            try_audit!(
+                audit,
+                self.conn.execute(
+                    "ALTER TABLE id2entry ADD COLUMN csn INTEGER NOT NULL DEFAULT 0",
+                    NO_PARAMS,
+                ),
+                "sqlite error {:?}",
+                OperationError::SQLiteError
+            );
+            try_audit!(
+                audit,
+                self.conn.execute(
+                    "CREATE INDEX IF NOT EXISTS id2entry_csn ON id2entry (csn)",
+                    NO_PARAMS,
+                ),
+                "sqlite error {:?}",
+                OperationError::SQLiteError
+            );
+            try_audit!(
+                audit,
+                self.conn.execute(
+                    "CREATE TABLE IF NOT EXISTS id2entry_removed (
+                        uuid TEXT PRIMARY KEY,
+                        csn INTEGER NOT NULL
+                    )
+                    ",
+                    NO_PARAMS,
+                ),
+                "sqlite error {:?}",
+                OperationError::SQLiteError
+            );
+            dbv_id2entry = 3;
+            audit_log!(audit, "dbv_id2entry migrated -> {}", dbv_id2entry);
+        }
+        //   * if v3 -> add the index usage counts, so the hottest index slots
+        //     can be warmed at startup.
+        if dbv_id2entry == 3 {
+            try_audit!(
+                audit,
+                self.conn.execute(
+                    "CREATE TABLE IF NOT EXISTS index_usage (
+                        attr TEXT NOT NULL,
+                        itype TEXT NOT NULL,
+                        key TEXT NOT NULL,
+                        hits INTEGER NOT NULL,
+                        PRIMARY KEY (attr, itype, key)
+                    )
+                    ",
+                    NO_PARAMS,
+                ),
+                "sqlite error {:?}",
+                OperationError::SQLiteError
+            );
+            dbv_id2entry = 4;
+            audit_log!(audit, "dbv_id2entry migrated -> {}", dbv_id2entry);
+        }
+        //   * if v4 -> add the index statistics, refreshed by analyze.
+        if dbv_id2entry == 4 {
+            try_audit!(
+                audit,
+                self.conn.execute(
+                    "CREATE TABLE IF NOT EXISTS index_stats (
+                        attr TEXT NOT NULL,
+                        itype TEXT NOT NULL,
+                        keys INTEGER NOT NULL,
+                        PRIMARY KEY (attr, itype)
+                    )
+                    ",
+                    NO_PARAMS,
+                ),
+                "sqlite error {:?}",
+                OperationError::SQLiteError
+            );
+            dbv_id2entry = 5;
+            audit_log!(audit, "dbv_id2entry migrated -> {}", dbv_id2entry);
+        }
+        //   * if v5 -> track the idl lengths and allids fallbacks as well.
+        if dbv_id2entry == 5 {
+            for column in ["idl_total", "idl_max", "allids"].iter() {
+                try_audit!(
+                    audit,
+                    self.conn.execute(
+                        format!(
+                            "ALTER TABLE index_stats ADD COLUMN {} INTEGER NOT NULL DEFAULT 0",
+                            column
+                        )
+                        .as_str(),
+                        NO_PARAMS,
+                    ),
+                    "sqlite error {:?}",
+                    OperationError::SQLiteError
+                );
+            }
+            dbv_id2entry = 6;
+            audit_log!(audit, "dbv_id2entry migrated -> {}", dbv_id2entry);
+        }
+        //   * if v6 -> add the access journal.
+        if dbv_id2entry == 6 {
+            try_audit!(
+                audit,
+                self.conn.execute(
+                    "CREATE TABLE IF NOT EXISTS access_journal (
+                        id INTEGER PRIMARY KEY AUTOINCREMENT,
+                        time TEXT NOT NULL,
+                        uuid TEXT NOT NULL,
+                        identity TEXT NOT NULL,
+                        operation TEXT NOT NULL,
+                        hash TEXT NOT NULL
                     )
                     ",
                     NO_PARAMS,
@@ -632,11 +2685,93 @@ impl IdlSqliteWriteTransaction {
                 "sqlite error {:?}",
                 OperationError::SQLiteError
             );
+            dbv_id2entry = 7;
+            audit_log!(audit, "dbv_id2entry migrated -> {}", dbv_id2entry);
+        }
+        //   * if v7 -> add the slow query log.
+        if dbv_id2entry == 7 {
             try_audit!(
                 audit,
                 self.conn.execute(
-                    "CREATE TABLE IF NOT EXISTS db_sid (
-                        id INTEGER PRIMARY KEY ASC,
+                    "CREATE TABLE IF NOT EXISTS slow_queries (
+                        id INTEGER PRIMARY KEY AUTOINCREMENT,
+                        time TEXT NOT NULL,
+                        filter TEXT NOT NULL,
+                        duration_ms INTEGER NOT NULL,
+                        candidates INTEGER NOT NULL,
+                        results INTEGER NOT NULL,
+                        allids INTEGER NOT NULL
+                    )
+                    ",
+                    NO_PARAMS,
+                ),
+                "sqlite error {:?}",
+                OperationError::SQLiteError
+            );
+            dbv_id2entry = 8;
+            audit_log!(audit, "dbv_id2entry migrated -> {}", dbv_id2entry);
+        }
+        //   * if v8 -> add the changelog.
+        if dbv_id2entry == 8 {
+            try_audit!(
+                audit,
+                self.conn.execute(
+                    "CREATE TABLE IF NOT EXISTS changelog (
+                        id INTEGER PRIMARY KEY AUTOINCREMENT,
+                        time TEXT NOT NULL,
+                        uuid TEXT NOT NULL,
+                        identity TEXT NOT NULL,
+                        operation TEXT NOT NULL,
+                        hash TEXT NOT NULL,
+                        signature TEXT
+                    )
+                    ",
+                    NO_PARAMS,
+                ),
+                "sqlite error {:?}",
+                OperationError::SQLiteError
+            );
+            dbv_id2entry = 9;
+            audit_log!(audit, "dbv_id2entry migrated -> {}", dbv_id2entry);
+        }
+        //   * if v9 -> add the report history.
+        if dbv_id2entry == 9 {
+            try_audit!(
+                audit,
+                self.conn.execute(
+                    "CREATE TABLE IF NOT EXISTS report_history (
+                        id INTEGER PRIMARY KEY AUTOINCREMENT,
+                        report TEXT NOT NULL,
+                        time TEXT NOT NULL,
+                        epoch INTEGER NOT NULL,
+                        count INTEGER NOT NULL,
+                        allids INTEGER NOT NULL
+                    )
+                    ",
+                    NO_PARAMS,
+                ),
+                "sqlite error {:?}",
+                OperationError::SQLiteError
+            );
+            try_audit!(
+                audit,
+                self.conn.execute(
+                    "CREATE INDEX IF NOT EXISTS report_history_report ON report_history (report, id)",
+                    NO_PARAMS,
+                ),
+                "sqlite error {:?}",
+                OperationError::SQLiteError
+            );
+            dbv_id2entry = 10;
+            audit_log!(audit, "dbv_id2entry migrated -> {}", dbv_id2entry);
+        }
+        //   * if v10 -> add the blob store for large values.
+        if dbv_id2entry == 10 {
+            try_audit!(
+                audit,
+                self.conn.execute(
+                    "CREATE TABLE IF NOT EXISTS id2blob (
+                        hash TEXT PRIMARY KEY,
                         data BLOB NOT NULL
                     )
                     ",
@@ -645,10 +2780,76 @@ impl IdlSqliteWriteTransaction {
                 "sqlite error {:?}",
                 OperationError::SQLiteError
             );
-            dbv_id2entry = 1;
+            try_audit!(
+                audit,
+                self.conn.execute(
+                    "CREATE TABLE IF NOT EXISTS id2blob_ref (
+                        id INTEGER NOT NULL,
+                        hash TEXT NOT NULL,
+                        PRIMARY KEY (id, hash)
+                    )
+                    ",
+                    NO_PARAMS,
+                ),
+                "sqlite error {:?}",
+                OperationError::SQLiteError
+            );
+            try_audit!(
+                audit,
+                self.conn.execute(
+                    "CREATE INDEX IF NOT EXISTS id2blob_ref_hash ON id2blob_ref (hash)",
+                    NO_PARAMS,
+                ),
+                "sqlite error {:?}",
+                OperationError::SQLiteError
+            );
+            dbv_id2entry = 11;
+            audit_log!(audit, "dbv_id2entry migrated -> {}", dbv_id2entry);
+        }
+        //   * if v11 -> entry ids become IdKey blobs, in id2entry and
+        //     id2blob_ref.
+        if dbv_id2entry == 11 {
+            try_audit!(
+                audit,
+                self.migrate_id_keys(),
+                "sqlite error {:?}",
+                OperationError::SQLiteError
+            );
+            dbv_id2entry = 12;
+            audit_log!(audit, "dbv_id2entry migrated -> {}", dbv_id2entry);
+        }
+        //   * if v12 -> replace the server id with the domain record.
+        if dbv_id2entry == 12 {
+            try_audit!(
+                audit,
+                self.migrate_db_domain(),
+                "sqlite error {:?}",
+                OperationError::SQLiteError
+            );
+            dbv_id2entry = 13;
+            audit_log!(audit, "dbv_id2entry migrated -> {}", dbv_id2entry);
+        }
+        //   * if v13 -> add the per attribute change state for replication.
+        //     What's already here has none, IE was changed before any CID.
+        if dbv_id2entry == 13 {
+            try_audit!(
+                audit,
+                self.conn.execute_batch(
+                    "CREATE TABLE IF NOT EXISTS changestate (
+                        uuid TEXT NOT NULL,
+                        attr TEXT NOT NULL,
+                        cid TEXT NOT NULL,
+                        PRIMARY KEY (uuid, attr)
+                    );
+                    CREATE INDEX IF NOT EXISTS changestate_cid ON changestate (cid);",
+                ),
+                "sqlite error {:?}",
+                OperationError::SQLiteError
+            );
+            dbv_id2entry = 14;
             audit_log!(audit, "dbv_id2entry migrated -> {}", dbv_id2entry);
         }
-        //   * if v1 -> complete.
+        //   * if v14 -> complete.
 
         try_audit!(
             audit,
@@ -657,6 +2858,13 @@ impl IdlSqliteWriteTransaction {
             OperationError::SQLiteError
         );
 
+        // name2uuid, uuid2name and uuid2spn are kept up to date by every
+        // write, so they must exist before the first one, whatever the index
+        // version.
+        self.create_name2uuid(audit)?;
+        self.create_uuid2name(audit)?;
+        self.create_uuid2spn(audit)?;
+
         // NOTE: Indexing is configured in a different step!
         // Indexing uses a db version flag to represent the version
         // of the indexes representation on disk in case we change
@@ -665,9 +2873,220 @@ impl IdlSqliteWriteTransaction {
     }
 }
 
-impl IdlSqlite {
-    pub fn new(audit: &mut AuditScope, path: &str, pool_size: u32) -> Result<Self, OperationError> {
-        let manager = SqliteConnectionManager::file(path);
+impl IdlSqliteWriteTransaction {
+    // ===== inner helpers =====
+    // Some of these are not self due to use in new()
+    // The number of ids the key has before it's written. An unreadable idl is
+    // about to be replaced, so counts as empty.
+    // query is the get statement of the idx, from idx_stmts.
+    fn get_idl_len(
+        &self,
+        audit: &mut AuditScope,
+        query: &str,
+        idx_key: &String,
+    ) -> Result<i64, OperationError> {
+        let idl_raw: Option<Vec<u8>> = try_audit!(
+            audit,
+            self.conn.prepare_cached(query).and_then(|mut stmt| {
+                stmt.query_row_named(&[(":key", idx_key)], |row| row.get(0))
+                    .optional()
+            }),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        Ok(match idl_raw {
+            Some(d) => match serde_cbor::from_slice::<IDLBitRange>(d.as_slice()) {
+                Ok(idl) => idl.len() as i64,
+                Err(e) => {
+                    audit_log!(audit, "Replacing unreadable idl -> {:?}", e);
+                    0
+                }
+            },
+            None => 0,
+        })
+    }
+
+    // Make sure the index has a row in index_stats to update.
+    fn touch_idx_stats(
+        &self,
+        audit: &mut AuditScope,
+        attr: &String,
+        itype: &IndexType,
+    ) -> Result<(), OperationError> {
+        try_audit!(
+            audit,
+            self.conn
+                .prepare_cached(
+                    "INSERT OR IGNORE INTO index_stats (attr, itype, keys) VALUES(:attr, :itype, 0)"
+                )
+                .and_then(|mut stmt| stmt.execute_named(&[
+                    (":attr", attr as &dyn ToSql),
+                    (":itype", &itype.as_idx_str())
+                ])),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        Ok(())
+    }
+
+    // Keep index_stats in step with a key going from old_len ids to new_len.
+    // The max is only ever raised here, analyze doesn't lower it either.
+    fn update_idx_stats(
+        &self,
+        audit: &mut AuditScope,
+        attr: &String,
+        itype: &IndexType,
+        old_len: i64,
+        new_len: i64,
+    ) -> Result<(), OperationError> {
+        let keys: i64 = match (old_len, new_len) {
+            (0, n) if n > 0 => 1,
+            (o, 0) if o > 0 => -1,
+            _ => 0,
+        };
+        self.touch_idx_stats(audit, attr, itype)?;
+        try_audit!(
+            audit,
+            self.conn
+                .prepare_cached(
+                    "UPDATE index_stats SET keys = keys + :keys, idl_total = idl_total + :total, idl_max = MAX(idl_max, :len) WHERE attr = :attr AND itype = :itype"
+                )
+                .and_then(|mut stmt| stmt.execute_named(&[
+                    (":keys", &keys as &dyn ToSql),
+                    (":total", &(new_len - old_len)),
+                    (":len", &new_len),
+                    (":attr", attr),
+                    (":itype", &itype.as_idx_str()),
+                ])),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        Ok(())
+    }
+
+    // The csn for this txn, allocated from the counter on first use.
+    fn get_txn_csn(&self, au: &mut AuditScope) -> Result<i64, OperationError> {
+        if self.csn.get() == 0 {
+            let csn = self.get_db_version_key(DBV_CSN) + 1;
+            try_audit!(
+                au,
+                self.set_db_version_key(DBV_CSN, csn),
+                "sqlite error {:?}",
+                OperationError::SQLiteError
+            );
+            self.csn.set(csn);
+        }
+        Ok(self.csn.get())
+    }
+
+    // Rebuild id2entry and id2blob_ref with IdKey ids. sqlite can't turn an
+    // integer into a blob itself, so the rows are copied through here.
+    fn migrate_id_keys(&self) -> Result<(), rusqlite::Error> {
+        self.conn.execute_batch(
+            "CREATE TABLE id2entry_new (
+                id BLOB PRIMARY KEY,
+                data BLOB NOT NULL,
+                csn INTEGER NOT NULL DEFAULT 0
+            );
+            CREATE TABLE id2blob_ref_new (
+                id BLOB NOT NULL,
+                hash TEXT NOT NULL,
+                PRIMARY KEY (id, hash)
+            );",
+        )?;
+        {
+            let mut insert = self.conn.prepare(
+                "INSERT INTO id2entry_new (id, data, csn) SELECT :id, data, csn FROM id2entry WHERE id = :old",
+            )?;
+            let mut insert_ref = self.conn.prepare(
+                "INSERT INTO id2blob_ref_new (id, hash) SELECT :id, hash FROM id2blob_ref WHERE id = :old",
+            )?;
+            let mut stmt = self.conn.prepare("SELECT id FROM id2entry")?;
+            let ids: Result<Vec<i64>, _> = stmt.query_map(NO_PARAMS, |row| row.get(0))?.collect();
+            for old in ids? {
+                // Ids were only ever allocated from 1, so can't be negative.
+                let id = IdKey(old as u64);
+                let params = [(":id", &id as &dyn ToSql), (":old", &old as &dyn ToSql)];
+                insert.execute_named(&params)?;
+                insert_ref.execute_named(&params)?;
+            }
+        }
+        self.conn.execute_batch(
+            "DROP TABLE id2entry;
+            ALTER TABLE id2entry_new RENAME TO id2entry;
+            CREATE INDEX IF NOT EXISTS id2entry_csn ON id2entry (csn);
+            DROP TABLE id2blob_ref;
+            ALTER TABLE id2blob_ref_new RENAME TO id2blob_ref;
+            CREATE INDEX IF NOT EXISTS id2blob_ref_hash ON id2blob_ref (hash);",
+        )
+    }
+
+    // A server id we already had is kept as the start of the domain uuid. The
+    // name isn't known here, and is filled in when the backend sets it.
+    fn migrate_db_domain(&self) -> Result<(), rusqlite::Error> {
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS db_domain (
+                id INTEGER PRIMARY KEY ASC,
+                uuid TEXT NOT NULL,
+                name TEXT NOT NULL,
+                generation INTEGER NOT NULL,
+                created INTEGER NOT NULL
+            )
+            ",
+            NO_PARAMS,
+        )?;
+        let sid: Option<Vec<u8>> = self
+            .conn
+            .query_row("SELECT data FROM db_sid WHERE id = 1", NO_PARAMS, |row| {
+                row.get(0)
+            })
+            .optional()?;
+        if let Some(sid) = sid.filter(|s| s.len() == 4) {
+            let mut nsid = [0; 4];
+            nsid.copy_from_slice(sid.as_slice());
+            write_db_domain(&self.conn, &DomainInfo::from_sid(&nsid, ""))?;
+        }
+        self.conn
+            .execute("DROP TABLE db_sid", NO_PARAMS)
+            .map(|_| ())
+    }
+
+    fn set_db_version_key(&self, key: &str, v: i64) -> Result<(), rusqlite::Error> {
+        self.conn
+            .execute_named(
+                "INSERT OR REPLACE INTO db_version (id, version) VALUES(:id, :dbv_id2entry)",
+                &[(":id", &key), (":dbv_id2entry", &v)],
+            )
+            .map(|_| ())
+    }
+}
+
+impl IdLayer for IdlSqlite {
+    type ReadTransaction = IdlSqliteReadTransaction;
+    type WriteTransaction = IdlSqliteWriteTransaction;
+
+    fn new(
+        audit: &mut AuditScope,
+        path: &str,
+        pool_size: u32,
+        db_key: Option<[u8; 32]>,
+        pragmas: DbPragmas,
+    ) -> Result<Self, OperationError> {
+        if let Some(key) = db_key.as_ref() {
+            check_db_key(audit, path, key)?;
+        }
+        if pragmas != DbPragmas::default() {
+            audit_log!(audit, "database tuning {:?}", pragmas);
+        }
+        let fsize_limit = file_size_limit();
+        if let Some(limit) = fsize_limit {
+            audit_log!(audit, "database size limited to {} bytes", limit);
+        }
+        if cfg!(target_pointer_width = "32") {
+            audit_log!(audit, "32 bit build, database will not be memory mapped");
+        }
+        let manager = SqliteConnectionManager::file(path)
+            .with_init(move |conn| init_conn(conn, fsize_limit, db_key, &pragmas));
         let builder1 = Pool::builder();
         let builder2 = if path == "" {
             // We are in a debug mode, with in memory. We MUST have only
@@ -682,25 +3101,478 @@ impl IdlSqlite {
             OperationError::SQLiteError
         })?;
 
-        Ok(IdlSqlite { pool: pool })
+        Ok(IdlSqlite {
+            pool: pool,
+            metrics: PoolMetrics::new(),
+        })
+    }
+
+    fn read(&self) -> Result<IdlSqliteReadTransaction, OperationError> {
+        self.get_pooled_conn()
+            .and_then(|conn| IdlSqliteReadTransaction::new(conn, self.metrics.clone()))
+    }
+
+    fn write(&self) -> Result<IdlSqliteWriteTransaction, OperationError> {
+        self.get_pooled_conn()
+            .and_then(|conn| IdlSqliteWriteTransaction::new(conn, self.metrics.clone()))
+    }
+
+    // For use in drop, where we would rather skip the work than block.
+    fn try_write(&self) -> Option<IdlSqliteWriteTransaction> {
+        self.pool.try_get().and_then(|conn| {
+            self.metrics.acquired(Duration::from_secs(0));
+            IdlSqliteWriteTransaction::new(conn, self.metrics.clone()).ok()
+        })
     }
 
-    pub fn read(&self) -> IdlSqliteReadTransaction {
-        let conn = self
-            .pool
-            .get()
-            .expect("Unable to get connection from pool!!!");
-        IdlSqliteReadTransaction::new(conn)
+    fn get_pool_stats(&self) -> DbPoolStats {
+        let state = self.pool.state();
+        self.metrics.to_proto(
+            self.pool.max_size(),
+            state.connections,
+            state.idle_connections,
+        )
     }
+}
 
-    pub fn write(&self) -> IdlSqliteWriteTransaction {
-        let conn = self
-            .pool
-            .get()
-            .expect("Unable to get connection from pool!!!");
-        IdlSqliteWriteTransaction::new(conn)
+impl IdlSqlite {
+    fn get_pooled_conn(
+        &self,
+    ) -> Result<r2d2::PooledConnection<SqliteConnectionManager>, OperationError> {
+        let mut attempt = 0;
+        loop {
+            // This already waits for a connection to be returned, so if every
+            // connection is handed out there is no point waiting again - we
+            // are just busy. With spare capacity, it's opening a new
+            // connection that is failing, which may pass.
+            let start = Instant::now();
+            match self.pool.get() {
+                Ok(conn) => {
+                    self.metrics.acquired(start.elapsed());
+                    return Ok(conn);
+                }
+                Err(e) => {
+                    let state = self.pool.state();
+                    if state.idle_connections == 0 && state.connections >= self.pool.max_size() {
+                        error!("Connection pool exhausted -> {:?}", e);
+                        self.metrics.exhausted();
+                        return Err(OperationError::Busy);
+                    } else if attempt < TXN_BEGIN_RETRIES {
+                        debug!("Unable to open connection, retrying -> {:?}", e);
+                        backoff(attempt);
+                        attempt += 1;
+                    } else {
+                        error!("Unable to open connection -> {:?}", e);
+                        return Err(OperationError::Unavailable);
+                    }
+                }
+            }
+        }
     }
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use crate::audit::AuditScope;
+    use crate::be::idl_sqlite::{init_conn, IdlSqlite};
+    use crate::be::idlayer::{IdLayer, IdLayerTransaction, IdLayerWriteTransaction};
+    use crate::be::{IdEntry, IDL};
+    use crate::config::DbPragmas;
+    use idlset::IDLBitRange;
+    use kanidm_proto::v1::OperationError;
+    use rusqlite::ErrorCode;
+    use rusqlite::NO_PARAMS;
+    use std::iter::FromIterator;
+
+    #[test]
+    fn test_idl_sqlite_get_identry() {
+        let mut audit = AuditScope::new("run_test");
+        let idlayer = IdlSqlite::new(&mut audit, "", 1, None, DbPragmas::default())
+            .expect("Failed to setup idlayer");
+        let idl_write = idlayer.write().expect("Failed to begin txn");
+        assert!(idl_write.setup(&mut audit).is_ok());
+
+        let entries = (1..=5)
+            .map(|id| IdEntry {
+                id: id,
+                data: vec![id as u8],
+            })
+            .collect();
+        assert!(idl_write.write_identries(&mut audit, entries).is_ok());
+        assert_eq!(idl_write.get_id2entry_count(), Ok(5));
+
+        // Missing ids are skipped, not an error.
+        let idl = IDL::Partial(IDLBitRange::from_iter(vec![1, 3, 9]));
+        let mut r: Vec<u64> = idl_write
+            .get_identry(&mut audit, &idl)
+            .expect("get_identry failed")
+            .into_iter()
+            .map(|ide| ide.id)
+            .collect();
+        r.sort();
+        assert_eq!(r, vec![1, 3]);
+
+        let idl = IDL::Indexed(IDLBitRange::new());
+        assert!(idl_write
+            .get_identry(&mut audit, &idl)
+            .expect("get_identry failed")
+            .is_empty());
+
+        assert_eq!(
+            idl_write
+                .get_identry(&mut audit, &IDL::ALLIDS)
+                .expect("get_identry failed")
+                .len(),
+            5
+        );
+    }
+
+    #[test]
+    fn test_idl_sqlite_vacuum() {
+        let mut audit = AuditScope::new("run_test");
+        let idlayer = IdlSqlite::new(&mut audit, "", 1, None, DbPragmas::default())
+            .expect("Failed to setup idlayer");
+        let idl_write = idlayer.write().expect("Failed to begin txn");
+        assert!(idl_write.setup(&mut audit).is_ok());
+        let entries = (1..256)
+            .map(|id| IdEntry {
+                id: id,
+                data: vec![0; 4096],
+            })
+            .collect();
+        assert!(idl_write.write_identries(&mut audit, entries).is_ok());
+        assert!(idl_write.commit(&mut audit).is_ok());
+
+        let idl_write = idlayer.write().expect("Failed to begin txn");
+        assert!(idl_write
+            .delete_identry(&mut audit, (1..256).collect())
+            .is_ok());
+        let (full, _) = idl_write.get_db_size(&mut audit).expect("No db size");
+        // Deleting leaves the pages to the db.
+        assert!(idl_write.commit(&mut audit).is_ok());
+
+        let idl_write = idlayer.write().expect("Failed to begin txn");
+        let size = idl_write.vacuum(&mut audit).expect("Vacuum failed");
+        assert!(size < full);
+        // And we are left able to write again.
+        assert!(idlayer.write().is_ok());
+    }
+
+    #[test]
+    fn test_idl_sqlite_get_identry() {
+        let mut audit = AuditScope::new("run_test");
+        let idlayer = IdlSqlite::new(&mut audit, "", 1, None, DbPragmas::default())
+            .expect("Failed to setup idlayer");
+        let idl_write = idlayer.write().expect("Failed to begin txn");
+        assert!(idl_write.setup(&mut audit).is_ok());
+        let entries = (1..=64)
+            .map(|id| IdEntry {
+                id: id,
+                data: vec![id as u8],
+            })
+            .collect();
+        assert!(idl_write.write_identries(&mut audit, entries).is_ok());
+
+        // Few enough to read one at a time, and enough for an IN query. Both
+        // skip the ids that don't exist, and return the rest in order.
+        for ids in vec![vec![2, 3, 100], (1..=100).collect::<Vec<u64>>()] {
+            let idl = IDL::Indexed(IDLBitRange::from_iter(ids.clone()));
+            let found: Vec<u64> = idl_write
+                .get_identry(&mut audit, &idl)
+                .expect("Failed to read")
+                .into_iter()
+                .map(|ide| ide.id)
+                .collect();
+            let expect: Vec<u64> = ids.into_iter().filter(|id| *id <= 64).collect();
+            assert_eq!(found, expect);
+        }
+    }
+
+    #[test]
+    fn test_idl_sqlite_pool_stats() {
+        let mut audit = AuditScope::new("run_test");
+        let idlayer = IdlSqlite::new(&mut audit, "", 1, None, DbPragmas::default())
+            .expect("Failed to setup idlayer");
+        let before = idlayer.get_pool_stats();
+        assert_eq!(before.max_size, 1);
+
+        let idl_write = idlayer.write().expect("Failed to begin txn");
+        assert!(idl_write.setup(&mut audit).is_ok());
+        assert!(idl_write.commit(&mut audit).is_ok());
+        std::mem::drop(idlayer.write().expect("Failed to begin txn"));
+        std::mem::drop(idlayer.read().expect("Failed to begin txn"));
+
+        let after = idlayer.get_pool_stats();
+        assert_eq!(after.commits, before.commits + 1);
+        assert_eq!(after.rollbacks, before.rollbacks + 1);
+        assert_eq!(after.write_txns.count, before.write_txns.count + 2);
+        assert_eq!(after.read_txns.count, before.read_txns.count + 1);
+        assert_eq!(after.waits.count, before.waits.count + 3);
+        // Our one connection is back in the pool.
+        assert_eq!(after.idle_connections, 1);
+    }
+
+    #[test]
+    fn test_idl_sqlite_integrity_check() {
+        let mut audit = AuditScope::new("run_test");
+        let idlayer = IdlSqlite::new(&mut audit, "", 1, None, DbPragmas::default())
+            .expect("Failed to setup idlayer");
+        let idl_write = idlayer.write().expect("Failed to begin txn");
+        assert!(idl_write.setup(&mut audit).is_ok());
+        assert!(idl_write
+            .write_identries(
+                &mut audit,
+                vec![IdEntry {
+                    id: 1,
+                    data: vec![0; 16],
+                }]
+            )
+            .is_ok());
+        assert!(idl_write.commit(&mut audit).is_ok());
+
+        let idl_read = idlayer.read().expect("Failed to begin txn");
+        assert_eq!(idl_read.integrity_check(&mut audit, true), Ok(Vec::new()));
+        assert_eq!(idl_read.integrity_check(&mut audit, false), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn test_idl_sqlite_get_identry_batch() {
+        let mut audit = AuditScope::new("run_test");
+        let idlayer = IdlSqlite::new(&mut audit, "", 1, None, DbPragmas::default())
+            .expect("Failed to setup idlayer");
+        let idl_write = idlayer.write().expect("Failed to begin txn");
+        assert!(idl_write.setup(&mut audit).is_ok());
+
+        let entries = vec![1, 2, 4, 7, 8]
+            .into_iter()
+            .map(|id| IdEntry {
+                id: id,
+                data: vec![id as u8],
+            })
+            .collect();
+        assert!(idl_write.write_identries(&mut audit, entries).is_ok());
+
+        let batch_ids = |after, limit| -> Vec<u64> {
+            idl_write
+                .get_identry_batch(&mut AuditScope::new("batch"), after, limit)
+                .expect("get_identry_batch failed")
+                .into_iter()
+                .map(|ide| ide.id)
+                .collect()
+        };
+        assert_eq!(batch_ids(0, 2), vec![1, 2]);
+        assert_eq!(batch_ids(2, 2), vec![4, 7]);
+        assert_eq!(batch_ids(7, 2), vec![8]);
+        assert!(batch_ids(8, 2).is_empty());
+    }
+
+    #[test]
+    fn test_idl_sqlite_savepoints() {
+        let mut audit = AuditScope::new("run_test");
+        let idlayer = IdlSqlite::new(&mut audit, "", 1, None, DbPragmas::default())
+            .expect("Failed to setup idlayer");
+        let idl_write = idlayer.write().expect("Failed to begin txn");
+        assert!(idl_write.setup(&mut audit).is_ok());
+
+        let write = |id: u64| {
+            let e = IdEntry {
+                id: id,
+                data: vec![id as u8],
+            };
+            idl_write
+                .write_identries(&mut AuditScope::new("write"), vec![e])
+                .expect("write_identries failed")
+        };
+        let ids = || -> Vec<u64> {
+            idl_write
+                .get_identry_batch(&mut AuditScope::new("batch"), 0, 10)
+                .expect("get_identry_batch failed")
+                .into_iter()
+                .map(|ide| ide.id)
+                .collect()
+        };
+
+        assert_eq!(idl_write.savepoint(&mut audit), Ok(0));
+        write(1);
+        let csn = idl_write.csn.get();
+        assert!(csn != 0);
+
+        // Only what was written inside the inner savepoint is undone.
+        assert_eq!(idl_write.savepoint(&mut audit), Ok(1));
+        write(2);
+        assert!(idl_write.rollback_to_savepoint(&mut audit, 1).is_ok());
+        assert_eq!(ids(), vec![1]);
+        assert_eq!(idl_write.csn.get(), csn);
+
+        assert_eq!(idl_write.savepoint(&mut audit), Ok(1));
+        write(3);
+        assert!(idl_write.release_savepoint(&mut audit, 1).is_ok());
+        assert_eq!(ids(), vec![1, 3]);
+        assert_eq!(
+            idl_write.release_savepoint(&mut audit, 1),
+            Err(OperationError::InvalidState)
+        );
+
+        // The outer one takes the released inner one with it, and the csn
+        // that was allocated inside it.
+        assert!(idl_write.rollback_to_savepoint(&mut audit, 0).is_ok());
+        assert!(ids().is_empty());
+        assert_eq!(idl_write.csn.get(), 0);
+        assert!(idl_write.savepoints.borrow().is_empty());
+        assert!(idl_write.commit(&mut audit).is_ok());
+    }
+
+    #[test]
+    fn test_idl_sqlite_id_keys() {
+        let mut audit = AuditScope::new("run_test");
+        let idlayer = IdlSqlite::new(&mut audit, "", 1, None, DbPragmas::default())
+            .expect("Failed to setup idlayer");
+        let idl_write = idlayer.write().expect("Failed to begin txn");
+        assert!(idl_write.setup(&mut audit).is_ok());
+
+        // Beyond what sqlite can hold as an integer, and ordered by value
+        // rather than by bytes.
+        let ids = vec![u64::max_value(), 1 << 40, 256, 1];
+        let entries = ids
+            .iter()
+            .map(|id| IdEntry {
+                id: *id,
+                data: vec![*id as u8],
+            })
+            .collect();
+        assert!(idl_write.write_identries(&mut audit, entries).is_ok());
+        assert_eq!(idl_write.get_id2entry_max_id(), Ok(u64::max_value()));
+
+        let r: Vec<u64> = idl_write
+            .get_identry_batch(&mut audit, 1, 10)
+            .expect("get_identry_batch failed")
+            .into_iter()
+            .map(|ide| ide.id)
+            .collect();
+        assert_eq!(r, vec![256, 1 << 40, u64::max_value()]);
+
+        let idl = IDL::Indexed(IDLBitRange::from_iter(vec![u64::max_value()]));
+        let r = idl_write
+            .get_identry(&mut audit, &idl)
+            .expect("get_identry failed");
+        assert_eq!(r.len(), 1);
+        assert_eq!(r[0].id, u64::max_value());
+
+        assert!(idl_write
+            .delete_identry(&mut audit, vec![u64::max_value()])
+            .is_ok());
+        assert_eq!(idl_write.get_id2entry_max_id(), Ok(1 << 40));
+    }
+
+    #[test]
+    fn test_idl_sqlite_begin_stale_txn() {
+        let mut audit = AuditScope::new("run_test");
+        let idlayer = IdlSqlite::new(&mut audit, "", 1, None, DbPragmas::default())
+            .expect("Failed to setup idlayer");
+
+        // As if a rollback failed in drop, the connection goes back to the
+        // pool still inside a txn.
+        {
+            let conn = idlayer.pool.get().expect("Failed to get connection");
+            conn.execute("BEGIN TRANSACTION", NO_PARAMS)
+                .expect("Failed to begin");
+        }
+
+        let idl_write = idlayer.write().expect("Failed to begin txn");
+        assert!(idl_write.setup(&mut audit).is_ok());
+        // The only connection is in use, so this gives up rather than block.
+        assert!(idlayer.try_write().is_none());
+        assert!(idl_write.commit(&mut audit).is_ok());
+        assert!(idlayer.read().is_ok());
+    }
+
+    #[test]
+    fn test_idl_sqlite_size_limit() {
+        let mut conn = rusqlite::Connection::open_in_memory().expect("Failed to open");
+        assert!(init_conn(&mut conn, Some(1 << 20), None, &DbPragmas::default()).is_ok());
+        let page_size: i64 = conn
+            .query_row("PRAGMA page_size", NO_PARAMS, |row| row.get(0))
+            .unwrap();
+        let max_pages: i64 = conn
+            .query_row("PRAGMA max_page_count", NO_PARAMS, |row| row.get(0))
+            .unwrap();
+        assert_eq!(max_pages, (1 << 20) / page_size);
+
+        // Growing past it is an error we can report, not a signal.
+        conn.execute_batch("CREATE TABLE t (data BLOB);").unwrap();
+        let full = (0..32)
+            .map(|_| conn.execute("INSERT INTO t VALUES (zeroblob(65536))", NO_PARAMS))
+            .find_map(|r| r.err());
+        match full {
+            Some(rusqlite::Error::SqliteFailure(f, _)) => assert_eq!(f.code, ErrorCode::DiskFull),
+            e => panic!("size limit not applied {:?}", e),
+        }
+
+        let mut audit = AuditScope::new("run_test");
+        let idlayer = IdlSqlite::new(&mut audit, "", 1, None, DbPragmas::default())
+            .expect("Failed to setup idlayer");
+        let idl_read = idlayer.read().expect("Failed to begin txn");
+        let (size, limit) = idl_read.get_db_size(&mut audit).expect("No db size");
+        assert!(limit > 0 && limit >= size);
+    }
+
+    #[test]
+    fn test_idl_sqlite_pragmas() {
+        let mut conn = rusqlite::Connection::open_in_memory().expect("Failed to open");
+        let pragmas = DbPragmas {
+            synchronous: Some("normal".to_string()),
+            cache_size: Some(-4000),
+            mmap_size: None,
+            wal_autocheckpoint: Some(500),
+            busy_timeout: Some(250),
+        };
+        assert!(init_conn(&mut conn, None, None, &pragmas).is_ok());
+        let get = |p: &str| -> i64 {
+            conn.query_row(format!("PRAGMA {}", p).as_str(), NO_PARAMS, |row| {
+                row.get(0)
+            })
+            .unwrap()
+        };
+        // normal is 1.
+        assert_eq!(get("synchronous"), 1);
+        assert_eq!(get("cache_size"), -4000);
+        assert_eq!(get("wal_autocheckpoint"), 500);
+        assert_eq!(get("busy_timeout"), 250);
+    }
+
+    #[cfg(not(feature = "sqlcipher"))]
+    #[test]
+    fn test_idl_sqlite_key_unsupported() {
+        // Plain sqlite would ignore the key, leaving the db in the clear.
+        let mut audit = AuditScope::new("run_test");
+        assert_eq!(
+            IdlSqlite::new(&mut audit, "", 1, Some([7; 32]), DbPragmas::default()).map(|_| ()),
+            Err(OperationError::InvalidState)
+        );
+    }
+
+    #[cfg(feature = "sqlcipher")]
+    #[test]
+    fn test_idl_sqlite_key() {
+        let mut audit = AuditScope::new("run_test");
+        let path = std::env::temp_dir().join(format!("kanidm_db_key_{}", uuid::Uuid::new_v4()));
+        let path = path.to_str().expect("Invalid temp path");
+        {
+            let idlayer = IdlSqlite::new(&mut audit, path, 1, Some([7; 32]), DbPragmas::default())
+                .expect("Failed to setup idlayer");
+            let idl_write = idlayer.write().expect("Failed to begin txn");
+            assert!(idl_write.setup(&mut audit).is_ok());
+            assert!(idl_write.commit(&mut audit).is_ok());
+        }
+        // Nothing is readable without the key.
+        let content = std::fs::read(path).expect("Failed to read db");
+        assert!(!content.starts_with(b"SQLite format 3"));
+
+        assert_eq!(
+            IdlSqlite::new(&mut audit, path, 1, Some([8; 32]), DbPragmas::default()).map(|_| ()),
+            Err(OperationError::InvalidDBState)
+        );
+        assert!(IdlSqlite::new(&mut audit, path, 1, Some([7; 32]), DbPragmas::default()).is_ok());
+        let _ = std::fs::remove_file(path);
+    }
+}
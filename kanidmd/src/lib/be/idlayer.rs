@@ -0,0 +1,474 @@
+// The id layer is the storage underneath the backend - it maps entry ids to
+// serialised entries, and index keys to idls. Backend only talks to it through
+// these traits, so an alternate key-value store can replace sqlite by
+// implementing them and changing BackendIdLayer in be/mod.rs.
+
+use crate::audit::AuditScope;
+use crate::be::changelog::ChangelogRow;
+use crate::be::domain::DomainInfo;
+use crate::be::journal::JournalRecord;
+use crate::be::slowlog::SlowQuery;
+use crate::be::usage::IdxSlot;
+use crate::be::{IdEntry, IDL};
+use crate::config::DbPragmas;
+use crate::repl::cid::Cid;
+use crate::value::IndexType;
+use idlset::IDLBitRange;
+use kanidm_proto::v1::{DbPoolStats, IndexStat, OperationError, ReportRecord, SlowQueryRecord};
+use std::collections::BTreeMap;
+use uuid::Uuid;
+
+pub trait IdLayer: Clone + Sized {
+    type ReadTransaction: IdLayerTransaction;
+    type WriteTransaction: IdLayerWriteTransaction;
+
+    // An empty path requests an in memory database.
+    // With a key, the database is encrypted with it, and opening it with any
+    // other fails. pragmas tune every connection that's opened.
+    fn new(
+        audit: &mut AuditScope,
+        path: &str,
+        pool_size: u32,
+        db_key: Option<[u8; 32]>,
+        pragmas: DbPragmas,
+    ) -> Result<Self, OperationError>;
+
+    // These fail with Busy if the store is too busy to start a txn right now,
+    // and Unavailable if it can't start one at all.
+    fn read(&self) -> Result<Self::ReadTransaction, OperationError>;
+
+    fn write(&self) -> Result<Self::WriteTransaction, OperationError>;
+
+    // Must not block - returns None if a write can't be started right now.
+    fn try_write(&self) -> Option<Self::WriteTransaction>;
+
+    // How busy the connection pool is now, and what the txns on it have
+    // done since we started.
+    fn get_pool_stats(&self) -> DbPoolStats;
+}
+
+pub trait IdLayerTransaction {
+    fn get_identry(&self, au: &mut AuditScope, idl: &IDL) -> Result<Vec<IdEntry>, OperationError>;
+
+    // Up to limit entries with an id greater than after, in id order. This
+    // lets the whole of id2entry be walked without holding it all in memory.
+    fn get_identry_batch(
+        &self,
+        au: &mut AuditScope,
+        after: u64,
+        limit: usize,
+    ) -> Result<Vec<IdEntry>, OperationError>;
+
+    // As get_identry_batch, but only entries written after the change
+    // sequence number since.
+    fn get_identry_changed(
+        &self,
+        au: &mut AuditScope,
+        since: i64,
+        after: u64,
+        limit: usize,
+    ) -> Result<Vec<IdEntry>, OperationError>;
+
+    // The uuids of entries deleted after the change sequence number since.
+    fn get_removed_since(
+        &self,
+        au: &mut AuditScope,
+        since: i64,
+    ) -> Result<Vec<String>, OperationError>;
+
+    // The most recent change sequence number.
+    fn get_change_marker(&self) -> i64;
+
+    // The change sequence number the last backup was taken at, or 0 if none
+    // has been recorded.
+    fn get_backup_marker(&self) -> i64;
+
+    // The format entries are written to id2entry in, one of the
+    // DBENTRY_FORMAT_ values, or 0 if it has never been set.
+    fn get_db_entry_format(&self) -> i64;
+
+    // The number of entries in id2entry.
+    fn get_id2entry_count(&self) -> Result<i64, OperationError>;
+
+    // None means the index does not exist, which is different to an index
+    // with no ids for this key.
+    fn get_idl(
+        &self,
+        audit: &mut AuditScope,
+        attr: &String,
+        itype: &IndexType,
+        idx_key: &String,
+    ) -> Result<Option<IDLBitRange>, OperationError>;
+
+    // Every key in the index with its idl. This is for verification, as it
+    // loads the whole index.
+    fn get_idx_keys(
+        &self,
+        audit: &mut AuditScope,
+        attr: &String,
+        itype: &IndexType,
+    ) -> Result<Vec<(String, IDLBitRange)>, OperationError>;
+
+    // The keys of the index from lower to upper inclusive, with their idls.
+    // Either bound may be open. None means the index does not exist.
+    fn get_idx_range(
+        &self,
+        audit: &mut AuditScope,
+        attr: &String,
+        itype: &IndexType,
+        lower: Option<&String>,
+        upper: Option<&String>,
+    ) -> Result<Option<Vec<(String, IDLBitRange)>>, OperationError>;
+
+    // The uuid of the live entry with this name, if any.
+    fn get_name2uuid(
+        &self,
+        audit: &mut AuditScope,
+        name: &str,
+    ) -> Result<Option<Uuid>, OperationError>;
+
+    // The name of the live entry with this uuid, if it has one.
+    fn get_uuid2name(
+        &self,
+        audit: &mut AuditScope,
+        uuid: &Uuid,
+    ) -> Result<Option<String>, OperationError>;
+
+    // The spn (name@domain) of the live entry with this uuid, if it has one.
+    fn get_uuid2spn(
+        &self,
+        audit: &mut AuditScope,
+        uuid: &Uuid,
+    ) -> Result<Option<String>, OperationError>;
+
+    // The names of the index tables, IE idx_eq_name.
+    fn list_idxs(&self, audit: &mut AuditScope) -> Result<Vec<String>, OperationError>;
+
+    // The number of keys in the index, counted now.
+    fn count_idx_keys(
+        &self,
+        audit: &mut AuditScope,
+        attr: &String,
+        itype: &IndexType,
+    ) -> Result<i64, OperationError>;
+
+    // The domain record, if one has been written.
+    fn get_db_domain(&self) -> Result<Option<DomainInfo>, OperationError>;
+
+    // The cbor of an externalised value, by its hash.
+    fn get_blob(&self, hash: &str) -> Result<Option<Vec<u8>>, OperationError>;
+
+    // What is recorded of each index, and of any missing index searches have
+    // fallen back to allids for.
+    fn get_idx_stats(&self, audit: &mut AuditScope) -> Result<Vec<IndexStat>, OperationError>;
+
+    // The size of the database in bytes, and the most it may grow to.
+    fn get_db_size(&self, audit: &mut AuditScope) -> Result<(u64, u64), OperationError>;
+
+    // The storage's own check of the file, below anything we wrote. quick
+    // skips the more expensive parts. Returns the problems found, so nothing
+    // means the file is sound.
+    fn integrity_check(
+        &self,
+        audit: &mut AuditScope,
+        quick: bool,
+    ) -> Result<Vec<String>, OperationError>;
+
+    // The number of ids a key of this index is expected to have, or None if
+    // that isn't known.
+    fn get_idx_estimate(
+        &self,
+        audit: &mut AuditScope,
+        attr: &String,
+        itype: &IndexType,
+    ) -> Result<Option<u64>, OperationError>;
+
+    // The limit most read index slots, most read first.
+    fn get_idx_usage(
+        &self,
+        audit: &mut AuditScope,
+        limit: usize,
+    ) -> Result<Vec<IdxSlot>, OperationError>;
+
+    // The whole access journal in the order it was written, as the id, the
+    // record and the hash stored with it.
+    fn get_access_journal(
+        &self,
+        audit: &mut AuditScope,
+    ) -> Result<Vec<(i64, JournalRecord, String)>, OperationError>;
+
+    // The limit most recent slow queries, newest first.
+    fn get_slow_queries(
+        &self,
+        audit: &mut AuditScope,
+        limit: usize,
+    ) -> Result<Vec<SlowQueryRecord>, OperationError>;
+
+    // The whole changelog in the order it was written.
+    fn get_changelog(&self, audit: &mut AuditScope) -> Result<Vec<ChangelogRow>, OperationError>;
+
+    // The limit most recent runs of the report, newest first.
+    fn get_reports(
+        &self,
+        audit: &mut AuditScope,
+        report: &str,
+        limit: usize,
+    ) -> Result<Vec<ReportRecord>, OperationError>;
+
+    // When the report last ran, in seconds since the epoch.
+    fn get_report_last_run(
+        &self,
+        audit: &mut AuditScope,
+        report: &str,
+    ) -> Result<Option<i64>, OperationError>;
+
+    // The CID of each attribute of the entry that has one.
+    fn get_changestate(
+        &self,
+        audit: &mut AuditScope,
+        uuid: &Uuid,
+    ) -> Result<BTreeMap<String, Cid>, OperationError>;
+
+    // The latest CID of any attribute, ours or a partner's.
+    fn get_max_cid(&self, audit: &mut AuditScope) -> Result<Option<Cid>, OperationError>;
+}
+
+// Dropping a write transaction without commit must abort it.
+pub trait IdLayerWriteTransaction: IdLayerTransaction {
+    fn commit(self, audit: &mut AuditScope) -> Result<(), OperationError>;
+
+    // Commit, then rebuild the store to give back the space of what was
+    // deleted. Returns the size of the store after, in bytes.
+    fn vacuum(self, audit: &mut AuditScope) -> Result<u64, OperationError>;
+
+    // Savepoints nest within the txn. savepoint returns the depth of the new
+    // one. Releasing or rolling back to it ends it and any taken after it,
+    // and a rollback undoes everything written since it was taken.
+    fn savepoint(&self, au: &mut AuditScope) -> Result<usize, OperationError>;
+
+    fn release_savepoint(&self, au: &mut AuditScope, depth: usize) -> Result<(), OperationError>;
+
+    fn rollback_to_savepoint(
+        &self,
+        au: &mut AuditScope,
+        depth: usize,
+    ) -> Result<(), OperationError>;
+
+    fn get_id2entry_max_id(&self) -> Result<u64, OperationError>;
+
+    fn write_identries(
+        &self,
+        au: &mut AuditScope,
+        entries: Vec<IdEntry>,
+    ) -> Result<(), OperationError>;
+
+    // Also drops the blob references of the entries.
+    fn delete_identry(&self, au: &mut AuditScope, idl: Vec<u64>) -> Result<(), OperationError>;
+
+    // Store an externalised value, unless we already hold one with this hash.
+    fn write_blob(
+        &self,
+        au: &mut AuditScope,
+        hash: &str,
+        data: &[u8],
+    ) -> Result<(), OperationError>;
+
+    // Replace the blobs the entry id refers to with hashes.
+    fn write_blob_refs(
+        &self,
+        au: &mut AuditScope,
+        id: u64,
+        hashes: &[String],
+    ) -> Result<(), OperationError>;
+
+    // Remove the blobs no entry refers to anymore, returning how many.
+    fn purge_blobs(&self, au: &mut AuditScope) -> Result<usize, OperationError>;
+
+    // Record that the entries with these uuids were deleted, so incremental
+    // backups can carry the deletion.
+    fn write_removed(&self, au: &mut AuditScope, uuids: Vec<String>) -> Result<(), OperationError>;
+
+    fn set_backup_marker(&self, csn: i64) -> Result<(), OperationError>;
+
+    // Replaces the CIDs of these attributes.
+    fn write_changestate(
+        &self,
+        audit: &mut AuditScope,
+        rows: &[(Uuid, String, Cid)],
+    ) -> Result<(), OperationError>;
+
+    fn delete_changestate(
+        &self,
+        audit: &mut AuditScope,
+        uuids: &[Uuid],
+    ) -> Result<(), OperationError>;
+
+    // Add these read counts to those already recorded.
+    fn write_idx_usage(
+        &self,
+        audit: &mut AuditScope,
+        usage: &BTreeMap<IdxSlot, i64>,
+    ) -> Result<(), OperationError>;
+
+    // Append to the access journal, chaining each record to the last.
+    fn write_access_journal(
+        &self,
+        audit: &mut AuditScope,
+        records: &[JournalRecord],
+    ) -> Result<(), OperationError>;
+
+    // Append to the changelog, chaining each record to the last and signing
+    // the hash if there is a key.
+    fn write_changelog(
+        &self,
+        audit: &mut AuditScope,
+        records: &[JournalRecord],
+        key: Option<&[u8; 32]>,
+    ) -> Result<(), OperationError>;
+
+    // Append to the slow queries, dropping the oldest past those we keep.
+    fn write_slow_queries(
+        &self,
+        audit: &mut AuditScope,
+        queries: &[SlowQuery],
+    ) -> Result<(), OperationError>;
+
+    // Record a run of the report, dropping its oldest past those we keep.
+    fn write_report(
+        &self,
+        audit: &mut AuditScope,
+        report: &str,
+        epoch: i64,
+        time: &str,
+        count: u64,
+        allids: bool,
+    ) -> Result<(), OperationError>;
+
+    // An empty idl removes the key. The index statistics are kept up to date
+    // as it's written. Returns the size of what was stored, in bytes.
+    fn write_idl(
+        &self,
+        audit: &mut AuditScope,
+        attr: &String,
+        itype: &IndexType,
+        idx_key: &String,
+        idl: &IDLBitRange,
+    ) -> Result<usize, OperationError>;
+
+    // Record the number of keys in an index, for planning searches.
+    fn write_idx_cardinality(
+        &self,
+        audit: &mut AuditScope,
+        attr: &String,
+        itype: &IndexType,
+        keys: i64,
+    ) -> Result<(), OperationError>;
+
+    // Add these to the counts of searches that fell back to allids.
+    fn write_idx_allids(
+        &self,
+        audit: &mut AuditScope,
+        allids: &BTreeMap<(String, IndexType), i64>,
+    ) -> Result<(), OperationError>;
+
+    // Refresh whatever statistics the store keeps for itself.
+    fn analyze(&self, audit: &mut AuditScope) -> Result<(), OperationError>;
+
+    // A name only maps to the uuid it's removed with, so a name moving
+    // between entries in one txn is kept whatever order they're indexed in.
+    fn write_name2uuid_add(
+        &self,
+        audit: &mut AuditScope,
+        name: &str,
+        uuid: &Uuid,
+    ) -> Result<(), OperationError>;
+
+    fn write_name2uuid_rem(
+        &self,
+        audit: &mut AuditScope,
+        name: &str,
+        uuid: &Uuid,
+    ) -> Result<(), OperationError>;
+
+    fn write_uuid2name_add(
+        &self,
+        audit: &mut AuditScope,
+        uuid: &Uuid,
+        name: &str,
+    ) -> Result<(), OperationError>;
+
+    fn write_uuid2name_rem(
+        &self,
+        audit: &mut AuditScope,
+        uuid: &Uuid,
+    ) -> Result<(), OperationError>;
+
+    fn write_uuid2spn_add(
+        &self,
+        audit: &mut AuditScope,
+        uuid: &Uuid,
+        spn: &str,
+    ) -> Result<(), OperationError>;
+
+    fn write_uuid2spn_rem(&self, audit: &mut AuditScope, uuid: &Uuid)
+        -> Result<(), OperationError>;
+
+    // Recompute every spn from uuid2name, for when the domain changes.
+    fn rebuild_uuid2spn(&self, audit: &mut AuditScope, domain: &str) -> Result<(), OperationError>;
+
+    fn create_name2uuid(&self, audit: &mut AuditScope) -> Result<(), OperationError>;
+
+    fn create_uuid2name(&self, audit: &mut AuditScope) -> Result<(), OperationError>;
+
+    fn create_uuid2spn(&self, audit: &mut AuditScope) -> Result<(), OperationError>;
+
+    fn create_idx(
+        &self,
+        audit: &mut AuditScope,
+        attr: &String,
+        itype: &IndexType,
+    ) -> Result<(), OperationError>;
+
+    // name2uuid, uuid2name and uuid2spn are emptied rather than dropped.
+    unsafe fn purge_idxs(&self, audit: &mut AuditScope) -> Result<(), OperationError>;
+
+    // Empty one index, leaving the table in place.
+    unsafe fn purge_idx(
+        &self,
+        audit: &mut AuditScope,
+        attr: &String,
+        itype: &IndexType,
+    ) -> Result<(), OperationError>;
+
+    // Remove an index that is no longer in the schema, if it's there.
+    fn drop_idx(
+        &self,
+        audit: &mut AuditScope,
+        attr: &String,
+        itype: &IndexType,
+    ) -> Result<(), OperationError>;
+
+    unsafe fn purge_id2entry(&self, audit: &mut AuditScope) -> Result<(), OperationError>;
+
+    fn write_db_domain(&self, domain: &DomainInfo) -> Result<(), OperationError>;
+
+    fn get_db_owner(&self) -> Result<Option<(i64, String, i64)>, OperationError>;
+
+    fn write_db_owner(
+        &self,
+        pid: i64,
+        instance: &str,
+        heartbeat: i64,
+    ) -> Result<(), OperationError>;
+
+    fn delete_db_owner(&self, instance: &str) -> Result<(), OperationError>;
+
+    fn get_db_index_version(&self) -> i64;
+
+    fn set_db_index_version(&self, v: i64) -> Result<(), OperationError>;
+
+    fn set_db_entry_format(&self, f: i64) -> Result<(), OperationError>;
+
+    // Create or migrate the on disk structures, inside this transaction.
+    fn setup(&self, audit: &mut AuditScope) -> Result<(), OperationError>;
+}
@@ -0,0 +1,156 @@
+// Reads of entries marked access_journal, recorded with who read them and
+// how. Records are taken in memory on the read path and written to the
+// access_journal table by the write worker on each heartbeat, the same as
+// index usage, so a search never has to wait on the write lock.
+//
+// Each record is chained to the one before it by a hash over both, so editing
+// or removing a record breaks the chain from that point on. Only the newest
+// records can be dropped unnoticed, so operators who need that should keep
+// the head hash somewhere else as well.
+
+use openssl::sha;
+use std::sync::{Arc, Mutex, MutexGuard};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct JournalRecord {
+    // generalizedtime, as createtimestamp.
+    pub time: String,
+    pub uuid: String,
+    // The uuid of who read the entry.
+    pub identity: String,
+    pub operation: String,
+}
+
+impl JournalRecord {
+    pub fn new(uuid: &Uuid, identity: &str, operation: &str) -> Self {
+        let time = time::now_utc()
+            .strftime("%Y%m%d%H%M%SZ")
+            .map(|t| t.to_string())
+            .unwrap_or_default();
+        JournalRecord {
+            time: time,
+            uuid: uuid.to_string(),
+            identity: identity.to_string(),
+            operation: operation.to_string(),
+        }
+    }
+
+    // The hash chaining this record to the one before it, which is the empty
+    // string for the first.
+    pub fn chain_hash(&self, prev: &str) -> String {
+        let data = format!(
+            "{}\n{}\n{}\n{}\n{}",
+            prev, self.time, self.uuid, self.identity, self.operation
+        );
+        let hs: Vec<String> = sha::sha256(data.as_bytes())
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        hs.concat()
+    }
+}
+
+// The id of the first of these, as read back in order, whose hash doesn't
+// follow from the one before it.
+pub fn first_broken(journal: &[(i64, JournalRecord, String)]) -> Option<i64> {
+    let mut prev = "";
+    for (id, rec, hash) in journal {
+        if rec.chain_hash(prev) != *hash {
+            return Some(*id);
+        }
+        prev = hash.as_str();
+    }
+    None
+}
+
+#[derive(Clone)]
+pub struct AccessJournal {
+    inner: Arc<Mutex<Vec<JournalRecord>>>,
+}
+
+impl AccessJournal {
+    pub fn new() -> Self {
+        AccessJournal {
+            inner: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<JournalRecord>> {
+        // Unlike usage counts these matter, so keep what we have.
+        self.inner
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn record(&self, uuid: &Uuid, identity: &str, operation: &str) {
+        self.lock()
+            .push(JournalRecord::new(uuid, identity, operation));
+    }
+
+    pub fn take(&self) -> Vec<JournalRecord> {
+        std::mem::replace(&mut *self.lock(), Vec::new())
+    }
+
+    // Put back records that couldn't be written, ahead of any taken since,
+    // so they are retried in order on the next flush.
+    pub fn requeue(&self, mut records: Vec<JournalRecord>) {
+        let mut inner = self.lock();
+        records.append(&mut inner);
+        *inner = records;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{first_broken, AccessJournal};
+    use uuid::Uuid;
+
+    #[test]
+    fn test_be_access_journal_requeue() {
+        let journal = AccessJournal::new();
+        let u = Uuid::new_v4();
+        journal.record(&u, "a", "search");
+        let first = journal.take();
+        journal.record(&u, "b", "search");
+        journal.requeue(first);
+        let records = journal.take();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].identity, "a");
+        assert_eq!(records[1].identity, "b");
+        assert!(journal.take().is_empty());
+
+        // The chain depends on what came before.
+        assert_ne!(records[0].chain_hash(""), records[0].chain_hash("x"));
+        assert_ne!(records[0].chain_hash(""), records[1].chain_hash(""));
+    }
+
+    #[test]
+    fn test_be_access_journal_chain() {
+        let journal = AccessJournal::new();
+        let u = Uuid::new_v4();
+        journal.record(&u, "a", "search");
+        journal.record(&u, "b", "search");
+        journal.record(&u, "c", "search");
+        let mut prev = String::new();
+        let mut rows: Vec<_> = journal
+            .take()
+            .into_iter()
+            .zip(1..)
+            .map(|(rec, id)| {
+                prev = rec.chain_hash(prev.as_str());
+                (id, rec, prev.clone())
+            })
+            .collect();
+        assert_eq!(first_broken(rows.as_slice()), None);
+
+        // Editing a record is caught at that record.
+        rows[1].1.identity = "x".to_string();
+        assert_eq!(first_broken(rows.as_slice()), Some(2));
+        rows[1].1.identity = "b".to_string();
+
+        // As is removing one, at the record after it.
+        rows.remove(1);
+        assert_eq!(first_broken(rows.as_slice()), Some(3));
+    }
+}
@@ -0,0 +1,314 @@
+// Export of entries as RFC2849 LDIF, so admins can diff a backup or feed it to
+// standard LDAP tooling. We have no DIT, so each entry is named by its uuid.
+//
+// Values that aren't a SAFE-STRING per the RFC are base64 encoded, and lines
+// are folded at 76 columns. Credentials and tagged strings have no sensible
+// text form, so they are written as their json, which is then base64'd.
+//
+// The reader handles content records only (no changetype), which is what
+// slapcat and db2ldif produce. It knows nothing of schema - mapping the
+// attributes onto ours is the job of the import.
+
+use crate::be::dbentry::{DbEntry, DbEntryVers};
+use crate::be::dbvalue::DbValueV1;
+use crate::value::{IndexType, SyntaxType};
+use openssl::base64::{decode_block, encode_block};
+use std::convert::TryFrom;
+use std::io::{self, BufRead, Write};
+
+pub const LDIF_VERSION: &[u8] = b"version: 1\n";
+
+const LDIF_FOLD_WIDTH: usize = 76;
+
+pub(crate) fn value_to_string(v: &DbValueV1) -> io::Result<String> {
+    Ok(match v {
+        DbValueV1::U8(s) | DbValueV1::I8(s) | DbValueV1::JF(s) | DbValueV1::RU(s) => s.clone(),
+        DbValueV1::UU(u) | DbValueV1::RF(u) => u.to_hyphenated().to_string(),
+        DbValueV1::BO(b) => b.to_string(),
+        DbValueV1::UI(u) => u.to_string(),
+        DbValueV1::SY(s) => usize::try_from(*s)
+            .ok()
+            .and_then(|s| SyntaxType::try_from(s).ok())
+            .map(|s| s.to_string())
+            .unwrap_or_else(|| s.to_string()),
+        DbValueV1::IN(i) => usize::try_from(*i)
+            .ok()
+            .and_then(|i| IndexType::try_from(i).ok())
+            .map(|i| i.to_string())
+            .unwrap_or_else(|| i.to_string()),
+        DbValueV1::CR(c) => serde_json::to_string(c)?,
+        DbValueV1::SK(t) => serde_json::to_string(t)?,
+        DbValueV1::XB(h) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("blob {} was not read in", h),
+            ))
+        }
+    })
+}
+
+// SAFE-INIT-CHAR followed by SAFE-CHARs, from the RFC. A trailing space is
+// also unsafe, as many parsers strip it.
+fn is_safe_string(s: &str) -> bool {
+    let b = s.as_bytes();
+    match b.first() {
+        None => true,
+        Some(c) if *c == b' ' || *c == b':' || *c == b'<' => false,
+        Some(_) => {
+            b.last() != Some(&b' ')
+                && b.iter()
+                    .all(|c| *c != 0 && *c != b'\n' && *c != b'\r' && c.is_ascii())
+        }
+    }
+}
+
+// Continuation lines start with a single space, which counts toward the width.
+fn write_folded<W: Write>(w: &mut W, line: &str) -> io::Result<()> {
+    let b = line.as_bytes();
+    let (first, mut rest) = b.split_at(std::cmp::min(b.len(), LDIF_FOLD_WIDTH));
+    w.write_all(first)?;
+    w.write_all(b"\n")?;
+    while !rest.is_empty() {
+        let (chunk, tail) = rest.split_at(std::cmp::min(rest.len(), LDIF_FOLD_WIDTH - 1));
+        w.write_all(b" ")?;
+        w.write_all(chunk)?;
+        w.write_all(b"\n")?;
+        rest = tail;
+    }
+    Ok(())
+}
+
+fn write_attr<W: Write>(w: &mut W, attr: &str, value: &str) -> io::Result<()> {
+    if is_safe_string(value) {
+        write_folded(w, format!("{}: {}", attr, value).as_str())
+    } else {
+        write_folded(
+            w,
+            format!("{}:: {}", attr, encode_block(value.as_bytes())).as_str(),
+        )
+    }
+}
+
+// Writes one entry, followed by the blank line that separates records.
+pub fn write_entry<W: Write>(w: &mut W, dbe: &DbEntry) -> io::Result<()> {
+    let attrs = match &dbe.ent {
+        DbEntryVers::V1(v1) => &v1.attrs,
+    };
+    let uuid = match attrs.get("uuid").and_then(|v| v.first()) {
+        Some(DbValueV1::UU(u)) => u.to_hyphenated().to_string(),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "entry has no uuid",
+            ))
+        }
+    };
+
+    write_attr(w, "dn", format!("uuid={}", uuid).as_str())?;
+    for (attr, values) in attrs.iter() {
+        for v in values.iter() {
+            write_attr(w, attr.as_str(), value_to_string(v)?.as_str())?;
+        }
+    }
+    w.write_all(b"\n")
+}
+
+fn invalid(lineno: usize, msg: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("ldif line {}: {}", lineno, msg),
+    )
+}
+
+pub struct LdifReader<R: BufRead> {
+    lines: io::Lines<R>,
+    lineno: usize,
+    // The line after the one being unfolded, which we had to read to know
+    // the previous line was complete.
+    peeked: Option<String>,
+}
+
+impl<R: BufRead> LdifReader<R> {
+    pub fn new(r: R) -> Self {
+        LdifReader {
+            lines: r.lines(),
+            lineno: 0,
+            peeked: None,
+        }
+    }
+
+    fn next_line(&mut self) -> io::Result<Option<String>> {
+        match self.peeked.take() {
+            Some(l) => Ok(Some(l)),
+            None => match self.lines.next() {
+                Some(l) => {
+                    self.lineno += 1;
+                    let mut l = l?;
+                    if l.ends_with('\r') {
+                        l.pop();
+                    }
+                    Ok(Some(l))
+                }
+                None => Ok(None),
+            },
+        }
+    }
+
+    // A logical line, with any continuation lines joined on. Comments are
+    // dropped, continuations and all.
+    fn next_unfolded(&mut self) -> io::Result<Option<String>> {
+        loop {
+            let mut line = match self.next_line()? {
+                Some(l) => l,
+                None => return Ok(None),
+            };
+            loop {
+                match self.next_line()? {
+                    Some(ref next) if next.starts_with(' ') => line.push_str(&next[1..]),
+                    next => {
+                        self.peeked = next;
+                        break;
+                    }
+                }
+            }
+            if !line.starts_with('#') {
+                return Ok(Some(line));
+            }
+        }
+    }
+
+    // The next record as (attribute, value) pairs in file order, attribute
+    // names lowercased and stripped of options. The dn is included as the
+    // first pair.
+    pub fn next_record(&mut self) -> io::Result<Option<Vec<(String, String)>>> {
+        let mut record = Vec::new();
+        loop {
+            let line = match self.next_unfolded()? {
+                Some(l) => l,
+                None => break,
+            };
+            if line.is_empty() {
+                if record.is_empty() {
+                    continue;
+                }
+                break;
+            }
+
+            let colon = match line.find(':') {
+                Some(c) => c,
+                None => return Err(invalid(self.lineno, "expected attribute: value")),
+            };
+            let attr = line[..colon].split(';').next().unwrap_or("").to_lowercase();
+            let rest = &line[colon + 1..];
+            let value = if rest.starts_with(':') {
+                let raw = decode_block(rest[1..].trim())
+                    .map_err(|_| invalid(self.lineno, "invalid base64"))?;
+                String::from_utf8(raw).map_err(|_| invalid(self.lineno, "value is not utf8"))?
+            } else if rest.starts_with('<') {
+                return Err(invalid(self.lineno, "url values are not supported"));
+            } else {
+                rest.trim_start_matches(' ').to_string()
+            };
+
+            if record.is_empty() && attr == "version" {
+                if value != "1" {
+                    return Err(invalid(self.lineno, "unsupported ldif version"));
+                }
+                continue;
+            }
+            if record.is_empty() && attr != "dn" {
+                return Err(invalid(self.lineno, "record does not start with dn"));
+            }
+            if attr == "changetype" {
+                return Err(invalid(self.lineno, "change records are not supported"));
+            }
+            record.push((attr, value));
+        }
+
+        if record.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(record))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_safe_string, write_entry, LdifReader};
+    use crate::be::dbentry::{DbEntry, DbEntryV1, DbEntryVers};
+    use crate::be::dbvalue::DbValueV1;
+    use std::collections::BTreeMap;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_be_ldif_write_entry() {
+        assert!(is_safe_string("testperson"));
+        assert!(!is_safe_string(" leading"));
+        assert!(!is_safe_string(":colon"));
+        assert!(!is_safe_string("trailing "));
+        assert!(!is_safe_string("multi\nline"));
+        assert!(!is_safe_string("ünicode"));
+
+        let u = Uuid::parse_str("cc8e95b4-c24f-4d68-ba54-8bed76f63930").unwrap();
+        let mut attrs = BTreeMap::new();
+        attrs.insert("uuid".to_string(), vec![DbValueV1::UU(u)]);
+        attrs.insert(
+            "class".to_string(),
+            vec![
+                DbValueV1::I8("object".to_string()),
+                DbValueV1::I8("person".to_string()),
+            ],
+        );
+        attrs.insert(
+            "description".to_string(),
+            vec![DbValueV1::U8(" spaced".to_string())],
+        );
+        attrs.insert(
+            "displayname".to_string(),
+            vec![DbValueV1::U8("a".repeat(100))],
+        );
+        let dbe = DbEntry {
+            ent: DbEntryVers::V1(DbEntryV1 { attrs: attrs }),
+        };
+
+        let mut out = Vec::new();
+        write_entry(&mut out, &dbe).expect("write failed");
+        let out = String::from_utf8(out).expect("ldif is not utf8");
+
+        let expected = format!(
+            "dn: uuid=cc8e95b4-c24f-4d68-ba54-8bed76f63930\n\
+             class: object\n\
+             class: person\n\
+             description:: IHNwYWNlZA==\n\
+             displayname: {}\n {}\n\
+             uuid: cc8e95b4-c24f-4d68-ba54-8bed76f63930\n\n",
+            "a".repeat(76 - 13),
+            "a".repeat(100 - 63)
+        );
+        assert_eq!(out, expected);
+        assert!(out.lines().all(|l| l.len() <= 76));
+
+        // What we write, we can read back.
+        let mut out = format!("version: 1\n# a comment\n  that is folded\n\n{}", out);
+        out.push_str("dn: cn=other\r\nobjectClass;x-tag: top\r\n");
+        let mut r = LdifReader::new(out.as_bytes());
+        let rec = r.next_record().expect("read failed").expect("no record");
+        assert_eq!(rec.len(), 6);
+        assert_eq!(
+            rec[0],
+            (
+                "dn".to_string(),
+                "uuid=cc8e95b4-c24f-4d68-ba54-8bed76f63930".to_string()
+            )
+        );
+        assert_eq!(rec[3], ("description".to_string(), " spaced".to_string()));
+        assert_eq!(rec[4], ("displayname".to_string(), "a".repeat(100)));
+        let rec = r.next_record().expect("read failed").expect("no record");
+        assert_eq!(rec[1], ("objectclass".to_string(), "top".to_string()));
+        assert!(r.next_record().expect("read failed").is_none());
+
+        let mut r = LdifReader::new("dn: cn=x\nchangetype: delete\n".as_bytes());
+        assert!(r.next_record().is_err());
+    }
+}
@@ -0,0 +1,64 @@
+// What a single search may cost, so that one client can't tie a worker up in
+// a huge allids scan. Each limit that is None is unlimited. Exceeding any of
+// them fails the search with ResourceLimit, rather than returning part of it.
+
+use std::time::{Duration, Instant};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct SearchLimits {
+    // Entries loaded from id2entry to be tested against the filter.
+    pub max_candidates: Option<usize>,
+    // Entries the search returns.
+    pub max_results: Option<usize>,
+    pub max_time: Option<Duration>,
+}
+
+impl SearchLimits {
+    pub fn unlimited() -> Self {
+        SearchLimits::default()
+    }
+
+    // Those set in other replace ours, IE an account's own limits over the
+    // server's.
+    pub fn with_overrides(&self, other: &SearchLimits) -> Self {
+        SearchLimits {
+            max_candidates: other.max_candidates.or(self.max_candidates),
+            max_results: other.max_results.or(self.max_results),
+            max_time: other.max_time.or(self.max_time),
+        }
+    }
+
+    // Where we must have finished by, for a search that began at start.
+    pub fn deadline(&self, start: Instant) -> Option<Instant> {
+        self.max_time.map(|t| start + t)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SearchLimits;
+    use std::time::Duration;
+
+    #[test]
+    fn test_be_search_limits_overrides() {
+        let global = SearchLimits {
+            max_candidates: Some(1000),
+            max_results: Some(100),
+            max_time: None,
+        };
+        let account = SearchLimits {
+            max_results: Some(5000),
+            max_time: Some(Duration::from_secs(1)),
+            ..SearchLimits::unlimited()
+        };
+        assert_eq!(
+            global.with_overrides(&account),
+            SearchLimits {
+                max_candidates: Some(1000),
+                max_results: Some(5000),
+                max_time: Some(Duration::from_secs(1)),
+            }
+        );
+        assert_eq!(global.with_overrides(&SearchLimits::unlimited()), global);
+    }
+}
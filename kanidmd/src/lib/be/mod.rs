@@ -1,72 +1,656 @@
-use rand::prelude::*;
-use serde_cbor;
+use crate::config::{BackupCompression, BackupFormat, BackupKey, DbPragmas, ExportSpec};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::de::{Deserializer, Error as DeError, SeqAccess, Visitor};
 use serde_json;
-use std::convert::TryFrom;
+use std::fmt;
 use std::fs;
-
-use crate::value::IndexType;
-use std::collections::BTreeSet;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::iter::FromIterator;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+use zstd::stream::read::Decoder as ZstdDecoder;
+use zstd::stream::write::Encoder as ZstdEncoder;
+
+use crate::value::{IndexType, PartialValue};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
 
 use crate::audit::AuditScope;
-use crate::be::dbentry::DbEntry;
+use crate::be::dbentry::{DbEntry, DbIncremental, DBENTRY_FORMAT_CBOR, DBENTRY_FORMAT_CBOR_ZSTD};
+use crate::commits::ChangeSet;
 use crate::entry::{Entry, EntryCommitted, EntryNew, EntryValid};
 use crate::filter::{Filter, FilterResolved, FilterValidResolved};
-use crate::utils::SID;
+use crate::modify::{ModifyList, ModifyValid};
+use crate::optrack;
+use crate::repl::cid::Cid;
+use crate::repl::ChangeState;
+use crate::schema::SchemaTransaction;
 use idlset::AndNot;
 use idlset::IDLBitRange;
-use kanidm_proto::v1::{ConsistencyError, OperationError};
+use kanidm_proto::v1::{
+    AccessJournalResponse, AccessRecord, ConsistencyError, DbPoolStats, ExplainIdl,
+    ExplainShortcut, ExplainTerm, IndexStat, OperationError, ReportRecord, SearchExplain,
+    SlowQueryRecord, WriteStatsRecord,
+};
+use rayon::prelude::*;
 
+pub mod capture;
+mod changelog;
+mod compat;
+mod cursor;
 pub mod dbentry;
+mod dblock;
 pub mod dbvalue;
+pub mod domain;
+mod encrypt;
+mod entrycache;
+mod export;
+mod filtercache;
 mod idl_sqlite;
+mod idlayer;
+mod journal;
+pub mod ldif;
+mod limits;
+mod poolmetrics;
+mod slowlog;
+mod sort;
+mod usage;
+mod writestats;
+
+use crate::be::capture::{Capture, CaptureOp, CaptureRecord};
+use crate::be::cursor::{cursor_token, parse_cursor_token, SearchCursors};
+use crate::be::dblock::DbLock;
+use crate::be::domain::DomainInfo;
+use crate::be::encrypt::{DecryptReader, EncryptWriter, ENCRYPT_MAGIC};
+use crate::be::entrycache::{EntryCache, ENTRY_CACHE_SIZE};
+use crate::be::filtercache::{FilterCache, FILTER_CACHE_SIZE};
+use crate::be::idl_sqlite::IdlSqlite;
+use crate::be::idlayer::{IdLayer, IdLayerTransaction, IdLayerWriteTransaction};
+use crate::be::journal::{AccessJournal, JournalRecord};
+pub use crate::be::limits::SearchLimits;
+use crate::be::slowlog::SlowQueryLog;
+pub use crate::be::slowlog::SLOW_QUERY_THRESHOLD;
+pub use crate::be::sort::SortSpec;
+use crate::be::usage::IdxUsage;
+use crate::be::writestats::{WriteStats, WriteStatsLog};
+
+// The storage engine the backend is built on. Any type implementing IdLayer
+// can be substituted here.
+type BackendIdLayer = IdlSqlite;
+type BackendIdLayerRead = <BackendIdLayer as IdLayer>::ReadTransaction;
+type BackendIdLayerWrite = <BackendIdLayer as IdLayer>::WriteTransaction;
+
+// Once an and term has narrowed the candidates to fewer than this many ids,
+// filter2idl stops reading indexes and leaves the rest to the entry filter
+// test. This is the default, the server configuration may change it.
+pub const FILTER_TEST_THRESHOLD: usize = 8;
+// Past this percentage of its size limit, the database is reported as near full.
+const DB_SIZE_WARN_PERCENT: u64 = 90;
+// How many entries backup reads from id2entry at a time.
+const BACKUP_BATCH_SIZE: usize = 1024;
+// The default number of entries restore writes at a time.
+pub const RESTORE_BATCH_SIZE: usize = 1024;
+// Entries indexed per batch by reindex, and how often it reports to stderr.
+const REINDEX_BATCH_SIZE: usize = 1024;
+const REINDEX_PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+// The estimated ids of a term we can't estimate, and of an unindexed term.
+// Both sort after any term with an estimate, and unindexed terms last.
+const FILTER_COST_UNKNOWN: u64 = std::u64::MAX - 1;
+const FILTER_COST_ALLIDS: u64 = std::u64::MAX;
+// How many entries a search filter-tests between checks for cancellation.
+const CANCEL_CHECK_INTERVAL: usize = 256;
+// Above this many candidates, entries are decoded and tested against the
+// filter across the rayon pool rather than on our thread. Below it, the cost
+// of handing the work out is more than we would save.
+const PARALLEL_SEARCH_THRESHOLD: usize = 2048;
+// How much of a parallel filter test is done between cancellation checks.
+const PARALLEL_SEARCH_CHUNK: usize = 8192;
+// Larger pages are cut down to this.
+pub const SEARCH_PAGE_MAX: usize = 1000;
+// The domain of spns until the server configuration sets one, as its default.
+const DEFAULT_DOMAIN: &str = "localhost";
+
+lazy_static! {
+    static ref PVCLASS_TOMBSTONE: PartialValue = PartialValue::new_class("tombstone");
+    static ref PVCLASS_RECYCLED: PartialValue = PartialValue::new_class("recycled");
+}
 
-use crate::be::idl_sqlite::{
-    IdlSqlite, IdlSqliteReadTransaction, IdlSqliteTransaction, IdlSqliteWriteTransaction,
-};
-
-static FILTER_TEST_THRESHOLD: usize = 8;
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum IDL {
     ALLIDS,
     Partial(IDLBitRange),
     Indexed(IDLBitRange),
 }
 
+impl IDL {
+    fn to_explain(&self) -> ExplainIdl {
+        match self {
+            IDL::ALLIDS => ExplainIdl::AllIds,
+            IDL::Partial(idl) => ExplainIdl::Partial(idl.len()),
+            IDL::Indexed(idl) => ExplainIdl::Indexed(idl.len()),
+        }
+    }
+}
+
+// Record on the step of the plan for a term why its resolution stopped early.
+fn plan_shortcut(
+    plan: &mut Option<Vec<ExplainTerm>>,
+    step: Option<usize>,
+    shortcut: ExplainShortcut,
+    skipped: usize,
+) {
+    if let (Some(p), Some(i)) = (plan.as_mut(), step) {
+        p[i].shortcut = Some(shortcut);
+        p[i].skipped = skipped;
+    }
+}
+
+fn explain_term_name(f: &FilterResolved) -> String {
+    let unindexed = |idx: &bool| if *idx { "" } else { " (not indexed)" };
+    match f {
+        FilterResolved::Eq(attr, value, idx) => {
+            format!("eq {} {}{}", attr, value.get_idx_eq_key(), unindexed(idx))
+        }
+        FilterResolved::Sub(attr, value, idx) => {
+            format!("sub {} {}{}", attr, value.get_idx_eq_key(), unindexed(idx))
+        }
+        FilterResolved::Fuzzy(attr, value, idx) => {
+            format!(
+                "fuzzy {} {}{}",
+                attr,
+                value.get_idx_eq_key(),
+                unindexed(idx)
+            )
+        }
+        FilterResolved::Ge(attr, value, idx) => {
+            format!("ge {} {}{}", attr, value.get_idx_eq_key(), unindexed(idx))
+        }
+        FilterResolved::Le(attr, value, idx) => {
+            format!("le {} {}{}", attr, value.get_idx_eq_key(), unindexed(idx))
+        }
+        FilterResolved::Regex(attr, r) => format!("regex {} {} (not indexed)", attr, r.as_str()),
+        FilterResolved::Pres(attr, idx) => format!("pres {}{}", attr, unindexed(idx)),
+        FilterResolved::Or(l) => format!("or ({} terms)", l.len()),
+        FilterResolved::And(l) => format!("and ({} terms)", l.len()),
+        FilterResolved::AndNot(_) => "andnot".to_string(),
+    }
+}
+
+// Hands the entries of a json array to restore_fn a batch at a time as they
+// are parsed. serde can only carry its own error type back out, so a failed
+// batch is stashed in err.
+struct RestoreSeqVisitor<'a, F> {
+    batch_size: usize,
+    restore_fn: F,
+    err: &'a mut Option<OperationError>,
+}
+
+impl<'a, F> RestoreSeqVisitor<'a, F>
+where
+    F: FnMut(Vec<DbEntry>) -> Result<(), OperationError>,
+{
+    fn restore(&mut self, batch: Vec<DbEntry>) -> bool {
+        match (self.restore_fn)(batch) {
+            Ok(_) => true,
+            Err(e) => {
+                *self.err = Some(e);
+                false
+            }
+        }
+    }
+}
+
+impl<'de, 'a, F> Visitor<'de> for RestoreSeqVisitor<'a, F>
+where
+    F: FnMut(Vec<DbEntry>) -> Result<(), OperationError>,
+{
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a sequence of entries")
+    }
+
+    fn visit_seq<A>(mut self, mut seq: A) -> Result<(), A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        let mut batch = Vec::with_capacity(self.batch_size);
+        while let Some(dbe) = seq.next_element::<DbEntry>()? {
+            batch.push(dbe);
+            if batch.len() >= self.batch_size {
+                let full = std::mem::replace(&mut batch, Vec::with_capacity(self.batch_size));
+                if !self.restore(full) {
+                    return Err(A::Error::custom("restore batch failed"));
+                }
+            }
+        }
+        if !self.restore(batch) {
+            return Err(A::Error::custom("restore batch failed"));
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
 pub struct IdEntry {
-    // TODO #20: for now this is i64 to make sqlite work, but entry is u64 for indexing reasons!
-    id: i64,
+    id: u64,
     data: Vec<u8>,
 }
 
 #[derive(Clone)]
 pub struct Backend {
-    idlayer: IdlSqlite,
+    idlayer: BackendIdLayer,
+    lock: Arc<DbLock>,
+    usage: IdxUsage,
+    journal: AccessJournal,
+    slowlog: SlowQueryLog,
+    writestats: WriteStatsLog,
+    filtercache: FilterCache,
+    entrycache: EntryCache,
+    changelog_key: Option<[u8; 32]>,
+    // The domain part of the spns we write to uuid2spn.
+    domain: String,
+    // How entries are written to id2entry, one of the DBENTRY_FORMAT_ values.
+    entry_format: u8,
+    // Where write txns are captured to, when asked for.
+    capture: Option<Capture>,
+    // Shared by every clone, so it can be changed once the workers have
+    // started.
+    read_only: Arc<AtomicBool>,
+    // As with read_only, but set once we are a read replica. This is where
+    // writes should be sent instead.
+    primary: Arc<Mutex<Option<String>>>,
+    cursors: SearchCursors,
+    filter_test_threshold: usize,
 }
 
 pub struct BackendReadTransaction {
-    idlayer: IdlSqliteReadTransaction,
+    idlayer: BackendIdLayerRead,
+    // The change marker of our snapshot, which filtercache results and
+    // entrycache entries are tagged with.
+    marker: i64,
+    filtercache: FilterCache,
+    entrycache: EntryCache,
+    // Which index slots searches read, for the warm up at next start.
+    usage: IdxUsage,
+    journal: AccessJournal,
+    slowlog: SlowQueryLog,
+    writestats: WriteStatsLog,
+    cursors: SearchCursors,
+    filter_test_threshold: usize,
 }
 
+// IDLs changed during a write txn, keyed by (attr, itype, idx_key). These are
+// only written back to the idlayer at commit, so an idl touched by many
+// entries in a txn is only loaded and stored once.
+type IdxCache = BTreeMap<(String, IndexType, String), IDLBitRange>;
+
 pub struct BackendWriteTransaction {
     idxmeta: BTreeSet<(String, IndexType)>,
-    // idxcache: IdxCache,
-    idlayer: IdlSqliteWriteTransaction,
+    idxcache: RefCell<IdxCache>,
+    // Indexes in idxmeta whose table was missing, IE a db that was only
+    // partly upgraded. We created them when first written to, and fill them
+    // from id2entry at commit. Until then they are treated as unindexed.
+    backfill: RefCell<BTreeSet<(String, IndexType)>>,
+    // The entries and attributes written by this txn, for the commit event.
+    changes: RefCell<ChangeSet>,
+    // The same, per entry, for the CIDs written with it at commit.
+    changestate: RefCell<ChangeState>,
+    // The changelog records for this txn, written with it at commit.
+    changelog: RefCell<Vec<JournalRecord>>,
+    changelog_key: Option<[u8; 32]>,
+    domain: String,
+    entry_format: u8,
+    idlayer: BackendIdLayerWrite,
+    filtercache: FilterCache,
+    // The marker we began at, and the ids we wrote or deleted since, to be
+    // dropped from the entrycache at commit. None means every id, IE after
+    // id2entry is purged.
+    marker: i64,
+    dirty: RefCell<Option<BTreeSet<u64>>>,
+    entrycache: EntryCache,
+    journal: AccessJournal,
+    slowlog: SlowQueryLog,
+    // What this txn has written so far, kept in writestats at commit.
+    stats: RefCell<WriteStats>,
+    writestats: WriteStatsLog,
+    // The capture this txn is part of, if any, and what it did to be written
+    // there at commit.
+    capture: Option<Capture>,
+    capture_ops: RefCell<Vec<CaptureOp>>,
+    filter_test_threshold: usize,
+}
+
+// A point in a write txn that it can be rolled back to, with what the txn had
+// kept in memory by then. dirty isn't kept, as dropping more of the
+// entrycache than we must at commit is harmless.
+pub struct Savepoint {
+    depth: usize,
+    idxcache: IdxCache,
+    backfill: BTreeSet<(String, IndexType)>,
+    changes: ChangeSet,
+    changestate: ChangeState,
+    changelog: usize,
+    stats: WriteStats,
+    capture_ops: usize,
 }
 
 impl IdEntry {
-    fn to_entry(self) -> Result<Entry<EntryValid, EntryCommitted>, OperationError> {
-        let db_e = serde_cbor::from_slice(self.data.as_slice())
-            .map_err(|_| OperationError::SerdeCborError)?;
-        let id = u64::try_from(self.id).map_err(|_| OperationError::InvalidEntryID)?;
+    // The stored entry, with its externalised values read back in from
+    // idlayer.
+    fn to_dbentry<T: IdLayerTransaction>(&self, idlayer: &T) -> Result<DbEntry, OperationError> {
+        let (db_e, _) = DbEntry::from_blob(self.data.as_slice())?;
+        db_e.internalise(|hash| idlayer.get_blob(hash))
+    }
+
+    fn to_entry<T: IdLayerTransaction>(
+        self,
+        idlayer: &T,
+    ) -> Result<Entry<EntryValid, EntryCommitted>, OperationError> {
+        let db_e = self.to_dbentry(idlayer)?;
+        let id = self.id;
         Entry::from_dbentry(db_e, id).map_err(|_| OperationError::CorruptedEntry(id))
     }
+
+    // As to_entry, and if this wasn't stored in format, the IdEntry to
+    // replace it with. The replacement keeps referring to the same blobs.
+    fn to_entry_upgrade<T: IdLayerTransaction>(
+        self,
+        idlayer: &T,
+        format: u8,
+    ) -> Result<(Entry<EntryValid, EntryCommitted>, Option<IdEntry>), OperationError> {
+        let (db_e, stored) = DbEntry::from_blob(self.data.as_slice())?;
+        let upgrade = if stored == format {
+            None
+        } else {
+            Some(IdEntry {
+                id: self.id,
+                data: db_e.to_blob(format)?,
+            })
+        };
+        let db_e = db_e.internalise(|hash| idlayer.get_blob(hash))?;
+        let id = self.id;
+        Entry::from_dbentry(db_e, id)
+            .map(|e| (e, upgrade))
+            .map_err(|_| OperationError::CorruptedEntry(id))
+    }
+}
+
+// Whether a search that must be done by deadline has run out of time.
+fn check_deadline(au: &mut AuditScope, deadline: Option<Instant>) -> Result<(), OperationError> {
+    match deadline {
+        Some(d) if Instant::now() >= d => {
+            audit_log!(au, "search exceeded its time limit, refusing");
+            Err(OperationError::ResourceLimit)
+        }
+        _ => Ok(()),
+    }
+}
+
+// As to_entry on each, but the cbor is decoded across the rayon pool. The
+// txn can only be used from our thread, so externalised values are read back
+// in here between the two parallel steps. Order is kept.
+fn to_entries_par<T: IdLayerTransaction>(
+    idlayer: &T,
+    raw_entries: Vec<IdEntry>,
+) -> Result<Vec<Entry<EntryValid, EntryCommitted>>, OperationError> {
+    let decoded: Vec<(u64, DbEntry)> = raw_entries
+        .into_par_iter()
+        .map(|ide| DbEntry::from_blob(ide.data.as_slice()).map(|(db_e, _)| (ide.id, db_e)))
+        .collect::<Result<_, _>>()?;
+    let internalised: Vec<(u64, DbEntry)> = decoded
+        .into_iter()
+        .map(|(id, db_e)| {
+            db_e.internalise(|hash| idlayer.get_blob(hash))
+                .map(|db_e| (id, db_e))
+        })
+        .collect::<Result<_, _>>()?;
+    internalised
+        .into_par_iter()
+        .map(|(id, db_e)| {
+            Entry::from_dbentry(db_e, id).map_err(|_| OperationError::CorruptedEntry(id))
+        })
+        .collect()
+}
+
+// The attribute and type of an index from its table name, IE idx_eq_name.
+// Other idx_ tables, like idx_name2uuid, give None.
+fn parse_idx_table(t: &str) -> Option<(String, IndexType)> {
+    let mut parts = t.trim_start_matches("idx_").splitn(2, '_');
+    match (parts.next().and_then(IndexType::from_idx_str), parts.next()) {
+        (Some(itype), Some(attr)) => Some((attr.to_string(), itype)),
+        _ => None,
+    }
+}
+
+// idxmeta as it's written to a capture.
+fn capture_idxmeta(idxmeta: &BTreeSet<(String, IndexType)>) -> Vec<(String, String)> {
+    idxmeta
+        .iter()
+        .map(|(attr, itype)| (attr.clone(), itype.as_idx_str().to_string()))
+        .collect()
 }
 
 pub trait BackendTransaction {
-    type IdlLayerType: IdlSqliteTransaction;
+    type IdlLayerType: IdLayerTransaction;
     fn get_idlayer(&self) -> &Self::IdlLayerType;
+    fn get_filter_test_threshold(&self) -> usize;
+    fn get_slowlog(&self) -> &SlowQueryLog;
+
+    // The shared filter cache, and the marker to use it with. Only read txns
+    // have one.
+    fn get_filtercache(&self) -> Option<(&FilterCache, i64)> {
+        None
+    }
+
+    fn get_entrycache(&self) -> Option<(&EntryCache, i64)> {
+        None
+    }
+
+    // The entries of idl, from the entrycache where we can.
+    fn get_entries(
+        &self,
+        au: &mut AuditScope,
+        idl: &IDL,
+    ) -> Result<Vec<Entry<EntryValid, EntryCommitted>>, OperationError> {
+        let (ec, marker) = match (self.get_entrycache(), idl) {
+            (Some(c), IDL::Partial(_)) | (Some(c), IDL::Indexed(_)) => c,
+            // An allids scan would push out the hot entries.
+            _ => {
+                let raw_entries = try_audit!(au, self.get_idlayer().get_identry(au, idl));
+                let entries = if raw_entries.len() > PARALLEL_SEARCH_THRESHOLD {
+                    to_entries_par(self.get_idlayer(), raw_entries)
+                } else {
+                    raw_entries
+                        .into_iter()
+                        .map(|ide| ide.to_entry(self.get_idlayer()))
+                        .collect()
+                };
+                return Ok(try_audit!(au, entries));
+            }
+        };
+        let mut found = BTreeMap::new();
+        let mut missing = Vec::new();
+        if let IDL::Partial(ids) | IDL::Indexed(ids) = idl {
+            for id in ids {
+                match ec.get(marker, id) {
+                    Some(e) => {
+                        found.insert(id, e);
+                    }
+                    None => missing.push(id),
+                }
+            }
+        }
+        audit_log!(
+            au,
+            "entry cache: {} hits, {} misses",
+            found.len(),
+            missing.len()
+        );
+        if !missing.is_empty() {
+            let missing = IDL::Indexed(IDLBitRange::from_iter(missing));
+            let raw_entries = try_audit!(au, self.get_idlayer().get_identry(au, &missing));
+            for ide in raw_entries {
+                let e = try_audit!(au, ide.to_entry(self.get_idlayer()));
+                ec.insert(marker, &e);
+                found.insert(e.get_id(), e);
+            }
+        }
+        Ok(found.into_iter().map(|(_, e)| e).collect())
+    }
+
+    fn get_idl(
+        &self,
+        audit: &mut AuditScope,
+        attr: &String,
+        itype: &IndexType,
+        idx_key: &String,
+    ) -> Result<Option<IDLBitRange>, OperationError> {
+        self.get_idlayer().get_idl(audit, attr, itype, idx_key)
+    }
+
+    // The ids in the order sort asks for. An ORDERING index on the attribute
+    // gives this without loading the entries.
+    fn sort_ids(
+        &self,
+        au: &mut AuditScope,
+        ids: Vec<u64>,
+        sort: &SortSpec,
+    ) -> Result<Vec<u64>, OperationError> {
+        match self.get_idx_range(au, &sort.attr, &IndexType::ORDERING, None, None)? {
+            Some(keys) => Ok(sort.order_ids(ids.as_slice(), keys)),
+            None => {
+                audit_log!(au, "no ordering index for {}, sorting entries", sort.attr);
+                let entries = self.get_entries(au, &IDL::Indexed(IDLBitRange::from_iter(ids)))?;
+                Ok(sort
+                    .sort_entries(entries)
+                    .iter()
+                    .map(|e| e.get_id())
+                    .collect())
+            }
+        }
+    }
+
+    // The keys of the index from lower to upper inclusive, either of which may
+    // be open, with their idls. None means the index does not exist.
+    fn get_idx_range(
+        &self,
+        audit: &mut AuditScope,
+        attr: &String,
+        itype: &IndexType,
+        lower: Option<&String>,
+        upper: Option<&String>,
+    ) -> Result<Option<Vec<(String, IDLBitRange)>>, OperationError> {
+        self.get_idlayer()
+            .get_idx_range(audit, attr, itype, lower, upper)
+    }
+
+    // The entries holding every one of idx_keys, or None if there are no keys.
+    // A single key is exact, but more could be spread across the value, or
+    // over several values, so those candidates still need the filter test.
+    fn get_idl_all_keys(
+        &self,
+        au: &mut AuditScope,
+        attr: &String,
+        itype: &IndexType,
+        idx_keys: &[String],
+    ) -> Result<Option<IDL>, OperationError> {
+        let mut result: Option<IDLBitRange> = None;
+        for idx_key in idx_keys.iter() {
+            let idl = match self.get_idl(au, attr, itype, idx_key)? {
+                Some(idl) => idl,
+                None => {
+                    self.note_allids(attr, itype);
+                    return Ok(Some(IDL::ALLIDS));
+                }
+            };
+            let idl = match result {
+                Some(r) => r & idl,
+                None => idl,
+            };
+            // Nothing has this key, so nothing matches.
+            if idl.len() == 0 {
+                return Ok(Some(IDL::Indexed(idl)));
+            }
+            result = Some(idl);
+        }
+        Ok(result.map(|idl| {
+            if idx_keys.len() == 1 {
+                IDL::Indexed(idl)
+            } else {
+                IDL::Partial(idl)
+            }
+        }))
+    }
+
+    // How many entries hold attr, read from its presence index, or None if it
+    // has none. Recycled entries keep their attributes, so are counted too.
+    fn count_attr_usage(
+        &self,
+        audit: &mut AuditScope,
+        attr: &String,
+    ) -> Result<Option<u64>, OperationError> {
+        self.get_idl(audit, attr, &IndexType::PRESENCE, &"_".to_string())
+            .map(|idl| idl.map(|idl| idl.len() as u64))
+    }
+
+    // Called when a search term has to fall back to allids for want of an
+    // index, so it can be counted.
+    fn note_allids(&self, _attr: &String, _itype: &IndexType) {}
+
+    // Called when an entry marked access_journal is returned to identity, so
+    // the read is journaled.
+    fn note_access(&self, uuid: &Uuid, identity: &str, operation: &str);
+
+    fn idx_cost(&self, au: &mut AuditScope, attr: &String, itype: &IndexType) -> u64 {
+        match self.get_idlayer().get_idx_estimate(au, attr, itype) {
+            Ok(Some(n)) => n,
+            Ok(None) => FILTER_COST_UNKNOWN,
+            Err(e) => {
+                audit_log!(au, "Unable to estimate {:?} {} -> {:?}", itype, attr, e);
+                FILTER_COST_UNKNOWN
+            }
+        }
+    }
+
+    // Roughly how many ids filter2idl will give for this term, from the index
+    // statistics. This doesn't need to be accurate, only good enough to tell
+    // a term matching one entry from one matching thousands.
+    fn filter_cost(&self, au: &mut AuditScope, filt: &FilterResolved) -> u64 {
+        match filt {
+            FilterResolved::Eq(attr, _, true) => self.idx_cost(au, attr, &IndexType::EQUALITY),
+            FilterResolved::Sub(attr, _, true) => self.idx_cost(au, attr, &IndexType::SUBSTRING),
+            FilterResolved::Fuzzy(attr, _, true) => self.idx_cost(au, attr, &IndexType::FUZZY),
+            FilterResolved::Pres(attr, true) => self.idx_cost(au, attr, &IndexType::PRESENCE),
+            // The statistics are per key, which says little about a range.
+            FilterResolved::Ge(_, _, true) | FilterResolved::Le(_, _, true) => FILTER_COST_UNKNOWN,
+            FilterResolved::Eq(_, _, false)
+            | FilterResolved::Sub(_, _, false)
+            | FilterResolved::Fuzzy(_, _, false)
+            | FilterResolved::Ge(_, _, false)
+            | FilterResolved::Le(_, _, false)
+            | FilterResolved::Regex(_, _)
+            | FilterResolved::Pres(_, false) => FILTER_COST_ALLIDS,
+            FilterResolved::Or(l) => l
+                .iter()
+                .fold(0, |acc: u64, f| acc.saturating_add(self.filter_cost(au, f))),
+            FilterResolved::And(l) => l
+                .iter()
+                .filter(|f| !f.is_andnot())
+                .map(|f| self.filter_cost(au, f))
+                .min()
+                .unwrap_or(FILTER_COST_ALLIDS),
+            FilterResolved::AndNot(_) => FILTER_COST_ALLIDS,
+        }
+    }
 
     /// Recursively apply a filter, transforming into IDL's on the way.
     fn filter2idl(
@@ -74,57 +658,155 @@ pub trait BackendTransaction {
         au: &mut AuditScope,
         filt: &FilterResolved,
         thres: usize,
+    ) -> Result<IDL, OperationError> {
+        match self.get_filtercache() {
+            Some((fc, marker)) => {
+                let key = FilterCache::key(filt, thres);
+                if let Some(idl) = fc.get(marker, &key) {
+                    audit_log!(au, "filter cache hit -> {:?}", idl);
+                    return Ok(idl);
+                }
+                let idl = self.filter2idl_plan(au, filt, thres, 0, &mut None)?;
+                fc.insert(marker, key, &idl);
+                Ok(idl)
+            }
+            None => self.filter2idl_plan(au, filt, thres, 0, &mut None),
+        }
+    }
+
+    // As filter2idl, and if there is a plan, each term is added to it as it
+    // is resolved, along with its result.
+    fn filter2idl_plan(
+        &self,
+        au: &mut AuditScope,
+        filt: &FilterResolved,
+        thres: usize,
+        depth: usize,
+        plan: &mut Option<Vec<ExplainTerm>>,
     ) -> Result<IDL, OperationError> {
         debug!("testing filter -> {:?}", filt);
-        let fr = Ok(match filt {
+        let step = plan.as_mut().map(|p| {
+            p.push(ExplainTerm {
+                depth: depth,
+                term: explain_term_name(filt),
+                idl: ExplainIdl::AllIds,
+                shortcut: None,
+                skipped: 0,
+            });
+            p.len() - 1
+        });
+        let fr = self.filter2idl_term(au, filt, thres, depth, plan, step);
+        if let (Some(p), Some(i), Ok(idl)) = (plan.as_mut(), step, &fr) {
+            p[i].idl = idl.to_explain();
+        }
+        debug!("result of {:?} -> {:?}", filt, fr);
+        fr
+    }
+
+    fn filter2idl_term(
+        &self,
+        au: &mut AuditScope,
+        filt: &FilterResolved,
+        thres: usize,
+        depth: usize,
+        plan: &mut Option<Vec<ExplainTerm>>,
+        step: Option<usize>,
+    ) -> Result<IDL, OperationError> {
+        Ok(match filt {
             FilterResolved::Eq(attr, value, idx) => {
                 if *idx {
                     // Get the idx_key
                     let idx_key = value.get_idx_eq_key();
                     // Get the idl for this
-                    match self
-                        .get_idlayer()
-                        .get_idl(au, attr, &IndexType::EQUALITY, &idx_key)?
-                    {
+                    match self.get_idl(au, attr, &IndexType::EQUALITY, &idx_key)? {
                         Some(idl) => IDL::Indexed(idl),
-                        None => IDL::ALLIDS,
+                        None => {
+                            self.note_allids(attr, &IndexType::EQUALITY);
+                            IDL::ALLIDS
+                        }
                     }
                 } else {
                     // Schema believes this is not indexed
+                    self.note_allids(attr, &IndexType::EQUALITY);
                     IDL::ALLIDS
                 }
             }
             FilterResolved::Sub(attr, subvalue, idx) => {
                 if *idx {
-                    // Get the idx_key
-                    let idx_key = subvalue.get_idx_sub_key();
-                    // Get the idl for this
-                    match self
-                        .get_idlayer()
-                        .get_idl(au, attr, &IndexType::SUBSTRING, &idx_key)?
-                    {
-                        Some(idl) => IDL::Indexed(idl),
+                    // The entries holding every trigram of the substring.
+                    match self.get_idl_all_keys(
+                        au,
+                        attr,
+                        &IndexType::SUBSTRING,
+                        subvalue.get_idx_sub_keys().as_slice(),
+                    )? {
+                        Some(idl) => idl,
+                        // Shorter than a trigram, which the index can't help with.
                         None => IDL::ALLIDS,
                     }
                 } else {
                     // Schema believes this is not indexed
+                    self.note_allids(attr, &IndexType::SUBSTRING);
                     IDL::ALLIDS
                 }
             }
-            FilterResolved::Pres(attr, idx) => {
+            FilterResolved::Fuzzy(attr, value, idx) => {
                 if *idx {
-                    // Get the idl for this
-                    match self.get_idlayer().get_idl(
+                    match self.get_idl_all_keys(
                         au,
                         attr,
-                        &IndexType::PRESENCE,
-                        &"_".to_string(),
+                        &IndexType::FUZZY,
+                        value.get_idx_fuzzy_keys().as_slice(),
                     )? {
+                        Some(idl) => idl,
+                        // No words to sound out, which matches nothing.
+                        None => IDL::Indexed(IDLBitRange::new()),
+                    }
+                } else {
+                    // Schema believes this is not indexed
+                    self.note_allids(attr, &IndexType::FUZZY);
+                    IDL::ALLIDS
+                }
+            }
+            FilterResolved::Ge(attr, value, idx) | FilterResolved::Le(attr, value, idx) => {
+                let idx_key = value.get_idx_eq_key();
+                let (lower, upper) = match filt {
+                    FilterResolved::Ge(_, _, _) => (Some(&idx_key), None),
+                    _ => (None, Some(&idx_key)),
+                };
+                let range = if *idx {
+                    self.get_idx_range(au, attr, &IndexType::ORDERING, lower, upper)?
+                } else {
+                    // Schema believes this is not indexed
+                    None
+                };
+                match range {
+                    // The union of every key in the range.
+                    Some(keys) => IDL::Indexed(
+                        keys.into_iter()
+                            .fold(IDLBitRange::new(), |acc, (_, idl)| acc | idl),
+                    ),
+                    None => {
+                        self.note_allids(attr, &IndexType::ORDERING);
+                        IDL::ALLIDS
+                    }
+                }
+            }
+            // No index can answer a regex, so there is nothing to suggest.
+            FilterResolved::Regex(_, _) => IDL::ALLIDS,
+            FilterResolved::Pres(attr, idx) => {
+                if *idx {
+                    // Get the idl for this
+                    match self.get_idl(au, attr, &IndexType::PRESENCE, &"_".to_string())? {
                         Some(idl) => IDL::Indexed(idl),
-                        None => IDL::ALLIDS,
+                        None => {
+                            self.note_allids(attr, &IndexType::PRESENCE);
+                            IDL::ALLIDS
+                        }
                     }
                 } else {
                     // Schema believes this is not indexed
+                    self.note_allids(attr, &IndexType::PRESENCE);
                     IDL::ALLIDS
                 }
             }
@@ -134,9 +816,9 @@ pub trait BackendTransaction {
                 let mut result = IDLBitRange::new();
                 let mut partial = false;
                 // For each filter in l
-                for f in l.iter() {
+                for (i, f) in l.iter().enumerate() {
                     // get their idls
-                    match self.filter2idl(au, f, thres)? {
+                    match self.filter2idl_plan(au, f, thres, depth + 1, plan)? {
                         IDL::Indexed(idl) => {
                             // now union them (if possible)
                             result = result | idl;
@@ -149,6 +831,7 @@ pub trait BackendTransaction {
                         IDL::ALLIDS => {
                             // If we find anything unindexed, the whole term is unindexed.
                             audit_log!(au, "Term {:?} is ALLIDS, shortcut return", f);
+                            plan_shortcut(plan, step, ExplainShortcut::OrAllIds, l.len() - i - 1);
                             return Ok(IDL::ALLIDS);
                         }
                     }
@@ -167,9 +850,18 @@ pub trait BackendTransaction {
                 // First, setup the two filter lists.
                 let (f_andnot, mut f_rem): (Vec<_>, Vec<_>) = l.iter().partition(|f| f.is_andnot());
 
+                // Resolve the most selective terms first, so the candidate set
+                // is small from the start, and once it's under the threshold
+                // the rest needn't be loaded at all.
+                if f_rem.len() > 1 {
+                    f_rem.sort_by_cached_key(|f| self.filter_cost(au, f));
+                    audit_log!(au, "And term order -> {:?}", f_rem);
+                }
+                let mut f_rem = f_rem.into_iter();
+
                 // Setup the initial result.
-                let mut cand_idl = match f_rem.pop() {
-                    Some(f) => self.filter2idl(au, f, thres)?,
+                let mut cand_idl = match f_rem.next() {
+                    Some(f) => self.filter2idl_plan(au, f, thres, depth + 1, plan)?,
                     None => {
                         audit_log!(au, "WARNING: And filter was empty, or contains only AndNot, can not evaluate.");
                         return Ok(IDL::Indexed(IDLBitRange::new()));
@@ -180,20 +872,29 @@ pub trait BackendTransaction {
                         if idl.len() < thres {
                             // When belowe thres, we have to return partials to trigger the entry_no_match_filter check.
                             audit_log!(au, "NOTICE: Cand set shorter than threshold, early return");
+                            plan_shortcut(
+                                plan,
+                                step,
+                                ExplainShortcut::AndThreshold,
+                                f_rem.len() + f_andnot.len(),
+                            );
                             return Ok(IDL::Partial(idl.clone()));
                         }
                     }
                     IDL::ALLIDS => {}
                 }
 
-                for f in f_rem.iter() {
-                    let inter = self.filter2idl(au, f, thres)?;
+                let rem = f_rem.len();
+                for (i, f) in f_rem.enumerate() {
+                    let inter = self.filter2idl_plan(au, f, thres, depth + 1, plan)?;
+                    let skipped = rem - i - 1 + f_andnot.len();
                     cand_idl = match (cand_idl, inter) {
                         (IDL::Indexed(ia), IDL::Indexed(ib)) => {
                             let r = ia & ib;
                             if r.len() < thres {
                                 // When below thres, we have to return partials to trigger the entry_no_match_filter check.
                                 debug!("shortcut cand set ==> {:?}", r);
+                                plan_shortcut(plan, step, ExplainShortcut::AndThreshold, skipped);
                                 return Ok(IDL::Partial(r));
                             } else {
                                 IDL::Indexed(r)
@@ -206,6 +907,7 @@ pub trait BackendTransaction {
                             if r.len() < thres {
                                 // When below thres, we have to return partials to trigger the entry_no_match_filter check.
                                 debug!("shortcut cand set ==> {:?}", r);
+                                plan_shortcut(plan, step, ExplainShortcut::AndThreshold, skipped);
                                 return Ok(IDL::Partial(r));
                             } else {
                                 IDL::Partial(r)
@@ -221,7 +923,8 @@ pub trait BackendTransaction {
 
                 debug!("partial cand set ==> {:?}", cand_idl);
 
-                for f in f_andnot.iter() {
+                for (i, f) in f_andnot.iter().enumerate() {
+                    let skipped = f_andnot.len() - i - 1;
                     let f_in = match f {
                         FilterResolved::AndNot(f_in) => f_in,
                         _ => {
@@ -232,13 +935,14 @@ pub trait BackendTransaction {
                             return Err(OperationError::InvalidState);
                         }
                     };
-                    let inter = self.filter2idl(au, f_in, thres)?;
+                    let inter = self.filter2idl_plan(au, f_in, thres, depth + 1, plan)?;
                     cand_idl = match (cand_idl, inter) {
                         (IDL::Indexed(ia), IDL::Indexed(ib)) => {
                             let r = ia.andnot(ib);
                             if r.len() < thres {
                                 // When below thres, we have to return partials to trigger the entry_no_match_filter check.
                                 debug!("shortcut cand set ==> {:?}", r);
+                                plan_shortcut(plan, step, ExplainShortcut::AndThreshold, skipped);
                                 return Ok(IDL::Partial(r));
                             } else {
                                 IDL::Indexed(r)
@@ -251,6 +955,7 @@ pub trait BackendTransaction {
                             if r.len() < thres {
                                 // When below thres, we have to return partials to trigger the entry_no_match_filter check.
                                 debug!("shortcut cand set ==> {:?}", r);
+                                plan_shortcut(plan, step, ExplainShortcut::AndThreshold, skipped);
                                 return Ok(IDL::Partial(r));
                             } else {
                                 IDL::Partial(r)
@@ -286,9 +991,7 @@ pub trait BackendTransaction {
                 );
                 IDL::Indexed(IDLBitRange::new())
             }
-        });
-        debug!("result of {:?} -> {:?}", filt, fr);
-        fr
+        })
     }
 
     // Take filter, and AuditScope ref?
@@ -296,43 +999,140 @@ pub trait BackendTransaction {
         &self,
         au: &mut AuditScope,
         filt: &Filter<FilterValidResolved>,
+    ) -> Result<Vec<Entry<EntryValid, EntryCommitted>>, OperationError> {
+        self.search_limited(au, filt, &SearchLimits::unlimited())
+    }
+
+    // As search, but fail with ResourceLimit if it would go past any of
+    // limits. When the idl is fully indexed we can tell if too many would
+    // match, or be loaded, before we touch id2entry.
+    fn search_limited(
+        &self,
+        au: &mut AuditScope,
+        filt: &Filter<FilterValidResolved>,
+        limits: &SearchLimits,
     ) -> Result<Vec<Entry<EntryValid, EntryCommitted>>, OperationError> {
         //
         // Unlike DS, even if we don't get the index back, we can just pass
         // to the in-memory filter test and be done.
         audit_segment!(au, || {
+            let start = Instant::now();
+            let deadline = limits.deadline(start);
             // Do a final optimise of the filter
             let filt = filt.optimise();
             audit_log!(au, "filter optimised to --> {:?}", filt);
 
             // Using the indexes, resolve the IDL here, or ALLIDS.
             // Also get if the filter was 100% resolved or not.
-            let idl = self.filter2idl(au, filt.to_inner(), FILTER_TEST_THRESHOLD)?;
+            optrack::set_phase("resolving indexes");
+            let idl = self.filter2idl(au, filt.to_inner(), self.get_filter_test_threshold())?;
+            optrack::check_cancelled()?;
+            check_deadline(au, deadline)?;
+
+            match (&idl, limits.max_results) {
+                (IDL::Indexed(i), Some(limit)) if i.len() > limit => {
+                    audit_log!(au, "search would exceed size limit {}, refusing", limit);
+                    return Err(OperationError::ResourceLimit);
+                }
+                _ => {}
+            };
+
+            if let Some(limit) = limits.max_candidates {
+                let to_load = match &idl {
+                    IDL::ALLIDS => self.get_idlayer().get_id2entry_count()? as usize,
+                    IDL::Partial(i) | IDL::Indexed(i) => i.len(),
+                };
+                if to_load > limit {
+                    audit_log!(
+                        au,
+                        "search would load {} candidates, over the limit of {}, refusing",
+                        to_load,
+                        limit
+                    );
+                    return Err(OperationError::ResourceLimit);
+                }
+            }
 
-            let raw_entries = try_audit!(au, self.get_idlayer().get_identry(au, &idl));
-            let entries: Result<Vec<_>, _> =
-                raw_entries.into_iter().map(|ide| ide.to_entry()).collect();
-            let entries = try_audit!(au, entries);
+            optrack::set_phase("loading entries");
+            let entries = self.get_entries(au, &idl)?;
+            optrack::check_cancelled()?;
+            check_deadline(au, deadline)?;
             // Do other things
             // Now, de-serialise the raw_entries back to entries, and populate their ID's
 
             // if not 100% resolved.
 
-            let entries_filtered = match idl {
-                IDL::ALLIDS | IDL::Partial(_) => entries
-                    .into_iter()
-                    .filter(|e| e.entry_match_no_index(&filt))
-                    .collect(),
+            let allids = match idl {
+                IDL::ALLIDS => true,
+                _ => false,
+            };
+            let candidates = entries.len();
+
+            optrack::set_phase("testing filter");
+            let entries_filtered: Vec<_> = match idl {
+                IDL::ALLIDS | IDL::Partial(_) if candidates > PARALLEL_SEARCH_THRESHOLD => {
+                    let mut matched = Vec::with_capacity(candidates);
+                    for chunk in entries.chunks(PARALLEL_SEARCH_CHUNK) {
+                        optrack::check_cancelled()?;
+                        check_deadline(au, deadline)?;
+                        matched.par_extend(chunk.par_iter().map(|e| e.entry_match_no_index(&filt)));
+                    }
+                    entries
+                        .into_iter()
+                        .zip(matched)
+                        .filter_map(|(e, m)| if m { Some(e) } else { None })
+                        .collect()
+                }
+                IDL::ALLIDS | IDL::Partial(_) => {
+                    let mut entries_filtered = Vec::new();
+                    for (i, e) in entries.into_iter().enumerate() {
+                        if i % CANCEL_CHECK_INTERVAL == 0 {
+                            optrack::check_cancelled()?;
+                            check_deadline(au, deadline)?;
+                        }
+                        if e.entry_match_no_index(&filt) {
+                            entries_filtered.push(e);
+                        }
+                    }
+                    entries_filtered
+                }
                 // Since the index fully resolved, we can shortcut the filter test step here!
                 IDL::Indexed(_) => entries,
             };
 
+            match limits.max_results {
+                Some(limit) if entries_filtered.len() > limit => {
+                    audit_log!(au, "search exceeded size limit {}, refusing", limit);
+                    return Err(OperationError::ResourceLimit);
+                }
+                _ => {}
+            };
+
+            let elapsed = start.elapsed();
+            let slowlog = self.get_slowlog();
+            if slowlog.is_slow(elapsed, allids) {
+                audit_log!(
+                    au,
+                    "slow search: {:?}, allids {}, {} candidates",
+                    elapsed,
+                    allids,
+                    candidates
+                );
+                slowlog.record(
+                    format!("{:?}", filt.to_inner()),
+                    elapsed,
+                    candidates,
+                    entries_filtered.len(),
+                    allids,
+                );
+            }
+
             /*
              // This is good for testing disagreements between the idl layer and the filter/entries
             if cfg!(test) {
                 let check_raw_entries = try_audit!(au, self.get_idlayer().get_identry(au, &IDL::ALLIDS));
                 let check_entries: Result<Vec<_>, _> =
-                    check_raw_entries.into_iter().map(|ide| ide.to_entry()).collect();
+                    check_raw_entries.into_iter().map(|ide| ide.to_entry(self.get_idlayer())).collect();
                 let check_entries = try_audit!(au, check_entries);
                 let f_check_entries: Vec<_> =
                     check_entries
@@ -365,7 +1165,7 @@ pub trait BackendTransaction {
 
             // Using the indexes, resolve the IDL here, or ALLIDS.
             // Also get if the filter was 100% resolved or not.
-            let idl = self.filter2idl(au, filt.to_inner(), FILTER_TEST_THRESHOLD)?;
+            let idl = self.filter2idl(au, filt.to_inner(), self.get_filter_test_threshold())?;
 
             // Now, check the idl -- if it's fully resolved, we can skip this because the query
             // was fully indexed.
@@ -374,10 +1174,7 @@ pub trait BackendTransaction {
                     return Ok(idl.len() > 0);
                 }
                 _ => {
-                    let raw_entries = try_audit!(au, self.get_idlayer().get_identry(au, &idl));
-                    let entries: Result<Vec<_>, _> =
-                        raw_entries.into_iter().map(|ide| ide.to_entry()).collect();
-                    let entries = try_audit!(au, entries);
+                    let entries = self.get_entries(au, &idl)?;
 
                     // if not 100% resolved query, apply the filter test.
                     let entries_filtered: Vec<_> = entries
@@ -391,946 +1188,5653 @@ pub trait BackendTransaction {
         }) // end audit segment
     }
 
-    fn verify(&self) -> Vec<Result<(), ConsistencyError>> {
-        Vec::new()
-    }
-
-    fn backup(&self, audit: &mut AuditScope, dst_path: &str) -> Result<(), OperationError> {
-        // load all entries into RAM, may need to change this later
-        // if the size of the database compared to RAM is an issue
-        let idl = IDL::ALLIDS;
-        let raw_entries: Vec<IdEntry> = self.get_idlayer().get_identry(audit, &idl)?;
-
-        let entries: Result<Vec<DbEntry>, _> = raw_entries
-            .iter()
-            .map(|id_ent| {
-                serde_cbor::from_slice(id_ent.data.as_slice())
-                    .map_err(|_| OperationError::SerdeJsonError)
-            })
-            .collect();
-
-        let entries = entries?;
+    /// How many entries match the filter, and whether it had to fall back to
+    /// allids to find them. When the indexes fully resolve the filter this is
+    /// just the length of the idl, and no entry is loaded.
+    fn count(
+        &self,
+        au: &mut AuditScope,
+        filt: &Filter<FilterValidResolved>,
+    ) -> Result<(u64, bool), OperationError> {
+        audit_segment!(au, || {
+            let filt = filt.optimise();
+            audit_log!(au, "filter optimised to --> {:?}", filt);
 
-        let serialized_entries = serde_json::to_string_pretty(&entries);
+            let idl = self.filter2idl(au, filt.to_inner(), self.get_filter_test_threshold())?;
 
-        let serialized_entries_str = try_audit!(
-            audit,
-            serialized_entries,
-            "serde error {:?}",
-            OperationError::SerdeJsonError
-        );
+            match &idl {
+                IDL::Indexed(idl) => Ok((idl.len() as u64, false)),
+                _ => {
+                    let allids = match idl {
+                        IDL::ALLIDS => true,
+                        _ => false,
+                    };
+                    let entries = self.get_entries(au, &idl)?;
+                    let count = entries
+                        .iter()
+                        .filter(|e| e.entry_match_no_index(&filt))
+                        .count();
+                    Ok((count as u64, allids))
+                }
+            }
+        })
+    }
 
-        let result = fs::write(dst_path, serialized_entries_str);
+    /// The uuid of the live entry with this name. This reads name2uuid alone,
+    /// so it's far cheaper than searching for the name.
+    fn name2uuid(&self, au: &mut AuditScope, name: &str) -> Result<Option<Uuid>, OperationError> {
+        // Names are stored as iname, so lower case.
+        self.get_idlayer()
+            .get_name2uuid(au, name.to_lowercase().as_str())
+    }
 
-        try_audit!(
-            audit,
-            result,
-            "fs::write error {:?}",
-            OperationError::FsError
-        );
+    /// The name of the live entry with this uuid, if it has one.
+    fn uuid2name(
+        &self,
+        au: &mut AuditScope,
+        uuid: &Uuid,
+    ) -> Result<Option<String>, OperationError> {
+        self.get_idlayer().get_uuid2name(au, uuid)
+    }
 
-        Ok(())
+    /// The spn (name@domain) of the live entry with this uuid, if it has one.
+    /// As with uuid2name this needs no entry to be loaded.
+    fn uuid2spn(&self, au: &mut AuditScope, uuid: &Uuid) -> Result<Option<String>, OperationError> {
+        self.get_idlayer().get_uuid2spn(au, uuid)
     }
-}
 
-impl BackendTransaction for BackendReadTransaction {
-    type IdlLayerType = IdlSqliteReadTransaction;
-    fn get_idlayer(&self) -> &IdlSqliteReadTransaction {
-        &self.idlayer
-    }
-}
+    /// Describe how a filter is resolved by the indexes, for debugging slow
+    /// searches. Each term is listed as filter2idl reached it, along with
+    /// where an and or an or stopped early. The cache is bypassed, so this
+    /// shows the work a miss would do.
+    fn explain(
+        &self,
+        au: &mut AuditScope,
+        filt: &Filter<FilterValidResolved>,
+    ) -> Result<SearchExplain, OperationError> {
+        audit_segment!(au, || {
+            let filt = filt.optimise();
+            let thres = self.get_filter_test_threshold();
+
+            let mut plan = Some(Vec::new());
+            let idl = self.filter2idl_plan(au, filt.to_inner(), thres, 0, &mut plan)?;
+            let entries = self.get_entries(au, &idl)?;
+            let candidates = entries.len();
+
+            let (filter_test, matched) = match idl {
+                IDL::ALLIDS | IDL::Partial(_) => (
+                    true,
+                    entries
+                        .iter()
+                        .filter(|e| e.entry_match_no_index(&filt))
+                        .count(),
+                ),
+                IDL::Indexed(_) => (false, entries.len()),
+            };
 
-impl BackendTransaction for BackendWriteTransaction {
-    type IdlLayerType = IdlSqliteWriteTransaction;
-    fn get_idlayer(&self) -> &IdlSqliteWriteTransaction {
-        &self.idlayer
+            Ok(SearchExplain {
+                filter: format!("{:?}", filt.to_inner()),
+                terms: plan.unwrap_or_default(),
+                result: idl.to_explain(),
+                threshold: thres,
+                candidates: candidates,
+                filter_test: filter_test,
+                matched: matched,
+            })
+        })
     }
-}
 
-impl BackendWriteTransaction {
-    pub fn create(
-        &mut self,
-        au: &mut AuditScope,
-        entries: Vec<Entry<EntryValid, EntryNew>>,
-    ) -> Result<Vec<Entry<EntryValid, EntryCommitted>>, OperationError> {
-        // figured we would want a audit_segment to wrap internal_create so when doing profiling we can
-        // tell which function is calling it. either this one or restore.
-        audit_segment!(au, || {
-            if entries.is_empty() {
-                audit_log!(
-                    au,
-                    "No entries provided to BE to create, invalid server call!"
-                );
-                return Err(OperationError::EmptyRequest);
-            }
+    fn get_idx_keys(
+        &self,
+        audit: &mut AuditScope,
+        attr: &String,
+        itype: &IndexType,
+    ) -> Result<Vec<(String, IDLBitRange)>, OperationError> {
+        self.get_idlayer().get_idx_keys(audit, attr, itype)
+    }
 
-            // Now, assign id's to all the new entries.
+    // Limits the database is under that will stop it working, or that an
+    // operator should know of, for status to report.
+    fn db_warnings(&self, audit: &mut AuditScope) -> Result<Vec<String>, OperationError> {
+        let (size, limit) = self.get_idlayer().get_db_size(audit)?;
+        let mut warnings = Vec::new();
+        if size >= limit / 100 * DB_SIZE_WARN_PERCENT {
+            warnings.push(format!(
+                "database is {} bytes, near its size limit of {} bytes",
+                size, limit
+            ));
+        }
+        if cfg!(target_pointer_width = "32") {
+            warnings.push("32 bit build, database is not memory mapped".to_string());
+        }
+        Ok(warnings)
+    }
 
-            let mut id_max = self.idlayer.get_id2entry_max_id().and_then(|id_max| {
-                u64::try_from(id_max).map_err(|_| OperationError::InvalidEntryID)
-            })?;
-            let c_entries: Vec<_> = entries
+    // Check the file itself is sound, then everything we keep in it. A file
+    // that fails its integrity check is still checked as far as it can be,
+    // as that shows what has been lost.
+    fn verify(&self, audit: &mut AuditScope) -> Vec<Result<(), ConsistencyError>> {
+        let mut results: Vec<_> = match self.get_idlayer().integrity_check(audit, false) {
+            Ok(problems) => problems
                 .into_iter()
-                .map(|e| {
-                    id_max = id_max + 1;
-                    e.to_valid_committed_id(id_max)
-                })
-                .collect();
-
-            let identries: Result<Vec<_>, _> = c_entries
-                .iter()
-                .map(|e| {
-                    let dbe = e.into_dbentry();
-                    let data =
-                        serde_cbor::to_vec(&dbe).map_err(|_| OperationError::SerdeCborError)?;
-
-                    Ok(IdEntry {
-                        id: i64::try_from(e.get_id())
-                            .map_err(|_| OperationError::InvalidEntryID)?,
-                        data: data,
-                    })
+                .map(|p| {
+                    audit_log!(audit, "verify: integrity check failed {}", p);
+                    Err(ConsistencyError::SqliteIntegrityFailure(p))
                 })
-                .collect();
-
-            self.idlayer.write_identries(au, identries?)?;
+                .collect(),
+            Err(e) => {
+                audit_log!(audit, "verify: unable to check integrity {:?}", e);
+                vec![Err(ConsistencyError::SqliteIntegrityFailure(format!(
+                    "{:?}",
+                    e
+                )))]
+            }
+        };
+        results.extend(self.verify_idxs(audit));
+        results
+    }
 
-            // Now update the indexes as required.
-            for e in c_entries.iter() {
-                self.entry_index(au, None, Some(e))?
+    // Check that the indexes agree with id2entry. The keys of each entry are
+    // generated as entry_index would, then compared to what the idx tables
+    // hold. A read txn has no idxmeta, so the set of indexes is taken from
+    // the tables that exist.
+    fn verify_idxs(&self, audit: &mut AuditScope) -> Vec<Result<(), ConsistencyError>> {
+        let idxmeta: BTreeSet<(String, IndexType)> = match self.get_idlayer().list_idxs(audit) {
+            Ok(tables) => tables.iter().filter_map(|t| parse_idx_table(t)).collect(),
+            Err(e) => {
+                audit_log!(audit, "verify: unable to list indexes {:?}", e);
+                return vec![Err(ConsistencyError::Unknown)];
             }
+        };
 
-            Ok(c_entries)
-        })
-    }
+        let mut results = Vec::new();
+        let mut all_ids = BTreeSet::new();
+        let mut expected: BTreeMap<(String, IndexType, String), BTreeSet<u64>> = BTreeMap::new();
+
+        let mut after = 0;
+        loop {
+            let batch = match self
+                .get_idlayer()
+                .get_identry_batch(audit, after, BACKUP_BATCH_SIZE)
+            {
+                Ok(b) => b,
+                Err(e) => {
+                    audit_log!(audit, "verify: unable to read id2entry {:?}", e);
+                    return vec![Err(ConsistencyError::Unknown)];
+                }
+            };
+            if batch.is_empty() {
+                break;
+            }
+            after = batch.last().map(|ide| ide.id).unwrap_or(after);
+
+            for ide in batch.into_iter() {
+                let id = ide.id;
+                all_ids.insert(id);
+                let e = match ide.to_entry(self.get_idlayer()) {
+                    Ok(e) => e,
+                    Err(_) => {
+                        results.push(Err(ConsistencyError::CorruptedEntry(id)));
+                        continue;
+                    }
+                };
+                for (attr, itype, key) in Entry::idx_diff(&idxmeta, None, Some(&e))
+                    .into_iter()
+                    .filter_map(|r| r.ok())
+                {
+                    expected
+                        .entry((attr.clone(), itype.clone(), key))
+                        .or_insert_with(BTreeSet::new)
+                        .insert(id);
+                }
+            }
+        }
 
-    pub fn modify(
-        &self,
-        au: &mut AuditScope,
-        pre_entries: &Vec<Entry<EntryValid, EntryCommitted>>,
-        post_entries: &Vec<Entry<EntryValid, EntryCommitted>>,
-    ) -> Result<(), OperationError> {
-        if post_entries.is_empty() || pre_entries.is_empty() {
-            audit_log!(
-                au,
-                "No entries provided to BE to modify, invalid server call!"
-            );
-            return Err(OperationError::EmptyRequest);
+        for (attr, itype) in idxmeta.iter() {
+            let keys = match self.get_idx_keys(audit, attr, itype) {
+                Ok(k) => k,
+                Err(e) => {
+                    audit_log!(audit, "verify: unable to read {:?} {} {:?}", itype, attr, e);
+                    results.push(Err(ConsistencyError::IndexUnreadable(
+                        attr.clone(),
+                        itype.as_idx_str().to_string(),
+                    )));
+                    continue;
+                }
+            };
+            for (key, idl) in keys.into_iter() {
+                let exp = expected
+                    .remove(&(attr.clone(), itype.clone(), key.clone()))
+                    .unwrap_or_else(BTreeSet::new);
+                let mut found = BTreeSet::new();
+                for id in &idl {
+                    found.insert(id);
+                    if !all_ids.contains(&id) {
+                        results.push(Err(ConsistencyError::IndexDanglingId(
+                            attr.clone(),
+                            itype.as_idx_str().to_string(),
+                            key.clone(),
+                            id,
+                        )));
+                    } else if !exp.contains(&id) {
+                        results.push(Err(ConsistencyError::IndexExtraId(
+                            attr.clone(),
+                            itype.as_idx_str().to_string(),
+                            key.clone(),
+                            id,
+                        )));
+                    }
+                }
+                exp.difference(&found).for_each(|id| {
+                    results.push(Err(ConsistencyError::IndexMissingId(
+                        attr.clone(),
+                        itype.as_idx_str().to_string(),
+                        key.clone(),
+                        *id,
+                    )))
+                });
+            }
         }
 
-        assert!(post_entries.len() == pre_entries.len());
+        // Anything left had no key at all in its index.
+        for ((attr, itype, key), ids) in expected.into_iter() {
+            for id in ids.into_iter() {
+                results.push(Err(ConsistencyError::IndexMissingId(
+                    attr.clone(),
+                    itype.as_idx_str().to_string(),
+                    key.clone(),
+                    id,
+                )));
+            }
+        }
 
-        // Assert the Id's exist on the entry, and serialise them.
-        // Now, that means the ID must be > 0!!!
-        let ser_entries: Result<Vec<IdEntry>, _> = post_entries
-            .iter()
-            .map(|e| {
-                let db_e = e.into_dbentry();
-
-                let id = i64::try_from(e.get_id())
-                    .map_err(|_| OperationError::InvalidEntryID)
-                    .and_then(|id| {
-                        if id == 0 {
-                            Err(OperationError::InvalidEntryID)
-                        } else {
-                            Ok(id)
-                        }
-                    })?;
+        audit_log!(
+            audit,
+            "verify: checked {} entries against {} indexes, {} errors",
+            all_ids.len(),
+            idxmeta.len(),
+            results.len()
+        );
+        results
+    }
 
-                let data = serde_cbor::to_vec(&db_e).map_err(|_| OperationError::SerdeCborError)?;
+    // The CID of each attribute of the entry that has one. Those without were
+    // last changed before CIDs were kept.
+    fn get_changestate(
+        &self,
+        audit: &mut AuditScope,
+        uuid: &Uuid,
+    ) -> Result<BTreeMap<String, Cid>, OperationError> {
+        self.get_idlayer().get_changestate(audit, uuid)
+    }
 
-                Ok(IdEntry { id: id, data: data })
-            })
-            .collect();
+    // The change sequence number of the latest write this txn can see.
+    fn get_change_marker(&self) -> i64 {
+        self.get_idlayer().get_change_marker()
+    }
 
-        let ser_entries = try_audit!(au, ser_entries);
+    // Where the last recorded backup got to, 0 if there has been none.
+    fn get_backup_marker(&self) -> i64 {
+        self.get_idlayer().get_backup_marker()
+    }
 
-        // Simple: If the list of id's is not the same as the input list, we are missing id's
-        //
-        // The entry state checks prevent this from really ever being triggered, but we
-        // still prefer paranoia :)
-        if post_entries.len() != ser_entries.len() {
-            return Err(OperationError::InvalidEntryState);
+    // Entries written after the change sequence number since, and the uuids
+    // of entries removed from the database after it. Recycled and tombstoned
+    // entries are returned like any other, it's up to the caller what they
+    // mean.
+    fn get_changed_since(
+        &self,
+        audit: &mut AuditScope,
+        since: i64,
+    ) -> Result<(Vec<Entry<EntryValid, EntryCommitted>>, Vec<String>), OperationError> {
+        let mut entries = Vec::new();
+        let mut after = 0;
+        loop {
+            let raw_entries =
+                self.get_idlayer()
+                    .get_identry_changed(audit, since, after, BACKUP_BATCH_SIZE)?;
+            let last_id = match raw_entries.last() {
+                Some(id_ent) => id_ent.id,
+                None => break,
+            };
+            for id_ent in raw_entries.into_iter() {
+                entries.push(try_audit!(audit, id_ent.to_entry(self.get_idlayer())));
+            }
+            after = last_id;
         }
+        let removed = self.get_idlayer().get_removed_since(audit, since)?;
+        audit_log!(
+            audit,
+            "{} entries changed and {} removed since {}",
+            entries.len(),
+            removed.len(),
+            since
+        );
+        Ok((entries, removed))
+    }
 
-        // Now, given the list of id's, update them
-        self.idlayer.write_identries(au, ser_entries)?;
-
-        // Finally, we now reindex all the changed entries. We do this by iterating and zipping
-        // over the set, because we know the list is in the same order.
-        pre_entries
-            .iter()
-            .zip(post_entries.iter())
-            .try_for_each(|(pre, post)| self.entry_index(au, Some(pre), Some(post)))
+    fn backup(&self, audit: &mut AuditScope, dst_path: &str) -> Result<(), OperationError> {
+        self.backup_ext(
+            audit,
+            dst_path,
+            BackupCompression::None,
+            BackupFormat::Json,
+            None,
+        )
     }
 
-    pub fn delete(
+    fn backup_ext(
         &self,
-        au: &mut AuditScope,
-        entries: &Vec<Entry<EntryValid, EntryCommitted>>,
+        audit: &mut AuditScope,
+        dst_path: &str,
+        compression: BackupCompression,
+        format: BackupFormat,
+        key: Option<&BackupKey>,
     ) -> Result<(), OperationError> {
-        // Perform a search for the entries --> This is a problem for the caller
-        audit_segment!(au, || {
-            if entries.is_empty() {
-                audit_log!(
-                    au,
-                    "No entries provided to BE to delete, invalid server call!"
-                );
-                return Err(OperationError::EmptyRequest);
-            }
-
-            // Assert the Id's exist on the entry.
-            let id_list: Result<Vec<i64>, _> = entries
-                .iter()
-                .map(|e| {
-                    i64::try_from(e.get_id())
-                        .map_err(|_| OperationError::InvalidEntryID)
-                        .and_then(|id| {
-                            if id == 0 {
-                                Err(OperationError::InvalidEntryID)
-                            } else {
-                                Ok(id)
-                            }
-                        })
-                })
-                .collect();
-
-            let id_list = try_audit!(au, id_list);
-
-            // Simple: If the list of id's is not the same as the input list, we are missing id's
-            if entries.len() != id_list.len() {
-                return Err(OperationError::InvalidEntryState);
-            }
-
-            // Now, given the list of id's, delete them.
-            self.idlayer.delete_identry(au, id_list)?;
+        self.backup_write(audit, dst_path, compression, format, key, None)
+    }
 
-            // Finally, purge the indexes from the entries we removed.
-            entries
-                .iter()
-                .try_for_each(|e| self.entry_index(au, Some(e), None))
-        })
+    // Only the entries written, and the uuids of those deleted, after since.
+    // The result is restored on top of the full backup it follows.
+    fn backup_incremental(
+        &self,
+        audit: &mut AuditScope,
+        dst_path: &str,
+        compression: BackupCompression,
+        key: Option<&BackupKey>,
+        since: i64,
+    ) -> Result<(), OperationError> {
+        self.backup_write(
+            audit,
+            dst_path,
+            compression,
+            BackupFormat::Json,
+            key,
+            Some(since),
+        )
     }
 
-    // Should take a mut index set, and then we write the whole thing back
-    // in a single stripe.
-    //
-    // So we need a cache, which we load indexes into as we do ops, then we
-    // modify them.
-    //
-    // At the end, we flush those cchange outs in a single run.
-    // For create this is probably a
-    fn entry_index(
+    // Compression happens before encryption, as ciphertext doesn't compress.
+    fn backup_write(
         &self,
         audit: &mut AuditScope,
-        pre: Option<&Entry<EntryValid, EntryCommitted>>,
-        post: Option<&Entry<EntryValid, EntryCommitted>>,
+        dst_path: &str,
+        compression: BackupCompression,
+        format: BackupFormat,
+        key: Option<&BackupKey>,
+        since: Option<i64>,
     ) -> Result<(), OperationError> {
-        let e_id = match (pre, post) {
-            (None, None) => {
-                audit_log!(audit, "Invalid call to entry_index - no entries provided");
-                return Err(OperationError::InvalidState);
+        let file = try_audit!(
+            audit,
+            fs::File::create(dst_path),
+            "fs::File::create error {:?}",
+            OperationError::FsError
+        );
+        let writer = BufWriter::new(file);
+
+        let r = match key {
+            None => self
+                .backup_compressed(audit, writer, compression, format, since)?
+                .flush(),
+            Some(key) => {
+                let enc = try_audit!(
+                    audit,
+                    EncryptWriter::new(writer, key),
+                    "encrypt error {:?}",
+                    OperationError::FsError
+                );
+                self.backup_compressed(audit, enc, compression, format, since)?
+                    .finish()
+                    .and_then(|mut w| w.flush())
             }
-            (Some(pre), None) => {
-                audit_log!(audit, "Attempting to remove indexes");
-                pre.get_id()
+        };
+        try_audit!(audit, r, "write error {:?}", OperationError::FsError);
+        Ok(())
+    }
+
+    // The encoders must be finished, not just dropped, or the tail of the
+    // stream is lost.
+    fn backup_compressed<W: Write>(
+        &self,
+        audit: &mut AuditScope,
+        writer: W,
+        compression: BackupCompression,
+        format: BackupFormat,
+        since: Option<i64>,
+    ) -> Result<W, OperationError> {
+        let r = match compression {
+            BackupCompression::None => {
+                let mut writer = writer;
+                self.backup_format(audit, &mut writer, format, since)?;
+                Ok(writer)
             }
-            (None, Some(post)) => {
-                audit_log!(audit, "Attempting to update indexes");
-                post.get_id()
+            BackupCompression::Gzip => {
+                let mut enc = GzEncoder::new(writer, Compression::default());
+                self.backup_format(audit, &mut enc, format, since)?;
+                enc.finish()
             }
-            (Some(pre), Some(post)) => {
-                audit_log!(audit, "Attempting to modify indexes");
-                assert!(pre.get_id() == post.get_id());
-                post.get_id()
+            BackupCompression::Zstd => {
+                let mut enc = try_audit!(
+                    audit,
+                    ZstdEncoder::new(writer, 0),
+                    "zstd error {:?}",
+                    OperationError::FsError
+                );
+                self.backup_format(audit, &mut enc, format, since)?;
+                enc.finish()
             }
         };
+        Ok(try_audit!(
+            audit,
+            r,
+            "write error {:?}",
+            OperationError::FsError
+        ))
+    }
 
-        let idx_diff = Entry::idx_diff(&self.idxmeta, pre, post);
-
-        idx_diff.iter()
-            .try_for_each(|act| {
-                match act {
-                    Ok((attr, itype, idx_key)) => {
-                        audit_log!(audit, "Adding {:?} idx -> {:?}: {:?}", itype, attr, idx_key);
-                        match self.idlayer.get_idl(audit, attr, itype, idx_key)? {
-                            Some(mut idl) => {
-                                idl.insert_id(e_id);
-                                self.idlayer.write_idl(audit, attr, itype, idx_key, &idl)
-                            }
-                            None => {
-                                audit_log!(
-                                    audit,
-                                    "WARNING: index {:?} {:?} was not found. YOU MUST REINDEX YOUR DATABASE",
-                                    attr, itype
-                                );
-                                Ok(())
-                            }
-                        }
-                    }
-                    Err((attr, itype, idx_key)) => {
-                        audit_log!(audit, "Removing {:?} idx -> {:?}: {:?}", itype, attr, idx_key);
-                        match self.idlayer.get_idl(audit, attr, itype, idx_key)? {
-                            Some(mut idl) => {
-                                idl.remove_id(e_id);
-                                self.idlayer.write_idl(audit, attr, itype, idx_key, &idl)
-                            }
-                            None => {
-                                audit_log!(
-                                    audit,
-                                    "WARNING: index {:?} {:?} was not found. YOU MUST REINDEX YOUR DATABASE",
-                                    attr, itype
-                                );
-                                Ok(())
-                            }
-                        }
-                    }
-                }
-            })
-        // End try_for_each
+    fn backup_format<W: Write>(
+        &self,
+        audit: &mut AuditScope,
+        writer: &mut W,
+        format: BackupFormat,
+        since: Option<i64>,
+    ) -> Result<(), OperationError> {
+        match (format, since) {
+            (BackupFormat::Json, _) => self.backup_to(audit, writer, since),
+            (BackupFormat::Ldif, None) => self.backup_ldif_to(audit, writer),
+            // Ldif content records can't also describe deletions.
+            (BackupFormat::Ldif, Some(_)) => {
+                audit_log!(audit, "incremental backups can't be written as ldif");
+                Err(OperationError::InvalidRequestState)
+            }
+        }
     }
 
-    #[allow(dead_code)]
-    fn missing_idxs(
+    fn backup_ldif_to<W: Write>(
         &self,
         audit: &mut AuditScope,
-    ) -> Result<Vec<(String, IndexType)>, OperationError> {
-        let idx_table_list = self.idlayer.list_idxs(audit)?;
+        writer: &mut W,
+    ) -> Result<(), OperationError> {
+        try_audit!(
+            audit,
+            writer.write_all(ldif::LDIF_VERSION),
+            "write error {:?}",
+            OperationError::FsError
+        );
 
-        // Turn the vec to a real set
-        let idx_table_set: BTreeSet<_> = idx_table_list.into_iter().collect();
+        let mut after = 0;
+        loop {
+            let raw_entries =
+                self.get_idlayer()
+                    .get_identry_batch(audit, after, BACKUP_BATCH_SIZE)?;
+            let last_id = match raw_entries.last() {
+                Some(id_ent) => id_ent.id,
+                None => break,
+            };
 
-        let missing: Vec<_> = self
-            .idxmeta
-            .iter()
-            .filter_map(|(attr, itype)| {
-                // what would the table name be?
-                let tname = format!("idx_{}_{}", itype.as_idx_str(), attr);
-                audit_log!(audit, "Checking for {}", tname);
+            for id_ent in raw_entries.iter() {
+                let dbe = try_audit!(audit, id_ent.to_dbentry(self.get_idlayer()));
+                try_audit!(
+                    audit,
+                    ldif::write_entry(&mut *writer, &dbe),
+                    "ldif error {:?}",
+                    OperationError::FsError
+                );
+            }
 
-                if idx_table_set.contains(&tname) {
-                    None
-                } else {
-                    Some((attr.clone(), itype.clone()))
-                }
-            })
-            .collect();
-        Ok(missing)
+            after = last_id;
+        }
+        Ok(())
     }
 
-    fn create_idxs(&self, audit: &mut AuditScope) -> Result<(), OperationError> {
-        // Create name2uuid and uuid2name
-        audit_log!(audit, "Creating index -> name2uuid");
-        self.idlayer.create_name2uuid(audit)?;
+    // One csv per class in dst_dir, all from this txn. An entry with several
+    // of the classes is written to each of their files.
+    fn export_csv(
+        &self,
+        audit: &mut AuditScope,
+        dst_dir: &str,
+        spec: &ExportSpec,
+    ) -> Result<(), OperationError> {
+        try_audit!(
+            audit,
+            fs::create_dir_all(dst_dir),
+            "fs::create_dir_all error {:?}",
+            OperationError::FsError
+        );
+        let mut writers = Vec::with_capacity(spec.classes.len());
+        for class in spec.classes.iter() {
+            let path = Path::new(dst_dir).join(format!("{}.csv", class));
+            let file = try_audit!(
+                audit,
+                fs::File::create(path),
+                "fs::File::create error {:?}",
+                OperationError::FsError
+            );
+            let mut writer = BufWriter::new(file);
+            try_audit!(
+                audit,
+                export::write_header(&mut writer, spec),
+                "write error {:?}",
+                OperationError::FsError
+            );
+            writers.push(writer);
+        }
+        let mut counts = vec![0; spec.classes.len()];
+
+        let mut after = 0;
+        loop {
+            let raw_entries =
+                self.get_idlayer()
+                    .get_identry_batch(audit, after, BACKUP_BATCH_SIZE)?;
+            let last_id = match raw_entries.last() {
+                Some(id_ent) => id_ent.id,
+                None => break,
+            };
 
-        audit_log!(audit, "Creating index -> uuid2name");
-        self.idlayer.create_uuid2name(audit)?;
+            for id_ent in raw_entries.iter() {
+                let dbe = try_audit!(audit, id_ent.to_dbentry(self.get_idlayer()));
+                for i in export::entry_classes(&dbe, spec) {
+                    try_audit!(
+                        audit,
+                        export::write_entry(&mut writers[i], &dbe, spec),
+                        "csv error {:?}",
+                        OperationError::FsError
+                    );
+                    counts[i] += 1;
+                }
+            }
 
-        self.idxmeta
-            .iter()
-            .try_for_each(|(attr, itype)| self.idlayer.create_idx(audit, attr, itype))
-    }
+            after = last_id;
+        }
 
-    pub fn upgrade_reindex(&self, audit: &mut AuditScope, v: i64) -> Result<(), OperationError> {
-        if self.get_db_index_version() < v {
-            self.reindex(audit)?;
+        for (i, mut writer) in writers.into_iter().enumerate() {
+            try_audit!(
+                audit,
+                writer.flush(),
+                "write error {:?}",
+                OperationError::FsError
+            );
+            audit_log!(audit, "exported {} {} entries", counts[i], spec.classes[i]);
         }
-        self.set_db_index_version(v)
+        Ok(())
     }
 
-    pub fn reindex(&self, audit: &mut AuditScope) -> Result<(), OperationError> {
-        // Purge the idxs
-        unsafe { self.idlayer.purge_idxs(audit)? };
+    fn backup_to<W: Write>(
+        &self,
+        audit: &mut AuditScope,
+        writer: &mut W,
+        since: Option<i64>,
+    ) -> Result<(), OperationError> {
+        // The output is one json array as it always has been, but we walk
+        // id2entry in batches and write each entry as we go, so memory use
+        // doesn't grow with the size of the database. An incremental backup
+        // is a DbIncremental, with the entries array streamed the same way.
+        let header = match since {
+            None => "[\n".to_string(),
+            Some(since) => {
+                let removed = self.get_idlayer().get_removed_since(audit, since)?;
+                let removed = try_audit!(
+                    audit,
+                    serde_json::to_string(&removed),
+                    "serde error {:?}",
+                    OperationError::SerdeJsonError
+                );
+                format!(
+                    "{{\"since\": {}, \"marker\": {}, \"removed\": {}, \"entries\": [\n",
+                    since,
+                    self.get_change_marker(),
+                    removed
+                )
+            }
+        };
+        try_audit!(
+            audit,
+            writer.write_all(header.as_bytes()),
+            "write error {:?}",
+            OperationError::FsError
+        );
 
-        // Using the index metadata on the txn, create all our idx tables
-        self.create_idxs(audit)?;
+        let mut after = 0;
+        let mut first = true;
+        loop {
+            let raw_entries = match since {
+                None => self
+                    .get_idlayer()
+                    .get_identry_batch(audit, after, BACKUP_BATCH_SIZE)?,
+                Some(since) => self.get_idlayer().get_identry_changed(
+                    audit,
+                    since,
+                    after,
+                    BACKUP_BATCH_SIZE,
+                )?,
+            };
+            let last_id = match raw_entries.last() {
+                Some(id_ent) => id_ent.id,
+                None => break,
+            };
+
+            for id_ent in raw_entries.iter() {
+                let dbe = try_audit!(audit, id_ent.to_dbentry(self.get_idlayer()));
+                if !first {
+                    try_audit!(
+                        audit,
+                        writer.write_all(b",\n"),
+                        "write error {:?}",
+                        OperationError::FsError
+                    );
+                }
+                first = false;
+                try_audit!(
+                    audit,
+                    serde_json::to_writer_pretty(&mut *writer, &dbe),
+                    "serde error {:?}",
+                    OperationError::SerdeJsonError
+                );
+            }
+
+            after = last_id;
+        }
 
-        // Now, we need to iterate over everything in id2entry and index them
-        // Future idea: Do this in batches of X amount to limit memory
-        // consumption.
-        let idl = IDL::ALLIDS;
-        let raw_entries = try_audit!(audit, self.idlayer.get_identry(audit, &idl));
-        let entries: Result<Vec<_>, _> =
-            raw_entries.into_iter().map(|ide| ide.to_entry()).collect();
-        let entries = try_audit!(audit, entries);
-
-        // WHEN do we update name2uuid and uuid2name?
-        // Do they become attrs of the idx_cache? Should that be a struct?
+        let footer: &[u8] = match since {
+            None => b"\n]\n",
+            Some(_) => b"\n]}\n",
+        };
         try_audit!(
             audit,
-            entries
-                .iter()
-                .try_for_each(|e| self.entry_index(audit, None, Some(e)))
+            writer.write_all(footer),
+            "write error {:?}",
+            OperationError::FsError
         );
+
         Ok(())
     }
+}
 
-    #[cfg(test)]
-    pub fn purge_idxs(&self, audit: &mut AuditScope) -> Result<(), OperationError> {
-        unsafe { self.idlayer.purge_idxs(audit) }
+impl BackendTransaction for BackendReadTransaction {
+    type IdlLayerType = BackendIdLayerRead;
+    fn get_idlayer(&self) -> &BackendIdLayerRead {
+        &self.idlayer
     }
 
-    #[cfg(test)]
-    pub fn load_test_idl(
+    fn get_filter_test_threshold(&self) -> usize {
+        self.filter_test_threshold
+    }
+
+    fn get_slowlog(&self) -> &SlowQueryLog {
+        &self.slowlog
+    }
+
+    fn get_filtercache(&self) -> Option<(&FilterCache, i64)> {
+        Some((&self.filtercache, self.marker))
+    }
+
+    fn get_entrycache(&self) -> Option<(&EntryCache, i64)> {
+        Some((&self.entrycache, self.marker))
+    }
+
+    fn get_idl(
         &self,
         audit: &mut AuditScope,
         attr: &String,
         itype: &IndexType,
         idx_key: &String,
     ) -> Result<Option<IDLBitRange>, OperationError> {
+        self.usage.hit(attr, itype, idx_key);
         self.idlayer.get_idl(audit, attr, itype, idx_key)
     }
 
-    pub fn restore(
-        &mut self,
-        audit: &mut AuditScope,
-        src_path: &str,
-    ) -> Result<(), OperationError> {
-        // load all entries into RAM, may need to change this later
-        // if the size of the database compared to RAM is an issue
-        let serialized_string_option = fs::read_to_string(src_path);
-
-        let serialized_string = try_audit!(
-            audit,
-            serialized_string_option,
-            "fs::read_to_string {:?}",
-            OperationError::FsError
-        );
+    fn note_allids(&self, attr: &String, itype: &IndexType) {
+        self.usage.allids(attr, itype);
+    }
 
-        try_audit!(audit, unsafe { self.idlayer.purge_id2entry(audit) });
+    fn note_access(&self, uuid: &Uuid, identity: &str, operation: &str) {
+        self.journal.record(uuid, identity, operation);
+    }
+}
 
-        let dbentries_option: Result<Vec<DbEntry>, serde_json::Error> =
-            serde_json::from_str(&serialized_string);
+impl BackendReadTransaction {
+    // The domain record is written the first time the server starts, so is
+    // always there by the time anyone can ask.
+    pub fn get_domain_info(&self) -> Result<DomainInfo, OperationError> {
+        self.idlayer
+            .get_db_domain()?
+            .ok_or(OperationError::InvalidDBState)
+    }
 
-        let dbentries = try_audit!(
-            audit,
-            dbentries_option,
-            "serde_json error {:?}",
-            OperationError::SerdeJsonError
-        );
+    // For operators deciding what to index. See IndexStat.
+    pub fn index_stats(&self, audit: &mut AuditScope) -> Result<Vec<IndexStat>, OperationError> {
+        self.idlayer.get_idx_stats(audit)
+    }
 
-        // Now, we setup all the entries with new ids.
-        let mut id_max = 0;
-        let identries: Result<Vec<IdEntry>, _> = dbentries
-            .iter()
-            .map(|ser_db_e| {
-                id_max = id_max + 1;
-                let data =
-                    serde_cbor::to_vec(&ser_db_e).map_err(|_| OperationError::SerdeCborError)?;
-
-                Ok(IdEntry {
-                    id: id_max,
-                    data: data,
-                })
+    // The access journal as written so far, with the id of the first record
+    // that doesn't chain from those before it, if any. Records still waiting
+    // to be flushed aren't included.
+    pub fn access_journal(
+        &self,
+        audit: &mut AuditScope,
+    ) -> Result<AccessJournalResponse, OperationError> {
+        let journal = self.idlayer.get_access_journal(audit)?;
+        let broken_at = journal::first_broken(journal.as_slice());
+        if let Some(id) = broken_at {
+            audit_log!(audit, "access journal chain broken at {}", id);
+        }
+        let records = journal
+            .into_iter()
+            .map(|(id, rec, hash)| AccessRecord {
+                id: id as u64,
+                time: rec.time,
+                uuid: rec.uuid,
+                identity: rec.identity,
+                operation: rec.operation,
+                hash: hash,
             })
             .collect();
+        Ok(AccessJournalResponse {
+            records: records,
+            broken_at: broken_at.map(|id| id as u64),
+        })
+    }
 
-        self.idlayer.write_identries(audit, identries?)?;
+    // What the most recent write txns wrote, newest first.
+    pub fn write_stats(&self) -> Vec<WriteStatsRecord> {
+        self.writestats.recent()
+    }
 
-        // Reindex now we are loaded.
-        self.reindex(audit)?;
+    // One page of the entries matching filt, and the cursor for the next
+    // page if there is one. The first page resolves the candidates, and
+    // sorts them if asked, which the cursor keeps, so each later page is only
+    // those ids loaded and tested again - an entry added since won't appear,
+    // and one that changed to no longer match or was deleted since is
+    // skipped.
+    pub fn search_page(
+        &self,
+        au: &mut AuditScope,
+        filt: &Filter<FilterValidResolved>,
+        sort: Option<&SortSpec>,
+        owner: Option<&Uuid>,
+        page_size: usize,
+        cursor: Option<&str>,
+        limits: &SearchLimits,
+    ) -> Result<(Vec<Entry<EntryValid, EntryCommitted>>, Option<String>), OperationError> {
+        audit_segment!(au, || {
+            let page_size = page_size.max(1).min(SEARCH_PAGE_MAX);
+            let (cursor_id, ids, offset) = match cursor {
+                Some(token) => {
+                    let cursor = parse_cursor_token(token).and_then(|(id, offset)| {
+                        self.cursors
+                            .get(&id, owner, filt, sort)
+                            .map(|ids| (Some(id), ids, offset))
+                    });
+                    match cursor {
+                        Some(c) if c.2 <= c.1.len() => c,
+                        _ => {
+                            audit_log!(au, "search cursor {} is unknown or expired", token);
+                            return Err(OperationError::InvalidSearchCursor);
+                        }
+                    }
+                }
+                None => {
+                    let idl = self.filter2idl(
+                        au,
+                        filt.optimise().to_inner(),
+                        self.filter_test_threshold,
+                    )?;
+                    let ids: Vec<u64> = match &idl {
+                        IDL::Indexed(i) => match limits.max_results {
+                            Some(limit) if i.len() > limit => {
+                                audit_log!(
+                                    au,
+                                    "search would exceed size limit {}, refusing",
+                                    limit
+                                );
+                                return Err(OperationError::ResourceLimit);
+                            }
+                            _ => match sort {
+                                Some(sort) => self.sort_ids(au, i.into_iter().collect(), sort)?,
+                                None => i.into_iter().collect(),
+                            },
+                        },
+                        // The entries are loaded to be tested, so sort them
+                        // as they are.
+                        _ => {
+                            let entries = self.search_limited(au, filt, limits)?;
+                            let entries = match sort {
+                                Some(sort) => sort.sort_entries(entries),
+                                None => entries,
+                            };
+                            entries.iter().map(|e| e.get_id()).collect()
+                        }
+                    };
+                    audit_log!(au, "paged search resolved {} candidates", ids.len());
+                    (None, Arc::new(ids), 0)
+                }
+            };
 
-        let vr = self.verify();
-        if vr.len() == 0 {
-            Ok(())
-        } else {
-            Err(OperationError::ConsistencyError(vr))
-        }
+            let end = (offset + page_size).min(ids.len());
+            let page_ids = &ids[offset..end];
+            let page = IDL::Indexed(IDLBitRange::from_iter(page_ids.iter().cloned()));
+            let filt_opt = filt.optimise();
+            let mut found: BTreeMap<u64, _> = self
+                .get_entries(au, &page)?
+                .into_iter()
+                .filter(|e| e.entry_match_no_index(&filt_opt))
+                .map(|e| (e.get_id(), e))
+                .collect();
+            // Back in the order of the candidates, rather than of their ids.
+            let entries: Vec<_> = page_ids.iter().filter_map(|id| found.remove(id)).collect();
+
+            let next = if end < ids.len() {
+                let id = cursor_id
+                    .unwrap_or_else(|| self.cursors.insert(ids.clone(), owner, filt, sort));
+                Some(cursor_token(&id, end))
+            } else {
+                // That was the last page.
+                if let Some(id) = cursor_id {
+                    self.cursors.remove(&id);
+                }
+                None
+            };
+            Ok((entries, next))
+        })
     }
 
-    pub fn commit(self, audit: &mut AuditScope) -> Result<(), OperationError> {
-        self.idlayer.commit(audit)
+    // The limit most recent slow or allids searches, newest first. Those
+    // still waiting to be flushed aren't included.
+    pub fn slow_queries(
+        &self,
+        audit: &mut AuditScope,
+        limit: usize,
+    ) -> Result<Vec<SlowQueryRecord>, OperationError> {
+        self.idlayer.get_slow_queries(audit, limit)
     }
 
-    fn reset_db_sid(&self) -> Result<SID, OperationError> {
-        // The value is missing. Generate a new one and store it.
-        let mut nsid = [0; 4];
-        let mut rng = StdRng::from_entropy();
-        rng.fill(&mut nsid);
-
-        self.idlayer.write_db_sid(&nsid)?;
-
-        Ok(nsid)
+    // The limit most recent runs of the report, newest first.
+    pub fn reports(
+        &self,
+        audit: &mut AuditScope,
+        report: &str,
+        limit: usize,
+    ) -> Result<Vec<ReportRecord>, OperationError> {
+        self.idlayer.get_reports(audit, report, limit)
     }
 
-    #[allow(dead_code)]
-    fn get_db_sid(&self) -> SID {
-        match self.get_idlayer().get_db_sid().expect("DBLayer Error!!!") {
-            Some(sid) => sid,
-            None => self.reset_db_sid().expect("Failed to regenerate SID"),
+    // Check the changelog, returning how many records it holds and the id of
+    // the first that fails, if any. Without the key only the chain is checked.
+    pub fn verify_changelog(
+        &self,
+        audit: &mut AuditScope,
+        key: Option<&[u8; 32]>,
+    ) -> Result<(usize, Option<i64>), OperationError> {
+        let rows = self.idlayer.get_changelog(audit)?;
+        let invalid_at = changelog::first_invalid(rows.as_slice(), key);
+        if let Some(id) = invalid_at {
+            audit_log!(audit, "changelog invalid at {}", id);
         }
+        Ok((rows.len(), invalid_at))
     }
+}
 
-    fn get_db_index_version(&self) -> i64 {
-        self.get_idlayer().get_db_index_version()
+impl BackendTransaction for BackendWriteTransaction {
+    type IdlLayerType = BackendIdLayerWrite;
+    fn get_idlayer(&self) -> &BackendIdLayerWrite {
+        &self.idlayer
     }
 
-    fn set_db_index_version(&self, v: i64) -> Result<(), OperationError> {
-        self.get_idlayer().set_db_index_version(v)
+    fn get_filter_test_threshold(&self) -> usize {
+        self.filter_test_threshold
     }
-}
 
-// In the future this will do the routing between the chosen backends etc.
-impl Backend {
-    pub fn new(audit: &mut AuditScope, path: &str, pool_size: u32) -> Result<Self, OperationError> {
-        // this has a ::memory() type, but will path == "" work?
-        audit_segment!(audit, || {
-            let be = Backend {
-                idlayer: IdlSqlite::new(audit, path, pool_size)?,
-            };
+    fn get_slowlog(&self) -> &SlowQueryLog {
+        &self.slowlog
+    }
 
-            // Now complete our setup with a txn
-            // In this case we can use an empty idx meta because we don't
-            // access any parts of
-            // the indexing subsystem here.
-            let r = {
-                let idl_write = be.idlayer.write();
-                idl_write.setup(audit).and_then(|_| idl_write.commit(audit))
-            };
+    fn note_access(&self, uuid: &Uuid, identity: &str, operation: &str) {
+        self.journal.record(uuid, identity, operation);
+    }
 
-            audit_log!(audit, "be new setup: {:?}", r);
+    // As get_idl, verify must see our uncommitted index changes.
+    fn get_idx_keys(
+        &self,
+        audit: &mut AuditScope,
+        attr: &String,
+        itype: &IndexType,
+    ) -> Result<Vec<(String, IDLBitRange)>, OperationError> {
+        let mut keys: BTreeMap<String, IDLBitRange> = self
+            .idlayer
+            .get_idx_keys(audit, attr, itype)?
+            .into_iter()
+            .collect();
+        self.idxcache
+            .borrow()
+            .iter()
+            .filter(|((a, i, _), _)| a == attr && i == itype)
+            .for_each(|((_, _, k), idl)| {
+                keys.insert(k.clone(), idl.clone());
+            });
+        Ok(keys.into_iter().collect())
+    }
 
-            match r {
-                Ok(_) => Ok(be),
-                Err(e) => Err(e),
-            }
-        })
+    // As get_idl, a range must see our uncommitted index changes.
+    fn get_idx_range(
+        &self,
+        audit: &mut AuditScope,
+        attr: &String,
+        itype: &IndexType,
+        lower: Option<&String>,
+        upper: Option<&String>,
+    ) -> Result<Option<Vec<(String, IDLBitRange)>>, OperationError> {
+        let mut keys: BTreeMap<String, IDLBitRange> = match self
+            .idlayer
+            .get_idx_range(audit, attr, itype, lower, upper)?
+        {
+            Some(keys) => keys.into_iter().collect(),
+            None => return Ok(None),
+        };
+        self.idxcache
+            .borrow()
+            .iter()
+            .filter(|((a, i, k), _)| {
+                a == attr
+                    && i == itype
+                    && lower.map(|l| k >= l).unwrap_or(true)
+                    && upper.map(|u| k <= u).unwrap_or(true)
+            })
+            .for_each(|((_, _, k), idl)| {
+                keys.insert(k.clone(), idl.clone());
+            });
+        Ok(Some(keys.into_iter().collect()))
     }
 
-    pub fn read(&self) -> BackendReadTransaction {
-        BackendReadTransaction {
-            idlayer: self.idlayer.read(),
+    // Searches inside the txn must see our uncommitted index changes.
+    fn get_idl(
+        &self,
+        audit: &mut AuditScope,
+        attr: &String,
+        itype: &IndexType,
+        idx_key: &String,
+    ) -> Result<Option<IDLBitRange>, OperationError> {
+        // The table is there, but empty until commit.
+        if self
+            .backfill
+            .borrow()
+            .contains(&(attr.clone(), itype.clone()))
+        {
+            return Ok(None);
+        }
+        let cache_key = (attr.clone(), itype.clone(), idx_key.clone());
+        match self.idxcache.borrow().get(&cache_key) {
+            Some(idl) => Ok(Some(idl.clone())),
+            None => self.idlayer.get_idl(audit, attr, itype, idx_key),
         }
     }
+}
 
-    pub fn write(&self, idxmeta: BTreeSet<(String, IndexType)>) -> BackendWriteTransaction {
-        BackendWriteTransaction {
-            idlayer: self.idlayer.write(),
-            idxmeta: idxmeta,
+impl BackendWriteTransaction {
+    // All changes to id2entry go through these, so that we know what to drop
+    // from the entrycache at commit.
+    fn write_identries(
+        &self,
+        au: &mut AuditScope,
+        identries: Vec<IdEntry>,
+    ) -> Result<(), OperationError> {
+        if let Some(dirty) = self.dirty.borrow_mut().as_mut() {
+            dirty.extend(identries.iter().map(|ide| ide.id));
+        }
+        {
+            let mut stats = self.stats.borrow_mut();
+            stats.entries += identries.len() as u64;
+            stats.id2entry_bytes += identries
+                .iter()
+                .map(|ide| ide.data.len() as u64)
+                .sum::<u64>();
         }
+        self.idlayer.write_identries(au, identries)
     }
 
-    // Should this actually call the idlayer directly?
-    pub fn reset_db_sid(&self, audit: &mut AuditScope) -> SID {
-        let wr = self.write(BTreeSet::new());
-        let sid = wr.reset_db_sid().unwrap();
-        wr.commit(audit).unwrap();
-        sid
+    fn delete_identry(&self, au: &mut AuditScope, idl: Vec<u64>) -> Result<(), OperationError> {
+        if let Some(dirty) = self.dirty.borrow_mut().as_mut() {
+            dirty.extend(idl.iter().cloned());
+        }
+        self.idlayer.delete_identry(au, idl)
     }
 
-    pub fn get_db_sid(&self) -> SID {
-        let wr = self.write(BTreeSet::new());
-        wr.reset_db_sid().unwrap()
+    // Serialise db_e to be written as id, moving its large values to
+    // id2blob. Ids are allocated from 1, so id 0 is an entry that was never
+    // committed.
+    fn to_identry(
+        &self,
+        au: &mut AuditScope,
+        id: u64,
+        db_e: DbEntry,
+    ) -> Result<IdEntry, OperationError> {
+        if id == 0 {
+            return Err(OperationError::InvalidEntryID);
+        }
+        let (db_e, refs) =
+            db_e.externalise(|hash, data| self.idlayer.write_blob(au, hash, data))?;
+        self.idlayer.write_blob_refs(au, id, refs.as_slice())?;
+        Ok(IdEntry {
+            id: id,
+            data: db_e.to_blob(self.entry_format)?,
+        })
     }
-}
 
-// What are the possible actions we'll recieve here?
+    unsafe fn purge_id2entry(&self, audit: &mut AuditScope) -> Result<(), OperationError> {
+        self.dirty.replace(None);
+        self.idlayer.purge_id2entry(audit)
+    }
 
-#[cfg(test)]
-mod tests {
+    pub fn create(
+        &mut self,
+        au: &mut AuditScope,
+        entries: Vec<Entry<EntryValid, EntryNew>>,
+    ) -> Result<Vec<Entry<EntryValid, EntryCommitted>>, OperationError> {
+        self.create_ext(au, entries, true)
+    }
 
-    use idlset::IDLBitRange;
-    use std::collections::BTreeSet;
-    use std::fs;
-    use std::iter::FromIterator;
+    // Without index, the entries are written to id2entry only, and nothing
+    // that reads an index finds them until the next reindex. Only a bulk
+    // import that reindexes when it's done should ask for that.
+    pub fn create_ext(
+        &mut self,
+        au: &mut AuditScope,
+        entries: Vec<Entry<EntryValid, EntryNew>>,
+        index: bool,
+    ) -> Result<Vec<Entry<EntryValid, EntryCommitted>>, OperationError> {
+        // figured we would want a audit_segment to wrap internal_create so when doing profiling we can
+        // tell which function is calling it. either this one or restore.
+        audit_segment!(au, || {
+            if entries.is_empty() {
+                audit_log!(
+                    au,
+                    "No entries provided to BE to create, invalid server call!"
+                );
+                return Err(OperationError::EmptyRequest);
+            }
 
-    use super::super::audit::AuditScope;
-    use super::super::entry::{Entry, EntryInvalid, EntryNew};
-    use super::{Backend, BackendTransaction, BackendWriteTransaction, OperationError, IDL};
-    use crate::value::{IndexType, PartialValue, Value};
+            // Now, assign id's to all the new entries.
 
-    macro_rules! run_test {
-        ($test_fn:expr) => {{
-            use env_logger;
-            ::std::env::set_var("RUST_LOG", "kanidm=debug");
-            let _ = env_logger::builder().is_test(true).try_init();
+            let mut id_max = self.idlayer.get_id2entry_max_id()?;
+            let c_entries: Vec<_> = entries
+                .into_iter()
+                .map(|e| {
+                    id_max = id_max + 1;
+                    e.to_valid_committed_id(id_max)
+                })
+                .collect();
 
-            let mut audit = AuditScope::new("run_test");
+            let identries: Result<Vec<_>, _> = c_entries
+                .iter()
+                .map(|e| self.to_identry(au, e.get_id(), e.into_dbentry()))
+                .collect();
 
-            let be = Backend::new(&mut audit, "", 1).expect("Failed to setup backend");
+            self.write_identries(au, identries?)?;
+            self.capture_op(au, |c| {
+                CaptureOp::Write(
+                    c_entries
+                        .iter()
+                        .map(|e| (e.get_id(), c.anonymise(e.into_dbentry())))
+                        .collect(),
+                )
+            });
 
-            // This is a demo idxmeta, purely for testing.
-            let mut idxmeta = BTreeSet::new();
-            idxmeta.insert(("name".to_string(), IndexType::EQUALITY));
-            idxmeta.insert(("name".to_string(), IndexType::PRESENCE));
-            idxmeta.insert(("name".to_string(), IndexType::SUBSTRING));
-            idxmeta.insert(("uuid".to_string(), IndexType::EQUALITY));
-            idxmeta.insert(("uuid".to_string(), IndexType::PRESENCE));
-            idxmeta.insert(("ta".to_string(), IndexType::EQUALITY));
-            idxmeta.insert(("tb".to_string(), IndexType::EQUALITY));
-            let mut be_txn = be.write(idxmeta);
-
-            // Could wrap another future here for the future::ok bit...
-            let r = $test_fn(&mut audit, &mut be_txn);
-            // Commit, to guarantee it worked.
-            assert!(be_txn.commit(&mut audit).is_ok());
-            println!("{}", audit);
-            r
-        }};
-    }
+            // Now update the indexes as required.
+            for e in c_entries.iter() {
+                if index {
+                    self.entry_index(au, None, Some(e))?;
+                }
+                self.changes.borrow_mut().record(None, Some(e));
+                self.changestate.borrow_mut().record(None, Some(e));
+            }
 
-    macro_rules! entry_exists {
-        ($audit:expr, $be:expr, $ent:expr) => {{
-            let ei = unsafe { $ent.clone().to_valid_committed() };
-            let filt = unsafe {
-                ei.filter_from_attrs(&vec![String::from("userid")])
-                    .expect("failed to generate filter")
-                    .to_valid_resolved()
-            };
-            let entries = $be.search($audit, &filt).expect("failed to search");
-            entries.first().is_some()
-        }};
+            Ok(c_entries)
+        })
     }
 
-    macro_rules! entry_attr_pres {
-        ($audit:expr, $be:expr, $ent:expr, $attr:expr) => {{
-            let ei = unsafe { $ent.clone().to_valid_committed() };
-            let filt = unsafe {
-                ei.filter_from_attrs(&vec![String::from("userid")])
-                    .expect("failed to generate filter")
-                    .to_valid_resolved()
-            };
-            let entries = $be.search($audit, &filt).expect("failed to search");
-            match entries.first() {
-                Some(ent) => ent.attribute_pres($attr),
-                None => false,
-            }
-        }};
-    }
+    pub fn modify(
+        &self,
+        au: &mut AuditScope,
+        pre_entries: &Vec<Entry<EntryValid, EntryCommitted>>,
+        post_entries: &Vec<Entry<EntryValid, EntryCommitted>>,
+    ) -> Result<(), OperationError> {
+        if post_entries.is_empty() || pre_entries.is_empty() {
+            audit_log!(
+                au,
+                "No entries provided to BE to modify, invalid server call!"
+            );
+            return Err(OperationError::EmptyRequest);
+        }
 
-    macro_rules! idl_state {
-        ($audit:expr, $be:expr, $attr:expr, $itype:expr, $idx_key:expr, $expect:expr) => {{
-            let t_idl = $be
-                .load_test_idl($audit, &$attr.to_string(), &$itype, &$idx_key.to_string())
-                .expect("IDL Load failed");
-            let t = $expect.map(|v: Vec<u64>| IDLBitRange::from_iter(v));
-            assert_eq!(t_idl, t);
-        }};
-    }
+        assert!(post_entries.len() == pre_entries.len());
 
-    #[test]
-    fn test_be_simple_create() {
-        run_test!(|audit: &mut AuditScope, be: &mut BackendWriteTransaction| {
-            audit_log!(audit, "Simple Create");
+        // Serialise them. to_identry checks the ids are ones we allocated.
+        let ser_entries: Result<Vec<IdEntry>, _> = post_entries
+            .iter()
+            .map(|e| self.to_identry(au, e.get_id(), e.into_dbentry()))
+            .collect();
 
-            let empty_result = be.create(audit, Vec::new());
-            audit_log!(audit, "{:?}", empty_result);
-            assert_eq!(empty_result, Err(OperationError::EmptyRequest));
+        let ser_entries = try_audit!(au, ser_entries);
 
-            let mut e: Entry<EntryInvalid, EntryNew> = Entry::new();
-            e.add_ava("userid", &Value::from("william"));
-            e.add_ava("uuid", &Value::from("db237e8a-0079-4b8c-8a56-593b22aa44d1"));
-            let e = unsafe { e.to_valid_new() };
+        // Simple: If the list of id's is not the same as the input list, we are missing id's
+        //
+        // The entry state checks prevent this from really ever being triggered, but we
+        // still prefer paranoia :)
+        if post_entries.len() != ser_entries.len() {
+            return Err(OperationError::InvalidEntryState);
+        }
 
-            let single_result = be.create(audit, vec![e.clone()]);
+        // Entries the modify didn't actually change - such as when a sync agent
+        // replays state we already hold - don't need to be written or reindexed.
+        // Entries are normalised, so comparing them is enough to know this.
+        let (changed, ser_entries): (Vec<_>, Vec<_>) = pre_entries
+            .iter()
+            .zip(post_entries.iter())
+            .zip(ser_entries.into_iter())
+            .filter(|((pre, post), _)| pre != post)
+            .unzip();
 
-            assert!(single_result.is_ok());
+        if changed.len() != post_entries.len() {
+            audit_log!(
+                au,
+                "Skipping {} unchanged entries",
+                post_entries.len() - changed.len()
+            );
+        }
+        if changed.is_empty() {
+            return Ok(());
+        }
 
-            // Construct a filter
-            assert!(entry_exists!(audit, be, e));
+        // Now, given the list of id's, update them
+        self.write_identries(au, ser_entries)?;
+        self.capture_op(au, |c| {
+            CaptureOp::Write(
+                changed
+                    .iter()
+                    .map(|(_, post)| (post.get_id(), c.anonymise(post.into_dbentry())))
+                    .collect(),
+            )
         });
-    }
-
-    #[test]
-    fn test_be_simple_search() {
-        run_test!(|audit: &mut AuditScope, be: &mut BackendWriteTransaction| {
-            audit_log!(audit, "Simple Search");
 
-            let mut e: Entry<EntryInvalid, EntryNew> = Entry::new();
-            e.add_ava("userid", &Value::from("claire"));
-            e.add_ava("uuid", &Value::from("db237e8a-0079-4b8c-8a56-593b22aa44d1"));
-            let e = unsafe { e.to_valid_new() };
+        // Finally, we now reindex all the changed entries. We do this by iterating and zipping
+        // over the set, because we know the list is in the same order.
+        changed.into_iter().try_for_each(|(pre, post)| {
+            self.changes.borrow_mut().record(Some(pre), Some(post));
+            self.changestate.borrow_mut().record(Some(pre), Some(post));
+            self.entry_index(au, Some(pre), Some(post))
+        })
+    }
 
-            let single_result = be.create(audit, vec![e.clone()]);
-            assert!(single_result.is_ok());
-            // Test a simple EQ search
+    // Apply each modlist to the current content of the entry with that uuid
+    // and write the results, returning the post entries. The entries are read
+    // here in the txn, so callers don't need to have searched and copied them
+    // first. This is a backend primitive - no plugins are run.
+    pub fn modify_modlist(
+        &self,
+        au: &mut AuditScope,
+        schema: &dyn SchemaTransaction,
+        changes: &[(Uuid, ModifyList<ModifyValid>)],
+    ) -> Result<Vec<Entry<EntryValid, EntryCommitted>>, OperationError> {
+        audit_segment!(au, || {
+            if changes.is_empty() {
+                audit_log!(
+                    au,
+                    "No entries provided to BE to modify, invalid server call!"
+                );
+                return Err(OperationError::EmptyRequest);
+            }
 
-            let filt =
-                unsafe { filter_resolved!(f_eq("userid", PartialValue::new_utf8s("claire"))) };
+            // A second change to the same entry would be applied to the
+            // original, not the result of the first, so refuse it.
+            let uuids: BTreeSet<&Uuid> = changes.iter().map(|(u, _)| u).collect();
+            if uuids.len() != changes.len() {
+                audit_log!(au, "modify_modlist: duplicate uuid in changes");
+                return Err(OperationError::InvalidRequestState);
+            }
 
-            let r = be.search(audit, &filt);
-            assert!(r.expect("Search failed!").len() == 1);
+            let pre_entries = self.get_uuid_entries(au, uuids.into_iter())?;
 
-            // Test empty search
+            let post_entries: Result<Vec<_>, _> = changes
+                .iter()
+                .map(|(u, modlist)| {
+                    let pre = pre_entries.get(u).ok_or_else(|| {
+                        audit_log!(au, "modify_modlist: {:?} not found", u);
+                        OperationError::NoMatchingEntries
+                    })?;
+                    let mut post = pre.clone().invalidate();
+                    post.apply_modlist(modlist);
+                    post.validate(schema)
+                        .map_err(|e| OperationError::SchemaViolation(e))
+                })
+                .collect();
+            let post_entries = try_audit!(au, post_entries);
 
-            // Test class pres
+            let pre_entries: Vec<_> = changes
+                .iter()
+                .filter_map(|(u, _)| pre_entries.get(u).cloned())
+                .collect();
 
-            // Search with no results
-        });
+            self.modify(au, &pre_entries, &post_entries)?;
+            Ok(post_entries)
+        })
     }
 
-    #[test]
-    fn test_be_simple_modify() {
-        run_test!(|audit: &mut AuditScope, be: &mut BackendWriteTransaction| {
-            audit_log!(audit, "Simple Modify");
-            // First create some entries (3?)
-            let mut e1: Entry<EntryInvalid, EntryNew> = Entry::new();
-            e1.add_ava("userid", &Value::from("william"));
-            e1.add_ava("uuid", &Value::from("db237e8a-0079-4b8c-8a56-593b22aa44d1"));
-
-            let mut e2: Entry<EntryInvalid, EntryNew> = Entry::new();
-            e2.add_ava("userid", &Value::from("alice"));
-            e2.add_ava("uuid", &Value::from("4b6228ab-1dbe-42a4-a9f5-f6368222438e"));
-
-            let ve1 = unsafe { e1.clone().to_valid_new() };
-            let ve2 = unsafe { e2.clone().to_valid_new() };
+    // Read the current entries for a set of uuids, via the uuid index if we
+    // have one.
+    fn get_uuid_entries<'a, I>(
+        &self,
+        au: &mut AuditScope,
+        uuids: I,
+    ) -> Result<BTreeMap<Uuid, Entry<EntryValid, EntryCommitted>>, OperationError>
+    where
+        I: Iterator<Item = &'a Uuid>,
+    {
+        let uuid_attr = "uuid".to_string();
+        let mut wanted = BTreeSet::new();
+        let mut idl = Some(IDLBitRange::new());
+        for u in uuids {
+            let idx_key = u.to_hyphenated_ref().to_string();
+            idl = match (
+                idl,
+                self.get_idl(au, &uuid_attr, &IndexType::EQUALITY, &idx_key)?,
+            ) {
+                (Some(acc), Some(u_idl)) => Some(acc | u_idl),
+                _ => None,
+            };
+            wanted.insert(u.clone());
+        }
+        let idl = match idl {
+            Some(idl) => IDL::Indexed(idl),
+            None => IDL::ALLIDS,
+        };
 
-            assert!(be.create(audit, vec![ve1, ve2]).is_ok());
-            assert!(entry_exists!(audit, be, e1));
-            assert!(entry_exists!(audit, be, e2));
+        let raw_entries = try_audit!(au, self.idlayer.get_identry(au, &idl));
+        raw_entries
+            .into_iter()
+            .map(|ide| ide.to_entry(self.get_idlayer()))
+            .filter(|e| match e {
+                Ok(e) => wanted.contains(e.get_uuid()),
+                Err(_) => true,
+            })
+            .map(|e| e.map(|e| (e.get_uuid().clone(), e)))
+            .collect()
+    }
 
-            // You need to now retrieve the entries back out to get the entry id's
-            let mut results = be
-                .search(audit, unsafe { &filter_resolved!(f_pres("userid")) })
-                .expect("Failed to search");
+    // When an entry's visible identifier (such as its name) changes, entries
+    // that refer to it may carry values derived from the old one. This finds
+    // every referrer of target and applies the modlist refresh returns for
+    // it, writing them all in the one modify so the cascade lands as a
+    // single batch. refresh returns None for referrers needing no change.
+    pub fn rename_cascade<F>(
+        &self,
+        au: &mut AuditScope,
+        schema: &dyn SchemaTransaction,
+        target: &Uuid,
+        mut refresh: F,
+    ) -> Result<Vec<Entry<EntryValid, EntryCommitted>>, OperationError>
+    where
+        F: FnMut(&Entry<EntryValid, EntryCommitted>) -> Option<ModifyList<ModifyValid>>,
+    {
+        audit_segment!(au, || {
+            let referrers = self.get_referring_entries(au, schema, target)?;
+
+            let mut pre_entries = Vec::new();
+            let mut post_entries = Vec::new();
+            for pre in referrers {
+                if let Some(modlist) = refresh(&pre) {
+                    let mut post = pre.clone().invalidate();
+                    post.apply_modlist(&modlist);
+                    let post = try_audit!(
+                        au,
+                        post.validate(schema)
+                            .map_err(|e| OperationError::SchemaViolation(e))
+                    );
+                    pre_entries.push(pre);
+                    post_entries.push(post);
+                }
+            }
 
-            // Get these out to usable entries.
-            let r1 = results.remove(0);
-            let r2 = results.remove(0);
+            audit_log!(
+                au,
+                "rename_cascade: updating {} referrers of {:?}",
+                post_entries.len(),
+                target
+            );
+            if !post_entries.is_empty() {
+                self.modify(au, &pre_entries, &post_entries)?;
+            }
+            Ok(post_entries)
+        })
+    }
 
-            let mut r1 = r1.invalidate();
-            let mut r2 = r2.invalidate();
+    // Every entry holding a reference to target. The equality indexes of the
+    // reference attributes are the reverse reference index - if any of them
+    // is missing we have to fall back to a full scan.
+    fn get_referring_entries(
+        &self,
+        au: &mut AuditScope,
+        schema: &dyn SchemaTransaction,
+        target: &Uuid,
+    ) -> Result<Vec<Entry<EntryValid, EntryCommitted>>, OperationError> {
+        let ref_attrs: Vec<String> = schema
+            .get_reference_types()
+            .keys()
+            .map(|a| (*a).clone())
+            .collect();
+        let idx_key = target.to_hyphenated_ref().to_string();
+        let mut idl = Some(IDLBitRange::new());
+        for attr in ref_attrs.iter() {
+            idl = match (idl, self.get_idl(au, attr, &IndexType::EQUALITY, &idx_key)?) {
+                (Some(acc), Some(a_idl)) => Some(acc | a_idl),
+                _ => None,
+            };
+        }
+        let idl = match idl {
+            Some(idl) => IDL::Indexed(idl),
+            None => IDL::ALLIDS,
+        };
 
-            // Modify no id (err)
-            // This is now impossible due to the state machine design.
-            // However, with some unsafe ....
-            let ue1 = unsafe { e1.clone().to_valid_committed() };
-            assert!(be.modify(audit, &vec![ue1.clone()], &vec![ue1]).is_err());
-            // Modify none
-            assert!(be.modify(audit, &vec![], &vec![]).is_err());
+        let pv = PartialValue::new_refer_r(target);
+        let raw_entries = try_audit!(au, self.idlayer.get_identry(au, &idl));
+        raw_entries
+            .into_iter()
+            .map(|ide| ide.to_entry(self.get_idlayer()))
+            .filter(|e| match e {
+                Ok(e) => ref_attrs.iter().any(|a| e.attribute_value_pres(a, &pv)),
+                Err(_) => true,
+            })
+            .collect()
+    }
 
-            // Make some changes to r1, r2.
-            let pre1 = unsafe { r1.clone().to_valid_committed() };
-            let pre2 = unsafe { r2.clone().to_valid_committed() };
-            r1.add_ava("desc", &Value::from("modified"));
-            r2.add_ava("desc", &Value::from("modified"));
+    pub fn delete(
+        &self,
+        au: &mut AuditScope,
+        entries: &Vec<Entry<EntryValid, EntryCommitted>>,
+    ) -> Result<(), OperationError> {
+        // Perform a search for the entries --> This is a problem for the caller
+        audit_segment!(au, || {
+            if entries.is_empty() {
+                audit_log!(
+                    au,
+                    "No entries provided to BE to delete, invalid server call!"
+                );
+                return Err(OperationError::EmptyRequest);
+            }
 
-            // Now ... cheat.
+            let id_list: Vec<u64> = entries.iter().map(|e| e.get_id()).collect();
 
-            let vr1 = unsafe { r1.to_valid_committed() };
-            let vr2 = unsafe { r2.to_valid_committed() };
+            // Simple: If the list of id's is not the same as the input list, we are missing id's
+            if entries.len() != id_list.len() {
+                return Err(OperationError::InvalidEntryState);
+            }
 
-            // Modify single
-            assert!(be
-                .modify(audit, &vec![pre1.clone()], &vec![vr1.clone()])
-                .is_ok());
-            // Assert no other changes
-            assert!(entry_attr_pres!(audit, be, vr1, "desc"));
-            assert!(!entry_attr_pres!(audit, be, vr2, "desc"));
+            // Now, given the list of id's, delete them.
+            self.delete_identry(au, id_list)?;
+            self.capture_op(au, |_| {
+                CaptureOp::Delete(entries.iter().map(|e| e.get_id()).collect())
+            });
+            self.idlayer.write_removed(
+                au,
+                entries
+                    .iter()
+                    .map(|e| e.get_uuid().to_hyphenated_ref().to_string())
+                    .collect(),
+            )?;
 
-            // Modify both
-            assert!(be
-                .modify(
-                    audit,
-                    &vec![vr1.clone(), pre2.clone()],
-                    &vec![vr1.clone(), vr2.clone()]
-                )
-                .is_ok());
+            // Finally, purge the indexes from the entries we removed.
+            entries.iter().try_for_each(|e| {
+                self.changes.borrow_mut().record(Some(e), None);
+                self.changestate.borrow_mut().record(Some(e), None);
+                self.entry_index(au, Some(e), None)
+            })
+        })
+    }
 
-            assert!(entry_attr_pres!(audit, be, vr1, "desc"));
-            assert!(entry_attr_pres!(audit, be, vr2, "desc"));
+    // Changes are made to the idls in the idxcache, which are loaded on first
+    // use and then written back in a single stripe by flush_idxcache at commit.
+    fn entry_index(
+        &self,
+        audit: &mut AuditScope,
+        pre: Option<&Entry<EntryValid, EntryCommitted>>,
+        post: Option<&Entry<EntryValid, EntryCommitted>>,
+    ) -> Result<(), OperationError> {
+        let e_id = match (pre, post) {
+            (None, None) => {
+                audit_log!(audit, "Invalid call to entry_index - no entries provided");
+                return Err(OperationError::InvalidState);
+            }
+            (Some(pre), None) => {
+                audit_log!(audit, "Attempting to remove indexes");
+                pre.get_id()
+            }
+            (None, Some(post)) => {
+                audit_log!(audit, "Attempting to update indexes");
+                post.get_id()
+            }
+            (Some(pre), Some(post)) => {
+                audit_log!(audit, "Attempting to modify indexes");
+                assert!(pre.get_id() == post.get_id());
+                post.get_id()
+            }
+        };
+
+        self.entry_index_names(audit, pre, post)?;
+
+        let idx_diff = Entry::idx_diff(&self.idxmeta, pre, post);
+
+        idx_diff.iter().try_for_each(|act| {
+            let (attr, itype, idx_key, add) = match act {
+                Ok((attr, itype, idx_key)) => {
+                    audit_log!(audit, "Adding {:?} idx -> {:?}: {:?}", itype, attr, idx_key);
+                    (attr, itype, idx_key, true)
+                }
+                Err((attr, itype, idx_key)) => {
+                    audit_log!(audit, "Removing {:?} idx -> {:?}: {:?}", itype, attr, idx_key);
+                    (attr, itype, idx_key, false)
+                }
+            };
+            let idx = (attr.to_string(), (*itype).clone());
+            if self.backfill.borrow().contains(&idx) {
+                return Ok(());
+            }
+            let cache_key = (attr.to_string(), (*itype).clone(), idx_key.clone());
+            let mut idxcache = self.idxcache.borrow_mut();
+            if !idxcache.contains_key(&cache_key) {
+                match self.idlayer.get_idl(audit, attr, itype, idx_key)? {
+                    Some(idl) => {
+                        idxcache.insert(cache_key.clone(), idl);
+                    }
+                    None => {
+                        audit_log!(
+                            audit,
+                            "WARNING: index {:?} {:?} was not found, creating it to be filled at commit",
+                            attr,
+                            itype
+                        );
+                        self.idlayer.create_idx(audit, attr, itype)?;
+                        self.backfill.borrow_mut().insert(idx);
+                        return Ok(());
+                    }
+                }
+            }
+            // Present by now, we either had it or just loaded it.
+            if let Some(idl) = idxcache.get_mut(&cache_key) {
+                if add {
+                    idl.insert_id(e_id);
+                } else {
+                    idl.remove_id(e_id);
+                }
+            }
+            Ok(())
+        })
+    }
+
+    // name2uuid, uuid2name and uuid2spn only hold live entries, so a tombstone or
+    // recycled entry's name can be taken by another, as attrunique allows.
+    // These go straight to the idlayer, as they are single rows.
+    fn entry_index_names(
+        &self,
+        audit: &mut AuditScope,
+        pre: Option<&Entry<EntryValid, EntryCommitted>>,
+        post: Option<&Entry<EntryValid, EntryCommitted>>,
+    ) -> Result<(), OperationError> {
+        let live_name = |e: Option<&Entry<EntryValid, EntryCommitted>>| {
+            e.filter(|e| {
+                !e.attribute_value_pres("class", &PVCLASS_TOMBSTONE)
+                    && !e.attribute_value_pres("class", &PVCLASS_RECYCLED)
+            })
+            .and_then(|e| {
+                e.get_ava_single_str("name")
+                    .map(|n| (e.get_uuid().clone(), n.to_string()))
+            })
+        };
+        let pre_name = live_name(pre);
+        let post_name = live_name(post);
+        if pre_name == post_name {
+            return Ok(());
+        }
+        if let Some((uuid, name)) = pre_name {
+            audit_log!(audit, "Removing name2uuid -> {:?}: {:?}", name, uuid);
+            self.idlayer
+                .write_name2uuid_rem(audit, name.as_str(), &uuid)?;
+            self.idlayer.write_uuid2name_rem(audit, &uuid)?;
+            self.idlayer.write_uuid2spn_rem(audit, &uuid)?;
+        }
+        if let Some((uuid, name)) = post_name {
+            audit_log!(audit, "Adding name2uuid -> {:?}: {:?}", name, uuid);
+            self.idlayer
+                .write_name2uuid_add(audit, name.as_str(), &uuid)?;
+            self.idlayer
+                .write_uuid2name_add(audit, &uuid, name.as_str())?;
+            let spn = format!("{}@{}", name, self.domain);
+            self.idlayer
+                .write_uuid2spn_add(audit, &uuid, spn.as_str())?;
+        }
+        Ok(())
+    }
+
+    // Write out everything the txn changed in the idxcache.
+    fn flush_idxcache(&self, audit: &mut AuditScope) -> Result<(), OperationError> {
+        let idxcache = self.idxcache.replace(BTreeMap::new());
+        audit_log!(audit, "Flushing {} idls", idxcache.len());
+        idxcache
+            .iter()
+            .try_for_each(|((attr, itype, idx_key), idl)| {
+                let written = self.idlayer.write_idl(audit, attr, itype, idx_key, idl)?;
+                let mut stats = self.stats.borrow_mut();
+                stats.idx_slots += 1;
+                stats.idx_bytes += written as u64;
+                Ok(())
+            })
+    }
+
+    #[allow(dead_code)]
+    fn missing_idxs(
+        &self,
+        audit: &mut AuditScope,
+    ) -> Result<Vec<(String, IndexType)>, OperationError> {
+        let idx_table_list = self.idlayer.list_idxs(audit)?;
+
+        // Turn the vec to a real set
+        let idx_table_set: BTreeSet<_> = idx_table_list.into_iter().collect();
+
+        let missing: Vec<_> = self
+            .idxmeta
+            .iter()
+            .filter_map(|(attr, itype)| {
+                // what would the table name be?
+                let tname = format!("idx_{}_{}", itype.as_idx_str(), attr);
+                audit_log!(audit, "Checking for {}", tname);
+
+                if idx_table_set.contains(&tname) {
+                    None
+                } else {
+                    Some((attr.clone(), itype.clone()))
+                }
+            })
+            .collect();
+        Ok(missing)
+    }
+
+    fn create_idxs(&self, audit: &mut AuditScope) -> Result<(), OperationError> {
+        // Create name2uuid, uuid2name and uuid2spn
+        audit_log!(audit, "Creating index -> name2uuid");
+        self.idlayer.create_name2uuid(audit)?;
+
+        audit_log!(audit, "Creating index -> uuid2name");
+        self.idlayer.create_uuid2name(audit)?;
+
+        audit_log!(audit, "Creating index -> uuid2spn");
+        self.idlayer.create_uuid2spn(audit)?;
+
+        self.idxmeta
+            .iter()
+            .try_for_each(|(attr, itype)| self.idlayer.create_idx(audit, attr, itype))
+    }
+
+    // Rewrite every entry not already in our entry format.
+    fn rewrite_identries(&self, audit: &mut AuditScope) -> Result<(), OperationError> {
+        let mut after = 0;
+        let mut rewritten = 0;
+        loop {
+            optrack::check_cancelled()?;
+            let batch = self
+                .idlayer
+                .get_identry_batch(audit, after, REINDEX_BATCH_SIZE)?;
+            if batch.is_empty() {
+                break;
+            }
+            after = batch.last().map(|ide| ide.id).unwrap_or(after);
+
+            let mut upgraded = Vec::new();
+            for ide in batch.into_iter() {
+                let (_, upgrade) = try_audit!(
+                    audit,
+                    ide.to_entry_upgrade(&self.idlayer, self.entry_format)
+                );
+                upgraded.extend(upgrade);
+            }
+            if !upgraded.is_empty() {
+                rewritten += upgraded.len();
+                self.write_identries(audit, upgraded)?;
+            }
+        }
+        audit_log!(
+            audit,
+            "rewrote {} entries in format {}",
+            rewritten,
+            self.entry_format
+        );
+        Ok(())
+    }
+
+    pub fn upgrade_reindex(&self, audit: &mut AuditScope, v: i64) -> Result<(), OperationError> {
+        if self.get_db_index_version() < v {
+            self.reindex(audit)?;
+        }
+        self.set_db_index_version(v)
+    }
+
+    pub fn reindex(&self, audit: &mut AuditScope) -> Result<(), OperationError> {
+        self.reindex_ext(audit, false)
+    }
+
+    // Entries are indexed a batch at a time from id2entry, so only one batch
+    // is held in memory. Progress goes to the audit log after every batch,
+    // and to stderr if requested, for an operator watching the cli.
+    pub fn reindex_ext(
+        &self,
+        audit: &mut AuditScope,
+        progress: bool,
+    ) -> Result<(), OperationError> {
+        self.capture_op(audit, |_| CaptureOp::Reindex);
+        // Purge the idxs, including anything pending for the old ones.
+        self.idxcache.borrow_mut().clear();
+        self.backfill.borrow_mut().clear();
+        unsafe { self.idlayer.purge_idxs(audit)? };
+
+        // Using the index metadata on the txn, create all our idx tables
+        self.create_idxs(audit)?;
+
+        let total = self.idlayer.get_id2entry_count()?;
+        let start = Instant::now();
+        let mut last_report = start;
+        let mut done: i64 = 0;
+        let mut after = 0;
+        loop {
+            optrack::check_cancelled()?;
+            let batch = self
+                .idlayer
+                .get_identry_batch(audit, after, REINDEX_BATCH_SIZE)?;
+            if batch.is_empty() {
+                break;
+            }
+            after = batch.last().map(|ide| ide.id).unwrap_or(after);
+            done += batch.len() as i64;
+
+            // name2uuid, uuid2name and uuid2spn are filled by entry_index as
+            // well. Entries still in an older format are rewritten as we go.
+            let mut upgraded = Vec::new();
+            for ide in batch.into_iter() {
+                let (e, upgrade) = try_audit!(
+                    audit,
+                    ide.to_entry_upgrade(&self.idlayer, self.entry_format)
+                );
+                try_audit!(audit, self.entry_index(audit, None, Some(&e)));
+                upgraded.extend(upgrade);
+            }
+            if !upgraded.is_empty() {
+                audit_log!(audit, "reindex: upgrading {} entries", upgraded.len());
+                self.write_identries(audit, upgraded)?;
+            }
+
+            let elapsed = start.elapsed().as_secs();
+            // The rate so far, applied to what's left.
+            let eta = elapsed * (total - done).max(0) as u64 / done as u64;
+            audit_log!(
+                audit,
+                "reindex: {}/{} entries, {}s elapsed, eta {}s",
+                done,
+                total,
+                elapsed,
+                eta
+            );
+            if progress && (last_report.elapsed() >= REINDEX_PROGRESS_INTERVAL || done >= total) {
+                eprintln!(
+                    "reindex: {}/{} entries ({}%), eta {}s",
+                    done,
+                    total,
+                    done * 100 / total.max(1),
+                    eta
+                );
+                last_report = Instant::now();
+            }
+        }
+        Ok(())
+    }
+
+    // Rebuild only the indexes that verify finds inconsistent, and any in
+    // idxmeta that are missing, rather than everything as reindex does.
+    // Returns the indexes that were rebuilt.
+    pub fn repair_idxs(
+        &self,
+        audit: &mut AuditScope,
+    ) -> Result<Vec<(String, IndexType)>, OperationError> {
+        let mut broken = BTreeSet::new();
+        let mut unrepairable = Vec::new();
+        for r in self.verify(audit).into_iter() {
+            match r {
+                Err(ConsistencyError::IndexMissingId(attr, itype, _, _))
+                | Err(ConsistencyError::IndexExtraId(attr, itype, _, _))
+                | Err(ConsistencyError::IndexDanglingId(attr, itype, _, _))
+                | Err(ConsistencyError::IndexUnreadable(attr, itype)) => {
+                    match IndexType::from_idx_str(itype.as_str()) {
+                        Some(i) => {
+                            broken.insert((attr, i));
+                        }
+                        None => {
+                            unrepairable.push(Err(ConsistencyError::IndexUnreadable(attr, itype)))
+                        }
+                    }
+                }
+                Ok(()) => {}
+                r => unrepairable.push(r),
+            }
+        }
+        if !unrepairable.is_empty() {
+            audit_log!(
+                audit,
+                "repair_idxs: not an index problem {:?}",
+                unrepairable
+            );
+            return Err(OperationError::ConsistencyError(unrepairable));
+        }
+
+        let existing = self.idlayer.list_idxs(audit)?;
+        for (attr, itype) in self.idxmeta.iter() {
+            if !existing.contains(&format!("idx_{}_{}", itype.as_idx_str(), attr)) {
+                self.idlayer.create_idx(audit, attr, itype)?;
+                broken.insert((attr.clone(), itype.clone()));
+            }
+        }
+
+        if broken.is_empty() {
+            audit_log!(audit, "repair_idxs: nothing to repair");
+            return Ok(Vec::new());
+        }
+
+        for (attr, itype) in broken.iter() {
+            audit_log!(audit, "repair_idxs: rebuilding {:?} {}", itype, attr);
+        }
+        self.rebuild_idxs(audit, &broken)?;
+        Ok(broken.into_iter().collect())
+    }
+
+    // Build a single index from id2entry, IE when schema has just gained it,
+    // leaving the others alone. The index is added to idxmeta, so the rest of
+    // this txn keeps it up to date.
+    pub fn reindex_attr(
+        &mut self,
+        audit: &mut AuditScope,
+        attr: &str,
+        itype: &IndexType,
+    ) -> Result<(), OperationError> {
+        let attr = attr.to_string();
+        audit_log!(audit, "reindex_attr: building {:?} {}", itype, attr);
+        self.idlayer.create_idx(audit, &attr, itype)?;
+        self.idxmeta.insert((attr.clone(), itype.clone()));
+
+        let mut idxs = BTreeSet::new();
+        idxs.insert((attr, itype.clone()));
+        self.rebuild_idxs(audit, &idxs)
+    }
+
+    // Rewrite up to limit entries that still hold from, after the id after,
+    // so that they hold to instead. Returns how many were rewritten, and the
+    // id to carry on after, or None once the end of id2entry is reached.
+    pub fn rename_attr(
+        &self,
+        audit: &mut AuditScope,
+        from: &str,
+        to: &str,
+        after: u64,
+        limit: usize,
+    ) -> Result<(usize, Option<u64>), OperationError> {
+        let mut pre_entries = Vec::new();
+        let mut next = Some(after);
+        while let Some(after) = next {
+            if pre_entries.len() >= limit {
+                break;
+            }
+            let batch = self
+                .idlayer
+                .get_identry_batch(audit, after, BACKUP_BATCH_SIZE)?;
+            next = batch.last().map(|ide| ide.id);
+            for ide in batch.into_iter() {
+                let e = try_audit!(audit, ide.to_entry(self.get_idlayer()));
+                if e.attribute_pres(from) {
+                    pre_entries.push(e);
+                    if pre_entries.len() >= limit {
+                        next = Some(ide.id);
+                        break;
+                    }
+                }
+            }
+        }
+
+        if pre_entries.is_empty() {
+            return Ok((0, next));
+        }
+        audit_log!(
+            audit,
+            "rename_attr: {} -> {} in {} entries",
+            from,
+            to,
+            pre_entries.len()
+        );
+        let post_entries: Vec<_> = pre_entries
+            .iter()
+            .map(|e| {
+                let mut e = e.clone();
+                e.rename_ava(from, to);
+                e
+            })
+            .collect();
+        self.modify(audit, &pre_entries, &post_entries)
+            .map(|_| (pre_entries.len(), next))
+    }
+
+    // Once nothing holds from, rebuild the indexes of to - entries written
+    // under the new name while the rename was under way were indexed as if
+    // they'd always had it - and drop any indexes left under the old name.
+    pub fn finish_rename(
+        &self,
+        audit: &mut AuditScope,
+        from: &str,
+        to: &str,
+    ) -> Result<(), OperationError> {
+        let idxs: BTreeSet<_> = self
+            .idxmeta
+            .iter()
+            .filter(|(attr, _)| attr == to)
+            .cloned()
+            .collect();
+        if !idxs.is_empty() {
+            self.rebuild_idxs(audit, &idxs)?;
+        }
+        let from = from.to_string();
+        [
+            IndexType::EQUALITY,
+            IndexType::PRESENCE,
+            IndexType::SUBSTRING,
+            IndexType::ORDERING,
+            IndexType::FUZZY,
+        ]
+        .iter()
+        .try_for_each(|itype| self.idlayer.drop_idx(audit, &from, itype))
+    }
+
+    // Purge and rebuild these indexes, in one pass over id2entry. The idls
+    // are built in the idxcache, and written out at commit.
+    fn rebuild_idxs(
+        &self,
+        audit: &mut AuditScope,
+        idxs: &BTreeSet<(String, IndexType)>,
+    ) -> Result<(), OperationError> {
+        {
+            let mut idxcache = self.idxcache.borrow_mut();
+            let stale: Vec<_> = idxcache
+                .keys()
+                .filter(|(attr, itype, _)| idxs.contains(&(attr.clone(), itype.clone())))
+                .cloned()
+                .collect();
+            stale.iter().for_each(|k| {
+                idxcache.remove(k);
+            });
+        }
+        for (attr, itype) in idxs.iter() {
+            unsafe { self.idlayer.purge_idx(audit, attr, itype)? };
+        }
+
+        let mut after = 0;
+        loop {
+            let batch = self
+                .idlayer
+                .get_identry_batch(audit, after, BACKUP_BATCH_SIZE)?;
+            if batch.is_empty() {
+                break;
+            }
+            after = batch.last().map(|ide| ide.id).unwrap_or(after);
+            for ide in batch.into_iter() {
+                let e = try_audit!(audit, ide.to_entry(self.get_idlayer()));
+                let mut idxcache = self.idxcache.borrow_mut();
+                for (attr, itype, key) in Entry::idx_diff(idxs, None, Some(&e))
+                    .into_iter()
+                    .filter_map(|r| r.ok())
+                {
+                    idxcache
+                        .entry((attr.clone(), itype.clone(), key))
+                        .or_insert_with(IDLBitRange::new)
+                        .insert_id(e.get_id());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get_idxmeta(&self) -> &BTreeSet<(String, IndexType)> {
+        &self.idxmeta
+    }
+
+    // Taken just before commit, to be published once it succeeds.
+    pub fn take_changes(&self) -> ChangeSet {
+        std::mem::replace(&mut *self.changes.borrow_mut(), ChangeSet::new())
+    }
+
+    // For an attribute merged from a replication partner, which keeps the
+    // partner's CID rather than being given this txn's.
+    pub fn set_changestate(&self, uuid: &Uuid, attr: &str, cid: &Cid) {
+        self.changestate.borrow_mut().set(uuid, attr, cid)
+    }
+
+    // Everything this txn changed is given one CID, after any we've seen.
+    fn flush_changestate(&self, audit: &mut AuditScope) -> Result<(), OperationError> {
+        let changestate = self.changestate.replace(ChangeState::new());
+        if changestate.is_empty() {
+            return Ok(());
+        }
+        let s_uuid = self.get_domain_info()?.uuid;
+        let latest = std::cmp::max(
+            self.idlayer.get_max_cid(audit)?,
+            changestate.latest().cloned(),
+        );
+        let txn_cid = Cid::new_after(&s_uuid, latest.as_ref());
+        let (rows, removed) = changestate.into_rows(&txn_cid);
+        audit_log!(
+            audit,
+            "changestate: {} attributes at {}, {} entries removed",
+            rows.len(),
+            txn_cid,
+            removed.len()
+        );
+        self.idlayer.delete_changestate(audit, removed.as_slice())?;
+        self.idlayer.write_changestate(audit, rows.as_slice())
+    }
+
+    #[cfg(test)]
+    pub fn purge_idxs(&self, audit: &mut AuditScope) -> Result<(), OperationError> {
+        self.idxcache.borrow_mut().clear();
+        unsafe { self.idlayer.purge_idxs(audit) }
+    }
+
+    #[cfg(test)]
+    pub fn load_test_idl(
+        &self,
+        audit: &mut AuditScope,
+        attr: &String,
+        itype: &IndexType,
+        idx_key: &String,
+    ) -> Result<Option<IDLBitRange>, OperationError> {
+        self.get_idl(audit, attr, itype, idx_key)
+    }
+
+    pub fn restore(
+        &mut self,
+        audit: &mut AuditScope,
+        src_path: &str,
+    ) -> Result<(), OperationError> {
+        self.restore_batched(audit, src_path, RESTORE_BATCH_SIZE, None)
+    }
+
+    // Entries are read from the backup, written to id2entry and indexed a
+    // batch at a time, so only one batch is ever held in memory. Both the
+    // json array that backup writes and newline delimited entries are accepted.
+    pub fn restore_batched(
+        &mut self,
+        audit: &mut AuditScope,
+        src_path: &str,
+        batch_size: usize,
+        key: Option<&BackupKey>,
+    ) -> Result<(), OperationError> {
+        if batch_size == 0 {
+            audit_log!(audit, "restore batch size must be greater than 0");
+            return Err(OperationError::InvalidState);
+        }
+
+        let mut reader = Self::backup_reader(audit, src_path, key)?;
+
+        // Find out what kind of backup this is from the first byte that isn't
+        // whitespace.
+        let is_array = loop {
+            let (skip, first) = {
+                let buf = try_audit!(
+                    audit,
+                    reader.fill_buf(),
+                    "read error {:?}",
+                    OperationError::FsError
+                );
+                match buf.iter().position(|b| !b.is_ascii_whitespace()) {
+                    Some(i) => (i, Some(buf[i])),
+                    None => (buf.len(), None),
+                }
+            };
+            reader.consume(skip);
+            match first {
+                Some(b) => break b == b'[',
+                // Either the buffer was all whitespace, or this is the end of
+                // the file. An empty file is an empty ndjson backup.
+                None if skip == 0 => break false,
+                None => {}
+            }
+        };
+
+        try_audit!(audit, unsafe { self.purge_id2entry(audit) });
+
+        // Start from empty indexes, each batch is indexed as it goes in.
+        self.idxcache.borrow_mut().clear();
+        unsafe { self.idlayer.purge_idxs(audit)? };
+        self.create_idxs(audit)?;
+
+        let mut id_max = 0;
+        if is_array {
+            let mut batch_err = None;
+            let r = {
+                let visitor = RestoreSeqVisitor {
+                    batch_size: batch_size,
+                    restore_fn: |batch| self.restore_batch(audit, &mut id_max, batch),
+                    err: &mut batch_err,
+                };
+                let mut de = serde_json::Deserializer::from_reader(reader);
+                (&mut de).deserialize_seq(visitor).and_then(|_| de.end())
+            };
+            match (r, batch_err) {
+                (_, Some(e)) => {
+                    audit_log!(audit, "restore batch failed {:?}", e);
+                    return Err(e);
+                }
+                (Err(e), None) => {
+                    audit_log!(audit, "serde_json error {:?}", e);
+                    return Err(OperationError::SerdeJsonError);
+                }
+                (Ok(_), None) => {}
+            }
+        } else {
+            let mut batch = Vec::with_capacity(batch_size);
+            for dbe in serde_json::Deserializer::from_reader(reader).into_iter::<DbEntry>() {
+                let dbe = try_audit!(
+                    audit,
+                    dbe,
+                    "serde_json error {:?}",
+                    OperationError::SerdeJsonError
+                );
+                batch.push(dbe);
+                if batch.len() >= batch_size {
+                    let full = std::mem::replace(&mut batch, Vec::with_capacity(batch_size));
+                    self.restore_batch(audit, &mut id_max, full)?;
+                }
+            }
+            self.restore_batch(audit, &mut id_max, batch)?;
+        }
+
+        audit_log!(audit, "restored {} entries", id_max);
+
+        let vr = self.verify(audit);
+        if vr.len() == 0 {
+            Ok(())
+        } else {
+            Err(OperationError::ConsistencyError(vr))
+        }
+    }
+
+    // Restore a full backup, then apply each incremental backup that follows
+    // it in order. Each increment must start where the one before it ended,
+    // otherwise changes between them would be silently lost.
+    pub fn restore_chain(
+        &mut self,
+        audit: &mut AuditScope,
+        src_path: &str,
+        incremental_paths: &[&str],
+        batch_size: usize,
+        key: Option<&BackupKey>,
+    ) -> Result<(), OperationError> {
+        self.restore_batched(audit, src_path, batch_size, key)?;
+
+        let mut last_marker = None;
+        for path in incremental_paths {
+            let (since, marker) = self.restore_incremental(audit, path, key)?;
+            match last_marker {
+                Some(m) if m != since => {
+                    audit_log!(
+                        audit,
+                        "incremental backup {} starts at {}, but the previous ended at {}",
+                        path,
+                        since,
+                        m
+                    );
+                    return Err(OperationError::InvalidRequestState);
+                }
+                _ => {}
+            }
+            last_marker = Some(marker);
+        }
+        Ok(())
+    }
+
+    // Apply an incremental backup on top of what we hold, returning the
+    // since and marker it covers. Deletions go first, so that an entry
+    // deleted and then recreated with the same uuid in the window survives.
+    fn restore_incremental(
+        &self,
+        audit: &mut AuditScope,
+        src_path: &str,
+        key: Option<&BackupKey>,
+    ) -> Result<(i64, i64), OperationError> {
+        let reader = Self::backup_reader(audit, src_path, key)?;
+        let incr: DbIncremental = try_audit!(
+            audit,
+            serde_json::from_reader(reader),
+            "serde_json error {:?}",
+            OperationError::SerdeJsonError
+        );
+        audit_log!(
+            audit,
+            "applying incremental backup {} -> {}, {} written, {} removed",
+            incr.since,
+            incr.marker,
+            incr.entries.len(),
+            incr.removed.len()
+        );
+
+        let removed: Result<Vec<Uuid>, _> =
+            incr.removed.iter().map(|u| Uuid::parse_str(u)).collect();
+        let removed = try_audit!(
+            audit,
+            removed,
+            "invalid uuid {:?}",
+            OperationError::InvalidState
+        );
+        let del_entries: Vec<_> = self
+            .get_uuid_entries(audit, removed.iter())?
+            .into_iter()
+            .map(|(_, e)| e)
+            .collect();
+        if !del_entries.is_empty() {
+            self.delete(audit, &del_entries)?;
+        }
+
+        let written: Result<Vec<_>, _> = incr
+            .entries
+            .into_iter()
+            .map(|dbe| compat::upgrade_dbentry(dbe).and_then(|dbe| Entry::from_dbentry(dbe, 0)))
+            .collect();
+        let written = try_audit!(
+            audit,
+            written,
+            "invalid entry {:?}",
+            OperationError::CorruptedEntry(0)
+        );
+        let current = self.get_uuid_entries(audit, written.iter().map(|e| e.get_uuid()))?;
+
+        // Entries we already hold are replaced in place, the rest are new.
+        let mut pre_entries = Vec::new();
+        let mut post_entries = Vec::new();
+        let mut new_entries = Vec::new();
+        for e in written.into_iter() {
+            match current.get(e.get_uuid()) {
+                Some(pre) => {
+                    let post = try_audit!(
+                        audit,
+                        Entry::from_dbentry(e.into_dbentry(), pre.get_id()),
+                        "invalid entry {:?}",
+                        OperationError::CorruptedEntry(pre.get_id())
+                    );
+                    pre_entries.push(pre.clone());
+                    post_entries.push(post);
+                }
+                None => new_entries.push(e.into_dbentry()),
+            }
+        }
+        if !post_entries.is_empty() {
+            self.modify(audit, &pre_entries, &post_entries)?;
+        }
+        let mut id_max = self.idlayer.get_id2entry_max_id()?;
+        self.restore_batch(audit, &mut id_max, new_entries)?;
+
+        Ok((incr.since, incr.marker))
+    }
+
+    // Record the change sequence number a backup was taken at, which the
+    // next incremental backup follows on from.
+    pub fn set_backup_marker(&self, csn: i64) -> Result<(), OperationError> {
+        self.idlayer.set_backup_marker(csn)
+    }
+
+    // Encrypted and compressed backups are recognised by their magic headers,
+    // anything else is read as plain json.
+    fn backup_reader(
+        audit: &mut AuditScope,
+        src_path: &str,
+        key: Option<&BackupKey>,
+    ) -> Result<Box<dyn BufRead>, OperationError> {
+        let file = try_audit!(
+            audit,
+            fs::File::open(src_path),
+            "fs::File::open {:?}",
+            OperationError::FsError
+        );
+        let mut reader = BufReader::new(file);
+        let encrypted = try_audit!(
+            audit,
+            reader.fill_buf(),
+            "read error {:?}",
+            OperationError::FsError
+        )
+        .starts_with(&ENCRYPT_MAGIC);
+
+        let mut reader: Box<dyn BufRead> = match (encrypted, key) {
+            (true, Some(key)) => Box::new(BufReader::new(try_audit!(
+                audit,
+                DecryptReader::new(reader, key),
+                "decrypt error {:?}",
+                OperationError::InvalidRequestState
+            ))),
+            (true, None) => {
+                audit_log!(audit, "backup is encrypted, but no key was provided");
+                return Err(OperationError::InvalidRequestState);
+            }
+            (false, Some(_)) => {
+                audit_log!(audit, "a key was provided, but the backup is not encrypted");
+                Box::new(reader)
+            }
+            (false, None) => Box::new(reader),
+        };
+
+        let compression = {
+            let buf = try_audit!(
+                audit,
+                reader.fill_buf(),
+                "read error {:?}",
+                OperationError::FsError
+            );
+            if buf.starts_with(&GZIP_MAGIC) {
+                BackupCompression::Gzip
+            } else if buf.starts_with(&ZSTD_MAGIC) {
+                BackupCompression::Zstd
+            } else {
+                BackupCompression::None
+            }
+        };
+        audit_log!(
+            audit,
+            "restoring from {:?} backup, encrypted {}",
+            compression,
+            encrypted
+        );
+
+        Ok(match compression {
+            BackupCompression::None => reader,
+            BackupCompression::Gzip => Box::new(BufReader::new(GzDecoder::new(reader))),
+            BackupCompression::Zstd => Box::new(BufReader::new(try_audit!(
+                audit,
+                ZstdDecoder::with_buffer(reader),
+                "zstd error {:?}",
+                OperationError::FsError
+            ))),
+        })
+    }
+
+    // The entries are given new ids, following on from id_max.
+    fn restore_batch(
+        &self,
+        audit: &mut AuditScope,
+        id_max: &mut u64,
+        dbentries: Vec<DbEntry>,
+    ) -> Result<(), OperationError> {
+        if dbentries.len() == 0 {
+            return Ok(());
+        }
+        audit_log!(audit, "restoring batch of {}", dbentries.len());
+
+        let mut identries = Vec::with_capacity(dbentries.len());
+        let mut entries = Vec::with_capacity(dbentries.len());
+        for ser_db_e in dbentries.into_iter() {
+            *id_max = *id_max + 1;
+            let ser_db_e = try_audit!(
+                audit,
+                compat::upgrade_dbentry(ser_db_e),
+                "unable to upgrade entry {:?}",
+                OperationError::CorruptedEntry(*id_max)
+            );
+            // The entry is put back together from what we wrote, which is
+            // how it will be read.
+            let ide = try_audit!(audit, self.to_identry(audit, *id_max, ser_db_e));
+            let id = *id_max;
+            let ser_db_e = try_audit!(audit, ide.to_dbentry(&self.idlayer));
+            identries.push(ide);
+            entries.push(try_audit!(
+                audit,
+                Entry::from_dbentry(ser_db_e, id),
+                "invalid entry {:?}",
+                OperationError::CorruptedEntry(id)
+            ));
+        }
+
+        self.write_identries(audit, identries)?;
+
+        entries
+            .iter()
+            .try_for_each(|e| self.entry_index(audit, None, Some(e)))
+    }
+
+    // Replace the content of this backend with the content of src. With reid
+    // the entries are given new sequential ids, closing any gaps left by
+    // deletes - uuids live in the entries so references are unaffected. The
+    // server id is carried over unless new_sid is set, which is what you want
+    // when the copy will run alongside the original (IE staging).
+    pub fn copy_from<T: BackendTransaction>(
+        &mut self,
+        audit: &mut AuditScope,
+        src: &T,
+        reid: bool,
+        new_sid: bool,
+    ) -> Result<(), OperationError> {
+        let mut raw_entries = src.get_idlayer().get_identry(audit, &IDL::ALLIDS)?;
+        audit_log!(audit, "copying {} entries", raw_entries.len());
+
+        if reid {
+            raw_entries.sort_unstable_by_key(|ide| ide.id);
+        }
+
+        try_audit!(audit, unsafe { self.purge_id2entry(audit) });
+        // The values src keeps in its id2blob are read in, and written again
+        // to ours.
+        let mut identries = Vec::with_capacity(raw_entries.len());
+        for (ide, new_id) in raw_entries.into_iter().zip(1..) {
+            let db_e = try_audit!(audit, ide.to_dbentry(src.get_idlayer()));
+            let id = if reid { new_id } else { ide.id };
+            identries.push(try_audit!(audit, self.to_identry(audit, id, db_e)));
+        }
+        self.write_identries(audit, identries)?;
+
+        match src.get_idlayer().get_db_domain()? {
+            Some(domain) if !new_sid => self.idlayer.write_db_domain(&domain)?,
+            _ => {
+                let domain = self.reset_domain_info()?;
+                audit_log!(audit, "generated new domain uuid {}", domain.uuid);
+            }
+        };
+
+        self.reindex(audit)?;
+
+        let vr = self.verify(audit);
+        if vr.len() == 0 {
+            Ok(())
+        } else {
+            Err(OperationError::ConsistencyError(vr))
+        }
+    }
+
+    // Called for each entry a write changes, on behalf of identity.
+    pub fn note_change(&self, uuid: &Uuid, identity: &str, operation: &str) {
+        self.changelog
+            .borrow_mut()
+            .push(JournalRecord::new(uuid, identity, operation));
+    }
+
+    // When the report last ran, in seconds since the epoch.
+    pub fn report_last_run(
+        &self,
+        audit: &mut AuditScope,
+        report: &str,
+    ) -> Result<Option<i64>, OperationError> {
+        self.idlayer.get_report_last_run(audit, report)
+    }
+
+    pub fn write_report(
+        &self,
+        audit: &mut AuditScope,
+        report: &str,
+        epoch: i64,
+        time: &str,
+        count: u64,
+        allids: bool,
+    ) -> Result<(), OperationError> {
+        self.idlayer
+            .write_report(audit, report, epoch, time, count, allids)
+    }
+
+    // Savepoints nest, and each must be released or rolled back to, the most
+    // recent first. Dropping one does neither, and it is released with the
+    // txn at commit.
+    pub fn savepoint(&self, audit: &mut AuditScope) -> Result<Savepoint, OperationError> {
+        let depth = self.idlayer.savepoint(audit)?;
+        Ok(Savepoint {
+            depth: depth,
+            idxcache: self.idxcache.borrow().clone(),
+            backfill: self.backfill.borrow().clone(),
+            changes: self.changes.borrow().clone(),
+            changestate: self.changestate.borrow().clone(),
+            changelog: self.changelog.borrow().len(),
+            stats: self.stats.borrow().clone(),
+            capture_ops: self.capture_ops.borrow().len(),
+        })
+    }
+
+    pub fn release_savepoint(
+        &self,
+        audit: &mut AuditScope,
+        sp: Savepoint,
+    ) -> Result<(), OperationError> {
+        self.idlayer.release_savepoint(audit, sp.depth)
+    }
+
+    // Undo everything written since sp was taken, leaving the rest of the
+    // txn as it was.
+    pub fn rollback_to_savepoint(
+        &self,
+        audit: &mut AuditScope,
+        sp: Savepoint,
+    ) -> Result<(), OperationError> {
+        self.idlayer.rollback_to_savepoint(audit, sp.depth)?;
+        audit_log!(audit, "Rolled back to savepoint {}", sp.depth);
+        self.idxcache.replace(sp.idxcache);
+        self.backfill.replace(sp.backfill);
+        self.changes.replace(sp.changes);
+        self.changestate.replace(sp.changestate);
+        self.changelog.borrow_mut().truncate(sp.changelog);
+        self.stats.replace(sp.stats);
+        self.capture_ops.borrow_mut().truncate(sp.capture_ops);
+        Ok(())
+    }
+
+    // Give back the space left by deletes and reindexing, returning the size
+    // of the db before and after. This commits the txn, which must not have
+    // changed anything, as vacuum can't run in one.
+    pub fn vacuum(self, audit: &mut AuditScope) -> Result<(u64, u64), OperationError> {
+        let unchanged = self.idxcache.borrow().is_empty()
+            && self.backfill.borrow().is_empty()
+            && self.changelog.borrow().is_empty()
+            && self
+                .dirty
+                .borrow()
+                .as_ref()
+                .map(|d| d.is_empty())
+                .unwrap_or(false);
+        if !unchanged {
+            audit_log!(audit, "vacuum: txn has pending changes");
+            return Err(OperationError::InvalidState);
+        }
+        let (before, _) = self.idlayer.get_db_size(audit)?;
+        let after = self.idlayer.vacuum(audit)?;
+        audit_log!(audit, "vacuum: {} -> {} bytes", before, after);
+        Ok((before, after))
+    }
+
+    pub fn commit(self, audit: &mut AuditScope) -> Result<(), OperationError> {
+        let backfill = self.backfill.replace(BTreeSet::new());
+        if !backfill.is_empty() {
+            audit_log!(audit, "Backfilling created indexes {:?}", backfill);
+            self.rebuild_idxs(audit, &backfill)?;
+        }
+        self.flush_idxcache(audit)?;
+        self.flush_changestate(audit)?;
+        // Entries we wrote or deleted may have let go of blobs.
+        if self
+            .dirty
+            .borrow()
+            .as_ref()
+            .map(|d| !d.is_empty())
+            .unwrap_or(true)
+        {
+            self.idlayer.purge_blobs(audit)?;
+        }
+        let changelog = self.changelog.replace(Vec::new());
+        self.stats.borrow_mut().changelog_records += changelog.len() as u64;
+        if !changelog.is_empty() {
+            self.idlayer.write_changelog(
+                audit,
+                changelog.as_slice(),
+                self.changelog_key.as_ref(),
+            )?;
+        }
+        // Our writes moved the marker, so this is where the entrycache goes
+        // to.
+        let marker = self.idlayer.get_change_marker();
+        let filtercache = self.filtercache;
+        let entrycache = self.entrycache;
+        let (from, dirty) = (self.marker, self.dirty.into_inner());
+        let (writestats, stats) = (self.writestats, self.stats.into_inner());
+        let capture = self.capture;
+        let capture_ops = self.capture_ops.into_inner();
+        let capture_idxmeta = capture_idxmeta(&self.idxmeta);
+        self.idlayer.commit(audit).map(|_| {
+            filtercache.invalidate();
+            entrycache.commit(from, marker, dirty.as_ref());
+            writestats.record(&stats);
+            // Only what was committed is captured. This is for debugging, so
+            // a failure here mustn't fail the txn.
+            if let Some(c) = capture {
+                if !capture_ops.is_empty() {
+                    if let Err(e) = c.record(capture_idxmeta, capture_ops) {
+                        error!("unable to write capture, abandoning it - {:?}", e);
+                        c.abandon();
+                    }
+                }
+            }
+        })
+    }
+
+    // Note op for the capture, if there is one. Before the first op of a
+    // capture, every entry is written as the snapshot the rest is replayed
+    // on, which is the content as of the start of this txn as nothing is
+    // captured until it changes. Ops that don't go through create, modify,
+    // delete or reindex (restore, copy_from) are offline only, so won't be
+    // seen in a capture.
+    fn capture_op<F>(&self, audit: &mut AuditScope, f: F)
+    where
+        F: FnOnce(&Capture) -> CaptureOp,
+    {
+        let capture = match &self.capture {
+            Some(c) => c,
+            None => return,
+        };
+        if capture.needs_snapshot() {
+            if let Err(e) = self.capture_snapshot(audit, capture) {
+                error!("unable to snapshot for capture, abandoning it - {:?}", e);
+                capture.abandon();
+                return;
+            }
+        }
+        self.capture_ops.borrow_mut().push(f(capture));
+    }
+
+    fn capture_snapshot(
+        &self,
+        audit: &mut AuditScope,
+        capture: &Capture,
+    ) -> Result<(), OperationError> {
+        let mut entries = Vec::new();
+        let mut after = 0;
+        loop {
+            let batch = self
+                .idlayer
+                .get_identry_batch(audit, after, BACKUP_BATCH_SIZE)?;
+            if batch.is_empty() {
+                break;
+            }
+            after = batch.last().map(|ide| ide.id).unwrap_or(after);
+            for ide in batch.iter() {
+                entries.push((ide.id, capture.anonymise(ide.to_dbentry(&self.idlayer)?)));
+            }
+        }
+        audit_log!(audit, "capture snapshot of {} entries", entries.len());
+        capture
+            .record(
+                capture_idxmeta(&self.idxmeta),
+                vec![CaptureOp::Write(entries)],
+            )
+            .map_err(|e| {
+                audit_log!(audit, "capture error {:?}", e);
+                OperationError::FsError
+            })
+    }
+
+    // Apply an op read back from a capture, as the txn that captured it did.
+    pub fn replay(&self, audit: &mut AuditScope, op: CaptureOp) -> Result<(), OperationError> {
+        match op {
+            CaptureOp::Write(dbentries) => {
+                let ids: Vec<u64> = dbentries.iter().map(|(id, _)| *id).collect();
+                let pre = self.get_entries(audit, &IDL::Indexed(IDLBitRange::from_iter(ids)))?;
+                let mut identries = Vec::with_capacity(dbentries.len());
+                let mut post = Vec::with_capacity(dbentries.len());
+                for (id, dbe) in dbentries.into_iter() {
+                    // As in restore, the entry is read back from what we
+                    // wrote.
+                    let ide = self.to_identry(audit, id, dbe)?;
+                    let dbe = ide.to_dbentry(&self.idlayer)?;
+                    identries.push(ide);
+                    post.push(try_audit!(
+                        audit,
+                        Entry::from_dbentry(dbe, id),
+                        "invalid entry {:?}",
+                        OperationError::CorruptedEntry(id)
+                    ));
+                }
+                self.write_identries(audit, identries)?;
+                post.iter().try_for_each(|e| {
+                    let pre_e = pre.iter().find(|p| p.get_id() == e.get_id());
+                    self.entry_index(audit, pre_e, Some(e))
+                })
+            }
+            CaptureOp::Delete(ids) => {
+                let pre = self.get_entries(audit, &IDL::Indexed(IDLBitRange::from_iter(ids)))?;
+                self.delete_identry(audit, pre.iter().map(|e| e.get_id()).collect())?;
+                pre.iter()
+                    .try_for_each(|e| self.entry_index(audit, Some(e), None))
+            }
+            CaptureOp::Reindex => self.reindex(audit),
+        }
+    }
+
+    // A new identity for the domain, keeping its name.
+    fn reset_domain_info(&self) -> Result<DomainInfo, OperationError> {
+        let domain = match self.idlayer.get_db_domain()? {
+            Some(d) => d.reset(),
+            None => DomainInfo::new(self.domain.as_str()),
+        };
+        self.idlayer.write_db_domain(&domain)?;
+        Ok(domain)
+    }
+
+    fn get_domain_info(&self) -> Result<DomainInfo, OperationError> {
+        match self.idlayer.get_db_domain()? {
+            Some(d) => Ok(d),
+            None => {
+                let domain = DomainInfo::new(self.domain.as_str());
+                self.idlayer.write_db_domain(&domain)?;
+                Ok(domain)
+            }
+        }
+    }
+
+    fn get_db_index_version(&self) -> i64 {
+        self.get_idlayer().get_db_index_version()
+    }
+
+    fn set_db_index_version(&self, v: i64) -> Result<(), OperationError> {
+        self.get_idlayer().set_db_index_version(v)
+    }
+}
+
+// In the future this will do the routing between the chosen backends etc.
+impl Backend {
+    pub fn new(
+        audit: &mut AuditScope,
+        path: &str,
+        pool_size: u32,
+        filter_test_threshold: usize,
+    ) -> Result<Self, OperationError> {
+        Self::new_with_options(
+            audit,
+            path,
+            pool_size,
+            filter_test_threshold,
+            None,
+            DbPragmas::default(),
+        )
+    }
+
+    // As new, but the database is encrypted with db_key, and connections are
+    // tuned with pragmas. See IdLayer::new.
+    pub fn new_with_options(
+        audit: &mut AuditScope,
+        path: &str,
+        pool_size: u32,
+        filter_test_threshold: usize,
+        db_key: Option<[u8; 32]>,
+        pragmas: DbPragmas,
+    ) -> Result<Self, OperationError> {
+        // this has a ::memory() type, but will path == "" work?
+        audit_segment!(audit, || {
+            // Make sure no one else is using this db before we open it.
+            let lock_file = DbLock::lock_file(audit, path)?;
+            let idlayer = BackendIdLayer::new(audit, path, pool_size, db_key, pragmas)?;
+
+            // Now complete our setup with a txn
+            // In this case we can use an empty idx meta because we don't
+            // access any parts of
+            // the indexing subsystem here.
+            let r = {
+                let idl_write = idlayer.write()?;
+                idl_write.setup(audit).and_then(|_| idl_write.commit(audit))
+            };
+
+            audit_log!(audit, "be new setup: {:?}", r);
+
+            // Now the owner table exists, record that it's ours.
+            r.and_then(|_| DbLock::claim(audit, lock_file, idlayer.clone()))
+                .and_then(|lock| {
+                    // Entries are written as they were last time, until
+                    // set_entry_compression says otherwise.
+                    let entry_format = match idlayer.read()?.get_db_entry_format() {
+                        0 => DBENTRY_FORMAT_CBOR,
+                        f if f == i64::from(DBENTRY_FORMAT_CBOR)
+                            || f == i64::from(DBENTRY_FORMAT_CBOR_ZSTD) =>
+                        {
+                            f as u8
+                        }
+                        f => {
+                            audit_log!(audit, "unknown entry format {}", f);
+                            return Err(OperationError::InvalidDBState);
+                        }
+                    };
+                    Ok(Backend {
+                        idlayer: idlayer,
+                        lock: Arc::new(lock),
+                        usage: IdxUsage::new(),
+                        journal: AccessJournal::new(),
+                        slowlog: SlowQueryLog::new(SLOW_QUERY_THRESHOLD),
+                        writestats: WriteStatsLog::new(),
+                        filtercache: FilterCache::new(FILTER_CACHE_SIZE),
+                        entrycache: EntryCache::new(ENTRY_CACHE_SIZE),
+                        changelog_key: None,
+                        domain: DEFAULT_DOMAIN.to_string(),
+                        entry_format: entry_format,
+                        capture: None,
+                        read_only: Arc::new(AtomicBool::new(false)),
+                        primary: Arc::new(Mutex::new(None)),
+                        cursors: SearchCursors::new(),
+                        filter_test_threshold: filter_test_threshold,
+                    })
+                })
+                .and_then(|be| {
+                    // Better to say so now than when a write fails.
+                    be.read()?
+                        .db_warnings(audit)?
+                        .iter()
+                        .for_each(|w| warn!("{}", w));
+                    Ok(be)
+                })
+        })
+    }
+
+    pub fn heartbeat(&self, audit: &mut AuditScope) -> Result<(), OperationError> {
+        self.lock.heartbeat(audit)
+    }
+
+    // Searches taking at least this long are noted in the slow query log, as
+    // are all that fall back to allids. This must be set before the backend is
+    // cloned to the workers.
+    pub fn set_slow_query_threshold(&mut self, threshold: Duration) {
+        self.slowlog = SlowQueryLog::new(threshold);
+    }
+
+    // While read only, write refuses to begin a txn, IE so a backup or
+    // migration sees nothing change. Our own bookkeeping, such as the slow
+    // query log, is still written. A write txn already begun can commit.
+    pub fn set_read_only(&self, read_only: bool) {
+        self.read_only.store(read_only, Ordering::SeqCst);
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only.load(Ordering::SeqCst)
+    }
+
+    // A read replica refuses writes with a referral to its primary, except
+    // through write_replicated. This is set once startup is done, since our
+    // own migrations still need to write.
+    pub fn set_primary(&self, primary: Option<String>) {
+        *self.primary.lock().unwrap_or_else(|p| p.into_inner()) = primary;
+    }
+
+    pub fn get_primary(&self) -> Option<String> {
+        self.primary
+            .lock()
+            .unwrap_or_else(|p| p.into_inner())
+            .clone()
+    }
+
+    pub fn pool_stats(&self) -> DbPoolStats {
+        self.idlayer.get_pool_stats()
+    }
+
+    // The domain of the spns in uuid2spn. Those already written are rebuilt,
+    // since it may have changed since we last ran. As with the slow query
+    // threshold, this must be set before the backend is cloned.
+    pub fn set_domain(
+        &mut self,
+        audit: &mut AuditScope,
+        domain: &str,
+    ) -> Result<(), OperationError> {
+        self.domain = domain.to_string();
+        let wr = self.write(BTreeSet::new())?;
+        wr.idlayer.rebuild_uuid2spn(audit, domain)?;
+        // The record is made with this name when first asked for.
+        if let Some(info) = wr.idlayer.get_db_domain()? {
+            if info.name != domain {
+                audit_log!(audit, "domain renamed {} -> {}", info.name, domain);
+                wr.idlayer.write_db_domain(&info.rename(domain))?;
+            }
+        }
+        wr.commit(audit)
+    }
+
+    // Whether entries are zstd compressed in id2entry. If that isn't how they
+    // were last written, every entry is rewritten now, and the choice is kept
+    // in the db so that offline tools like restore follow it. As with the
+    // domain, this must be set before the backend is cloned.
+    pub fn set_entry_compression(
+        &mut self,
+        audit: &mut AuditScope,
+        compress: bool,
+    ) -> Result<(), OperationError> {
+        self.entry_format = if compress {
+            DBENTRY_FORMAT_CBOR_ZSTD
+        } else {
+            DBENTRY_FORMAT_CBOR
+        };
+        let wr = self.write(BTreeSet::new())?;
+        if wr.idlayer.get_db_entry_format() != i64::from(self.entry_format) {
+            wr.rewrite_identries(audit)?;
+            wr.idlayer
+                .set_db_entry_format(i64::from(self.entry_format))?;
+        }
+        wr.commit(audit)
+    }
+
+    // Capture the write txns of the next window to path, to be replayed by
+    // `kanidmd replay`. As with the domain, this must be set before the
+    // backend is cloned.
+    pub fn set_capture(&mut self, path: &str, window: Duration) -> Result<(), OperationError> {
+        let capture = Capture::new(path, window).map_err(|e| {
+            error!("unable to create capture {} - {:?}", path, e);
+            OperationError::FsError
+        })?;
+        self.capture = Some(capture);
+        Ok(())
+    }
+
+    // Replay the capture at path into this backend, which should be empty,
+    // one txn at a time as they were committed, verifying after each. This
+    // stops at the first txn that leaves us inconsistent, or after txn until,
+    // and returns the last txn replayed with what verify found.
+    pub fn replay_capture(
+        &self,
+        audit: &mut AuditScope,
+        path: &str,
+        until: Option<u64>,
+    ) -> Result<(u64, Vec<Result<(), ConsistencyError>>), OperationError> {
+        let file = try_audit!(
+            audit,
+            fs::File::open(path),
+            "unable to open capture {:?}",
+            OperationError::FsError
+        );
+        let mut idxmeta = BTreeSet::new();
+        let mut last = (0, Vec::new());
+        for line in BufReader::new(file).lines() {
+            let line = try_audit!(
+                audit,
+                line,
+                "unable to read capture {:?}",
+                OperationError::FsError
+            );
+            let record: CaptureRecord = try_audit!(
+                audit,
+                serde_json::from_str(line.as_str()),
+                "invalid capture record {:?}",
+                OperationError::SerdeJsonError
+            );
+            audit_log!(audit, "replaying txn {}", record.txn);
+            let reindex = match record.idxmeta {
+                Some(meta) => {
+                    let meta: Option<BTreeSet<_>> = meta
+                        .into_iter()
+                        .map(|(attr, itype)| {
+                            IndexType::from_idx_str(itype.as_str()).map(|i| (attr, i))
+                        })
+                        .collect();
+                    idxmeta = meta.ok_or(OperationError::InvalidDBState)?;
+                    true
+                }
+                None => false,
+            };
+            let wr = self.write(idxmeta.clone())?;
+            // The indexes changed between txns, so are made as they were.
+            if reindex {
+                wr.reindex(audit)?;
+            }
+            for op in record.ops.into_iter() {
+                wr.replay(audit, op)?;
+            }
+            wr.commit(audit)?;
+
+            let r = self.read()?.verify(audit);
+            let done = r.iter().any(|v| v.is_err()) || until == Some(record.txn);
+            last = (record.txn, r);
+            if done {
+                break;
+            }
+        }
+        Ok(last)
+    }
+
+    // Sign changelog records with this key from here on.
+    pub fn set_changelog_key(&mut self, key: Option<[u8; 32]>) {
+        self.changelog_key = key;
+    }
+
+    pub fn read(&self) -> Result<BackendReadTransaction, OperationError> {
+        self.idlayer.read().map(|idlayer| BackendReadTransaction {
+            // The first read in the txn, so this is what our snapshot holds.
+            marker: idlayer.get_change_marker(),
+            idlayer: idlayer,
+            filtercache: self.filtercache.clone(),
+            entrycache: self.entrycache.clone(),
+            usage: self.usage.clone(),
+            journal: self.journal.clone(),
+            slowlog: self.slowlog.clone(),
+            writestats: self.writestats.clone(),
+            cursors: self.cursors.clone(),
+            filter_test_threshold: self.filter_test_threshold,
+        })
+    }
+
+    pub fn write(
+        &self,
+        idxmeta: BTreeSet<(String, IndexType)>,
+    ) -> Result<BackendWriteTransaction, OperationError> {
+        if let Some(primary) = self.get_primary() {
+            return Err(OperationError::ReadReplica(primary));
+        }
+        self.write_replicated(idxmeta)
+    }
+
+    // The changes we pulled from our primary, and the housekeeping every
+    // server does for itself, are still written on a read replica.
+    pub fn write_replicated(
+        &self,
+        idxmeta: BTreeSet<(String, IndexType)>,
+    ) -> Result<BackendWriteTransaction, OperationError> {
+        if self.is_read_only() {
+            return Err(OperationError::ReadOnly);
+        }
+        self.idlayer.write().map(|idlayer| BackendWriteTransaction {
+            marker: idlayer.get_change_marker(),
+            dirty: RefCell::new(Some(BTreeSet::new())),
+            idlayer: idlayer,
+            filtercache: self.filtercache.clone(),
+            entrycache: self.entrycache.clone(),
+            idxcache: RefCell::new(BTreeMap::new()),
+            backfill: RefCell::new(BTreeSet::new()),
+            changes: RefCell::new(ChangeSet::new()),
+            changestate: RefCell::new(ChangeState::new()),
+            changelog: RefCell::new(Vec::new()),
+            changelog_key: self.changelog_key,
+            domain: self.domain.clone(),
+            entry_format: self.entry_format,
+            idxmeta: idxmeta,
+            journal: self.journal.clone(),
+            slowlog: self.slowlog.clone(),
+            stats: RefCell::new(WriteStats::default()),
+            writestats: self.writestats.clone(),
+            capture: self.capture.clone().filter(|c| c.is_active()),
+            capture_ops: RefCell::new(Vec::new()),
+            filter_test_threshold: self.filter_test_threshold,
+        })
+    }
+
+    // Should this actually call the idlayer directly?
+    pub fn reset_domain_info(&self, audit: &mut AuditScope) -> Result<DomainInfo, OperationError> {
+        let wr = self.write(BTreeSet::new())?;
+        let domain = wr.reset_domain_info()?;
+        wr.commit(audit).map(|_| domain)
+    }
+
+    // The domain record is made on first use, and must be committed then so
+    // that every later caller sees the same one. The server id is its sid().
+    pub fn get_domain_info(&self, audit: &mut AuditScope) -> Result<DomainInfo, OperationError> {
+        let wr = self.write(BTreeSet::new())?;
+        let domain = wr.get_domain_info()?;
+        wr.commit(audit).map(|_| domain)
+    }
+
+    // Refresh the statistics used to plan searches, returning the number of
+    // indexes counted. This holds the write lock throughout, so the caller
+    // should only run it when the server is quiet.
+    pub fn analyze(&self, audit: &mut AuditScope) -> Result<usize, OperationError> {
+        audit_segment!(audit, || {
+            let wr = self.idlayer.write()?;
+            wr.analyze(audit)?;
+            let mut counted = 0;
+            for (attr, itype) in wr
+                .list_idxs(audit)?
+                .iter()
+                .filter_map(|t| parse_idx_table(t))
+            {
+                let keys = wr.count_idx_keys(audit, &attr, &itype)?;
+                audit_log!(audit, "analyze: {:?} {} has {} keys", itype, attr, keys);
+                wr.write_idx_cardinality(audit, &attr, &itype, keys)?;
+                counted += 1;
+            }
+            wr.commit(audit).map(|_| counted)
+        })
+    }
+
+    // Add the index reads and allids fallbacks counted since the last flush
+    // to those on disk.
+    pub fn flush_idx_usage(&self, audit: &mut AuditScope) -> Result<(), OperationError> {
+        let (usage, allids) = self.usage.take();
+        if usage.is_empty() && allids.is_empty() {
+            return Ok(());
+        }
+        audit_log!(
+            audit,
+            "flushing usage of {} index slots, {} allids fallbacks",
+            usage.len(),
+            allids.len()
+        );
+        let wr = self.idlayer.write()?;
+        wr.write_idx_usage(audit, &usage)
+            .and_then(|_| wr.write_idx_allids(audit, &allids))
+            .and_then(|_| wr.commit(audit))
+    }
+
+    // Write out the access journal records taken since the last flush. If
+    // that fails they are kept for the next, as they mustn't be lost.
+    pub fn flush_access_journal(&self, audit: &mut AuditScope) -> Result<(), OperationError> {
+        let records = self.journal.take();
+        if records.is_empty() {
+            return Ok(());
+        }
+        audit_log!(audit, "flushing {} access journal records", records.len());
+        let r = self.idlayer.write().and_then(|wr| {
+            wr.write_access_journal(audit, records.as_slice())
+                .and_then(|_| wr.commit(audit))
+        });
+        if r.is_err() {
+            self.journal.requeue(records);
+        }
+        r
+    }
+
+    // Write out the slow queries noted since the last flush. As with index
+    // usage, losing a round of these is fine.
+    pub fn flush_slow_queries(&self, audit: &mut AuditScope) -> Result<(), OperationError> {
+        let queries = self.slowlog.take();
+        if queries.is_empty() {
+            return Ok(());
+        }
+        audit_log!(audit, "flushing {} slow queries", queries.len());
+        let wr = self.idlayer.write()?;
+        wr.write_slow_queries(audit, queries.as_slice())
+            .and_then(|_| wr.commit(audit))
+    }
+
+    // Read the limit most used index slots, and the entries they and the
+    // system entries refer to, so their pages are already cached when the
+    // first searches arrive. These reads aren't counted as usage. Returns the
+    // number of slots and entries read.
+    pub fn warm_up(
+        &self,
+        audit: &mut AuditScope,
+        limit: usize,
+    ) -> Result<(usize, usize), OperationError> {
+        audit_segment!(audit, || {
+            let rd = self.idlayer.read()?;
+            let mut slots = 0;
+            let mut ids = IDLBitRange::new();
+            for (attr, itype, idx_key) in rd.get_idx_usage(audit, limit)?.iter() {
+                // The index may have been removed since this was recorded.
+                if let Some(idl) = rd.get_idl(audit, attr, itype, idx_key)? {
+                    slots += 1;
+                    ids = ids | idl;
+                }
+            }
+            if let Some(idl) = rd.get_idl(
+                audit,
+                &"class".to_string(),
+                &IndexType::EQUALITY,
+                &PartialValue::new_class("system").get_idx_eq_key(),
+            )? {
+                ids = ids | idl;
+            }
+            let entries = rd.get_identry(audit, &IDL::Indexed(ids))?.len();
+            audit_log!(
+                audit,
+                "warmed {} index slots and {} entries",
+                slots,
+                entries
+            );
+            Ok((slots, entries))
+        })
+    }
+}
+
+// What are the possible actions we'll recieve here?
+
+#[cfg(test)]
+mod tests {
+
+    use idlset::IDLBitRange;
+    use std::collections::{BTreeMap, BTreeSet};
+    use std::fs;
+    use std::iter::FromIterator;
+    use std::time::Duration;
+
+    use super::super::audit::AuditScope;
+    use super::super::entry::{Entry, EntryCommitted, EntryInvalid, EntryNew, EntryValid};
+    use super::dbentry::{
+        DbEntry, DbEntryVers, DBENTRY_BLOB_THRESHOLD, DBENTRY_FORMAT_CBOR,
+        DBENTRY_FORMAT_CBOR_ZSTD, DBENTRY_FORMAT_LEGACY,
+    };
+    use super::dbvalue::DbValueV1;
+    use super::idlayer::{IdLayerTransaction, IdLayerWriteTransaction};
+    use super::{
+        Backend, BackendTransaction, BackendWriteTransaction, IdEntry, OperationError,
+        SearchLimits, SortSpec, FILTER_COST_ALLIDS, IDL, SEARCH_PAGE_MAX,
+    };
+    use super::{
+        DEFAULT_DOMAIN, FILTER_TEST_THRESHOLD, GZIP_MAGIC, PARALLEL_SEARCH_THRESHOLD,
+        RESTORE_BATCH_SIZE, ZSTD_MAGIC,
+    };
+    use crate::config::{BackupCompression, BackupFormat, BackupKey, ExportSpec};
+    use crate::modify::{Modify, ModifyList};
+    use crate::schema::Schema;
+    use crate::value::{IndexType, PartialValue, Value};
+    use kanidm_proto::v1::{ConsistencyError, ExplainIdl, ExplainShortcut};
+    use uuid::Uuid;
+
+    macro_rules! run_test {
+        ($test_fn:expr) => {{
+            use env_logger;
+            ::std::env::set_var("RUST_LOG", "kanidm=debug");
+            let _ = env_logger::builder().is_test(true).try_init();
+
+            let mut audit = AuditScope::new("run_test");
+
+            let be = Backend::new(&mut audit, "", 1, FILTER_TEST_THRESHOLD)
+                .expect("Failed to setup backend");
+
+            // This is a demo idxmeta, purely for testing.
+            let mut idxmeta = BTreeSet::new();
+            idxmeta.insert(("name".to_string(), IndexType::EQUALITY));
+            idxmeta.insert(("name".to_string(), IndexType::PRESENCE));
+            idxmeta.insert(("name".to_string(), IndexType::SUBSTRING));
+            idxmeta.insert(("uuid".to_string(), IndexType::EQUALITY));
+            idxmeta.insert(("uuid".to_string(), IndexType::PRESENCE));
+            idxmeta.insert(("ta".to_string(), IndexType::EQUALITY));
+            idxmeta.insert(("tb".to_string(), IndexType::EQUALITY));
+            let mut be_txn = be.write(idxmeta).expect("Failed to begin txn");
+
+            // Could wrap another future here for the future::ok bit...
+            let r = $test_fn(&mut audit, &mut be_txn);
+            // Commit, to guarantee it worked.
+            assert!(be_txn.commit(&mut audit).is_ok());
+            println!("{}", audit);
+            r
+        }};
+    }
+
+    macro_rules! entry_exists {
+        ($audit:expr, $be:expr, $ent:expr) => {{
+            let ei = unsafe { $ent.clone().to_valid_committed() };
+            let filt = unsafe {
+                ei.filter_from_attrs(&vec![String::from("userid")])
+                    .expect("failed to generate filter")
+                    .to_valid_resolved()
+            };
+            let entries = $be.search($audit, &filt).expect("failed to search");
+            entries.first().is_some()
+        }};
+    }
+
+    macro_rules! entry_attr_pres {
+        ($audit:expr, $be:expr, $ent:expr, $attr:expr) => {{
+            let ei = unsafe { $ent.clone().to_valid_committed() };
+            let filt = unsafe {
+                ei.filter_from_attrs(&vec![String::from("userid")])
+                    .expect("failed to generate filter")
+                    .to_valid_resolved()
+            };
+            let entries = $be.search($audit, &filt).expect("failed to search");
+            match entries.first() {
+                Some(ent) => ent.attribute_pres($attr),
+                None => false,
+            }
+        }};
+    }
+
+    macro_rules! idl_state {
+        ($audit:expr, $be:expr, $attr:expr, $itype:expr, $idx_key:expr, $expect:expr) => {{
+            let t_idl = $be
+                .load_test_idl($audit, &$attr.to_string(), &$itype, &$idx_key.to_string())
+                .expect("IDL Load failed");
+            let t = $expect.map(|v: Vec<u64>| IDLBitRange::from_iter(v));
+            assert_eq!(t_idl, t);
+        }};
+    }
+
+    #[test]
+    fn test_be_simple_create() {
+        run_test!(|audit: &mut AuditScope, be: &mut BackendWriteTransaction| {
+            audit_log!(audit, "Simple Create");
+
+            let empty_result = be.create(audit, Vec::new());
+            audit_log!(audit, "{:?}", empty_result);
+            assert_eq!(empty_result, Err(OperationError::EmptyRequest));
+
+            let mut e: Entry<EntryInvalid, EntryNew> = Entry::new();
+            e.add_ava("userid", &Value::from("william"));
+            e.add_ava("uuid", &Value::from("db237e8a-0079-4b8c-8a56-593b22aa44d1"));
+            let e = unsafe { e.to_valid_new() };
+
+            let single_result = be.create(audit, vec![e.clone()]);
+
+            assert!(single_result.is_ok());
+
+            // Construct a filter
+            assert!(entry_exists!(audit, be, e));
+        });
+    }
+
+    #[test]
+    fn test_be_savepoint() {
+        run_test!(|audit: &mut AuditScope, be: &mut BackendWriteTransaction| {
+            let mk = |name: &str, u: &str| {
+                let mut e: Entry<EntryInvalid, EntryNew> = Entry::new();
+                e.add_ava("name", &Value::new_iutf8s(name));
+                e.add_ava("uuid", &Value::from(u));
+                unsafe { e.to_valid_new() }
+            };
+            macro_rules! count {
+                ($name:expr) => {{
+                    let filt =
+                        unsafe { filter_resolved!(f_eq("name", PartialValue::new_iutf8s($name))) };
+                    be.search(audit, &filt).expect("Search failed!").len()
+                }};
+            }
+            assert!(be
+                .create(
+                    audit,
+                    vec![mk("william", "db237e8a-0079-4b8c-8a56-593b22aa44d1")]
+                )
+                .is_ok());
+
+            // The rolled back create leaves no entry, index or change behind.
+            let sp = be.savepoint(audit).expect("savepoint failed");
+            assert!(be
+                .create(
+                    audit,
+                    vec![mk("claire", "4b6228ab-1dbe-42a4-a9f5-f6368222438e")]
+                )
+                .is_ok());
+            assert_eq!(count!("claire"), 1);
+            assert!(be.rollback_to_savepoint(audit, sp).is_ok());
+            assert_eq!(count!("claire"), 0);
+            assert_eq!(count!("william"), 1);
+            assert_eq!(be.changes.borrow().uuids.len(), 1);
+
+            // A released one keeps what it wrote.
+            let sp = be.savepoint(audit).expect("savepoint failed");
+            assert!(be
+                .create(
+                    audit,
+                    vec![mk("claire", "4b6228ab-1dbe-42a4-a9f5-f6368222438e")]
+                )
+                .is_ok());
+            assert!(be.release_savepoint(audit, sp).is_ok());
+            assert_eq!(count!("claire"), 1);
+            assert_eq!(be.changes.borrow().uuids.len(), 2);
+        });
+    }
+
+    #[test]
+    fn test_be_simple_search() {
+        run_test!(|audit: &mut AuditScope, be: &mut BackendWriteTransaction| {
+            audit_log!(audit, "Simple Search");
+
+            let mut e: Entry<EntryInvalid, EntryNew> = Entry::new();
+            e.add_ava("userid", &Value::from("claire"));
+            e.add_ava("uuid", &Value::from("db237e8a-0079-4b8c-8a56-593b22aa44d1"));
+            let e = unsafe { e.to_valid_new() };
+
+            let single_result = be.create(audit, vec![e.clone()]);
+            assert!(single_result.is_ok());
+            // Test a simple EQ search
+
+            let filt =
+                unsafe { filter_resolved!(f_eq("userid", PartialValue::new_utf8s("claire"))) };
+
+            let r = be.search(audit, &filt);
+            assert!(r.expect("Search failed!").len() == 1);
+
+            // Test empty search
+
+            // Test class pres
+
+            // Search with no results
+        });
+    }
+
+    #[test]
+    fn test_be_search_limited() {
+        run_test!(|audit: &mut AuditScope, be: &mut BackendWriteTransaction| {
+            let mut e1: Entry<EntryInvalid, EntryNew> = Entry::new();
+            e1.add_ava("name", &Value::from("william"));
+            e1.add_ava("userid", &Value::from("william"));
+            e1.add_ava("uuid", &Value::from("db237e8a-0079-4b8c-8a56-593b22aa44d1"));
+            let e1 = unsafe { e1.to_valid_new() };
+
+            let mut e2: Entry<EntryInvalid, EntryNew> = Entry::new();
+            e2.add_ava("name", &Value::from("claire"));
+            e2.add_ava("userid", &Value::from("claire"));
+            e2.add_ava("uuid", &Value::from("bd651620-00dd-426b-aaa0-4494f7b7906f"));
+            let e2 = unsafe { e2.to_valid_new() };
+
+            assert!(be.create(audit, vec![e1, e2]).is_ok());
+
+            // Fully indexed, so this is refused before we touch id2entry.
+            let f_pres = unsafe { filter_resolved!(f_pres("name")) };
+            let results = |n| SearchLimits {
+                max_results: Some(n),
+                ..SearchLimits::unlimited()
+            };
+            assert_eq!(
+                be.search_limited(audit, &f_pres, &results(1)),
+                Err(OperationError::ResourceLimit)
+            );
+            assert!(
+                be.search_limited(audit, &f_pres, &results(2))
+                    .unwrap()
+                    .len()
+                    == 2
+            );
+            assert!(
+                be.search_limited(audit, &f_pres, &SearchLimits::unlimited())
+                    .unwrap()
+                    .len()
+                    == 2
+            );
+
+            // Unindexed, so the limit applies after the entry filter test.
+            let f_un = unsafe { filter_resolved!(f_pres("userid")) };
+            assert_eq!(
+                be.search_limited(audit, &f_un, &results(1)),
+                Err(OperationError::ResourceLimit)
+            );
+            assert!(be.search_limited(audit, &f_un, &results(2)).unwrap().len() == 2);
+
+            // But every entry must be loaded to test it, which is refused
+            // when there are more than we may examine.
+            let candidates = |n| SearchLimits {
+                max_candidates: Some(n),
+                ..SearchLimits::unlimited()
+            };
+            assert_eq!(
+                be.search_limited(audit, &f_un, &candidates(1)),
+                Err(OperationError::ResourceLimit)
+            );
+            assert!(be.search_limited(audit, &f_un, &candidates(2)).is_ok());
+            assert_eq!(
+                be.search_limited(audit, &f_pres, &candidates(1)),
+                Err(OperationError::ResourceLimit)
+            );
+
+            // Out of time before it started.
+            let no_time = SearchLimits {
+                max_time: Some(Duration::from_secs(0)),
+                ..SearchLimits::unlimited()
+            };
+            assert_eq!(
+                be.search_limited(audit, &f_un, &no_time),
+                Err(OperationError::ResourceLimit)
+            );
+        });
+    }
+
+    #[test]
+    fn test_be_explain() {
+        run_test!(|audit: &mut AuditScope, be: &mut BackendWriteTransaction| {
+            let mut e1: Entry<EntryInvalid, EntryNew> = Entry::new();
+            e1.add_ava("name", &Value::from("william"));
+            e1.add_ava("uuid", &Value::from("db237e8a-0079-4b8c-8a56-593b22aa44d1"));
+            e1.add_ava("no-index", &Value::from("william"));
+            let e1 = unsafe { e1.to_valid_new() };
+
+            let mut e2: Entry<EntryInvalid, EntryNew> = Entry::new();
+            e2.add_ava("name", &Value::from("claire"));
+            e2.add_ava("uuid", &Value::from("bd651620-00dd-426b-aaa0-4494f7b7906f"));
+            let e2 = unsafe { e2.to_valid_new() };
+
+            assert!(be.create(audit, vec![e1, e2]).is_ok());
+
+            // Fully indexed, no entry test needed.
+            let f_eq = unsafe { filter_resolved!(f_eq("name", PartialValue::new_utf8s("claire"))) };
+            let ex = be.explain(audit, &f_eq).expect("explain failed");
+            assert_eq!(ex.result, ExplainIdl::Indexed(1));
+            assert_eq!(ex.terms.len(), 1);
+            assert_eq!(ex.terms[0].term, "eq name claire");
+            assert!(!ex.filter_test);
+            assert_eq!(ex.matched, 1);
+
+            // Unindexed falls back to testing every entry.
+            let f_un =
+                unsafe { filter_resolved!(f_eq("no-index", PartialValue::new_utf8s("william"))) };
+            let ex = be.explain(audit, &f_un).expect("explain failed");
+            assert_eq!(ex.result, ExplainIdl::AllIds);
+            assert!(ex.filter_test);
+            assert_eq!(ex.candidates, 2);
+            assert_eq!(ex.matched, 1);
+
+            // Name is resolved first, and leaves so few candidates that the
+            // unindexed term is never looked at.
+            let f_and = unsafe {
+                filter_resolved!(f_and!([
+                    f_eq("no-index", PartialValue::new_utf8s("william")),
+                    f_eq("name", PartialValue::new_utf8s("william"))
+                ]))
+            };
+            let ex = be.explain(audit, &f_and).expect("explain failed");
+            assert_eq!(ex.result, ExplainIdl::Partial(1));
+            assert_eq!(ex.terms.len(), 2);
+            assert_eq!(ex.terms[0].shortcut, Some(ExplainShortcut::AndThreshold));
+            assert_eq!(ex.terms[0].skipped, 1);
+            assert_eq!(ex.terms[1].depth, 1);
+            assert_eq!(ex.terms[1].term, "eq name william");
+            assert_eq!(ex.terms[1].idl, ExplainIdl::Indexed(1));
+            assert!(ex.filter_test);
+            assert_eq!(ex.candidates, 1);
+            assert_eq!(ex.matched, 1);
+
+            // One unindexed term makes the whole or unindexed.
+            let f_or = unsafe {
+                filter_resolved!(f_or!([
+                    f_eq("no-index", PartialValue::new_utf8s("william")),
+                    f_eq("name", PartialValue::new_utf8s("claire"))
+                ]))
+            };
+            let ex = be.explain(audit, &f_or).expect("explain failed");
+            assert_eq!(ex.result, ExplainIdl::AllIds);
+            assert_eq!(ex.candidates, 2);
+            assert_eq!(ex.matched, 2);
+            assert_eq!(ex.terms[0].shortcut, Some(ExplainShortcut::OrAllIds));
+            assert_eq!(ex.terms[0].skipped, 1);
+            assert_eq!(ex.terms[1].idl, ExplainIdl::AllIds);
+        });
+    }
+
+    #[test]
+    fn test_be_simple_modify() {
+        run_test!(|audit: &mut AuditScope, be: &mut BackendWriteTransaction| {
+            audit_log!(audit, "Simple Modify");
+            // First create some entries (3?)
+            let mut e1: Entry<EntryInvalid, EntryNew> = Entry::new();
+            e1.add_ava("userid", &Value::from("william"));
+            e1.add_ava("uuid", &Value::from("db237e8a-0079-4b8c-8a56-593b22aa44d1"));
+
+            let mut e2: Entry<EntryInvalid, EntryNew> = Entry::new();
+            e2.add_ava("userid", &Value::from("alice"));
+            e2.add_ava("uuid", &Value::from("4b6228ab-1dbe-42a4-a9f5-f6368222438e"));
+
+            let ve1 = unsafe { e1.clone().to_valid_new() };
+            let ve2 = unsafe { e2.clone().to_valid_new() };
+
+            assert!(be.create(audit, vec![ve1, ve2]).is_ok());
+            assert!(entry_exists!(audit, be, e1));
+            assert!(entry_exists!(audit, be, e2));
+
+            // You need to now retrieve the entries back out to get the entry id's
+            let mut results = be
+                .search(audit, unsafe { &filter_resolved!(f_pres("userid")) })
+                .expect("Failed to search");
+
+            // Get these out to usable entries.
+            let r1 = results.remove(0);
+            let r2 = results.remove(0);
+
+            let mut r1 = r1.invalidate();
+            let mut r2 = r2.invalidate();
+
+            // Modify no id (err)
+            // This is now impossible due to the state machine design.
+            // However, with some unsafe ....
+            let ue1 = unsafe { e1.clone().to_valid_committed() };
+            assert!(be.modify(audit, &vec![ue1.clone()], &vec![ue1]).is_err());
+            // Modify none
+            assert!(be.modify(audit, &vec![], &vec![]).is_err());
+
+            // Make some changes to r1, r2.
+            let pre1 = unsafe { r1.clone().to_valid_committed() };
+            let pre2 = unsafe { r2.clone().to_valid_committed() };
+            r1.add_ava("desc", &Value::from("modified"));
+            r2.add_ava("desc", &Value::from("modified"));
+
+            // Now ... cheat.
+
+            let vr1 = unsafe { r1.to_valid_committed() };
+            let vr2 = unsafe { r2.to_valid_committed() };
+
+            // Modify single
+            assert!(be
+                .modify(audit, &vec![pre1.clone()], &vec![vr1.clone()])
+                .is_ok());
+            // Assert no other changes
+            assert!(entry_attr_pres!(audit, be, vr1, "desc"));
+            assert!(!entry_attr_pres!(audit, be, vr2, "desc"));
+
+            // Modify both
+            assert!(be
+                .modify(
+                    audit,
+                    &vec![vr1.clone(), pre2.clone()],
+                    &vec![vr1.clone(), vr2.clone()]
+                )
+                .is_ok());
+
+            assert!(entry_attr_pres!(audit, be, vr1, "desc"));
+            assert!(entry_attr_pres!(audit, be, vr2, "desc"));
+        });
+    }
+
+    #[test]
+    fn test_be_modify_unchanged() {
+        run_test!(|audit: &mut AuditScope, be: &mut BackendWriteTransaction| {
+            let mut e1: Entry<EntryInvalid, EntryNew> = Entry::new();
+            e1.add_ava("userid", &Value::from("william"));
+            e1.add_ava("uuid", &Value::from("db237e8a-0079-4b8c-8a56-593b22aa44d1"));
+            let e1 = unsafe { e1.to_valid_new() };
+            assert!(be.create(audit, vec![e1]).is_ok());
+
+            let r1 = be
+                .search(audit, unsafe { &filter_resolved!(f_pres("userid")) })
+                .expect("Failed to search")
+                .remove(0);
+
+            // Claim the entry has desc, and that it's unchanged. As pre == post
+            // nothing is written, so the stored entry still lacks it.
+            let mut r1 = r1.invalidate();
+            r1.add_ava("desc", &Value::from("modified"));
+            let vr1 = unsafe { r1.to_valid_committed() };
+            assert!(be
+                .modify(audit, &vec![vr1.clone()], &vec![vr1.clone()])
+                .is_ok());
+            assert!(!entry_attr_pres!(audit, be, vr1, "desc"));
+        });
+    }
+
+    #[test]
+    fn test_be_modify_modlist() {
+        run_test!(|audit: &mut AuditScope, be: &mut BackendWriteTransaction| {
+            let schema_outer = Schema::new(audit).expect("Failed to init schema");
+            let schema = schema_outer.read();
+
+            let u1 = Uuid::parse_str("db237e8a-0079-4b8c-8a56-593b22aa44d1").unwrap();
+            let u2 = Uuid::parse_str("4b6228ab-1dbe-42a4-a9f5-f6368222438e").unwrap();
+            let entries: Vec<_> = vec![&u1, &u2]
+                .into_iter()
+                .map(|u| {
+                    let mut e: Entry<EntryInvalid, EntryNew> = Entry::new();
+                    e.add_ava("class", &Value::new_class("object"));
+                    e.add_ava("uuid", &Value::new_uuidr(u));
+                    e.add_ava("description", &Value::new_utf8s("a"));
+                    unsafe { e.to_valid_new() }
+                })
+                .collect();
+            assert!(be.create(audit, entries).is_ok());
+
+            let set_desc = |v: &str| unsafe {
+                ModifyList::new_valid_list(vec![
+                    Modify::Purged("description".to_string()),
+                    Modify::Present("description".to_string(), Value::new_utf8s(v)),
+                ])
+            };
+
+            let post = be
+                .modify_modlist(audit, &schema, &[(u1.clone(), set_desc("b"))])
+                .expect("modify_modlist failed");
+            assert_eq!(post.len(), 1);
+            assert!(post[0].attribute_value_pres("description", &PartialValue::new_utf8s("b")));
+
+            // The change was written, and only to the one entry.
+            let f_b =
+                unsafe { filter_resolved!(f_eq("description", PartialValue::new_utf8s("b"))) };
+            let r = be.search(audit, &f_b).expect("search failed");
+            assert_eq!(r.len(), 1);
+            assert_eq!(r[0].get_uuid(), &u1);
+
+            // Unknown uuids, duplicates and invalid results are refused.
+            let u3 = Uuid::parse_str("7b23c99d-c06b-4a9a-a958-3afa56383e1d").unwrap();
+            assert_eq!(
+                be.modify_modlist(audit, &schema, &[(u3, set_desc("c"))]),
+                Err(OperationError::NoMatchingEntries)
+            );
+            assert_eq!(
+                be.modify_modlist(
+                    audit,
+                    &schema,
+                    &[(u2.clone(), set_desc("c")), (u2.clone(), set_desc("d"))]
+                ),
+                Err(OperationError::InvalidRequestState)
+            );
+            let ml_bad =
+                unsafe { ModifyList::new_valid_list(vec![Modify::Purged("uuid".to_string())]) };
+            assert!(be
+                .modify_modlist(audit, &schema, &[(u2.clone(), ml_bad)])
+                .is_err());
+        });
+    }
+
+    #[test]
+    fn test_be_rename_cascade() {
+        run_test!(|audit: &mut AuditScope, be: &mut BackendWriteTransaction| {
+            let schema_outer = Schema::new(audit).expect("Failed to init schema");
+            let schema = schema_outer.read();
+
+            let target = Uuid::parse_str("db237e8a-0079-4b8c-8a56-593b22aa44d1").unwrap();
+            let u1 = Uuid::parse_str("4b6228ab-1dbe-42a4-a9f5-f6368222438e").unwrap();
+            let u2 = Uuid::parse_str("7b23c99d-c06b-4a9a-a958-3afa56383e1d").unwrap();
+            let u3 = Uuid::parse_str("bd651620-00dd-426b-aaa0-4494f7b7906f").unwrap();
+            // u1 and u2 refer to the target, u3 doesn't.
+            let entries: Vec<_> = vec![(&u1, Some(&target)), (&u2, Some(&target)), (&u3, None)]
+                .into_iter()
+                .map(|(u, m)| {
+                    let mut e: Entry<EntryInvalid, EntryNew> = Entry::new();
+                    e.add_ava("class", &Value::new_class("object"));
+                    e.add_ava("class", &Value::new_class("extensibleobject"));
+                    e.add_ava("uuid", &Value::new_uuidr(u));
+                    e.add_ava("description", &Value::new_utf8s("old name"));
+                    if let Some(m) = m {
+                        e.add_ava("member", &Value::new_refer_r(m));
+                    }
+                    unsafe { e.to_valid_new() }
+                })
+                .collect();
+            assert!(be.create(audit, entries).is_ok());
+
+            let set_desc = || unsafe {
+                ModifyList::new_valid_list(vec![
+                    Modify::Purged("description".to_string()),
+                    Modify::Present("description".to_string(), Value::new_utf8s("new name")),
+                ])
+            };
+            let post = be
+                .rename_cascade(audit, &schema, &target, |e| {
+                    // Referrers that are already current are left alone.
+                    if e.get_uuid() == &u2 {
+                        None
+                    } else {
+                        Some(set_desc())
+                    }
+                })
+                .expect("rename_cascade failed");
+            assert_eq!(post.len(), 1);
+            assert_eq!(post[0].get_uuid(), &u1);
+
+            let f_new = unsafe {
+                filter_resolved!(f_eq("description", PartialValue::new_utf8s("new name")))
+            };
+            let r = be.search(audit, &f_new).expect("search failed");
+            assert_eq!(r.len(), 1);
+            assert_eq!(r[0].get_uuid(), &u1);
+
+            // Nothing refers to u3, so there is nothing to do.
+            let post = be
+                .rename_cascade(audit, &schema, &u3, |_| Some(set_desc()))
+                .expect("rename_cascade failed");
+            assert!(post.is_empty());
+        });
+    }
+
+    #[test]
+    fn test_be_simple_delete() {
+        run_test!(|audit: &mut AuditScope, be: &mut BackendWriteTransaction| {
+            audit_log!(audit, "Simple Delete");
+
+            // First create some entries (3?)
+            let mut e1: Entry<EntryInvalid, EntryNew> = Entry::new();
+            e1.add_ava("userid", &Value::from("william"));
+            e1.add_ava("uuid", &Value::from("db237e8a-0079-4b8c-8a56-593b22aa44d1"));
+
+            let mut e2: Entry<EntryInvalid, EntryNew> = Entry::new();
+            e2.add_ava("userid", &Value::from("alice"));
+            e2.add_ava("uuid", &Value::from("4b6228ab-1dbe-42a4-a9f5-f6368222438e"));
+
+            let mut e3: Entry<EntryInvalid, EntryNew> = Entry::new();
+            e3.add_ava("userid", &Value::from("lucy"));
+            e3.add_ava("uuid", &Value::from("7b23c99d-c06b-4a9a-a958-3afa56383e1d"));
+
+            let ve1 = unsafe { e1.clone().to_valid_new() };
+            let ve2 = unsafe { e2.clone().to_valid_new() };
+            let ve3 = unsafe { e3.clone().to_valid_new() };
+
+            assert!(be.create(audit, vec![ve1, ve2, ve3]).is_ok());
+            assert!(entry_exists!(audit, be, e1));
+            assert!(entry_exists!(audit, be, e2));
+            assert!(entry_exists!(audit, be, e3));
+
+            // You need to now retrieve the entries back out to get the entry id's
+            let mut results = be
+                .search(audit, unsafe { &filter_resolved!(f_pres("userid")) })
+                .expect("Failed to search");
+
+            // Get these out to usable entries.
+            let r1 = results.remove(0);
+            let r2 = results.remove(0);
+            let r3 = results.remove(0);
+
+            // Delete one
+            assert!(be.delete(audit, &vec![r1.clone()]).is_ok());
+            assert!(!entry_exists!(audit, be, r1));
+
+            // delete none (no match filter)
+            assert!(be.delete(audit, &vec![]).is_err());
+
+            // Delete with no id
+            // WARNING: Normally, this isn't possible, but we are pursposefully breaking
+            // the state machine rules here!!!!
+            let mut e4: Entry<EntryInvalid, EntryNew> = Entry::new();
+            e4.add_ava("userid", &Value::from("amy"));
+            e4.add_ava("uuid", &Value::from("21d816b5-1f6a-4696-b7c1-6ed06d22ed81"));
+
+            let ve4 = unsafe { e4.clone().to_valid_committed() };
+
+            assert!(be.delete(audit, &vec![ve4]).is_err());
+
+            assert!(entry_exists!(audit, be, r2));
+            assert!(entry_exists!(audit, be, r3));
+
+            // delete batch
+            assert!(be.delete(audit, &vec![r2.clone(), r3.clone()]).is_ok());
+
+            assert!(!entry_exists!(audit, be, r2));
+            assert!(!entry_exists!(audit, be, r3));
+
+            // delete none (no entries left)
+            // see fn delete for why this is ok, not err
+            assert!(be.delete(audit, &vec![r2.clone(), r3.clone()]).is_ok());
+        });
+    }
+
+    pub static DB_BACKUP_FILE_NAME: &'static str = "./.backup_test.db";
+
+    #[test]
+    fn test_be_backup_restore() {
+        run_test!(|audit: &mut AuditScope, be: &mut BackendWriteTransaction| {
+            // First create some entries (3?)
+            let mut e1: Entry<EntryInvalid, EntryNew> = Entry::new();
+            e1.add_ava("userid", &Value::from("william"));
+            e1.add_ava("uuid", &Value::from("db237e8a-0079-4b8c-8a56-593b22aa44d1"));
+
+            let mut e2: Entry<EntryInvalid, EntryNew> = Entry::new();
+            e2.add_ava("userid", &Value::from("alice"));
+            e2.add_ava("uuid", &Value::from("4b6228ab-1dbe-42a4-a9f5-f6368222438e"));
+
+            let mut e3: Entry<EntryInvalid, EntryNew> = Entry::new();
+            e3.add_ava("userid", &Value::from("lucy"));
+            e3.add_ava("uuid", &Value::from("7b23c99d-c06b-4a9a-a958-3afa56383e1d"));
+
+            let ve1 = unsafe { e1.clone().to_valid_new() };
+            let ve2 = unsafe { e2.clone().to_valid_new() };
+            let ve3 = unsafe { e3.clone().to_valid_new() };
+
+            assert!(be.create(audit, vec![ve1, ve2, ve3]).is_ok());
+            assert!(entry_exists!(audit, be, e1));
+            assert!(entry_exists!(audit, be, e2));
+            assert!(entry_exists!(audit, be, e3));
+
+            let result = fs::remove_file(DB_BACKUP_FILE_NAME);
+
+            match result {
+                Err(e) => {
+                    // if the error is the file is not found, thats what we want so continue,
+                    // otherwise return the error
+                    match e.kind() {
+                        std::io::ErrorKind::NotFound => {}
+                        _ => (),
+                    }
+                }
+                _ => (),
+            }
+
+            be.backup(audit, DB_BACKUP_FILE_NAME)
+                .expect("Backup failed!");
+            be.restore(audit, DB_BACKUP_FILE_NAME)
+                .expect("Restore failed!");
+        });
+    }
+
+    #[test]
+    fn test_be_backup_compressed() {
+        run_test!(|audit: &mut AuditScope, be: &mut BackendWriteTransaction| {
+            let mut e1: Entry<EntryInvalid, EntryNew> = Entry::new();
+            e1.add_ava("name", &Value::from("william"));
+            e1.add_ava("uuid", &Value::from("db237e8a-0079-4b8c-8a56-593b22aa44d1"));
+            let e1 = unsafe { e1.to_valid_new() };
+            assert!(be.create(audit, vec![e1]).is_ok());
+
+            let f_pres = unsafe { filter_resolved!(f_pres("name")) };
+            vec![
+                (
+                    "./.backup_gzip_test.db",
+                    BackupCompression::Gzip,
+                    GZIP_MAGIC.to_vec(),
+                ),
+                (
+                    "./.backup_zstd_test.db",
+                    BackupCompression::Zstd,
+                    ZSTD_MAGIC.to_vec(),
+                ),
+            ]
+            .into_iter()
+            .for_each(|(path, compression, magic)| {
+                be.backup_ext(audit, path, compression, BackupFormat::Json, None)
+                    .expect("Backup failed!");
+                assert!(fs::read(path).unwrap().starts_with(magic.as_slice()));
+                // Restore works out the compression on its own.
+                be.restore(audit, path).expect("Restore failed!");
+                assert_eq!(be.search(audit, &f_pres).unwrap().len(), 1);
+                let _ = fs::remove_file(path);
+            });
+        });
+    }
+
+    #[test]
+    fn test_be_backup_ldif() {
+        run_test!(|audit: &mut AuditScope, be: &mut BackendWriteTransaction| {
+            let mut e1: Entry<EntryInvalid, EntryNew> = Entry::new();
+            e1.add_ava("name", &Value::from("william"));
+            e1.add_ava("uuid", &Value::from("db237e8a-0079-4b8c-8a56-593b22aa44d1"));
+            let e1 = unsafe { e1.to_valid_new() };
+            assert!(be.create(audit, vec![e1]).is_ok());
+
+            let path = "./.backup_ldif_test.ldif";
+            be.backup_ext(
+                audit,
+                path,
+                BackupCompression::None,
+                BackupFormat::Ldif,
+                None,
+            )
+            .expect("Backup failed!");
+            let ldif = fs::read_to_string(path).expect("failed to read ldif");
+            let _ = fs::remove_file(path);
+            assert_eq!(
+                ldif,
+                "version: 1\n\
+                 dn: uuid=db237e8a-0079-4b8c-8a56-593b22aa44d1\n\
+                 name: william\n\
+                 uuid: db237e8a-0079-4b8c-8a56-593b22aa44d1\n\n"
+            );
+        });
+    }
+
+    #[test]
+    fn test_be_export_csv() {
+        run_test!(|audit: &mut AuditScope, be: &mut BackendWriteTransaction| {
+            let mut e1: Entry<EntryInvalid, EntryNew> = Entry::new();
+            e1.add_ava("class", &Value::new_class("person"));
+            e1.add_ava("name", &Value::from("william"));
+            e1.add_ava("uuid", &Value::from("db237e8a-0079-4b8c-8a56-593b22aa44d1"));
+            let e1 = unsafe { e1.to_valid_new() };
+            let mut e2: Entry<EntryInvalid, EntryNew> = Entry::new();
+            e2.add_ava("class", &Value::new_class("group"));
+            e2.add_ava("name", &Value::from("testgroup"));
+            e2.add_ava("uuid", &Value::from("4b6228ab-1dbe-42a4-a9f5-f6368222438e"));
+            let e2 = unsafe { e2.to_valid_new() };
+            assert!(be.create(audit, vec![e1, e2]).is_ok());
+
+            let spec = ExportSpec {
+                classes: vec!["person".to_string(), "group".to_string()],
+                attrs: vec!["name".to_string()],
+                redact: BTreeMap::new(),
+            };
+            let path = "./.export_csv_test";
+            be.export_csv(audit, path, &spec).expect("Export failed!");
+            let person = fs::read_to_string(format!("{}/person.csv", path)).unwrap();
+            let group = fs::read_to_string(format!("{}/group.csv", path)).unwrap();
+            let _ = fs::remove_dir_all(path);
+            assert_eq!(
+                person,
+                "uuid,name\r\ndb237e8a-0079-4b8c-8a56-593b22aa44d1,william\r\n"
+            );
+            assert_eq!(
+                group,
+                "uuid,name\r\n4b6228ab-1dbe-42a4-a9f5-f6368222438e,testgroup\r\n"
+            );
+        });
+    }
+
+    #[test]
+    fn test_be_backup_encrypted() {
+        run_test!(|audit: &mut AuditScope, be: &mut BackendWriteTransaction| {
+            let backup_path = "./.backup_encrypted_test.db";
+            let mut e1: Entry<EntryInvalid, EntryNew> = Entry::new();
+            e1.add_ava("name", &Value::from("william"));
+            e1.add_ava("uuid", &Value::from("db237e8a-0079-4b8c-8a56-593b22aa44d1"));
+            let e1 = unsafe { e1.to_valid_new() };
+            assert!(be.create(audit, vec![e1]).is_ok());
+
+            let key = BackupKey::Passphrase("correct horse".to_string());
+            be.backup_ext(
+                audit,
+                backup_path,
+                BackupCompression::Zstd,
+                BackupFormat::Json,
+                Some(&key),
+            )
+            .expect("Backup failed!");
+            assert!(!fs::read_to_string(backup_path)
+                .map(|s| s.contains("william"))
+                .unwrap_or(false));
+
+            // Without the key there is nothing to restore from.
+            assert_eq!(
+                be.restore_batched(audit, backup_path, 10, None),
+                Err(OperationError::InvalidRequestState)
+            );
+            be.restore_batched(audit, backup_path, 10, Some(&key))
+                .expect("Restore failed!");
+            let f_pres = unsafe { filter_resolved!(f_pres("name")) };
+            assert_eq!(be.search(audit, &f_pres).unwrap().len(), 1);
+            let _ = fs::remove_file(backup_path);
+        });
+    }
+
+    #[test]
+    fn test_be_restore_batched() {
+        run_test!(|audit: &mut AuditScope, be: &mut BackendWriteTransaction| {
+            let backup_path = "./.backup_batched_test.db";
+            let names = vec!["william", "alice", "lucy", "claire", "sam"];
+            let entries: Vec<_> = names
+                .iter()
+                .map(|n| {
+                    let mut e: Entry<EntryInvalid, EntryNew> = Entry::new();
+                    e.add_ava("name", &Value::from(*n));
+                    e.add_ava("uuid", &Value::new_uuid(uuid::Uuid::new_v4()));
+                    unsafe { e.to_valid_new() }
+                })
+                .collect();
+            assert!(be.create(audit, entries).is_ok());
+
+            let f_pres = unsafe { filter_resolved!(f_pres("name")) };
+            let f_lucy = unsafe { filter_resolved!(f_eq("name", PartialValue::new_utf8s("lucy"))) };
+
+            // A batch size that doesn't divide the entry count, so the tail
+            // batch is exercised too.
+            be.backup(audit, backup_path).expect("Backup failed!");
+            be.restore_batched(audit, backup_path, 2, None)
+                .expect("Restore failed!");
+            assert_eq!(be.search(audit, &f_pres).unwrap().len(), 5);
+            // Indexes were built as the batches went in.
+            match be.filter2idl(audit, f_lucy.to_inner(), 0).unwrap() {
+                IDL::Indexed(idl) => assert_eq!(idl.len(), 1),
+                _ => panic!(""),
+            }
+
+            // The same entries, one per line.
+            let array: Vec<serde_json::Value> =
+                serde_json::from_str(&fs::read_to_string(backup_path).unwrap()).unwrap();
+            let ndjson: Vec<String> = array.iter().map(|v| v.to_string()).collect();
+            fs::write(backup_path, ndjson.join("\n")).unwrap();
+
+            be.restore_batched(audit, backup_path, 2, None)
+                .expect("Restore failed!");
+            assert_eq!(be.search(audit, &f_pres).unwrap().len(), 5);
+
+            assert_eq!(
+                be.restore_batched(audit, backup_path, 0, None),
+                Err(OperationError::InvalidState)
+            );
+            let _ = fs::remove_file(backup_path);
+        });
+    }
+
+    // A backup as written on x86_64. JSON has no byte order or word size, but
+    // this pins down that it reads the same on any host, and that we still
+    // write exactly this.
+    static BACKUP_X86_64: &'static str = r#"[
+{"ent":{"V1":{"attrs":{"index":[{"IN":1}],"name":[{"U8":"william"}],"syntax":[{"SY":4}],"uuid":[{"UU":"db237e8a-0079-4b8c-8a56-593b22aa44d1"}]}}}}
+]"#;
+
+    #[test]
+    fn test_be_restore_portable() {
+        run_test!(|audit: &mut AuditScope, be: &mut BackendWriteTransaction| {
+            let backup_path = "./.backup_portable_test.db";
+            fs::write(backup_path, BACKUP_X86_64).unwrap();
+            be.restore(audit, backup_path).expect("Restore failed!");
+
+            let f_w = unsafe { filter_resolved!(f_eq("name", PartialValue::new_utf8s("william"))) };
+            let r = be.search(audit, &f_w).expect("Search failed!");
+            let e = r.first().expect("Entry not restored");
+            assert!(
+                e.attribute_value_pres("syntax", &PartialValue::new_syntaxs("SYNTAX_ID").unwrap())
+            );
+            assert!(e.attribute_value_pres("index", &PartialValue::new_indexs("PRESENCE").unwrap()));
+
+            be.backup(audit, backup_path).expect("Backup failed!");
+            let written: serde_json::Value =
+                serde_json::from_str(&fs::read_to_string(backup_path).unwrap()).unwrap();
+            let golden: serde_json::Value = serde_json::from_str(BACKUP_X86_64).unwrap();
+            assert_eq!(written, golden);
+            let _ = fs::remove_file(backup_path);
+        });
+    }
+
+    #[test]
+    fn test_be_restore_fixtures() {
+        run_test!(|audit: &mut AuditScope, be: &mut BackendWriteTransaction| {
+            let fixture = |name: &str| {
+                format!(
+                    "{}/src/lib/be/fixtures/{}",
+                    env!("CARGO_MANIFEST_DIR"),
+                    name
+                )
+            };
+            let find = |audit: &mut AuditScope, be: &BackendWriteTransaction, name: &str| {
+                let f = unsafe { filter_resolved!(f_eq("name", PartialValue::new_utf8s(name))) };
+                be.search(audit, &f).expect("Search failed!").pop()
+            };
+
+            for name in &[
+                "backup_pretty.json",
+                "backup_streamed.json",
+                "backup_streamed.json.gz",
+                "backup_streamed.json.zst",
+                "backup_ndjson.json",
+            ] {
+                be.restore(audit, fixture(name).as_str())
+                    .unwrap_or_else(|e| panic!("Restore of {} failed! {:?}", name, e));
+
+                let e = find(audit, be, "william").expect("william not restored");
+                assert_eq!(e.get_ava_single_str("description"), Some("William"));
+                assert!(e.attribute_value_pres(
+                    "memberof",
+                    &PartialValue::new_refer_s("bd651620-00dd-426b-aaa0-4494f7b7906f").unwrap()
+                ));
+                assert!(e.attribute_value_pres(
+                    "syntax",
+                    &PartialValue::new_syntaxs("SYNTAX_ID").unwrap()
+                ));
+                assert!(e.attribute_value_pres("unique", &PartialValue::new_bool(false)));
+                [
+                    "acp_targetscope",
+                    "primary_credential",
+                    "radius_secret",
+                    "ssh_publickey",
+                ]
+                .iter()
+                .for_each(|a| assert!(e.attribute_pres(a)));
+                assert!(find(audit, be, "claire").is_some());
+            }
+
+            // The incremental follows on from the full backup.
+            be.restore_chain(
+                audit,
+                fixture("backup_streamed.json").as_str(),
+                &[fixture("backup_incremental.json").as_str()],
+                RESTORE_BATCH_SIZE,
+                None,
+            )
+            .expect("Restore of chain failed!");
+            let e = find(audit, be, "william").expect("william not restored");
+            assert_eq!(e.get_ava_single_str("description"), Some("Bill"));
+            assert!(find(audit, be, "claire").is_none());
+            assert!(find(audit, be, "alice").is_some());
+        });
+    }
+
+    #[test]
+    fn test_be_cbor_portable() {
+        // An entry as serde_cbor writes it to id2entry. Integers are big
+        // endian, in the fewest bytes that hold them, whatever the host.
+        let golden = [
+            &b"\xa1\x63ent\xa1\x62V1\xa1\x65attrs\xa2\x66syntax\x81\xa1\x62SY\x04\x64uuid\x81\xa1\x62UU\x50"[..],
+            &[
+                0xdb, 0x23, 0x7e, 0x8a, 0x00, 0x79, 0x4b, 0x8c, 0x8a, 0x56, 0x59, 0x3b, 0x22, 0xaa,
+                0x44, 0xd1,
+            ][..],
+        ]
+        .concat();
+        let dbe: DbEntry = serde_cbor::from_slice(golden.as_slice()).expect("Invalid cbor");
+        let e = Entry::from_dbentry(dbe, 1).expect("Invalid entry");
+        assert!(e.attribute_value_pres("syntax", &PartialValue::new_syntaxs("SYNTAX_ID").unwrap()));
+        assert_eq!(
+            e.get_uuid(),
+            &Uuid::parse_str("db237e8a-0079-4b8c-8a56-593b22aa44d1").unwrap()
+        );
+
+        // Ids past 32 bits have to survive the idl blobs too.
+        let idl = IDLBitRange::from_iter(vec![1, 64, 65, 1 << 33]);
+        let raw = serde_cbor::to_vec(&idl).expect("Failed to encode idl");
+        let idl_de: IDLBitRange = serde_cbor::from_slice(raw.as_slice()).expect("Invalid idl");
+        assert_eq!(idl, idl_de);
+    }
+
+    #[test]
+    fn test_be_dbentry_format() {
+        run_test!(|audit: &mut AuditScope, be: &mut BackendWriteTransaction| {
+            let mut e1: Entry<EntryInvalid, EntryNew> = Entry::new();
+            e1.add_ava("name", &Value::from("william"));
+            e1.add_ava("uuid", &Value::from("db237e8a-0079-4b8c-8a56-593b22aa44d1"));
+            let e1 = unsafe { e1.to_valid_new() };
+            be.create(audit, vec![e1]).unwrap();
+
+            let filt =
+                unsafe { filter_resolved!(f_eq("name", PartialValue::new_utf8s("william"))) };
+            let e1 = be
+                .search(audit, &filt)
+                .expect("search failed")
+                .pop()
+                .unwrap();
+            let dbe = e1.into_dbentry();
+            let blob = dbe.to_blob(DBENTRY_FORMAT_CBOR).unwrap();
+            assert_eq!(blob[0], 0xff);
+            assert_eq!(
+                DbEntry::from_blob(blob.as_slice()).map(|(_, f)| f),
+                Ok(DBENTRY_FORMAT_CBOR)
+            );
+            // A format from the future can't be read.
+            let mut future = blob.clone();
+            future[1] = 0xfe;
+            assert_eq!(
+                DbEntry::from_blob(future.as_slice()).map(|(_, f)| f),
+                Err(OperationError::InvalidDBState)
+            );
+
+            // Put it back as a release without the header would have written it.
+            let legacy = serde_cbor::to_vec(&dbe).unwrap();
+            assert_eq!(
+                DbEntry::from_blob(legacy.as_slice()).map(|(_, f)| f),
+                Ok(DBENTRY_FORMAT_LEGACY)
+            );
+            be.write_identries(
+                audit,
+                vec![IdEntry {
+                    id: 1,
+                    data: legacy,
+                }],
+            )
+            .unwrap();
+            let r = be.search(audit, &filt).expect("search failed");
+            assert_eq!(r, vec![e1.clone()]);
+
+            // Reindexing brings it up to date.
+            assert!(be.reindex(audit).is_ok());
+            let raw = be
+                .idlayer
+                .get_identry(audit, &IDL::Indexed(IDLBitRange::from_iter(vec![1])))
+                .unwrap();
+            assert_eq!(raw[0].data, blob);
+            let r = be.search(audit, &filt).expect("search failed");
+            assert_eq!(r, vec![e1]);
+        });
+    }
+
+    #[test]
+    fn test_be_backup_incremental() {
+        // Each write txn gets one change sequence number, so this needs
+        // several txns rather than the one run_test gives us.
+        let mut audit = AuditScope::new("run_test");
+        let audit = &mut audit;
+        let be =
+            Backend::new(audit, "", 1, FILTER_TEST_THRESHOLD).expect("Failed to setup backend");
+        let mut idxmeta = BTreeSet::new();
+        idxmeta.insert(("uuid".to_string(), IndexType::EQUALITY));
+        let full_path = "./.backup_incr_full_test.db";
+        let incr_path = "./.backup_incr_test.db";
+
+        let mk = |n: &str, u: &str| {
+            let mut e: Entry<EntryInvalid, EntryNew> = Entry::new();
+            e.add_ava("userid", &Value::from(n));
+            e.add_ava("uuid", &Value::from(u));
+            e
+        };
+        let e1 = mk("william", "db237e8a-0079-4b8c-8a56-593b22aa44d1");
+        let e2 = mk("alice", "4b6228ab-1dbe-42a4-a9f5-f6368222438e");
+        let e3 = mk("lucy", "7b23c99d-c06b-4a9a-a958-3afa56383e1d");
+
+        let mut be_txn = be.write(idxmeta.clone()).expect("Failed to begin txn");
+        let ve1 = unsafe { e1.clone().to_valid_new() };
+        let ve2 = unsafe { e2.clone().to_valid_new() };
+        assert!(be_txn.create(audit, vec![ve1, ve2]).is_ok());
+        assert!(be_txn.commit(audit).is_ok());
+
+        // Without a full backup there is nothing to follow on from.
+        let be_ro = be.read().expect("Failed to begin txn");
+        assert_eq!(be_ro.get_backup_marker(), 0);
+        let marker = be_ro.get_change_marker();
+        be_ro.backup(audit, full_path).expect("Backup failed!");
+        std::mem::drop(be_ro);
+        let be_txn = be.write(idxmeta.clone()).expect("Failed to begin txn");
+        assert!(be_txn.set_backup_marker(marker).is_ok());
+        assert!(be_txn.commit(audit).is_ok());
+
+        // Change william, delete alice and add lucy.
+        let mut be_txn = be.write(idxmeta.clone()).expect("Failed to begin txn");
+        let mut r = be_txn
+            .search(audit, unsafe { &filter_resolved!(f_pres("userid")) })
+            .expect("Failed to search");
+        r.sort_by_key(|e| e.get_id());
+        let pre1 = r.remove(0);
+        let mut post1 = pre1.clone().invalidate();
+        post1.add_ava("desc", &Value::from("modified"));
+        let post1 = unsafe { post1.to_valid_committed() };
+        assert!(be_txn.modify(audit, &vec![pre1], &vec![post1]).is_ok());
+        assert!(be_txn.delete(audit, &vec![r.remove(0)]).is_ok());
+        let ve3 = unsafe { e3.clone().to_valid_new() };
+        assert!(be_txn.create(audit, vec![ve3]).is_ok());
+        assert!(be_txn.commit(audit).is_ok());
+
+        let be_ro = be.read().expect("Failed to begin txn");
+        assert_eq!(be_ro.get_backup_marker(), marker);
+        be_ro
+            .backup_incremental(audit, incr_path, BackupCompression::None, None, marker)
+            .expect("Backup failed!");
+        std::mem::drop(be_ro);
+
+        let mut be_txn = be.write(idxmeta.clone()).expect("Failed to begin txn");
+        be_txn
+            .restore_chain(audit, full_path, &[incr_path], 10, None)
+            .expect("Restore failed!");
+        assert!(entry_attr_pres!(audit, be_txn, e1, "desc"));
+        assert!(!entry_exists!(audit, be_txn, e2));
+        assert!(entry_exists!(audit, be_txn, e3));
+
+        // The same increment twice leaves a gap in the chain.
+        assert_eq!(
+            be_txn.restore_chain(audit, full_path, &[incr_path, incr_path], 10, None),
+            Err(OperationError::InvalidRequestState)
+        );
+        std::mem::drop(be_txn);
+        let _ = fs::remove_file(full_path);
+        let _ = fs::remove_file(incr_path);
+    }
+
+    #[test]
+    fn test_be_copy_reid() {
+        run_test!(|audit: &mut AuditScope, be: &mut BackendWriteTransaction| {
+            let mut e1: Entry<EntryInvalid, EntryNew> = Entry::new();
+            e1.add_ava("userid", &Value::from("william"));
+            e1.add_ava("uuid", &Value::from("db237e8a-0079-4b8c-8a56-593b22aa44d1"));
+
+            let mut e2: Entry<EntryInvalid, EntryNew> = Entry::new();
+            e2.add_ava("userid", &Value::from("alice"));
+            e2.add_ava("uuid", &Value::from("4b6228ab-1dbe-42a4-a9f5-f6368222438e"));
+
+            let mut e3: Entry<EntryInvalid, EntryNew> = Entry::new();
+            e3.add_ava("userid", &Value::from("lucy"));
+            e3.add_ava("uuid", &Value::from("7b23c99d-c06b-4a9a-a958-3afa56383e1d"));
+
+            let ve1 = unsafe { e1.clone().to_valid_new() };
+            let ve2 = unsafe { e2.clone().to_valid_new() };
+            let ve3 = unsafe { e3.clone().to_valid_new() };
+
+            let rset = be.create(audit, vec![ve1, ve2, ve3]).unwrap();
+            // Leave a hole at id 2.
+            let alice: Vec<_> = rset.into_iter().filter(|e| e.get_id() == 2).collect();
+            assert!(be.delete(audit, &alice).is_ok());
+            let src_domain = be.get_domain_info().expect("Failed to get domain");
+
+            let dst =
+                Backend::new(audit, "", 1, FILTER_TEST_THRESHOLD).expect("Failed to setup backend");
+            let mut dst_txn = dst.write(be.idxmeta.clone()).expect("Failed to begin txn");
+            assert!(dst_txn.copy_from(audit, be, true, true).is_ok());
+
+            assert!(entry_exists!(audit, dst_txn, e1));
+            assert!(!entry_exists!(audit, dst_txn, e2));
+            assert!(entry_exists!(audit, dst_txn, e3));
+            // The gap is closed, and the indexes agree with the new ids.
+            idl_state!(
+                audit,
+                dst_txn,
+                "uuid",
+                IndexType::EQUALITY,
+                "7b23c99d-c06b-4a9a-a958-3afa56383e1d",
+                Some(vec![2])
+            );
+            assert!(dst_txn.get_domain_info().map(|d| d.uuid) != Ok(src_domain.uuid));
+
+            // Without reid or a new sid, the copy is identical.
+            assert!(dst_txn.copy_from(audit, be, false, false).is_ok());
+            idl_state!(
+                audit,
+                dst_txn,
+                "uuid",
+                IndexType::EQUALITY,
+                "7b23c99d-c06b-4a9a-a958-3afa56383e1d",
+                Some(vec![3])
+            );
+            assert!(dst_txn.get_domain_info() == Ok(src_domain));
+            assert!(dst_txn.commit(audit).is_ok());
+        });
+    }
+
+    #[test]
+    fn test_be_domain_info_generation_and_reset() {
+        run_test!(
+            |_audit: &mut AuditScope, be: &mut BackendWriteTransaction| {
+                let d1 = be.get_domain_info().unwrap();
+                let d2 = be.get_domain_info().unwrap();
+                assert!(d1 == d2);
+                assert_eq!(d1.name, DEFAULT_DOMAIN);
+                let d3 = be.reset_domain_info().unwrap();
+                assert!(d1.sid() != d3.sid());
+                assert_eq!(d3.generation, d1.generation + 1);
+                let d4 = be.get_domain_info().unwrap();
+                assert!(d3 == d4);
+            }
+        );
+    }
+
+    #[test]
+    fn test_be_reindex_empty() {
+        run_test!(|audit: &mut AuditScope, be: &mut BackendWriteTransaction| {
+            // Add some test data?
+            let missing = be.missing_idxs(audit).unwrap();
+            assert!(missing.len() == 7);
+            assert!(be.reindex(audit).is_ok());
+            let missing = be.missing_idxs(audit).unwrap();
+            println!("{:?}", missing);
+            assert!(missing.len() == 0);
+        });
+    }
+
+    #[test]
+    fn test_be_reindex_data() {
+        run_test!(|audit: &mut AuditScope, be: &mut BackendWriteTransaction| {
+            // Add some test data?
+            // TODO: Test reindex duplicate eq?
+            let mut e1: Entry<EntryInvalid, EntryNew> = Entry::new();
+            e1.add_ava("name", &Value::from("william"));
+            e1.add_ava("uuid", &Value::from("db237e8a-0079-4b8c-8a56-593b22aa44d1"));
+            let e1 = unsafe { e1.to_valid_new() };
+
+            let mut e2: Entry<EntryInvalid, EntryNew> = Entry::new();
+            e2.add_ava("name", &Value::from("claire"));
+            e2.add_ava("uuid", &Value::from("bd651620-00dd-426b-aaa0-4494f7b7906f"));
+            let e2 = unsafe { e2.to_valid_new() };
+
+            be.create(audit, vec![e1.clone(), e2.clone()]).unwrap();
+
+            // purge indexes
+            be.purge_idxs(audit).unwrap();
+            // Check they are gone
+            let missing = be.missing_idxs(audit).unwrap();
+            assert!(missing.len() == 7);
+            assert!(be.reindex(audit).is_ok());
+            let missing = be.missing_idxs(audit).unwrap();
+            println!("{:?}", missing);
+            assert!(missing.len() == 0);
+            // check name and uuid ids on eq, sub, pres
+
+            idl_state!(
+                audit,
+                be,
+                "name",
+                IndexType::EQUALITY,
+                "william",
+                Some(vec![1])
+            );
+
+            idl_state!(
+                audit,
+                be,
+                "name",
+                IndexType::EQUALITY,
+                "claire",
+                Some(vec![2])
+            );
+
+            idl_state!(
+                audit,
+                be,
+                "name",
+                IndexType::PRESENCE,
+                "_",
+                Some(vec![1, 2])
+            );
+
+            idl_state!(
+                audit,
+                be,
+                "uuid",
+                IndexType::EQUALITY,
+                "db237e8a-0079-4b8c-8a56-593b22aa44d1",
+                Some(vec![1])
+            );
+
+            idl_state!(
+                audit,
+                be,
+                "uuid",
+                IndexType::EQUALITY,
+                "bd651620-00dd-426b-aaa0-4494f7b7906f",
+                Some(vec![2])
+            );
+
+            idl_state!(
+                audit,
+                be,
+                "uuid",
+                IndexType::PRESENCE,
+                "_",
+                Some(vec![1, 2])
+            );
+
+            // Show what happens with empty
+
+            idl_state!(
+                audit,
+                be,
+                "name",
+                IndexType::EQUALITY,
+                "not-exist",
+                Some(Vec::new())
+            );
+
+            idl_state!(
+                audit,
+                be,
+                "uuid",
+                IndexType::EQUALITY,
+                "fake-0079-4b8c-8a56-593b22aa44d1",
+                Some(Vec::new())
+            );
+
+            let uuid_p_idl = be
+                .load_test_idl(
+                    audit,
+                    &"not_indexed".to_string(),
+                    &IndexType::PRESENCE,
+                    &"_".to_string(),
+                )
+                .unwrap(); // unwrap the result
+            assert_eq!(uuid_p_idl, None);
+
+            // Check name2uuid
+            let u1 = Uuid::parse_str("db237e8a-0079-4b8c-8a56-593b22aa44d1").unwrap();
+            let u2 = Uuid::parse_str("bd651620-00dd-426b-aaa0-4494f7b7906f").unwrap();
+            assert_eq!(be.name2uuid(audit, "william").unwrap(), Some(u1));
+            assert_eq!(be.name2uuid(audit, "Claire").unwrap(), Some(u2));
+            assert_eq!(be.name2uuid(audit, "not-exist").unwrap(), None);
+
+            // check uuid2name
+            assert_eq!(
+                be.uuid2name(audit, &u1).unwrap(),
+                Some("william".to_string())
+            );
+            assert_eq!(
+                be.uuid2name(audit, &u2).unwrap(),
+                Some("claire".to_string())
+            );
+            assert_eq!(be.uuid2name(audit, &Uuid::new_v4()).unwrap(), None);
+        });
+    }
+
+    #[test]
+    fn test_be_verify_idx() {
+        run_test!(|audit: &mut AuditScope, be: &mut BackendWriteTransaction| {
+            assert!(be.reindex(audit).is_ok());
+            let mut e1: Entry<EntryInvalid, EntryNew> = Entry::new();
+            e1.add_ava("name", &Value::from("william"));
+            e1.add_ava("uuid", &Value::from("db237e8a-0079-4b8c-8a56-593b22aa44d1"));
+            let e1 = unsafe { e1.to_valid_new() };
+            let mut e2: Entry<EntryInvalid, EntryNew> = Entry::new();
+            e2.add_ava("name", &Value::from("claire"));
+            e2.add_ava("uuid", &Value::from("bd651620-00dd-426b-aaa0-4494f7b7906f"));
+            let e2 = unsafe { e2.to_valid_new() };
+            be.create(audit, vec![e1, e2]).unwrap();
+
+            assert!(be.verify(audit).is_empty());
+
+            // Lose william from his key, and point it at an id that doesn't
+            // exist, and at claire who doesn't have it.
+            be.idxcache.borrow_mut().insert(
+                (
+                    "name".to_string(),
+                    IndexType::EQUALITY,
+                    "william".to_string(),
+                ),
+                IDLBitRange::from_iter(vec![2, 99]),
+            );
+            let vr = be.verify(audit);
+            assert_eq!(vr.len(), 3);
+            assert!(vr.contains(&Err(ConsistencyError::IndexMissingId(
+                "name".to_string(),
+                "eq".to_string(),
+                "william".to_string(),
+                1
+            ))));
+            assert!(vr.contains(&Err(ConsistencyError::IndexExtraId(
+                "name".to_string(),
+                "eq".to_string(),
+                "william".to_string(),
+                2
+            ))));
+            assert!(vr.contains(&Err(ConsistencyError::IndexDanglingId(
+                "name".to_string(),
+                "eq".to_string(),
+                "william".to_string(),
+                99
+            ))));
+
+            // Put it back, so the commit leaves things as we found them.
+            be.idxcache.borrow_mut().insert(
+                (
+                    "name".to_string(),
+                    IndexType::EQUALITY,
+                    "william".to_string(),
+                ),
+                IDLBitRange::from_iter(vec![1]),
+            );
+            assert!(be.verify(audit).is_empty());
+        });
+    }
+
+    #[test]
+    fn test_be_repair_idxs() {
+        run_test!(|audit: &mut AuditScope, be: &mut BackendWriteTransaction| {
+            assert!(be.reindex(audit).is_ok());
+            let mut e1: Entry<EntryInvalid, EntryNew> = Entry::new();
+            e1.add_ava("name", &Value::from("william"));
+            e1.add_ava("uuid", &Value::from("db237e8a-0079-4b8c-8a56-593b22aa44d1"));
+            let e1 = unsafe { e1.to_valid_new() };
+            be.create(audit, vec![e1]).unwrap();
+
+            // Nothing wrong, so nothing is touched.
+            assert_eq!(be.repair_idxs(audit), Ok(Vec::new()));
+
+            be.idxcache.borrow_mut().insert(
+                (
+                    "name".to_string(),
+                    IndexType::EQUALITY,
+                    "william".to_string(),
+                ),
+                IDLBitRange::from_iter(vec![2]),
+            );
+            be.idxcache.borrow_mut().insert(
+                (
+                    "name".to_string(),
+                    IndexType::EQUALITY,
+                    "claire".to_string(),
+                ),
+                IDLBitRange::from_iter(vec![1]),
+            );
+            assert_eq!(
+                be.repair_idxs(audit),
+                Ok(vec![("name".to_string(), IndexType::EQUALITY)])
+            );
+            assert!(be.verify(audit).is_empty());
+            idl_state!(
+                audit,
+                be,
+                "name",
+                IndexType::EQUALITY,
+                "william",
+                Some(vec![1])
+            );
+            idl_state!(
+                audit,
+                be,
+                "name",
+                IndexType::EQUALITY,
+                "claire",
+                Some(Vec::new())
+            );
+        });
+    }
+
+    #[test]
+    fn test_be_missing_idx_backfill() {
+        let mut audit = AuditScope::new("run_test");
+        let audit = &mut audit;
+        let be =
+            Backend::new(audit, "", 1, FILTER_TEST_THRESHOLD).expect("Failed to setup backend");
+        let mk = |n: &str, u: &str| {
+            let mut e: Entry<EntryInvalid, EntryNew> = Entry::new();
+            e.add_ava("name", &Value::from(n));
+            e.add_ava("uuid", &Value::from(u));
+            unsafe { e.to_valid_new() }
+        };
+        let mut idxmeta = BTreeSet::new();
+        idxmeta.insert(("name".to_string(), IndexType::EQUALITY));
+        let mut be_txn = be.write(idxmeta.clone()).expect("Failed to begin txn");
+        assert!(be_txn.reindex(audit).is_ok());
+        assert!(be_txn
+            .create(
+                audit,
+                vec![mk("william", "db237e8a-0079-4b8c-8a56-593b22aa44d1")]
+            )
+            .is_ok());
+        assert!(be_txn.commit(audit).is_ok());
+
+        // As though schema gained an index without a reindex.
+        idxmeta.insert(("name".to_string(), IndexType::PRESENCE));
+        let mut be_txn = be.write(idxmeta.clone()).expect("Failed to begin txn");
+        assert!(be_txn
+            .create(
+                audit,
+                vec![mk("claire", "bd651620-00dd-426b-aaa0-4494f7b7906f")]
+            )
+            .is_ok());
+        // Until commit it's as if there were no index.
+        idl_state!(audit, be_txn, "name", IndexType::PRESENCE, "_", None);
+        idl_state!(
+            audit,
+            be_txn,
+            "name",
+            IndexType::EQUALITY,
+            "claire",
+            Some(vec![2])
+        );
+        assert!(be_txn.commit(audit).is_ok());
+
+        let be_txn = be.write(idxmeta).expect("Failed to begin txn");
+        idl_state!(
+            audit,
+            be_txn,
+            "name",
+            IndexType::PRESENCE,
+            "_",
+            Some(vec![1, 2])
+        );
+        assert!(be_txn.verify(audit).is_empty());
+    }
+
+    #[test]
+    fn test_be_reindex_attr() {
+        run_test!(|audit: &mut AuditScope, be: &mut BackendWriteTransaction| {
+            assert!(be.reindex(audit).is_ok());
+            let mut e1: Entry<EntryInvalid, EntryNew> = Entry::new();
+            e1.add_ava("name", &Value::from("william"));
+            e1.add_ava("uuid", &Value::from("db237e8a-0079-4b8c-8a56-593b22aa44d1"));
+            e1.add_ava("tc", &Value::from("test"));
+            let e1 = unsafe { e1.to_valid_new() };
+            be.create(audit, vec![e1]).unwrap();
+
+            // Not indexed, so there is no index to ask.
+            idl_state!(audit, be, "tc", IndexType::EQUALITY, "test", None);
+
+            assert!(be.reindex_attr(audit, "tc", &IndexType::EQUALITY).is_ok());
+            idl_state!(audit, be, "tc", IndexType::EQUALITY, "test", Some(vec![1]));
+            assert!(be.verify(audit).is_empty());
+
+            // And from now on it's maintained.
+            let mut e2: Entry<EntryInvalid, EntryNew> = Entry::new();
+            e2.add_ava("name", &Value::from("claire"));
+            e2.add_ava("uuid", &Value::from("bd651620-00dd-426b-aaa0-4494f7b7906f"));
+            e2.add_ava("tc", &Value::from("test"));
+            let e2 = unsafe { e2.to_valid_new() };
+            be.create(audit, vec![e2]).unwrap();
+            idl_state!(
+                audit,
+                be,
+                "tc",
+                IndexType::EQUALITY,
+                "test",
+                Some(vec![1, 2])
+            );
+            // The others were left alone.
+            idl_state!(
+                audit,
+                be,
+                "name",
+                IndexType::EQUALITY,
+                "william",
+                Some(vec![1])
+            );
         });
     }
 
     #[test]
-    fn test_be_simple_delete() {
-        run_test!(|audit: &mut AuditScope, be: &mut BackendWriteTransaction| {
-            audit_log!(audit, "Simple Delete");
-
-            // First create some entries (3?)
-            let mut e1: Entry<EntryInvalid, EntryNew> = Entry::new();
-            e1.add_ava("userid", &Value::from("william"));
-            e1.add_ava("uuid", &Value::from("db237e8a-0079-4b8c-8a56-593b22aa44d1"));
+    fn test_be_analyze() {
+        let mut audit = AuditScope::new("run_test");
+        let audit = &mut audit;
+        let be =
+            Backend::new(audit, "", 1, FILTER_TEST_THRESHOLD).expect("Failed to setup backend");
+        // No indexes yet, but analyze still has to work.
+        assert_eq!(be.analyze(audit), Ok(0));
+
+        let mut idxmeta = BTreeSet::new();
+        idxmeta.insert(("name".to_string(), IndexType::EQUALITY));
+        idxmeta.insert(("name".to_string(), IndexType::PRESENCE));
+        let mut be_txn = be.write(idxmeta).expect("Failed to begin txn");
+        assert!(be_txn.reindex(audit).is_ok());
+        let mut e1: Entry<EntryInvalid, EntryNew> = Entry::new();
+        e1.add_ava("name", &Value::from("william"));
+        e1.add_ava("uuid", &Value::from("db237e8a-0079-4b8c-8a56-593b22aa44d1"));
+        let ve1 = unsafe { e1.to_valid_new() };
+        assert!(be_txn.create(audit, vec![ve1]).is_ok());
+        assert!(be_txn.commit(audit).is_ok());
+
+        assert_eq!(be.analyze(audit), Ok(2));
+        let be_ro = be.read().expect("Failed to begin txn");
+        assert_eq!(
+            be_ro
+                .get_idlayer()
+                .count_idx_keys(audit, &"name".to_string(), &IndexType::EQUALITY),
+            Ok(1)
+        );
+    }
 
-            let mut e2: Entry<EntryInvalid, EntryNew> = Entry::new();
-            e2.add_ava("userid", &Value::from("alice"));
-            e2.add_ava("uuid", &Value::from("4b6228ab-1dbe-42a4-a9f5-f6368222438e"));
+    #[test]
+    fn test_be_filter2idl_and_order() {
+        let mut audit = AuditScope::new("run_test");
+        let audit = &mut audit;
+        let be =
+            Backend::new(audit, "", 1, FILTER_TEST_THRESHOLD).expect("Failed to setup backend");
+        let mut idxmeta = BTreeSet::new();
+        idxmeta.insert(("name".to_string(), IndexType::EQUALITY));
+        idxmeta.insert(("class".to_string(), IndexType::EQUALITY));
+
+        let entries = (0..10)
+            .map(|i| {
+                let mut e: Entry<EntryInvalid, EntryNew> = Entry::new();
+                e.add_ava("name", &Value::new_iutf8s(format!("user{}", i).as_str()));
+                e.add_ava("class", &Value::new_class("person"));
+                e.add_ava("uuid", &Value::new_uuid(Uuid::new_v4()));
+                unsafe { e.to_valid_new() }
+            })
+            .collect();
+        let mut be_txn = be.write(idxmeta).expect("Failed to begin txn");
+        assert!(be_txn.reindex(audit).is_ok());
+        assert!(be_txn.create(audit, entries).is_ok());
+        assert!(be_txn.commit(audit).is_ok());
+        // Don't count the reindex.
+        let _ = be.usage.take();
+
+        let be_ro = be.read().expect("Failed to begin txn");
+        let f_name = unsafe { filter_resolved!(f_eq("name", PartialValue::new_iutf8s("user3"))) };
+        let f_class = unsafe { filter_resolved!(f_eq("class", PartialValue::new_class("person"))) };
+        let f_tc = unsafe { filter_resolved!(f_eq("tc", PartialValue::new_utf8s("test"))) };
+        assert_eq!(be_ro.filter_cost(audit, f_name.to_inner()), 1);
+        assert_eq!(be_ro.filter_cost(audit, f_class.to_inner()), 10);
+        assert_eq!(
+            be_ro.filter_cost(audit, f_tc.to_inner()),
+            FILTER_COST_ALLIDS
+        );
 
-            let mut e3: Entry<EntryInvalid, EntryNew> = Entry::new();
-            e3.add_ava("userid", &Value::from("lucy"));
-            e3.add_ava("uuid", &Value::from("7b23c99d-c06b-4a9a-a958-3afa56383e1d"));
+        // Name is so selective that once it's resolved, class never needs
+        // to be loaded.
+        let f_and = unsafe {
+            filter_resolved!(f_and!([
+                f_eq("name", PartialValue::new_iutf8s("user3")),
+                f_eq("class", PartialValue::new_class("person"))
+            ]))
+        };
+        match be_ro.filter2idl(audit, f_and.to_inner(), 3).unwrap() {
+            IDL::Partial(idl) => assert_eq!(idl.len(), 1),
+            _ => panic!(""),
+        }
+        let (usage, _) = be.usage.take();
+        assert_eq!(usage.len(), 1);
+        assert!(usage.contains_key(&(
+            "name".to_string(),
+            IndexType::EQUALITY,
+            "user3".to_string()
+        )));
+    }
 
-            let ve1 = unsafe { e1.clone().to_valid_new() };
-            let ve2 = unsafe { e2.clone().to_valid_new() };
-            let ve3 = unsafe { e3.clone().to_valid_new() };
+    #[test]
+    fn test_be_filter_cache() {
+        let mut audit = AuditScope::new("run_test");
+        let audit = &mut audit;
+        let be =
+            Backend::new(audit, "", 1, FILTER_TEST_THRESHOLD).expect("Failed to setup backend");
+        let mut idxmeta = BTreeSet::new();
+        idxmeta.insert(("name".to_string(), IndexType::EQUALITY));
+
+        let mk = |u: &str| {
+            let mut e: Entry<EntryInvalid, EntryNew> = Entry::new();
+            e.add_ava("name", &Value::new_iutf8s("william"));
+            e.add_ava("uuid", &Value::from(u));
+            unsafe { e.to_valid_new() }
+        };
+        let mut be_txn = be.write(idxmeta.clone()).expect("Failed to begin txn");
+        assert!(be_txn.reindex(audit).is_ok());
+        assert!(be_txn
+            .create(audit, vec![mk("db237e8a-0079-4b8c-8a56-593b22aa44d1")])
+            .is_ok());
+        assert!(be_txn.commit(audit).is_ok());
+
+        let f_name = unsafe { filter_resolved!(f_eq("name", PartialValue::new_iutf8s("william"))) };
+        let resolve = |audit: &mut AuditScope| {
+            let be_ro = be.read().expect("Failed to begin txn");
+            match be_ro.filter2idl(audit, f_name.to_inner(), 0).unwrap() {
+                IDL::Indexed(idl) => idl.len(),
+                _ => panic!(""),
+            }
+        };
+        assert_eq!(resolve(audit), 1);
+        let _ = be.usage.take();
+        // The second read is served from the cache, so the index isn't read.
+        assert_eq!(resolve(audit), 1);
+        assert!(be.usage.take().0.is_empty());
+
+        let mut be_txn = be.write(idxmeta).expect("Failed to begin txn");
+        assert!(be_txn
+            .create(audit, vec![mk("4b6228ab-1dbe-42a4-a9f5-f6368222438e")])
+            .is_ok());
+        assert!(be_txn.commit(audit).is_ok());
+        assert_eq!(resolve(audit), 2);
+    }
 
-            assert!(be.create(audit, vec![ve1, ve2, ve3]).is_ok());
-            assert!(entry_exists!(audit, be, e1));
-            assert!(entry_exists!(audit, be, e2));
-            assert!(entry_exists!(audit, be, e3));
+    #[test]
+    fn test_be_entry_cache() {
+        let mut audit = AuditScope::new("run_test");
+        let audit = &mut audit;
+        let be =
+            Backend::new(audit, "", 1, FILTER_TEST_THRESHOLD).expect("Failed to setup backend");
+        let mut idxmeta = BTreeSet::new();
+        idxmeta.insert(("uuid".to_string(), IndexType::EQUALITY));
+
+        let mut e1: Entry<EntryInvalid, EntryNew> = Entry::new();
+        e1.add_ava("name", &Value::from("william"));
+        e1.add_ava("uuid", &Value::from("db237e8a-0079-4b8c-8a56-593b22aa44d1"));
+        let e1 = unsafe { e1.to_valid_new() };
+        let mut be_txn = be.write(idxmeta.clone()).expect("Failed to begin txn");
+        assert!(be_txn.reindex(audit).is_ok());
+        assert!(be_txn.create(audit, vec![e1]).is_ok());
+        assert!(be_txn.commit(audit).is_ok());
+
+        let f_uuid = unsafe {
+            filter_resolved!(f_eq(
+                "uuid",
+                PartialValue::new_uuids("db237e8a-0079-4b8c-8a56-593b22aa44d1").unwrap()
+            ))
+        };
+        let names = |audit: &mut AuditScope| {
+            let be_ro = be.read().expect("Failed to begin txn");
+            let r = be_ro.search(audit, &f_uuid).expect("Search failed");
+            assert_eq!(r.len(), 1);
+            r[0].get_ava_single_string("name")
+        };
+        assert_eq!(names(audit), Some("william".to_string()));
+        // Now from the cache.
+        assert_eq!(names(audit), Some("william".to_string()));
+
+        // Once the modify commits, the cached entry is dropped.
+        let be_txn = be.write(idxmeta).expect("Failed to begin txn");
+        let rset = be_txn.search(audit, &f_uuid).expect("Search failed");
+        let mut ce1 = rset[0].clone().invalidate();
+        ce1.purge_ava("name");
+        ce1.add_ava("name", &Value::from("claire"));
+        let ce1 = unsafe { ce1.to_valid_committed() };
+        assert!(be_txn.modify(audit, &rset, &vec![ce1]).is_ok());
+        assert!(be_txn.commit(audit).is_ok());
+        assert_eq!(names(audit), Some("claire".to_string()));
+    }
 
-            // You need to now retrieve the entries back out to get the entry id's
-            let mut results = be
-                .search(audit, unsafe { &filter_resolved!(f_pres("userid")) })
-                .expect("Failed to search");
+    #[test]
+    fn test_be_filter_test_threshold() {
+        let mut audit = AuditScope::new("run_test");
+        let audit = &mut audit;
+        let f_and = unsafe {
+            filter_resolved!(f_and!([
+                f_eq("name", PartialValue::new_iutf8s("user3")),
+                f_eq("class", PartialValue::new_class("person"))
+            ]))
+        };
+        // With the default, the and is cut short once name has resolved to one
+        // entry. With zero, both terms are always read so no entry test is needed.
+        for (thres, expect) in vec![
+            (FILTER_TEST_THRESHOLD, ExplainIdl::Partial(1)),
+            (0, ExplainIdl::Indexed(1)),
+        ] {
+            let be = Backend::new(audit, "", 1, thres).expect("Failed to setup backend");
+            let mut idxmeta = BTreeSet::new();
+            idxmeta.insert(("name".to_string(), IndexType::EQUALITY));
+            idxmeta.insert(("class".to_string(), IndexType::EQUALITY));
+            let entries = (0..10)
+                .map(|i| {
+                    let mut e: Entry<EntryInvalid, EntryNew> = Entry::new();
+                    e.add_ava("name", &Value::new_iutf8s(format!("user{}", i).as_str()));
+                    e.add_ava("class", &Value::new_class("person"));
+                    e.add_ava("uuid", &Value::new_uuid(Uuid::new_v4()));
+                    unsafe { e.to_valid_new() }
+                })
+                .collect();
+            let mut be_txn = be.write(idxmeta).expect("Failed to begin txn");
+            assert!(be_txn.reindex(audit).is_ok());
+            assert!(be_txn.create(audit, entries).is_ok());
+            assert!(be_txn.commit(audit).is_ok());
+
+            let be_ro = be.read().expect("Failed to begin txn");
+            let ex = be_ro.explain(audit, &f_and).expect("explain failed");
+            assert_eq!(ex.result, expect);
+            assert_eq!(ex.matched, 1);
+        }
+    }
 
-            // Get these out to usable entries.
-            let r1 = results.remove(0);
-            let r2 = results.remove(0);
-            let r3 = results.remove(0);
+    #[test]
+    fn test_be_index_stats() {
+        let mut audit = AuditScope::new("run_test");
+        let audit = &mut audit;
+        let be =
+            Backend::new(audit, "", 1, FILTER_TEST_THRESHOLD).expect("Failed to setup backend");
+        let mut idxmeta = BTreeSet::new();
+        idxmeta.insert(("name".to_string(), IndexType::EQUALITY));
+        idxmeta.insert(("name".to_string(), IndexType::PRESENCE));
+
+        let mk = |n: &str, u: &str| {
+            let mut e: Entry<EntryInvalid, EntryNew> = Entry::new();
+            e.add_ava("name", &Value::from(n));
+            e.add_ava("uuid", &Value::from(u));
+            unsafe { e.to_valid_new() }
+        };
+        let mut be_txn = be.write(idxmeta).expect("Failed to begin txn");
+        assert!(be_txn.reindex(audit).is_ok());
+        assert!(be_txn
+            .create(
+                audit,
+                vec![
+                    mk("william", "db237e8a-0079-4b8c-8a56-593b22aa44d1"),
+                    mk("claire", "bd651620-00dd-426b-aaa0-4494f7b7906f"),
+                ]
+            )
+            .is_ok());
+        assert!(be_txn.commit(audit).is_ok());
+
+        // Search on something we don't index.
+        let be_ro = be.read().expect("Failed to begin txn");
+        let filt = unsafe { filter_resolved!(f_eq("tc", PartialValue::new_utf8s("test"))) };
+        assert!(be_ro.search(audit, &filt).is_ok());
+        std::mem::drop(be_ro);
+        assert!(be.flush_idx_usage(audit).is_ok());
+
+        let be_ro = be.read().expect("Failed to begin txn");
+        let stats = be_ro.index_stats(audit).expect("Failed to get stats");
+        let find = |attr: &str, itype: &str| {
+            stats
+                .iter()
+                .find(|s| s.attr == attr && s.itype == itype)
+                .expect("missing index stats")
+        };
+        let eq = find("name", "eq");
+        assert_eq!((eq.keys, eq.max_idl, eq.allids), (2, 1, 0));
+        assert!((eq.avg_idl - 1.0).abs() < std::f64::EPSILON);
+        let pres = find("name", "pres");
+        assert_eq!((pres.keys, pres.max_idl), (1, 2));
+        let tc = find("tc", "eq");
+        assert_eq!((tc.keys, tc.allids), (0, 1));
+    }
 
-            // Delete one
-            assert!(be.delete(audit, &vec![r1.clone()]).is_ok());
-            assert!(!entry_exists!(audit, be, r1));
+    #[test]
+    fn test_be_ordering_index() {
+        let mut audit = AuditScope::new("run_test");
+        let audit = &mut audit;
+        let be =
+            Backend::new(audit, "", 1, FILTER_TEST_THRESHOLD).expect("Failed to setup backend");
+        let mut idxmeta = BTreeSet::new();
+        idxmeta.insert(("name".to_string(), IndexType::ORDERING));
+
+        let entries: Vec<_> = vec!["alice", "bob", "claire", "william"]
+            .into_iter()
+            .map(|n| {
+                let mut e: Entry<EntryInvalid, EntryNew> = Entry::new();
+                e.add_ava("name", &Value::from(n));
+                e.add_ava("uuid", &Value::new_uuid(Uuid::new_v4()));
+                unsafe { e.to_valid_new() }
+            })
+            .collect();
+        let f_ge = unsafe { filter_resolved!(f_ge("name", PartialValue::new_utf8s("bob"))) };
+        let f_le = unsafe { filter_resolved!(f_le("name", PartialValue::new_utf8s("bob"))) };
+        let f_none = unsafe { filter_resolved!(f_ge("name", PartialValue::new_utf8s("zzz"))) };
+        let names = |r: Vec<Entry<EntryValid, EntryCommitted>>| -> Vec<String> {
+            let mut n: Vec<_> = r
+                .iter()
+                .filter_map(|e| e.get_ava_single_string("name"))
+                .collect();
+            n.sort();
+            n
+        };
 
-            // delete none (no match filter)
-            assert!(be.delete(audit, &vec![]).is_err());
+        // Before commit, the range sees the idls we have yet to write.
+        let mut be_txn = be.write(idxmeta).expect("Failed to begin txn");
+        assert!(be_txn.reindex(audit).is_ok());
+        assert!(be_txn.create(audit, entries).is_ok());
+        match be_txn.filter2idl(audit, f_ge.to_inner(), 0).unwrap() {
+            IDL::Indexed(idl) => assert_eq!(idl.len(), 3),
+            _ => panic!("range not indexed"),
+        }
+        assert!(be_txn.commit(audit).is_ok());
 
-            // Delete with no id
-            // WARNING: Normally, this isn't possible, but we are pursposefully breaking
-            // the state machine rules here!!!!
-            let mut e4: Entry<EntryInvalid, EntryNew> = Entry::new();
-            e4.add_ava("userid", &Value::from("amy"));
-            e4.add_ava("uuid", &Value::from("21d816b5-1f6a-4696-b7c1-6ed06d22ed81"));
+        let be_ro = be.read().expect("Failed to begin txn");
+        match be_ro.filter2idl(audit, f_le.to_inner(), 0).unwrap() {
+            IDL::Indexed(idl) => assert_eq!(idl.len(), 2),
+            _ => panic!("range not indexed"),
+        }
+        assert_eq!(
+            names(be_ro.search(audit, &f_ge).unwrap()),
+            vec!["bob", "claire", "william"]
+        );
+        assert_eq!(
+            names(be_ro.search(audit, &f_le).unwrap()),
+            vec!["alice", "bob"]
+        );
+        match be_ro.filter2idl(audit, f_none.to_inner(), 0).unwrap() {
+            IDL::Indexed(idl) => assert_eq!(idl.len(), 0),
+            _ => panic!("range not indexed"),
+        }
+    }
 
-            let ve4 = unsafe { e4.clone().to_valid_committed() };
+    #[test]
+    fn test_be_slow_queries() {
+        let mut audit = AuditScope::new("run_test");
+        let audit = &mut audit;
+        let mut be =
+            Backend::new(audit, "", 1, FILTER_TEST_THRESHOLD).expect("Failed to setup backend");
+        be.set_slow_query_threshold(Duration::from_secs(3600));
+        let mut idxmeta = BTreeSet::new();
+        idxmeta.insert(("name".to_string(), IndexType::EQUALITY));
+
+        let mk = |n: &str, u: &str| {
+            let mut e: Entry<EntryInvalid, EntryNew> = Entry::new();
+            e.add_ava("name", &Value::from(n));
+            e.add_ava("uuid", &Value::from(u));
+            unsafe { e.to_valid_new() }
+        };
+        let mut be_txn = be.write(idxmeta).expect("Failed to begin txn");
+        assert!(be_txn.reindex(audit).is_ok());
+        assert!(be_txn
+            .create(
+                audit,
+                vec![
+                    mk("william", "db237e8a-0079-4b8c-8a56-593b22aa44d1"),
+                    mk("claire", "bd651620-00dd-426b-aaa0-4494f7b7906f"),
+                ]
+            )
+            .is_ok());
+        assert!(be_txn.commit(audit).is_ok());
+
+        // Only the unindexed search is noted, however quick it was.
+        let be_ro = be.read().expect("Failed to begin txn");
+        let f_idx = unsafe { filter_resolved!(f_eq("name", PartialValue::new_utf8s("william"))) };
+        let f_allids = unsafe { filter_resolved!(f_eq("tc", PartialValue::new_utf8s("test"))) };
+        assert!(be_ro.search(audit, &f_idx).is_ok());
+        assert!(be_ro.search(audit, &f_allids).is_ok());
+        std::mem::drop(be_ro);
+        assert!(be.flush_slow_queries(audit).is_ok());
+
+        let be_ro = be.read().expect("Failed to begin txn");
+        let slow = be_ro.slow_queries(audit, 10).expect("No slow queries");
+        assert_eq!(slow.len(), 1);
+        assert!(slow[0].allids);
+        assert_eq!((slow[0].candidates, slow[0].results), (2, 0));
+        assert!(slow[0].filter.contains("tc"));
+        std::mem::drop(be_ro);
+
+        // With no threshold, everything is.
+        be.set_slow_query_threshold(Duration::from_secs(0));
+        let be_ro = be.read().expect("Failed to begin txn");
+        assert!(be_ro.search(audit, &f_idx).is_ok());
+        std::mem::drop(be_ro);
+        assert!(be.flush_slow_queries(audit).is_ok());
+
+        let be_ro = be.read().expect("Failed to begin txn");
+        let slow = be_ro.slow_queries(audit, 10).expect("No slow queries");
+        assert_eq!(slow.len(), 2);
+        assert!(!slow[0].allids);
+        assert_eq!((slow[0].candidates, slow[0].results), (1, 1));
+    }
 
-            assert!(be.delete(audit, &vec![ve4]).is_err());
+    #[test]
+    fn test_be_search_parallel() {
+        run_test!(|audit: &mut AuditScope, be: &mut BackendWriteTransaction| {
+            // Enough candidates that the allids scan is decoded and tested in
+            // parallel, which must give what the serial path would.
+            let entries: Vec<_> = (0..PARALLEL_SEARCH_THRESHOLD + 10)
+                .map(|i| {
+                    let mut e: Entry<EntryInvalid, EntryNew> = Entry::new();
+                    e.add_ava("userid", &Value::from(format!("user{}", i).as_str()));
+                    e.add_ava("uuid", &Value::new_uuid(Uuid::new_v4()));
+                    if i % 3 == 0 {
+                        e.add_ava("tc", &Value::from("test"));
+                    }
+                    unsafe { e.to_valid_new() }
+                })
+                .collect();
+            assert!(be.create(audit, entries).is_ok());
+
+            let filt = unsafe { filter_resolved!(f_eq("tc", PartialValue::new_utf8s("test"))) };
+            let r = be.search(audit, &filt).expect("Search failed");
+            assert_eq!(r.len(), (PARALLEL_SEARCH_THRESHOLD + 10 + 2) / 3);
+            let ids: Vec<_> = r.iter().map(|e| e.get_id()).collect();
+            let mut sorted = ids.clone();
+            sorted.sort();
+            assert_eq!(ids, sorted);
+        });
+    }
 
-            assert!(entry_exists!(audit, be, r2));
-            assert!(entry_exists!(audit, be, r3));
+    #[test]
+    fn test_be_read_only() {
+        let mut audit = AuditScope::new("run_test");
+        let audit = &mut audit;
+        let be =
+            Backend::new(audit, "", 1, FILTER_TEST_THRESHOLD).expect("Failed to setup backend");
+        // Set on one clone, seen by all.
+        be.clone().set_read_only(true);
+        assert!(be.is_read_only());
+        assert_eq!(
+            be.write(BTreeSet::new()).map(|_| ()).err(),
+            Some(OperationError::ReadOnly)
+        );
+        assert!(be.read().is_ok());
 
-            // delete batch
-            assert!(be.delete(audit, &vec![r2.clone(), r3.clone()]).is_ok());
+        be.set_read_only(false);
+        let be_txn = be.write(BTreeSet::new()).expect("Failed to begin txn");
+        assert!(be_txn.commit(audit).is_ok());
+    }
 
-            assert!(!entry_exists!(audit, be, r2));
-            assert!(!entry_exists!(audit, be, r3));
+    #[test]
+    fn test_be_read_replica() {
+        let mut audit = AuditScope::new("run_test");
+        let audit = &mut audit;
+        let be =
+            Backend::new(audit, "", 1, FILTER_TEST_THRESHOLD).expect("Failed to setup backend");
+        let primary = "https://idm.example.com".to_string();
+        be.clone().set_primary(Some(primary.clone()));
+        assert_eq!(
+            be.write(BTreeSet::new()).map(|_| ()).err(),
+            Some(OperationError::ReadReplica(primary))
+        );
+        assert!(be.read().is_ok());
+        // Replication still writes, unless we're also read only.
+        let be_txn = be
+            .write_replicated(BTreeSet::new())
+            .expect("Failed to begin txn");
+        assert!(be_txn.commit(audit).is_ok());
+        be.set_read_only(true);
+        assert_eq!(
+            be.write_replicated(BTreeSet::new()).map(|_| ()).err(),
+            Some(OperationError::ReadOnly)
+        );
 
-            // delete none (no entries left)
-            // see fn delete for why this is ok, not err
-            assert!(be.delete(audit, &vec![r2.clone(), r3.clone()]).is_ok());
-        });
+        be.set_read_only(false);
+        be.set_primary(None);
+        assert!(be.write(BTreeSet::new()).is_ok());
     }
 
-    pub static DB_BACKUP_FILE_NAME: &'static str = "./.backup_test.db";
-
     #[test]
-    fn test_be_backup_restore() {
-        run_test!(|audit: &mut AuditScope, be: &mut BackendWriteTransaction| {
-            // First create some entries (3?)
-            let mut e1: Entry<EntryInvalid, EntryNew> = Entry::new();
-            e1.add_ava("userid", &Value::from("william"));
-            e1.add_ava("uuid", &Value::from("db237e8a-0079-4b8c-8a56-593b22aa44d1"));
-
-            let mut e2: Entry<EntryInvalid, EntryNew> = Entry::new();
-            e2.add_ava("userid", &Value::from("alice"));
-            e2.add_ava("uuid", &Value::from("4b6228ab-1dbe-42a4-a9f5-f6368222438e"));
+    fn test_be_write_stats() {
+        let mut audit = AuditScope::new("run_test");
+        let audit = &mut audit;
+        let be =
+            Backend::new(audit, "", 1, FILTER_TEST_THRESHOLD).expect("Failed to setup backend");
+        let mut idxmeta = BTreeSet::new();
+        idxmeta.insert(("name".to_string(), IndexType::EQUALITY));
+        idxmeta.insert(("name".to_string(), IndexType::PRESENCE));
+
+        let mut e: Entry<EntryInvalid, EntryNew> = Entry::new();
+        e.add_ava("name", &Value::from("william"));
+        e.add_ava("uuid", &Value::from("db237e8a-0079-4b8c-8a56-593b22aa44d1"));
+        let e = unsafe { e.to_valid_new() };
+
+        let mut be_txn = be.write(idxmeta.clone()).expect("Failed to begin txn");
+        assert!(be_txn.create(audit, vec![e]).is_ok());
+        assert!(be_txn.commit(audit).is_ok());
+
+        // A txn that wrote nothing isn't kept.
+        let be_txn = be.write(idxmeta).expect("Failed to begin txn");
+        assert!(be_txn.commit(audit).is_ok());
+
+        let be_ro = be.read().expect("Failed to begin txn");
+        let stats = be_ro.write_stats();
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats[0].entries, 1);
+        assert!(stats[0].id2entry_bytes > 0);
+        // The name eq and pres idls at least, and the uuid ones.
+        assert!(stats[0].idx_slots >= 2);
+        assert!(stats[0].idx_bytes > 0);
+    }
 
-            let mut e3: Entry<EntryInvalid, EntryNew> = Entry::new();
-            e3.add_ava("userid", &Value::from("lucy"));
-            e3.add_ava("uuid", &Value::from("7b23c99d-c06b-4a9a-a958-3afa56383e1d"));
+    #[test]
+    fn test_be_search_sort() {
+        let mut audit = AuditScope::new("run_test");
+        let audit = &mut audit;
+        let be =
+            Backend::new(audit, "", 1, FILTER_TEST_THRESHOLD).expect("Failed to setup backend");
+        let mut idxmeta = BTreeSet::new();
+        idxmeta.insert(("name".to_string(), IndexType::ORDERING));
+        idxmeta.insert(("uuid".to_string(), IndexType::PRESENCE));
+
+        // The same values in name, which has an ordering index, and tb which
+        // doesn't. The last has none, and the one before two.
+        let values = vec![
+            vec!["delta"],
+            vec!["alpha"],
+            vec!["charlie"],
+            vec!["bravo"],
+            vec!["echo", "aardvark"],
+            vec![],
+        ];
+        let entries: Vec<_> = values
+            .iter()
+            .map(|vs| {
+                let mut e: Entry<EntryInvalid, EntryNew> = Entry::new();
+                e.add_ava("uuid", &Value::new_uuid(Uuid::new_v4()));
+                vs.iter().for_each(|v| {
+                    e.add_ava("name", &Value::from(*v));
+                    e.add_ava("tb", &Value::from(*v));
+                });
+                unsafe { e.to_valid_new() }
+            })
+            .collect();
+        let mut be_txn = be.write(idxmeta).expect("Failed to begin txn");
+        assert!(be_txn.create(audit, entries).is_ok());
+        assert!(be_txn.commit(audit).is_ok());
+
+        let be_ro = be.read().expect("Failed to begin txn");
+        let ids: Vec<u64> = (1..=6).collect();
+        let asc = be_ro
+            .sort_ids(audit, ids.clone(), &SortSpec::new("name", false))
+            .expect("Sort failed");
+        assert_eq!(asc, vec![5, 2, 4, 3, 1, 6]);
+        let desc = be_ro
+            .sort_ids(audit, ids.clone(), &SortSpec::new("name", true))
+            .expect("Sort failed");
+        assert_eq!(desc, vec![5, 1, 3, 4, 2, 6]);
+        // Without the index, the same.
+        assert_eq!(
+            be_ro
+                .sort_ids(audit, ids.clone(), &SortSpec::new("tb", false))
+                .expect("Sort failed"),
+            asc
+        );
+        assert_eq!(
+            be_ro
+                .sort_ids(audit, ids, &SortSpec::new("tb", true))
+                .expect("Sort failed"),
+            desc
+        );
 
-            let ve1 = unsafe { e1.clone().to_valid_new() };
-            let ve2 = unsafe { e2.clone().to_valid_new() };
-            let ve3 = unsafe { e3.clone().to_valid_new() };
+        // Paged, the cursor keeps the order.
+        let filt = unsafe { filter_resolved!(f_pres("uuid")) };
+        let sort = SortSpec::new("name", true);
+        let (first, cursor) = be_ro
+            .search_page(
+                audit,
+                &filt,
+                Some(&sort),
+                None,
+                4,
+                None,
+                &SearchLimits::unlimited(),
+            )
+            .expect("Search failed");
+        let (rest, last) = be_ro
+            .search_page(
+                audit,
+                &filt,
+                Some(&sort),
+                None,
+                4,
+                cursor.as_ref().map(|c| c.as_str()),
+                &SearchLimits::unlimited(),
+            )
+            .expect("Search failed");
+        assert!(last.is_none());
+        let paged: Vec<u64> = first
+            .iter()
+            .chain(rest.iter())
+            .map(|e| e.get_id())
+            .collect();
+        assert_eq!(paged, desc);
+        // And is only for that sort.
+        assert_eq!(
+            be_ro
+                .search_page(
+                    audit,
+                    &filt,
+                    None,
+                    None,
+                    4,
+                    cursor.as_ref().map(|c| c.as_str()),
+                    &SearchLimits::unlimited()
+                )
+                .err(),
+            Some(OperationError::InvalidSearchCursor)
+        );
+    }
 
-            assert!(be.create(audit, vec![ve1, ve2, ve3]).is_ok());
-            assert!(entry_exists!(audit, be, e1));
-            assert!(entry_exists!(audit, be, e2));
-            assert!(entry_exists!(audit, be, e3));
+    #[test]
+    fn test_be_search_page() {
+        let mut audit = AuditScope::new("run_test");
+        let audit = &mut audit;
+        let be =
+            Backend::new(audit, "", 1, FILTER_TEST_THRESHOLD).expect("Failed to setup backend");
+        let mut idxmeta = BTreeSet::new();
+        idxmeta.insert(("name".to_string(), IndexType::PRESENCE));
+
+        let entries: Vec<_> = (0..10)
+            .map(|i| {
+                let mut e: Entry<EntryInvalid, EntryNew> = Entry::new();
+                e.add_ava("name", &Value::from(format!("user{}", i).as_str()));
+                e.add_ava("uuid", &Value::new_uuid(Uuid::new_v4()));
+                unsafe { e.to_valid_new() }
+            })
+            .collect();
+        let mut be_txn = be.write(idxmeta.clone()).expect("Failed to begin txn");
+        assert!(be_txn.create(audit, entries).is_ok());
+        assert!(be_txn.commit(audit).is_ok());
+
+        let owner = Uuid::new_v4();
+        let filt = unsafe { filter_resolved!(f_pres("name")) };
+        let names = |r: &Vec<Entry<EntryValid, EntryCommitted>>| -> Vec<String> {
+            r.iter()
+                .map(|e| e.get_ava_single_string("name").expect("no name"))
+                .collect()
+        };
 
-            let result = fs::remove_file(DB_BACKUP_FILE_NAME);
+        let be_ro = be.read().expect("Failed to begin txn");
+        let (page, cursor) = be_ro
+            .search_page(
+                audit,
+                &filt,
+                None,
+                Some(&owner),
+                4,
+                None,
+                &SearchLimits::unlimited(),
+            )
+            .expect("Search failed");
+        assert_eq!(names(&page), vec!["user0", "user1", "user2", "user3"]);
+        let cursor = cursor.expect("no cursor");
+        let (page, _) = be_ro
+            .search_page(
+                audit,
+                &filt,
+                None,
+                Some(&owner),
+                4,
+                Some(cursor.as_str()),
+                &SearchLimits::unlimited(),
+            )
+            .expect("Search failed");
+        assert_eq!(names(&page), vec!["user4", "user5", "user6", "user7"]);
+        // Asking again gives the same page.
+        let (again, _) = be_ro
+            .search_page(
+                audit,
+                &filt,
+                None,
+                Some(&owner),
+                4,
+                Some(cursor.as_str()),
+                &SearchLimits::unlimited(),
+            )
+            .expect("Search failed");
+        assert_eq!(names(&again), names(&page));
+        // Only the owner may continue it, with the same filter.
+        assert_eq!(
+            be_ro
+                .search_page(
+                    audit,
+                    &filt,
+                    None,
+                    None,
+                    4,
+                    Some(cursor.as_str()),
+                    &SearchLimits::unlimited()
+                )
+                .err(),
+            Some(OperationError::InvalidSearchCursor)
+        );
+        let other = unsafe { filter_resolved!(f_pres("uuid")) };
+        assert_eq!(
+            be_ro
+                .search_page(
+                    audit,
+                    &other,
+                    None,
+                    Some(&owner),
+                    4,
+                    Some(cursor.as_str()),
+                    &SearchLimits::unlimited()
+                )
+                .err(),
+            Some(OperationError::InvalidSearchCursor)
+        );
+        drop(be_ro);
+
+        // Between pages, remove one of the next page and add a new entry.
+        // The candidates don't change, so only the removal is seen.
+        let mut be_txn = be.write(idxmeta).expect("Failed to begin txn");
+        let gone = be_txn
+            .search(audit, &unsafe {
+                filter_resolved!(f_eq("name", PartialValue::new_utf8s("user5")))
+            })
+            .expect("Search failed");
+        assert!(be_txn.delete(audit, &gone).is_ok());
+        let mut e: Entry<EntryInvalid, EntryNew> = Entry::new();
+        e.add_ava("name", &Value::from("user10"));
+        e.add_ava("uuid", &Value::new_uuid(Uuid::new_v4()));
+        assert!(be_txn
+            .create(audit, vec![unsafe { e.to_valid_new() }])
+            .is_ok());
+        assert!(be_txn.commit(audit).is_ok());
+
+        let be_ro = be.read().expect("Failed to begin txn");
+        let (page, cursor) = be_ro
+            .search_page(
+                audit,
+                &filt,
+                None,
+                Some(&owner),
+                4,
+                Some(cursor.as_str()),
+                &SearchLimits::unlimited(),
+            )
+            .expect("Search failed");
+        assert_eq!(names(&page), vec!["user4", "user6", "user7"]);
+        let cursor = cursor.expect("no cursor");
+        let (page, last) = be_ro
+            .search_page(
+                audit,
+                &filt,
+                None,
+                Some(&owner),
+                4,
+                Some(cursor.as_str()),
+                &SearchLimits::unlimited(),
+            )
+            .expect("Search failed");
+        assert_eq!(names(&page), vec!["user8", "user9"]);
+        assert!(last.is_none());
+        // Once read to the end, the cursor is gone.
+        assert_eq!(
+            be_ro
+                .search_page(
+                    audit,
+                    &filt,
+                    None,
+                    Some(&owner),
+                    4,
+                    Some(cursor.as_str()),
+                    &SearchLimits::unlimited()
+                )
+                .err(),
+            Some(OperationError::InvalidSearchCursor)
+        );
 
-            match result {
-                Err(e) => {
-                    // if the error is the file is not found, thats what we want so continue,
-                    // otherwise return the error
-                    match e.kind() {
-                        std::io::ErrorKind::NotFound => {}
-                        _ => (),
+        // A single page needs no cursor, and the page size is capped.
+        let (page, cursor) = be_ro
+            .search_page(
+                audit,
+                &filt,
+                None,
+                None,
+                SEARCH_PAGE_MAX + 1,
+                None,
+                &SearchLimits::unlimited(),
+            )
+            .expect("Search failed");
+        assert_eq!(page.len(), 10);
+        assert!(cursor.is_none());
+        assert_eq!(
+            be_ro
+                .search_page(
+                    audit,
+                    &filt,
+                    None,
+                    None,
+                    4,
+                    None,
+                    &SearchLimits {
+                        max_results: Some(5),
+                        ..SearchLimits::unlimited()
                     }
-                }
-                _ => (),
-            }
+                )
+                .err(),
+            Some(OperationError::ResourceLimit)
+        );
+    }
 
-            be.backup(audit, DB_BACKUP_FILE_NAME)
-                .expect("Backup failed!");
-            be.restore(audit, DB_BACKUP_FILE_NAME)
-                .expect("Restore failed!");
-        });
+    #[test]
+    fn test_be_count_and_reports() {
+        let mut audit = AuditScope::new("run_test");
+        let audit = &mut audit;
+        let mut be =
+            Backend::new(audit, "", 1, FILTER_TEST_THRESHOLD).expect("Failed to setup backend");
+        let mut idxmeta = BTreeSet::new();
+        idxmeta.insert(("name".to_string(), IndexType::EQUALITY));
+
+        let mk = |n: &str, u: &str| {
+            let mut e: Entry<EntryInvalid, EntryNew> = Entry::new();
+            e.add_ava("name", &Value::from(n));
+            e.add_ava("uuid", &Value::from(u));
+            e.add_ava("tc", &Value::from("test"));
+            unsafe { e.to_valid_new() }
+        };
+        let mut be_txn = be.write(idxmeta).expect("Failed to begin txn");
+        assert!(be_txn.reindex(audit).is_ok());
+        assert!(be_txn
+            .create(
+                audit,
+                vec![
+                    mk("william", "db237e8a-0079-4b8c-8a56-593b22aa44d1"),
+                    mk("claire", "bd651620-00dd-426b-aaa0-4494f7b7906f"),
+                ]
+            )
+            .is_ok());
+
+        // Indexed, and unindexed so tested against every entry.
+        let f_idx = unsafe { filter_resolved!(f_eq("name", PartialValue::new_utf8s("william"))) };
+        let f_allids = unsafe { filter_resolved!(f_eq("tc", PartialValue::new_utf8s("test"))) };
+        assert_eq!(be_txn.count(audit, &f_idx), Ok((1, false)));
+        assert_eq!(be_txn.count(audit, &f_allids), Ok((2, true)));
+
+        assert_eq!(be_txn.report_last_run(audit, "report"), Ok(None));
+        assert!(be_txn
+            .write_report(audit, "report", 10, "19700101000010Z", 1, false)
+            .is_ok());
+        assert!(be_txn
+            .write_report(audit, "report", 20, "19700101000020Z", 2, true)
+            .is_ok());
+        assert!(be_txn
+            .write_report(audit, "other", 30, "19700101000030Z", 3, false)
+            .is_ok());
+        assert_eq!(be_txn.report_last_run(audit, "report"), Ok(Some(20)));
+        assert!(be_txn.commit(audit).is_ok());
+
+        let be_ro = be.read().expect("Failed to begin txn");
+        let reports = be_ro.reports(audit, "report", 10).expect("No reports");
+        assert_eq!(reports.len(), 2);
+        assert_eq!(reports[0].time, "19700101000020Z");
+        assert_eq!((reports[0].count, reports[0].allids), (2, true));
+        assert_eq!(be_ro.reports(audit, "report", 1).map(|r| r.len()), Ok(1));
     }
 
     #[test]
-    fn test_be_sid_generation_and_reset() {
-        run_test!(
-            |_audit: &mut AuditScope, be: &mut BackendWriteTransaction| {
-                let sid1 = be.get_db_sid();
-                let sid2 = be.get_db_sid();
-                assert!(sid1 == sid2);
-                let sid3 = be.reset_db_sid().unwrap();
-                assert!(sid1 != sid3);
-                let sid4 = be.get_db_sid();
-                assert!(sid3 == sid4);
-            }
+    fn test_be_changelog() {
+        let mut audit = AuditScope::new("run_test");
+        let audit = &mut audit;
+        let mut be =
+            Backend::new(audit, "", 1, FILTER_TEST_THRESHOLD).expect("Failed to setup backend");
+        let u = Uuid::new_v4();
+        let key = [5; 32];
+
+        // Aborted txns leave nothing behind.
+        {
+            let be_txn = be.write(BTreeSet::new()).expect("Failed to begin txn");
+            be_txn.note_change(&u, "a", "create");
+        }
+        let be_txn = be.write(BTreeSet::new()).expect("Failed to begin txn");
+        be_txn.note_change(&u, "a", "create");
+        assert!(be_txn.commit(audit).is_ok());
+
+        // Records from before the key was set are still accepted.
+        be.set_changelog_key(Some(key));
+        let be_txn = be.write(BTreeSet::new()).expect("Failed to begin txn");
+        be_txn.note_change(&u, "b", "modify");
+        be_txn.note_change(&u, "b", "delete");
+        assert!(be_txn.commit(audit).is_ok());
+
+        let be_ro = be.read().expect("Failed to begin txn");
+        assert_eq!(be_ro.verify_changelog(audit, None), Ok((3, None)));
+        assert_eq!(be_ro.verify_changelog(audit, Some(&key)), Ok((3, None)));
+        assert_eq!(
+            be_ro.verify_changelog(audit, Some(&[6; 32])),
+            Ok((3, Some(2)))
         );
     }
 
     #[test]
-    fn test_be_reindex_empty() {
-        run_test!(|audit: &mut AuditScope, be: &mut BackendWriteTransaction| {
-            // Add some test data?
-            let missing = be.missing_idxs(audit).unwrap();
-            assert!(missing.len() == 7);
-            assert!(be.reindex(audit).is_ok());
-            let missing = be.missing_idxs(audit).unwrap();
-            println!("{:?}", missing);
-            assert!(missing.len() == 0);
-        });
+    fn test_be_access_journal_flush() {
+        let mut audit = AuditScope::new("run_test");
+        let audit = &mut audit;
+        let be =
+            Backend::new(audit, "", 1, FILTER_TEST_THRESHOLD).expect("Failed to setup backend");
+        let u = Uuid::new_v4();
+
+        // Nothing is written until the flush, and writes carry on the chain.
+        for identity in ["a", "b"].iter() {
+            let be_ro = be.read().expect("Failed to begin txn");
+            be_ro.note_access(&u, identity, "search");
+            let journal = be_ro.access_journal(audit).expect("No journal");
+            assert_eq!(journal.records.len(), if *identity == "a" { 0 } else { 1 });
+            assert!(be.flush_access_journal(audit).is_ok());
+        }
+        assert!(be.flush_access_journal(audit).is_ok());
+
+        let be_ro = be.read().expect("Failed to begin txn");
+        let journal = be_ro.access_journal(audit).expect("No journal");
+        assert_eq!(journal.broken_at, None);
+        let identities: Vec<_> = journal
+            .records
+            .iter()
+            .map(|r| r.identity.as_str())
+            .collect();
+        assert_eq!(identities, vec!["a", "b"]);
+        assert!(journal
+            .records
+            .iter()
+            .all(|r| r.uuid == u.to_string() && r.operation == "search"));
     }
 
     #[test]
-    fn test_be_reindex_data() {
+    fn test_be_warm_up() {
+        // The usage is counted by read txns, so this needs the backend
+        // rather than the write txn run_test gives us.
+        let mut audit = AuditScope::new("run_test");
+        let audit = &mut audit;
+        let be =
+            Backend::new(audit, "", 1, FILTER_TEST_THRESHOLD).expect("Failed to setup backend");
+        let mut idxmeta = BTreeSet::new();
+        idxmeta.insert(("name".to_string(), IndexType::EQUALITY));
+        idxmeta.insert(("class".to_string(), IndexType::EQUALITY));
+
+        let mut e1: Entry<EntryInvalid, EntryNew> = Entry::new();
+        e1.add_ava("name", &Value::from("william"));
+        e1.add_ava("uuid", &Value::from("db237e8a-0079-4b8c-8a56-593b22aa44d1"));
+        let mut e2: Entry<EntryInvalid, EntryNew> = Entry::new();
+        e2.add_ava("name", &Value::from("system_info"));
+        e2.add_ava("class", &Value::new_class("system"));
+        e2.add_ava("uuid", &Value::from("bd651620-00dd-426b-aaa0-4494f7b7906f"));
+        let mut be_txn = be.write(idxmeta).expect("Failed to begin txn");
+        assert!(be_txn.reindex(audit).is_ok());
+        let ve1 = unsafe { e1.to_valid_new() };
+        let ve2 = unsafe { e2.to_valid_new() };
+        assert!(be_txn.create(audit, vec![ve1, ve2]).is_ok());
+        assert!(be_txn.commit(audit).is_ok());
+
+        // Nothing has been read yet.
+        assert_eq!(be.warm_up(audit, 10), Ok((0, 1)));
+
+        let be_ro = be.read().expect("Failed to begin txn");
+        let filt = unsafe { filter_resolved!(f_eq("name", PartialValue::new_iutf8s("william"))) };
+        assert_eq!(be_ro.search(audit, &filt).map(|r| r.len()), Ok(1));
+        std::mem::drop(be_ro);
+        assert!(be.flush_idx_usage(audit).is_ok());
+
+        assert_eq!(be.warm_up(audit, 10), Ok((1, 2)));
+        assert_eq!(be.warm_up(audit, 0), Ok((0, 1)));
+    }
+
+    #[test]
+    fn test_be_index_create_delete_simple() {
         run_test!(|audit: &mut AuditScope, be: &mut BackendWriteTransaction| {
-            // Add some test data?
-            // TODO: Test reindex duplicate eq?
+            // First, setup our index tables!
+            assert!(be.reindex(audit).is_ok());
+            // Test that on entry create, the indexes are made correctly.
+            // this is a similar case to reindex.
             let mut e1: Entry<EntryInvalid, EntryNew> = Entry::new();
             e1.add_ava("name", &Value::from("william"));
             e1.add_ava("uuid", &Value::from("db237e8a-0079-4b8c-8a56-593b22aa44d1"));
             let e1 = unsafe { e1.to_valid_new() };
 
-            let mut e2: Entry<EntryInvalid, EntryNew> = Entry::new();
-            e2.add_ava("name", &Value::from("claire"));
-            e2.add_ava("uuid", &Value::from("bd651620-00dd-426b-aaa0-4494f7b7906f"));
-            let e2 = unsafe { e2.to_valid_new() };
-
-            be.create(audit, vec![e1.clone(), e2.clone()]).unwrap();
-
-            // purge indexes
-            be.purge_idxs(audit).unwrap();
-            // Check they are gone
-            let missing = be.missing_idxs(audit).unwrap();
-            assert!(missing.len() == 7);
-            assert!(be.reindex(audit).is_ok());
-            let missing = be.missing_idxs(audit).unwrap();
-            println!("{:?}", missing);
-            assert!(missing.len() == 0);
-            // check name and uuid ids on eq, sub, pres
-
-            idl_state!(
-                audit,
-                be,
-                "name",
-                IndexType::EQUALITY,
-                "william",
-                Some(vec![1])
-            );
-
+            let rset = be.create(audit, vec![e1.clone()]).unwrap();
+
             idl_state!(
                 audit,
                 be,
                 "name",
                 IndexType::EQUALITY,
-                "claire",
-                Some(vec![2])
+                "william",
+                Some(vec![1])
             );
 
-            idl_state!(
-                audit,
-                be,
-                "name",
-                IndexType::PRESENCE,
-                "_",
-                Some(vec![1, 2])
-            );
+            idl_state!(audit, be, "name", IndexType::PRESENCE, "_", Some(vec![1]));
 
             idl_state!(
                 audit,
@@ -1341,32 +6845,35 @@ mod tests {
                 Some(vec![1])
             );
 
+            idl_state!(audit, be, "uuid", IndexType::PRESENCE, "_", Some(vec![1]));
+
+            // == Now we delete, and assert we removed the items.
+            be.delete(audit, &rset).unwrap();
+
             idl_state!(
                 audit,
                 be,
-                "uuid",
+                "name",
                 IndexType::EQUALITY,
-                "bd651620-00dd-426b-aaa0-4494f7b7906f",
-                Some(vec![2])
+                "william",
+                Some(Vec::new())
             );
 
             idl_state!(
                 audit,
                 be,
-                "uuid",
+                "name",
                 IndexType::PRESENCE,
                 "_",
-                Some(vec![1, 2])
+                Some(Vec::new())
             );
 
-            // Show what happens with empty
-
             idl_state!(
                 audit,
                 be,
-                "name",
+                "uuid",
                 IndexType::EQUALITY,
-                "not-exist",
+                "db237e8a-0079-4b8c-8a56-593b22aa44d1",
                 Some(Vec::new())
             );
 
@@ -1374,64 +6881,250 @@ mod tests {
                 audit,
                 be,
                 "uuid",
-                IndexType::EQUALITY,
-                "fake-0079-4b8c-8a56-593b22aa44d1",
+                IndexType::PRESENCE,
+                "_",
                 Some(Vec::new())
             );
+        })
+    }
 
-            let uuid_p_idl = be
-                .load_test_idl(
-                    audit,
-                    &"not_indexed".to_string(),
-                    &IndexType::PRESENCE,
-                    &"_".to_string(),
-                )
-                .unwrap(); // unwrap the result
-            assert_eq!(uuid_p_idl, None);
+    #[test]
+    fn test_be_index_name2uuid_live() {
+        run_test!(|audit: &mut AuditScope, be: &mut BackendWriteTransaction| {
+            let u1 = Uuid::parse_str("db237e8a-0079-4b8c-8a56-593b22aa44d1").unwrap();
+            let u2 = Uuid::parse_str("bd651620-00dd-426b-aaa0-4494f7b7906f").unwrap();
+            let mut e1: Entry<EntryInvalid, EntryNew> = Entry::new();
+            e1.add_ava("name", &Value::from("william"));
+            e1.add_ava("uuid", &Value::new_uuid(u1));
+            let e1 = unsafe { e1.to_valid_new() };
+            let mut e2: Entry<EntryInvalid, EntryNew> = Entry::new();
+            e2.add_ava("name", &Value::from("claire"));
+            e2.add_ava("uuid", &Value::new_uuid(u2));
+            let e2 = unsafe { e2.to_valid_new() };
+            let rset = be.create(audit, vec![e1, e2]).unwrap();
 
-            // Check name2uuid
-            // check uuid2name
+            // Swapping names in one modify keeps both.
+            let swapped: Vec<_> = rset
+                .iter()
+                .zip(vec!["claire", "william"])
+                .map(|(e, n)| {
+                    let mut e = e.clone().invalidate();
+                    e.purge_ava("name");
+                    e.add_ava("name", &Value::from(n));
+                    unsafe { e.to_valid_committed() }
+                })
+                .collect();
+            be.modify(audit, &rset, &swapped).unwrap();
+            assert_eq!(be.name2uuid(audit, "william").unwrap(), Some(u2));
+            assert_eq!(be.name2uuid(audit, "claire").unwrap(), Some(u1));
+            assert_eq!(
+                be.uuid2name(audit, &u1).unwrap(),
+                Some("claire".to_string())
+            );
+            assert_eq!(
+                be.uuid2spn(audit, &u1).unwrap(),
+                Some("claire@localhost".to_string())
+            );
+
+            // A recycled entry is no longer found by name.
+            let mut recycled = swapped[0].clone().invalidate();
+            recycled.add_ava("class", &Value::new_class("recycled"));
+            let recycled = unsafe { recycled.to_valid_committed() };
+            be.modify(audit, &vec![swapped[0].clone()], &vec![recycled])
+                .unwrap();
+            assert_eq!(be.name2uuid(audit, "claire").unwrap(), None);
+            assert_eq!(be.uuid2name(audit, &u1).unwrap(), None);
+            assert_eq!(be.uuid2spn(audit, &u1).unwrap(), None);
+
+            // And nor is a deleted one.
+            be.delete(audit, &vec![swapped[1].clone()]).unwrap();
+            assert_eq!(be.name2uuid(audit, "william").unwrap(), None);
+            assert_eq!(be.uuid2name(audit, &u2).unwrap(), None);
+            assert_eq!(be.uuid2spn(audit, &u2).unwrap(), None);
         });
     }
 
     #[test]
-    fn test_be_index_create_delete_simple() {
+    fn test_be_set_domain() {
+        let mut audit = AuditScope::new("run_test");
+        let audit = &mut audit;
+        let mut be =
+            Backend::new(audit, "", 1, FILTER_TEST_THRESHOLD).expect("Failed to setup backend");
+        let u1 = Uuid::parse_str("db237e8a-0079-4b8c-8a56-593b22aa44d1").unwrap();
+        let mut e1: Entry<EntryInvalid, EntryNew> = Entry::new();
+        e1.add_ava("name", &Value::from("william"));
+        e1.add_ava("uuid", &Value::new_uuid(u1));
+        let e1 = unsafe { e1.to_valid_new() };
+
+        let mut be_txn = be.write(BTreeSet::new()).expect("Failed to begin txn");
+        assert!(be_txn.create(audit, vec![e1]).is_ok());
+        assert!(be_txn.commit(audit).is_ok());
+
+        // Those already written move to the new domain, as do those after.
+        assert!(be.set_domain(audit, "example.com").is_ok());
+        let mut be_txn = be.write(BTreeSet::new()).expect("Failed to begin txn");
+        assert_eq!(
+            be_txn.uuid2spn(audit, &u1),
+            Ok(Some("william@example.com".to_string()))
+        );
+        let mut e2: Entry<EntryInvalid, EntryNew> = Entry::new();
+        e2.add_ava("name", &Value::from("claire"));
+        e2.add_ava("uuid", &Value::from("bd651620-00dd-426b-aaa0-4494f7b7906f"));
+        let rset = be_txn
+            .create(audit, vec![unsafe { e2.to_valid_new() }])
+            .expect("Failed to create");
+        assert_eq!(
+            be_txn.uuid2spn(audit, rset[0].get_uuid()),
+            Ok(Some("claire@example.com".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_be_entry_compression() {
+        let mut audit = AuditScope::new("run_test");
+        let audit = &mut audit;
+        let mut be =
+            Backend::new(audit, "", 1, FILTER_TEST_THRESHOLD).expect("Failed to setup backend");
+        let mut e1: Entry<EntryInvalid, EntryNew> = Entry::new();
+        e1.add_ava("name", &Value::from("william"));
+        e1.add_ava("uuid", &Value::from("db237e8a-0079-4b8c-8a56-593b22aa44d1"));
+        let e1 = unsafe { e1.to_valid_new() };
+        let filt = unsafe { filter_resolved!(f_eq("name", PartialValue::new_utf8s("william"))) };
+        let stored_format = |be: &Backend, audit: &mut AuditScope| {
+            let be_txn = be.read().expect("Failed to begin txn");
+            let raw = be_txn
+                .get_idlayer()
+                .get_identry(audit, &IDL::Indexed(IDLBitRange::from_iter(vec![1])))
+                .unwrap();
+            raw[0].data[1]
+        };
+
+        let mut be_txn = be.write(BTreeSet::new()).expect("Failed to begin txn");
+        assert!(be_txn.create(audit, vec![e1]).is_ok());
+        assert!(be_txn.commit(audit).is_ok());
+        assert_eq!(stored_format(&be, audit), DBENTRY_FORMAT_CBOR);
+
+        // Turning it on compresses what's there, and what comes after.
+        assert!(be.set_entry_compression(audit, true).is_ok());
+        assert_eq!(stored_format(&be, audit), DBENTRY_FORMAT_CBOR_ZSTD);
+        let be_txn = be.write(BTreeSet::new()).expect("Failed to begin txn");
+        let r = be_txn.search(audit, &filt).expect("search failed");
+        assert_eq!(r.len(), 1);
+        let mut e1 = r[0].clone().invalidate();
+        e1.add_ava("description", &Value::from("Bill"));
+        let e1 = unsafe { e1.to_valid_committed() };
+        assert!(be_txn.modify(audit, &r, &vec![e1]).is_ok());
+        assert!(be_txn.commit(audit).is_ok());
+        assert_eq!(stored_format(&be, audit), DBENTRY_FORMAT_CBOR_ZSTD);
+
+        // The db remembers, for the next backend to open it.
+        assert_eq!(
+            be.read().unwrap().get_idlayer().get_db_entry_format(),
+            i64::from(DBENTRY_FORMAT_CBOR_ZSTD)
+        );
+        assert!(be.set_entry_compression(audit, false).is_ok());
+        assert_eq!(stored_format(&be, audit), DBENTRY_FORMAT_CBOR);
+        let r = be
+            .read()
+            .and_then(|be_txn| be_txn.search(audit, &filt))
+            .expect("search failed");
+        assert_eq!(r[0].get_ava_single_str("description"), Some("Bill"));
+    }
+
+    #[test]
+    fn test_be_blob_storage() {
         run_test!(|audit: &mut AuditScope, be: &mut BackendWriteTransaction| {
-            // First, setup our index tables!
-            assert!(be.reindex(audit).is_ok());
-            // Test that on entry create, the indexes are made correctly.
-            // this is a similar case to reindex.
+            let photo = "x".repeat(DBENTRY_BLOB_THRESHOLD + 1);
             let mut e1: Entry<EntryInvalid, EntryNew> = Entry::new();
             e1.add_ava("name", &Value::from("william"));
             e1.add_ava("uuid", &Value::from("db237e8a-0079-4b8c-8a56-593b22aa44d1"));
+            e1.add_ava("photo", &Value::from(photo.as_str()));
             let e1 = unsafe { e1.to_valid_new() };
+            assert!(be.create(audit, vec![e1]).is_ok());
+
+            // The entry as stored only refers to the photo.
+            let stored = |be: &BackendWriteTransaction, audit: &mut AuditScope| {
+                be.get_idlayer()
+                    .get_identry(audit, &IDL::Indexed(IDLBitRange::from_iter(vec![1])))
+                    .unwrap()
+                    .remove(0)
+            };
+            let raw = stored(be, audit);
+            assert!(raw.data.len() < DBENTRY_BLOB_THRESHOLD);
+            let (dbe, _) = DbEntry::from_blob(raw.data.as_slice()).unwrap();
+            let hash = match &dbe.ent {
+                DbEntryVers::V1(v1) => match v1.attrs.get("photo").map(|vs| &vs[0]) {
+                    Some(DbValueV1::XB(h)) => h.clone(),
+                    v => panic!("photo not externalised {:?}", v),
+                },
+            };
+            assert!(be.get_idlayer().get_blob(hash.as_str()).unwrap().is_some());
 
-            let rset = be.create(audit, vec![e1.clone()]).unwrap();
+            // Reads put it back, and a modify of something else leaves it be.
+            let filt =
+                unsafe { filter_resolved!(f_eq("name", PartialValue::new_utf8s("william"))) };
+            let r = be.search(audit, &filt).expect("search failed");
+            assert_eq!(r[0].get_ava_single_str("photo"), Some(photo.as_str()));
+            let mut e1 = r[0].clone().invalidate();
+            e1.add_ava("description", &Value::from("Bill"));
+            let e1 = unsafe { e1.to_valid_committed() };
+            assert!(be.modify(audit, &r, &vec![e1]).is_ok());
+            assert!(stored(be, audit).data.len() < DBENTRY_BLOB_THRESHOLD);
+            let r = be.search(audit, &filt).expect("search failed");
+            assert_eq!(r[0].get_ava_single_str("photo"), Some(photo.as_str()));
+            assert_eq!(r[0].get_ava_single_str("description"), Some("Bill"));
+
+            // Once nothing refers to it, it's purged.
+            let mut e1 = r[0].clone().invalidate();
+            e1.purge_ava("photo");
+            let e1 = unsafe { e1.to_valid_committed() };
+            assert!(be.modify(audit, &r, &vec![e1]).is_ok());
+            assert_eq!(be.get_idlayer().purge_blobs(audit), Ok(1));
+            assert!(be.get_idlayer().get_blob(hash.as_str()).unwrap().is_none());
+        })
+    }
 
-            idl_state!(
-                audit,
-                be,
-                "name",
-                IndexType::EQUALITY,
-                "william",
-                Some(vec![1])
-            );
+    #[test]
+    fn test_be_rename_attr() {
+        run_test!(|audit: &mut AuditScope, be: &mut BackendWriteTransaction| {
+            let mut e1: Entry<EntryInvalid, EntryNew> = Entry::new();
+            e1.add_ava("userid", &Value::from("william"));
+            e1.add_ava("uuid", &Value::from("db237e8a-0079-4b8c-8a56-593b22aa44d1"));
+            let e1 = unsafe { e1.to_valid_new() };
 
-            idl_state!(audit, be, "name", IndexType::PRESENCE, "_", Some(vec![1]));
+            let mut e2: Entry<EntryInvalid, EntryNew> = Entry::new();
+            e2.add_ava("userid", &Value::from("alice"));
+            e2.add_ava("uuid", &Value::from("4b6228ab-1dbe-42a4-a9f5-f6368222438e"));
+            let e2 = unsafe { e2.to_valid_new() };
 
-            idl_state!(
-                audit,
-                be,
-                "uuid",
-                IndexType::EQUALITY,
-                "db237e8a-0079-4b8c-8a56-593b22aa44d1",
-                Some(vec![1])
-            );
+            // Already written under the new name.
+            let mut e3: Entry<EntryInvalid, EntryNew> = Entry::new();
+            e3.add_ava("name", &Value::from("claire"));
+            e3.add_ava("uuid", &Value::from("bd651620-00dd-426b-aaa0-4494f7b7906f"));
+            let e3 = unsafe { e3.to_valid_new() };
 
-            idl_state!(audit, be, "uuid", IndexType::PRESENCE, "_", Some(vec![1]));
+            be.create(audit, vec![e1, e2, e3]).unwrap();
 
-            // == Now we delete, and assert we removed the items.
-            be.delete(audit, &rset).unwrap();
+            // A batch at a time, carrying on from where the last stopped.
+            assert_eq!(
+                be.rename_attr(audit, "userid", "name", 0, 1),
+                Ok((1, Some(1)))
+            );
+            assert_eq!(
+                be.rename_attr(audit, "userid", "name", 1, 1),
+                Ok((1, Some(2)))
+            );
+            assert_eq!(be.rename_attr(audit, "userid", "name", 2, 1), Ok((0, None)));
+            assert!(be.finish_rename(audit, "userid", "name").is_ok());
+
+            let r = be
+                .search(audit, unsafe { &filter_resolved!(f_pres("userid")) })
+                .expect("Failed to search");
+            assert!(r.is_empty());
+            let r = be
+                .search(audit, unsafe { &filter_resolved!(f_pres("name")) })
+                .expect("Failed to search");
+            assert_eq!(r.len(), 3);
 
             idl_state!(
                 audit,
@@ -1439,36 +7132,118 @@ mod tests {
                 "name",
                 IndexType::EQUALITY,
                 "william",
-                Some(Vec::new())
+                Some(vec![1])
             );
-
             idl_state!(
                 audit,
                 be,
                 "name",
                 IndexType::PRESENCE,
                 "_",
-                Some(Vec::new())
+                Some(vec![1, 2, 3])
             );
+        });
+    }
 
-            idl_state!(
-                audit,
-                be,
-                "uuid",
-                IndexType::EQUALITY,
-                "db237e8a-0079-4b8c-8a56-593b22aa44d1",
-                Some(Vec::new())
-            );
+    #[test]
+    fn test_be_capture_replay() {
+        let mut audit = AuditScope::new("test_be_capture_replay");
+        let path = "/tmp/kanidm_test_be_capture_replay.json";
+        let mut idxmeta = BTreeSet::new();
+        idxmeta.insert(("name".to_string(), IndexType::EQUALITY));
+        idxmeta.insert(("name".to_string(), IndexType::PRESENCE));
+        idxmeta.insert(("uuid".to_string(), IndexType::EQUALITY));
+
+        let mut be = Backend::new(&mut audit, "", 1, FILTER_TEST_THRESHOLD)
+            .expect("Failed to setup backend");
+        be.set_capture(path, Duration::from_secs(60))
+            .expect("Failed to setup capture");
+        let mut wr = be.write(idxmeta.clone()).expect("Failed to begin txn");
+        assert!(wr.reindex(&mut audit).is_ok());
+        let entries: Vec<_> = vec![
+            ("william", "db237e8a-0079-4b8c-8a56-593b22aa44d1"),
+            ("claire", "bd651620-00dd-426b-aaa0-4494f7b7906f"),
+        ]
+        .into_iter()
+        .map(|(n, u)| {
+            let mut e: Entry<EntryInvalid, EntryNew> = Entry::new();
+            e.add_ava("name", &Value::from(n));
+            e.add_ava("uuid", &Value::from(u));
+            unsafe { e.to_valid_new() }
+        })
+        .collect();
+        let created = wr.create(&mut audit, entries).expect("create failed");
+        assert!(wr.commit(&mut audit).is_ok());
+
+        let wr = be.write(idxmeta).expect("Failed to begin txn");
+        assert!(wr.delete(&mut audit, &vec![created[0].clone()]).is_ok());
+        assert!(wr.commit(&mut audit).is_ok());
+
+        // A new db ends up where the captured one did, less the names.
+        let replay = Backend::new(&mut audit, "", 1, FILTER_TEST_THRESHOLD)
+            .expect("Failed to setup backend");
+        let (txn, r) = replay
+            .replay_capture(&mut audit, path, None)
+            .expect("replay failed");
+        assert_eq!(txn, 2);
+        assert!(r.iter().all(|v| v.is_ok()));
+        let rd = replay.read().expect("Failed to begin txn");
+        let filt = unsafe { filter_resolved!(f_pres("name")) };
+        let found = rd.search(&mut audit, &filt).expect("search failed");
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].get_id(), created[1].get_id());
+        assert_eq!(found[0].get_uuid(), created[1].get_uuid());
+        assert!(found[0].get_ava_single_str("name") != Some("claire"));
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_be_index_cache_flush() {
+        run_test!(|audit: &mut AuditScope, be: &mut BackendWriteTransaction| {
+            assert!(be.reindex(audit).is_ok());
 
+            let entries: Vec<_> = vec![
+                ("william", "db237e8a-0079-4b8c-8a56-593b22aa44d1"),
+                ("claire", "bd651620-00dd-426b-aaa0-4494f7b7906f"),
+                ("lucy", "7b23c99d-c06b-4a9a-a958-3afa56383e1d"),
+            ]
+            .into_iter()
+            .map(|(n, u)| {
+                let mut e: Entry<EntryInvalid, EntryNew> = Entry::new();
+                e.add_ava("name", &Value::from(n));
+                e.add_ava("uuid", &Value::from(u));
+                unsafe { e.to_valid_new() }
+            })
+            .collect();
+            assert!(be.create(audit, entries).is_ok());
+
+            // The txn sees the change, but nothing has been written yet.
             idl_state!(
                 audit,
                 be,
-                "uuid",
+                "name",
                 IndexType::PRESENCE,
                 "_",
-                Some(Vec::new())
+                Some(vec![1, 2, 3])
             );
-        })
+            let name = "name".to_string();
+            let pres = "_".to_string();
+            assert_eq!(
+                be.idlayer
+                    .get_idl(audit, &name, &IndexType::PRESENCE, &pres)
+                    .unwrap(),
+                Some(IDLBitRange::new())
+            );
+
+            assert!(be.flush_idxcache(audit).is_ok());
+            assert!(be.idxcache.borrow().is_empty());
+            assert_eq!(
+                be.idlayer
+                    .get_idl(audit, &name, &IndexType::PRESENCE, &pres)
+                    .unwrap(),
+                Some(IDLBitRange::from_iter(vec![1, 2, 3]))
+            );
+        });
     }
 
     #[test]
@@ -1572,6 +7347,195 @@ mod tests {
         })
     }
 
+    #[test]
+    fn test_be_count_attr_usage() {
+        run_test!(|audit: &mut AuditScope, be: &mut BackendWriteTransaction| {
+            assert!(be.reindex(audit).is_ok());
+            let entries: Vec<_> = vec![Some("william"), Some("claire"), None]
+                .into_iter()
+                .map(|n| {
+                    let mut e: Entry<EntryInvalid, EntryNew> = Entry::new();
+                    if let Some(n) = n {
+                        e.add_ava("name", &Value::from(n));
+                    }
+                    e.add_ava("uuid", &Value::new_uuid(Uuid::new_v4()));
+                    unsafe { e.to_valid_new() }
+                })
+                .collect();
+            be.create(audit, entries).unwrap();
+
+            let count = |be: &BackendWriteTransaction, audit: &mut AuditScope, attr: &str| {
+                be.count_attr_usage(audit, &attr.to_string()).unwrap()
+            };
+            assert_eq!(count(be, audit, "name"), Some(2));
+            assert_eq!(count(be, audit, "uuid"), Some(3));
+            // Only equality indexed, so there is nothing to count from.
+            assert_eq!(count(be, audit, "ta"), None);
+        });
+    }
+
+    #[test]
+    fn test_be_index_substring() {
+        run_test!(|audit: &mut AuditScope, be: &mut BackendWriteTransaction| {
+            assert!(be.reindex(audit).is_ok());
+            let entries: Vec<_> = vec!["william", "willow", "abcxbcd"]
+                .into_iter()
+                .map(|n| {
+                    let mut e: Entry<EntryInvalid, EntryNew> = Entry::new();
+                    e.add_ava("name", &Value::from(n));
+                    e.add_ava("uuid", &Value::new_uuid(Uuid::new_v4()));
+                    unsafe { e.to_valid_new() }
+                })
+                .collect();
+            let rset = be.create(audit, entries).unwrap();
+
+            idl_state!(
+                audit,
+                be,
+                "name",
+                IndexType::SUBSTRING,
+                "wil",
+                Some(vec![1, 2])
+            );
+            idl_state!(
+                audit,
+                be,
+                "name",
+                IndexType::SUBSTRING,
+                "iam",
+                Some(vec![1])
+            );
+
+            let sub =
+                |v: &str| unsafe { filter_resolved!(f_sub("name", PartialValue::new_utf8s(v))) };
+            // One trigram is exact.
+            match be.filter2idl(audit, sub("ill").to_inner(), 0).unwrap() {
+                IDL::Indexed(idl) => assert_eq!(idl.len(), 2),
+                _ => panic!("substring not indexed"),
+            }
+            // willow has ill but not lli, william has both.
+            match be.filter2idl(audit, sub("illi").to_inner(), 0).unwrap() {
+                IDL::Partial(idl) => assert_eq!(idl.len(), 1),
+                _ => panic!("substring not partial"),
+            }
+            // But the trigrams being there doesn't make it a match.
+            match be.filter2idl(audit, sub("abcd").to_inner(), 0).unwrap() {
+                IDL::Partial(idl) => assert_eq!(idl.len(), 1),
+                _ => panic!("substring not partial"),
+            }
+            assert!(be.search(audit, &sub("abcd")).unwrap().is_empty());
+            match be.filter2idl(audit, sub("iamwil").to_inner(), 0).unwrap() {
+                IDL::Indexed(idl) => assert_eq!(idl.len(), 0),
+                _ => panic!("substring not indexed"),
+            }
+            match be.filter2idl(audit, sub("wi").to_inner(), 0).unwrap() {
+                IDL::ALLIDS => {}
+                _ => panic!("short substring indexed"),
+            }
+
+            // Only the trigrams the new value doesn't share are changed.
+            let mut ce1 = rset[0].clone().invalidate();
+            ce1.purge_ava("name");
+            ce1.add_ava("name", &Value::from("willy"));
+            let ce1 = unsafe { ce1.to_valid_committed() };
+            be.modify(audit, &vec![rset[0].clone()], &vec![ce1])
+                .unwrap();
+
+            idl_state!(
+                audit,
+                be,
+                "name",
+                IndexType::SUBSTRING,
+                "wil",
+                Some(vec![1, 2])
+            );
+            idl_state!(audit, be, "name", IndexType::SUBSTRING, "iam", Some(vec![]));
+            idl_state!(
+                audit,
+                be,
+                "name",
+                IndexType::SUBSTRING,
+                "lly",
+                Some(vec![1])
+            );
+        })
+    }
+
+    #[test]
+    fn test_be_index_fuzzy() {
+        run_test!(|audit: &mut AuditScope, be: &mut BackendWriteTransaction| {
+            assert!(be.reindex(audit).is_ok());
+            let entries: Vec<_> = vec!["John Smith", "Jon Smithers", "Jane Smyth"]
+                .into_iter()
+                .map(|n| {
+                    let mut e: Entry<EntryInvalid, EntryNew> = Entry::new();
+                    e.add_ava("name", &Value::from(n));
+                    e.add_ava("uuid", &Value::new_uuid(Uuid::new_v4()));
+                    unsafe { e.to_valid_new() }
+                })
+                .collect();
+            let rset = be.create(audit, entries).unwrap();
+            // Built from what is already there.
+            assert!(be.reindex_attr(audit, "name", &IndexType::FUZZY).is_ok());
+
+            idl_state!(
+                audit,
+                be,
+                "name",
+                IndexType::FUZZY,
+                "J500",
+                Some(vec![1, 2, 3])
+            );
+            idl_state!(
+                audit,
+                be,
+                "name",
+                IndexType::FUZZY,
+                "S530",
+                Some(vec![1, 3])
+            );
+
+            let fuzzy =
+                |v: &str| unsafe { filter_resolved!(f_fuzzy("name", PartialValue::new_utf8s(v))) };
+            match be.filter2idl(audit, fuzzy("smyth").to_inner(), 0).unwrap() {
+                IDL::Indexed(idl) => assert_eq!(idl.len(), 2),
+                _ => panic!("fuzzy not indexed"),
+            }
+            // Both words have to be in the same value, so this is tested.
+            match be
+                .filter2idl(audit, fuzzy("jon smyth").to_inner(), 0)
+                .unwrap()
+            {
+                IDL::Partial(idl) => assert_eq!(idl.len(), 2),
+                _ => panic!("fuzzy not partial"),
+            }
+            assert_eq!(be.search(audit, &fuzzy("jon smyth")).unwrap().len(), 2);
+            assert!(be.search(audit, &fuzzy("jon doe")).unwrap().is_empty());
+            match be.filter2idl(audit, fuzzy("42").to_inner(), 0).unwrap() {
+                IDL::Indexed(idl) => assert_eq!(idl.len(), 0),
+                _ => panic!("fuzzy not indexed"),
+            }
+
+            let mut ce3 = rset[2].clone().invalidate();
+            ce3.purge_ava("name");
+            ce3.add_ava("name", &Value::from("Jane Doe"));
+            let ce3 = unsafe { ce3.to_valid_committed() };
+            be.modify(audit, &vec![rset[2].clone()], &vec![ce3])
+                .unwrap();
+
+            idl_state!(
+                audit,
+                be,
+                "name",
+                IndexType::FUZZY,
+                "J500",
+                Some(vec![1, 2, 3])
+            );
+            idl_state!(audit, be, "name", IndexType::FUZZY, "S530", Some(vec![1]));
+            idl_state!(audit, be, "name", IndexType::FUZZY, "D000", Some(vec![3]));
+        })
+    }
+
     #[test]
     fn test_be_index_modify_rename() {
         run_test!(|audit: &mut AuditScope, be: &mut BackendWriteTransaction| {
@@ -1632,6 +7596,16 @@ mod tests {
                 "william",
                 Some(Vec::new())
             );
+
+            let u1 = Uuid::parse_str("db237e8a-0079-4b8c-8a56-593b22aa44d1").unwrap();
+            let u2 = Uuid::parse_str("04091a7a-6ce4-42d2-abf5-c2ce244ac9e8").unwrap();
+            assert_eq!(be.name2uuid(audit, "william").unwrap(), None);
+            assert_eq!(be.name2uuid(audit, "claire").unwrap(), Some(u2));
+            assert_eq!(be.uuid2name(audit, &u1).unwrap(), None);
+            assert_eq!(
+                be.uuid2name(audit, &u2).unwrap(),
+                Some("claire".to_string())
+            );
         })
     }
 
@@ -0,0 +1,137 @@
+// How the connection pool and the txns on it are doing since we started, so
+// that a pool that is often exhausted, or write txns that hold the db for a
+// long time, can be seen before they become an outage. These are only kept
+// in memory.
+
+use kanidm_proto::v1::{DbPoolStats, TxnStats};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+#[derive(Default)]
+struct Timings {
+    count: u64,
+    total: Duration,
+    max: Duration,
+}
+
+impl Timings {
+    fn add(&mut self, d: Duration) {
+        self.count += 1;
+        self.total += d;
+        if d > self.max {
+            self.max = d;
+        }
+    }
+
+    fn to_proto(&self) -> TxnStats {
+        TxnStats {
+            count: self.count,
+            ms_total: self.total.as_millis() as u64,
+            ms_max: self.max.as_millis() as u64,
+        }
+    }
+}
+
+#[derive(Default)]
+struct PoolMetricsInner {
+    // Time spent waiting for a connection, one per connection handed out.
+    waits: Timings,
+    exhausted: u64,
+    read_txns: Timings,
+    write_txns: Timings,
+    commits: u64,
+    rollbacks: u64,
+}
+
+#[derive(Clone)]
+pub struct PoolMetrics {
+    inner: Arc<Mutex<PoolMetricsInner>>,
+}
+
+impl PoolMetrics {
+    pub fn new() -> Self {
+        PoolMetrics {
+            inner: Arc::new(Mutex::new(PoolMetricsInner::default())),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, PoolMetricsInner> {
+        // As with the other logs, a panic elsewhere mustn't stop these.
+        self.inner
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn acquired(&self, wait: Duration) {
+        self.lock().waits.add(wait)
+    }
+
+    pub fn exhausted(&self) {
+        self.lock().exhausted += 1
+    }
+
+    pub fn read_txn_end(&self, held: Duration) {
+        self.lock().read_txns.add(held)
+    }
+
+    pub fn write_txn_end(&self, held: Duration, committed: bool) {
+        let mut inner = self.lock();
+        inner.write_txns.add(held);
+        if committed {
+            inner.commits += 1;
+        } else {
+            inner.rollbacks += 1;
+        }
+    }
+
+    // The pool's state is asked of the pool as we go, so is passed in.
+    pub fn to_proto(&self, max_size: u32, connections: u32, idle_connections: u32) -> DbPoolStats {
+        let inner = self.lock();
+        DbPoolStats {
+            max_size: max_size,
+            connections: connections,
+            idle_connections: idle_connections,
+            waits: inner.waits.to_proto(),
+            exhausted: inner.exhausted,
+            read_txns: inner.read_txns.to_proto(),
+            write_txns: inner.write_txns.to_proto(),
+            commits: inner.commits,
+            rollbacks: inner.rollbacks,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PoolMetrics;
+    use std::time::Duration;
+
+    #[test]
+    fn test_be_pool_metrics() {
+        let m = PoolMetrics::new();
+        m.acquired(Duration::from_millis(5));
+        m.acquired(Duration::from_millis(15));
+        m.exhausted();
+        m.write_txn_end(Duration::from_millis(40), true);
+        m.write_txn_end(Duration::from_millis(10), false);
+        m.read_txn_end(Duration::from_millis(1));
+
+        let s = m.clone().to_proto(4, 3, 1);
+        assert_eq!((s.max_size, s.connections, s.idle_connections), (4, 3, 1));
+        assert_eq!(
+            (s.waits.count, s.waits.ms_total, s.waits.ms_max),
+            (2, 20, 15)
+        );
+        assert_eq!(s.exhausted, 1);
+        assert_eq!(
+            (
+                s.write_txns.count,
+                s.write_txns.ms_total,
+                s.write_txns.ms_max
+            ),
+            (2, 50, 40)
+        );
+        assert_eq!((s.commits, s.rollbacks), (1, 1));
+        assert_eq!(s.read_txns.count, 1);
+    }
+}
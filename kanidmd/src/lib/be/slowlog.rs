@@ -0,0 +1,105 @@
+// Searches that fell back to testing every entry, or that took longer than the
+// threshold, noted with the filter, how long they took and how many entries
+// they loaded, so that an operator can find what is missing an index in
+// production. As with index usage these are kept in memory on the read path
+// and written to the slow_queries table by the write worker.
+
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+// The default of how long a search may take before it's noted.
+pub const SLOW_QUERY_THRESHOLD: Duration = Duration::from_millis(1000);
+// More than this between flushes are dropped, as the ones we have already say
+// what needs fixing.
+const SLOW_QUERY_MAX_PENDING: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SlowQuery {
+    // generalizedtime, as createtimestamp.
+    pub time: String,
+    pub filter: String,
+    pub duration_ms: u64,
+    // The entries loaded to be tested, and how many of them matched.
+    pub candidates: u64,
+    pub results: u64,
+    pub allids: bool,
+}
+
+#[derive(Clone)]
+pub struct SlowQueryLog {
+    threshold: Duration,
+    inner: Arc<Mutex<Vec<SlowQuery>>>,
+}
+
+impl SlowQueryLog {
+    pub fn new(threshold: Duration) -> Self {
+        SlowQueryLog {
+            threshold: threshold,
+            inner: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<SlowQuery>> {
+        // Losing a record to a panic doesn't matter.
+        self.inner
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    // Whether a search that took elapsed should be noted.
+    pub fn is_slow(&self, elapsed: Duration, allids: bool) -> bool {
+        allids || elapsed >= self.threshold
+    }
+
+    pub fn record(
+        &self,
+        filter: String,
+        elapsed: Duration,
+        candidates: usize,
+        results: usize,
+        allids: bool,
+    ) {
+        let mut inner = self.lock();
+        if inner.len() >= SLOW_QUERY_MAX_PENDING {
+            return;
+        }
+        let time = time::now_utc()
+            .strftime("%Y%m%d%H%M%SZ")
+            .map(|t| t.to_string())
+            .unwrap_or_default();
+        inner.push(SlowQuery {
+            time: time,
+            filter: filter,
+            duration_ms: elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_millis()),
+            candidates: candidates as u64,
+            results: results as u64,
+            allids: allids,
+        });
+    }
+
+    pub fn take(&self) -> Vec<SlowQuery> {
+        std::mem::replace(&mut *self.lock(), Vec::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SlowQueryLog, SLOW_QUERY_MAX_PENDING};
+    use std::time::Duration;
+
+    #[test]
+    fn test_be_slow_query_log() {
+        let log = SlowQueryLog::new(Duration::from_millis(100));
+        assert!(!log.is_slow(Duration::from_millis(99), false));
+        assert!(log.is_slow(Duration::from_millis(100), false));
+        assert!(log.is_slow(Duration::from_millis(0), true));
+
+        for _ in 0..SLOW_QUERY_MAX_PENDING + 1 {
+            log.record("f".to_string(), Duration::from_millis(1500), 10, 1, true);
+        }
+        let records = log.take();
+        assert_eq!(records.len(), SLOW_QUERY_MAX_PENDING);
+        assert_eq!(records[0].duration_ms, 1500);
+        assert!(log.take().is_empty());
+    }
+}
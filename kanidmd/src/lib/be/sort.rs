@@ -0,0 +1,86 @@
+// Putting search results in the order a client asked for. Values are ordered
+// by their index keys - what an ORDERING index holds, and what Ge and Le
+// compare - so a sort gives the same order whether it could walk the index or
+// had to look at the entries. Entries without the attribute come last either
+// way, and ties are in id order.
+
+use crate::entry::{Entry, EntryCommitted, EntryValid};
+use idlset::IDLBitRange;
+use std::cmp::Ordering;
+use std::collections::BTreeSet;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SortSpec {
+    pub attr: String,
+    pub descending: bool,
+}
+
+impl SortSpec {
+    pub fn new(attr: &str, descending: bool) -> Self {
+        SortSpec {
+            attr: attr.to_string(),
+            descending: descending,
+        }
+    }
+
+    // An entry with several values sorts by the one that comes first, IE the
+    // least ascending and the greatest descending.
+    fn key(&self, e: &Entry<EntryValid, EntryCommitted>) -> Option<String> {
+        let keys = e
+            .get_ava(self.attr.as_str())?
+            .into_iter()
+            .flat_map(|v| v.generate_idx_eq_keys());
+        if self.descending {
+            keys.max()
+        } else {
+            keys.min()
+        }
+    }
+
+    fn cmp_keys(&self, a: &Option<String>, b: &Option<String>) -> Ordering {
+        match (a, b) {
+            (Some(a), Some(b)) if self.descending => b.cmp(a),
+            (Some(a), Some(b)) => a.cmp(b),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => Ordering::Equal,
+        }
+    }
+
+    pub fn sort_entries(
+        &self,
+        entries: Vec<Entry<EntryValid, EntryCommitted>>,
+    ) -> Vec<Entry<EntryValid, EntryCommitted>> {
+        let mut keyed: Vec<_> = entries.into_iter().map(|e| (self.key(&e), e)).collect();
+        keyed.sort_by(|(ka, a), (kb, b)| {
+            self.cmp_keys(ka, kb)
+                .then_with(|| a.get_id().cmp(&b.get_id()))
+        });
+        keyed.into_iter().map(|(_, e)| e).collect()
+    }
+
+    // The order of ids from the keys of an ORDERING index on attr, without
+    // loading any of the entries. Walking the keys from the end for
+    // descending meets each entry at its greatest value, as key does.
+    pub fn order_ids(&self, ids: &[u64], mut keys: Vec<(String, IDLBitRange)>) -> Vec<u64> {
+        keys.sort_by(|(a, _), (b, _)| a.cmp(b));
+        if self.descending {
+            keys.reverse();
+        }
+        let mut remaining: BTreeSet<u64> = ids.iter().cloned().collect();
+        let mut ordered = Vec::with_capacity(ids.len());
+        for (_, idl) in keys.iter() {
+            if remaining.is_empty() {
+                break;
+            }
+            for id in idl {
+                if remaining.remove(&id) {
+                    ordered.push(id);
+                }
+            }
+        }
+        // Those without a value, in id order.
+        ordered.extend(remaining);
+        ordered
+    }
+}
@@ -0,0 +1,100 @@
+// Counts of the index slots that searches read, so that after a restart the
+// hottest can be read back in before the first requests arrive, rather than
+// each of them paying to fault its pages in from disk. Alongside, the times a
+// search had to fall back to all ids for want of an index, which is how an
+// operator can tell what to index.
+//
+// Counting happens in memory on the read path, and is merged into the
+// database periodically by the write worker.
+
+use crate::value::IndexType;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+// Once this many distinct slots are counted between flushes, only those
+// already counted are kept up, so a scan over many keys can't grow this
+// without bound.
+const IDX_USAGE_MAX_SLOTS: usize = 8192;
+
+pub type IdxSlot = (String, IndexType, String);
+
+#[derive(Default)]
+struct UsageState {
+    slots: BTreeMap<IdxSlot, i64>,
+    allids: BTreeMap<(String, IndexType), i64>,
+}
+
+#[derive(Clone)]
+pub struct IdxUsage {
+    inner: Arc<Mutex<UsageState>>,
+}
+
+impl IdxUsage {
+    pub fn new() -> Self {
+        IdxUsage {
+            inner: Arc::new(Mutex::new(UsageState::default())),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, UsageState> {
+        // Losing a count to a panic doesn't matter.
+        self.inner
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn hit(&self, attr: &String, itype: &IndexType, idx_key: &String) {
+        let mut inner = self.lock();
+        let slot = (attr.clone(), itype.clone(), idx_key.clone());
+        match inner.slots.get_mut(&slot) {
+            Some(hits) => *hits += 1,
+            None => {
+                if inner.slots.len() < IDX_USAGE_MAX_SLOTS {
+                    inner.slots.insert(slot, 1);
+                }
+            }
+        }
+    }
+
+    // There are only as many of these as attributes searched on, so they
+    // need no bound.
+    pub fn allids(&self, attr: &String, itype: &IndexType) {
+        *self
+            .lock()
+            .allids
+            .entry((attr.clone(), itype.clone()))
+            .or_insert(0) += 1;
+    }
+
+    // The counts since the last take, which start again from nothing.
+    pub fn take(&self) -> (BTreeMap<IdxSlot, i64>, BTreeMap<(String, IndexType), i64>) {
+        let state = std::mem::replace(&mut *self.lock(), UsageState::default());
+        (state.slots, state.allids)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::IdxUsage;
+    use crate::value::IndexType;
+
+    #[test]
+    fn test_be_idx_usage() {
+        let usage = IdxUsage::new();
+        let attr = "name".to_string();
+        usage.hit(&attr, &IndexType::EQUALITY, &"william".to_string());
+        usage.hit(&attr, &IndexType::EQUALITY, &"william".to_string());
+        usage.hit(&attr, &IndexType::PRESENCE, &"_".to_string());
+        usage.allids(&attr, &IndexType::SUBSTRING);
+
+        let (counts, allids) = usage.take();
+        assert_eq!(counts.len(), 2);
+        assert_eq!(
+            counts.get(&(attr.clone(), IndexType::EQUALITY, "william".to_string())),
+            Some(&2)
+        );
+        assert_eq!(allids.get(&(attr.clone(), IndexType::SUBSTRING)), Some(&1));
+        let (counts, allids) = usage.take();
+        assert!(counts.is_empty() && allids.is_empty());
+    }
+}
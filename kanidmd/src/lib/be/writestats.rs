@@ -0,0 +1,105 @@
+// What each write txn wrote, split between id2entry, the idls and the
+// changelog, so that a change rewriting far more than it should stands out -
+// IE one that touches an attribute whose presence idl holds every entry. These
+// are only kept in memory, for the most recent commits.
+
+use kanidm_proto::v1::WriteStatsRecord;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+// Enough to see a pattern, without holding on to much.
+pub const WRITE_STATS_KEEP: usize = 256;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct WriteStats {
+    pub entries: u64,
+    pub id2entry_bytes: u64,
+    // Each idl written or removed is a slot.
+    pub idx_slots: u64,
+    pub idx_bytes: u64,
+    pub changelog_records: u64,
+}
+
+struct WriteStatsLogInner {
+    next_id: u64,
+    records: VecDeque<WriteStatsRecord>,
+}
+
+#[derive(Clone)]
+pub struct WriteStatsLog {
+    inner: Arc<Mutex<WriteStatsLogInner>>,
+}
+
+impl WriteStatsLog {
+    pub fn new() -> Self {
+        WriteStatsLog {
+            inner: Arc::new(Mutex::new(WriteStatsLogInner {
+                next_id: 1,
+                records: VecDeque::new(),
+            })),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, WriteStatsLogInner> {
+        // Losing a record to a panic doesn't matter.
+        self.inner
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    // A txn that wrote nothing isn't worth a record.
+    pub fn record(&self, stats: &WriteStats) {
+        if *stats == WriteStats::default() {
+            return;
+        }
+        let time = time::now_utc()
+            .strftime("%Y%m%d%H%M%SZ")
+            .map(|t| t.to_string())
+            .unwrap_or_default();
+        let mut inner = self.lock();
+        let id = inner.next_id;
+        inner.next_id += 1;
+        inner.records.push_back(WriteStatsRecord {
+            id: id,
+            time: time,
+            entries: stats.entries,
+            id2entry_bytes: stats.id2entry_bytes,
+            idx_slots: stats.idx_slots,
+            idx_bytes: stats.idx_bytes,
+            changelog_records: stats.changelog_records,
+        });
+        while inner.records.len() > WRITE_STATS_KEEP {
+            inner.records.pop_front();
+        }
+    }
+
+    // Newest first.
+    pub fn recent(&self) -> Vec<WriteStatsRecord> {
+        self.lock().records.iter().rev().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{WriteStats, WriteStatsLog, WRITE_STATS_KEEP};
+
+    #[test]
+    fn test_be_write_stats_log() {
+        let log = WriteStatsLog::new();
+        log.record(&WriteStats::default());
+        assert!(log.recent().is_empty());
+
+        for i in 0..WRITE_STATS_KEEP + 1 {
+            log.record(&WriteStats {
+                entries: i as u64,
+                ..WriteStats::default()
+            });
+        }
+        let recent = log.recent();
+        assert_eq!(recent.len(), WRITE_STATS_KEEP);
+        assert_eq!(recent[0].entries, WRITE_STATS_KEEP as u64);
+        assert_eq!(recent[0].id, WRITE_STATS_KEEP as u64 + 1);
+        // The first, which wrote no entries, has gone.
+        assert_eq!(recent[WRITE_STATS_KEEP - 1].entries, 1);
+    }
+}
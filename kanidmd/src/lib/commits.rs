@@ -0,0 +1,182 @@
+// Every write txn that commits a change publishes what it changed, for the
+// things that follow the server from outside a txn, such as persistent
+// searches and the rate limits.
+//
+// The filter, entry and access control caches aren't driven by this. A read
+// txn that begins after a commit must never see them stale, and a channel is
+// only read some time after the publish, so those are invalidated within the
+// commit itself.
+//
+// Subscribers each get their own channel. Events are only sent once the
+// backend commit has succeeded, so an aborted txn is never seen. A subscriber
+// that goes away is dropped on the next publish.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
+use uuid::Uuid;
+
+use crate::entry::{Entry, EntryCommitted, EntryValid};
+use crate::value::PartialValue;
+
+lazy_static! {
+    static ref PVCLASS_RECYCLED: PartialValue = PartialValue::new_class("recycled");
+    static ref PVCLASS_TOMBSTONE: PartialValue = PartialValue::new_class("tombstone");
+}
+
+// As a client would see it, so an entry being recycled or tombstoned is a
+// delete, and one revived is a create.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChangeType {
+    Created,
+    Modified,
+    Deleted,
+}
+
+fn is_gone(e: &Entry<EntryValid, EntryCommitted>) -> bool {
+    e.attribute_value_pres("class", &PVCLASS_RECYCLED)
+        || e.attribute_value_pres("class", &PVCLASS_TOMBSTONE)
+}
+
+// The attributes whose values differ between pre and post.
+pub fn changed_attrs(
+    pre: &Entry<EntryValid, EntryCommitted>,
+    post: &Entry<EntryValid, EntryCommitted>,
+) -> BTreeSet<String> {
+    let names = pre.get_ava_names();
+    names
+        .union(&post.get_ava_names())
+        .filter(|attr| pre.get_ava_set(attr) != post.get_ava_set(attr))
+        .map(|attr| attr.to_string())
+        .collect()
+}
+
+// What a txn has changed so far. The backend records this as it writes.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ChangeSet {
+    pub uuids: BTreeSet<Uuid>,
+    pub attrs: BTreeSet<String>,
+    // What happened to each of uuids, over the whole txn.
+    pub types: BTreeMap<Uuid, ChangeType>,
+}
+
+impl ChangeSet {
+    pub fn new() -> Self {
+        ChangeSet::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.uuids.is_empty()
+    }
+
+    // An entry created and changed again in the same txn was still created,
+    // and one deleted and created again was only changed.
+    fn record_type(&mut self, uuid: &Uuid, change: ChangeType) {
+        let change = match (self.types.get(uuid), change) {
+            (Some(ChangeType::Created), ChangeType::Deleted) => ChangeType::Deleted,
+            (Some(ChangeType::Created), _) => ChangeType::Created,
+            (Some(ChangeType::Deleted), ChangeType::Created) => ChangeType::Modified,
+            (Some(ChangeType::Deleted), ChangeType::Modified) => ChangeType::Deleted,
+            (_, change) => change,
+        };
+        self.types.insert(uuid.clone(), change);
+    }
+
+    // A create or delete changes every attribute the entry has, a modify only
+    // those whose values differ.
+    pub fn record(
+        &mut self,
+        pre: Option<&Entry<EntryValid, EntryCommitted>>,
+        post: Option<&Entry<EntryValid, EntryCommitted>>,
+    ) {
+        match (pre, post) {
+            (Some(pre), Some(post)) => {
+                self.uuids.insert(post.get_uuid().clone());
+                let change = match (is_gone(pre), is_gone(post)) {
+                    (false, true) => ChangeType::Deleted,
+                    (true, false) => ChangeType::Created,
+                    _ => ChangeType::Modified,
+                };
+                self.record_type(post.get_uuid(), change);
+                self.attrs.extend(changed_attrs(pre, post));
+            }
+            (Some(e), None) | (None, Some(e)) => {
+                self.uuids.insert(e.get_uuid().clone());
+                let change = if post.is_some() {
+                    ChangeType::Created
+                } else {
+                    ChangeType::Deleted
+                };
+                self.record_type(e.get_uuid(), change);
+                self.attrs
+                    .extend(e.get_ava_names().into_iter().map(|a| a.to_string()));
+            }
+            (None, None) => {}
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct CommitEvent {
+    // Increases by one with each commit that changed something.
+    pub generation: u64,
+    pub changes: ChangeSet,
+}
+
+struct BroadcastState {
+    generation: u64,
+    subscribers: Vec<Sender<Arc<CommitEvent>>>,
+}
+
+#[derive(Clone)]
+pub struct CommitBroadcast {
+    inner: Arc<Mutex<BroadcastState>>,
+}
+
+impl CommitBroadcast {
+    pub fn new() -> Self {
+        CommitBroadcast {
+            inner: Arc::new(Mutex::new(BroadcastState {
+                generation: 0,
+                subscribers: Vec::new(),
+            })),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, BroadcastState> {
+        // A panic can't leave the state inconsistent.
+        self.inner
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn subscribe(&self) -> Receiver<Arc<CommitEvent>> {
+        let (tx, rx) = channel();
+        self.lock().subscribers.push(tx);
+        rx
+    }
+
+    // Writes are serialised by the write txn, so generations are published
+    // in order.
+    pub fn publish(&self, changes: ChangeSet) -> u64 {
+        let mut inner = self.lock();
+        if changes.is_empty() {
+            return inner.generation;
+        }
+        inner.generation += 1;
+        let event = Arc::new(CommitEvent {
+            generation: inner.generation,
+            changes: changes,
+        });
+        inner
+            .subscribers
+            .retain(|tx| tx.send(event.clone()).is_ok());
+        debug!(
+            "Published commit generation {} ({} entries) to {} subscribers",
+            event.generation,
+            event.changes.uuids.len(),
+            inner.subscribers.len()
+        );
+        event.generation
+    }
+}
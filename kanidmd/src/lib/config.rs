@@ -1,7 +1,13 @@
+use crate::be::{SearchLimits, FILTER_TEST_THRESHOLD, SLOW_QUERY_THRESHOLD};
 use num_cpus;
+use openssl::sha;
 use rand::prelude::*;
+use std::collections::BTreeMap;
 use std::fmt;
+use std::fs;
 use std::path::PathBuf;
+use std::process::Command;
+use std::time::Duration;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct IntegrationTestConfig {
@@ -15,17 +21,307 @@ pub struct TlsConfiguration {
     pub key: String,
 }
 
+// How filter values are shown in the access log, and how redacted attributes
+// are written in an export.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum LogRedaction {
+    Clear,
+    Truncate(usize),
+    Hash,
+}
+
+impl LogRedaction {
+    pub fn from_str(s: &str) -> Option<Self> {
+        // Accept clear, hash, truncate, or truncate=N
+        let mut parts = s.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some("clear"), None) => Some(LogRedaction::Clear),
+            (Some("hash"), None) => Some(LogRedaction::Hash),
+            (Some("truncate"), None) => Some(LogRedaction::Truncate(4)),
+            (Some("truncate"), Some(n)) => n.parse().ok().map(|n| LogRedaction::Truncate(n)),
+            _ => None,
+        }
+    }
+
+    pub fn redact(&self, v: &str) -> String {
+        match self {
+            LogRedaction::Clear => v.to_string(),
+            LogRedaction::Truncate(len) => {
+                let t: String = v.chars().take(*len).collect();
+                if t.len() < v.len() {
+                    format!("{}...", t)
+                } else {
+                    t
+                }
+            }
+            LogRedaction::Hash => {
+                // We only need enough of the hash to correlate repeated values.
+                let h = sha::sha256(v.as_bytes());
+                let hs: Vec<String> = h[..8].iter().map(|b| format!("{:02x}", b)).collect();
+                format!("#{}", hs.concat())
+            }
+        }
+    }
+}
+
+// Compression of backup files. Restore detects this from the file itself.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum BackupCompression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl BackupCompression {
+    pub fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "none" => Some(BackupCompression::None),
+            "gzip" => Some(BackupCompression::Gzip),
+            "zstd" => Some(BackupCompression::Zstd),
+            _ => None,
+        }
+    }
+}
+
+// What a backup is written as. Only json can be restored, ldif is for
+// consumption by other tools.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum BackupFormat {
+    Json,
+    Ldif,
+}
+
+impl BackupFormat {
+    pub fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "json" => Some(BackupFormat::Json),
+            "ldif" => Some(BackupFormat::Ldif),
+            _ => None,
+        }
+    }
+}
+
+// What an analytics export contains. Each class gets its own csv, with the
+// uuid followed by attrs as columns. Attributes named in redact are written
+// through their rule, everything else as is.
+#[derive(Debug, Clone)]
+pub struct ExportSpec {
+    pub classes: Vec<String>,
+    pub attrs: Vec<String>,
+    pub redact: BTreeMap<String, LogRedaction>,
+}
+
+// The key backups are encrypted with. This deliberately isn't Debug or
+// Serialize so it can't end up in a log.
+#[derive(Clone)]
+pub enum BackupKey {
+    Key([u8; 32]),
+    Passphrase(String),
+}
+
+// A key file holds 32 bytes as hex, IE from openssl rand -hex 32
+pub fn read_key_file(p: &PathBuf) -> Option<[u8; 32]> {
+    let content = fs::read_to_string(p).ok()?;
+    parse_hex_key(content.as_str())
+}
+
+fn parse_hex_key(content: &str) -> Option<[u8; 32]> {
+    let content = content.trim();
+    if content.len() != 64 || !content.is_ascii() {
+        return None;
+    }
+    let mut key = [0; 32];
+    for (i, k) in key.iter_mut().enumerate() {
+        *k = u8::from_str_radix(&content[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(key)
+}
+
+// Where the key the database is encrypted with comes from. A command is run
+// by the shell and must print the key as hex, so it can be unsealed from a
+// TPM or fetched from a KMS rather than kept on disk beside the database.
+#[derive(Debug, Clone)]
+pub enum DbKeySource {
+    File(PathBuf),
+    Command(String),
+}
+
+impl DbKeySource {
+    pub fn from_opts(file: &Option<PathBuf>, command: &Option<String>) -> Option<Self> {
+        match (file, command) {
+            (None, None) => None,
+            (Some(p), None) => Some(DbKeySource::File(p.clone())),
+            (None, Some(c)) => Some(DbKeySource::Command(c.clone())),
+            (Some(_), Some(_)) => {
+                error!("Only one of a database key file or key command may be given");
+                std::process::exit(1);
+            }
+        }
+    }
+
+    pub fn load(&self) -> Option<[u8; 32]> {
+        match self {
+            DbKeySource::File(p) => read_key_file(p),
+            DbKeySource::Command(c) => {
+                let out = Command::new("sh").arg("-c").arg(c).output().ok()?;
+                if !out.status.success() {
+                    error!("Database key command failed -> {:?}", out.status);
+                    return None;
+                }
+                parse_hex_key(String::from_utf8(out.stdout).ok()?.as_str())
+            }
+        }
+    }
+}
+
+impl BackupKey {
+    pub fn from_key_file(p: &PathBuf) -> Option<Self> {
+        read_key_file(p).map(BackupKey::Key)
+    }
+}
+
+// How group sizes are spread when generating test data. Real directories
+// tend to look like zipf - a few huge groups and a long tail of small ones.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum GroupSizeDistribution {
+    Uniform,
+    Zipf,
+}
+
+impl GroupSizeDistribution {
+    pub fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "uniform" => Some(GroupSizeDistribution::Uniform),
+            "zipf" => Some(GroupSizeDistribution::Zipf),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct GenerateConfig {
+    pub accounts: usize,
+    pub groups: usize,
+    // The size of the largest group.
+    pub max_members: usize,
+    pub distribution: GroupSizeDistribution,
+    // The same seed always yields the same dataset.
+    pub seed: u64,
+}
+
+// Sqlite tuning, since what suits a server on fast disks is wrong for a small
+// arm board on an sd card. Anything unset is left as sqlite's default.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct DbPragmas {
+    // One of off, normal, full or extra.
+    pub synchronous: Option<String>,
+    // Pages if positive, KiB if negative, as sqlite takes it.
+    pub cache_size: Option<i64>,
+    // In bytes. Ignored on 32 bit builds, which never map the db.
+    pub mmap_size: Option<i64>,
+    // In pages of wal.
+    pub wal_autocheckpoint: Option<i64>,
+    // Milliseconds sqlite waits on a lock before giving up as busy, which
+    // we then retry a few times before failing the operation.
+    pub busy_timeout: Option<i64>,
+}
+
+impl DbPragmas {
+    pub fn synchronous_valid(s: &str) -> bool {
+        match s {
+            "off" | "normal" | "full" | "extra" => true,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OnlineBackup {
+    // The directory backups are written to.
+    pub path: String,
+    // Seconds between backups.
+    pub interval: u64,
+    // How many backups are kept - beyond this the oldest are removed.
+    pub versions: usize,
+}
+
+// Pulling from the other servers of this domain, see repl/mod.rs.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ReplConfig {
+    // Their origins, IE https://idm2.example.com:8443
+    pub partners: Vec<String>,
+    // Who we authenticate to them as, which must be a member of
+    // system_admins. Being replicated, it's the same on every partner.
+    pub account: String,
+    pub password: String,
+    // To verify the partners with, rather than the system's roots.
+    pub ca: Option<String>,
+    // Seconds between pulls.
+    pub interval: u64,
+    // If set we're a read replica of this partner, and refer writes to it.
+    pub primary: Option<String>,
+}
+
+// Not the password.
+impl fmt::Debug for ReplConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReplConfig")
+            .field("partners", &self.partners)
+            .field("account", &self.account)
+            .field("ca", &self.ca)
+            .field("interval", &self.interval)
+            .field("primary", &self.primary)
+            .finish()
+    }
+}
+
+// The LDAPS frontend, see ldap/mod.rs.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LdapConfig {
+    pub address: String,
+    // Entries are presented beneath this, IE dc=example,dc=com
+    pub basedn: String,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Configuration {
     pub address: String,
     pub domain: String,
+    // Where clients reach us, IE https://idm.example.com. OAuth2 issuers and
+    // the endpoints relying parties are given are beneath this.
+    pub origin: String,
     pub threads: usize,
     // db type later
     pub db_path: String,
+    // If set, the database is opened with SQLCipher using this key.
+    pub db_key: Option<DbKeySource>,
+    pub db_pragmas: DbPragmas,
     pub maximum_request: usize,
     pub secure_cookies: bool,
     pub tls_config: Option<TlsConfiguration>,
     pub cookie_key: [u8; 32],
+    pub log_redaction: LogRedaction,
+    // If set, access lines are appended here rather than to the server log.
+    pub access_log: Option<PathBuf>,
+    // What any one search may cost, unless the account sets its own.
+    pub search_limits: SearchLimits,
+    pub online_backup: Option<OnlineBackup>,
+    // If set, the number of most used index slots to preload at startup.
+    pub warmup: Option<usize>,
+    // See be::FILTER_TEST_THRESHOLD.
+    pub filter_test_threshold: usize,
+    // Searches taking longer than this are recorded in the slow query log.
+    pub slow_query_threshold: Duration,
+    // If set, changelog records are signed with this.
+    pub changelog_key: Option<[u8; 32]>,
+    // Whether to zstd compress entries in id2entry. If not set, the db keeps
+    // doing whatever it did last.
+    pub entry_compression: Option<bool>,
+    // If set, backend write txns are captured to this path for this long, to
+    // be replayed with `kanidmd replay`.
+    pub capture: Option<(String, Duration)>,
+    pub replication: Option<ReplConfig>,
+    pub ldap: Option<LdapConfig>,
     pub integration_test_config: Option<Box<IntegrationTestConfig>>,
 }
 
@@ -33,11 +329,26 @@ impl fmt::Display for Configuration {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "address: {}, ", self.address)
             .and_then(|_| write!(f, "domain: {}, ", self.domain))
+            .and_then(|_| write!(f, "origin: {}, ", self.origin))
             .and_then(|_| write!(f, "thread count: {}, ", self.threads))
             .and_then(|_| write!(f, "dbpath: {}, ", self.db_path))
+            .and_then(|_| write!(f, "db encryption: {}, ", self.db_key.is_some()))
+            .and_then(|_| write!(f, "db pragmas: {:?}, ", self.db_pragmas))
             .and_then(|_| write!(f, "max request size: {}b, ", self.maximum_request))
             .and_then(|_| write!(f, "secure cookies: {}, ", self.secure_cookies))
             .and_then(|_| write!(f, "with TLS: {}, ", self.tls_config.is_some()))
+            .and_then(|_| write!(f, "access log redaction: {:?}, ", self.log_redaction))
+            .and_then(|_| write!(f, "access log: {:?}, ", self.access_log))
+            .and_then(|_| write!(f, "search limits: {:?}, ", self.search_limits))
+            .and_then(|_| write!(f, "online backup: {:?}, ", self.online_backup))
+            .and_then(|_| write!(f, "warm up slots: {:?}, ", self.warmup))
+            .and_then(|_| write!(f, "filter test threshold: {}, ", self.filter_test_threshold))
+            .and_then(|_| write!(f, "slow query threshold: {:?}, ", self.slow_query_threshold))
+            .and_then(|_| write!(f, "signed changelog: {}, ", self.changelog_key.is_some()))
+            .and_then(|_| write!(f, "entry compression: {:?}, ", self.entry_compression))
+            .and_then(|_| write!(f, "capture: {:?}, ", self.capture))
+            .and_then(|_| write!(f, "replication: {:?}, ", self.replication))
+            .and_then(|_| write!(f, "ldap: {:?}, ", self.ldap))
             .and_then(|_| {
                 write!(
                     f,
@@ -53,8 +364,11 @@ impl Configuration {
         let mut c = Configuration {
             address: String::from("127.0.0.1:8080"),
             domain: String::from("localhost"),
+            origin: String::from("https://localhost"),
             threads: num_cpus::get(),
             db_path: String::from(""),
+            db_key: None,
+            db_pragmas: DbPragmas::default(),
             maximum_request: 262144, // 256k
             // log type
             // log path
@@ -62,6 +376,18 @@ impl Configuration {
             secure_cookies: if cfg!(test) { false } else { true },
             tls_config: None,
             cookie_key: [0; 32],
+            log_redaction: LogRedaction::Hash,
+            access_log: None,
+            search_limits: SearchLimits::unlimited(),
+            online_backup: None,
+            warmup: None,
+            filter_test_threshold: FILTER_TEST_THRESHOLD,
+            slow_query_threshold: SLOW_QUERY_THRESHOLD,
+            changelog_key: None,
+            entry_compression: None,
+            capture: None,
+            replication: None,
+            ldap: None,
             integration_test_config: None,
         };
         let mut rng = StdRng::from_entropy();
@@ -79,6 +405,41 @@ impl Configuration {
         }
     }
 
+    pub fn update_db_key(&mut self, file: &Option<PathBuf>, command: &Option<String>) {
+        self.db_key = DbKeySource::from_opts(file, command);
+    }
+
+    pub fn update_db_pragmas(
+        &mut self,
+        synchronous: &Option<String>,
+        cache_size: &Option<i64>,
+        mmap_size: &Option<i64>,
+        wal_autocheckpoint: &Option<i64>,
+        busy_timeout: &Option<i64>,
+    ) {
+        let synchronous = synchronous.as_ref().map(|s| s.to_lowercase());
+        if let Some(s) = synchronous.as_ref() {
+            if !DbPragmas::synchronous_valid(s.as_str()) {
+                error!("Invalid db synchronous - must be off, normal, full or extra");
+                std::process::exit(1);
+            }
+        }
+        if mmap_size.map(|m| m < 0).unwrap_or(false)
+            || wal_autocheckpoint.map(|w| w < 0).unwrap_or(false)
+            || busy_timeout.map(|b| b < 0).unwrap_or(false)
+        {
+            error!("Invalid db pragmas - mmap size, wal autocheckpoint and busy timeout can't be negative");
+            std::process::exit(1);
+        }
+        self.db_pragmas = DbPragmas {
+            synchronous: synchronous,
+            cache_size: *cache_size,
+            mmap_size: *mmap_size,
+            wal_autocheckpoint: *wal_autocheckpoint,
+            busy_timeout: *busy_timeout,
+        };
+    }
+
     pub fn update_bind(&mut self, b: &Option<String>) {
         self.address = b
             .as_ref()
@@ -86,6 +447,227 @@ impl Configuration {
             .unwrap_or_else(|| String::from("127.0.0.1:8080"));
     }
 
+    pub fn update_log_redaction(&mut self, r: &Option<String>) {
+        match r {
+            Some(r) => match LogRedaction::from_str(r.as_str()) {
+                Some(lr) => self.log_redaction = lr,
+                None => {
+                    error!("Invalid log redaction - must be clear, hash or truncate[=N]");
+                    std::process::exit(1);
+                }
+            },
+            None => {}
+        }
+    }
+
+    pub fn update_access_log(&mut self, p: &Option<PathBuf>) {
+        self.access_log = p.clone();
+    }
+
+    pub fn update_search_limits(
+        &mut self,
+        max_candidates: &Option<usize>,
+        max_results: &Option<usize>,
+        max_time_ms: &Option<u64>,
+    ) {
+        if *max_candidates == Some(0) || *max_results == Some(0) || *max_time_ms == Some(0) {
+            error!("Invalid search limit - must be at least 1");
+            std::process::exit(1);
+        }
+        self.search_limits = SearchLimits {
+            max_candidates: *max_candidates,
+            max_results: *max_results,
+            max_time: max_time_ms.map(Duration::from_millis),
+        };
+    }
+
+    pub fn update_online_backup(&mut self, path: &Option<PathBuf>, interval: u64, versions: usize) {
+        match path {
+            Some(p) => {
+                let path = match p.to_str() {
+                    Some(p) => p.to_string(),
+                    None => {
+                        error!("Invalid online backup path");
+                        std::process::exit(1);
+                    }
+                };
+                if interval == 0 || versions == 0 {
+                    error!("Invalid online backup - interval and versions must be at least 1");
+                    std::process::exit(1);
+                }
+                self.online_backup = Some(OnlineBackup {
+                    path: path,
+                    interval: interval,
+                    versions: versions,
+                });
+            }
+            None => {}
+        }
+    }
+
+    pub fn update_replication(
+        &mut self,
+        partners: &[String],
+        primary: &Option<String>,
+        account: &Option<String>,
+        password_file: &Option<PathBuf>,
+        ca: &Option<PathBuf>,
+        interval: u64,
+    ) {
+        // We pull from our primary as from any other partner.
+        let mut partners = partners.to_vec();
+        if let Some(p) = primary {
+            if !partners.contains(p) {
+                partners.insert(0, p.clone());
+            }
+        }
+        if partners.is_empty() {
+            return;
+        }
+        let (account, password_file) = match (account, password_file) {
+            (Some(a), Some(p)) => (a.clone(), p),
+            _ => {
+                error!("Invalid replication - an account and password file are required");
+                std::process::exit(1);
+            }
+        };
+        let password = match fs::read_to_string(password_file) {
+            Ok(p) => p.trim_end().to_string(),
+            Err(e) => {
+                error!("Unable to read replication password file -> {:?}", e);
+                std::process::exit(1);
+            }
+        };
+        // The client only reads it once we start, and can't report it then.
+        let ca = match ca {
+            Some(p) => match p.to_str() {
+                Some(s) if p.is_file() => Some(s.to_string()),
+                _ => {
+                    error!("Invalid replication CA path");
+                    std::process::exit(1);
+                }
+            },
+            None => None,
+        };
+        if interval == 0 {
+            error!("Invalid replication interval - must be at least 1");
+            std::process::exit(1);
+        }
+        self.replication = Some(ReplConfig {
+            partners: partners,
+            account: account,
+            password: password,
+            ca: ca,
+            interval: interval,
+            primary: primary.clone(),
+        });
+    }
+
+    // Must follow setting the domain, which is the default host.
+    pub fn update_origin(&mut self, o: &Option<String>) {
+        let origin = match o {
+            Some(o) => o.trim_end_matches('/').to_string(),
+            None => format!("https://{}", self.domain),
+        };
+        let host = ["https://", "http://"]
+            .iter()
+            .find(|s| origin.starts_with(*s))
+            .map(|s| &origin[s.len()..]);
+        match host {
+            Some(h) if !h.is_empty() && !h.contains('/') => self.origin = origin,
+            _ => {
+                error!("Invalid origin - must be a scheme and host, IE https://idm.example.com");
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // Must follow update_tls and setting the domain, as LDAP is only served
+    // over TLS and the basedn defaults to the domain's components.
+    pub fn update_ldap(&mut self, address: &Option<String>, basedn: &Option<String>) {
+        let address = match address {
+            Some(a) => a.clone(),
+            None => return,
+        };
+        if self.tls_config.is_none() {
+            error!("Invalid ldap - LDAPS requires the TLS ca, cert and key");
+            std::process::exit(1);
+        }
+        let basedn = match basedn {
+            Some(b) => b
+                .split(',')
+                .map(|rdn| rdn.trim().to_lowercase())
+                .collect::<Vec<_>>()
+                .join(","),
+            None => self
+                .domain
+                .split('.')
+                .filter(|c| !c.is_empty())
+                .map(|c| format!("dc={}", c.to_lowercase()))
+                .collect::<Vec<_>>()
+                .join(","),
+        };
+        if basedn.is_empty()
+            || !basedn
+                .split(',')
+                .all(|rdn| rdn.len() > 2 && rdn.contains('='))
+        {
+            error!("Invalid ldap basedn - must be a dn, IE dc=example,dc=com");
+            std::process::exit(1);
+        }
+        self.ldap = Some(LdapConfig {
+            address: address,
+            basedn: basedn,
+        });
+    }
+
+    pub fn update_warmup(&mut self, slots: &Option<usize>) {
+        match slots {
+            Some(0) => {
+                error!("Invalid warm up - slots must be at least 1");
+                std::process::exit(1);
+            }
+            _ => self.warmup = slots.clone(),
+        }
+    }
+
+    pub fn update_filter_test_threshold(&mut self, t: &Option<usize>) {
+        // Zero is allowed, and means the indexes of an and are always all read.
+        if let Some(t) = t {
+            self.filter_test_threshold = *t;
+        }
+    }
+
+    pub fn update_slow_query_threshold(&mut self, ms: &Option<u64>) {
+        if let Some(ms) = ms {
+            self.slow_query_threshold = Duration::from_millis(*ms);
+        }
+    }
+
+    pub fn update_capture(&mut self, p: &Option<PathBuf>, secs: u64) {
+        if let Some(p) = p {
+            match p.to_str() {
+                Some(p) => self.capture = Some((p.to_string(), Duration::from_secs(secs))),
+                None => {
+                    error!("Invalid capture path");
+                    std::process::exit(1);
+                }
+            }
+        }
+    }
+
+    pub fn update_changelog_key(&mut self, p: &Option<PathBuf>) {
+        if let Some(p) = p {
+            match read_key_file(p) {
+                Some(k) => self.changelog_key = Some(k),
+                None => {
+                    error!("Invalid changelog key file - must contain 32 bytes as hex");
+                    std::process::exit(1);
+                }
+            }
+        }
+    }
+
     pub fn update_tls(
         &mut self,
         ca: &Option<PathBuf>,
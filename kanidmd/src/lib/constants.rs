@@ -6,8 +6,37 @@ pub static PURGE_TIMEOUT: u64 = 60;
 // For production, 1 hour.
 #[cfg(not(test))]
 pub static PURGE_TIMEOUT: u64 = 3600;
+// How long a deleted entry stays in the recycle bin, where it can still be
+// revived, before it's made a tombstone (7 days). Then how long the tombstone
+// is kept (14 days), which must be longer than any replication partner goes
+// without pulling from us, else it never learns of the delete.
+pub static RECYCLEBIN_MAX_AGE: i64 = 604_800;
+pub static TOMBSTONE_MAX_AGE: i64 = 1_209_600;
+// The most of each that one purge will reap, so it never holds the write lock
+// for long. Any left over wait for the next PURGE_TIMEOUT.
+pub static PURGE_BATCH: usize = 1000;
 // 5 minute auth session window.
 pub static AUTH_SESSION_TIMEOUT: u64 = 300;
+// How often we mark that we still own the database, and how long before a
+// claim from a process that stopped heartbeating can be taken over.
+pub static DB_HEARTBEAT_INTERVAL: u64 = 30;
+pub static DB_OWNER_STALE: u64 = 90;
+// How often the database statistics are refreshed. Each run is delayed by up
+// to the jitter, so that servers started together don't all analyze at once.
+pub static DB_ANALYZE_INTERVAL: u64 = 21600;
+pub static DB_ANALYZE_JITTER: u64 = 1800;
+// How often we look for saved searches whose report is due.
+pub static REPORT_CHECK_INTERVAL: u64 = 60;
+// How often entries still holding a renamed attribute are rewritten, and how
+// many at a time, so the migration never holds the write lock for long.
+pub static ATTR_MIGRATE_INTERVAL: u64 = 10;
+pub static ATTR_MIGRATE_BATCH: usize = 256;
+// How long after authenticating a session may make sensitive changes.
+pub static ELEVATION_TIMEOUT: u64 = 300;
+// How long an OAuth2 authorisation code may wait to be exchanged, and how
+// long the tokens it's exchanged for are valid.
+pub static OAUTH2_CODE_TIMEOUT: u64 = 60;
+pub static OAUTH2_TOKEN_EXPIRY: u64 = 900;
 
 pub static STR_UUID_ADMIN: &'static str = "00000000-0000-0000-0000-000000000000";
 pub static STR_UUID_ANONYMOUS: &'static str = "00000000-0000-0000-0000-ffffffffffff";
@@ -16,6 +45,12 @@ lazy_static! {
     pub static ref UUID_ADMIN: Uuid = Uuid::parse_str(STR_UUID_ADMIN).unwrap();
     pub static ref UUID_DOES_NOT_EXIST: Uuid = Uuid::parse_str(STR_UUID_DOES_NOT_EXIST).unwrap();
     pub static ref UUID_ANONYMOUS: Uuid = Uuid::parse_str(STR_UUID_ANONYMOUS).unwrap();
+    pub static ref UUID_SYSTEM_ADMINS: Uuid = Uuid::parse_str(_UUID_SYSTEM_ADMINS).unwrap();
+    pub static ref UUID_DOMAIN_BRANDING: Uuid = Uuid::parse_str(STR_UUID_DOMAIN_BRANDING).unwrap();
+    pub static ref UUID_ANONYMOUS_POLICY: Uuid =
+        Uuid::parse_str(STR_UUID_ANONYMOUS_POLICY).unwrap();
+    pub static ref UUID_RATELIMIT_POLICY: Uuid =
+        Uuid::parse_str(STR_UUID_RATELIMIT_POLICY).unwrap();
 }
 
 pub static JSON_ADMIN_V1: &'static str = r#"{
@@ -576,13 +611,13 @@ pub static JSON_IDM_ACP_GROUP_WRITE_PRIV_V1: &'static str = r#"{
             "{\"And\": [{\"Eq\": [\"class\",\"group\"]}, {\"AndNot\": {\"Or\": [{\"Eq\": [\"memberof\",\"00000000-0000-0000-0000-000000001000\"]}, {\"Eq\": [\"class\", \"tombstone\"]}, {\"Eq\": [\"class\", \"recycled\"]}]}}]}"
         ],
         "acp_search_attr": [
-            "class", "name", "uuid", "description", "member"
+            "class", "name", "uuid", "description", "member", "membership_approver", "radius_vlan"
         ],
         "acp_modify_removedattr": [
-            "name", "description", "member"
+            "name", "description", "member", "membership_approver", "radius_vlan"
         ],
         "acp_modify_presentattr": [
-            "name", "description", "member"
+            "name", "description", "member", "membership_approver", "radius_vlan"
         ]
     }
 }"#;
@@ -607,7 +642,9 @@ pub static JSON_IDM_ACP_ACCOUNT_READ_PRIV_V1: &'static str = r#"{
             "{\"And\": [{\"Eq\": [\"class\",\"account\"]}, {\"AndNot\": {\"Or\": [{\"Eq\": [\"memberof\",\"00000000-0000-0000-0000-000000001000\"]}, {\"Eq\": [\"class\", \"tombstone\"]}, {\"Eq\": [\"class\", \"recycled\"]}]}}]}"
         ],
         "acp_search_attr": [
-            "class", "name", "uuid", "displayname", "ssh_publickey", "primary_credential", "memberof", "mail"
+            "class", "name", "uuid", "displayname", "ssh_publickey", "primary_credential", "memberof", "mail",
+            "limit_search_max_results", "limit_search_max_candidates", "limit_search_max_time",
+            "ratelimit_search", "ratelimit_write"
         ]
     }
 }"#;
@@ -632,10 +669,14 @@ pub static JSON_IDM_ACP_ACCOUNT_WRITE_PRIV_V1: &'static str = r#"{
             "{\"And\": [{\"Eq\": [\"class\",\"account\"]}, {\"AndNot\": {\"Or\": [{\"Eq\": [\"memberof\",\"00000000-0000-0000-0000-000000001000\"]}, {\"Eq\": [\"class\", \"tombstone\"]}, {\"Eq\": [\"class\", \"recycled\"]}]}}]}"
         ],
         "acp_modify_removedattr": [
-            "name", "displayname", "ssh_publickey", "primary_credential", "mail"
+            "name", "displayname", "ssh_publickey", "primary_credential", "mail",
+            "limit_search_max_results", "limit_search_max_candidates", "limit_search_max_time",
+            "ratelimit_search", "ratelimit_write"
         ],
         "acp_modify_presentattr": [
-            "name", "displayname", "ssh_publickey", "primary_credential", "mail"
+            "name", "displayname", "ssh_publickey", "primary_credential", "mail",
+            "limit_search_max_results", "limit_search_max_candidates", "limit_search_max_time",
+            "ratelimit_search", "ratelimit_write"
         ]
     }
 }"#;
@@ -731,7 +772,7 @@ pub static JSON_IDM_ACP_RADIUS_SERVERS_V1: &'static str = r#"{
             "{\"And\": [{\"Pres\": \"class\"}, {\"AndNot\": {\"Or\": [{\"Eq\": [\"class\", \"tombstone\"]}, {\"Eq\": [\"class\", \"recycled\"]}]}}]}"
         ],
         "acp_search_attr": [
-            "name", "uuid", "radius_secret"
+            "name", "uuid", "radius_secret", "radius_vlan"
         ]
     }
 }"#;
@@ -756,7 +797,9 @@ pub static JSON_IDM_ACP_HP_ACCOUNT_READ_PRIV_V1: &'static str = r#"{
             "{\"And\": [{\"Eq\": [\"class\",\"account\"]}, {\"Eq\": [\"memberof\",\"00000000-0000-0000-0000-000000001000\"]}, {\"AndNot\": {\"Or\": [{\"Eq\": [\"class\", \"tombstone\"]}, {\"Eq\": [\"class\", \"recycled\"]}]}}]}"
         ],
         "acp_search_attr": [
-            "class", "name", "uuid", "displayname", "ssh_publickey", "primary_credential", "memberof"
+            "class", "name", "uuid", "displayname", "ssh_publickey", "primary_credential", "memberof",
+            "limit_search_max_results", "limit_search_max_candidates", "limit_search_max_time",
+            "ratelimit_search", "ratelimit_write"
         ]
     }
 }"#;
@@ -781,10 +824,14 @@ pub static JSON_IDM_ACP_HP_ACCOUNT_WRITE_PRIV_V1: &'static str = r#"{
             "{\"And\": [{\"Eq\": [\"class\",\"account\"]}, {\"Eq\": [\"memberof\",\"00000000-0000-0000-0000-000000001000\"]}, {\"AndNot\": {\"Or\": [{\"Eq\": [\"class\", \"tombstone\"]}, {\"Eq\": [\"class\", \"recycled\"]}]}}]}"
         ],
         "acp_modify_removedattr": [
-            "name", "displayname", "ssh_publickey", "primary_credential"
+            "name", "displayname", "ssh_publickey", "primary_credential", "access_journal",
+            "limit_search_max_results", "limit_search_max_candidates", "limit_search_max_time",
+            "ratelimit_search", "ratelimit_write"
         ],
         "acp_modify_presentattr": [
-            "name", "displayname", "ssh_publickey", "primary_credential"
+            "name", "displayname", "ssh_publickey", "primary_credential", "access_journal",
+            "limit_search_max_results", "limit_search_max_candidates", "limit_search_max_time",
+            "ratelimit_search", "ratelimit_write"
         ]
     }
 }"#;
@@ -1141,189 +1188,1596 @@ pub static JSON_IDM_ACP_HP_GROUP_MANAGE_PRIV_V1: &'static str = r#"{
     }
 }"#;
 
-// Anonymous should be the last opbject in the range here.
-pub static JSON_ANONYMOUS_V1: &'static str = r#"{
+// 25 - saved search manage
+pub static _UUID_IDM_ACP_SAVEDSEARCH_MANAGE_V1: &'static str =
+    "00000000-0000-0000-0000-ffffff000025";
+pub static JSON_IDM_ACP_SAVEDSEARCH_MANAGE_V1: &'static str = r#"{
     "attrs": {
-        "class": ["account", "object"],
-        "name": ["anonymous"],
-        "uuid": ["00000000-0000-0000-0000-ffffffffffff"],
-        "description": ["Anonymous access account."],
-        "displayname": ["Anonymous"]
+        "class": [
+            "object",
+            "access_control_profile",
+            "access_control_search",
+            "access_control_modify",
+            "access_control_create",
+            "access_control_delete"
+        ],
+        "name": ["idm_acp_savedsearch_manage"],
+        "uuid": ["00000000-0000-0000-0000-ffffff000025"],
+        "description": ["Builtin IDM Control for managing and running saved searches and their reports."],
+        "acp_enable": ["true"],
+        "acp_receiver": [
+            "{\"Eq\":[\"memberof\",\"00000000-0000-0000-0000-000000000001\"]}"
+        ],
+        "acp_targetscope": [
+            "{\"And\": [{\"Eq\": [\"class\",\"savedsearch\"]}, {\"AndNot\": {\"Or\": [{\"Eq\": [\"class\", \"tombstone\"]}, {\"Eq\": [\"class\", \"recycled\"]}]}}]}"
+        ],
+        "acp_search_attr": [
+            "name",
+            "class",
+            "description",
+            "savedsearch_filter",
+            "report_interval"
+        ],
+        "acp_modify_removedattr": [
+            "name",
+            "description",
+            "savedsearch_filter",
+            "report_interval"
+        ],
+        "acp_modify_presentattr": [
+            "name",
+            "description",
+            "savedsearch_filter",
+            "report_interval"
+        ],
+        "acp_create_attr": [
+            "class",
+            "name",
+            "description",
+            "savedsearch_filter",
+            "report_interval"
+        ],
+        "acp_create_class": [
+            "object", "savedsearch"
+        ]
     }
 }"#;
 
-// Core
-// Schema uuids start at 00000000-0000-0000-0000-ffff00000000
-pub static UUID_SCHEMA_ATTR_CLASS: &'static str = "00000000-0000-0000-0000-ffff00000000";
-pub static UUID_SCHEMA_ATTR_UUID: &'static str = "00000000-0000-0000-0000-ffff00000001";
-pub static UUID_SCHEMA_ATTR_NAME: &'static str = "00000000-0000-0000-0000-ffff00000002";
-pub static UUID_SCHEMA_ATTR_ATTRIBUTENAME: &'static str = "00000000-0000-0000-0000-ffff00000048";
-pub static UUID_SCHEMA_ATTR_CLASSNAME: &'static str = "00000000-0000-0000-0000-ffff00000049";
-pub static UUID_SCHEMA_ATTR_PRINCIPAL_NAME: &'static str = "00000000-0000-0000-0000-ffff00000003";
-pub static UUID_SCHEMA_ATTR_DESCRIPTION: &'static str = "00000000-0000-0000-0000-ffff00000004";
-pub static UUID_SCHEMA_ATTR_MULTIVALUE: &'static str = "00000000-0000-0000-0000-ffff00000005";
-pub static UUID_SCHEMA_ATTR_UNIQUE: &'static str = "00000000-0000-0000-0000-ffff00000047";
-pub static UUID_SCHEMA_ATTR_INDEX: &'static str = "00000000-0000-0000-0000-ffff00000006";
-pub static UUID_SCHEMA_ATTR_SYNTAX: &'static str = "00000000-0000-0000-0000-ffff00000007";
-pub static UUID_SCHEMA_ATTR_SYSTEMMAY: &'static str = "00000000-0000-0000-0000-ffff00000008";
-pub static UUID_SCHEMA_ATTR_MAY: &'static str = "00000000-0000-0000-0000-ffff00000009";
-pub static UUID_SCHEMA_ATTR_SYSTEMMUST: &'static str = "00000000-0000-0000-0000-ffff00000010";
-pub static UUID_SCHEMA_ATTR_MUST: &'static str = "00000000-0000-0000-0000-ffff00000011";
-pub static UUID_SCHEMA_ATTR_MEMBEROF: &'static str = "00000000-0000-0000-0000-ffff00000012";
-pub static UUID_SCHEMA_ATTR_MEMBER: &'static str = "00000000-0000-0000-0000-ffff00000013";
-pub static UUID_SCHEMA_ATTR_DIRECTMEMBEROF: &'static str = "00000000-0000-0000-0000-ffff00000014";
-pub static UUID_SCHEMA_ATTR_VERSION: &'static str = "00000000-0000-0000-0000-ffff00000015";
-pub static UUID_SCHEMA_ATTR_DOMAIN: &'static str = "00000000-0000-0000-0000-ffff00000016";
-pub static UUID_SCHEMA_ATTR_ACP_ENABLE: &'static str = "00000000-0000-0000-0000-ffff00000017";
-pub static UUID_SCHEMA_ATTR_ACP_RECEIVER: &'static str = "00000000-0000-0000-0000-ffff00000018";
-pub static UUID_SCHEMA_ATTR_ACP_TARGETSCOPE: &'static str = "00000000-0000-0000-0000-ffff00000019";
-pub static UUID_SCHEMA_ATTR_ACP_SEARCH_ATTR: &'static str = "00000000-0000-0000-0000-ffff00000020";
-pub static UUID_SCHEMA_ATTR_ACP_CREATE_CLASS: &'static str = "00000000-0000-0000-0000-ffff00000021";
-pub static UUID_SCHEMA_ATTR_ACP_CREATE_ATTR: &'static str = "00000000-0000-0000-0000-ffff00000022";
-pub static UUID_SCHEMA_ATTR_ACP_MODIFY_REMOVEDATTR: &'static str =
-    "00000000-0000-0000-0000-ffff00000023";
-pub static UUID_SCHEMA_ATTR_ACP_MODIFY_PRESENTATTR: &'static str =
-    "00000000-0000-0000-0000-ffff00000024";
-pub static UUID_SCHEMA_ATTR_ACP_MODIFY_CLASS: &'static str = "00000000-0000-0000-0000-ffff00000025";
-
-pub static UUID_SCHEMA_CLASS_ATTRIBUTETYPE: &'static str = "00000000-0000-0000-0000-ffff00000026";
-pub static UUID_SCHEMA_CLASS_CLASSTYPE: &'static str = "00000000-0000-0000-0000-ffff00000027";
-pub static UUID_SCHEMA_CLASS_OBJECT: &'static str = "00000000-0000-0000-0000-ffff00000028";
-pub static UUID_SCHEMA_CLASS_EXTENSIBLEOBJECT: &'static str =
-    "00000000-0000-0000-0000-ffff00000029";
-pub static UUID_SCHEMA_CLASS_MEMBEROF: &'static str = "00000000-0000-0000-0000-ffff00000030";
-
-pub static UUID_SCHEMA_CLASS_RECYCLED: &'static str = "00000000-0000-0000-0000-ffff00000031";
-pub static UUID_SCHEMA_CLASS_TOMBSTONE: &'static str = "00000000-0000-0000-0000-ffff00000032";
-pub static UUID_SCHEMA_CLASS_SYSTEM_INFO: &'static str = "00000000-0000-0000-0000-ffff00000033";
-pub static UUID_SCHEMA_CLASS_ACCESS_CONTROL_PROFILE: &'static str =
-    "00000000-0000-0000-0000-ffff00000034";
-pub static UUID_SCHEMA_CLASS_ACCESS_CONTROL_SEARCH: &'static str =
-    "00000000-0000-0000-0000-ffff00000035";
-pub static UUID_SCHEMA_CLASS_ACCESS_CONTROL_DELETE: &'static str =
-    "00000000-0000-0000-0000-ffff00000036";
-pub static UUID_SCHEMA_CLASS_ACCESS_CONTROL_MODIFY: &'static str =
-    "00000000-0000-0000-0000-ffff00000037";
-pub static UUID_SCHEMA_CLASS_ACCESS_CONTROL_CREATE: &'static str =
-    "00000000-0000-0000-0000-ffff00000038";
-pub static UUID_SCHEMA_CLASS_SYSTEM: &'static str = "00000000-0000-0000-0000-ffff00000039";
+// 26 - membership request read. Requests are made and decided through their
+// own operations, this lets admins review them, including those decided.
+pub static _UUID_IDM_ACP_MEMBERSHIPREQUEST_READ_V1: &'static str =
+    "00000000-0000-0000-0000-ffffff000026";
+pub static JSON_IDM_ACP_MEMBERSHIPREQUEST_READ_V1: &'static str = r#"{
+    "attrs": {
+        "class": [
+            "object",
+            "access_control_profile",
+            "access_control_search"
+        ],
+        "name": ["idm_acp_membershiprequest_read"],
+        "uuid": ["00000000-0000-0000-0000-ffffff000026"],
+        "description": ["Builtin IDM Control for reviewing membership requests."],
+        "acp_enable": ["true"],
+        "acp_receiver": [
+            "{\"Eq\":[\"memberof\",\"00000000-0000-0000-0000-000000000001\"]}"
+        ],
+        "acp_targetscope": [
+            "{\"And\": [{\"Eq\": [\"class\",\"membershiprequest\"]}, {\"AndNot\": {\"Or\": [{\"Eq\": [\"class\", \"tombstone\"]}, {\"Eq\": [\"class\", \"recycled\"]}]}}]}"
+        ],
+        "acp_search_attr": [
+            "class",
+            "uuid",
+            "description",
+            "request_group",
+            "request_member",
+            "request_action",
+            "request_state",
+            "request_requester",
+            "request_approver"
+        ]
+    }
+}"#;
 
-// system supplementary
-pub static UUID_SCHEMA_ATTR_DISPLAYNAME: &'static str = "00000000-0000-0000-0000-ffff00000040";
-pub static JSON_SCHEMA_ATTR_DISPLAYNAME: &'static str = r#"{
-    "valid": {
-      "uuid": "00000000-0000-0000-0000-ffff00000040"
-    },
-    "state": null,
+// 27 - branding read. Everyone, including anonymous, needs this to draw the
+// login page.
+pub static _UUID_IDM_ACP_BRANDING_READ_V1: &'static str = "00000000-0000-0000-0000-ffffff000027";
+pub static JSON_IDM_ACP_BRANDING_READ_V1: &'static str = r#"{
     "attrs": {
-      "class": [
-        "object",
-        "system",
-        "attributetype"
-      ],
-      "description": [
-        "The publicly visible display name of this person"
-      ],
-      "index": [
-        "EQUALITY"
-      ],
-      "unique": [
-        "false"
-      ],
-      "multivalue": [
-        "false"
-      ],
-      "attributename": [
-        "displayname"
-      ],
-      "syntax": [
-        "UTF8STRING"
-      ],
-      "uuid": [
-        "00000000-0000-0000-0000-ffff00000040"
-      ]
+        "class": [
+            "object",
+            "access_control_profile",
+            "access_control_search"
+        ],
+        "name": ["idm_acp_branding_read"],
+        "uuid": ["00000000-0000-0000-0000-ffffff000027"],
+        "description": ["Builtin IDM Control for reading the domain branding."],
+        "acp_enable": ["true"],
+        "acp_receiver": [
+            "{\"Pres\":\"class\"}"
+        ],
+        "acp_targetscope": [
+            "{\"And\": [{\"Eq\": [\"class\",\"domain_branding\"]}, {\"AndNot\": {\"Or\": [{\"Eq\": [\"class\", \"tombstone\"]}, {\"Eq\": [\"class\", \"recycled\"]}]}}]}"
+        ],
+        "acp_search_attr": [
+            "class",
+            "uuid",
+            "displayname",
+            "branding_logo",
+            "login_banner"
+        ]
     }
 }"#;
-pub static UUID_SCHEMA_ATTR_MAIL: &'static str = "00000000-0000-0000-0000-ffff00000041";
-pub static JSON_SCHEMA_ATTR_MAIL: &'static str = r#"
-  {
-    "valid": {
-      "uuid": "00000000-0000-0000-0000-ffff00000041"
-    },
-    "state": null,
+
+// 28 - branding manage. There is only the one branding entry, so this can't
+// create or delete them.
+pub static _UUID_IDM_ACP_BRANDING_MANAGE_V1: &'static str = "00000000-0000-0000-0000-ffffff000028";
+pub static JSON_IDM_ACP_BRANDING_MANAGE_V1: &'static str = r#"{
     "attrs": {
-      "class": [
-        "object",
-        "system",
-        "attributetype"
-      ],
-      "description": [
-        "mail addresses of the object"
-      ],
-      "index": [
-        "EQUALITY"
-      ],
-      "unique": [
-        "true"
-      ],
-      "multivalue": [
-        "true"
-      ],
-      "attributename": [
-        "mail"
-      ],
-      "syntax": [
-        "UTF8STRING"
-      ],
-      "uuid": [
-        "00000000-0000-0000-0000-ffff00000041"
-      ]
+        "class": [
+            "object",
+            "access_control_profile",
+            "access_control_search",
+            "access_control_modify"
+        ],
+        "name": ["idm_acp_branding_manage"],
+        "uuid": ["00000000-0000-0000-0000-ffffff000028"],
+        "description": ["Builtin IDM Control for changing the domain branding."],
+        "acp_enable": ["true"],
+        "acp_receiver": [
+            "{\"Eq\":[\"memberof\",\"00000000-0000-0000-0000-000000000001\"]}"
+        ],
+        "acp_targetscope": [
+            "{\"And\": [{\"Eq\": [\"class\",\"domain_branding\"]}, {\"AndNot\": {\"Or\": [{\"Eq\": [\"class\", \"tombstone\"]}, {\"Eq\": [\"class\", \"recycled\"]}]}}]}"
+        ],
+        "acp_search_attr": [
+            "class",
+            "uuid",
+            "description",
+            "displayname",
+            "branding_logo",
+            "login_banner"
+        ],
+        "acp_modify_removedattr": [
+            "description",
+            "displayname",
+            "branding_logo",
+            "login_banner"
+        ],
+        "acp_modify_presentattr": [
+            "description",
+            "displayname",
+            "branding_logo",
+            "login_banner"
+        ]
     }
-  }
-"#;
-pub static UUID_SCHEMA_ATTR_SSH_PUBLICKEY: &'static str = "00000000-0000-0000-0000-ffff00000042";
-pub static JSON_SCHEMA_ATTR_SSH_PUBLICKEY: &'static str = r#"
-  {
-    "valid": {
-      "uuid": "00000000-0000-0000-0000-ffff00000042"
-    },
-    "state": null,
+}"#;
+
+// 29 - notification template manage
+pub static _UUID_IDM_ACP_NOTIFICATION_TEMPLATE_MANAGE_V1: &'static str =
+    "00000000-0000-0000-0000-ffffff000029";
+pub static JSON_IDM_ACP_NOTIFICATION_TEMPLATE_MANAGE_V1: &'static str = r#"{
     "attrs": {
-      "class": [
-        "object",
-        "system",
-        "attributetype"
-      ],
-      "description": [
-        "SSH public keys of the object"
-      ],
-      "index": [],
-      "unique": [
-        "false"
-      ],
-      "multivalue": [
-        "true"
-      ],
-      "attributename": [
-        "ssh_publickey"
-      ],
-      "syntax": [
-        "SSHKEY"
-      ],
+        "class": [
+            "object",
+            "access_control_profile",
+            "access_control_search",
+            "access_control_modify",
+            "access_control_create",
+            "access_control_delete"
+        ],
+        "name": ["idm_acp_notification_template_manage"],
+        "uuid": ["00000000-0000-0000-0000-ffffff000029"],
+        "description": ["Builtin IDM Control for managing notification templates."],
+        "acp_enable": ["true"],
+        "acp_receiver": [
+            "{\"Eq\":[\"memberof\",\"00000000-0000-0000-0000-000000000001\"]}"
+        ],
+        "acp_targetscope": [
+            "{\"And\": [{\"Eq\": [\"class\",\"notification_template\"]}, {\"AndNot\": {\"Or\": [{\"Eq\": [\"class\", \"tombstone\"]}, {\"Eq\": [\"class\", \"recycled\"]}]}}]}"
+        ],
+        "acp_search_attr": [
+            "name",
+            "class",
+            "uuid",
+            "description",
+            "template_subject",
+            "template_body"
+        ],
+        "acp_modify_removedattr": [
+            "name",
+            "description",
+            "template_subject",
+            "template_body"
+        ],
+        "acp_modify_presentattr": [
+            "name",
+            "description",
+            "template_subject",
+            "template_body"
+        ],
+        "acp_create_attr": [
+            "class",
+            "name",
+            "description",
+            "template_subject",
+            "template_body"
+        ],
+        "acp_create_class": [
+            "object", "notification_template"
+        ]
+    }
+}"#;
+
+// The branding of the domain. This is only created if it's missing, so that
+// changes made at runtime are kept over restarts.
+pub static STR_UUID_DOMAIN_BRANDING: &'static str = "00000000-0000-0000-0000-000000000021";
+pub static JSON_DOMAIN_BRANDING_V1: &'static str = r#"{
+    "attrs": {
+        "class": ["domain_branding", "object"],
+        "uuid": ["00000000-0000-0000-0000-000000000021"],
+        "description": ["The branding of this domain."],
+        "displayname": ["Kanidm"]
+    }
+}"#;
+
+// How often accounts may search and write, unless the account sets its own.
+// As with the branding this is only created if it's missing.
+pub static STR_UUID_RATELIMIT_POLICY: &'static str = "00000000-0000-0000-0000-000000000023";
+pub static JSON_RATELIMIT_POLICY_V1: &'static str = r#"{
+    "attrs": {
+        "class": ["ratelimit_policy", "object"],
+        "uuid": ["00000000-0000-0000-0000-000000000023"],
+        "description": ["How often accounts may search and write."]
+    }
+}"#;
+
+// What anonymous may search, whatever the acps allow. As with the branding
+// this is only created if it's missing. How many results anonymous may have
+// is the search limit of the anonymous account itself.
+pub static STR_UUID_ANONYMOUS_POLICY: &'static str = "00000000-0000-0000-0000-000000000022";
+pub static JSON_ANONYMOUS_POLICY_V1: &'static str = r#"{
+    "attrs": {
+        "class": ["anonymous_policy", "object"],
+        "uuid": ["00000000-0000-0000-0000-000000000022"],
+        "description": ["What anonymous may search."],
+        "anonymous_allow_search": ["true"]
+    }
+}"#;
+
+// A report of the groups with no members, run daily.
+pub static _UUID_IDM_REPORT_UNUSED_GROUPS: &'static str = "00000000-0000-0000-0000-000000000020";
+pub static JSON_IDM_REPORT_UNUSED_GROUPS_V1: &'static str = r#"{
+    "attrs": {
+        "class": ["savedsearch", "object"],
+        "name": ["idm_report_unused_groups"],
+        "uuid": ["00000000-0000-0000-0000-000000000020"],
+        "description": ["Builtin report of groups with no members."],
+        "savedsearch_filter": [
+            "{\"And\": [{\"Eq\": [\"class\",\"group\"]}, {\"AndNot\": {\"Pres\": \"member\"}}]}"
+        ],
+        "report_interval": ["86400"]
+    }
+}"#;
+
+// 30 - oauth2 relying party manage
+pub static _UUID_IDM_ACP_OAUTH2_MANAGE_V1: &'static str = "00000000-0000-0000-0000-ffffff000030";
+pub static JSON_IDM_ACP_OAUTH2_MANAGE_V1: &'static str = r#"{
+    "attrs": {
+        "class": [
+            "object",
+            "access_control_profile",
+            "access_control_search",
+            "access_control_modify",
+            "access_control_create",
+            "access_control_delete"
+        ],
+        "name": ["idm_acp_oauth2_manage"],
+        "uuid": ["00000000-0000-0000-0000-ffffff000030"],
+        "description": ["Builtin IDM Control for managing oauth2 relying parties."],
+        "acp_enable": ["true"],
+        "acp_receiver": [
+            "{\"Eq\":[\"memberof\",\"00000000-0000-0000-0000-000000000001\"]}"
+        ],
+        "acp_targetscope": [
+            "{\"And\": [{\"Eq\": [\"class\",\"oauth2_resource_server\"]}, {\"AndNot\": {\"Or\": [{\"Eq\": [\"class\", \"tombstone\"]}, {\"Eq\": [\"class\", \"recycled\"]}]}}]}"
+        ],
+        "acp_search_attr": [
+            "name",
+            "class",
+            "uuid",
+            "description",
+            "displayname",
+            "oauth2_rs_origin",
+            "oauth2_rs_scope_map",
+            "oauth2_rs_basic_secret"
+        ],
+        "acp_modify_removedattr": [
+            "name",
+            "description",
+            "displayname",
+            "oauth2_rs_origin",
+            "oauth2_rs_scope_map",
+            "oauth2_rs_basic_secret",
+            "oauth2_rs_token_key"
+        ],
+        "acp_modify_presentattr": [
+            "name",
+            "description",
+            "displayname",
+            "oauth2_rs_origin",
+            "oauth2_rs_scope_map"
+        ],
+        "acp_create_attr": [
+            "class",
+            "name",
+            "description",
+            "displayname",
+            "oauth2_rs_origin",
+            "oauth2_rs_scope_map"
+        ],
+        "acp_create_class": [
+            "object", "oauth2_resource_server"
+        ]
+    }
+}"#;
+
+// 31 - unix read. Everyone, including anonymous, needs to be able to resolve
+// posix accounts and groups for nss on a client machine.
+pub static _UUID_IDM_ACP_UNIX_READ_V1: &'static str = "00000000-0000-0000-0000-ffffff000031";
+pub static JSON_IDM_ACP_UNIX_READ_V1: &'static str = r#"{
+    "attrs": {
+        "class": [
+            "object",
+            "access_control_profile",
+            "access_control_search"
+        ],
+        "name": ["idm_acp_unix_read"],
+        "uuid": ["00000000-0000-0000-0000-ffffff000031"],
+        "description": ["Builtin IDM Control for reading posix account and group attributes."],
+        "acp_enable": ["true"],
+        "acp_receiver": [
+            "{\"Pres\":\"class\"}"
+        ],
+        "acp_targetscope": [
+            "{\"And\": [{\"Or\": [{\"Eq\": [\"class\",\"posixaccount\"]}, {\"Eq\": [\"class\",\"posixgroup\"]}]}, {\"AndNot\": {\"Or\": [{\"Eq\": [\"class\", \"tombstone\"]}, {\"Eq\": [\"class\", \"recycled\"]}]}}]}"
+        ],
+        "acp_search_attr": [
+            "class",
+            "name",
+            "uuid",
+            "displayname",
+            "gidnumber",
+            "loginshell",
+            "homedirectory",
+            "memberof",
+            "member"
+        ]
+    }
+}"#;
+
+// 32 - unix manage. Admins can extend accounts and groups with posix
+// attributes.
+pub static _UUID_IDM_ACP_UNIX_MANAGE_V1: &'static str = "00000000-0000-0000-0000-ffffff000032";
+pub static JSON_IDM_ACP_UNIX_MANAGE_V1: &'static str = r#"{
+    "attrs": {
+        "class": [
+            "object",
+            "access_control_profile",
+            "access_control_search",
+            "access_control_modify"
+        ],
+        "name": ["idm_acp_unix_manage"],
+        "uuid": ["00000000-0000-0000-0000-ffffff000032"],
+        "description": ["Builtin IDM Control for extending accounts and groups with posix attributes."],
+        "acp_enable": ["true"],
+        "acp_receiver": [
+            "{\"Eq\":[\"memberof\",\"00000000-0000-0000-0000-000000000001\"]}"
+        ],
+        "acp_targetscope": [
+            "{\"And\": [{\"Or\": [{\"Eq\": [\"class\",\"account\"]}, {\"Eq\": [\"class\",\"group\"]}]}, {\"AndNot\": {\"Or\": [{\"Eq\": [\"class\", \"tombstone\"]}, {\"Eq\": [\"class\", \"recycled\"]}, {\"Eq\": [\"uuid\", \"00000000-0000-0000-0000-ffffffffffff\"]}]}}]}"
+        ],
+        "acp_search_attr": [
+            "class",
+            "name",
+            "uuid",
+            "gidnumber",
+            "loginshell",
+            "homedirectory"
+        ],
+        "acp_modify_removedattr": [
+            "gidnumber",
+            "loginshell",
+            "homedirectory"
+        ],
+        "acp_modify_presentattr": [
+            "class",
+            "gidnumber",
+            "loginshell",
+            "homedirectory"
+        ],
+        "acp_modify_class": [
+            "posixaccount",
+            "posixgroup"
+        ]
+    }
+}"#;
+
+// 33 - anonymous policy manage. There is only the one policy entry, so this
+// can't create or delete them.
+pub static _UUID_IDM_ACP_ANONYMOUS_POLICY_MANAGE_V1: &'static str =
+    "00000000-0000-0000-0000-ffffff000033";
+pub static JSON_IDM_ACP_ANONYMOUS_POLICY_MANAGE_V1: &'static str = r#"{
+    "attrs": {
+        "class": [
+            "object",
+            "access_control_profile",
+            "access_control_search",
+            "access_control_modify"
+        ],
+        "name": ["idm_acp_anonymous_policy_manage"],
+        "uuid": ["00000000-0000-0000-0000-ffffff000033"],
+        "description": ["Builtin IDM Control for changing what anonymous may search."],
+        "acp_enable": ["true"],
+        "acp_receiver": [
+            "{\"Eq\":[\"memberof\",\"00000000-0000-0000-0000-000000000001\"]}"
+        ],
+        "acp_targetscope": [
+            "{\"And\": [{\"Eq\": [\"class\",\"anonymous_policy\"]}, {\"AndNot\": {\"Or\": [{\"Eq\": [\"class\", \"tombstone\"]}, {\"Eq\": [\"class\", \"recycled\"]}]}}]}"
+        ],
+        "acp_search_attr": [
+            "class",
+            "uuid",
+            "description",
+            "anonymous_allow_search",
+            "anonymous_attr_allow"
+        ],
+        "acp_modify_removedattr": [
+            "description",
+            "anonymous_allow_search",
+            "anonymous_attr_allow"
+        ],
+        "acp_modify_presentattr": [
+            "description",
+            "anonymous_allow_search",
+            "anonymous_attr_allow"
+        ]
+    }
+}"#;
+
+// 34 - rate limit policy manage. Again there is only the one policy entry.
+pub static _UUID_IDM_ACP_RATELIMIT_POLICY_MANAGE_V1: &'static str =
+    "00000000-0000-0000-0000-ffffff000034";
+pub static JSON_IDM_ACP_RATELIMIT_POLICY_MANAGE_V1: &'static str = r#"{
+    "attrs": {
+        "class": [
+            "object",
+            "access_control_profile",
+            "access_control_search",
+            "access_control_modify"
+        ],
+        "name": ["idm_acp_ratelimit_policy_manage"],
+        "uuid": ["00000000-0000-0000-0000-ffffff000034"],
+        "description": ["Builtin IDM Control for changing how often accounts may search and write."],
+        "acp_enable": ["true"],
+        "acp_receiver": [
+            "{\"Eq\":[\"memberof\",\"00000000-0000-0000-0000-000000000001\"]}"
+        ],
+        "acp_targetscope": [
+            "{\"And\": [{\"Eq\": [\"class\",\"ratelimit_policy\"]}, {\"AndNot\": {\"Or\": [{\"Eq\": [\"class\", \"tombstone\"]}, {\"Eq\": [\"class\", \"recycled\"]}]}}]}"
+        ],
+        "acp_search_attr": [
+            "class",
+            "uuid",
+            "description",
+            "ratelimit_search",
+            "ratelimit_write"
+        ],
+        "acp_modify_removedattr": [
+            "description",
+            "ratelimit_search",
+            "ratelimit_write"
+        ],
+        "acp_modify_presentattr": [
+            "description",
+            "ratelimit_search",
+            "ratelimit_write"
+        ]
+    }
+}"#;
+
+// Anonymous should be the last opbject in the range here.
+pub static JSON_ANONYMOUS_V1: &'static str = r#"{
+    "attrs": {
+        "class": ["account", "object"],
+        "name": ["anonymous"],
+        "uuid": ["00000000-0000-0000-0000-ffffffffffff"],
+        "description": ["Anonymous access account."],
+        "displayname": ["Anonymous"]
+    }
+}"#;
+
+// Core
+// Schema uuids start at 00000000-0000-0000-0000-ffff00000000
+pub static UUID_SCHEMA_ATTR_CLASS: &'static str = "00000000-0000-0000-0000-ffff00000000";
+pub static UUID_SCHEMA_ATTR_UUID: &'static str = "00000000-0000-0000-0000-ffff00000001";
+pub static UUID_SCHEMA_ATTR_NAME: &'static str = "00000000-0000-0000-0000-ffff00000002";
+pub static UUID_SCHEMA_ATTR_ATTRIBUTENAME: &'static str = "00000000-0000-0000-0000-ffff00000048";
+pub static UUID_SCHEMA_ATTR_CLASSNAME: &'static str = "00000000-0000-0000-0000-ffff00000049";
+pub static UUID_SCHEMA_ATTR_PRINCIPAL_NAME: &'static str = "00000000-0000-0000-0000-ffff00000003";
+pub static UUID_SCHEMA_ATTR_DESCRIPTION: &'static str = "00000000-0000-0000-0000-ffff00000004";
+pub static UUID_SCHEMA_ATTR_MULTIVALUE: &'static str = "00000000-0000-0000-0000-ffff00000005";
+pub static UUID_SCHEMA_ATTR_UNIQUE: &'static str = "00000000-0000-0000-0000-ffff00000047";
+pub static UUID_SCHEMA_ATTR_INDEX: &'static str = "00000000-0000-0000-0000-ffff00000006";
+pub static UUID_SCHEMA_ATTR_SYNTAX: &'static str = "00000000-0000-0000-0000-ffff00000007";
+pub static UUID_SCHEMA_ATTR_SYSTEMMAY: &'static str = "00000000-0000-0000-0000-ffff00000008";
+pub static UUID_SCHEMA_ATTR_MAY: &'static str = "00000000-0000-0000-0000-ffff00000009";
+pub static UUID_SCHEMA_ATTR_SYSTEMMUST: &'static str = "00000000-0000-0000-0000-ffff00000010";
+pub static UUID_SCHEMA_ATTR_MUST: &'static str = "00000000-0000-0000-0000-ffff00000011";
+pub static UUID_SCHEMA_ATTR_MEMBEROF: &'static str = "00000000-0000-0000-0000-ffff00000012";
+pub static UUID_SCHEMA_ATTR_MEMBER: &'static str = "00000000-0000-0000-0000-ffff00000013";
+pub static UUID_SCHEMA_ATTR_DIRECTMEMBEROF: &'static str = "00000000-0000-0000-0000-ffff00000014";
+pub static UUID_SCHEMA_ATTR_VERSION: &'static str = "00000000-0000-0000-0000-ffff00000015";
+pub static UUID_SCHEMA_ATTR_DOMAIN: &'static str = "00000000-0000-0000-0000-ffff00000016";
+pub static UUID_SCHEMA_ATTR_ACP_ENABLE: &'static str = "00000000-0000-0000-0000-ffff00000017";
+pub static UUID_SCHEMA_ATTR_ACP_RECEIVER: &'static str = "00000000-0000-0000-0000-ffff00000018";
+pub static UUID_SCHEMA_ATTR_ACP_TARGETSCOPE: &'static str = "00000000-0000-0000-0000-ffff00000019";
+pub static UUID_SCHEMA_ATTR_ACP_SEARCH_ATTR: &'static str = "00000000-0000-0000-0000-ffff00000020";
+pub static UUID_SCHEMA_ATTR_ACP_CREATE_CLASS: &'static str = "00000000-0000-0000-0000-ffff00000021";
+pub static UUID_SCHEMA_ATTR_ACP_CREATE_ATTR: &'static str = "00000000-0000-0000-0000-ffff00000022";
+pub static UUID_SCHEMA_ATTR_ACP_MODIFY_REMOVEDATTR: &'static str =
+    "00000000-0000-0000-0000-ffff00000023";
+pub static UUID_SCHEMA_ATTR_ACP_MODIFY_PRESENTATTR: &'static str =
+    "00000000-0000-0000-0000-ffff00000024";
+pub static UUID_SCHEMA_ATTR_ACP_MODIFY_CLASS: &'static str = "00000000-0000-0000-0000-ffff00000025";
+pub static UUID_SCHEMA_ATTR_OPERATIONAL: &'static str = "00000000-0000-0000-0000-ffff00000052";
+pub static UUID_SCHEMA_ATTR_CREATETIMESTAMP: &'static str = "00000000-0000-0000-0000-ffff00000053";
+pub static UUID_SCHEMA_ATTR_MODIFYTIMESTAMP: &'static str = "00000000-0000-0000-0000-ffff00000054";
+pub static UUID_SCHEMA_ATTR_CREATORSNAME: &'static str = "00000000-0000-0000-0000-ffff00000055";
+pub static UUID_SCHEMA_ATTR_ACCESS_JOURNAL: &'static str = "00000000-0000-0000-0000-ffff00000056";
+pub static UUID_SCHEMA_ATTR_ATTRIBUTEALIAS: &'static str = "00000000-0000-0000-0000-ffff00000068";
+
+pub static UUID_SCHEMA_CLASS_ATTRIBUTETYPE: &'static str = "00000000-0000-0000-0000-ffff00000026";
+pub static UUID_SCHEMA_CLASS_CLASSTYPE: &'static str = "00000000-0000-0000-0000-ffff00000027";
+pub static UUID_SCHEMA_CLASS_OBJECT: &'static str = "00000000-0000-0000-0000-ffff00000028";
+pub static UUID_SCHEMA_CLASS_EXTENSIBLEOBJECT: &'static str =
+    "00000000-0000-0000-0000-ffff00000029";
+pub static UUID_SCHEMA_CLASS_MEMBEROF: &'static str = "00000000-0000-0000-0000-ffff00000030";
+
+pub static UUID_SCHEMA_CLASS_RECYCLED: &'static str = "00000000-0000-0000-0000-ffff00000031";
+pub static UUID_SCHEMA_CLASS_TOMBSTONE: &'static str = "00000000-0000-0000-0000-ffff00000032";
+pub static UUID_SCHEMA_CLASS_SYSTEM_INFO: &'static str = "00000000-0000-0000-0000-ffff00000033";
+pub static UUID_SCHEMA_CLASS_ACCESS_CONTROL_PROFILE: &'static str =
+    "00000000-0000-0000-0000-ffff00000034";
+pub static UUID_SCHEMA_CLASS_ACCESS_CONTROL_SEARCH: &'static str =
+    "00000000-0000-0000-0000-ffff00000035";
+pub static UUID_SCHEMA_CLASS_ACCESS_CONTROL_DELETE: &'static str =
+    "00000000-0000-0000-0000-ffff00000036";
+pub static UUID_SCHEMA_CLASS_ACCESS_CONTROL_MODIFY: &'static str =
+    "00000000-0000-0000-0000-ffff00000037";
+pub static UUID_SCHEMA_CLASS_ACCESS_CONTROL_CREATE: &'static str =
+    "00000000-0000-0000-0000-ffff00000038";
+pub static UUID_SCHEMA_CLASS_SYSTEM: &'static str = "00000000-0000-0000-0000-ffff00000039";
+
+// system supplementary
+pub static UUID_SCHEMA_ATTR_DISPLAYNAME: &'static str = "00000000-0000-0000-0000-ffff00000040";
+pub static JSON_SCHEMA_ATTR_DISPLAYNAME: &'static str = r#"{
+    "valid": {
+      "uuid": "00000000-0000-0000-0000-ffff00000040"
+    },
+    "state": null,
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "The publicly visible display name of this person"
+      ],
+      "index": [
+        "EQUALITY"
+      ],
+      "unique": [
+        "false"
+      ],
+      "multivalue": [
+        "false"
+      ],
+      "attributename": [
+        "displayname"
+      ],
+      "syntax": [
+        "UTF8STRING"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000040"
+      ]
+    }
+}"#;
+pub static UUID_SCHEMA_ATTR_MAIL: &'static str = "00000000-0000-0000-0000-ffff00000041";
+pub static JSON_SCHEMA_ATTR_MAIL: &'static str = r#"
+  {
+    "valid": {
+      "uuid": "00000000-0000-0000-0000-ffff00000041"
+    },
+    "state": null,
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "mail addresses of the object"
+      ],
+      "index": [
+        "EQUALITY"
+      ],
+      "unique": [
+        "true"
+      ],
+      "multivalue": [
+        "true"
+      ],
+      "attributename": [
+        "mail"
+      ],
+      "syntax": [
+        "UTF8STRING"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000041"
+      ]
+    }
+  }
+"#;
+pub static UUID_SCHEMA_ATTR_SSH_PUBLICKEY: &'static str = "00000000-0000-0000-0000-ffff00000042";
+pub static JSON_SCHEMA_ATTR_SSH_PUBLICKEY: &'static str = r#"
+  {
+    "valid": {
+      "uuid": "00000000-0000-0000-0000-ffff00000042"
+    },
+    "state": null,
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "SSH public keys of the object"
+      ],
+      "index": [],
+      "unique": [
+        "false"
+      ],
+      "multivalue": [
+        "true"
+      ],
+      "attributename": [
+        "ssh_publickey"
+      ],
+      "syntax": [
+        "SSHKEY"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000042"
+      ]
+    }
+  }
+"#;
+pub static UUID_SCHEMA_ATTR_PRIMARY_CREDENTIAL: &'static str =
+    "00000000-0000-0000-0000-ffff00000043";
+pub static JSON_SCHEMA_ATTR_PRIMARY_CREDENTIAL: &'static str = r#"
+  {
+    "valid": {
+      "uuid": "00000000-0000-0000-0000-ffff00000043"
+    },
+    "state": null,
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "Primary credential material of the account for authentication interactively."
+      ],
+      "index": [],
+      "unique": [
+        "false"
+      ],
+      "multivalue": [
+        "false"
+      ],
+      "attributename": [
+        "primary_credential"
+      ],
+      "syntax": [
+        "CREDENTIAL"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000043"
+      ]
+    }
+  }
+"#;
+pub static UUID_SCHEMA_ATTR_LEGALNAME: &'static str = "00000000-0000-0000-0000-ffff00000050";
+pub static JSON_SCHEMA_ATTR_LEGALNAME: &'static str = r#"{
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "The private and sensitive legal name of this person"
+      ],
+      "index": [
+        "EQUALITY"
+      ],
+      "unique": [
+        "false"
+      ],
+      "multivalue": [
+        "false"
+      ],
+      "attributename": [
+        "legalname"
+      ],
+      "syntax": [
+        "UTF8STRING"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000050"
+      ]
+    }
+}"#;
+pub static UUID_SCHEMA_ATTR_RADIUS_SECRET: &'static str = "00000000-0000-0000-0000-ffff00000051";
+pub static JSON_SCHEMA_ATTR_RADIUS_SECRET: &'static str = r#"{
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "The accounts generated radius secret for device network authentication"
+      ],
+      "index": [],
+      "unique": [
+        "false"
+      ],
+      "multivalue": [
+        "false"
+      ],
+      "attributename": [
+        "radius_secret"
+      ],
+      "syntax": [
+        "RADIUS_UTF8STRING"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000051"
+      ]
+    }
+}"#;
+
+pub static UUID_SCHEMA_CLASS_PERSON: &'static str = "00000000-0000-0000-0000-ffff00000044";
+pub static JSON_SCHEMA_CLASS_PERSON: &'static str = r#"
+  {
+    "valid": {
+      "uuid": "00000000-0000-0000-0000-ffff00000044"
+    },
+    "state": null,
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "classtype"
+      ],
+      "description": [
+        "Object representation of a person"
+      ],
+      "classname": [
+        "person"
+      ],
+      "systemmay": [
+        "mail",
+        "legalname"
+      ],
+      "systemmust": [
+        "displayname",
+        "name"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000044"
+      ]
+    }
+  }
+"#;
+
+pub static UUID_SCHEMA_CLASS_GROUP: &'static str = "00000000-0000-0000-0000-ffff00000045";
+pub static JSON_SCHEMA_CLASS_GROUP: &'static str = r#"
+  {
+    "valid": {
+      "uuid": "00000000-0000-0000-0000-ffff00000045"
+    },
+    "state": null,
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "classtype"
+      ],
+      "description": [
+        "Object representation of a group"
+      ],
+      "classname": [
+        "group"
+      ],
+      "systemmay": [
+        "member",
+        "membership_approver",
+        "radius_vlan"
+      ],
+      "systemmust": [
+        "name"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000045"
+      ]
+    }
+  }
+"#;
+pub static UUID_SCHEMA_CLASS_ACCOUNT: &'static str = "00000000-0000-0000-0000-ffff00000046";
+pub static JSON_SCHEMA_CLASS_ACCOUNT: &'static str = r#"
+  {
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "classtype"
+      ],
+      "description": [
+        "Object representation of a account"
+      ],
+      "classname": [
+        "account"
+      ],
+      "systemmay": [
+        "primary_credential",
+        "ssh_publickey",
+        "radius_secret",
+        "limit_search_max_results",
+        "limit_search_max_candidates",
+        "limit_search_max_time",
+        "ratelimit_search",
+        "ratelimit_write"
+      ],
+      "systemmust": [
+        "displayname",
+        "name"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000046"
+      ]
+    }
+  }
+"#;
+
+pub static UUID_SCHEMA_ATTR_SAVEDSEARCH_FILTER: &'static str =
+    "00000000-0000-0000-0000-ffff00000057";
+pub static JSON_SCHEMA_ATTR_SAVEDSEARCH_FILTER: &'static str = r#"{
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "The filter of a saved search. Values may hold ${param} placeholders, given when it's run"
+      ],
+      "index": [],
+      "unique": [
+        "false"
+      ],
+      "multivalue": [
+        "false"
+      ],
+      "attributename": [
+        "savedsearch_filter"
+      ],
+      "syntax": [
+        "JSON_FILTER"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000057"
+      ]
+    }
+}"#;
+
+pub static UUID_SCHEMA_CLASS_SAVEDSEARCH: &'static str = "00000000-0000-0000-0000-ffff00000058";
+pub static JSON_SCHEMA_CLASS_SAVEDSEARCH: &'static str = r#"
+  {
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "classtype"
+      ],
+      "description": [
+        "A named filter that can be run by name"
+      ],
+      "classname": [
+        "savedsearch"
+      ],
+      "systemmay": [
+        "description",
+        "report_interval"
+      ],
+      "systemmust": [
+        "name",
+        "savedsearch_filter"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000058"
+      ]
+    }
+  }
+"#;
+
+pub static UUID_SCHEMA_ATTR_REPORT_INTERVAL: &'static str = "00000000-0000-0000-0000-ffff00000059";
+pub static JSON_SCHEMA_ATTR_REPORT_INTERVAL: &'static str = r#"{
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "How often, in seconds, a saved search is run as a report and its count kept"
+      ],
+      "index": [],
+      "unique": [
+        "false"
+      ],
+      "multivalue": [
+        "false"
+      ],
+      "attributename": [
+        "report_interval"
+      ],
+      "syntax": [
+        "UTF8STRING"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000059"
+      ]
+    }
+}"#;
+
+pub static UUID_SCHEMA_ATTR_MEMBERSHIP_APPROVER: &'static str =
+    "00000000-0000-0000-0000-ffff00000060";
+pub static JSON_SCHEMA_ATTR_MEMBERSHIP_APPROVER: &'static str = r#"{
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "Who may approve requests to change the members of a group, as accounts or groups of them"
+      ],
+      "index": [],
+      "unique": [
+        "false"
+      ],
+      "multivalue": [
+        "true"
+      ],
+      "attributename": [
+        "membership_approver"
+      ],
+      "syntax": [
+        "REFERENCE_UUID"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000060"
+      ]
+    }
+}"#;
+
+pub static UUID_SCHEMA_ATTR_REQUEST_GROUP: &'static str = "00000000-0000-0000-0000-ffff00000061";
+pub static JSON_SCHEMA_ATTR_REQUEST_GROUP: &'static str = r#"{
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "The group a membership request is for"
+      ],
+      "index": [
+        "EQUALITY"
+      ],
+      "unique": [
+        "false"
+      ],
+      "multivalue": [
+        "false"
+      ],
+      "attributename": [
+        "request_group"
+      ],
+      "syntax": [
+        "UUID"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000061"
+      ]
+    }
+}"#;
+
+pub static UUID_SCHEMA_ATTR_REQUEST_MEMBER: &'static str = "00000000-0000-0000-0000-ffff00000062";
+pub static JSON_SCHEMA_ATTR_REQUEST_MEMBER: &'static str = r#"{
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "The member a membership request would add or remove"
+      ],
+      "index": [
+        "EQUALITY"
+      ],
+      "unique": [
+        "false"
+      ],
+      "multivalue": [
+        "false"
+      ],
+      "attributename": [
+        "request_member"
+      ],
+      "syntax": [
+        "UUID"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000062"
+      ]
+    }
+}"#;
+
+pub static UUID_SCHEMA_ATTR_REQUEST_ACTION: &'static str = "00000000-0000-0000-0000-ffff00000063";
+pub static JSON_SCHEMA_ATTR_REQUEST_ACTION: &'static str = r#"{
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "Whether a membership request adds or removes its member"
+      ],
+      "index": [],
+      "unique": [
+        "false"
+      ],
+      "multivalue": [
+        "false"
+      ],
+      "attributename": [
+        "request_action"
+      ],
+      "syntax": [
+        "UTF8STRING_INSENSITIVE"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000063"
+      ]
+    }
+}"#;
+
+pub static UUID_SCHEMA_ATTR_REQUEST_STATE: &'static str = "00000000-0000-0000-0000-ffff00000064";
+pub static JSON_SCHEMA_ATTR_REQUEST_STATE: &'static str = r#"{
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "Whether a membership request is pending, approved or rejected"
+      ],
+      "index": [
+        "EQUALITY"
+      ],
+      "unique": [
+        "false"
+      ],
+      "multivalue": [
+        "false"
+      ],
+      "attributename": [
+        "request_state"
+      ],
+      "syntax": [
+        "UTF8STRING_INSENSITIVE"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000064"
+      ]
+    }
+}"#;
+
+pub static UUID_SCHEMA_ATTR_REQUEST_REQUESTER: &'static str =
+    "00000000-0000-0000-0000-ffff00000065";
+pub static JSON_SCHEMA_ATTR_REQUEST_REQUESTER: &'static str = r#"{
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "Who made a membership request"
+      ],
+      "index": [],
+      "unique": [
+        "false"
+      ],
+      "multivalue": [
+        "false"
+      ],
+      "attributename": [
+        "request_requester"
+      ],
+      "syntax": [
+        "UUID"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000065"
+      ]
+    }
+}"#;
+
+pub static UUID_SCHEMA_ATTR_REQUEST_APPROVER: &'static str = "00000000-0000-0000-0000-ffff00000066";
+pub static JSON_SCHEMA_ATTR_REQUEST_APPROVER: &'static str = r#"{
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "Who approved or rejected a membership request"
+      ],
+      "index": [],
+      "unique": [
+        "false"
+      ],
+      "multivalue": [
+        "false"
+      ],
+      "attributename": [
+        "request_approver"
+      ],
+      "syntax": [
+        "UUID"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000066"
+      ]
+    }
+}"#;
+
+pub static UUID_SCHEMA_CLASS_MEMBERSHIPREQUEST: &'static str =
+    "00000000-0000-0000-0000-ffff00000067";
+pub static JSON_SCHEMA_CLASS_MEMBERSHIPREQUEST: &'static str = r#"
+  {
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "classtype"
+      ],
+      "description": [
+        "A request to change the members of a group, applied once approved"
+      ],
+      "classname": [
+        "membershiprequest"
+      ],
+      "systemmay": [
+        "description",
+        "request_approver"
+      ],
+      "systemmust": [
+        "request_group",
+        "request_member",
+        "request_action",
+        "request_state",
+        "request_requester"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000067"
+      ]
+    }
+  }
+"#;
+
+pub static UUID_SCHEMA_ATTR_BRANDING_LOGO: &'static str = "00000000-0000-0000-0000-ffff00000069";
+pub static JSON_SCHEMA_ATTR_BRANDING_LOGO: &'static str = r#"{
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "A reference to the logo shown by the UI, IE a url or a path under the server"
+      ],
+      "index": [],
+      "unique": [
+        "false"
+      ],
+      "multivalue": [
+        "false"
+      ],
+      "attributename": [
+        "branding_logo"
+      ],
+      "syntax": [
+        "UTF8STRING"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000069"
+      ]
+    }
+}"#;
+
+pub static UUID_SCHEMA_ATTR_LOGIN_BANNER: &'static str = "00000000-0000-0000-0000-ffff00000070";
+pub static JSON_SCHEMA_ATTR_LOGIN_BANNER: &'static str = r#"{
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "Text shown to everyone before they log in"
+      ],
+      "index": [],
+      "unique": [
+        "false"
+      ],
+      "multivalue": [
+        "false"
+      ],
+      "attributename": [
+        "login_banner"
+      ],
+      "syntax": [
+        "UTF8STRING"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000070"
+      ]
+    }
+}"#;
+
+pub static UUID_SCHEMA_CLASS_DOMAIN_BRANDING: &'static str = "00000000-0000-0000-0000-ffff00000071";
+pub static JSON_SCHEMA_CLASS_DOMAIN_BRANDING: &'static str = r#"
+  {
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "classtype"
+      ],
+      "description": [
+        "How this domain presents itself to people using it"
+      ],
+      "classname": [
+        "domain_branding"
+      ],
+      "systemmay": [
+        "description",
+        "branding_logo",
+        "login_banner"
+      ],
+      "systemmust": [
+        "displayname"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000071"
+      ]
+    }
+  }
+"#;
+
+pub static UUID_SCHEMA_ATTR_TEMPLATE_SUBJECT: &'static str = "00000000-0000-0000-0000-ffff00000072";
+pub static JSON_SCHEMA_ATTR_TEMPLATE_SUBJECT: &'static str = r#"{
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "The subject of a notification made from this template"
+      ],
+      "index": [],
+      "unique": [
+        "false"
+      ],
+      "multivalue": [
+        "false"
+      ],
+      "attributename": [
+        "template_subject"
+      ],
+      "syntax": [
+        "UTF8STRING"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000072"
+      ]
+    }
+}"#;
+
+pub static UUID_SCHEMA_ATTR_TEMPLATE_BODY: &'static str = "00000000-0000-0000-0000-ffff00000073";
+pub static JSON_SCHEMA_ATTR_TEMPLATE_BODY: &'static str = r#"{
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "The body of a notification. Values may hold ${param} placeholders, given when it's sent"
+      ],
+      "index": [],
+      "unique": [
+        "false"
+      ],
+      "multivalue": [
+        "false"
+      ],
+      "attributename": [
+        "template_body"
+      ],
+      "syntax": [
+        "UTF8STRING"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000073"
+      ]
+    }
+}"#;
+
+pub static UUID_SCHEMA_CLASS_NOTIFICATION_TEMPLATE: &'static str =
+    "00000000-0000-0000-0000-ffff00000074";
+pub static JSON_SCHEMA_CLASS_NOTIFICATION_TEMPLATE: &'static str = r#"
+  {
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "classtype"
+      ],
+      "description": [
+        "The text of a notification sent to people, found by name"
+      ],
+      "classname": [
+        "notification_template"
+      ],
+      "systemmay": [
+        "description",
+        "template_subject"
+      ],
+      "systemmust": [
+        "name",
+        "template_body"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000074"
+      ]
+    }
+  }
+"#;
+
+pub static UUID_SCHEMA_ATTR_LIMIT_SEARCH_MAX_RESULTS: &'static str =
+    "00000000-0000-0000-0000-ffff00000075";
+pub static JSON_SCHEMA_ATTR_LIMIT_SEARCH_MAX_RESULTS: &'static str = r#"{
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "The most entries a search by this account may return, over the server's limit"
+      ],
+      "index": [],
+      "unique": [
+        "false"
+      ],
+      "multivalue": [
+        "false"
+      ],
+      "attributename": [
+        "limit_search_max_results"
+      ],
+      "syntax": [
+        "UTF8STRING"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000075"
+      ]
+    }
+}"#;
+
+pub static UUID_SCHEMA_ATTR_LIMIT_SEARCH_MAX_CANDIDATES: &'static str =
+    "00000000-0000-0000-0000-ffff00000076";
+pub static JSON_SCHEMA_ATTR_LIMIT_SEARCH_MAX_CANDIDATES: &'static str = r#"{
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "The most entries a search by this account may examine, over the server's limit"
+      ],
+      "index": [],
+      "unique": [
+        "false"
+      ],
+      "multivalue": [
+        "false"
+      ],
+      "attributename": [
+        "limit_search_max_candidates"
+      ],
+      "syntax": [
+        "UTF8STRING"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000076"
+      ]
+    }
+}"#;
+
+pub static UUID_SCHEMA_ATTR_LIMIT_SEARCH_MAX_TIME: &'static str =
+    "00000000-0000-0000-0000-ffff00000077";
+pub static JSON_SCHEMA_ATTR_LIMIT_SEARCH_MAX_TIME: &'static str = r#"{
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "The longest in milliseconds a search by this account may take, over the server's limit"
+      ],
+      "index": [],
+      "unique": [
+        "false"
+      ],
+      "multivalue": [
+        "false"
+      ],
+      "attributename": [
+        "limit_search_max_time"
+      ],
+      "syntax": [
+        "UTF8STRING"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000077"
+      ]
+    }
+}"#;
+
+pub static UUID_SCHEMA_ATTR_OAUTH2_RS_ORIGIN: &'static str = "00000000-0000-0000-0000-ffff00000078";
+pub static JSON_SCHEMA_ATTR_OAUTH2_RS_ORIGIN: &'static str = r#"{
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "A redirect uri an oauth2 relying party may be sent its codes at, matched exactly"
+      ],
+      "index": [],
+      "unique": [
+        "false"
+      ],
+      "multivalue": [
+        "true"
+      ],
+      "attributename": [
+        "oauth2_rs_origin"
+      ],
+      "syntax": [
+        "UTF8STRING"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000078"
+      ]
+    }
+}"#;
+
+pub static UUID_SCHEMA_ATTR_OAUTH2_RS_SCOPE_MAP: &'static str =
+    "00000000-0000-0000-0000-ffff00000079";
+pub static JSON_SCHEMA_ATTR_OAUTH2_RS_SCOPE_MAP: &'static str = r#"{
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "The scopes members of a group may be granted by an oauth2 relying party, as group: scope scope"
+      ],
+      "index": [],
+      "unique": [
+        "false"
+      ],
+      "multivalue": [
+        "true"
+      ],
+      "attributename": [
+        "oauth2_rs_scope_map"
+      ],
+      "syntax": [
+        "UTF8STRING"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000079"
+      ]
+    }
+}"#;
+
+pub static UUID_SCHEMA_ATTR_OAUTH2_RS_BASIC_SECRET: &'static str =
+    "00000000-0000-0000-0000-ffff00000080";
+pub static JSON_SCHEMA_ATTR_OAUTH2_RS_BASIC_SECRET: &'static str = r#"{
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "The client secret of an oauth2 relying party, generated when it's missing"
+      ],
+      "index": [],
+      "unique": [
+        "false"
+      ],
+      "multivalue": [
+        "false"
+      ],
+      "attributename": [
+        "oauth2_rs_basic_secret"
+      ],
+      "syntax": [
+        "UTF8STRING"
+      ],
       "uuid": [
-        "00000000-0000-0000-0000-ffff00000042"
+        "00000000-0000-0000-0000-ffff00000080"
       ]
     }
-  }
-"#;
-pub static UUID_SCHEMA_ATTR_PRIMARY_CREDENTIAL: &'static str =
-    "00000000-0000-0000-0000-ffff00000043";
-pub static JSON_SCHEMA_ATTR_PRIMARY_CREDENTIAL: &'static str = r#"
-  {
-    "valid": {
-      "uuid": "00000000-0000-0000-0000-ffff00000043"
-    },
-    "state": null,
+}"#;
+
+pub static UUID_SCHEMA_ATTR_OAUTH2_RS_TOKEN_KEY: &'static str =
+    "00000000-0000-0000-0000-ffff00000081";
+pub static JSON_SCHEMA_ATTR_OAUTH2_RS_TOKEN_KEY: &'static str = r#"{
     "attrs": {
       "class": [
         "object",
@@ -1331,7 +2785,7 @@ pub static JSON_SCHEMA_ATTR_PRIMARY_CREDENTIAL: &'static str = r#"
         "attributetype"
       ],
       "description": [
-        "Primary credential material of the account for authentication interactively."
+        "The private key an oauth2 relying party's tokens are signed with, generated when it's missing"
       ],
       "index": [],
       "unique": [
@@ -1341,19 +2795,53 @@ pub static JSON_SCHEMA_ATTR_PRIMARY_CREDENTIAL: &'static str = r#"
         "false"
       ],
       "attributename": [
-        "primary_credential"
+        "oauth2_rs_token_key"
       ],
       "syntax": [
-        "CREDENTIAL"
+        "UTF8STRING"
       ],
       "uuid": [
-        "00000000-0000-0000-0000-ffff00000043"
+        "00000000-0000-0000-0000-ffff00000081"
+      ]
+    }
+}"#;
+
+pub static UUID_SCHEMA_CLASS_OAUTH2_RESOURCE_SERVER: &'static str =
+    "00000000-0000-0000-0000-ffff00000082";
+pub static JSON_SCHEMA_CLASS_OAUTH2_RESOURCE_SERVER: &'static str = r#"
+  {
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "classtype"
+      ],
+      "description": [
+        "A web application that authenticates people with oauth2 or openid connect, named by its client id"
+      ],
+      "classname": [
+        "oauth2_resource_server"
+      ],
+      "systemmay": [
+        "description",
+        "displayname",
+        "oauth2_rs_scope_map"
+      ],
+      "systemmust": [
+        "name",
+        "oauth2_rs_origin",
+        "oauth2_rs_basic_secret",
+        "oauth2_rs_token_key"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000082"
       ]
     }
   }
 "#;
-pub static UUID_SCHEMA_ATTR_LEGALNAME: &'static str = "00000000-0000-0000-0000-ffff00000050";
-pub static JSON_SCHEMA_ATTR_LEGALNAME: &'static str = r#"{
+
+pub static UUID_SCHEMA_ATTR_RADIUS_VLAN: &'static str = "00000000-0000-0000-0000-ffff00000083";
+pub static JSON_SCHEMA_ATTR_RADIUS_VLAN: &'static str = r#"{
     "attrs": {
       "class": [
         "object",
@@ -1361,11 +2849,71 @@ pub static JSON_SCHEMA_ATTR_LEGALNAME: &'static str = r#"{
         "attributetype"
       ],
       "description": [
-        "The private and sensitive legal name of this person"
+        "The vlan RADIUS should place a group's members on"
+      ],
+      "index": [],
+      "unique": [
+        "false"
+      ],
+      "multivalue": [
+        "false"
+      ],
+      "attributename": [
+        "radius_vlan"
+      ],
+      "syntax": [
+        "UINT32"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000083"
+      ]
+    }
+}"#;
+
+pub static UUID_SCHEMA_ATTR_GIDNUMBER: &'static str = "00000000-0000-0000-0000-ffff00000084";
+pub static JSON_SCHEMA_ATTR_GIDNUMBER: &'static str = r#"{
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "The groupid (uid) number of a group or account. This is the same value as the UID number on posix accounts for security reasons."
       ],
       "index": [
         "EQUALITY"
       ],
+      "unique": [
+        "true"
+      ],
+      "multivalue": [
+        "false"
+      ],
+      "attributename": [
+        "gidnumber"
+      ],
+      "syntax": [
+        "UINT32"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000084"
+      ]
+    }
+}"#;
+
+pub static UUID_SCHEMA_ATTR_LOGINSHELL: &'static str = "00000000-0000-0000-0000-ffff00000085";
+pub static JSON_SCHEMA_ATTR_LOGINSHELL: &'static str = r#"{
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "A posix user's default login shell"
+      ],
+      "index": [],
       "unique": [
         "false"
       ],
@@ -1373,18 +2921,19 @@ pub static JSON_SCHEMA_ATTR_LEGALNAME: &'static str = r#"{
         "false"
       ],
       "attributename": [
-        "legalname"
+        "loginshell"
       ],
       "syntax": [
         "UTF8STRING"
       ],
       "uuid": [
-        "00000000-0000-0000-0000-ffff00000050"
+        "00000000-0000-0000-0000-ffff00000085"
       ]
     }
 }"#;
-pub static UUID_SCHEMA_ATTR_RADIUS_SECRET: &'static str = "00000000-0000-0000-0000-ffff00000051";
-pub static JSON_SCHEMA_ATTR_RADIUS_SECRET: &'static str = r#"{
+
+pub static UUID_SCHEMA_ATTR_HOMEDIRECTORY: &'static str = "00000000-0000-0000-0000-ffff00000086";
+pub static JSON_SCHEMA_ATTR_HOMEDIRECTORY: &'static str = r#"{
     "attrs": {
       "class": [
         "object",
@@ -1392,7 +2941,7 @@ pub static JSON_SCHEMA_ATTR_RADIUS_SECRET: &'static str = r#"{
         "attributetype"
       ],
       "description": [
-        "The accounts generated radius secret for device network authentication"
+        "A posix user's home directory. If not set, clients derive it from the account name"
       ],
       "index": [],
       "unique": [
@@ -1402,24 +2951,20 @@ pub static JSON_SCHEMA_ATTR_RADIUS_SECRET: &'static str = r#"{
         "false"
       ],
       "attributename": [
-        "radius_secret"
+        "homedirectory"
       ],
       "syntax": [
-        "RADIUS_UTF8STRING"
+        "UTF8STRING"
       ],
       "uuid": [
-        "00000000-0000-0000-0000-ffff00000051"
+        "00000000-0000-0000-0000-ffff00000086"
       ]
     }
 }"#;
 
-pub static UUID_SCHEMA_CLASS_PERSON: &'static str = "00000000-0000-0000-0000-ffff00000044";
-pub static JSON_SCHEMA_CLASS_PERSON: &'static str = r#"
+pub static UUID_SCHEMA_CLASS_POSIXACCOUNT: &'static str = "00000000-0000-0000-0000-ffff00000087";
+pub static JSON_SCHEMA_CLASS_POSIXACCOUNT: &'static str = r#"
   {
-    "valid": {
-      "uuid": "00000000-0000-0000-0000-ffff00000044"
-    },
-    "state": null,
     "attrs": {
       "class": [
         "object",
@@ -1427,33 +2972,28 @@ pub static JSON_SCHEMA_CLASS_PERSON: &'static str = r#"
         "classtype"
       ],
       "description": [
-        "Object representation of a person"
+        "Object representation of a posix account"
       ],
       "classname": [
-        "person"
+        "posixaccount"
       ],
       "systemmay": [
-        "mail",
-        "legalname"
+        "loginshell",
+        "homedirectory"
       ],
       "systemmust": [
-        "displayname",
-        "name"
+        "gidnumber"
       ],
       "uuid": [
-        "00000000-0000-0000-0000-ffff00000044"
+        "00000000-0000-0000-0000-ffff00000087"
       ]
     }
   }
 "#;
 
-pub static UUID_SCHEMA_CLASS_GROUP: &'static str = "00000000-0000-0000-0000-ffff00000045";
-pub static JSON_SCHEMA_CLASS_GROUP: &'static str = r#"
+pub static UUID_SCHEMA_CLASS_POSIXGROUP: &'static str = "00000000-0000-0000-0000-ffff00000088";
+pub static JSON_SCHEMA_CLASS_POSIXGROUP: &'static str = r#"
   {
-    "valid": {
-      "uuid": "00000000-0000-0000-0000-ffff00000045"
-    },
-    "state": null,
     "attrs": {
       "class": [
         "object",
@@ -1461,25 +3001,86 @@ pub static JSON_SCHEMA_CLASS_GROUP: &'static str = r#"
         "classtype"
       ],
       "description": [
-        "Object representation of a group"
+        "Object representation of a posix group"
       ],
       "classname": [
-        "group"
-      ],
-      "systemmay": [
-        "member"
+        "posixgroup"
       ],
       "systemmust": [
-        "name"
+        "gidnumber"
       ],
       "uuid": [
-        "00000000-0000-0000-0000-ffff00000045"
+        "00000000-0000-0000-0000-ffff00000088"
       ]
     }
   }
 "#;
-pub static UUID_SCHEMA_CLASS_ACCOUNT: &'static str = "00000000-0000-0000-0000-ffff00000046";
-pub static JSON_SCHEMA_CLASS_ACCOUNT: &'static str = r#"
+
+pub static UUID_SCHEMA_ATTR_ANONYMOUS_ALLOW_SEARCH: &'static str =
+    "00000000-0000-0000-0000-ffff00000089";
+pub static JSON_SCHEMA_ATTR_ANONYMOUS_ALLOW_SEARCH: &'static str = r#"{
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "If anonymous may search at all"
+      ],
+      "index": [],
+      "unique": [
+        "false"
+      ],
+      "multivalue": [
+        "false"
+      ],
+      "attributename": [
+        "anonymous_allow_search"
+      ],
+      "syntax": [
+        "BOOLEAN"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000089"
+      ]
+    }
+}"#;
+
+pub static UUID_SCHEMA_ATTR_ANONYMOUS_ATTR_ALLOW: &'static str =
+    "00000000-0000-0000-0000-ffff00000090";
+pub static JSON_SCHEMA_ATTR_ANONYMOUS_ATTR_ALLOW: &'static str = r#"{
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "The only attributes anonymous may filter on or read, if any are set"
+      ],
+      "index": [],
+      "unique": [
+        "false"
+      ],
+      "multivalue": [
+        "true"
+      ],
+      "attributename": [
+        "anonymous_attr_allow"
+      ],
+      "syntax": [
+        "UTF8STRING_INSENSITIVE"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000090"
+      ]
+    }
+}"#;
+
+pub static UUID_SCHEMA_CLASS_ANONYMOUS_POLICY: &'static str =
+    "00000000-0000-0000-0000-ffff00000091";
+pub static JSON_SCHEMA_CLASS_ANONYMOUS_POLICY: &'static str = r#"
   {
     "attrs": {
       "class": [
@@ -1488,22 +3089,106 @@ pub static JSON_SCHEMA_CLASS_ACCOUNT: &'static str = r#"
         "classtype"
       ],
       "description": [
-        "Object representation of a account"
+        "What anonymous may search, whatever the access controls allow"
       ],
       "classname": [
-        "account"
+        "anonymous_policy"
       ],
       "systemmay": [
-        "primary_credential",
-        "ssh_publickey",
-        "radius_secret"
+        "description",
+        "anonymous_allow_search",
+        "anonymous_attr_allow"
       ],
-      "systemmust": [
-        "displayname",
-        "name"
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000091"
+      ]
+    }
+  }
+"#;
+
+pub static UUID_SCHEMA_ATTR_RATELIMIT_SEARCH: &'static str = "00000000-0000-0000-0000-ffff00000092";
+pub static JSON_SCHEMA_ATTR_RATELIMIT_SEARCH: &'static str = r#"{
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "How often searches may be made, as capacity:per_second"
+      ],
+      "index": [],
+      "unique": [
+        "false"
+      ],
+      "multivalue": [
+        "false"
+      ],
+      "attributename": [
+        "ratelimit_search"
+      ],
+      "syntax": [
+        "UTF8STRING"
       ],
       "uuid": [
-        "00000000-0000-0000-0000-ffff00000046"
+        "00000000-0000-0000-0000-ffff00000092"
+      ]
+    }
+}"#;
+
+pub static UUID_SCHEMA_ATTR_RATELIMIT_WRITE: &'static str = "00000000-0000-0000-0000-ffff00000093";
+pub static JSON_SCHEMA_ATTR_RATELIMIT_WRITE: &'static str = r#"{
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "How often writes may be made, as capacity:per_second"
+      ],
+      "index": [],
+      "unique": [
+        "false"
+      ],
+      "multivalue": [
+        "false"
+      ],
+      "attributename": [
+        "ratelimit_write"
+      ],
+      "syntax": [
+        "UTF8STRING"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000093"
+      ]
+    }
+}"#;
+
+pub static UUID_SCHEMA_CLASS_RATELIMIT_POLICY: &'static str =
+    "00000000-0000-0000-0000-ffff00000094";
+pub static JSON_SCHEMA_CLASS_RATELIMIT_POLICY: &'static str = r#"
+  {
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "classtype"
+      ],
+      "description": [
+        "How often accounts may search and write, unless they set their own"
+      ],
+      "classname": [
+        "ratelimit_policy"
+      ],
+      "systemmay": [
+        "description",
+        "ratelimit_search",
+        "ratelimit_write"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000094"
       ]
     }
   }
@@ -1,48 +1,74 @@
 // use actix::SystemRunner;
 use actix::Actor;
 use actix_web::middleware::session::{self, RequestSession};
-use actix_web::Path;
 use actix_web::{
     error, http, middleware, App, Error, HttpMessage, HttpRequest, HttpResponse, Result, State,
 };
+use actix_web::{Form, Path, Query};
 
-use bytes::BytesMut;
+use bytes::{Bytes, BytesMut};
 use futures::{future, Future, Stream};
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::BufReader;
 use std::sync::Arc;
 use time::Duration;
 
-use crate::config::Configuration;
-
+use crate::config::{
+    BackupCompression, BackupFormat, BackupKey, Configuration, DbKeySource, ExportSpec,
+    GenerateConfig,
+};
 // SearchResult
 use crate::actors::v1_read::QueryServerReadV1;
 use crate::actors::v1_read::{
-    AuthMessage, InternalRadiusReadMessage, InternalRadiusTokenReadMessage, InternalSearchMessage,
-    SearchMessage, WhoamiMessage,
+    AccessJournalMessage, AttrUsageMessage, AuthMessage, BrandingMessage, ChangesMessage,
+    CompareMessage, DbPoolStatsMessage, DeletePreviewMessage, DomainInfoMessage, ExplainMessage,
+    IndexStatsMessage, InternalRadiusReadMessage, InternalRadiusTokenReadMessage,
+    InternalSearchMessage, InternalSearchRecycledMessage, InternalUnixGroupTokenReadMessage,
+    InternalUnixUserTokenReadMessage, MembershipRequestsMessage, Oauth2AuthoriseMessage,
+    Oauth2DiscoveryMessage, Oauth2JwksMessage, Oauth2TokenMessage, OperationCancelMessage,
+    OperationsMessage, PurgeStatsMessage, ReadOnlyMessage, ReplSupplyMessage, ReportsMessage,
+    SavedSearchMessage, SearchMessage, SearchQueryMessage, SlowQueriesMessage, StatusMessage,
+    VerifyMessage, WhoamiMessage, WriteStatsMessage,
 };
 use crate::actors::v1_write::QueryServerWriteV1;
 use crate::actors::v1_write::{
-    AppendAttributeMessage, CreateMessage, DeleteMessage, IdmAccountSetPasswordMessage,
+    AppendAttributeMessage, CreateMessage, DeleteMessage, GroupMembersMessage,
+    IdmAccountSetPasswordMessage, IdmAccountUnixExtendMessage, IdmGroupUnixExtendMessage,
     InternalCredentialSetMessage, InternalDeleteMessage, InternalRegenerateRadiusMessage,
-    ModifyMessage, PurgeAttributeMessage, SetAttributeMessage,
+    MembershipDecisionMessage, MembershipRequestMessage, ModifyMessage, PurgeAttributeMessage,
+    ReindexMessage, ReviveRecycledMessage, SetAttributeMessage, VacuumMessage,
 };
 use crate::async_log;
 use crate::audit::AuditScope;
 use crate::be::{Backend, BackendTransaction};
 use crate::crypto::setup_tls;
 use crate::filter::{Filter, FilterInvalid};
+use crate::generate::generate;
 use crate::idm::server::IdmServer;
+use crate::import::{import_entries, import_ldif};
 use crate::interval::IntervalActor;
+use crate::ldap;
+use crate::optrack::OpTracker;
+use crate::priority::{slots_for_workers, OpScheduler};
+use crate::psearch::{self, PersistentSearches};
+use crate::ratelimit::{self, RateLimitMiddleware, RateLimiter};
+use crate::repl;
 use crate::schema::Schema;
 use crate::schema::SchemaTransaction;
 use crate::server::QueryServer;
 use crate::utils::SID;
 use crate::value::PartialValue;
+use crate::webui::ui_asset;
 
 use kanidm_proto::v1::Entry as ProtoEntry;
 use kanidm_proto::v1::OperationError;
 use kanidm_proto::v1::{
-    AuthRequest, AuthState, CreateRequest, DeleteRequest, ModifyRequest, SearchRequest,
-    SetAuthCredential, SingleStringRequest, UserAuthToken,
+    AccountUnixExtend, AuthRequest, AuthState, ChangesRequest, CompareRequest, CreateRequest,
+    DeleteRequest, GroupMembersRequest, GroupUnixExtend, MembershipRequest, ModifyRequest,
+    Oauth2AuthoriseRequest, Oauth2ErrorResponse, Oauth2TokenRequest, PersistentSearchRequest,
+    ReplSupplyRequest, SavedSearchRequest, SearchQueryRequest, SearchRequest, SetAuthCredential,
+    SingleStringRequest, UserAuthToken,
 };
 
 use uuid::Uuid;
@@ -51,6 +77,10 @@ struct AppState {
     qe_r: actix::Addr<QueryServerReadV1>,
     qe_w: actix::Addr<QueryServerWriteV1>,
     max_size: usize,
+    psearches: PersistentSearches,
+    // The configured origin, not what the client says it used, as that's in
+    // the tokens we issue.
+    origin: String,
 }
 
 fn get_current_user(req: &HttpRequest<AppState>) -> Option<UserAuthToken> {
@@ -66,11 +96,25 @@ fn get_current_user(req: &HttpRequest<AppState>) -> Option<UserAuthToken> {
 fn operation_error_to_response(e: OperationError) -> HttpResponse {
     match e {
         OperationError::NotAuthenticated => HttpResponse::Unauthorized().json(e),
-        OperationError::AccessDenied | OperationError::SystemProtectedObject => {
-            HttpResponse::Forbidden().json(e)
+        OperationError::AccessDenied
+        | OperationError::SystemProtectedObject
+        | OperationError::ElevationRequired => HttpResponse::Forbidden().json(e),
+        OperationError::Busy | OperationError::Unavailable | OperationError::ReadOnly => {
+            HttpResponse::ServiceUnavailable().json(e)
+        }
+        // A referral, the client should retry this at our primary.
+        OperationError::ReadReplica(primary) => {
+            HttpResponse::build(http::StatusCode::MISDIRECTED_REQUEST)
+                .header(http::header::LOCATION, primary.as_str())
+                .json(OperationError::ReadReplica(primary))
         }
         OperationError::EmptyRequest
         | OperationError::NoMatchingEntries
+        | OperationError::ResourceLimit
+        | OperationError::InvalidFilterQuery(_, _)
+        | OperationError::SavedSearchParamMissing(_)
+        | OperationError::InvalidSearchCursor
+        | OperationError::Oauth2(_)
         | OperationError::SchemaViolation(_) => HttpResponse::BadRequest().json(e),
         _ => HttpResponse::InternalServerError().json(e),
     }
@@ -174,12 +218,97 @@ fn delete(
     json_event_post!(req, state, DeleteMessage, DeleteRequest, state.qe_w)
 }
 
+fn delete_preview(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    json_event_post!(req, state, DeletePreviewMessage, DeleteRequest, state.qe_r)
+}
+
 fn search(
     (req, state): (HttpRequest<AppState>, State<AppState>),
 ) -> impl Future<Item = HttpResponse, Error = Error> {
     json_event_post!(req, state, SearchMessage, SearchRequest, state.qe_r)
 }
 
+fn search_query(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    json_event_post!(
+        req,
+        state,
+        SearchQueryMessage,
+        SearchQueryRequest,
+        state.qe_r
+    )
+}
+
+fn compare(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    json_event_post!(req, state, CompareMessage, CompareRequest, state.qe_r)
+}
+
+fn changes(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    json_event_post!(req, state, ChangesMessage, ChangesRequest, state.qe_r)
+}
+
+fn repl_supply(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    json_event_post!(req, state, ReplSupplyMessage, ReplSupplyRequest, state.qe_r)
+}
+
+// Held open, sending a PersistentSearchNotice per line as entries change, and
+// an empty line now and then to show we're still here.
+fn psearch(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let max_size = state.max_size;
+    let uat = get_current_user(&req);
+    let psearches = state.psearches.clone();
+
+    req.payload()
+        .from_err()
+        .fold(BytesMut::new(), move |mut body, chunk| {
+            if (body.len() + chunk.len()) > max_size {
+                Err(error::ErrorBadRequest("overflow"))
+            } else {
+                body.extend_from_slice(&chunk);
+                Ok(body)
+            }
+        })
+        .and_then(move |body| -> Result<HttpResponse, Error> {
+            let r_obj = serde_json::from_slice::<PersistentSearchRequest>(&body)
+                .map_err(|e| error::ErrorBadRequest(format!("Json Decode Failed: {:?}", e)))?;
+            match psearches.register(uat, r_obj.filter) {
+                Ok(rx) => {
+                    let lines = rx
+                        .map(|notice| {
+                            let mut line = match notice {
+                                Some(n) => serde_json::to_vec(&n).unwrap_or_default(),
+                                None => Vec::new(),
+                            };
+                            line.push(b'\n');
+                            Bytes::from(line)
+                        })
+                        .map_err(|_| error::ErrorInternalServerError("persistent search failed"));
+                    Ok(HttpResponse::Ok()
+                        .content_type("application/x-ndjson")
+                        .streaming(lines))
+                }
+                Err(e) => Ok(operation_error_to_response(e)),
+            }
+        })
+}
+
+fn explain(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    json_event_post!(req, state, ExplainMessage, SearchRequest, state.qe_r)
+}
+
 fn whoami(
     (req, state): (HttpRequest<AppState>, State<AppState>),
 ) -> impl Future<Item = HttpResponse, Error = Error> {
@@ -779,6 +908,66 @@ fn account_get_id_radius_token(
     Box::new(res)
 }
 
+fn account_post_id_unix(
+    (path, req, state): (Path<String>, HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let max_size = state.max_size;
+    let uat = get_current_user(&req);
+    let id = path.into_inner();
+
+    req.payload()
+        .from_err()
+        .fold(BytesMut::new(), move |mut body, chunk| {
+            // limit max size of in-memory payload
+            if (body.len() + chunk.len()) > max_size {
+                Err(error::ErrorBadRequest("overflow"))
+            } else {
+                body.extend_from_slice(&chunk);
+                Ok(body)
+            }
+        })
+        .and_then(
+            move |body| -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+                let r_obj = serde_json::from_slice::<AccountUnixExtend>(&body);
+                match r_obj {
+                    Ok(obj) => {
+                        let m_obj = IdmAccountUnixExtendMessage::new(uat, id, obj);
+                        let res = state.qe_w.send(m_obj).from_err().and_then(|res| match res {
+                            Ok(r) => Ok(HttpResponse::Ok().json(r)),
+                            Err(e) => Ok(operation_error_to_response(e)),
+                        });
+
+                        Box::new(res)
+                    }
+                    Err(e) => Box::new(future::err(error::ErrorBadRequest(format!(
+                        "Json Decode Failed: {:?}",
+                        e
+                    )))),
+                } // end match
+            },
+        ) // end and_then
+}
+
+// What a unix client resolves an account, or a uid, to.
+fn account_get_id_unix_token(
+    (path, req, state): (Path<String>, HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let uat = get_current_user(&req);
+    let id = path.into_inner();
+
+    let obj = InternalUnixUserTokenReadMessage {
+        uat: uat,
+        uuid_or_name: id,
+    };
+
+    let res = state.qe_r.send(obj).from_err().and_then(|res| match res {
+        Ok(event_result) => Ok(HttpResponse::Ok().json(event_result)),
+        Err(e) => Ok(operation_error_to_response(e)),
+    });
+
+    Box::new(res)
+}
+
 fn group_get(
     (req, state): (HttpRequest<AppState>, State<AppState>),
 ) -> impl Future<Item = HttpResponse, Error = Error> {
@@ -822,46 +1011,56 @@ fn group_id_post_attr(
     json_rest_event_post_id_attr(path, req, state, filter)
 }
 
-fn group_id_delete_attr(
-    (path, req, state): (
-        Path<(String, String)>,
-        HttpRequest<AppState>,
-        State<AppState>,
-    ),
-) -> impl Future<Item = HttpResponse, Error = Error> {
-    let filter = filter_all!(f_eq("class", PartialValue::new_class("group")));
-    json_rest_event_delete_id_attr(path, req, state, filter)
-}
-
-fn group_id_put_attr(
-    (path, req, state): (
-        Path<(String, String)>,
-        HttpRequest<AppState>,
-        State<AppState>,
-    ),
-) -> impl Future<Item = HttpResponse, Error = Error> {
-    let filter = filter_all!(f_eq("class", PartialValue::new_class("group")));
-    json_rest_event_put_id_attr(path, req, state, filter)
-}
-
-fn group_id_delete(
+fn group_id_post_members(
     (path, req, state): (Path<String>, HttpRequest<AppState>, State<AppState>),
 ) -> impl Future<Item = HttpResponse, Error = Error> {
-    let filter = filter_all!(f_eq("class", PartialValue::new_class("group")));
-    json_rest_event_delete_id(path, req, state, filter)
-}
+    let max_size = state.max_size;
+    let uat = get_current_user(&req);
+    let id = path.into_inner();
 
-fn do_nothing((_req, _state): (HttpRequest<AppState>, State<AppState>)) -> String {
-    "did nothing".to_string()
-}
+    req.payload()
+        .from_err()
+        .fold(BytesMut::new(), move |mut body, chunk| {
+            // limit max size of in-memory payload
+            if (body.len() + chunk.len()) > max_size {
+                Err(error::ErrorBadRequest("overflow"))
+            } else {
+                body.extend_from_slice(&chunk);
+                Ok(body)
+            }
+        })
+        .and_then(
+            move |body| -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+                let r_obj = serde_json::from_slice::<GroupMembersRequest>(&body);
+                match r_obj {
+                    Ok(obj) => {
+                        let m_obj = GroupMembersMessage {
+                            uat: uat,
+                            uuid_or_name: id,
+                            req: obj,
+                        };
+                        let res = state.qe_w.send(m_obj).from_err().and_then(|res| match res {
+                            Ok(r) => Ok(HttpResponse::Ok().json(r)),
+                            Err(e) => Ok(operation_error_to_response(e)),
+                        });
 
-// We probably need an extract auth or similar to handle the different
-// types (cookie, bearer), and to generic this over get/post.
+                        Box::new(res)
+                    }
+                    Err(e) => Box::new(future::err(error::ErrorBadRequest(format!(
+                        "Json Decode Failed: {:?}",
+                        e
+                    )))),
+                } // end match
+            },
+        ) // end and_then
+}
 
-fn auth(
-    (req, state): (HttpRequest<AppState>, State<AppState>),
+fn group_post_id_unix(
+    (path, req, state): (Path<String>, HttpRequest<AppState>, State<AppState>),
 ) -> impl Future<Item = HttpResponse, Error = Error> {
     let max_size = state.max_size;
+    let uat = get_current_user(&req);
+    let id = path.into_inner();
 
     req.payload()
         .from_err()
@@ -876,94 +1075,804 @@ fn auth(
         })
         .and_then(
             move |body| -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
-                let r_obj = serde_json::from_slice::<AuthRequest>(&body);
-
-                // Send to the db for action
+                let r_obj = serde_json::from_slice::<GroupUnixExtend>(&body);
                 match r_obj {
                     Ok(obj) => {
-                        // First, deal with some state management.
-                        // Do anything here first that's needed like getting the session details
-                        // out of the req cookie.
-
-                        // From the actix source errors here
-                        // seems to be related to the serde_json deserialise of the cookie
-                        // content, and because we control it's get/set it SHOULD be fine
-                        // provided we use secure cookies. But we can't always trust that ...
-                        let maybe_sessionid = match req.session().get::<Uuid>("auth-session-id") {
-                            Ok(c) => c,
-                            Err(e) => {
-                                return Box::new(future::err(e));
-                            }
-                        };
-
-                        let auth_msg = AuthMessage::new(obj, maybe_sessionid);
+                        let m_obj = IdmGroupUnixExtendMessage::new(uat, id, obj);
+                        let res = state.qe_w.send(m_obj).from_err().and_then(|res| match res {
+                            Ok(r) => Ok(HttpResponse::Ok().json(r)),
+                            Err(e) => Ok(operation_error_to_response(e)),
+                        });
 
-                        // We probably need to know if we allocate the cookie, that this is a
-                        // new session, and in that case, anything *except* authrequest init is
-                        // invalid.
-                        let res = state
-                            // This may change in the future ...
-                            .qe_r
-                            .send(auth_msg)
-                            .from_err()
-                            .and_then(move |res| match res {
-                                Ok(ar) => {
-                                    match &ar.state {
-                                        AuthState::Success(uat) => {
-                                            // Remove the auth-session-id
-                                            req.session().remove("auth-session-id");
-                                            // Set the uat into the cookie
-                                            match req.session().set("uat", uat) {
-                                                Ok(_) => Ok(HttpResponse::Ok().json(ar)),
-                                                Err(_) => {
-                                                    Ok(HttpResponse::InternalServerError().json(()))
-                                                }
-                                            }
-                                        }
-                                        AuthState::Denied(_) => {
-                                            // Remove the auth-session-id
-                                            req.session().remove("auth-session-id");
-                                            Ok(HttpResponse::Unauthorized().json(ar))
-                                        }
-                                        AuthState::Continue(_) => {
-                                            // Ensure the auth-session-id is set
-                                            match req.session().set("auth-session-id", ar.sessionid)
-                                            {
-                                                Ok(_) => Ok(HttpResponse::Ok().json(ar)),
-                                                Err(_) => {
-                                                    Ok(HttpResponse::InternalServerError().json(()))
-                                                }
-                                            }
-                                        }
-                                    }
-                                }
-                                Err(e) => Ok(operation_error_to_response(e)),
-                            });
                         Box::new(res)
                     }
                     Err(e) => Box::new(future::err(error::ErrorBadRequest(format!(
                         "Json Decode Failed: {:?}",
                         e
                     )))),
-                }
+                } // end match
             },
-        )
+        ) // end and_then
 }
 
-fn idm_account_set_password(
-    (req, state): (HttpRequest<AppState>, State<AppState>),
+fn group_get_id_unix_token(
+    (path, req, state): (Path<String>, HttpRequest<AppState>, State<AppState>),
 ) -> impl Future<Item = HttpResponse, Error = Error> {
-    json_event_post!(
-        req,
-        state,
-        IdmAccountSetPasswordMessage,
-        SingleStringRequest,
-        state.qe_w
-    )
-}
+    let uat = get_current_user(&req);
+    let id = path.into_inner();
 
-/*
-fn test_resource(
+    let obj = InternalUnixGroupTokenReadMessage {
+        uat: uat,
+        uuid_or_name: id,
+    };
+
+    let res = state.qe_r.send(obj).from_err().and_then(|res| match res {
+        Ok(event_result) => Ok(HttpResponse::Ok().json(event_result)),
+        Err(e) => Ok(operation_error_to_response(e)),
+    });
+
+    Box::new(res)
+}
+
+fn group_id_post_request(
+    (path, req, state): (Path<String>, HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let max_size = state.max_size;
+    let uat = get_current_user(&req);
+    let id = path.into_inner();
+
+    req.payload()
+        .from_err()
+        .fold(BytesMut::new(), move |mut body, chunk| {
+            // limit max size of in-memory payload
+            if (body.len() + chunk.len()) > max_size {
+                Err(error::ErrorBadRequest("overflow"))
+            } else {
+                body.extend_from_slice(&chunk);
+                Ok(body)
+            }
+        })
+        .and_then(
+            move |body| -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+                let r_obj = serde_json::from_slice::<MembershipRequest>(&body);
+                match r_obj {
+                    Ok(obj) => {
+                        let m_obj = MembershipRequestMessage {
+                            uat: uat,
+                            uuid_or_name: id,
+                            req: obj,
+                        };
+                        let res = state.qe_w.send(m_obj).from_err().and_then(|res| match res {
+                            Ok(r) => Ok(HttpResponse::Ok().json(r)),
+                            Err(e) => Ok(operation_error_to_response(e)),
+                        });
+
+                        Box::new(res)
+                    }
+                    Err(e) => Box::new(future::err(error::ErrorBadRequest(format!(
+                        "Json Decode Failed: {:?}",
+                        e
+                    )))),
+                } // end match
+            },
+        ) // end and_then
+}
+
+fn membershiprequest_get(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let uat = get_current_user(&req);
+    let m_obj = MembershipRequestsMessage::new(uat);
+    state.qe_r.send(m_obj).from_err().and_then(|res| match res {
+        Ok(r) => Ok(HttpResponse::Ok().json(r)),
+        Err(e) => Ok(operation_error_to_response(e)),
+    })
+}
+
+fn membershiprequest_decide(
+    path: Path<String>,
+    req: HttpRequest<AppState>,
+    state: State<AppState>,
+    approve: bool,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let m_obj = MembershipDecisionMessage {
+        uat: get_current_user(&req),
+        id: path.into_inner(),
+        approve: approve,
+    };
+    state.qe_w.send(m_obj).from_err().and_then(|res| match res {
+        Ok(r) => Ok(HttpResponse::Ok().json(r)),
+        Err(e) => Ok(operation_error_to_response(e)),
+    })
+}
+
+fn membershiprequest_id_post_approve(
+    (path, req, state): (Path<String>, HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    membershiprequest_decide(path, req, state, true)
+}
+
+fn membershiprequest_id_post_reject(
+    (path, req, state): (Path<String>, HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    membershiprequest_decide(path, req, state, false)
+}
+
+fn group_id_delete_attr(
+    (path, req, state): (
+        Path<(String, String)>,
+        HttpRequest<AppState>,
+        State<AppState>,
+    ),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let filter = filter_all!(f_eq("class", PartialValue::new_class("group")));
+    json_rest_event_delete_id_attr(path, req, state, filter)
+}
+
+fn group_id_put_attr(
+    (path, req, state): (
+        Path<(String, String)>,
+        HttpRequest<AppState>,
+        State<AppState>,
+    ),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let filter = filter_all!(f_eq("class", PartialValue::new_class("group")));
+    json_rest_event_put_id_attr(path, req, state, filter)
+}
+
+fn group_id_delete(
+    (path, req, state): (Path<String>, HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let filter = filter_all!(f_eq("class", PartialValue::new_class("group")));
+    json_rest_event_delete_id(path, req, state, filter)
+}
+
+fn recycle_bin_get(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let uat = get_current_user(&req);
+    let obj = InternalSearchRecycledMessage {
+        uat: uat,
+        filter: filter_all!(f_pres("class")),
+    };
+
+    let res = state.qe_r.send(obj).from_err().and_then(|res| match res {
+        Ok(event_result) => Ok(HttpResponse::Ok().json(event_result)),
+        Err(e) => Ok(operation_error_to_response(e)),
+    });
+
+    Box::new(res)
+}
+
+fn recycle_bin_id_get(
+    (path, req, state): (Path<String>, HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let uat = get_current_user(&req);
+    let obj = InternalSearchRecycledMessage {
+        uat: uat,
+        filter: filter_all!(f_id(path.as_str())),
+    };
+
+    let res = state.qe_r.send(obj).from_err().and_then(|res| match res {
+        // Only send back the first result, or None
+        Ok(mut event_result) => Ok(HttpResponse::Ok().json(event_result.pop())),
+        Err(e) => Ok(operation_error_to_response(e)),
+    });
+
+    Box::new(res)
+}
+
+fn recycle_bin_id_post_revive(
+    (path, req, state): (Path<String>, HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let uat = get_current_user(&req);
+    let obj = ReviveRecycledMessage {
+        uat: uat,
+        filter: filter_all!(f_id(path.as_str())),
+    };
+
+    let res = state.qe_w.send(obj).from_err().and_then(|res| match res {
+        Ok(_) => Ok(HttpResponse::Ok().json(())),
+        Err(e) => Ok(operation_error_to_response(e)),
+    });
+
+    Box::new(res)
+}
+
+fn savedsearch_get(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let filter = filter_all!(f_eq("class", PartialValue::new_class("savedsearch")));
+    json_rest_event_get(req, state, filter, None)
+}
+
+fn savedsearch_post(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let classes = vec!["savedsearch".to_string(), "object".to_string()];
+    json_rest_event_post(req, state, classes)
+}
+
+fn savedsearch_id_get(
+    (path, req, state): (Path<String>, HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let filter = filter_all!(f_eq("class", PartialValue::new_class("savedsearch")));
+    json_rest_event_get_id(path, req, state, filter, None)
+}
+
+fn savedsearch_id_delete(
+    (path, req, state): (Path<String>, HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let filter = filter_all!(f_eq("class", PartialValue::new_class("savedsearch")));
+    json_rest_event_delete_id(path, req, state, filter)
+}
+
+fn savedsearch_id_put_attr(
+    (path, req, state): (
+        Path<(String, String)>,
+        HttpRequest<AppState>,
+        State<AppState>,
+    ),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let filter = filter_all!(f_eq("class", PartialValue::new_class("savedsearch")));
+    json_rest_event_put_id_attr(path, req, state, filter)
+}
+
+fn savedsearch_id_post_search(
+    (path, req, state): (Path<String>, HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let max_size = state.max_size;
+    let uat = get_current_user(&req);
+    let name = path.into_inner();
+
+    req.payload()
+        .from_err()
+        .fold(BytesMut::new(), move |mut body, chunk| {
+            // limit max size of in-memory payload
+            if (body.len() + chunk.len()) > max_size {
+                Err(error::ErrorBadRequest("overflow"))
+            } else {
+                body.extend_from_slice(&chunk);
+                Ok(body)
+            }
+        })
+        .and_then(
+            move |body| -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+                let r_obj = serde_json::from_slice::<SavedSearchRequest>(&body);
+                match r_obj {
+                    Ok(obj) => {
+                        let m_obj = SavedSearchMessage::new(uat, name, obj);
+                        let res = state.qe_r.send(m_obj).from_err().and_then(|res| match res {
+                            Ok(r) => Ok(HttpResponse::Ok().json(r)),
+                            Err(e) => Ok(operation_error_to_response(e)),
+                        });
+
+                        Box::new(res)
+                    }
+                    Err(e) => Box::new(future::err(error::ErrorBadRequest(format!(
+                        "Json Decode Failed: {:?}",
+                        e
+                    )))),
+                } // end match
+            },
+        ) // end and_then
+}
+
+fn savedsearch_id_get_report(
+    (path, req, state): (Path<String>, HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let uat = get_current_user(&req);
+    let m_obj = ReportsMessage::new(uat, path.into_inner());
+    state.qe_r.send(m_obj).from_err().and_then(|res| match res {
+        Ok(r) => Ok(HttpResponse::Ok().json(r)),
+        Err(e) => Ok(operation_error_to_response(e)),
+    })
+}
+
+fn do_nothing((_req, _state): (HttpRequest<AppState>, State<AppState>)) -> String {
+    "did nothing".to_string()
+}
+
+fn operations_get(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let uat = get_current_user(&req);
+    state
+        .qe_r
+        .send(OperationsMessage { uat: uat })
+        .from_err()
+        .and_then(|res| match res {
+            Ok(event_result) => Ok(HttpResponse::Ok().json(event_result)),
+            Err(e) => Ok(operation_error_to_response(e)),
+        })
+}
+
+fn operations_cancel(
+    (path, req, state): (Path<u64>, HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let obj = OperationCancelMessage {
+        uat: get_current_user(&req),
+        id: path.into_inner(),
+    };
+    state.qe_r.send(obj).from_err().and_then(|res| match res {
+        Ok(event_result) => Ok(HttpResponse::Ok().json(event_result)),
+        Err(e) => Ok(operation_error_to_response(e)),
+    })
+}
+
+fn domain_info(
+    (_req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    // Unauthenticated, so that clients and other servers can learn which
+    // domain they are talking to before they log in.
+    state
+        .qe_r
+        .send(DomainInfoMessage)
+        .from_err()
+        .and_then(|res| match res {
+            Ok(event_result) => Ok(HttpResponse::Ok().json(event_result)),
+            Err(e) => Ok(operation_error_to_response(e)),
+        })
+}
+
+fn branding(
+    (_req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    // Unauthenticated, as it's shown before anyone logs in.
+    state
+        .qe_r
+        .send(BrandingMessage)
+        .from_err()
+        .and_then(|res| match res {
+            Ok(event_result) => Ok(HttpResponse::Ok().json(event_result)),
+            Err(e) => Ok(operation_error_to_response(e)),
+        })
+}
+
+// OAuth2 clients expect rfc6749's error responses, not ours.
+fn oauth2_error_to_response(e: OperationError) -> HttpResponse {
+    match e {
+        OperationError::Oauth2(error) => {
+            let mut resp = if error == "invalid_client" {
+                HttpResponse::Unauthorized()
+            } else {
+                HttpResponse::BadRequest()
+            };
+            resp.json(Oauth2ErrorResponse { error: error })
+        }
+        e => operation_error_to_response(e),
+    }
+}
+
+// The client id and secret from an Authorization: Basic header.
+fn basic_auth(req: &HttpRequest<AppState>) -> Option<(String, String)> {
+    let value = req
+        .headers()
+        .get(http::header::AUTHORIZATION)?
+        .to_str()
+        .ok()?;
+    if !value.starts_with("Basic ") {
+        return None;
+    }
+    let decoded = openssl::base64::decode_block(value["Basic ".len()..].trim()).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let mut parts = decoded.splitn(2, ':');
+    match (parts.next(), parts.next()) {
+        (Some(id), Some(secret)) => Some((id.to_string(), secret.to_string())),
+        _ => None,
+    }
+}
+
+fn oauth2_authorise(
+    (query, req, state): (
+        Query<Oauth2AuthoriseRequest>,
+        HttpRequest<AppState>,
+        State<AppState>,
+    ),
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    // The ui logs them in and sends them back here.
+    let uat = match get_current_user(&req) {
+        Some(uat) => uat,
+        None => {
+            return Box::new(future::ok(operation_error_to_response(
+                OperationError::NotAuthenticated,
+            )))
+        }
+    };
+    let obj = Oauth2AuthoriseMessage {
+        uat: uat,
+        req: query.into_inner(),
+    };
+    Box::new(state.qe_r.send(obj).from_err().and_then(|res| {
+        match res {
+            Ok(uri) => Ok(HttpResponse::Found()
+                .header(http::header::LOCATION, uri.as_str())
+                .finish()),
+            Err(e) => Ok(oauth2_error_to_response(e)),
+        }
+    }))
+}
+
+fn oauth2_token(
+    (form, req, state): (
+        Form<Oauth2TokenRequest>,
+        HttpRequest<AppState>,
+        State<AppState>,
+    ),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let obj = Oauth2TokenMessage {
+        req: form.into_inner(),
+        basic: basic_auth(&req),
+        origin: state.origin.clone(),
+    };
+    state.qe_r.send(obj).from_err().and_then(|res| match res {
+        // rfc6749 5.1, tokens must not be cached.
+        Ok(tokens) => Ok(HttpResponse::Ok()
+            .header(http::header::CACHE_CONTROL, "no-store")
+            .header(http::header::PRAGMA, "no-cache")
+            .json(tokens)),
+        Err(e) => Ok(oauth2_error_to_response(e)),
+    })
+}
+
+fn oauth2_discovery(
+    (path, state): (Path<String>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let obj = Oauth2DiscoveryMessage {
+        client_id: path.into_inner(),
+        origin: state.origin.clone(),
+    };
+    state.qe_r.send(obj).from_err().and_then(|res| match res {
+        Ok(event_result) => Ok(HttpResponse::Ok().json(event_result)),
+        Err(e) => Ok(oauth2_error_to_response(e)),
+    })
+}
+
+fn oauth2_jwks(
+    (path, state): (Path<String>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let obj = Oauth2JwksMessage {
+        client_id: path.into_inner(),
+    };
+    state.qe_r.send(obj).from_err().and_then(|res| match res {
+        Ok(event_result) => Ok(HttpResponse::Ok().json(event_result)),
+        Err(e) => Ok(oauth2_error_to_response(e)),
+    })
+}
+
+fn index_stats(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    let uat = get_current_user(&req);
+    Box::new(
+        state
+            .qe_r
+            .send(IndexStatsMessage { uat: uat })
+            .from_err()
+            .and_then(|res| match res {
+                Ok(event_result) => Ok(HttpResponse::Ok().json(event_result)),
+                Err(e) => Ok(operation_error_to_response(e)),
+            }),
+    )
+}
+
+fn attr_usage_inner(
+    req: &HttpRequest<AppState>,
+    state: &State<AppState>,
+    attr: Option<String>,
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    let uat = get_current_user(req);
+    Box::new(
+        state
+            .qe_r
+            .send(AttrUsageMessage {
+                uat: uat,
+                attr: attr,
+            })
+            .from_err()
+            .and_then(|res| match res {
+                Ok(event_result) => Ok(HttpResponse::Ok().json(event_result)),
+                Err(e) => Ok(operation_error_to_response(e)),
+            }),
+    )
+}
+
+fn attr_usage(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    attr_usage_inner(&req, &state, None)
+}
+
+fn attr_usage_id(
+    (path, req, state): (Path<String>, HttpRequest<AppState>, State<AppState>),
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    attr_usage_inner(&req, &state, Some(path.into_inner()))
+}
+
+fn access_journal(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    let uat = get_current_user(&req);
+    Box::new(
+        state
+            .qe_r
+            .send(AccessJournalMessage { uat: uat })
+            .from_err()
+            .and_then(|res| match res {
+                Ok(event_result) => Ok(HttpResponse::Ok().json(event_result)),
+                Err(e) => Ok(operation_error_to_response(e)),
+            }),
+    )
+}
+
+fn slow_queries(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    let uat = get_current_user(&req);
+    Box::new(
+        state
+            .qe_r
+            .send(SlowQueriesMessage { uat: uat })
+            .from_err()
+            .and_then(|res| match res {
+                Ok(event_result) => Ok(HttpResponse::Ok().json(event_result)),
+                Err(e) => Ok(operation_error_to_response(e)),
+            }),
+    )
+}
+
+// Takes true or false, and returns what it was set to.
+fn read_only_put(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    Box::new(json_event_post!(
+        req,
+        state,
+        ReadOnlyMessage,
+        bool,
+        state.qe_r
+    ))
+}
+
+fn db_pool_stats(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    let uat = get_current_user(&req);
+    Box::new(
+        state
+            .qe_r
+            .send(DbPoolStatsMessage { uat: uat })
+            .from_err()
+            .and_then(|res| match res {
+                Ok(event_result) => Ok(HttpResponse::Ok().json(event_result)),
+                Err(e) => Ok(operation_error_to_response(e)),
+            }),
+    )
+}
+
+fn purge_stats(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    let uat = get_current_user(&req);
+    Box::new(
+        state
+            .qe_r
+            .send(PurgeStatsMessage { uat: uat })
+            .from_err()
+            .and_then(|res| match res {
+                Ok(event_result) => Ok(HttpResponse::Ok().json(event_result)),
+                Err(e) => Ok(operation_error_to_response(e)),
+            }),
+    )
+}
+
+fn write_stats(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    let uat = get_current_user(&req);
+    Box::new(
+        state
+            .qe_r
+            .send(WriteStatsMessage { uat: uat })
+            .from_err()
+            .and_then(|res| match res {
+                Ok(event_result) => Ok(HttpResponse::Ok().json(event_result)),
+                Err(e) => Ok(operation_error_to_response(e)),
+            }),
+    )
+}
+
+fn verify(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    let uat = get_current_user(&req);
+    Box::new(
+        state
+            .qe_r
+            .send(VerifyMessage { uat: uat })
+            .from_err()
+            .and_then(|res| match res {
+                Ok(event_result) => Ok(HttpResponse::Ok().json(event_result)),
+                Err(e) => Ok(operation_error_to_response(e)),
+            }),
+    )
+}
+
+fn reindex(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    let uat = get_current_user(&req);
+    Box::new(
+        state
+            .qe_w
+            .send(ReindexMessage { uat: uat })
+            .from_err()
+            .and_then(|res| match res {
+                Ok(event_result) => Ok(HttpResponse::Ok().json(event_result)),
+                Err(e) => Ok(operation_error_to_response(e)),
+            }),
+    )
+}
+
+fn vacuum(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    let uat = get_current_user(&req);
+    Box::new(
+        state
+            .qe_w
+            .send(VacuumMessage { uat: uat })
+            .from_err()
+            .and_then(|res| match res {
+                Ok(event_result) => Ok(HttpResponse::Ok().json(event_result)),
+                Err(e) => Ok(operation_error_to_response(e)),
+            }),
+    )
+}
+
+fn status(
+    (_req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    // This is unauthenticated on purpose, it only tells you if the server can
+    // service requests, so that load balancers and the ui can check us.
+    state
+        .qe_r
+        .send(StatusMessage)
+        .from_err()
+        .and_then(|res| match res {
+            Ok(event_result) => Ok(HttpResponse::Ok().json(event_result)),
+            Err(e) => Ok(operation_error_to_response(e)),
+        })
+}
+
+fn ui_index((_req, _state): (HttpRequest<AppState>, State<AppState>)) -> HttpResponse {
+    ui_response("")
+}
+
+fn ui_get(
+    (path, _req, _state): (Path<String>, HttpRequest<AppState>, State<AppState>),
+) -> HttpResponse {
+    ui_response(path.as_str())
+}
+
+fn ui_response(name: &str) -> HttpResponse {
+    match ui_asset(name) {
+        Some(asset) => HttpResponse::Ok()
+            .content_type(asset.content_type)
+            .body(asset.body),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+// We probably need an extract auth or similar to handle the different
+// types (cookie, bearer), and to generic this over get/post.
+
+fn auth(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let max_size = state.max_size;
+
+    req.payload()
+        .from_err()
+        .fold(BytesMut::new(), move |mut body, chunk| {
+            // limit max size of in-memory payload
+            if (body.len() + chunk.len()) > max_size {
+                Err(error::ErrorBadRequest("overflow"))
+            } else {
+                body.extend_from_slice(&chunk);
+                Ok(body)
+            }
+        })
+        .and_then(
+            move |body| -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+                let r_obj = serde_json::from_slice::<AuthRequest>(&body);
+
+                // Send to the db for action
+                match r_obj {
+                    Ok(obj) => {
+                        // First, deal with some state management.
+                        // Do anything here first that's needed like getting the session details
+                        // out of the req cookie.
+
+                        // From the actix source errors here
+                        // seems to be related to the serde_json deserialise of the cookie
+                        // content, and because we control it's get/set it SHOULD be fine
+                        // provided we use secure cookies. But we can't always trust that ...
+                        let maybe_sessionid = match req.session().get::<Uuid>("auth-session-id") {
+                            Ok(c) => c,
+                            Err(e) => {
+                                return Box::new(future::err(e));
+                            }
+                        };
+
+                        let auth_msg = AuthMessage::new(obj, maybe_sessionid);
+
+                        // We probably need to know if we allocate the cookie, that this is a
+                        // new session, and in that case, anything *except* authrequest init is
+                        // invalid.
+                        let res = state
+                            // This may change in the future ...
+                            .qe_r
+                            .send(auth_msg)
+                            .from_err()
+                            .and_then(move |res| match res {
+                                Ok(ar) => {
+                                    match &ar.state {
+                                        AuthState::Success(uat) => {
+                                            // Remove the auth-session-id
+                                            req.session().remove("auth-session-id");
+                                            // Set the uat into the cookie
+                                            match req.session().set("uat", uat) {
+                                                Ok(_) => Ok(HttpResponse::Ok().json(ar)),
+                                                Err(_) => {
+                                                    Ok(HttpResponse::InternalServerError().json(()))
+                                                }
+                                            }
+                                        }
+                                        AuthState::Denied(_) => {
+                                            // Remove the auth-session-id
+                                            req.session().remove("auth-session-id");
+                                            Ok(HttpResponse::Unauthorized().json(ar))
+                                        }
+                                        AuthState::Continue(_) => {
+                                            // Ensure the auth-session-id is set
+                                            match req.session().set("auth-session-id", ar.sessionid)
+                                            {
+                                                Ok(_) => Ok(HttpResponse::Ok().json(ar)),
+                                                Err(_) => {
+                                                    Ok(HttpResponse::InternalServerError().json(()))
+                                                }
+                                            }
+                                        }
+                                    }
+                                }
+                                Err(e) => Ok(operation_error_to_response(e)),
+                            });
+                        Box::new(res)
+                    }
+                    Err(e) => Box::new(future::err(error::ErrorBadRequest(format!(
+                        "Json Decode Failed: {:?}",
+                        e
+                    )))),
+                }
+            },
+        )
+}
+
+fn idm_account_set_password(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    json_event_post!(
+        req,
+        state,
+        IdmAccountSetPasswordMessage,
+        SingleStringRequest,
+        state.qe_w
+    )
+}
+
+/*
+fn test_resource(
     (class, _req, _state): (Path<String>, HttpRequest<AppState> ,State<AppState>),
 ) -> String {
     format!("Hello {:?}!", class)
@@ -987,7 +1896,36 @@ fn test_resource_id(
 fn setup_backend(config: &Configuration) -> Result<Backend, OperationError> {
     let mut audit_be = AuditScope::new("backend_setup");
     let pool_size: u32 = config.threads as u32;
-    let be = Backend::new(&mut audit_be, config.db_path.as_str(), pool_size);
+    let db_key = match &config.db_key {
+        Some(source) => match source.load() {
+            Some(k) => Some(k),
+            None => {
+                error!("Unable to load database key - it must be 32 bytes as hex");
+                return Err(OperationError::InvalidState);
+            }
+        },
+        None => None,
+    };
+    let be = Backend::new_with_options(
+        &mut audit_be,
+        config.db_path.as_str(),
+        pool_size,
+        config.filter_test_threshold,
+        db_key,
+        config.db_pragmas.clone(),
+    )
+    .and_then(|mut be| {
+        be.set_slow_query_threshold(config.slow_query_threshold);
+        be.set_changelog_key(config.changelog_key);
+        be.set_domain(&mut audit_be, config.domain.as_str())?;
+        if let Some(compress) = config.entry_compression {
+            be.set_entry_compression(&mut audit_be, compress)?;
+        }
+        if let Some((path, window)) = &config.capture {
+            be.set_capture(path.as_str(), *window)?;
+        }
+        Ok(be)
+    });
     // debug!
     debug!("{}", audit_be);
     be
@@ -1001,6 +1939,7 @@ fn setup_qs_idms(
     audit: &mut AuditScope,
     be: Backend,
     sid: SID,
+    config: &Configuration,
 ) -> Result<(QueryServer, IdmServer), OperationError> {
     // Create "just enough" schema for us to be able to load from
     // disk ... Schema loading is one time where we validate the
@@ -1014,7 +1953,8 @@ fn setup_qs_idms(
     };
 
     // Create a query_server implementation
-    let query_server = QueryServer::new(be, schema);
+    let mut query_server = QueryServer::new(be, schema);
+    query_server.set_search_limits(config.search_limits.clone());
 
     // TODO #62: Should the IDM parts be broken out to the IdmServer?
     // What's important about this initial setup here is that it also triggers
@@ -1030,10 +1970,337 @@ fn setup_qs_idms(
 
     let idms = IdmServer::new(query_server.clone(), sid);
 
-    Ok((query_server, idms))
+    Ok((query_server, idms))
+}
+
+pub fn backup_server_core(
+    config: Configuration,
+    dst_path: &str,
+    compression: BackupCompression,
+    format: BackupFormat,
+    key: Option<BackupKey>,
+    incremental: bool,
+) {
+    let be = match setup_backend(&config) {
+        Ok(be) => be,
+        Err(e) => {
+            error!("Failed to setup BE: {:?}", e);
+            return;
+        }
+    };
+    let mut audit = AuditScope::new("backend_backup");
+
+    let be_ro_txn = match be.read() {
+        Ok(txn) => txn,
+        Err(e) => {
+            error!("Unable to begin backup txn -> {:?}", e);
+            std::process::exit(1);
+        }
+    };
+    // Taken in the same txn as the backup, so it's exactly what was saved.
+    let marker = be_ro_txn.get_change_marker();
+    let r = if incremental {
+        match be_ro_txn.get_backup_marker() {
+            0 => {
+                error!("No previous backup recorded - take a full backup first");
+                std::process::exit(1);
+            }
+            since => {
+                info!("Backing up changes since {}", since);
+                be_ro_txn.backup_incremental(&mut audit, dst_path, compression, key.as_ref(), since)
+            }
+        }
+    } else {
+        be_ro_txn.backup_ext(&mut audit, dst_path, compression, format, key.as_ref())
+    };
+    // Let the txn abort, even on success.
+    std::mem::drop(be_ro_txn);
+
+    // An ldif export can't be restored, so it mustn't become the base that
+    // incremental backups follow on from.
+    if format == BackupFormat::Ldif {
+        debug!("{}", audit);
+        match r {
+            Ok(_) => info!("Export success!"),
+            Err(e) => {
+                error!("Export failed: {:?}", e);
+                std::process::exit(1);
+            }
+        };
+        return;
+    }
+
+    // The next incremental backup follows on from this one.
+    let r = r
+        .and_then(|_| be.write(BTreeSet::new()))
+        .and_then(|be_wr_txn| {
+            be_wr_txn
+                .set_backup_marker(marker)
+                .and_then(|_| be_wr_txn.commit(&mut audit))
+        });
+    debug!("{}", audit);
+    match r {
+        Ok(_) => info!("Backup success! Marker is {}", marker),
+        Err(e) => {
+            error!("Backup failed: {:?}", e);
+            std::process::exit(1);
+        }
+    };
+}
+
+pub fn export_server_core(config: Configuration, dst_dir: &str, spec: ExportSpec) {
+    let be = match setup_backend(&config) {
+        Ok(be) => be,
+        Err(e) => {
+            error!("Failed to setup BE: {:?}", e);
+            return;
+        }
+    };
+    let mut audit = AuditScope::new("backend_export");
+
+    let be_ro_txn = match be.read() {
+        Ok(txn) => txn,
+        Err(e) => {
+            error!("Unable to begin export txn -> {:?}", e);
+            std::process::exit(1);
+        }
+    };
+    let r = be_ro_txn.export_csv(&mut audit, dst_dir, &spec);
+    debug!("{}", audit);
+    match r {
+        Ok(_) => info!("Export success!"),
+        Err(e) => {
+            error!("Export failed: {:?}", e);
+            std::process::exit(1);
+        }
+    };
+}
+
+pub fn restore_server_core(
+    config: Configuration,
+    dst_path: &str,
+    incremental_paths: &[&str],
+    batch_size: usize,
+    key: Option<BackupKey>,
+) {
+    let be = match setup_backend(&config) {
+        Ok(be) => be,
+        Err(e) => {
+            error!("Failed to setup BE: {:?}", e);
+            return;
+        }
+    };
+    let mut audit = AuditScope::new("backend_restore");
+
+    // First, we provide the in-memory schema so that core attrs are indexed correctly.
+    let schema = match Schema::new(&mut audit) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Failed to setup in memory schema: {:?}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // Limit the scope of the schema txn.
+    let idxmeta = { schema.write().get_idxmeta() };
+
+    let r = be.write(idxmeta).and_then(|mut be_wr_txn| {
+        be_wr_txn
+            .restore_chain(
+                &mut audit,
+                dst_path,
+                incremental_paths,
+                batch_size,
+                key.as_ref(),
+            )
+            .and_then(|_| be_wr_txn.commit(&mut audit))
+    });
+
+    if r.is_err() {
+        debug!("{}", audit);
+        error!("Failed to restore database: {:?}", r);
+        std::process::exit(1);
+    }
+    info!("Restore Success!");
+
+    info!("Attempting to init query server ...");
+    let server_id = match be.get_domain_info(&mut audit) {
+        Ok(domain) => domain.sid(),
+        Err(e) => {
+            error!("Unable to get server id -> {:?}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let (qs, _idms) = match setup_qs_idms(&mut audit, be, server_id, &config) {
+        Ok(t) => t,
+        Err(e) => {
+            debug!("{}", audit);
+            error!("Unable to setup query server or idm server -> {:?}", e);
+            return;
+        }
+    };
+    info!("Success!");
+
+    info!("Start reindex phase ...");
+
+    let r = qs.write().and_then(|qs_write| {
+        qs_write
+            .reindex(&mut audit, true)
+            .and_then(|_| qs_write.commit(&mut audit))
+    });
+
+    match r {
+        Ok(_) => info!("Reindex Success!"),
+        Err(e) => {
+            error!("Restore failed: {:?}", e);
+            std::process::exit(1);
+        }
+    };
+}
+
+pub fn copy_server_core(
+    mut config: Configuration,
+    dst_path: &str,
+    dst_key: Option<DbKeySource>,
+    reid: bool,
+    new_sid: bool,
+) {
+    if dst_path == "" || std::path::Path::new(dst_path).exists() {
+        error!("Copy destination {} must be a new file", dst_path);
+        std::process::exit(1);
+    }
+
+    let src_be = match setup_backend(&config) {
+        Ok(be) => be,
+        Err(e) => {
+            error!("Failed to setup BE: {:?}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // From here config describes the new database.
+    config.db_path = dst_path.to_string();
+    config.db_key = dst_key;
+    let dst_be = match setup_backend(&config) {
+        Ok(be) => be,
+        Err(e) => {
+            error!("Failed to setup destination BE: {:?}", e);
+            std::process::exit(1);
+        }
+    };
+    let mut audit = AuditScope::new("backend_copy");
+
+    let schema = match Schema::new(&mut audit) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Failed to setup in memory schema: {:?}", e);
+            std::process::exit(1);
+        }
+    };
+    let idxmeta = { schema.write().get_idxmeta() };
+
+    // Hold the read txn over the whole copy so we see a consistent snapshot.
+    let r = src_be.read().and_then(|src_txn| {
+        dst_be.write(idxmeta).and_then(|mut dst_txn| {
+            dst_txn
+                .copy_from(&mut audit, &src_txn, reid, new_sid)
+                .and_then(|_| dst_txn.commit(&mut audit))
+        })
+    });
+
+    if r.is_err() {
+        debug!("{}", audit);
+        error!("Failed to copy database: {:?}", r);
+        std::process::exit(1);
+    }
+    info!("Copy Success!");
+
+    // As with restore, the schema in the copy may index more than the in
+    // memory schema knows about, so let the query server reindex it.
+    info!("Attempting to init query server ...");
+    let server_id = match dst_be.get_domain_info(&mut audit) {
+        Ok(domain) => domain.sid(),
+        Err(e) => {
+            error!("Unable to get server id -> {:?}", e);
+            std::process::exit(1);
+        }
+    };
+    let (qs, _idms) = match setup_qs_idms(&mut audit, dst_be, server_id, &config) {
+        Ok(t) => t,
+        Err(e) => {
+            debug!("{}", audit);
+            error!("Unable to setup query server or idm server -> {:?}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let r = qs.write().and_then(|qs_write| {
+        qs_write
+            .reindex(&mut audit, true)
+            .and_then(|_| qs_write.commit(&mut audit))
+    });
+    debug!("{}", audit);
+
+    match r {
+        Ok(_) => info!("Copied to {} with server id {:?}", dst_path, server_id),
+        Err(e) => {
+            error!("Reindex of copy failed: {:?}", e);
+            std::process::exit(1);
+        }
+    };
+}
+
+pub fn generate_server_core(config: Configuration, gconfig: GenerateConfig) {
+    let mut audit = AuditScope::new("generate");
+    let be = match setup_backend(&config) {
+        Ok(be) => be,
+        Err(e) => {
+            error!("Failed to setup BE: {:?}", e);
+            return;
+        }
+    };
+    let server_id = match be.get_domain_info(&mut audit) {
+        Ok(domain) => domain.sid(),
+        Err(e) => {
+            error!("Unable to get server id -> {:?}", e);
+            std::process::exit(1);
+        }
+    };
+    let (qs, _idms) = match setup_qs_idms(&mut audit, be, server_id, &config) {
+        Ok(t) => t,
+        Err(e) => {
+            debug!("{}", audit);
+            error!("Unable to setup query server or idm server -> {:?}", e);
+            std::process::exit(1);
+        }
+    };
+
+    info!("Generating {:?} ...", gconfig);
+    let r = qs.write().and_then(|mut qs_write| {
+        generate(&mut audit, &mut qs_write, &gconfig)
+            .and_then(|counts| qs_write.commit(&mut audit).map(|_| counts))
+    });
+    debug!("{}", audit);
+
+    match r {
+        Ok((a, g)) => info!("Generated {} accounts and {} groups", a, g),
+        Err(e) => {
+            error!("Generate failed: {:?}", e);
+            std::process::exit(1);
+        }
+    };
 }
 
-pub fn backup_server_core(config: Configuration, dst_path: &str) {
+pub fn import_ldif_server_core(config: Configuration, src_path: &str) {
+    let mut audit = AuditScope::new("import_ldif");
+    let file = match File::open(src_path) {
+        Ok(f) => f,
+        Err(e) => {
+            error!("Unable to open {} -> {:?}", src_path, e);
+            std::process::exit(1);
+        }
+    };
     let be = match setup_backend(&config) {
         Ok(be) => be,
         Err(e) => {
@@ -1041,22 +2308,44 @@ pub fn backup_server_core(config: Configuration, dst_path: &str) {
             return;
         }
     };
-    let mut audit = AuditScope::new("backend_backup");
+    let server_id = match be.get_domain_info(&mut audit) {
+        Ok(domain) => domain.sid(),
+        Err(e) => {
+            error!("Unable to get server id -> {:?}", e);
+            std::process::exit(1);
+        }
+    };
+    let (qs, _idms) = match setup_qs_idms(&mut audit, be, server_id, &config) {
+        Ok(t) => t,
+        Err(e) => {
+            debug!("{}", audit);
+            error!("Unable to setup query server or idm server -> {:?}", e);
+            std::process::exit(1);
+        }
+    };
 
-    let be_ro_txn = be.read();
-    let r = be_ro_txn.backup(&mut audit, dst_path);
+    info!("Importing {} ...", src_path);
+    let r = qs.write().and_then(|mut qs_write| {
+        import_ldif(&mut audit, &mut qs_write, BufReader::new(file))
+            .and_then(|counts| qs_write.commit(&mut audit).map(|_| counts))
+    });
     debug!("{}", audit);
+
     match r {
-        Ok(_) => info!("Backup success!"),
+        Ok((entries, 0)) => info!("Imported {} entries", entries),
+        Ok((entries, skipped)) => warn!(
+            "Imported {} entries, skipping {} values we have no schema for or could not resolve - see the debug log for which",
+            entries, skipped
+        ),
         Err(e) => {
-            error!("Backup failed: {:?}", e);
+            error!("Import failed: {:?}", e);
             std::process::exit(1);
         }
     };
-    // Let the txn abort, even on success.
 }
 
-pub fn restore_server_core(config: Configuration, dst_path: &str) {
+pub fn import_server_core(config: Configuration, src_path: &str, batch_size: usize) {
+    let mut audit = AuditScope::new("import");
     let be = match setup_backend(&config) {
         Ok(be) => be,
         Err(e) => {
@@ -1064,56 +2353,36 @@ pub fn restore_server_core(config: Configuration, dst_path: &str) {
             return;
         }
     };
-    let mut audit = AuditScope::new("backend_restore");
-
-    // First, we provide the in-memory schema so that core attrs are indexed correctly.
-    let schema = match Schema::new(&mut audit) {
-        Ok(s) => s,
+    let server_id = match be.get_domain_info(&mut audit) {
+        Ok(domain) => domain.sid(),
         Err(e) => {
-            error!("Failed to setup in memory schema: {:?}", e);
+            error!("Unable to get server id -> {:?}", e);
             std::process::exit(1);
         }
     };
-
-    // Limit the scope of the schema txn.
-    let idxmeta = { schema.write().get_idxmeta() };
-
-    let mut be_wr_txn = be.write(idxmeta);
-    let r = be_wr_txn
-        .restore(&mut audit, dst_path)
-        .and_then(|_| be_wr_txn.commit(&mut audit));
-
-    if r.is_err() {
-        debug!("{}", audit);
-        error!("Failed to restore database: {:?}", r);
-        std::process::exit(1);
-    }
-    info!("Restore Success!");
-
-    info!("Attempting to init query server ...");
-    let server_id = be.get_db_sid();
-
-    let (qs, _idms) = match setup_qs_idms(&mut audit, be, server_id) {
+    let (qs, _idms) = match setup_qs_idms(&mut audit, be, server_id, &config) {
         Ok(t) => t,
         Err(e) => {
             debug!("{}", audit);
             error!("Unable to setup query server or idm server -> {:?}", e);
-            return;
+            std::process::exit(1);
         }
     };
-    info!("Success!");
-
-    info!("Start reindex phase ...");
 
-    let qs_write = qs.write();
-    let r = qs_write
-        .reindex(&mut audit)
-        .and_then(|_| qs_write.commit(&mut audit));
+    info!("Importing {} ...", src_path);
+    let open = || {
+        File::open(src_path).map(BufReader::new).map_err(|e| {
+            error!("Unable to open {} -> {:?}", src_path, e);
+            OperationError::InvalidRequestState
+        })
+    };
+    let r = import_entries(&mut audit, &qs, open, batch_size);
+    debug!("{}", audit);
 
     match r {
-        Ok(_) => info!("Reindex Success!"),
+        Ok(entries) => info!("Imported {} entries", entries),
         Err(e) => {
-            error!("Restore failed: {:?}", e);
+            error!("Import failed: {:?} - see the debug log for where", e);
             std::process::exit(1);
         }
     };
@@ -1129,9 +2398,19 @@ pub fn reset_sid_core(config: Configuration) {
             return;
         }
     };
-    let nsid = be.reset_db_sid(&mut audit);
+    let r = be.reset_domain_info(&mut audit);
     debug!("{}", audit);
-    info!("New Server ID: {:?}", nsid);
+    match r {
+        Ok(domain) => info!(
+            "New domain uuid: {}, Server ID: {:?}",
+            domain.uuid,
+            domain.sid()
+        ),
+        Err(e) => {
+            error!("Unable to reset server id -> {:?}", e);
+            std::process::exit(1);
+        }
+    };
 }
 
 pub fn verify_server_core(config: Configuration) {
@@ -1171,6 +2450,161 @@ pub fn verify_server_core(config: Configuration) {
     // Now add IDM server verifications?
 }
 
+// Replay a capture taken with --capture_path into a new database, verifying
+// after each txn, to find the one that broke it.
+pub fn replay_server_core(config: Configuration, capture_path: &str, until: Option<u64>) {
+    if config.db_path == "" || std::path::Path::new(config.db_path.as_str()).exists() {
+        error!(
+            "Replay database {} must be a new file, not the one captured",
+            config.db_path
+        );
+        std::process::exit(1);
+    }
+    let mut audit = AuditScope::new("replay_capture");
+    let be = match setup_backend(&config) {
+        Ok(be) => be,
+        Err(e) => {
+            error!("Failed to setup BE: {:?}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let r = be.replay_capture(&mut audit, capture_path, until);
+    debug!("{}", audit);
+
+    match r {
+        Ok((txn, r)) => {
+            let errs: Vec<_> = r.into_iter().filter_map(|v| v.err()).collect();
+            if errs.is_empty() {
+                info!("Replayed to txn {}, database is consistent", txn);
+            } else {
+                error!("Database is inconsistent after txn {}", txn);
+                for er in errs {
+                    error!("{:?}", er);
+                }
+                std::process::exit(1);
+            }
+        }
+        Err(e) => {
+            error!("Replay failed: {:?}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+pub fn verify_changelog_core(config: Configuration) {
+    let mut audit = AuditScope::new("verify_changelog");
+    let be = match setup_backend(&config) {
+        Ok(be) => be,
+        Err(e) => {
+            error!("Failed to setup BE: {:?}", e);
+            return;
+        }
+    };
+    let r = be
+        .read()
+        .and_then(|be_txn| be_txn.verify_changelog(&mut audit, config.changelog_key.as_ref()));
+    debug!("{}", audit);
+    match r {
+        Ok((count, None)) => {
+            if config.changelog_key.is_none() {
+                warn!("No key given, signatures were not checked");
+            }
+            info!("Changelog of {} records verified", count);
+        }
+        Ok((count, Some(id))) => {
+            error!(
+                "Changelog of {} records is invalid from record {} onwards",
+                count, id
+            );
+            std::process::exit(1);
+        }
+        Err(e) => {
+            error!("Unable to verify changelog -> {:?}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+pub fn repair_indexes_core(config: Configuration) {
+    let mut audit = AuditScope::new("repair_indexes");
+    let be = match setup_backend(&config) {
+        Ok(be) => be,
+        Err(e) => {
+            error!("Failed to setup BE: {:?}", e);
+            return;
+        }
+    };
+    let server_id = match be.get_domain_info(&mut audit) {
+        Ok(domain) => domain.sid(),
+        Err(e) => {
+            error!("Unable to get server id -> {:?}", e);
+            std::process::exit(1);
+        }
+    };
+    let (qs, _idms) = match setup_qs_idms(&mut audit, be, server_id, &config) {
+        Ok(t) => t,
+        Err(e) => {
+            debug!("{}", audit);
+            error!("Unable to setup query server or idm server -> {:?}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let r = qs.write().and_then(|qs_write| {
+        qs_write
+            .repair_idxs(&mut audit)
+            .and_then(|repaired| qs_write.commit(&mut audit).map(|_| repaired))
+    });
+    debug!("{}", audit);
+
+    match r {
+        Ok(repaired) => {
+            if repaired.is_empty() {
+                info!("All indexes are consistent, nothing to repair");
+            }
+            for (attr, itype) in repaired {
+                info!("Repaired {:?} index on {}", itype, attr);
+            }
+        }
+        Err(e) => {
+            error!("Repair failed: {:?}", e);
+            std::process::exit(1);
+        }
+    };
+}
+
+pub fn vacuum_server_core(config: Configuration) {
+    let mut audit = AuditScope::new("vacuum");
+    let be = match setup_backend(&config) {
+        Ok(be) => be,
+        Err(e) => {
+            error!("Failed to setup BE: {:?}", e);
+            return;
+        }
+    };
+
+    let r = be
+        .write(BTreeSet::new())
+        .and_then(|be_txn| be_txn.vacuum(&mut audit));
+    debug!("{}", audit);
+
+    match r {
+        Ok((before, after)) => {
+            info!(
+                "Vacuum complete, {} -> {} bytes, reclaimed {} bytes",
+                before,
+                after,
+                before.saturating_sub(after)
+            );
+        }
+        Err(e) => {
+            error!("Vacuum failed: {:?}", e);
+            std::process::exit(1);
+        }
+    };
+}
+
 pub fn recover_account_core(config: Configuration, name: String, password: String) {
     let mut audit = AuditScope::new("recover_account");
 
@@ -1182,9 +2616,15 @@ pub fn recover_account_core(config: Configuration, name: String, password: Strin
             return;
         }
     };
-    let server_id = be.get_db_sid();
+    let server_id = match be.get_domain_info(&mut audit) {
+        Ok(domain) => domain.sid(),
+        Err(e) => {
+            error!("Unable to get server id -> {:?}", e);
+            std::process::exit(1);
+        }
+    };
     // setup the qs - *with* init of the migrations and schema.
-    let (_qs, idms) = match setup_qs_idms(&mut audit, be, server_id) {
+    let (_qs, idms) = match setup_qs_idms(&mut audit, be, server_id, &config) {
         Ok(t) => t,
         Err(e) => {
             debug!("{}", audit);
@@ -1194,7 +2634,13 @@ pub fn recover_account_core(config: Configuration, name: String, password: Strin
     };
 
     // Run the password change.
-    let mut idms_prox_write = idms.proxy_write();
+    let mut idms_prox_write = match idms.proxy_write() {
+        Ok(txn) => txn,
+        Err(e) => {
+            error!("Unable to begin txn -> {:?}", e);
+            std::process::exit(1);
+        }
+    };
     match idms_prox_write.recover_account(&mut audit, name, password) {
         Ok(_) => {
             idms_prox_write
@@ -1224,7 +2670,7 @@ pub fn create_server_core(config: Configuration) {
     info!("Starting kanidm with configuration: {}", config);
     // The log server is started on it's own thread, and is contacted
     // asynchronously.
-    let log_addr = async_log::start();
+    let log_addr = async_log::start(config.log_redaction.clone(), config.access_log.clone());
 
     // Setup TLS (if any)
     let opt_tls_params = match setup_tls(&config) {
@@ -1247,12 +2693,18 @@ pub fn create_server_core(config: Configuration) {
         }
     };
 
-    let server_id = be.get_db_sid();
-    info!("Server ID -> {:?}", server_id);
-
     let mut audit = AuditScope::new("setup_qs_idms");
+    let server_id = match be.get_domain_info(&mut audit) {
+        Ok(domain) => domain.sid(),
+        Err(e) => {
+            debug!("{}", audit);
+            error!("Unable to get server id -> {:?}", e);
+            return;
+        }
+    };
+    info!("Server ID -> {:?}", server_id);
     // Start the IDM server.
-    let (qs, idms) = match setup_qs_idms(&mut audit, be, server_id) {
+    let (qs, idms) = match setup_qs_idms(&mut audit, be, server_id, &config) {
         Ok(t) => t,
         Err(e) => {
             debug!("{}", audit);
@@ -1261,9 +2713,24 @@ pub fn create_server_core(config: Configuration) {
         }
     };
     // Any pre-start tasks here.
+    if let Some(limit) = config.warmup {
+        // A failed warm up only costs us the first requests being slower.
+        match qs.warm_up(&mut audit, limit) {
+            Ok((slots, entries)) => {
+                info!("Warmed up {} index slots and {} entries", slots, entries)
+            }
+            Err(e) => warn!("Unable to warm up -> {:?}", e),
+        }
+    }
     match &config.integration_test_config {
         Some(itc) => {
-            let mut idms_prox_write = idms.proxy_write();
+            let mut idms_prox_write = match idms.proxy_write() {
+                Ok(txn) => txn,
+                Err(e) => {
+                    error!("Unable to begin txn -> {:?}", e);
+                    return;
+                }
+            };
             match idms_prox_write.recover_account(
                 &mut audit,
                 "admin".to_string(),
@@ -1292,29 +2759,78 @@ pub fn create_server_core(config: Configuration) {
     }
     log_addr.do_send(audit);
 
+    // Only now we've done our own migrations, which a replica must still run.
+    if let Some(primary) = config
+        .replication
+        .as_ref()
+        .and_then(|rc| rc.primary.clone())
+    {
+        info!("Running as a read replica of {}", primary);
+        qs.set_primary(Some(primary));
+    }
+
     // Arc the idms.
     let idms_arc = Arc::new(idms);
 
     // Pass it to the actor for threading.
     // Start the read query server with the given be path: future config
+    // The read and write workers share the scheduler, so that when every slot
+    // is busy the next to run is decided by priority rather than arrival. The
+    // writes run one at a time, so count as one more worker.
+    let sched = OpScheduler::new(slots_for_workers(config.threads + 1));
+    let ops = OpTracker::new();
     let server_read_addr = QueryServerReadV1::start(
         log_addr.clone(),
         qs.clone(),
         idms_arc.clone(),
+        sched.clone(),
+        ops.clone(),
+        config.threads,
+    );
+    // Start the write workers
+    let server_write_addr = QueryServerWriteV1::start(
+        log_addr.clone(),
+        qs.clone(),
+        idms_arc.clone(),
+        sched.clone(),
+        ops.clone(),
         config.threads,
     );
-    // Start the write thread
-    let server_write_addr =
-        QueryServerWriteV1::start(log_addr.clone(), qs.clone(), idms_arc.clone());
 
-    // Setup timed events associated to the write thread
-    let _int_addr = IntervalActor::new(server_write_addr.clone()).start();
+    // Setup timed events associated to the write workers
+    let _int_addr = IntervalActor::new(
+        server_write_addr.clone(),
+        server_read_addr.clone(),
+        config.online_backup.clone(),
+    )
+    .start();
+
+    // Follows commits from here on, for clients of /v1/raw/psearch.
+    let psearches = PersistentSearches::new();
+    psearch::start(qs.clone(), psearches.clone(), log_addr.clone());
+
+    if let Some(rc) = config.replication.clone() {
+        repl::consumer::start(rc, server_write_addr.clone());
+    }
+
+    // The acceptor builder isn't Clone, so we set up another for LDAPS.
+    if let Some(lc) = config.ldap.clone() {
+        match setup_tls(&config) {
+            Ok(Some(tls)) => ldap::start(lc, tls.build(), server_read_addr.clone()),
+            Ok(None) => error!("LDAPS requires TLS, not starting the ldap listener"),
+            Err(e) => error!("Failed to configure TLS for LDAPS -> {:?}", e),
+        }
+    }
 
     // Copy the max size
     let max_size = config.maximum_request;
     let secure_cookies = config.secure_cookies;
+    let origin = config.origin.clone();
     // let domain = config.domain.clone();
     let cookie_key: [u8; 32] = config.cookie_key.clone();
+    // Shared between all the workers, so the limit is per server, not per thread.
+    let rate_limiter = Arc::new(RateLimiter::new());
+    ratelimit::start(qs.clone(), rate_limiter.clone(), log_addr.clone());
 
     // start the web server
     let aws_builder = actix_web::server::new(move || {
@@ -1322,6 +2838,8 @@ pub fn create_server_core(config: Configuration) {
             qe_r: server_read_addr.clone(),
             qe_w: server_write_addr.clone(),
             max_size: max_size,
+            psearches: psearches.clone(),
+            origin: origin.clone(),
         })
         // Connect all our end points here.
         .middleware(middleware::Logger::default())
@@ -1346,6 +2864,15 @@ pub fn create_server_core(config: Configuration) {
                 // This forces https only if true
                 .secure(secure_cookies),
         ))
+        // Must be after the session so we can see who is asking.
+        .middleware(RateLimitMiddleware::new(rate_limiter.clone()))
+        // The admin web ui, and the status check it relies on.
+        .resource("/status", |r| {
+            r.method(http::Method::GET).with_async(status)
+        })
+        .resource("/ui", |r| r.method(http::Method::GET).with(ui_index))
+        .resource("/ui/", |r| r.method(http::Method::GET).with(ui_index))
+        .resource("/ui/{name}", |r| r.method(http::Method::GET).with(ui_get))
         .resource("/v1/raw/create", |r| {
             r.method(http::Method::POST).with_async(create)
         })
@@ -1355,12 +2882,96 @@ pub fn create_server_core(config: Configuration) {
         .resource("/v1/raw/delete", |r| {
             r.method(http::Method::POST).with_async(delete)
         })
+        .resource("/v1/raw/delete/preview", |r| {
+            r.method(http::Method::POST).with_async(delete_preview)
+        })
         .resource("/v1/raw/search", |r| {
             r.method(http::Method::POST).with_async(search)
         })
+        .resource("/v1/raw/search/query", |r| {
+            r.method(http::Method::POST).with_async(search_query)
+        })
+        .resource("/v1/raw/compare", |r| {
+            r.method(http::Method::POST).with_async(compare)
+        })
+        .resource("/v1/raw/changes", |r| {
+            r.method(http::Method::POST).with_async(changes)
+        })
+        .resource("/v1/raw/psearch", |r| {
+            r.method(http::Method::POST).with_async(psearch)
+        })
+        .resource("/v1/repl/supply", |r| {
+            r.method(http::Method::POST).with_async(repl_supply)
+        })
+        .resource("/v1/raw/explain", |r| {
+            r.method(http::Method::POST).with_async(explain)
+        })
         .resource("/v1/auth", |r| {
             r.method(http::Method::POST).with_async(auth)
         })
+        .resource("/v1/domain", |r| {
+            r.method(http::Method::GET).with_async(domain_info)
+        })
+        .resource("/v1/branding", |r| {
+            r.method(http::Method::GET).with_async(branding)
+        })
+        // OAuth2 and OpenID Connect for relying parties, at the paths they
+        // conventionally expect rather than under /v1.
+        .resource("/oauth2/authorise", |r| {
+            r.method(http::Method::GET).with_async(oauth2_authorise)
+        })
+        .resource("/oauth2/token", |r| {
+            r.method(http::Method::POST).with_async(oauth2_token)
+        })
+        .resource(
+            "/oauth2/openid/{client_id}/.well-known/openid-configuration",
+            |r| r.method(http::Method::GET).with_async(oauth2_discovery),
+        )
+        .resource("/oauth2/openid/{client_id}/public_key.jwk", |r| {
+            r.method(http::Method::GET).with_async(oauth2_jwks)
+        })
+        .resource("/v1/admin/operations", |r| {
+            r.method(http::Method::GET).with_async(operations_get)
+        })
+        .resource("/v1/admin/operations/{id}/cancel", |r| {
+            r.method(http::Method::POST).with_async(operations_cancel)
+        })
+        .resource("/v1/admin/index_stats", |r| {
+            r.method(http::Method::GET).with_async(index_stats)
+        })
+        .resource("/v1/admin/attr_usage", |r| {
+            r.method(http::Method::GET).with_async(attr_usage)
+        })
+        .resource("/v1/admin/attr_usage/{attr}", |r| {
+            r.method(http::Method::GET).with_async(attr_usage_id)
+        })
+        .resource("/v1/admin/slow_queries", |r| {
+            r.method(http::Method::GET).with_async(slow_queries)
+        })
+        .resource("/v1/admin/read_only", |r| {
+            r.method(http::Method::PUT).with_async(read_only_put)
+        })
+        .resource("/v1/admin/db_pool_stats", |r| {
+            r.method(http::Method::GET).with_async(db_pool_stats)
+        })
+        .resource("/v1/admin/purge_stats", |r| {
+            r.method(http::Method::GET).with_async(purge_stats)
+        })
+        .resource("/v1/admin/write_stats", |r| {
+            r.method(http::Method::GET).with_async(write_stats)
+        })
+        .resource("/v1/admin/access_journal", |r| {
+            r.method(http::Method::GET).with_async(access_journal)
+        })
+        .resource("/v1/admin/verify", |r| {
+            r.method(http::Method::POST).with_async(verify)
+        })
+        .resource("/v1/admin/reindex", |r| {
+            r.method(http::Method::POST).with_async(reindex)
+        })
+        .resource("/v1/admin/vacuum", |r| {
+            r.method(http::Method::POST).with_async(vacuum)
+        })
         // QS rest resources
         .resource("/v1/schema", |r| {
             r.method(http::Method::GET).with_async(schema_get)
@@ -1497,7 +3108,27 @@ pub fn create_server_core(config: Configuration) {
             r.method(http::Method::GET)
                 .with_async(account_get_id_radius_token)
         })
+        // Posix accounts, and how nss and pam on a unix client see them.
+        .resource("/v1/account/{id}/_unix", |r| {
+            r.method(http::Method::POST)
+                .with_async(account_post_id_unix)
+        })
+        .resource("/v1/account/{id}/_unix/_token", |r| {
+            r.method(http::Method::GET)
+                .with_async(account_get_id_unix_token)
+        })
         // People
+        // Deleted entries, until they're made tombstones.
+        .resource("/v1/recycle_bin", |r| {
+            r.method(http::Method::GET).with_async(recycle_bin_get)
+        })
+        .resource("/v1/recycle_bin/{id}", |r| {
+            r.method(http::Method::GET).with_async(recycle_bin_id_get)
+        })
+        .resource("/v1/recycle_bin/{id}/_revive", |r| {
+            r.method(http::Method::POST)
+                .with_async(recycle_bin_id_post_revive)
+        })
         // Groups
         .resource("/v1/group", |r| {
             r.method(http::Method::GET).with_async(group_get);
@@ -1508,6 +3139,14 @@ pub fn create_server_core(config: Configuration) {
             r.method(http::Method::DELETE).with_async(group_id_delete);
             // add put, patch
         })
+        .resource("/v1/group/{id}/_members", |r| {
+            r.method(http::Method::POST)
+                .with_async(group_id_post_members);
+        })
+        .resource("/v1/group/{id}/_request", |r| {
+            r.method(http::Method::POST)
+                .with_async(group_id_post_request);
+        })
         .resource("/v1/group/{id}/_attr/{attr}", |r| {
             r.method(http::Method::GET).with_async(group_id_get_attr);
             r.method(http::Method::POST).with_async(group_id_post_attr);
@@ -1515,18 +3154,48 @@ pub fn create_server_core(config: Configuration) {
             r.method(http::Method::DELETE)
                 .with_async(group_id_delete_attr);
         })
-        // Claims
-        // TBD
-        // Recycle Bin
-        .resource("/v1/recycle_bin", |r| {
-            r.method(http::Method::GET).with(do_nothing)
+        .resource("/v1/group/{id}/_unix", |r| {
+            r.method(http::Method::POST).with_async(group_post_id_unix)
         })
-        .resource("/v1/recycle_bin/{id}", |r| {
-            r.method(http::Method::GET).with(do_nothing)
+        .resource("/v1/group/{id}/_unix/_token", |r| {
+            r.method(http::Method::GET)
+                .with_async(group_get_id_unix_token)
         })
-        .resource("/v1/recycle_bin/{id}/_restore", |r| {
-            r.method(http::Method::POST).with(do_nothing)
+        .resource("/v1/savedsearch", |r| {
+            r.method(http::Method::GET).with_async(savedsearch_get);
+            r.method(http::Method::POST).with_async(savedsearch_post);
         })
+        .resource("/v1/savedsearch/{id}", |r| {
+            r.method(http::Method::GET).with_async(savedsearch_id_get);
+            r.method(http::Method::DELETE)
+                .with_async(savedsearch_id_delete);
+        })
+        .resource("/v1/savedsearch/{id}/_attr/{attr}", |r| {
+            r.method(http::Method::PUT)
+                .with_async(savedsearch_id_put_attr);
+        })
+        .resource("/v1/savedsearch/{id}/_search", |r| {
+            r.method(http::Method::POST)
+                .with_async(savedsearch_id_post_search);
+        })
+        .resource("/v1/savedsearch/{id}/_report", |r| {
+            r.method(http::Method::GET)
+                .with_async(savedsearch_id_get_report);
+        })
+        .resource("/v1/membershiprequest", |r| {
+            r.method(http::Method::GET)
+                .with_async(membershiprequest_get);
+        })
+        .resource("/v1/membershiprequest/{id}/_approve", |r| {
+            r.method(http::Method::POST)
+                .with_async(membershiprequest_id_post_approve);
+        })
+        .resource("/v1/membershiprequest/{id}/_reject", |r| {
+            r.method(http::Method::POST)
+                .with_async(membershiprequest_id_post_reject);
+        })
+        // Claims
+        // TBD
         // ACPs
         .resource("/v1/access_profile", |r| {
             r.method(http::Method::GET).with(do_nothing)
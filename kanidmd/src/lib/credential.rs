@@ -44,7 +44,7 @@ impl TryFrom<DbPasswordV1> for Password {
     fn try_from(value: DbPasswordV1) -> Result<Self, Self::Error> {
         match value {
             DbPasswordV1::PBKDF2(c, s, h) => Ok(Password {
-                material: KDF::PBKDF2(c, s, h),
+                material: KDF::PBKDF2(usize::try_from(c).map_err(|_| ())?, s, h),
             }),
         }
     }
@@ -174,9 +174,11 @@ impl Credential {
         DbCredV1 {
             password: match &self.password {
                 Some(pw) => match &pw.material {
-                    KDF::PBKDF2(cost, salt, hash) => {
-                        Some(DbPasswordV1::PBKDF2(*cost, salt.clone(), hash.clone()))
-                    }
+                    KDF::PBKDF2(cost, salt, hash) => Some(DbPasswordV1::PBKDF2(
+                        *cost as u64,
+                        salt.clone(),
+                        hash.clone(),
+                    )),
                 },
                 None => None,
             },
@@ -1,7 +1,7 @@
 // use serde_json::{Error, Value};
 use crate::audit::AuditScope;
 use crate::credential::Credential;
-use crate::filter::{Filter, FilterInvalid, FilterResolved, FilterValidResolved};
+use crate::filter::{Filter, FilterInvalid, FilterRegex, FilterResolved, FilterValidResolved};
 use crate::modify::{Modify, ModifyInvalid, ModifyList, ModifyValid};
 use crate::schema::{SchemaAttribute, SchemaClass, SchemaTransaction};
 use crate::server::{
@@ -15,6 +15,7 @@ use kanidm_proto::v1::{OperationError, SchemaError};
 
 use crate::be::dbentry::{DbEntry, DbEntryV1, DbEntryVers};
 
+use std::cmp::Ordering;
 use std::collections::btree_map::{Iter as BTreeIter, IterMut as BTreeIterMut};
 use std::collections::btree_set::Iter as BTreeSetIter;
 use std::collections::BTreeMap;
@@ -181,7 +182,6 @@ impl<STATE> std::fmt::Display for Entry<EntryValid, STATE> {
 }
 
 impl Entry<EntryInvalid, EntryNew> {
-    #[cfg(test)]
     pub fn new() -> Self {
         Entry {
             // This means NEVER COMMITED
@@ -207,20 +207,18 @@ impl Entry<EntryInvalid, EntryNew> {
 
         // Somehow we need to take the tree of e attrs, and convert
         // all ref types to our types ...
-        let map2: Result<BTreeMap<String, BTreeSet<Value>>, OperationError> = e
-            .attrs
-            .iter()
-            .map(|(k, v)| {
-                let nv: Result<BTreeSet<Value>, _> =
-                    v.iter().map(|vr| qs.clone_value(audit, &k, vr)).collect();
-                match nv {
-                    Ok(nvi) => Ok((k.clone(), nvi)),
-                    Err(e) => Err(e),
-                }
-            })
-            .collect();
-
-        let x = map2?;
+        //
+        // Names are normalised here, so a client still using an attribute's
+        // old name (or both names at once) ends up with the current one.
+        let schema = qs.get_schema();
+        let mut x: BTreeMap<String, BTreeSet<Value>> = BTreeMap::new();
+        for (k, v) in e.attrs.iter() {
+            let nv: Result<BTreeSet<Value>, _> =
+                v.iter().map(|vr| qs.clone_value(audit, &k, vr)).collect();
+            x.entry(schema.normalise_attr_name(k))
+                .or_insert_with(BTreeSet::new)
+                .extend(nv?);
+        }
 
         Ok(Entry {
             // For now, we do a straight move, and we sort the incoming data
@@ -317,9 +315,17 @@ impl Entry<EntryInvalid, EntryNew> {
                             })
                         ).collect()
                     }
-                    "displayname" | "description" => {
+                    "displayname" | "description" | "loginshell" | "homedirectory" => {
                         vs.into_iter().map(|v| Value::new_utf8(v)).collect()
                     }
+                    "radius_vlan" | "gidnumber" => {
+                        vs.into_iter().map(|v| Value::new_uint32_str(v.as_str())
+                            .unwrap_or_else(|| {
+                                warn!("WARNING: Allowing syntax incorrect attribute to be presented UTF8 string");
+                                Value::new_utf8(v)
+                            })
+                        ).collect()
+                    }
                     ia => {
                         warn!("WARNING: Allowing invalid attribute {} to be interpretted as UTF8 string. YOU MAY ENCOUNTER ODD BEHAVIOUR!!!", ia);
                         vs.into_iter().map(|v| Value::new_utf8(v)).collect()
@@ -705,6 +711,19 @@ impl Entry<EntryValid, EntryNew> {
     }
 }
 
+// The index keys of a set of values for the index types where values can
+// share keys, as they do trigrams for substring and word sounds for fuzzy.
+fn idx_shared_keys<'a, I: Iterator<Item = &'a Value>>(
+    itype: &IndexType,
+    vs: I,
+) -> BTreeSet<String> {
+    match itype {
+        IndexType::SUBSTRING => vs.flat_map(|v| v.generate_idx_sub_keys()).collect(),
+        IndexType::FUZZY => vs.flat_map(|v| v.generate_idx_fuzzy_keys()).collect(),
+        _ => BTreeSet::new(),
+    }
+}
+
 impl Entry<EntryValid, EntryCommitted> {
     #[cfg(test)]
     pub unsafe fn to_valid_committed(self) -> Entry<EntryValid, EntryCommitted> {
@@ -764,7 +783,7 @@ impl Entry<EntryValid, EntryCommitted> {
                             None => Vec::new(),
                             Some(vs) => {
                                 let changes: Vec<Result<_, _>> = match itype {
-                                    IndexType::EQUALITY => {
+                                    IndexType::EQUALITY | IndexType::ORDERING => {
                                         vs.iter()
                                             .flat_map(|v| {
                                                 // Turn each idx_key to the tuple of
@@ -778,7 +797,12 @@ impl Entry<EntryValid, EntryCommitted> {
                                     IndexType::PRESENCE => {
                                         vec![Err((attr, itype, "_".to_string()))]
                                     }
-                                    IndexType::SUBSTRING => Vec::new(),
+                                    IndexType::SUBSTRING | IndexType::FUZZY => {
+                                        idx_shared_keys(itype, vs.iter().cloned())
+                                            .into_iter()
+                                            .map(|idx_key| Err((attr, itype, idx_key)))
+                                            .collect()
+                                    }
                                 };
                                 changes
                             }
@@ -795,7 +819,7 @@ impl Entry<EntryValid, EntryCommitted> {
                             None => Vec::new(),
                             Some(vs) => {
                                 let changes: Vec<Result<_, _>> = match itype {
-                                    IndexType::EQUALITY => {
+                                    IndexType::EQUALITY | IndexType::ORDERING => {
                                         vs.iter()
                                             .flat_map(|v| {
                                                 // Turn each idx_key to the tuple of
@@ -807,7 +831,12 @@ impl Entry<EntryValid, EntryCommitted> {
                                             .collect()
                                     }
                                     IndexType::PRESENCE => vec![Ok((attr, itype, "_".to_string()))],
-                                    IndexType::SUBSTRING => Vec::new(),
+                                    IndexType::SUBSTRING | IndexType::FUZZY => {
+                                        idx_shared_keys(itype, vs.iter().cloned())
+                                            .into_iter()
+                                            .map(|idx_key| Ok((attr, itype, idx_key)))
+                                            .collect()
+                                    }
                                 };
                                 // For each value
                                 //
@@ -833,7 +862,7 @@ impl Entry<EntryValid, EntryCommitted> {
                             (Some(pre_vs), None) => {
                                 // It existed before, but not anymore
                                 let changes: Vec<Result<_, _>> = match itype {
-                                    IndexType::EQUALITY => {
+                                    IndexType::EQUALITY | IndexType::ORDERING => {
                                         pre_vs
                                             .iter()
                                             .flat_map(|v| {
@@ -848,14 +877,19 @@ impl Entry<EntryValid, EntryCommitted> {
                                     IndexType::PRESENCE => {
                                         vec![Err((attr, itype, "_".to_string()))]
                                     }
-                                    IndexType::SUBSTRING => Vec::new(),
+                                    IndexType::SUBSTRING | IndexType::FUZZY => {
+                                        idx_shared_keys(itype, pre_vs.iter().cloned())
+                                            .into_iter()
+                                            .map(|idx_key| Err((attr, itype, idx_key)))
+                                            .collect()
+                                    }
                                 };
                                 changes
                             }
                             (None, Some(post_vs)) => {
                                 // It was added now.
                                 let changes: Vec<Result<_, _>> = match itype {
-                                    IndexType::EQUALITY => {
+                                    IndexType::EQUALITY | IndexType::ORDERING => {
                                         post_vs
                                             .iter()
                                             .flat_map(|v| {
@@ -868,10 +902,32 @@ impl Entry<EntryValid, EntryCommitted> {
                                             .collect()
                                     }
                                     IndexType::PRESENCE => vec![Ok((attr, itype, "_".to_string()))],
-                                    IndexType::SUBSTRING => Vec::new(),
+                                    IndexType::SUBSTRING | IndexType::FUZZY => {
+                                        idx_shared_keys(itype, post_vs.iter().cloned())
+                                            .into_iter()
+                                            .map(|idx_key| Ok((attr, itype, idx_key)))
+                                            .collect()
+                                    }
                                 };
                                 changes
                             }
+                            (Some(pre_vs), Some(post_vs))
+                                if *itype == IndexType::SUBSTRING || *itype == IndexType::FUZZY =>
+                            {
+                                // Values share keys, so a key only goes once no
+                                // value has it, rather than with any value that did.
+                                let pre_keys = idx_shared_keys(itype, pre_vs.iter().cloned());
+                                let post_keys = idx_shared_keys(itype, post_vs.iter().cloned());
+                                pre_keys
+                                    .difference(&post_keys)
+                                    .map(|idx_key| Err((attr, itype, idx_key.clone())))
+                                    .chain(
+                                        post_keys
+                                            .difference(&pre_keys)
+                                            .map(|idx_key| Ok((attr, itype, idx_key.clone()))),
+                                    )
+                                    .collect()
+                            }
                             (Some(pre_vs), Some(post_vs)) => {
                                 // it exists in both, we need to work out the differents within the attr.
                                 pre_vs
@@ -879,7 +935,7 @@ impl Entry<EntryValid, EntryCommitted> {
                                     .map(|pre_v| {
                                         // Was in pre, now not in post
                                         match itype {
-                                            IndexType::EQUALITY => {
+                                            IndexType::EQUALITY | IndexType::ORDERING => {
                                                 // Remove the v
                                                 pre_v
                                                     .generate_idx_eq_keys()
@@ -891,13 +947,14 @@ impl Entry<EntryValid, EntryCommitted> {
                                                 // No action - we still are "present", so nothing to do!
                                                 Vec::new()
                                             }
-                                            IndexType::SUBSTRING => Vec::new(),
+                                            // Diffed as a whole above.
+                                            IndexType::SUBSTRING | IndexType::FUZZY => Vec::new(),
                                         }
                                     })
                                     .chain(post_vs.difference(&pre_vs).map(|post_v| {
                                         // is in post, but not in pre (add)
                                         match itype {
-                                            IndexType::EQUALITY => {
+                                            IndexType::EQUALITY | IndexType::ORDERING => {
                                                 // Remove the v
                                                 post_v
                                                     .generate_idx_eq_keys()
@@ -909,7 +966,8 @@ impl Entry<EntryValid, EntryCommitted> {
                                                 // No action - we still are "present", so nothing to do!
                                                 Vec::new()
                                             }
-                                            IndexType::SUBSTRING => Vec::new(),
+                                            // Diffed as a whole above.
+                                            IndexType::SUBSTRING | IndexType::FUZZY => Vec::new(),
                                         }
                                     }))
                                     .flatten() // flatten all the inner vecs
@@ -1127,6 +1185,10 @@ impl Entry<EntryReduced, EntryCommitted> {
         &self.valid.uuid
     }
 
+    pub fn get_id(&self) -> u64 {
+        self.state.id
+    }
+
     pub fn into_pe(
         &self,
         audit: &mut AuditScope,
@@ -1229,6 +1291,10 @@ impl<VALID, STATE> Entry<VALID, STATE> {
         }
     }
 
+    pub fn get_ava_single_uuid(&self, attr: &str) -> Option<&Uuid> {
+        self.get_ava_single(attr).and_then(|v| v.to_uuid())
+    }
+
     /// Get a bool from an ava
     pub fn get_ava_single_bool(&self, attr: &str) -> Option<bool> {
         match self.get_ava_single(attr) {
@@ -1237,6 +1303,10 @@ impl<VALID, STATE> Entry<VALID, STATE> {
         }
     }
 
+    pub fn get_ava_single_uint32(&self, attr: &str) -> Option<u32> {
+        self.get_ava_single(attr).and_then(|v| v.to_uint32())
+    }
+
     pub fn get_ava_single_syntax(&self, attr: &str) -> Option<&SyntaxType> {
         match self.get_ava_single(attr) {
             Some(a) => a.to_syntaxtype(),
@@ -1351,6 +1421,28 @@ impl<VALID, STATE> Entry<VALID, STATE> {
         self.attrs.contains_key(attr)
     }
 
+    // Move anything still stored under an alias that is being migrated to
+    // the attribute's current name, so nothing above the backend has to know
+    // the old name.
+    pub fn rename_aliases(&mut self, schema: &dyn SchemaTransaction) {
+        for (alias, name, _) in schema.get_alias_migrations() {
+            self.rename_ava(alias.as_str(), name.as_str());
+        }
+    }
+
+    pub(crate) fn rename_ava(&mut self, from: &str, to: &str) -> bool {
+        match self.attrs.remove(from) {
+            Some(vs) => {
+                self.attrs
+                    .entry(to.to_string())
+                    .or_insert_with(BTreeSet::new)
+                    .extend(vs);
+                true
+            }
+            None => false,
+        }
+    }
+
     #[inline]
     pub fn attribute_value_pres(&self, attr: &str, value: &PartialValue) -> bool {
         // Yeah, this is techdebt, but both names of this fn are valid - we are
@@ -1379,6 +1471,51 @@ impl<VALID, STATE> Entry<VALID, STATE> {
         }
     }
 
+    // Whether a single value of attr has every word of value, going by how
+    // the words sound. Words can't be matched across values, as then "john"
+    // in one name and "smith" in another would match "john smith".
+    pub fn attribute_fuzzy(&self, attr: &str, value: &PartialValue) -> bool {
+        let keys = value.get_idx_fuzzy_keys();
+        if keys.is_empty() {
+            return false;
+        }
+        match self.attrs.get(attr) {
+            Some(v_list) => v_list.iter().any(|v| {
+                let v_keys = v.generate_idx_fuzzy_keys();
+                keys.iter().all(|k| v_keys.contains(k))
+            }),
+            None => false,
+        }
+    }
+
+    // Whether any value of attr is at least (Greater), or at most (Less),
+    // value. This compares index keys, as an ordering index does, so that
+    // the index and the filter test always agree.
+    pub fn attribute_ordering(&self, attr: &str, value: &PartialValue, ord: Ordering) -> bool {
+        let key = value.get_idx_eq_key();
+        match self.attrs.get(attr) {
+            Some(v_list) => v_list.iter().any(|v| {
+                v.generate_idx_eq_keys()
+                    .iter()
+                    .any(|k| k.as_str().cmp(key.as_str()) != ord.reverse())
+            }),
+            None => false,
+        }
+    }
+
+    // Whether any value of attr matches the regex, tested against the index
+    // keys so values are seen the same way as by the other filter terms.
+    pub fn attribute_regex(&self, attr: &str, r: &FilterRegex) -> bool {
+        match self.attrs.get(attr) {
+            Some(v_list) => v_list.iter().any(|v| {
+                v.generate_idx_eq_keys()
+                    .iter()
+                    .any(|k| r.is_match(k.as_str()))
+            }),
+            None => false,
+        }
+    }
+
     pub fn classes(&self) -> Option<EntryClasses> {
         // Get the class vec, if any?
         // How do we indicate "empty?"
@@ -1412,6 +1549,14 @@ impl<VALID, STATE> Entry<VALID, STATE> {
             FilterResolved::Sub(attr, subvalue, _) => {
                 self.attribute_substring(attr.as_str(), subvalue)
             }
+            FilterResolved::Ge(attr, value, _) => {
+                self.attribute_ordering(attr.as_str(), value, Ordering::Greater)
+            }
+            FilterResolved::Le(attr, value, _) => {
+                self.attribute_ordering(attr.as_str(), value, Ordering::Less)
+            }
+            FilterResolved::Fuzzy(attr, value, _) => self.attribute_fuzzy(attr.as_str(), value),
+            FilterResolved::Regex(attr, r) => self.attribute_regex(attr.as_str(), r),
             FilterResolved::Pres(attr, _) => {
                 // Given attr, is is present in the entry?
                 self.attribute_pres(attr.as_str())
@@ -1531,6 +1676,7 @@ impl From<&SchemaAttribute> for Entry<EntryValid, EntryNew> {
 
         let multivalue_v = btreeset![Value::from(s.multivalue)];
         let unique_v = btreeset![Value::from(s.unique)];
+        let operational_v = btreeset![Value::from(s.operational)];
 
         let index_v: BTreeSet<_> = s.index.iter().map(|i| Value::from(i.clone())).collect();
 
@@ -1543,6 +1689,13 @@ impl From<&SchemaAttribute> for Entry<EntryValid, EntryNew> {
         attrs.insert("uuid".to_string(), uuid_v);
         attrs.insert("multivalue".to_string(), multivalue_v);
         attrs.insert("unique".to_string(), unique_v);
+        attrs.insert("operational".to_string(), operational_v);
+        if !s.aliases.is_empty() {
+            attrs.insert(
+                "attributealias".to_string(),
+                s.aliases.iter().map(|a| Value::new_iutf8s(a)).collect(),
+            );
+        }
         attrs.insert("index".to_string(), index_v);
         attrs.insert("syntax".to_string(), syntax_v);
         attrs.insert(
@@ -1,22 +1,30 @@
 use crate::audit::AuditScope;
+use crate::be::SortSpec;
+use crate::constants::UUID_ANONYMOUS;
 use crate::entry::{Entry, EntryCommitted, EntryInvalid, EntryNew, EntryReduced, EntryValid};
 use crate::filter::{Filter, FilterInvalid, FilterValid};
 use crate::schema::SchemaTransaction;
 use crate::value::PartialValue;
 use kanidm_proto::v1::Entry as ProtoEntry;
+use kanidm_proto::v1::Filter as ProtoFilter;
 use kanidm_proto::v1::ModifyList as ProtoModifyList;
 use kanidm_proto::v1::{
-    AuthCredential, AuthResponse, AuthState, AuthStep, SearchResponse, UserAuthToken,
+    AuthCredential, AuthResponse, AuthState, AuthStep, ReplEntry, SearchResponse, UserAuthToken,
     WhoamiResponse,
 };
 // use error::OperationError;
 use crate::modify::{ModifyList, ModifyValid};
+use crate::repl::MergeStats;
 use crate::server::{
     QueryServerReadTransaction, QueryServerTransaction, QueryServerWriteTransaction,
 };
 use kanidm_proto::v1::OperationError;
+use std::time::SystemTime;
 
-use crate::actors::v1_read::{AuthMessage, InternalSearchMessage, SearchMessage};
+use crate::actors::v1_read::{
+    AuthMessage, ChangesMessage, CompareMessage, InternalSearchMessage, ReplSupplyMessage,
+    SearchMessage,
+};
 use crate::actors::v1_write::{CreateMessage, DeleteMessage, ModifyMessage};
 // Bring in schematransaction trait for validate
 // use crate::schema::SchemaTransaction;
@@ -32,6 +40,7 @@ use uuid::Uuid;
 #[derive(Debug)]
 pub struct SearchResult {
     entries: Vec<ProtoEntry>,
+    next_cursor: Option<String>,
 }
 
 impl SearchResult {
@@ -49,13 +58,23 @@ impl SearchResult {
                 e.into_pe(audit, qs)
             })
             .collect();
-        Ok(SearchResult { entries: entries? })
+        Ok(SearchResult {
+            entries: entries?,
+            next_cursor: None,
+        })
+    }
+
+    // For a paged search, where the next page is asked for with.
+    pub fn with_next_cursor(mut self, next_cursor: Option<String>) -> Self {
+        self.next_cursor = next_cursor;
+        self
     }
 
     // Consume self into a search response
     pub fn response(self) -> SearchResponse {
         SearchResponse {
             entries: self.entries,
+            next_cursor: self.next_cursor,
         }
     }
 
@@ -84,6 +103,17 @@ pub struct Event {
     // The event's initiator aka origin source.
     // This importantly, is used for access control!
     pub origin: EventOrigin,
+    // If the user authenticated recently enough to make sensitive changes.
+    pub elevated: bool,
+}
+
+// Whether the session uat came from may still make sensitive changes.
+pub(crate) fn uat_is_elevated(uat: &UserAuthToken) -> bool {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    uat.is_elevated(now)
 }
 
 impl Event {
@@ -105,6 +135,7 @@ impl Event {
 
         Ok(Event {
             origin: EventOrigin::User(e),
+            elevated: false,
         })
     }
 
@@ -126,6 +157,7 @@ impl Event {
 
         Ok(Event {
             origin: EventOrigin::User(e),
+            elevated: uat_is_elevated(&uat),
         })
     }
 
@@ -147,6 +179,7 @@ impl Event {
 
         Ok(Event {
             origin: EventOrigin::User(e),
+            elevated: uat_is_elevated(&uat),
         })
     }
 
@@ -167,12 +200,14 @@ impl Event {
 
         Ok(Event {
             origin: EventOrigin::User(e),
+            elevated: false,
         })
     }
 
     pub fn from_internal() -> Self {
         Event {
             origin: EventOrigin::Internal,
+            elevated: true,
         }
     }
 
@@ -180,6 +215,7 @@ impl Event {
     pub fn from_impersonate_entry(e: Entry<EntryValid, EntryCommitted>) -> Self {
         Event {
             origin: EventOrigin::User(e),
+            elevated: false,
         }
     }
 
@@ -210,6 +246,19 @@ impl Event {
             EventOrigin::User(e) => Some(e.get_uuid()),
         }
     }
+
+    pub fn is_anonymous(&self) -> bool {
+        self.get_uuid() == Some(&UUID_ANONYMOUS)
+    }
+
+    pub fn is_memberof(&self, group: &Uuid) -> bool {
+        match &self.origin {
+            EventOrigin::Internal => false,
+            EventOrigin::User(e) => {
+                e.attribute_value_pres("memberof", &PartialValue::new_refer_r(group))
+            }
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -220,6 +269,8 @@ pub struct SearchEvent {
     // This is the original filter, for the purpose of ACI checking.
     pub filter_orig: Filter<FilterValid>,
     pub attrs: Option<BTreeSet<String>>,
+    // The order to return the entries in, otherwise by id.
+    pub sort: Option<SortSpec>,
 }
 
 impl SearchEvent {
@@ -228,6 +279,36 @@ impl SearchEvent {
         msg: SearchMessage,
         qs: &QueryServerReadTransaction,
     ) -> Result<Self, OperationError> {
+        let sort = match &msg.req.sort {
+            Some(s) => match qs.get_schema().normalise_attr_if_exists(s.attr.as_str()) {
+                Some(attr) => Some(SortSpec::new(attr.as_str(), s.descending)),
+                None => return Err(OperationError::InvalidAttributeName(s.attr.clone())),
+            },
+            None => None,
+        };
+        // Unlike internal searches, naming an attribute the schema doesn't
+        // have is an error, so a typo doesn't quietly return nothing.
+        let attrs = match &msg.req.attrs {
+            Some(vs) => {
+                let mut attrs = BTreeSet::new();
+                for a in vs.iter() {
+                    match qs.get_schema().normalise_attr_if_exists(a.as_str()) {
+                        Some(a) => attrs.insert(a),
+                        None => return Err(OperationError::InvalidAttributeName(a.clone())),
+                    };
+                }
+                if attrs.is_empty() {
+                    return Err(OperationError::EmptyRequest);
+                }
+                // Otherwise entries whose value is hidden can't be told from
+                // those without one, so their place would make no sense.
+                if let Some(s) = &sort {
+                    attrs.insert(s.attr.clone());
+                }
+                Some(attrs)
+            }
+            None => None,
+        };
         match Filter::from_ro(audit, &msg.req.filter, qs) {
             Ok(f) => Ok(SearchEvent {
                 event: Event::from_ro_uat(audit, qs, msg.uat)?,
@@ -241,9 +322,8 @@ impl SearchEvent {
                 filter_orig: f
                     .validate(qs.get_schema())
                     .map_err(|e| OperationError::SchemaViolation(e))?,
-                // We can't get this from the SearchMessage because it's annoying with the
-                // current macro design.
-                attrs: None,
+                attrs: attrs,
+                sort: sort,
             }),
             Err(e) => Err(e),
         }
@@ -284,6 +364,7 @@ impl SearchEvent {
                 .validate(qs.get_schema())
                 .map_err(|e| OperationError::SchemaViolation(e))?,
             attrs: r_attrs,
+            sort: None,
         })
     }
 
@@ -302,6 +383,7 @@ impl SearchEvent {
                 .map_err(|e| OperationError::SchemaViolation(e))?,
             // TODO: Should we limit this?
             attrs: None,
+            sort: None,
         })
     }
 
@@ -320,6 +402,7 @@ impl SearchEvent {
                 .validate(qs.get_schema())
                 .map_err(|e| OperationError::SchemaViolation(e))?,
             attrs: None,
+            sort: None,
         })
     }
 
@@ -331,6 +414,7 @@ impl SearchEvent {
             filter: filter.clone().to_valid(),
             filter_orig: filter.to_valid(),
             attrs: None,
+            sort: None,
         }
     }
 
@@ -344,6 +428,7 @@ impl SearchEvent {
             filter: filter.clone().to_valid(),
             filter_orig: filter.to_valid(),
             attrs: None,
+            sort: None,
         }
     }
 
@@ -357,33 +442,31 @@ impl SearchEvent {
             filter: filter,
             filter_orig: filter_orig,
             attrs: None,
+            sort: None,
         }
     }
 
-    /*
-    #[cfg(test)]
-    #[allow(dead_code)]
-    pub fn from_rec_request(
+    // As from_internal_message, but of the recycle bin.
+    pub fn from_rec_parts(
         audit: &mut AuditScope,
-        request: SearchRecycledRequest,
+        uat: Option<UserAuthToken>,
+        filter: Filter<FilterInvalid>,
         qs: &QueryServerReadTransaction,
     ) -> Result<Self, OperationError> {
-        match Filter::from_ro(audit, &request.filter, qs) {
-            Ok(f) => Ok(SearchEvent {
-                event: Event::from_ro_uat(audit, qs, msg.uat)?,
-                filter: f
-                    .clone()
-                    .to_recycled()
-                    .validate(qs.get_schema())
-                    .map_err(|e| OperationError::SchemaViolation(e))?,
-                filter_orig: f
-                    .validate(qs.get_schema())
-                    .map_err(|e| OperationError::SchemaViolation(e))?,
-            }),
-            Err(e) => Err(e),
-        }
+        Ok(SearchEvent {
+            event: Event::from_ro_uat(audit, qs, uat)?,
+            filter: filter
+                .clone()
+                .to_recycled()
+                .validate(qs.get_schema())
+                .map_err(|e| OperationError::SchemaViolation(e))?,
+            filter_orig: filter
+                .validate(qs.get_schema())
+                .map_err(|e| OperationError::SchemaViolation(e))?,
+            attrs: None,
+            sort: None,
+        })
     }
-    */
 
     #[cfg(test)]
     /* Impersonate a request for recycled objects */
@@ -396,6 +479,7 @@ impl SearchEvent {
             filter: filter.clone().to_recycled().to_valid(),
             filter_orig: filter.to_valid(),
             attrs: None,
+            sort: None,
         }
     }
 
@@ -410,6 +494,7 @@ impl SearchEvent {
             filter: filter.clone().to_ignore_hidden().to_valid(),
             filter_orig: filter.to_valid(),
             attrs: None,
+            sort: None,
         }
     }
 
@@ -420,6 +505,7 @@ impl SearchEvent {
             filter: filter.clone().to_valid(),
             filter_orig: filter.to_valid(),
             attrs: None,
+            sort: None,
         }
     }
 
@@ -429,6 +515,7 @@ impl SearchEvent {
             filter: filter.clone(),
             filter_orig: filter,
             attrs: None,
+            sort: None,
         }
     }
 }
@@ -490,6 +577,140 @@ impl CreateEvent {
     }
 }
 
+#[derive(Debug)]
+pub struct ChangesEvent {
+    pub event: Event,
+    // The change sequence number the client last saw, 0 for everything.
+    pub since: i64,
+}
+
+impl ChangesEvent {
+    pub fn from_message(
+        audit: &mut AuditScope,
+        msg: ChangesMessage,
+        qs: &QueryServerReadTransaction,
+    ) -> Result<Self, OperationError> {
+        let since = match msg.req.cookie {
+            None => 0,
+            Some(c) => try_audit!(
+                audit,
+                c.parse::<i64>(),
+                "invalid changes cookie {:?}",
+                OperationError::InvalidRequestState
+            ),
+        };
+        Ok(ChangesEvent {
+            event: Event::from_ro_uat(audit, qs, msg.uat)?,
+            since: since,
+        })
+    }
+
+    #[cfg(test)]
+    pub unsafe fn new_impersonate_entry(e: Entry<EntryValid, EntryCommitted>, since: i64) -> Self {
+        ChangesEvent {
+            event: Event::from_impersonate_entry(e),
+            since: since,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ReplSupplyEvent {
+    pub event: Event,
+    // The change sequence number the partner last saw, 0 for everything.
+    pub since: i64,
+}
+
+impl ReplSupplyEvent {
+    pub fn from_message(
+        audit: &mut AuditScope,
+        msg: ReplSupplyMessage,
+        qs: &QueryServerReadTransaction,
+    ) -> Result<Self, OperationError> {
+        let since = match msg.req.cookie {
+            None => 0,
+            Some(c) => try_audit!(
+                audit,
+                c.parse::<i64>(),
+                "invalid replication cookie {:?}",
+                OperationError::InvalidRequestState
+            ),
+        };
+        Ok(ReplSupplyEvent {
+            event: Event::from_ro_uat(audit, qs, msg.uat)?,
+            since: since,
+        })
+    }
+
+    #[cfg(test)]
+    pub unsafe fn new_impersonate_entry(e: Entry<EntryValid, EntryCommitted>, since: i64) -> Self {
+        ReplSupplyEvent {
+            event: Event::from_impersonate_entry(e),
+            since: since,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct CompareEvent {
+    pub event: Event,
+    pub target: Uuid,
+    // The assertion, as a filter that can only match the target.
+    pub filter: Filter<FilterValid>,
+    // Only the asserted attribute, for the purpose of ACI checking.
+    pub filter_orig: Filter<FilterValid>,
+}
+
+impl CompareEvent {
+    pub fn from_message(
+        audit: &mut AuditScope,
+        msg: CompareMessage,
+        qs: &QueryServerReadTransaction,
+    ) -> Result<Self, OperationError> {
+        let target = match Uuid::parse_str(msg.req.target.as_str()) {
+            Ok(u) => u,
+            Err(_) => qs.name_to_uuid(audit, msg.req.target.as_str())?,
+        };
+        // Going via the proto filter means the value is normalised by schema
+        // exactly as it would be in a search.
+        let pf_assert = ProtoFilter::Eq(msg.req.attr, msg.req.value);
+        let pf = ProtoFilter::And(vec![
+            ProtoFilter::Eq("uuid".to_string(), target.to_hyphenated_ref().to_string()),
+            pf_assert.clone(),
+        ]);
+        Ok(CompareEvent {
+            event: Event::from_ro_uat(audit, qs, msg.uat)?,
+            target: target,
+            filter: Filter::from_ro(audit, &pf, qs)?
+                .to_ignore_hidden()
+                .validate(qs.get_schema())
+                .map_err(|e| OperationError::SchemaViolation(e))?,
+            filter_orig: Filter::from_ro(audit, &pf_assert, qs)?
+                .validate(qs.get_schema())
+                .map_err(|e| OperationError::SchemaViolation(e))?,
+        })
+    }
+
+    #[cfg(test)]
+    pub unsafe fn new_impersonate_entry(
+        e: Entry<EntryValid, EntryCommitted>,
+        target: Uuid,
+        attr: &str,
+        value: PartialValue,
+    ) -> Self {
+        CompareEvent {
+            event: Event::from_impersonate_entry(e),
+            filter: filter!(f_and!([
+                f_eq("uuid", PartialValue::new_uuidr(&target)),
+                f_eq(attr, value.clone())
+            ]))
+            .to_valid(),
+            filter_orig: filter!(f_eq(attr, value)).to_valid(),
+            target: target,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct ExistsEvent {
     pub event: Event,
@@ -956,6 +1177,53 @@ impl PurgeTombstoneEvent {
     }
 }
 
+#[derive(Debug)]
+pub struct DbHeartbeatEvent;
+
+impl Message for DbHeartbeatEvent {
+    type Result = ();
+}
+
+#[derive(Debug)]
+pub struct DbAnalyzeEvent;
+
+impl Message for DbAnalyzeEvent {
+    type Result = ();
+}
+
+#[derive(Debug)]
+pub struct MigrateAliasesEvent;
+
+impl Message for MigrateAliasesEvent {
+    type Result = ();
+}
+
+#[derive(Debug)]
+pub struct RunReportsEvent;
+
+impl Message for RunReportsEvent {
+    type Result = ();
+}
+
+#[derive(Debug)]
+pub struct OnlineBackupEvent {
+    pub path: String,
+    pub versions: usize,
+}
+
+impl OnlineBackupEvent {
+    pub fn new(path: String, versions: usize) -> Self {
+        OnlineBackupEvent {
+            path: path,
+            versions: versions,
+        }
+    }
+}
+
+impl Message for OnlineBackupEvent {
+    type Result = ();
+}
+
 #[derive(Debug)]
 pub struct PurgeRecycledEvent {
     pub event: Event,
@@ -973,6 +1241,27 @@ impl PurgeRecycledEvent {
     }
 }
 
+// What a replication partner supplied, to be merged in. The partner is only
+// for the logs.
+#[derive(Debug)]
+pub struct ReplApplyEvent {
+    pub partner: String,
+    pub entries: Vec<ReplEntry>,
+}
+
+impl Message for ReplApplyEvent {
+    type Result = Result<MergeStats, OperationError>;
+}
+
+impl ReplApplyEvent {
+    pub fn new(partner: &str, entries: Vec<ReplEntry>) -> Self {
+        ReplApplyEvent {
+            partner: partner.to_string(),
+            entries: entries,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct ReviveRecycledEvent {
     pub event: Event,
@@ -989,24 +1278,20 @@ impl Message for ReviveRecycledEvent {
 }
 
 impl ReviveRecycledEvent {
-    /*
-    pub fn from_message(
+    pub fn from_parts(
         audit: &mut AuditScope,
-        msg: ReviveRecycledMessage,
+        uat: Option<UserAuthToken>,
+        filter: Filter<FilterInvalid>,
         qs: &QueryServerWriteTransaction,
     ) -> Result<Self, OperationError> {
-        match Filter::from_rw(audit, &msg.req.filter, qs) {
-            Ok(f) => Ok(ReviveRecycledEvent {
-                event: Event::from_rw_uat(audit, qs, msg.uat)?,
-                filter: f
-                    .to_recycled()
-                    .validate(qs.get_schema())
-                    .map_err(|e| OperationError::SchemaViolation(e))?,
-            }),
-            Err(e) => Err(e),
-        }
+        Ok(ReviveRecycledEvent {
+            event: Event::from_rw_uat(audit, qs, uat)?,
+            filter: filter
+                .to_recycled()
+                .validate(qs.get_schema())
+                .map_err(|e| OperationError::SchemaViolation(e))?,
+        })
     }
-    */
 
     #[cfg(test)]
     pub unsafe fn new_impersonate_entry(
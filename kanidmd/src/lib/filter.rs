@@ -11,6 +11,7 @@ use crate::server::{
 use crate::value::{IndexType, PartialValue};
 use kanidm_proto::v1::Filter as ProtoFilter;
 use kanidm_proto::v1::{OperationError, SchemaError};
+use regex::{Regex, RegexBuilder};
 use std::cmp::{Ordering, PartialOrd};
 use std::collections::BTreeSet;
 
@@ -30,6 +31,21 @@ pub fn f_sub<'a>(a: &'a str, v: PartialValue) -> FC<'a> {
     FC::Sub(a, v)
 }
 
+#[allow(dead_code)]
+pub fn f_fuzzy<'a>(a: &'a str, v: PartialValue) -> FC<'a> {
+    FC::Fuzzy(a, v)
+}
+
+#[allow(dead_code)]
+pub fn f_ge<'a>(a: &'a str, v: PartialValue) -> FC<'a> {
+    FC::Ge(a, v)
+}
+
+#[allow(dead_code)]
+pub fn f_le<'a>(a: &'a str, v: PartialValue) -> FC<'a> {
+    FC::Le(a, v)
+}
+
 #[allow(dead_code)]
 pub fn f_pres<'a>(a: &'a str) -> FC<'a> {
     FC::Pres(a)
@@ -65,12 +81,57 @@ pub fn f_id(id: &str) -> FC<'static> {
     }
 }
 
+// Limits on what a regex filter may compile to. The regex crate never
+// backtracks, so matching is linear in the value length, and these bound the
+// memory a client can make us spend on the pattern itself.
+const REGEX_SIZE_LIMIT: usize = 1 << 16;
+const REGEX_DFA_SIZE_LIMIT: usize = 1 << 18;
+const REGEX_NEST_LIMIT: u32 = 16;
+
+// A compiled regex filter term, compared by the pattern it came from.
+#[derive(Debug, Clone)]
+pub struct FilterRegex {
+    re: Regex,
+}
+
+impl FilterRegex {
+    pub fn new(pattern: &str) -> Result<Self, OperationError> {
+        RegexBuilder::new(pattern)
+            .size_limit(REGEX_SIZE_LIMIT)
+            .dfa_size_limit(REGEX_DFA_SIZE_LIMIT)
+            .nest_limit(REGEX_NEST_LIMIT)
+            .build()
+            .map(|re| FilterRegex { re: re })
+            .map_err(|e| match e {
+                regex::Error::CompiledTooBig(_) => OperationError::ResourceLimit,
+                _ => OperationError::SchemaViolation(SchemaError::InvalidAttributeSyntax),
+            })
+    }
+
+    pub fn as_str(&self) -> &str {
+        self.re.as_str()
+    }
+
+    pub fn is_match(&self, value: &str) -> bool {
+        self.re.is_match(value)
+    }
+}
+
+impl PartialEq for FilterRegex {
+    fn eq(&self, rhs: &FilterRegex) -> bool {
+        self.as_str() == rhs.as_str()
+    }
+}
+
 // This is the short-form for tests and internal filters that can then
 // be transformed into a filter for the server to use.
 #[derive(Debug, Deserialize)]
 pub enum FC<'a> {
     Eq(&'a str, PartialValue),
     Sub(&'a str, PartialValue),
+    Fuzzy(&'a str, PartialValue),
+    Ge(&'a str, PartialValue),
+    Le(&'a str, PartialValue),
     Pres(&'a str),
     Or(Vec<FC<'a>>),
     And(Vec<FC<'a>>),
@@ -85,6 +146,14 @@ enum FilterComp {
     // This is attr - value
     Eq(String, PartialValue),
     Sub(String, PartialValue),
+    // Some value of attr has a word sounding like each word of the value.
+    Fuzzy(String, PartialValue),
+    // Inclusive, IE attr >= value. Values are compared by their index keys,
+    // which is what an ordering index is sorted on.
+    Ge(String, PartialValue),
+    Le(String, PartialValue),
+    // Matched against the values' index keys, and never indexed.
+    Regex(String, FilterRegex),
     Pres(String),
     Or(Vec<FilterComp>),
     And(Vec<FilterComp>),
@@ -103,6 +172,10 @@ pub enum FilterResolved {
     // This is attr - value - indexed
     Eq(String, PartialValue, bool),
     Sub(String, PartialValue, bool),
+    Fuzzy(String, PartialValue, bool),
+    Ge(String, PartialValue, bool),
+    Le(String, PartialValue, bool),
+    Regex(String, FilterRegex),
     Pres(String, bool),
     Or(Vec<FilterResolved>),
     And(Vec<FilterResolved>),
@@ -165,6 +238,20 @@ impl Filter<FilterValidResolved> {
     pub fn to_inner(&self) -> &FilterResolved {
         &self.state.inner
     }
+
+    // Until every entry holding an attribute's old name has been migrated,
+    // terms on it need to match the old name too. Those terms aren't
+    // indexed, so they're tested against the entries themselves.
+    pub fn with_aliases(self, schema: &dyn SchemaTransaction) -> Self {
+        if schema.get_alias_migrations().is_empty() {
+            return self;
+        }
+        Filter {
+            state: FilterValidResolved {
+                inner: self.state.inner.with_aliases(schema),
+            },
+        }
+    }
 }
 
 impl Filter<FilterValid> {
@@ -199,6 +286,12 @@ impl Filter<FilterValid> {
         })
     }
 
+    // Turn this back into the protocol form, mainly so it can be reported in
+    // logs without holding internal types.
+    pub fn to_proto(&self) -> ProtoFilter {
+        self.state.inner.to_proto()
+    }
+
     pub fn get_attr_set(&self) -> BTreeSet<&str> {
         // Recurse through the filter getting an attribute set.
         let mut r_set: BTreeSet<&str> = BTreeSet::new();
@@ -279,6 +372,8 @@ impl Filter<FilterInvalid> {
             ("name".to_string(), IndexType::EQUALITY),
             ("name".to_string(), IndexType::SUBSTRING),
             ("name".to_string(), IndexType::PRESENCE),
+            ("name".to_string(), IndexType::ORDERING),
+            ("name".to_string(), IndexType::FUZZY),
             ("class".to_string(), IndexType::EQUALITY),
             ("class".to_string(), IndexType::PRESENCE),
             ("member".to_string(), IndexType::EQUALITY),
@@ -367,6 +462,9 @@ impl FilterComp {
         match fc {
             FC::Eq(a, v) => FilterComp::Eq(a.to_string(), v),
             FC::Sub(a, v) => FilterComp::Sub(a.to_string(), v),
+            FC::Fuzzy(a, v) => FilterComp::Fuzzy(a.to_string(), v),
+            FC::Ge(a, v) => FilterComp::Ge(a.to_string(), v),
+            FC::Le(a, v) => FilterComp::Le(a.to_string(), v),
             FC::Pres(a) => FilterComp::Pres(a.to_string()),
             FC::Or(v) => FilterComp::Or(v.into_iter().map(|c| FilterComp::new(c)).collect()),
             FC::And(v) => FilterComp::And(v.into_iter().map(|c| FilterComp::new(c)).collect()),
@@ -392,12 +490,34 @@ impl FilterComp {
         ])
     }
 
+    fn to_proto(&self) -> ProtoFilter {
+        match self {
+            FilterComp::Eq(a, v) => ProtoFilter::Eq(a.clone(), v.get_idx_eq_key()),
+            FilterComp::Sub(a, v) => ProtoFilter::Sub(a.clone(), v.get_idx_eq_key()),
+            FilterComp::Fuzzy(a, v) => ProtoFilter::Fuzzy(a.clone(), v.get_idx_eq_key()),
+            FilterComp::Ge(a, v) => ProtoFilter::Ge(a.clone(), v.get_idx_eq_key()),
+            FilterComp::Le(a, v) => ProtoFilter::Le(a.clone(), v.get_idx_eq_key()),
+            FilterComp::Regex(a, r) => ProtoFilter::Regex(a.clone(), r.as_str().to_string()),
+            FilterComp::Pres(a) => ProtoFilter::Pres(a.clone()),
+            FilterComp::Or(vs) => ProtoFilter::Or(vs.iter().map(|f| f.to_proto()).collect()),
+            FilterComp::And(vs) => ProtoFilter::And(vs.iter().map(|f| f.to_proto()).collect()),
+            FilterComp::AndNot(f) => ProtoFilter::AndNot(Box::new(f.to_proto())),
+            FilterComp::SelfUUID => ProtoFilter::SelfUUID,
+        }
+    }
+
     fn get_attr_set<'a>(&'a self, r_set: &mut BTreeSet<&'a str>) {
         match self {
             FilterComp::Eq(attr, _) => {
                 r_set.insert(attr.as_str());
             }
-            FilterComp::Sub(attr, _) => {
+            FilterComp::Sub(attr, _)
+            | FilterComp::Fuzzy(attr, _)
+            | FilterComp::Ge(attr, _)
+            | FilterComp::Le(attr, _) => {
+                r_set.insert(attr.as_str());
+            }
+            FilterComp::Regex(attr, _) => {
                 r_set.insert(attr.as_str());
             }
             FilterComp::Pres(attr) => {
@@ -455,6 +575,34 @@ impl FilterComp {
                     None => Err(SchemaError::InvalidAttribute),
                 }
             }
+            FilterComp::Fuzzy(attr, value) => {
+                let attr_norm = schema.normalise_attr_name(attr);
+                match schema_attributes.get(&attr_norm) {
+                    Some(schema_a) => schema_a
+                        .validate_partialvalue(&value)
+                        .map(|_| FilterComp::Fuzzy(attr_norm, value.clone())),
+                    None => Err(SchemaError::InvalidAttribute),
+                }
+            }
+            FilterComp::Ge(attr, value) | FilterComp::Le(attr, value) => {
+                let attr_norm = schema.normalise_attr_name(attr);
+                match schema_attributes.get(&attr_norm) {
+                    Some(schema_a) => schema_a.validate_partialvalue(&value).map(|_| match self {
+                        FilterComp::Ge(_, _) => FilterComp::Ge(attr_norm, value.clone()),
+                        _ => FilterComp::Le(attr_norm, value.clone()),
+                    }),
+                    None => Err(SchemaError::InvalidAttribute),
+                }
+            }
+            FilterComp::Regex(attr, r) => {
+                // The pattern was checked as it was compiled, so only the
+                // attribute is left.
+                let attr_norm = schema.normalise_attr_name(attr);
+                match schema_attributes.get(&attr_norm) {
+                    Some(_) => Ok(FilterComp::Regex(attr_norm, r.clone())),
+                    None => Err(SchemaError::InvalidAttribute),
+                }
+            }
             FilterComp::Pres(attr) => {
                 let attr_norm = schema.normalise_attr_name(attr);
                 // Now check it exists
@@ -517,6 +665,12 @@ impl FilterComp {
             ProtoFilter::Sub(a, v) => {
                 FilterComp::Sub(a.clone(), qs.clone_partialvalue(audit, a, v)?)
             }
+            ProtoFilter::Fuzzy(a, v) => {
+                FilterComp::Fuzzy(a.clone(), qs.clone_partialvalue(audit, a, v)?)
+            }
+            ProtoFilter::Ge(a, v) => FilterComp::Ge(a.clone(), qs.clone_partialvalue(audit, a, v)?),
+            ProtoFilter::Le(a, v) => FilterComp::Le(a.clone(), qs.clone_partialvalue(audit, a, v)?),
+            ProtoFilter::Regex(a, p) => FilterComp::Regex(a.clone(), FilterRegex::new(p)?),
             ProtoFilter::Pres(a) => FilterComp::Pres(a.clone()),
             ProtoFilter::Or(l) => FilterComp::Or(
                 l.iter()
@@ -543,6 +697,12 @@ impl FilterComp {
             ProtoFilter::Sub(a, v) => {
                 FilterComp::Sub(a.clone(), qs.clone_partialvalue(audit, a, v)?)
             }
+            ProtoFilter::Fuzzy(a, v) => {
+                FilterComp::Fuzzy(a.clone(), qs.clone_partialvalue(audit, a, v)?)
+            }
+            ProtoFilter::Ge(a, v) => FilterComp::Ge(a.clone(), qs.clone_partialvalue(audit, a, v)?),
+            ProtoFilter::Le(a, v) => FilterComp::Le(a.clone(), qs.clone_partialvalue(audit, a, v)?),
+            ProtoFilter::Regex(a, p) => FilterComp::Regex(a.clone(), FilterRegex::new(p)?),
             ProtoFilter::Pres(a) => FilterComp::Pres(a.clone()),
             ProtoFilter::Or(l) => FilterComp::Or(
                 l.iter()
@@ -590,6 +750,16 @@ impl PartialEq for FilterResolved {
             (FilterResolved::Sub(a1, v1, i1), FilterResolved::Sub(a2, v2, i2)) => {
                 a1 == a2 && v1 == v2 && i1 == i2
             }
+            (FilterResolved::Fuzzy(a1, v1, i1), FilterResolved::Fuzzy(a2, v2, i2)) => {
+                a1 == a2 && v1 == v2 && i1 == i2
+            }
+            (FilterResolved::Ge(a1, v1, i1), FilterResolved::Ge(a2, v2, i2)) => {
+                a1 == a2 && v1 == v2 && i1 == i2
+            }
+            (FilterResolved::Le(a1, v1, i1), FilterResolved::Le(a2, v2, i2)) => {
+                a1 == a2 && v1 == v2 && i1 == i2
+            }
+            (FilterResolved::Regex(a1, r1), FilterResolved::Regex(a2, r2)) => a1 == a2 && r1 == r2,
             (FilterResolved::Pres(a1, i1), FilterResolved::Pres(a2, i2)) => a1 == a2 && i1 == i2,
             (FilterResolved::And(vs1), FilterResolved::And(vs2)) => vs1 == vs2,
             (FilterResolved::Or(vs1), FilterResolved::Or(vs2)) => vs1 == vs2,
@@ -623,6 +793,11 @@ impl PartialOrd for FilterResolved {
 impl Ord for FilterResolved {
     fn cmp(&self, rhs: &FilterResolved) -> Ordering {
         match (self, rhs) {
+            // A regex is never indexed and is the most costly to test, so
+            // always goes last.
+            (FilterResolved::Regex(_, _), FilterResolved::Regex(_, _)) => Ordering::Equal,
+            (FilterResolved::Regex(_, _), _) => Ordering::Greater,
+            (_, FilterResolved::Regex(_, _)) => Ordering::Less,
             (FilterResolved::Eq(a1, v1, true), FilterResolved::Eq(a2, v2, true)) => {
                 match a1.cmp(a2) {
                     Ordering::Equal => v1.cmp(v2),
@@ -642,6 +817,31 @@ impl Ord for FilterResolved {
             (_, FilterResolved::Eq(_, _, true)) => Ordering::Greater,
             (FilterResolved::Pres(_, true), _) => Ordering::Less,
             (_, FilterResolved::Pres(_, true)) => Ordering::Greater,
+            // A range can cover any number of keys, so goes after those that
+            // are one key.
+            (FilterResolved::Ge(a1, v1, true), FilterResolved::Ge(a2, v2, true))
+            | (FilterResolved::Le(a1, v1, true), FilterResolved::Le(a2, v2, true)) => {
+                match a1.cmp(a2) {
+                    Ordering::Equal => v1.cmp(v2),
+                    o => o,
+                }
+            }
+            (FilterResolved::Ge(_, _, true), FilterResolved::Le(_, _, true)) => Ordering::Less,
+            (FilterResolved::Le(_, _, true), FilterResolved::Ge(_, _, true)) => Ordering::Greater,
+            (FilterResolved::Ge(_, _, true), _) | (FilterResolved::Le(_, _, true), _) => {
+                Ordering::Less
+            }
+            (_, FilterResolved::Ge(_, _, true)) | (_, FilterResolved::Le(_, _, true)) => {
+                Ordering::Greater
+            }
+            (FilterResolved::Fuzzy(a1, v1, true), FilterResolved::Fuzzy(a2, v2, true)) => {
+                match a1.cmp(a2) {
+                    Ordering::Equal => v1.cmp(v2),
+                    o => o,
+                }
+            }
+            (FilterResolved::Fuzzy(_, _, true), _) => Ordering::Greater,
+            (_, FilterResolved::Fuzzy(_, _, true)) => Ordering::Less,
             (FilterResolved::Sub(_, _, true), _) => Ordering::Greater,
             (_, FilterResolved::Sub(_, _, true)) => Ordering::Less,
             // Now prefer the unindexed types by performance order.
@@ -651,6 +851,21 @@ impl Ord for FilterResolved {
             (FilterResolved::Eq(_, _, false), FilterResolved::Eq(_, _, false)) => Ordering::Equal,
             (FilterResolved::Eq(_, _, false), _) => Ordering::Less,
             (_, FilterResolved::Eq(_, _, false)) => Ordering::Greater,
+            (FilterResolved::Ge(_, _, false), FilterResolved::Ge(_, _, false))
+            | (FilterResolved::Ge(_, _, false), FilterResolved::Le(_, _, false))
+            | (FilterResolved::Le(_, _, false), FilterResolved::Ge(_, _, false))
+            | (FilterResolved::Le(_, _, false), FilterResolved::Le(_, _, false)) => Ordering::Equal,
+            (FilterResolved::Ge(_, _, false), _) | (FilterResolved::Le(_, _, false), _) => {
+                Ordering::Less
+            }
+            (_, FilterResolved::Ge(_, _, false)) | (_, FilterResolved::Le(_, _, false)) => {
+                Ordering::Greater
+            }
+            (FilterResolved::Fuzzy(_, _, false), FilterResolved::Fuzzy(_, _, false)) => {
+                Ordering::Equal
+            }
+            (FilterResolved::Fuzzy(_, _, false), _) => Ordering::Greater,
+            (_, FilterResolved::Fuzzy(_, _, false)) => Ordering::Less,
             (FilterResolved::Sub(_, _, false), FilterResolved::Sub(_, _, false)) => Ordering::Equal,
             (FilterResolved::Sub(_, _, false), _) => Ordering::Greater,
             (_, FilterResolved::Sub(_, _, false)) => Ordering::Less,
@@ -661,6 +876,62 @@ impl Ord for FilterResolved {
 }
 
 impl FilterResolved {
+    fn with_aliases(self, schema: &dyn SchemaTransaction) -> Self {
+        match self {
+            FilterResolved::Or(vs) => {
+                FilterResolved::Or(vs.into_iter().map(|f| f.with_aliases(schema)).collect())
+            }
+            FilterResolved::And(vs) => {
+                FilterResolved::And(vs.into_iter().map(|f| f.with_aliases(schema)).collect())
+            }
+            FilterResolved::AndNot(f) => FilterResolved::AndNot(Box::new(f.with_aliases(schema))),
+            leaf => {
+                let mut terms: Vec<_> = match leaf.leaf_attr() {
+                    Some(a) => schema
+                        .get_pending_aliases(a)
+                        .into_iter()
+                        .filter_map(|alias| leaf.on_attr(alias))
+                        .collect(),
+                    None => Vec::new(),
+                };
+                if terms.is_empty() {
+                    leaf
+                } else {
+                    terms.insert(0, leaf);
+                    FilterResolved::Or(terms)
+                }
+            }
+        }
+    }
+
+    fn leaf_attr(&self) -> Option<&String> {
+        match self {
+            FilterResolved::Eq(a, _, _)
+            | FilterResolved::Sub(a, _, _)
+            | FilterResolved::Fuzzy(a, _, _)
+            | FilterResolved::Ge(a, _, _)
+            | FilterResolved::Le(a, _, _)
+            | FilterResolved::Regex(a, _)
+            | FilterResolved::Pres(a, _) => Some(a),
+            _ => None,
+        }
+    }
+
+    // The same term on another attribute, unindexed.
+    fn on_attr(&self, attr: &String) -> Option<Self> {
+        let attr = attr.clone();
+        match self {
+            FilterResolved::Eq(_, v, _) => Some(FilterResolved::Eq(attr, v.clone(), false)),
+            FilterResolved::Sub(_, v, _) => Some(FilterResolved::Sub(attr, v.clone(), false)),
+            FilterResolved::Fuzzy(_, v, _) => Some(FilterResolved::Fuzzy(attr, v.clone(), false)),
+            FilterResolved::Ge(_, v, _) => Some(FilterResolved::Ge(attr, v.clone(), false)),
+            FilterResolved::Le(_, v, _) => Some(FilterResolved::Le(attr, v.clone(), false)),
+            FilterResolved::Regex(_, r) => Some(FilterResolved::Regex(attr, r.clone())),
+            FilterResolved::Pres(_, _) => Some(FilterResolved::Pres(attr, false)),
+            _ => None,
+        }
+    }
+
     #[cfg(test)]
     unsafe fn from_invalid(fc: FilterComp, idxmeta: &BTreeSet<(&String, &IndexType)>) -> Self {
         match fc {
@@ -674,6 +945,19 @@ impl FilterResolved {
                 let idx = false;
                 FilterResolved::Sub(a, v, idx)
             }
+            FilterComp::Fuzzy(a, v) => {
+                let idx = idxmeta.contains(&(&a, &IndexType::FUZZY));
+                FilterResolved::Fuzzy(a, v, idx)
+            }
+            FilterComp::Ge(a, v) => {
+                let idx = idxmeta.contains(&(&a, &IndexType::ORDERING));
+                FilterResolved::Ge(a, v, idx)
+            }
+            FilterComp::Le(a, v) => {
+                let idx = idxmeta.contains(&(&a, &IndexType::ORDERING));
+                FilterResolved::Le(a, v, idx)
+            }
+            FilterComp::Regex(a, r) => FilterResolved::Regex(a, r),
             FilterComp::Pres(a) => {
                 let idx = idxmeta.contains(&(&a, &IndexType::PRESENCE));
                 FilterResolved::Pres(a, idx)
@@ -717,6 +1001,19 @@ impl FilterResolved {
                 let idx = idxmeta.contains(&(&a, &IndexType::SUBSTRING));
                 Some(FilterResolved::Sub(a, v, idx))
             }
+            FilterComp::Fuzzy(a, v) => {
+                let idx = idxmeta.contains(&(&a, &IndexType::FUZZY));
+                Some(FilterResolved::Fuzzy(a, v, idx))
+            }
+            FilterComp::Ge(a, v) => {
+                let idx = idxmeta.contains(&(&a, &IndexType::ORDERING));
+                Some(FilterResolved::Ge(a, v, idx))
+            }
+            FilterComp::Le(a, v) => {
+                let idx = idxmeta.contains(&(&a, &IndexType::ORDERING));
+                Some(FilterResolved::Le(a, v, idx))
+            }
+            FilterComp::Regex(a, r) => Some(FilterResolved::Regex(a, r)),
             FilterComp::Pres(a) => {
                 let idx = idxmeta.contains(&(&a, &IndexType::PRESENCE));
                 Some(FilterResolved::Pres(a, idx))
@@ -763,6 +1060,10 @@ impl FilterResolved {
         match fc {
             FilterComp::Eq(a, v) => Some(FilterResolved::Eq(a, v, false)),
             FilterComp::Sub(a, v) => Some(FilterResolved::Sub(a, v, false)),
+            FilterComp::Fuzzy(a, v) => Some(FilterResolved::Fuzzy(a, v, false)),
+            FilterComp::Ge(a, v) => Some(FilterResolved::Ge(a, v, false)),
+            FilterComp::Le(a, v) => Some(FilterResolved::Le(a, v, false)),
+            FilterComp::Regex(a, r) => Some(FilterResolved::Regex(a, r)),
             FilterComp::Pres(a) => Some(FilterResolved::Pres(a, false)),
             FilterComp::Or(vs) => {
                 let fi: Option<Vec<_>> = vs
@@ -869,8 +1170,9 @@ impl FilterResolved {
 #[cfg(test)]
 mod tests {
     use crate::entry::{Entry, EntryNew, EntryValid};
-    use crate::filter::{Filter, FilterInvalid};
+    use crate::filter::{Filter, FilterInvalid, FilterRegex, FilterResolved, FilterValidResolved};
     use crate::value::PartialValue;
+    use kanidm_proto::v1::{OperationError, SchemaError};
     use std::cmp::{Ordering, PartialOrd};
     use std::collections::BTreeSet;
 
@@ -1135,6 +1437,106 @@ mod tests {
         assert!(!e.entry_match_no_index(&f_t4a));
     }
 
+    #[test]
+    fn test_ordering_entry_filter() {
+        let e: Entry<EntryValid, EntryNew> = unsafe {
+            Entry::unsafe_from_entry_str(
+                r#"{
+            "valid": {
+                "uuid": "db237e8a-0079-4b8c-8a56-593b22aa44d1"
+            },
+            "state": null,
+            "attrs": {
+                "userid": ["william"],
+                "uuid": ["db237e8a-0079-4b8c-8a56-593b22aa44d1"],
+                "createtimestamp": ["20200101000000Z"]
+            }
+        }"#,
+            )
+            .to_valid_new()
+        };
+
+        // Both are inclusive.
+        let f_t1a =
+            unsafe { filter_resolved!(f_ge("userid", PartialValue::new_iutf8s("william"))) };
+        assert!(e.entry_match_no_index(&f_t1a));
+        let f_t1b =
+            unsafe { filter_resolved!(f_le("userid", PartialValue::new_iutf8s("william"))) };
+        assert!(e.entry_match_no_index(&f_t1b));
+
+        let f_t2a = unsafe {
+            filter_resolved!(f_le(
+                "createtimestamp",
+                PartialValue::new_utf8s("20191231235959Z")
+            ))
+        };
+        assert!(!e.entry_match_no_index(&f_t2a));
+        let f_t2b = unsafe {
+            filter_resolved!(f_ge(
+                "createtimestamp",
+                PartialValue::new_utf8s("20191231235959Z")
+            ))
+        };
+        assert!(e.entry_match_no_index(&f_t2b));
+
+        // Absent is never in range.
+        let f_t3a = unsafe { filter_resolved!(f_ge("uidnumber", PartialValue::new_utf8s(""))) };
+        assert!(!e.entry_match_no_index(&f_t3a));
+    }
+
+    #[test]
+    fn test_regex_entry_filter() {
+        let e: Entry<EntryValid, EntryNew> = unsafe {
+            Entry::unsafe_from_entry_str(
+                r#"{
+            "valid": {
+                "uuid": "db237e8a-0079-4b8c-8a56-593b22aa44d1"
+            },
+            "state": null,
+            "attrs": {
+                "userid": ["william"],
+                "uuid": ["db237e8a-0079-4b8c-8a56-593b22aa44d1"]
+            }
+        }"#,
+            )
+            .to_valid_new()
+        };
+
+        let f_regex = |attr: &str, p: &str| Filter {
+            state: FilterValidResolved {
+                inner: FilterResolved::Regex(
+                    attr.to_string(),
+                    FilterRegex::new(p).expect("invalid regex"),
+                ),
+            },
+        };
+        assert!(e.entry_match_no_index(&f_regex("userid", "^wil+i")));
+        assert!(!e.entry_match_no_index(&f_regex("userid", "^liam")));
+        assert!(!e.entry_match_no_index(&f_regex("name", ".*")));
+
+        // Patterns past the limits are refused before they're ever run.
+        assert_eq!(
+            FilterRegex::new("(a").unwrap_err(),
+            OperationError::SchemaViolation(SchemaError::InvalidAttributeSyntax)
+        );
+        assert_eq!(
+            FilterRegex::new("\\w{1000}{1000}").unwrap_err(),
+            OperationError::ResourceLimit
+        );
+        let nested = format!("{}a{}", "(".repeat(32), ")".repeat(32));
+        assert!(FilterRegex::new(nested.as_str()).is_err());
+
+        // A regex is tested after everything else.
+        let f_sub =
+            FilterResolved::Sub("userid".to_string(), PartialValue::new_iutf8s("wil"), false);
+        let f_re = FilterResolved::Regex(
+            "userid".to_string(),
+            FilterRegex::new("^w").expect("invalid regex"),
+        );
+        assert_eq!(f_re.cmp(&f_sub), Ordering::Greater);
+        assert_eq!(f_sub.cmp(&f_re), Ordering::Less);
+    }
+
     #[test]
     fn test_and_entry_filter() {
         let e: Entry<EntryValid, EntryNew> = unsafe {
@@ -1332,4 +1734,23 @@ mod tests {
 
         assert!(f_t2a.get_attr_set() == f_expect);
     }
+
+    #[test]
+    fn test_filter_to_proto() {
+        use kanidm_proto::v1::Filter as ProtoFilter;
+        let f_t1a = unsafe {
+            filter_valid!(f_and!([
+                f_eq("userid", PartialValue::new_iutf8s("alice")),
+                f_andnot(f_pres("class")),
+            ]))
+        };
+
+        assert!(
+            f_t1a.to_proto()
+                == ProtoFilter::And(vec![
+                    ProtoFilter::Eq("userid".to_string(), "alice".to_string()),
+                    ProtoFilter::AndNot(Box::new(ProtoFilter::Pres("class".to_string()))),
+                ])
+        );
+    }
 }
@@ -0,0 +1,157 @@
+// Synthetic accounts and groups for load testing. Everything is derived from
+// the seed, so the same config always produces the same entries and uuids,
+// which lets performance numbers be compared between runs and branches.
+
+use rand::prelude::*;
+use uuid::Uuid;
+
+use crate::audit::AuditScope;
+use crate::config::{GenerateConfig, GroupSizeDistribution};
+use crate::entry::{Entry, EntryInvalid, EntryNew};
+use crate::server::QueryServerWriteTransaction;
+use crate::value::Value;
+use kanidm_proto::v1::OperationError;
+
+// Entries are submitted in batches of this many, within the one txn.
+const GENERATE_BATCH_SIZE: usize = 1000;
+
+fn gen_uuid(rng: &mut StdRng) -> Uuid {
+    Uuid::from_random_bytes(rng.gen())
+}
+
+fn gen_account(n: usize, u: &Uuid) -> Entry<EntryInvalid, EntryNew> {
+    let mut e = Entry::new();
+    e.add_ava("class", &Value::new_class("object"));
+    e.add_ava("class", &Value::new_class("memberof"));
+    e.add_ava("class", &Value::new_class("account"));
+    e.add_ava("name", &Value::new_iutf8(format!("gen_account_{}", n)));
+    e.add_ava(
+        "displayname",
+        &Value::new_utf8(format!("Generated Account {}", n)),
+    );
+    e.add_ava("uuid", &Value::new_uuidr(u));
+    e
+}
+
+fn gen_group(n: usize, u: &Uuid, members: &[Uuid]) -> Entry<EntryInvalid, EntryNew> {
+    let mut e = Entry::new();
+    e.add_ava("class", &Value::new_class("object"));
+    e.add_ava("class", &Value::new_class("group"));
+    e.add_ava("name", &Value::new_iutf8(format!("gen_group_{}", n)));
+    e.add_ava("uuid", &Value::new_uuidr(u));
+    members
+        .iter()
+        .for_each(|m| e.add_ava("member", &Value::new_refer_r(m)));
+    e
+}
+
+// The number of members of the group at rank n.
+fn group_size(gconfig: &GenerateConfig, rng: &mut StdRng, n: usize) -> usize {
+    let size = match gconfig.distribution {
+        GroupSizeDistribution::Uniform => rng.gen_range(0, gconfig.max_members + 1),
+        GroupSizeDistribution::Zipf => gconfig.max_members / (n + 1),
+    };
+    std::cmp::min(size, gconfig.accounts)
+}
+
+fn create_batched(
+    audit: &mut AuditScope,
+    qs_write: &mut QueryServerWriteTransaction,
+    entries: Vec<Entry<EntryInvalid, EntryNew>>,
+) -> Result<(), OperationError> {
+    let mut entries = entries.into_iter().peekable();
+    while entries.peek().is_some() {
+        let batch: Vec<_> = entries.by_ref().take(GENERATE_BATCH_SIZE).collect();
+        audit_log!(audit, "generate: creating batch of {}", batch.len());
+        qs_write.internal_create(audit, batch)?;
+    }
+    Ok(())
+}
+
+// Returns the number of accounts and groups created.
+pub fn generate(
+    audit: &mut AuditScope,
+    qs_write: &mut QueryServerWriteTransaction,
+    gconfig: &GenerateConfig,
+) -> Result<(usize, usize), OperationError> {
+    let mut rng = StdRng::seed_from_u64(gconfig.seed);
+
+    let account_uuids: Vec<Uuid> = (0..gconfig.accounts).map(|_| gen_uuid(&mut rng)).collect();
+    let accounts: Vec<_> = account_uuids
+        .iter()
+        .enumerate()
+        .map(|(n, u)| gen_account(n, u))
+        .collect();
+    create_batched(audit, qs_write, accounts)?;
+
+    // Members must exist before they can be referenced, so groups go second.
+    let groups: Vec<_> = (0..gconfig.groups)
+        .map(|n| {
+            let u = gen_uuid(&mut rng);
+            let size = group_size(gconfig, &mut rng, n);
+            let members: Vec<Uuid> = account_uuids
+                .choose_multiple(&mut rng, size)
+                .cloned()
+                .collect();
+            gen_group(n, &u, members.as_slice())
+        })
+        .collect();
+    create_batched(audit, qs_write, groups)?;
+
+    Ok((gconfig.accounts, gconfig.groups))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{GenerateConfig, GroupSizeDistribution};
+    use crate::generate::generate;
+    use crate::server::{QueryServer, QueryServerTransaction};
+    use crate::value::PartialValue;
+
+    fn count_class(server: &QueryServer, audit: &mut crate::audit::AuditScope, c: &str) -> usize {
+        let qs_read = server.read().expect("Failed to begin txn");
+        qs_read
+            .internal_search(audit, filter!(f_eq("class", PartialValue::new_class(c))))
+            .expect("search failed")
+            .len()
+    }
+
+    #[test]
+    fn test_generate_zipf() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let gconfig = GenerateConfig {
+                accounts: 40,
+                groups: 5,
+                max_members: 20,
+                distribution: GroupSizeDistribution::Zipf,
+                seed: 0x4b49,
+            };
+            let base_accounts = count_class(server, audit, "account");
+            let base_groups = count_class(server, audit, "group");
+
+            let mut qs_write = server.write().expect("Failed to begin txn");
+            assert_eq!(generate(audit, &mut qs_write, &gconfig), Ok((40, 5)));
+            assert!(qs_write.commit(audit).is_ok());
+
+            assert_eq!(count_class(server, audit, "account"), base_accounts + 40);
+            assert_eq!(count_class(server, audit, "group"), base_groups + 5);
+
+            // The largest group is max_members, the tail gets smaller.
+            let qs_read = server.read().expect("Failed to begin txn");
+            let g0 = qs_read
+                .internal_search(
+                    audit,
+                    filter!(f_eq("name", PartialValue::new_iutf8s("gen_group_0"))),
+                )
+                .expect("search failed");
+            let g3 = qs_read
+                .internal_search(
+                    audit,
+                    filter!(f_eq("name", PartialValue::new_iutf8s("gen_group_3"))),
+                )
+                .expect("search failed");
+            assert_eq!(g0[0].get_ava("member").map(|m| m.len()), Some(20));
+            assert_eq!(g3[0].get_ava("member").map(|m| m.len()), Some(5));
+        });
+    }
+}
@@ -103,7 +103,11 @@ impl Account {
     }
 
     // Could this actually take a claims list and application instead?
-    pub(crate) fn to_userauthtoken(&self, claims: Vec<Claim>) -> Option<UserAuthToken> {
+    pub(crate) fn to_userauthtoken(
+        &self,
+        claims: Vec<Claim>,
+        elevated_until: Option<u64>,
+    ) -> Option<UserAuthToken> {
         // This could consume self?
         // The cred handler provided is what authenticated this user, so we can use it to
         // process what the proper claims should be.
@@ -117,6 +121,7 @@ impl Account {
             application: None,
             groups: self.groups.iter().map(|g| g.into_proto()).collect(),
             claims: claims.iter().map(|c| c.into_proto()).collect(),
+            elevated_until: elevated_until,
         })
     }
 
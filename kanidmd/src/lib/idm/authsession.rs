@@ -1,4 +1,5 @@
 use crate::audit::AuditScope;
+use crate::constants::ELEVATION_TIMEOUT;
 use crate::idm::account::Account;
 use crate::idm::claim::Claim;
 use kanidm_proto::v1::OperationError;
@@ -7,6 +8,8 @@ use kanidm_proto::v1::{AuthAllowed, AuthCredential, AuthState};
 use crate::credential::{Credential, Password};
 
 use std::convert::TryFrom;
+use std::time::Duration;
+use uuid::Uuid;
 
 // Each CredHandler takes one or more credentials and determines if the
 // handlers requirements can be 100% fufilled. This is where MFA or other
@@ -196,6 +199,7 @@ impl AuthSession {
         &mut self,
         au: &mut AuditScope,
         creds: &Vec<AuthCredential>,
+        ct: Duration,
     ) -> Result<AuthState, OperationError> {
         if self.finished {
             return Err(OperationError::InvalidAuthState(
@@ -207,9 +211,16 @@ impl AuthSession {
             CredState::Success(claims) => {
                 audit_log!(au, "Successful cred handling");
                 self.finished = true;
+                // Having just proven who they are, the session may make
+                // sensitive changes for a while. Anonymous proves nothing.
+                let elevated_until = if self.account.is_anonymous() {
+                    None
+                } else {
+                    Some(ct.as_secs() + ELEVATION_TIMEOUT)
+                };
                 let uat = self
                     .account
-                    .to_userauthtoken(claims)
+                    .to_userauthtoken(claims, elevated_until)
                     .ok_or(OperationError::InvalidState)?;
                 Ok(AuthState::Success(uat))
             }
@@ -236,6 +247,10 @@ impl AuthSession {
         //  If success, to authtoken?
     }
 
+    pub fn get_account_uuid(&self) -> &Uuid {
+        &self.account.uuid
+    }
+
     pub fn valid_auth_mechs(&self) -> Vec<AuthAllowed> {
         if self.finished {
             Vec::new()
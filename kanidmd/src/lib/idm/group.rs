@@ -17,6 +17,8 @@ lazy_static! {
 pub struct Group {
     name: String,
     uuid: Uuid,
+    // Where RADIUS puts the members, if anywhere in particular.
+    radius_vlan: Option<u32>,
     // We'll probably add policy and claims later to this
 }
 
@@ -99,9 +101,14 @@ impl Group {
         Ok(Group {
             name: name,
             uuid: uuid,
+            radius_vlan: value.get_ava_single_uint32("radius_vlan"),
         })
     }
 
+    pub fn radius_vlan(&self) -> Option<u32> {
+        self.radius_vlan
+    }
+
     pub fn into_proto(&self) -> ProtoGroup {
         ProtoGroup {
             name: self.name.clone(),
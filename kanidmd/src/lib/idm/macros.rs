@@ -16,7 +16,7 @@ macro_rules! entry_str_to_account {
 macro_rules! run_idm_test {
     ($test_fn:expr) => {{
         use crate::audit::AuditScope;
-        use crate::be::Backend;
+        use crate::be::{Backend, FILTER_TEST_THRESHOLD};
         use crate::idm::server::IdmServer;
         use crate::schema::Schema;
         use crate::server::QueryServer;
@@ -27,7 +27,7 @@ macro_rules! run_idm_test {
 
         let mut audit = AuditScope::new("run_test");
 
-        let be = Backend::new(&mut audit, "", 1).expect("Failed to init be");
+        let be = Backend::new(&mut audit, "", 1, FILTER_TEST_THRESHOLD).expect("Failed to init be");
         let schema_outer = Schema::new(&mut audit).expect("Failed to init schema");
 
         let test_server = QueryServer::new(be, schema_outer);
@@ -6,6 +6,8 @@ pub(crate) mod authsession;
 pub(crate) mod claim;
 pub(crate) mod event;
 pub(crate) mod group;
+pub(crate) mod oauth2;
 pub(crate) mod radius;
 pub(crate) mod server;
+pub(crate) mod unix;
 // mod identity;
@@ -0,0 +1,763 @@
+// OAuth2 and OpenID Connect, so web applications can send people here to
+// authenticate rather than keeping passwords of their own. Each application,
+// or relying party, is an oauth2_resource_server entry named by its client
+// id, holding the redirect uris it may use, its client secret, and the key
+// its tokens are signed with. The last two are generated by the oauth2
+// plugin, and removing either has it generate a new one.
+//
+// Only the authorisation code flow is offered, and PKCE is required, so that
+// an intercepted code is useless on its own. Codes are kept in memory for
+// OAUTH2_CODE_TIMEOUT, so they must be exchanged at the server that issued
+// them.
+//
+// Who may use a relying party, and with which scopes, is set by its scope
+// maps, each granting scopes to the members of a group, IE
+// "idm_admins: openid profile groups". Someone in none of the mapped groups
+// is denied. The groups claim lists only the mapped groups they're in, so a
+// relying party learns no more of someone's memberships than it's set up to
+// act on.
+
+use crate::audit::AuditScope;
+use crate::constants::{OAUTH2_CODE_TIMEOUT, OAUTH2_TOKEN_EXPIRY};
+use crate::entry::{Entry, EntryCommitted, EntryValid};
+use crate::server::QueryServerTransaction;
+use crate::value::PartialValue;
+
+use kanidm_proto::v1::{
+    Jwk, JwkSet, Oauth2AuthoriseRequest, Oauth2TokenRequest, Oauth2TokenResponse,
+    OidcDiscoveryResponse, OperationError, UserAuthToken,
+};
+
+use openssl::bn::BigNumContext;
+use openssl::ec::{EcGroup, EcKey, PointConversionForm};
+use openssl::ecdsa::EcdsaSig;
+use openssl::nid::Nid;
+use openssl::pkey::Private;
+use openssl::sha::sha256;
+use std::collections::BTreeSet;
+use std::time::Duration;
+use uuid::Uuid;
+
+// A code waiting to be exchanged for tokens.
+#[derive(Debug, Clone)]
+pub struct Oauth2Code {
+    rs_uuid: Uuid,
+    redirect_uri: String,
+    scopes: BTreeSet<String>,
+    nonce: Option<String>,
+    code_challenge: String,
+    uat: UserAuthToken,
+    expiry: Duration,
+}
+
+impl Oauth2Code {
+    pub fn is_expired(&self, ct: Duration) -> bool {
+        ct >= self.expiry
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Oauth2Claims {
+    iss: String,
+    sub: String,
+    aud: String,
+    iat: u64,
+    exp: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    nonce: Option<String>,
+    // Access tokens only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    scope: Option<String>,
+    // The profile scope.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    preferred_username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    // The groups scope.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    groups: Option<Vec<String>>,
+}
+
+fn oauth2_error(code: &str) -> OperationError {
+    OperationError::Oauth2(code.to_string())
+}
+
+fn crypto_error<E: std::fmt::Debug>(au: &mut AuditScope, e: E) -> OperationError {
+    audit_log!(au, "oauth2 crypto failure -> {:?}", e);
+    OperationError::InvalidState
+}
+
+pub fn base64url(b: &[u8]) -> String {
+    openssl::base64::encode_block(b)
+        .trim_end_matches('=')
+        .replace('+', "-")
+        .replace('/', "_")
+}
+
+// Only rfc3986's unreserved characters are left as they are.
+fn url_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            b => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+fn redirect(uri: &str, params: &[(&str, Option<&str>)]) -> String {
+    let query: Vec<String> = params
+        .iter()
+        .filter_map(|(k, v)| v.map(|v| format!("{}={}", k, url_encode(v))))
+        .collect();
+    let sep = if uri.contains('?') { '&' } else { '?' };
+    format!("{}{}{}", uri, sep, query.join("&"))
+}
+
+// rfc6749 3.3, any printable ascii but space, quote and backslash.
+fn valid_scope(s: &str) -> bool {
+    !s.is_empty()
+        && s.bytes()
+            .all(|b| b == 0x21 || (0x23..=0x5b).contains(&b) || (0x5d..=0x7e).contains(&b))
+}
+
+// A scope map value, IE "idm_admins: openid profile", as the group and its
+// scopes.
+pub fn parse_scope_map(v: &str) -> Option<(String, BTreeSet<String>)> {
+    let mut parts = v.splitn(2, ':');
+    let group = parts.next()?.trim().to_lowercase();
+    let scopes: BTreeSet<String> = parts
+        .next()?
+        .split_whitespace()
+        .map(|s| s.to_string())
+        .collect();
+    if group.is_empty() || scopes.is_empty() || !scopes.iter().all(|s| valid_scope(s)) {
+        None
+    } else {
+        Some((group, scopes))
+    }
+}
+
+// A new ES256 key for a relying party's tokens, as PEM.
+pub fn generate_token_key(au: &mut AuditScope) -> Result<String, OperationError> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).map_err(|e| crypto_error(au, e))?;
+    let key = EcKey::generate(&group).map_err(|e| crypto_error(au, e))?;
+    key.private_key_to_pem()
+        .map_err(|e| crypto_error(au, e))
+        .and_then(|pem| String::from_utf8(pem).map_err(|e| crypto_error(au, e)))
+}
+
+// The right length, left padded, for a coordinate or half a signature.
+fn pad32(b: Vec<u8>) -> Vec<u8> {
+    let mut out = vec![0; 32usize.saturating_sub(b.len())];
+    out.extend(b);
+    out
+}
+
+pub struct Oauth2ResourceServer {
+    name: String,
+    uuid: Uuid,
+    origins: Vec<String>,
+    secret: String,
+    scope_maps: Vec<(String, BTreeSet<String>)>,
+    key: EcKey<Private>,
+}
+
+impl Oauth2ResourceServer {
+    fn try_from_entry(
+        au: &mut AuditScope,
+        e: &Entry<EntryValid, EntryCommitted>,
+    ) -> Result<Self, OperationError> {
+        let name = e
+            .get_ava_single_string("name")
+            .ok_or(OperationError::InvalidEntryState)?;
+        let secret = e
+            .get_ava_single_string("oauth2_rs_basic_secret")
+            .ok_or(OperationError::InvalidEntryState)?;
+        let pem = e
+            .get_ava_single_str("oauth2_rs_token_key")
+            .ok_or(OperationError::InvalidEntryState)?;
+        let key = EcKey::private_key_from_pem(pem.as_bytes()).map_err(|e| crypto_error(au, e))?;
+        let origins = e
+            .get_ava_set_str("oauth2_rs_origin")
+            .map(|s| s.into_iter().map(|o| o.to_string()).collect())
+            .unwrap_or_default();
+        // The plugin checks these on the way in.
+        let scope_maps = e
+            .get_ava_set_str("oauth2_rs_scope_map")
+            .map(|s| s.into_iter().filter_map(parse_scope_map).collect())
+            .unwrap_or_default();
+        Ok(Oauth2ResourceServer {
+            name: name,
+            uuid: *e.get_uuid(),
+            origins: origins,
+            secret: secret,
+            scope_maps: scope_maps,
+            key: key,
+        })
+    }
+
+    pub fn find<T: QueryServerTransaction>(
+        au: &mut AuditScope,
+        qs: &T,
+        client_id: &str,
+    ) -> Result<Self, OperationError> {
+        let f = filter!(f_and!([
+            f_eq("class", PartialValue::new_class("oauth2_resource_server")),
+            f_eq("name", PartialValue::new_iutf8s(client_id))
+        ]));
+        let entries = qs.internal_search(au, f)?;
+        match entries.first() {
+            Some(e) => Self::try_from_entry(au, e),
+            None => {
+                audit_log!(au, "oauth2 relying party {} not found", client_id);
+                Err(oauth2_error("invalid_client"))
+            }
+        }
+    }
+
+    // The scopes uat may be granted, and the mapped groups it's in.
+    fn scopes_for(&self, uat: &UserAuthToken) -> (BTreeSet<String>, Vec<String>) {
+        let mut scopes = BTreeSet::new();
+        let mut groups = Vec::new();
+        for (group, s) in self.scope_maps.iter() {
+            let member = uat
+                .groups
+                .iter()
+                .find(|g| g.name.to_lowercase() == *group || g.uuid.to_lowercase() == *group);
+            if let Some(g) = member {
+                scopes.extend(s.iter().cloned());
+                if !groups.contains(&g.name) {
+                    groups.push(g.name.clone());
+                }
+            }
+        }
+        groups.sort();
+        (scopes, groups)
+    }
+
+    fn check_secret(&self, secret: &str) -> bool {
+        self.secret.len() == secret.len()
+            && openssl::memcmp::eq(self.secret.as_bytes(), secret.as_bytes())
+    }
+
+    fn public_point(&self, au: &mut AuditScope) -> Result<Vec<u8>, OperationError> {
+        let mut ctx = BigNumContext::new().map_err(|e| crypto_error(au, e))?;
+        self.key
+            .public_key()
+            .to_bytes(
+                self.key.group(),
+                PointConversionForm::UNCOMPRESSED,
+                &mut ctx,
+            )
+            .map_err(|e| crypto_error(au, e))
+    }
+
+    // Changes with the key, so relying parties know to fetch the new one.
+    fn kid(&self, au: &mut AuditScope) -> Result<String, OperationError> {
+        let digest = sha256(self.public_point(au)?.as_slice());
+        Ok(digest[..8].iter().map(|b| format!("{:02x}", b)).collect())
+    }
+
+    pub fn jwk(&self, au: &mut AuditScope) -> Result<Jwk, OperationError> {
+        // 0x04, then x and y.
+        let point = self.public_point(au)?;
+        if point.len() != 65 {
+            return Err(crypto_error(au, "unexpected public key length"));
+        }
+        Ok(Jwk {
+            kty: "EC".to_string(),
+            crv: "P-256".to_string(),
+            alg: "ES256".to_string(),
+            use_: "sig".to_string(),
+            kid: self.kid(au)?,
+            x: base64url(&point[1..33]),
+            y: base64url(&point[33..65]),
+        })
+    }
+
+    // A compact JWS of the claims.
+    fn sign(&self, au: &mut AuditScope, claims: &Oauth2Claims) -> Result<String, OperationError> {
+        let header = serde_json::json!({
+            "alg": "ES256",
+            "typ": "JWT",
+            "kid": self.kid(au)?,
+        });
+        let header = serde_json::to_vec(&header).map_err(|e| crypto_error(au, e))?;
+        let claims = serde_json::to_vec(claims).map_err(|e| crypto_error(au, e))?;
+        let input = format!("{}.{}", base64url(&header), base64url(&claims));
+        let sig = EcdsaSig::sign(&sha256(input.as_bytes()), &self.key)
+            .map_err(|e| crypto_error(au, e))?;
+        let mut raw = pad32(sig.r().to_vec());
+        raw.extend(pad32(sig.s().to_vec()));
+        Ok(format!("{}.{}", input, base64url(raw.as_slice())))
+    }
+
+    #[cfg(test)]
+    fn verify(&self, token: &str) -> Option<Oauth2Claims> {
+        let debase = |s: &str| {
+            let mut s = s.replace('-', "+").replace('_', "/");
+            while s.len() % 4 != 0 {
+                s.push('=');
+            }
+            openssl::base64::decode_block(s.as_str()).ok()
+        };
+        let mut parts = token.rsplitn(2, '.');
+        let sig = debase(parts.next()?)?;
+        let input = parts.next()?;
+        if sig.len() != 64 {
+            return None;
+        }
+        let r = openssl::bn::BigNum::from_slice(&sig[..32]).ok()?;
+        let s = openssl::bn::BigNum::from_slice(&sig[32..]).ok()?;
+        let sig = EcdsaSig::from_private_components(r, s).ok()?;
+        if !sig.verify(&sha256(input.as_bytes()), &self.key).ok()? {
+            return None;
+        }
+        let claims = debase(input.splitn(2, '.').nth(1)?)?;
+        serde_json::from_slice(claims.as_slice()).ok()
+    }
+
+    fn issuer(&self, origin: &str) -> String {
+        format!("{}/oauth2/openid/{}", origin, self.name)
+    }
+
+    // Where to send the browser, with a code for the relying party or why it
+    // was refused. An Err is for requests whose redirect_uri we can't trust,
+    // so must be shown to the person instead.
+    pub fn authorise(
+        &self,
+        au: &mut AuditScope,
+        uat: &UserAuthToken,
+        req: &Oauth2AuthoriseRequest,
+        ct: Duration,
+    ) -> Result<(String, Option<(String, Oauth2Code)>), OperationError> {
+        if !self.origins.contains(&req.redirect_uri) {
+            audit_log!(
+                au,
+                "oauth2 redirect_uri {} not registered",
+                req.redirect_uri
+            );
+            return Err(oauth2_error("invalid_request"));
+        }
+        let state = req.state.as_deref();
+        let refuse = |error: &str| {
+            (
+                redirect(
+                    req.redirect_uri.as_str(),
+                    &[("error", Some(error)), ("state", state)],
+                ),
+                None,
+            )
+        };
+
+        if req.response_type != "code" {
+            return Ok(refuse("unsupported_response_type"));
+        }
+        // base64url of a sha256 is always 43 long.
+        let code_challenge = match (&req.code_challenge, &req.code_challenge_method) {
+            (Some(c), Some(m)) if m == "S256" && c.len() == 43 => c.clone(),
+            _ => {
+                audit_log!(au, "oauth2 request without an S256 PKCE challenge");
+                return Ok(refuse("invalid_request"));
+            }
+        };
+        let requested: BTreeSet<String> = req
+            .scope
+            .as_ref()
+            .map(|s| s.split_whitespace().map(|s| s.to_string()).collect())
+            .unwrap_or_default();
+        if requested.is_empty() {
+            return Ok(refuse("invalid_scope"));
+        }
+        let (permitted, _) = self.scopes_for(uat);
+        if permitted.is_empty() {
+            audit_log!(
+                au,
+                "oauth2 {} is in none of {}'s groups",
+                uat.name,
+                self.name
+            );
+            return Ok(refuse("access_denied"));
+        }
+        if !requested.is_subset(&permitted) {
+            audit_log!(
+                au,
+                "oauth2 {} requested {:?} but may only have {:?}",
+                uat.name,
+                requested,
+                permitted
+            );
+            return Ok(refuse("invalid_scope"));
+        }
+
+        let code = crate::utils::password_from_random();
+        let uri = redirect(
+            req.redirect_uri.as_str(),
+            &[("code", Some(code.as_str())), ("state", state)],
+        );
+        Ok((
+            uri,
+            Some((
+                code,
+                Oauth2Code {
+                    rs_uuid: self.uuid,
+                    redirect_uri: req.redirect_uri.clone(),
+                    scopes: requested,
+                    nonce: req.nonce.clone(),
+                    code_challenge: code_challenge,
+                    uat: uat.clone(),
+                    expiry: ct + Duration::from_secs(OAUTH2_CODE_TIMEOUT),
+                },
+            )),
+        ))
+    }
+
+    // The client has already been authenticated, and the code taken, so it
+    // can't be tried again whether this works or not.
+    pub fn exchange(
+        &self,
+        au: &mut AuditScope,
+        code: Oauth2Code,
+        req: &Oauth2TokenRequest,
+        origin: &str,
+        ct: Duration,
+    ) -> Result<Oauth2TokenResponse, OperationError> {
+        if code.is_expired(ct) || code.rs_uuid != self.uuid || code.redirect_uri != req.redirect_uri
+        {
+            audit_log!(
+                au,
+                "oauth2 code expired, or not for this client or redirect"
+            );
+            return Err(oauth2_error("invalid_grant"));
+        }
+        // rfc7636 4.1
+        let verified = match &req.code_verifier {
+            Some(v) if (43..=128).contains(&v.len()) => {
+                base64url(&sha256(v.as_bytes())) == code.code_challenge
+            }
+            _ => false,
+        };
+        if !verified {
+            audit_log!(au, "oauth2 PKCE verifier doesn't match the challenge");
+            return Err(oauth2_error("invalid_grant"));
+        }
+
+        let iat = ct.as_secs();
+        let exp = iat + OAUTH2_TOKEN_EXPIRY;
+        let scope = code.scopes.iter().cloned().collect::<Vec<_>>().join(" ");
+        let access = Oauth2Claims {
+            iss: self.issuer(origin),
+            sub: code.uat.uuid.clone(),
+            aud: self.name.clone(),
+            iat: iat,
+            exp: exp,
+            nonce: None,
+            scope: Some(scope.clone()),
+            preferred_username: None,
+            name: None,
+            groups: None,
+        };
+        let id_token = if code.scopes.contains("openid") {
+            let profile = code.scopes.contains("profile");
+            let groups = if code.scopes.contains("groups") {
+                Some(self.scopes_for(&code.uat).1)
+            } else {
+                None
+            };
+            let id = Oauth2Claims {
+                iss: self.issuer(origin),
+                sub: code.uat.uuid.clone(),
+                aud: self.name.clone(),
+                iat: iat,
+                exp: exp,
+                nonce: code.nonce.clone(),
+                scope: None,
+                preferred_username: if profile {
+                    Some(code.uat.name.clone())
+                } else {
+                    None
+                },
+                name: if profile {
+                    Some(code.uat.displayname.clone())
+                } else {
+                    None
+                },
+                groups: groups,
+            };
+            Some(self.sign(au, &id)?)
+        } else {
+            None
+        };
+        audit_log!(
+            au,
+            "oauth2 issued tokens for {} to {} with {}",
+            code.uat.name,
+            self.name,
+            scope
+        );
+        Ok(Oauth2TokenResponse {
+            access_token: self.sign(au, &access)?,
+            token_type: "Bearer".to_string(),
+            expires_in: OAUTH2_TOKEN_EXPIRY,
+            scope: scope,
+            id_token: id_token,
+        })
+    }
+
+    pub fn discovery(&self, origin: &str) -> OidcDiscoveryResponse {
+        let strs = |v: &[&str]| v.iter().map(|s| s.to_string()).collect();
+        let scopes: BTreeSet<String> = self
+            .scope_maps
+            .iter()
+            .flat_map(|(_, s)| s.iter().cloned())
+            .collect();
+        OidcDiscoveryResponse {
+            issuer: self.issuer(origin),
+            authorization_endpoint: format!("{}/oauth2/authorise", origin),
+            token_endpoint: format!("{}/oauth2/token", origin),
+            jwks_uri: format!("{}/public_key.jwk", self.issuer(origin)),
+            scopes_supported: scopes.into_iter().collect(),
+            response_types_supported: strs(&["code"]),
+            grant_types_supported: strs(&["authorization_code"]),
+            subject_types_supported: strs(&["public"]),
+            id_token_signing_alg_values_supported: strs(&["ES256"]),
+            token_endpoint_auth_methods_supported: strs(&[
+                "client_secret_basic",
+                "client_secret_post",
+            ]),
+            code_challenge_methods_supported: strs(&["S256"]),
+            claims_supported: strs(&[
+                "iss",
+                "sub",
+                "aud",
+                "iat",
+                "exp",
+                "nonce",
+                "preferred_username",
+                "name",
+                "groups",
+            ]),
+        }
+    }
+
+    pub fn jwks(&self, au: &mut AuditScope) -> Result<JwkSet, OperationError> {
+        Ok(JwkSet {
+            keys: vec![self.jwk(au)?],
+        })
+    }
+}
+
+// The client id and secret of a token request, from basic auth if given,
+// else the form.
+pub fn client_credentials(
+    basic: Option<(String, String)>,
+    req: &Oauth2TokenRequest,
+) -> Result<(String, String), OperationError> {
+    match (basic, &req.client_id, &req.client_secret) {
+        (Some(b), _, _) => Ok(b),
+        (None, Some(id), Some(secret)) => Ok((id.clone(), secret.clone())),
+        _ => Err(oauth2_error("invalid_client")),
+    }
+}
+
+// The token request must come from the client the code was issued to.
+pub fn check_client(
+    rs: &Oauth2ResourceServer,
+    secret: &str,
+    req: &Oauth2TokenRequest,
+) -> Result<(), OperationError> {
+    if req.grant_type != "authorization_code" {
+        return Err(oauth2_error("unsupported_grant_type"));
+    }
+    if !rs.check_secret(secret) {
+        return Err(oauth2_error("invalid_client"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{base64url, generate_token_key, parse_scope_map, redirect, Oauth2ResourceServer};
+    use crate::audit::AuditScope;
+    use kanidm_proto::v1::{Group, Oauth2AuthoriseRequest, Oauth2TokenRequest, UserAuthToken};
+    use openssl::ec::EcKey;
+    use openssl::sha::sha256;
+    use std::collections::BTreeSet;
+    use std::time::Duration;
+    use uuid::Uuid;
+
+    const ORIGIN: &str = "https://idm.example.com";
+    const REDIRECT: &str = "https://app.example.com/oauth2/callback";
+    // Any 43 to 128 characters.
+    const VERIFIER: &str = "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk";
+
+    fn test_rs(au: &mut AuditScope) -> Oauth2ResourceServer {
+        let pem = generate_token_key(au).expect("key generation failed");
+        Oauth2ResourceServer {
+            name: "test_app".to_string(),
+            uuid: Uuid::new_v4(),
+            origins: vec![REDIRECT.to_string()],
+            secret: "secret".to_string(),
+            scope_maps: vec![
+                parse_scope_map("app_users: openid profile").unwrap(),
+                parse_scope_map("App_Admins: openid groups admin").unwrap(),
+            ],
+            key: EcKey::private_key_from_pem(pem.as_bytes()).unwrap(),
+        }
+    }
+
+    fn test_uat(groups: &[&str]) -> UserAuthToken {
+        UserAuthToken {
+            name: "testperson".to_string(),
+            displayname: "Test Person".to_string(),
+            uuid: "cc8e95b4-c24f-4d68-ba54-8bed76f63930".to_string(),
+            application: None,
+            groups: groups
+                .iter()
+                .map(|g| Group {
+                    name: g.to_string(),
+                    uuid: Uuid::new_v4().to_string(),
+                })
+                .collect(),
+            claims: Vec::new(),
+            elevated_until: None,
+        }
+    }
+
+    fn test_req(scope: &str) -> Oauth2AuthoriseRequest {
+        Oauth2AuthoriseRequest {
+            response_type: "code".to_string(),
+            client_id: "test_app".to_string(),
+            redirect_uri: REDIRECT.to_string(),
+            scope: Some(scope.to_string()),
+            state: Some("st a/te".to_string()),
+            nonce: Some("n0nce".to_string()),
+            code_challenge: Some(base64url(&sha256(VERIFIER.as_bytes()))),
+            code_challenge_method: Some("S256".to_string()),
+        }
+    }
+
+    fn token_req(code: &str, verifier: &str) -> Oauth2TokenRequest {
+        Oauth2TokenRequest {
+            grant_type: "authorization_code".to_string(),
+            code: code.to_string(),
+            redirect_uri: REDIRECT.to_string(),
+            code_verifier: Some(verifier.to_string()),
+            client_id: None,
+            client_secret: None,
+        }
+    }
+
+    #[test]
+    fn test_idm_oauth2_scope_map() {
+        assert_eq!(
+            parse_scope_map(" Admins :openid  profile"),
+            Some((
+                "admins".to_string(),
+                vec!["openid".to_string(), "profile".to_string()]
+                    .into_iter()
+                    .collect::<BTreeSet<_>>()
+            ))
+        );
+        assert_eq!(parse_scope_map("admins"), None);
+        assert_eq!(parse_scope_map("admins:"), None);
+        assert_eq!(parse_scope_map(": openid"), None);
+        assert_eq!(parse_scope_map("admins: open\"id"), None);
+        assert_eq!(
+            redirect(
+                "https://a/cb?x=1",
+                &[("code", Some("a b")), ("state", None)]
+            ),
+            "https://a/cb?x=1&code=a%20b"
+        );
+    }
+
+    #[test]
+    fn test_idm_oauth2_code_flow() {
+        let mut au = AuditScope::new("test_idm_oauth2_code_flow");
+        let au = &mut au;
+        let rs = test_rs(au);
+        let uat = test_uat(&["app_users", "app_admins", "unmapped"]);
+        let ct = Duration::from_secs(6000);
+
+        let (uri, code) = rs
+            .authorise(au, &uat, &test_req("openid groups profile"), ct)
+            .expect("authorise failed");
+        let (code, pending) = code.expect("no code issued");
+        assert_eq!(uri, format!("{}?code={}&state=st%20a%2Fte", REDIRECT, code));
+
+        // The wrong verifier is refused, and so is a late exchange.
+        let wrong = "x".repeat(43);
+        assert!(rs
+            .exchange(au, pending.clone(), &token_req(&code, &wrong), ORIGIN, ct)
+            .is_err());
+        let late = ct + Duration::from_secs(61);
+        assert!(rs
+            .exchange(
+                au,
+                pending.clone(),
+                &token_req(&code, VERIFIER),
+                ORIGIN,
+                late
+            )
+            .is_err());
+
+        let resp = rs
+            .exchange(au, pending, &token_req(&code, VERIFIER), ORIGIN, ct)
+            .expect("exchange failed");
+        assert_eq!(resp.scope, "groups openid profile");
+        let access = rs.verify(&resp.access_token).expect("bad access token");
+        assert_eq!(access.iss, format!("{}/oauth2/openid/test_app", ORIGIN));
+        assert_eq!(access.sub, uat.uuid);
+        assert_eq!(access.exp, 6900);
+        let id = rs
+            .verify(resp.id_token.as_ref().expect("no id token"))
+            .expect("bad id token");
+        assert_eq!(id.aud, "test_app");
+        assert_eq!(id.nonce, Some("n0nce".to_string()));
+        assert_eq!(id.preferred_username, Some("testperson".to_string()));
+        // Only the groups the relying party knows of.
+        assert_eq!(
+            id.groups,
+            Some(vec!["app_admins".to_string(), "app_users".to_string()])
+        );
+        // Tampering breaks the signature.
+        let mut forged = resp.access_token.clone();
+        forged.insert(forged.len() - 10, 'A');
+        assert!(rs.verify(&forged).is_none());
+
+        let jwk = rs.jwk(au).expect("no jwk");
+        assert_eq!((jwk.x.len(), jwk.y.len()), (43, 43));
+    }
+
+    #[test]
+    fn test_idm_oauth2_authorise_refused() {
+        let mut au = AuditScope::new("test_idm_oauth2_authorise_refused");
+        let au = &mut au;
+        let rs = test_rs(au);
+        let ct = Duration::from_secs(6000);
+        let user = test_uat(&["app_users"]);
+
+        // An unregistered redirect is never sent to.
+        let mut req = test_req("openid");
+        req.redirect_uri = "https://evil.example.com/".to_string();
+        assert!(rs.authorise(au, &user, &req, ct).is_err());
+
+        let refused = |au: &mut AuditScope, uat: &UserAuthToken, req: Oauth2AuthoriseRequest| {
+            let (uri, code) = rs.authorise(au, uat, &req, ct).expect("not redirected");
+            assert!(code.is_none());
+            uri
+        };
+        // Not in any mapped group.
+        assert!(refused(au, &test_uat(&["unmapped"]), test_req("openid"))
+            .contains("error=access_denied"));
+        // A scope their groups don't grant.
+        assert!(refused(au, &user, test_req("openid admin")).contains("error=invalid_scope"));
+        // PKCE is required.
+        let mut req = test_req("openid");
+        req.code_challenge_method = Some("plain".to_string());
+        assert!(refused(au, &user, req).contains("error=invalid_request"));
+    }
+}
@@ -73,6 +73,13 @@ impl RadiusAccount {
             uuid: self.uuid.to_hyphenated_ref().to_string(),
             secret: self.radius_secret.clone(),
             groups: self.groups.iter().map(|g| g.into_proto()).collect(),
+            vlan: self.radius_vlan(),
         })
     }
+
+    // Someone in several groups with a vlan gets the lowest numbered, so at
+    // least the choice doesn't change from one authentication to the next.
+    pub(crate) fn radius_vlan(&self) -> Option<u32> {
+        self.groups.iter().filter_map(|g| g.radius_vlan()).min()
+    }
 }
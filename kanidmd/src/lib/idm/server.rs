@@ -5,8 +5,11 @@ use crate::idm::account::Account;
 use crate::idm::authsession::AuthSession;
 use crate::idm::event::{
     GeneratePasswordEvent, PasswordChangeEvent, RadiusAuthTokenEvent, RegenerateRadiusSecretEvent,
+    UnixGroupTokenEvent, UnixUserTokenEvent,
 };
+use crate::idm::oauth2::{check_client, client_credentials, Oauth2Code, Oauth2ResourceServer};
 use crate::idm::radius::RadiusAccount;
+use crate::idm::unix::{UnixGroup, UnixUserAccount};
 use crate::server::QueryServerReadTransaction;
 use crate::server::{QueryServer, QueryServerTransaction, QueryServerWriteTransaction};
 use crate::utils::{password_from_random, readable_password_from_random, uuid_from_duration, SID};
@@ -15,6 +18,10 @@ use crate::value::PartialValue;
 use kanidm_proto::v1::AuthState;
 use kanidm_proto::v1::OperationError;
 use kanidm_proto::v1::RadiusAuthToken;
+use kanidm_proto::v1::{
+    JwkSet, Oauth2AuthoriseRequest, Oauth2TokenRequest, Oauth2TokenResponse, OidcDiscoveryResponse,
+    UnixGroupToken, UnixUserToken, UserAuthToken,
+};
 
 use concread::cowcell::{CowCell, CowCellWriteTxn};
 use std::collections::BTreeMap;
@@ -27,6 +34,8 @@ pub struct IdmServer {
     // variaous accounts, and we have a good idea of how to structure the
     // in memory caches related to locking.
     sessions: CowCell<BTreeMap<Uuid, AuthSession>>,
+    // OAuth2 codes issued and not yet exchanged, by code.
+    oauth2_codes: CowCell<BTreeMap<String, Oauth2Code>>,
     // Need a reference to the query server.
     qs: QueryServer,
     // thread/server id
@@ -38,6 +47,7 @@ pub struct IdmServerWriteTransaction<'a> {
     // the idm in memory structures (maybe the query server too). This is
     // things like authentication
     sessions: CowCellWriteTxn<'a, BTreeMap<Uuid, AuthSession>>,
+    oauth2_codes: CowCellWriteTxn<'a, BTreeMap<String, Oauth2Code>>,
     qs: &'a QueryServer,
     sid: &'a SID,
 }
@@ -59,29 +69,40 @@ impl IdmServer {
     pub fn new(qs: QueryServer, sid: SID) -> IdmServer {
         IdmServer {
             sessions: CowCell::new(BTreeMap::new()),
+            oauth2_codes: CowCell::new(BTreeMap::new()),
             qs: qs,
             sid: sid,
         }
     }
 
+    // The authentications in progress for the account.
+    pub fn count_auth_sessions(&self, uuid: &Uuid) -> usize {
+        self.sessions
+            .read()
+            .values()
+            .filter(|s| s.get_account_uuid() == uuid)
+            .count()
+    }
+
     pub fn write(&self) -> IdmServerWriteTransaction {
         IdmServerWriteTransaction {
             sessions: self.sessions.write(),
+            oauth2_codes: self.oauth2_codes.write(),
             qs: &self.qs,
             sid: &self.sid,
         }
     }
 
-    pub fn proxy_read(&self) -> IdmServerProxyReadTransaction {
-        IdmServerProxyReadTransaction {
-            qs_read: self.qs.read(),
-        }
+    pub fn proxy_read(&self) -> Result<IdmServerProxyReadTransaction, OperationError> {
+        self.qs
+            .read()
+            .map(|qs_read| IdmServerProxyReadTransaction { qs_read: qs_read })
     }
 
-    pub fn proxy_write(&self) -> IdmServerProxyWriteTransaction {
-        IdmServerProxyWriteTransaction {
-            qs_write: self.qs.write(),
-        }
+    pub fn proxy_write(&self) -> Result<IdmServerProxyWriteTransaction, OperationError> {
+        self.qs
+            .write()
+            .map(|qs_write| IdmServerProxyWriteTransaction { qs_write: qs_write })
     }
 }
 
@@ -128,7 +149,7 @@ impl<'a> IdmServerWriteTransaction<'a> {
                 //
                 // We *DO NOT* need a write though, because I think that lock outs
                 // and rate limits are *per server* and *in memory* only.
-                let qs_read = self.qs.read();
+                let qs_read = self.qs.read()?;
                 // Check anything needed? Get the current auth-session-id from request
                 // because it associates to the nonce's etc which were all cached.
 
@@ -195,19 +216,63 @@ impl<'a> IdmServerWriteTransaction<'a> {
                 // Process the credentials here as required.
                 // Basically throw them at the auth_session and see what
                 // falls out.
-                auth_session.validate_creds(au, &creds.creds).map(|aus| {
-                    AuthResult {
-                        // Is this right?
-                        sessionid: creds.sessionid,
-                        state: aus,
-                    }
-                })
+                auth_session
+                    .validate_creds(au, &creds.creds, ct)
+                    .map(|aus| {
+                        AuthResult {
+                            // Is this right?
+                            sessionid: creds.sessionid,
+                            state: aus,
+                        }
+                    })
+            }
+        }
+    }
+
+    // The redirect to send the browser to. An Err can't be redirected, so
+    // is shown to the person instead.
+    pub fn oauth2_authorise(
+        &mut self,
+        au: &mut AuditScope,
+        uat: &UserAuthToken,
+        req: &Oauth2AuthoriseRequest,
+        ct: Duration,
+    ) -> Result<String, OperationError> {
+        let qs_read = self.qs.read()?;
+        let rs = Oauth2ResourceServer::find(au, &qs_read, req.client_id.as_str())?;
+        let (uri, code) = rs.authorise(au, uat, req, ct)?;
+        if let Some((code, pending)) = code {
+            self.oauth2_codes.retain(|_, c| !c.is_expired(ct));
+            self.oauth2_codes.insert(code, pending);
+        }
+        Ok(uri)
+    }
+
+    pub fn oauth2_token_exchange(
+        &mut self,
+        au: &mut AuditScope,
+        req: &Oauth2TokenRequest,
+        basic: Option<(String, String)>,
+        origin: &str,
+        ct: Duration,
+    ) -> Result<Oauth2TokenResponse, OperationError> {
+        let (client_id, secret) = client_credentials(basic, req)?;
+        let qs_read = self.qs.read()?;
+        let rs = Oauth2ResourceServer::find(au, &qs_read, client_id.as_str())?;
+        check_client(&rs, secret.as_str(), req)?;
+        // Codes are single use, even when the exchange fails.
+        match self.oauth2_codes.remove(&req.code) {
+            Some(code) => rs.exchange(au, code, req, origin, ct),
+            None => {
+                audit_log!(au, "oauth2 code not found, or already used");
+                Err(OperationError::Oauth2("invalid_grant".to_string()))
             }
         }
     }
 
     pub fn commit(self) -> Result<(), OperationError> {
         self.sessions.commit();
+        self.oauth2_codes.commit();
         Ok(())
     }
 }
@@ -231,6 +296,56 @@ impl IdmServerProxyReadTransaction {
 
         account.to_radiusauthtoken()
     }
+
+    pub fn get_unixusertoken(
+        &self,
+        au: &mut AuditScope,
+        uute: &UnixUserTokenEvent,
+    ) -> Result<UnixUserToken, OperationError> {
+        let account_entry = try_audit!(
+            au,
+            self.qs_read
+                .impersonate_search_ext_uuid(au, &uute.target, &uute.event)
+        );
+        let account = try_audit!(
+            au,
+            UnixUserAccount::try_from_entry_reduced(au, account_entry, &self.qs_read)
+        );
+
+        account.to_unixusertoken()
+    }
+
+    pub fn get_unixgrouptoken(
+        &self,
+        au: &mut AuditScope,
+        uute: &UnixGroupTokenEvent,
+    ) -> Result<UnixGroupToken, OperationError> {
+        let group_entry = try_audit!(
+            au,
+            self.qs_read
+                .impersonate_search_ext_uuid(au, &uute.target, &uute.event)
+        );
+        let group = try_audit!(au, UnixGroup::try_from_entry_reduced(group_entry));
+
+        Ok(group.to_unixgrouptoken())
+    }
+
+    pub fn oauth2_discovery(
+        &self,
+        au: &mut AuditScope,
+        client_id: &str,
+        origin: &str,
+    ) -> Result<OidcDiscoveryResponse, OperationError> {
+        Oauth2ResourceServer::find(au, &self.qs_read, client_id).map(|rs| rs.discovery(origin))
+    }
+
+    pub fn oauth2_jwks(
+        &self,
+        au: &mut AuditScope,
+        client_id: &str,
+    ) -> Result<JwkSet, OperationError> {
+        Oauth2ResourceServer::find(au, &self.qs_read, client_id).and_then(|rs| rs.jwks(au))
+    }
 }
 
 impl<'a> IdmServerProxyWriteTransaction<'a> {
@@ -397,16 +512,24 @@ impl<'a> IdmServerProxyWriteTransaction<'a> {
 
 #[cfg(test)]
 mod tests {
-    use crate::constants::{AUTH_SESSION_TIMEOUT, UUID_ADMIN, UUID_ANONYMOUS};
+    use crate::constants::{
+        AUTH_SESSION_TIMEOUT, ELEVATION_TIMEOUT, STR_UUID_ADMIN, UUID_ADMIN, UUID_ANONYMOUS,
+    };
     use crate::credential::Credential;
+    use crate::entry::{Entry, EntryInvalid, EntryNew};
     use crate::event::{AuthEvent, AuthResult, ModifyEvent};
     use crate::idm::event::{
         PasswordChangeEvent, RadiusAuthTokenEvent, RegenerateRadiusSecretEvent,
+        UnixGroupTokenEvent, UnixUserTokenEvent,
     };
+    use crate::idm::oauth2::base64url;
     use crate::modify::{Modify, ModifyList};
+    use crate::server::QueryServerTransaction;
     use crate::value::{PartialValue, Value};
     use kanidm_proto::v1::OperationError;
     use kanidm_proto::v1::{AuthAllowed, AuthState};
+    use kanidm_proto::v1::{Group, Oauth2AuthoriseRequest, Oauth2TokenRequest, UserAuthToken};
+    use openssl::sha::sha256;
 
     use crate::audit::AuditScope;
     use crate::idm::server::IdmServer;
@@ -482,8 +605,9 @@ mod tests {
                             state,
                         } = ar;
                         match state {
-                            AuthState::Success(_uat) => {
+                            AuthState::Success(uat) => {
                                 // Check the uat.
+                                assert_eq!(uat.elevated_until, None);
                             }
                             _ => {
                                 error!(
@@ -539,7 +663,7 @@ mod tests {
     ) -> Result<(), OperationError> {
         let cred = Credential::new_password_only(pw);
         let v_cred = Value::new_credential("primary", cred);
-        let mut qs_write = qs.write();
+        let mut qs_write = qs.write()?;
 
         // now modify and provide a primary credential.
         let me_inv_m = unsafe {
@@ -598,8 +722,12 @@ mod tests {
                         state,
                     } = ar;
                     match state {
-                        AuthState::Success(_uat) => {
+                        AuthState::Success(uat) => {
                             // Check the uat.
+                            assert_eq!(
+                                uat.elevated_until,
+                                Some(TEST_CURRENT_TIME + ELEVATION_TIMEOUT)
+                            );
                         }
                         _ => {
                             error!("A critical error has occured! We have a non-succcess result!");
@@ -662,7 +790,7 @@ mod tests {
         run_idm_test!(|_qs: &QueryServer, idms: &IdmServer, au: &mut AuditScope| {
             let pce = PasswordChangeEvent::new_internal(&UUID_ADMIN, TEST_PASSWORD, None);
 
-            let mut idms_prox_write = idms.proxy_write();
+            let mut idms_prox_write = idms.proxy_write().expect("Failed to begin txn");
             assert!(idms_prox_write.set_account_password(au, &pce).is_ok());
             assert!(idms_prox_write.set_account_password(au, &pce).is_ok());
             assert!(idms_prox_write.commit(au).is_ok());
@@ -674,7 +802,7 @@ mod tests {
         run_idm_test!(|_qs: &QueryServer, idms: &IdmServer, au: &mut AuditScope| {
             let pce = PasswordChangeEvent::new_internal(&UUID_ANONYMOUS, TEST_PASSWORD, None);
 
-            let mut idms_prox_write = idms.proxy_write();
+            let mut idms_prox_write = idms.proxy_write().expect("Failed to begin txn");
             assert!(idms_prox_write.set_account_password(au, &pce).is_err());
             assert!(idms_prox_write.commit(au).is_ok());
         })
@@ -702,7 +830,7 @@ mod tests {
     #[test]
     fn test_idm_regenerate_radius_secret() {
         run_idm_test!(|_qs: &QueryServer, idms: &IdmServer, au: &mut AuditScope| {
-            let mut idms_prox_write = idms.proxy_write();
+            let mut idms_prox_write = idms.proxy_write().expect("Failed to begin txn");
             let rrse = RegenerateRadiusSecretEvent::new_internal(UUID_ADMIN.clone());
 
             // Generates a new credential when none exists
@@ -720,14 +848,14 @@ mod tests {
     #[test]
     fn test_idm_radiusauthtoken() {
         run_idm_test!(|_qs: &QueryServer, idms: &IdmServer, au: &mut AuditScope| {
-            let mut idms_prox_write = idms.proxy_write();
+            let mut idms_prox_write = idms.proxy_write().expect("Failed to begin txn");
             let rrse = RegenerateRadiusSecretEvent::new_internal(UUID_ADMIN.clone());
             let r1 = idms_prox_write
                 .regenerate_radius_secret(au, &rrse)
                 .expect("Failed to reset radius credential 1");
             idms_prox_write.commit(au).expect("failed to commit");
 
-            let idms_prox_read = idms.proxy_read();
+            let idms_prox_read = idms.proxy_read().expect("Failed to begin txn");
             let rate = RadiusAuthTokenEvent::new_internal(UUID_ADMIN.clone());
             let tok_r = idms_prox_read
                 .get_radiusauthtoken(au, &rate)
@@ -735,6 +863,241 @@ mod tests {
 
             // view the token?
             assert!(r1 == tok_r.secret);
+            assert_eq!(tok_r.vlan, None);
+        })
+    }
+
+    #[test]
+    fn test_idm_radiusauthtoken_vlan() {
+        run_idm_test!(|qs: &QueryServer, idms: &IdmServer, au: &mut AuditScope| {
+            let mut qs_write = qs.write().expect("Failed to begin txn");
+            let groups: Vec<Entry<EntryInvalid, EntryNew>> = vec![("staff", "20"), ("ops", "10")]
+                .into_iter()
+                .map(|(name, vlan)| {
+                    Entry::unsafe_from_entry_str(
+                        format!(
+                            r#"{{
+                            "valid": null,
+                            "state": null,
+                            "attrs": {{
+                                "class": ["object", "group"],
+                                "name": ["{}"],
+                                "member": ["{}"],
+                                "radius_vlan": ["{}"]
+                            }}
+                        }}"#,
+                            name, STR_UUID_ADMIN, vlan
+                        )
+                        .as_str(),
+                    )
+                })
+                .collect();
+            assert!(qs_write.internal_create(au, groups).is_ok());
+            qs_write.commit(au).expect("failed to commit");
+
+            let mut idms_prox_write = idms.proxy_write().expect("Failed to begin txn");
+            let rrse = RegenerateRadiusSecretEvent::new_internal(UUID_ADMIN.clone());
+            idms_prox_write
+                .regenerate_radius_secret(au, &rrse)
+                .expect("Failed to reset radius credential");
+            idms_prox_write.commit(au).expect("failed to commit");
+
+            let idms_prox_read = idms.proxy_read().expect("Failed to begin txn");
+            let rate = RadiusAuthTokenEvent::new_internal(UUID_ADMIN.clone());
+            let tok_r = idms_prox_read
+                .get_radiusauthtoken(au, &rate)
+                .expect("Failed to generate radius auth token");
+            // In both, so the lower.
+            assert_eq!(tok_r.vlan, Some(10));
+        })
+    }
+
+    #[test]
+    fn test_idm_unixusertoken() {
+        run_idm_test!(|qs: &QueryServer, idms: &IdmServer, au: &mut AuditScope| {
+            let mut qs_write = qs.write().expect("Failed to begin txn");
+            // One posix group, and one that isn't, which the token leaves out.
+            let e1: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "group", "posixgroup"],
+                    "name": ["testgroup"],
+                    "uuid": ["01609135-a1c4-43d5-966b-a28227644445"],
+                    "gidnumber": ["2001"],
+                    "member": ["00000000-0000-0000-0000-000000000000"]
+                }
+            }"#,
+            );
+            let e2: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "group"],
+                    "name": ["plaingroup"],
+                    "member": ["00000000-0000-0000-0000-000000000000"]
+                }
+            }"#,
+            );
+            assert!(qs_write.internal_create(au, vec![e1, e2]).is_ok());
+            let me = unsafe {
+                ModifyEvent::new_internal_invalid(
+                    filter!(f_eq("name", PartialValue::new_iutf8s("admin"))),
+                    ModifyList::new_list(vec![
+                        Modify::Present("class".to_string(), Value::new_class("posixaccount")),
+                        Modify::Present("gidnumber".to_string(), Value::new_uint32(2000)),
+                        Modify::Present("loginshell".to_string(), Value::new_utf8s("/bin/zsh")),
+                    ]),
+                )
+            };
+            assert!(qs_write.modify(au, &me).is_ok());
+            qs_write.commit(au).expect("failed to commit");
+
+            let idms_prox_read = idms.proxy_read().expect("Failed to begin txn");
+            let uute = UnixUserTokenEvent::new_internal(UUID_ADMIN.clone());
+            let tok = idms_prox_read
+                .get_unixusertoken(au, &uute)
+                .expect("Failed to generate unix user token");
+            assert_eq!(tok.name, "admin");
+            assert_eq!(tok.gidnumber, 2000);
+            assert_eq!(tok.shell, Some("/bin/zsh".to_string()));
+            assert_eq!(tok.home_directory, None);
+            let gids: Vec<u32> = tok.groups.iter().map(|g| g.gidnumber).collect();
+            assert_eq!(gids, vec![2000, 2001]);
+
+            let ugte = UnixGroupTokenEvent::new_internal(
+                Uuid::parse_str("01609135-a1c4-43d5-966b-a28227644445").unwrap(),
+            );
+            let gtok = idms_prox_read
+                .get_unixgrouptoken(au, &ugte)
+                .expect("Failed to generate unix group token");
+            assert_eq!(gtok.name, "testgroup");
+            assert_eq!(gtok.gidnumber, 2001);
+
+            // Anonymous isn't posix, so there is no token to give.
+            let uute = UnixUserTokenEvent::new_internal(UUID_ANONYMOUS.clone());
+            assert!(idms_prox_read.get_unixusertoken(au, &uute).is_err());
+        })
+    }
+
+    #[test]
+    fn test_idm_oauth2_code_exchange() {
+        run_idm_test!(|qs: &QueryServer, idms: &IdmServer, au: &mut AuditScope| {
+            let mut qs_write = qs.write().expect("Failed to begin txn");
+            let e: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "oauth2_resource_server"],
+                    "name": ["test_app"],
+                    "oauth2_rs_origin": ["https://app.example.com/cb"],
+                    "oauth2_rs_scope_map": ["idm_admins: openid"]
+                }
+            }"#,
+            );
+            assert!(qs_write.internal_create(au, vec![e]).is_ok());
+            qs_write.commit(au).expect("failed to commit");
+
+            let secret = {
+                let qs_read = qs.read().expect("Failed to begin txn");
+                let rs = qs_read
+                    .internal_search(
+                        au,
+                        filter!(f_eq("name", PartialValue::new_iutf8s("test_app"))),
+                    )
+                    .expect("Internal search failure");
+                rs[0]
+                    .get_ava_single_string("oauth2_rs_basic_secret")
+                    .expect("no secret generated")
+            };
+
+            let uat = UserAuthToken {
+                name: "admin".to_string(),
+                displayname: "admin".to_string(),
+                uuid: UUID_ADMIN.to_string(),
+                application: None,
+                groups: vec![Group {
+                    name: "idm_admins".to_string(),
+                    uuid: Uuid::new_v4().to_string(),
+                }],
+                claims: Vec::new(),
+                elevated_until: None,
+            };
+            let verifier = "x".repeat(64);
+            let req = Oauth2AuthoriseRequest {
+                response_type: "code".to_string(),
+                client_id: "test_app".to_string(),
+                redirect_uri: "https://app.example.com/cb".to_string(),
+                scope: Some("openid".to_string()),
+                state: None,
+                nonce: None,
+                code_challenge: Some(base64url(&sha256(verifier.as_bytes()))),
+                code_challenge_method: Some("S256".to_string()),
+            };
+            let ct = Duration::from_secs(TEST_CURRENT_TIME);
+
+            let mut idms_write = idms.write();
+            let uri = idms_write
+                .oauth2_authorise(au, &uat, &req, ct)
+                .expect("authorise failed");
+            idms_write.commit().expect("failed to commit");
+            let code = uri
+                .splitn(2, "code=")
+                .nth(1)
+                .expect("no code in redirect")
+                .to_string();
+
+            let treq = Oauth2TokenRequest {
+                grant_type: "authorization_code".to_string(),
+                code: code,
+                redirect_uri: "https://app.example.com/cb".to_string(),
+                code_verifier: Some(verifier),
+                client_id: Some("test_app".to_string()),
+                client_secret: Some(secret.clone()),
+            };
+            let origin = "https://idm.example.com";
+
+            // A wrong secret doesn't spend the code.
+            let mut idms_write = idms.write();
+            let bad = Some(("test_app".to_string(), "wrong".to_string()));
+            assert_eq!(
+                idms_write
+                    .oauth2_token_exchange(au, &treq, bad, origin, ct)
+                    .err(),
+                Some(OperationError::Oauth2("invalid_client".to_string()))
+            );
+            let resp = idms_write
+                .oauth2_token_exchange(au, &treq, None, origin, ct)
+                .expect("exchange failed");
+            assert!(resp.id_token.is_some());
+            // But a code works only once.
+            assert_eq!(
+                idms_write
+                    .oauth2_token_exchange(au, &treq, None, origin, ct)
+                    .err(),
+                Some(OperationError::Oauth2("invalid_grant".to_string()))
+            );
+            idms_write.commit().expect("failed to commit");
+
+            let idms_prox_read = idms.proxy_read().expect("Failed to begin txn");
+            let disc = idms_prox_read
+                .oauth2_discovery(au, "test_app", origin)
+                .expect("discovery failed");
+            assert_eq!(
+                disc.issuer,
+                "https://idm.example.com/oauth2/openid/test_app"
+            );
+            assert_eq!(disc.scopes_supported, vec!["openid".to_string()]);
+            assert_eq!(
+                idms_prox_read
+                    .oauth2_jwks(au, "test_app")
+                    .map(|j| j.keys.len()),
+                Ok(1)
+            );
+            assert!(idms_prox_read.oauth2_jwks(au, "nothing").is_err());
         })
     }
 }
@@ -0,0 +1,180 @@
+use uuid::Uuid;
+
+use crate::audit::AuditScope;
+use crate::entry::{Entry, EntryCommitted, EntryReduced, EntryValid};
+use crate::server::{QueryServerReadTransaction, QueryServerTransaction};
+use crate::value::PartialValue;
+use kanidm_proto::v1::OperationError;
+use kanidm_proto::v1::{UnixGroupToken, UnixUserToken};
+
+lazy_static! {
+    static ref PVCLASS_ACCOUNT: PartialValue = PartialValue::new_class("account");
+    static ref PVCLASS_POSIXACCOUNT: PartialValue = PartialValue::new_class("posixaccount");
+    static ref PVCLASS_GROUP: PartialValue = PartialValue::new_class("group");
+    static ref PVCLASS_POSIXGROUP: PartialValue = PartialValue::new_class("posixgroup");
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct UnixUserAccount {
+    pub name: String,
+    pub displayname: String,
+    pub uuid: Uuid,
+    pub gidnumber: u32,
+    pub shell: Option<String>,
+    pub home_directory: Option<String>,
+    // Only the posix groups, not including the user private group.
+    pub groups: Vec<UnixGroup>,
+}
+
+impl UnixUserAccount {
+    pub(crate) fn try_from_entry_reduced(
+        au: &mut AuditScope,
+        value: Entry<EntryReduced, EntryCommitted>,
+        qs: &QueryServerReadTransaction,
+    ) -> Result<Self, OperationError> {
+        if !value.attribute_value_pres("class", &PVCLASS_ACCOUNT) {
+            return Err(OperationError::InvalidAccountState(
+                "Missing class: account".to_string(),
+            ));
+        }
+
+        if !value.attribute_value_pres("class", &PVCLASS_POSIXACCOUNT) {
+            return Err(OperationError::InvalidAccountState(
+                "Missing class: posixaccount".to_string(),
+            ));
+        }
+
+        let name =
+            value
+                .get_ava_single_string("name")
+                .ok_or(OperationError::InvalidAccountState(
+                    "Missing attribute: name".to_string(),
+                ))?;
+
+        let uuid = value.get_uuid().clone();
+
+        let displayname = value
+            .get_ava_single_string("displayname")
+            .unwrap_or_else(|| name.clone());
+
+        let gidnumber =
+            value
+                .get_ava_single_uint32("gidnumber")
+                .ok_or(OperationError::InvalidAccountState(
+                    "Missing attribute: gidnumber".to_string(),
+                ))?;
+
+        let groups = match value.get_ava_reference_uuid("memberof") {
+            Some(l) => {
+                // Only groups that have been extended matter to a unix client.
+                let f = filter!(f_and!([
+                    f_eq("class", PVCLASS_POSIXGROUP.clone()),
+                    f_or(
+                        l.into_iter()
+                            .map(|u| f_eq("uuid", PartialValue::new_uuidr(u)))
+                            .collect()
+                    )
+                ]));
+                let ges = qs.internal_search(au, f)?;
+                let groups: Result<Vec<_>, _> =
+                    ges.into_iter().map(UnixGroup::try_from_entry).collect();
+                groups?
+            }
+            None => Vec::new(),
+        };
+
+        Ok(UnixUserAccount {
+            name: name,
+            displayname: displayname,
+            uuid: uuid,
+            gidnumber: gidnumber,
+            shell: value.get_ava_single_string("loginshell"),
+            home_directory: value.get_ava_single_string("homedirectory"),
+            groups: groups,
+        })
+    }
+
+    pub(crate) fn to_unixusertoken(&self) -> Result<UnixUserToken, OperationError> {
+        // Every account is its own user private group, so its primary group
+        // has the same name and number as the account.
+        let upg = UnixGroupToken {
+            name: self.name.clone(),
+            uuid: self.uuid.to_hyphenated_ref().to_string(),
+            gidnumber: self.gidnumber,
+        };
+        let mut groups = vec![upg];
+        groups.extend(self.groups.iter().map(|g| g.to_unixgrouptoken()));
+
+        Ok(UnixUserToken {
+            name: self.name.clone(),
+            displayname: self.displayname.clone(),
+            uuid: self.uuid.to_hyphenated_ref().to_string(),
+            gidnumber: self.gidnumber,
+            shell: self.shell.clone(),
+            home_directory: self.home_directory.clone(),
+            groups: groups,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct UnixGroup {
+    pub name: String,
+    pub uuid: Uuid,
+    pub gidnumber: u32,
+}
+
+macro_rules! try_from_group_e {
+    ($value:expr) => {{
+        if !$value.attribute_value_pres("class", &PVCLASS_GROUP) {
+            return Err(OperationError::InvalidAccountState(
+                "Missing class: group".to_string(),
+            ));
+        }
+
+        if !$value.attribute_value_pres("class", &PVCLASS_POSIXGROUP) {
+            return Err(OperationError::InvalidAccountState(
+                "Missing class: posixgroup".to_string(),
+            ));
+        }
+
+        let name =
+            $value
+                .get_ava_single_string("name")
+                .ok_or(OperationError::InvalidAccountState(
+                    "Missing attribute: name".to_string(),
+                ))?;
+
+        let gidnumber = $value.get_ava_single_uint32("gidnumber").ok_or(
+            OperationError::InvalidAccountState("Missing attribute: gidnumber".to_string()),
+        )?;
+
+        Ok(UnixGroup {
+            name: name,
+            uuid: $value.get_uuid().clone(),
+            gidnumber: gidnumber,
+        })
+    }};
+}
+
+impl UnixGroup {
+    pub(crate) fn try_from_entry(
+        value: Entry<EntryValid, EntryCommitted>,
+    ) -> Result<Self, OperationError> {
+        try_from_group_e!(value)
+    }
+
+    pub(crate) fn try_from_entry_reduced(
+        value: Entry<EntryReduced, EntryCommitted>,
+    ) -> Result<Self, OperationError> {
+        try_from_group_e!(value)
+    }
+
+    pub(crate) fn to_unixgrouptoken(&self) -> UnixGroupToken {
+        UnixGroupToken {
+            name: self.name.clone(),
+            uuid: self.uuid.to_hyphenated_ref().to_string(),
+            gidnumber: self.gidnumber,
+        }
+    }
+}
@@ -0,0 +1,573 @@
+// Bulk load of an LDIF export from another directory (slapcat, db2ldif), or
+// one of our own ldif backups. Each record is mapped through our schema.
+// Attributes we don't know are skipped rather than failing the import, as
+// exports carry plenty we have no use for - everything skipped is logged.
+//
+// References (IE member) are often to entries later in the file, so they are
+// held back and added once every entry exists. A DN is resolved by the value
+// of its first RDN, which must be a name (or uuid) we hold by then.
+//
+// import_entries is for our own entries, in far greater numbers - one json
+// entry per line, as the create api takes them. The whole file is checked
+// first, then each batch is created in a txn of its own, so memory and the
+// write lock are held for no longer than a batch. Entries aren't indexed or
+// given to the post plugins as they go. Instead everything is reindexed once
+// at the end, and memberof run over the groups that were imported. Should
+// the import die before then, kanidmd repair_indexes finds what it missed.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::BufRead;
+use uuid::Uuid;
+
+use crate::audit::AuditScope;
+use crate::be::ldif::LdifReader;
+use crate::entry::{Entry, EntryCommitted, EntryInvalid, EntryNew, EntryValid};
+use crate::modify::{Modify, ModifyList};
+use crate::plugins::Plugins;
+use crate::schema::SchemaTransaction;
+use crate::server::{QueryServer, QueryServerTransaction, QueryServerWriteTransaction};
+use crate::value::{PartialValue, SyntaxType, Value};
+use kanidm_proto::v1::Entry as ProtoEntry;
+use kanidm_proto::v1::{OperationError, PluginError};
+
+// Entries are submitted in batches of this many, within the one txn.
+const IMPORT_BATCH_SIZE: usize = 1000;
+
+// These are maintained by the server, so are never taken from an import.
+const IMPORT_IGNORE_ATTRS: [&str; 3] = ["dn", "memberof", "directmemberof"];
+
+// LDAP names for the attributes and classes we call something else.
+fn map_attr_name(a: &str) -> &str {
+    match a {
+        "objectclass" => "class",
+        "entryuuid" => "uuid",
+        "uid" => "name",
+        "uniquemember" | "memberuid" => "member",
+        _ => a,
+    }
+}
+
+fn map_class_name(c: &str) -> String {
+    let c = c.to_lowercase();
+    match c.as_str() {
+        "top" => "object".to_string(),
+        "inetorgperson" | "organizationalperson" | "posixaccount" => "account".to_string(),
+        "groupofnames" | "groupofuniquenames" | "posixgroup" => "group".to_string(),
+        _ => c,
+    }
+}
+
+// The value of the first RDN, IE alice from uid=alice,ou=people,dc=example.
+// Anything that isn't a DN is returned as is.
+fn rdn_value(v: &str) -> &str {
+    let rdn = v.split(',').next().unwrap_or(v);
+    match rdn.find('=') {
+        Some(i) => rdn[i + 1..].trim(),
+        None => v,
+    }
+}
+
+fn resolve_uuid(
+    audit: &mut AuditScope,
+    qs_write: &QueryServerWriteTransaction,
+    v: &str,
+) -> Option<Uuid> {
+    let v = rdn_value(v);
+    Uuid::parse_str(v)
+        .ok()
+        .or_else(|| qs_write.name_to_uuid(audit, v).ok())
+}
+
+// Any references are held back against the entry's uuid, which is generated
+// here if the record didn't carry one.
+fn map_record(
+    audit: &mut AuditScope,
+    qs_write: &QueryServerWriteTransaction,
+    record: Vec<(String, String)>,
+    references: &mut BTreeMap<Uuid, Vec<(String, String)>>,
+    skipped: &mut usize,
+) -> Result<Entry<EntryInvalid, EntryNew>, OperationError> {
+    let schema = qs_write.get_schema();
+    let attributes = schema.get_attributes();
+    let classes = schema.get_classes();
+
+    let dn = record.first().map(|(_, v)| v.clone()).unwrap_or_default();
+    let mut e = Entry::new();
+    let mut uuid = None;
+    let mut cn = None;
+    let mut refs = Vec::new();
+
+    for (attr, value) in record.into_iter() {
+        if IMPORT_IGNORE_ATTRS.contains(&attr.as_str()) {
+            continue;
+        }
+        if attr == "cn" && cn.is_none() {
+            cn = Some(value.clone());
+        }
+        let attr = schema.normalise_attr_name(map_attr_name(attr.as_str()));
+
+        if attr == "class" {
+            let c = map_class_name(value.as_str());
+            if classes.contains_key(&c) {
+                e.add_ava("class", &Value::new_class(c.as_str()));
+            } else {
+                audit_log!(audit, "{}: skipping unknown class {}", dn, c);
+                *skipped += 1;
+            }
+            continue;
+        }
+
+        match attributes.get(&attr).map(|sa| &sa.syntax) {
+            None => {
+                audit_log!(audit, "{}: skipping unknown attribute {}", dn, attr);
+                *skipped += 1;
+            }
+            Some(SyntaxType::REFERENCE_UUID) => refs.push((attr, value)),
+            Some(SyntaxType::UUID) => match resolve_uuid(audit, qs_write, value.as_str()) {
+                Some(u) => {
+                    if attr == "uuid" {
+                        uuid = Some(u);
+                    }
+                    e.add_ava(attr.as_str(), &Value::new_uuid(u));
+                }
+                None => {
+                    audit_log!(audit, "{}: skipping unresolved {} {}", dn, attr, value);
+                    *skipped += 1;
+                }
+            },
+            Some(SyntaxType::CREDENTIAL)
+            | Some(SyntaxType::RADIUS_UTF8STRING)
+            | Some(SyntaxType::SSHKEY) => {
+                audit_log!(
+                    audit,
+                    "{}: skipping {}, it must be set again through the idm api",
+                    dn,
+                    attr
+                );
+                *skipped += 1;
+            }
+            Some(_) => {
+                let v = qs_write.clone_value(audit, &attr, &value).map_err(|err| {
+                    audit_log!(audit, "{}: invalid value for {} -> {:?}", dn, attr, err);
+                    err
+                })?;
+                e.add_ava(attr.as_str(), &v);
+            }
+        }
+    }
+
+    // Other directories name entries by their DN, so take the name from the
+    // RDN, and the displayname from the cn where we need one.
+    if !e.attribute_pres("name") && !dn.to_lowercase().starts_with("uuid=") {
+        e.add_ava(
+            "name",
+            &Value::new_iutf8(rdn_value(dn.as_str()).to_string()),
+        );
+    }
+    if !e.attribute_pres("displayname")
+        && (e.attribute_value_pres("class", &PartialValue::new_class("account"))
+            || e.attribute_value_pres("class", &PartialValue::new_class("person")))
+    {
+        if let Some(cn) = cn {
+            e.add_ava("displayname", &Value::new_utf8(cn));
+        }
+    }
+
+    let uuid = match uuid {
+        Some(u) => u,
+        None => {
+            let u = Uuid::new_v4();
+            e.add_ava("uuid", &Value::new_uuidr(&u));
+            u
+        }
+    };
+    if !refs.is_empty() {
+        references.insert(uuid, refs);
+    }
+    Ok(e)
+}
+
+// Returns the number of entries imported, and the number of values that were
+// skipped. The import is all or nothing, as it happens within qs_write.
+pub fn import_ldif<R: BufRead>(
+    audit: &mut AuditScope,
+    qs_write: &mut QueryServerWriteTransaction,
+    reader: R,
+) -> Result<(usize, usize), OperationError> {
+    let mut ldif = LdifReader::new(reader);
+    let mut references = BTreeMap::new();
+    let mut skipped = 0;
+    let mut imported = 0;
+    let mut batch = Vec::with_capacity(IMPORT_BATCH_SIZE);
+
+    while let Some(record) = try_audit!(
+        audit,
+        ldif.next_record(),
+        "ldif error {:?}",
+        OperationError::InvalidRequestState
+    ) {
+        batch.push(map_record(
+            audit,
+            qs_write,
+            record,
+            &mut references,
+            &mut skipped,
+        )?);
+        if batch.len() >= IMPORT_BATCH_SIZE {
+            let full = std::mem::replace(&mut batch, Vec::with_capacity(IMPORT_BATCH_SIZE));
+            imported += full.len();
+            audit_log!(audit, "import: creating batch of {}", full.len());
+            qs_write.internal_create(audit, full)?;
+        }
+    }
+    if !batch.is_empty() {
+        imported += batch.len();
+        audit_log!(audit, "import: creating batch of {}", batch.len());
+        qs_write.internal_create(audit, batch)?;
+    }
+
+    // Everything exists now, so the references can be resolved.
+    for (u, refs) in references.into_iter() {
+        let mut mods = Vec::with_capacity(refs.len());
+        for (attr, v) in refs.into_iter() {
+            match resolve_uuid(audit, qs_write, v.as_str()) {
+                Some(r) => mods.push(Modify::Present(attr, Value::new_refer(r))),
+                None => {
+                    audit_log!(audit, "{}: skipping unresolved {} {}", u, attr, v);
+                    skipped += 1;
+                }
+            }
+        }
+        if !mods.is_empty() {
+            qs_write.internal_modify(
+                audit,
+                filter!(f_eq("uuid", PartialValue::new_uuidr(&u))),
+                ModifyList::new_list(mods),
+            )?;
+        }
+    }
+
+    Ok((imported, skipped))
+}
+
+// None for a blank line.
+fn parse_line(
+    audit: &mut AuditScope,
+    qs_write: &QueryServerWriteTransaction,
+    line_no: usize,
+    line: std::io::Result<String>,
+) -> Result<Option<Entry<EntryInvalid, EntryNew>>, OperationError> {
+    let line = try_audit!(
+        audit,
+        line,
+        "import: read error {:?}",
+        OperationError::InvalidRequestState
+    );
+    if line.trim().is_empty() {
+        return Ok(None);
+    }
+    let pe: ProtoEntry = serde_json::from_str(line.as_str()).map_err(|e| {
+        audit_log!(audit, "import: line {} is not an entry -> {:?}", line_no, e);
+        OperationError::SerdeJsonError
+    })?;
+    Entry::from_proto_entry(audit, &pe, qs_write).map(Some)
+}
+
+// Everything the post plugins and attrunique would have caught across
+// batches: a value of a unique attribute (uuid among them) given twice, and a
+// reference to neither an entry in the file nor a live one we hold. As
+// references to names only resolve against what's indexed, references to
+// entries in the file must be by uuid. Returns the number of entries.
+fn check_entries<R: BufRead>(
+    audit: &mut AuditScope,
+    qs_write: &QueryServerWriteTransaction,
+    reader: R,
+) -> Result<usize, OperationError> {
+    let schema = qs_write.get_schema();
+    let unique_attrs = schema.get_attributes_unique();
+    let ref_attrs: Vec<String> = schema
+        .get_reference_types()
+        .keys()
+        .map(|a| (*a).clone())
+        .collect();
+
+    let mut count = 0;
+    let mut unique: BTreeSet<(String, PartialValue)> = BTreeSet::new();
+    let mut known: BTreeSet<Uuid> = BTreeSet::new();
+    let mut refs: BTreeMap<Uuid, usize> = BTreeMap::new();
+    for (i, line) in reader.lines().enumerate() {
+        let e = match parse_line(audit, qs_write, i + 1, line)? {
+            Some(e) => e,
+            None => continue,
+        };
+        count += 1;
+        for attr in unique_attrs.iter() {
+            for v in e.get_ava(attr).into_iter().flatten() {
+                if !unique.insert((attr.clone(), v.to_partialvalue())) {
+                    audit_log!(audit, "import: line {} repeats a {} value", i + 1, attr);
+                    return Err(OperationError::Plugin(PluginError::AttrUnique(format!(
+                        "{} repeated at line {}",
+                        attr,
+                        i + 1
+                    ))));
+                }
+            }
+        }
+        if let Some(u) = e.get_ava_single("uuid").and_then(|v| v.to_uuid()) {
+            known.insert(u.clone());
+        }
+        for attr in ref_attrs.iter() {
+            for u in e.get_ava_reference_uuid(attr).into_iter().flatten() {
+                refs.entry(u.clone()).or_insert(i + 1);
+            }
+        }
+    }
+
+    for (u, line_no) in refs.into_iter().filter(|(u, _)| !known.contains(u)) {
+        let exists = qs_write.internal_exists(
+            audit,
+            filter!(f_eq("uuid", PartialValue::new_uuid(u.clone()))),
+        )?;
+        if !exists {
+            audit_log!(audit, "import: line {} refers to missing {}", line_no, u);
+            return Err(OperationError::Plugin(PluginError::ReferentialIntegrity(
+                format!("missing reference at line {}", line_no),
+            )));
+        }
+    }
+    Ok(count)
+}
+
+// Creates the entries a batch at a time, adding to imported as each commits,
+// and noting the groups for import_finish.
+fn import_batches<R: BufRead>(
+    audit: &mut AuditScope,
+    qs: &QueryServer,
+    reader: R,
+    batch_size: usize,
+    imported: &mut usize,
+    groups: &mut Vec<Uuid>,
+) -> Result<(), OperationError> {
+    let mut lines = reader.lines().enumerate().peekable();
+    while lines.peek().is_some() {
+        let mut qs_write = qs.write()?;
+        let mut batch = Vec::with_capacity(batch_size);
+        while batch.len() < batch_size {
+            match lines.next() {
+                Some((i, line)) => {
+                    if let Some(e) = parse_line(audit, &qs_write, i + 1, line)? {
+                        batch.push(e);
+                    }
+                }
+                None => break,
+            }
+        }
+        if batch.is_empty() {
+            break;
+        }
+        let created = qs_write.import_create(audit, batch)?;
+        qs_write.commit(audit)?;
+
+        *imported += created.len();
+        groups.extend(
+            created
+                .iter()
+                .filter(|e| e.attribute_value_pres("class", &PartialValue::new_class("group")))
+                .map(|e| e.get_uuid().clone()),
+        );
+        info!("import: {} entries imported", imported);
+    }
+    Ok(())
+}
+
+// Once every batch is in, build the indexes they skipped, and give memberof
+// the groups.
+fn import_finish(
+    audit: &mut AuditScope,
+    qs: &QueryServer,
+    groups: &[Uuid],
+    batch_size: usize,
+) -> Result<(), OperationError> {
+    let mut qs_write = qs.write()?;
+    qs_write.reindex(audit, true)?;
+    for chunk in groups.chunks(batch_size) {
+        let filt = filter!(f_or(
+            chunk
+                .iter()
+                .map(|u| f_eq("uuid", PartialValue::new_uuid(u.clone())))
+                .collect()
+        ));
+        let cand: Vec<Entry<EntryValid, EntryCommitted>> = qs_write.internal_search(audit, filt)?;
+        Plugins::run_post_import(audit, &mut qs_write, &cand)?;
+    }
+    qs_write.commit(audit)
+}
+
+// open is called twice, for the check and then the import. Nothing is
+// written unless the check passes, but once it has, batches that committed
+// before any failure stay. Returns the number of entries imported.
+pub fn import_entries<R, F>(
+    audit: &mut AuditScope,
+    qs: &QueryServer,
+    open: F,
+    batch_size: usize,
+) -> Result<usize, OperationError>
+where
+    R: BufRead,
+    F: Fn() -> Result<R, OperationError>,
+{
+    let count = {
+        let qs_write = qs.write()?;
+        check_entries(audit, &qs_write, open()?)?
+    };
+    audit_log!(audit, "import: {} entries checked", count);
+    info!("import: {} entries checked, importing ...", count);
+
+    let mut imported = 0;
+    let mut groups = Vec::new();
+    let res = open().and_then(|reader| {
+        import_batches(audit, qs, reader, batch_size, &mut imported, &mut groups)
+    });
+    if imported > 0 {
+        import_finish(audit, qs, groups.as_slice(), batch_size)?;
+    }
+    res.map(|_| imported)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::import::{import_entries, import_ldif};
+    use crate::server::{QueryServer, QueryServerTransaction};
+    use crate::value::PartialValue;
+    use kanidm_proto::v1::{OperationError, PluginError};
+
+    // As slapcat would give it to us. The group comes first, so its members
+    // don't exist yet when it's created.
+    static LDIF_OPENLDAP: &'static str = "version: 1
+
+dn: cn=testgroup,ou=groups,dc=example,dc=com
+objectClass: top
+objectClass: groupOfNames
+cn: testgroup
+member: uid=testperson,ou=people,dc=example,dc=com
+member: uid=missing,ou=people,dc=example,dc=com
+
+dn: uid=testperson,ou=people,dc=example,dc=com
+objectClass: top
+objectClass: inetOrgPerson
+objectClass: shadowAccount
+uid: testperson
+cn:: VGVzdCBQw6lyc29u
+sn: Person
+entryUUID: 2e9a4b8e-14a5-4d4b-8a6e-dfe0c50f2b4d
+description: a person that
+  goes on
+";
+
+    #[test]
+    fn test_import_ldif() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let mut qs_write = server.write().expect("Failed to begin txn");
+            // We have no cn, sn or shadowAccount, and the missing member
+            // can't be resolved, so those are skipped.
+            assert_eq!(
+                import_ldif(audit, &mut qs_write, LDIF_OPENLDAP.as_bytes()),
+                Ok((2, 5))
+            );
+            assert!(qs_write.commit(audit).is_ok());
+
+            let qs_read = server.read().expect("Failed to begin txn");
+            let person = qs_read
+                .internal_search(
+                    audit,
+                    filter!(f_eq("name", PartialValue::new_iutf8s("testperson"))),
+                )
+                .expect("search failed");
+            let person = person.first().expect("person not imported");
+            assert_eq!(
+                person.get_ava_single_string("displayname"),
+                Some("Test Pérson".to_string())
+            );
+            assert_eq!(
+                person.get_ava_single_string("description"),
+                Some("a person that goes on".to_string())
+            );
+            assert!(person.attribute_value_pres(
+                "uuid",
+                &PartialValue::new_uuids("2e9a4b8e-14a5-4d4b-8a6e-dfe0c50f2b4d")
+                    .expect("invalid uuid")
+            ));
+
+            let group = qs_read
+                .internal_search(
+                    audit,
+                    filter!(f_eq("name", PartialValue::new_iutf8s("testgroup"))),
+                )
+                .expect("search failed");
+            let group = group.first().expect("group not imported");
+            assert!(group.attribute_value_pres(
+                "member",
+                &PartialValue::new_refer_s("2e9a4b8e-14a5-4d4b-8a6e-dfe0c50f2b4d")
+                    .expect("invalid uuid")
+            ));
+        })
+    }
+
+    // The group comes first, so it's created a batch before its member.
+    static ENTRIES_JSON: &'static str = r#"{"attrs":{"class":["group"],"name":["testgroup"],"member":["2e9a4b8e-14a5-4d4b-8a6e-dfe0c50f2b4d"]}}
+
+{"attrs":{"class":["person"],"name":["testperson1"],"uuid":["2e9a4b8e-14a5-4d4b-8a6e-dfe0c50f2b4d"],"description":["testperson"],"displayname":["Test Person"]}}
+{"attrs":{"class":["person"],"name":["testperson2"],"description":["testperson"],"displayname":["Test Person"]}}
+"#;
+
+    #[test]
+    fn test_import_entries() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            assert_eq!(
+                import_entries(audit, server, || Ok(ENTRIES_JSON.as_bytes()), 1),
+                Ok(3)
+            );
+
+            // Found through the indexes, so those were built, and memberof
+            // caught up.
+            let qs_read = server.read().expect("Failed to begin txn");
+            let search = |audit: &mut AuditScope, name: &str| {
+                qs_read
+                    .internal_search(audit, filter!(f_eq("name", PartialValue::new_iutf8s(name))))
+                    .expect("search failed")
+            };
+            let group = search(audit, "testgroup");
+            let group = group.first().expect("group not imported");
+            let person = search(audit, "testperson1");
+            let person = person.first().expect("person not imported");
+            assert!(person
+                .attribute_value_pres("memberof", &PartialValue::new_refer_r(group.get_uuid())));
+            assert_eq!(search(audit, "testperson2").len(), 1);
+            drop(qs_read);
+
+            // Nothing is written if the file doesn't check out.
+            let dangling = r#"{"attrs":{"class":["group"],"name":["testgroup2"],"member":["d2b496bd-8493-47b7-8142-f568b5cf47ee"]}}"#;
+            match import_entries(audit, server, || Ok(dangling.as_bytes()), 1) {
+                Err(OperationError::Plugin(PluginError::ReferentialIntegrity(_))) => {}
+                r => panic!("unexpected {:?}", r),
+            }
+            let repeated = r#"{"attrs":{"class":["group"],"name":["testgroup3"]}}
+{"attrs":{"class":["group"],"name":["testgroup3"]}}"#;
+            match import_entries(audit, server, || Ok(repeated.as_bytes()), 1) {
+                Err(OperationError::Plugin(PluginError::AttrUnique(_))) => {}
+                r => panic!("unexpected {:?}", r),
+            }
+            let qs_read = server.read().expect("Failed to begin txn");
+            assert!(qs_read
+                .internal_search(
+                    audit,
+                    filter!(f_or!([
+                        f_eq("name", PartialValue::new_iutf8s("testgroup2")),
+                        f_eq("name", PartialValue::new_iutf8s("testgroup3"))
+                    ]))
+                )
+                .expect("search failed")
+                .is_empty());
+        })
+    }
+}
@@ -1,18 +1,38 @@
 use actix::prelude::*;
+use rand::{thread_rng, Rng};
 use std::time::Duration;
 
+use crate::actors::v1_read::QueryServerReadV1;
 use crate::actors::v1_write::QueryServerWriteV1;
-use crate::constants::PURGE_TIMEOUT;
-use crate::event::{PurgeRecycledEvent, PurgeTombstoneEvent};
+use crate::config::OnlineBackup;
+use crate::constants::{
+    ATTR_MIGRATE_INTERVAL, DB_ANALYZE_INTERVAL, DB_ANALYZE_JITTER, DB_HEARTBEAT_INTERVAL,
+    PURGE_TIMEOUT, REPORT_CHECK_INTERVAL,
+};
+use crate::event::{
+    DbAnalyzeEvent, DbHeartbeatEvent, MigrateAliasesEvent, OnlineBackupEvent, PurgeRecycledEvent,
+    PurgeTombstoneEvent, RunReportsEvent,
+};
 
 pub struct IntervalActor {
     // Store any addresses we require
     server: actix::Addr<QueryServerWriteV1>,
+    // Backups only need a read txn, so they don't hold up writes.
+    server_read: actix::Addr<QueryServerReadV1>,
+    online_backup: Option<OnlineBackup>,
 }
 
 impl IntervalActor {
-    pub fn new(server: actix::Addr<QueryServerWriteV1>) -> Self {
-        IntervalActor { server: server }
+    pub fn new(
+        server: actix::Addr<QueryServerWriteV1>,
+        server_read: actix::Addr<QueryServerReadV1>,
+        online_backup: Option<OnlineBackup>,
+    ) -> Self {
+        IntervalActor {
+            server: server,
+            server_read: server_read,
+            online_backup: online_backup,
+        }
     }
 
     // Define new events here
@@ -26,6 +46,43 @@ impl IntervalActor {
         let pe = PurgeRecycledEvent::new();
         self.server.do_send(pe)
     }
+
+    fn db_heartbeat(&mut self) {
+        self.server.do_send(DbHeartbeatEvent)
+    }
+
+    fn online_backup(&mut self, ob: &OnlineBackup) {
+        let be = OnlineBackupEvent::new(ob.path.clone(), ob.versions);
+        self.server_read.do_send(be)
+    }
+
+    fn db_analyze(&mut self) {
+        self.server.do_send(DbAnalyzeEvent)
+    }
+
+    // Each report has its own interval, this only looks for those due.
+    fn run_reports(&mut self) {
+        self.server.do_send(RunReportsEvent)
+    }
+
+    // Entries still holding a renamed attribute are moved over a batch at a
+    // time.
+    fn migrate_aliases(&mut self) {
+        self.server.do_send(MigrateAliasesEvent)
+    }
+
+    // Each run is scheduled from the last, so every interval gets its own
+    // jitter.
+    fn schedule_db_analyze(&mut self, ctx: &mut actix::Context<Self>) {
+        let jitter = thread_rng().gen_range(0, DB_ANALYZE_JITTER);
+        ctx.run_later(
+            Duration::from_secs(DB_ANALYZE_INTERVAL + jitter),
+            move |act, ctx| {
+                act.db_analyze();
+                act.schedule_db_analyze(ctx);
+            },
+        );
+    }
 }
 
 impl Actor for IntervalActor {
@@ -39,5 +96,29 @@ impl Actor for IntervalActor {
         ctx.run_interval(Duration::from_secs(PURGE_TIMEOUT), move |act, _ctx| {
             act.purge_tombstones();
         });
+        ctx.run_interval(
+            Duration::from_secs(DB_HEARTBEAT_INTERVAL),
+            move |act, _ctx| {
+                act.db_heartbeat();
+            },
+        );
+        ctx.run_interval(
+            Duration::from_secs(REPORT_CHECK_INTERVAL),
+            move |act, _ctx| {
+                act.run_reports();
+            },
+        );
+        ctx.run_interval(
+            Duration::from_secs(ATTR_MIGRATE_INTERVAL),
+            move |act, _ctx| {
+                act.migrate_aliases();
+            },
+        );
+        self.schedule_db_analyze(ctx);
+        if let Some(ob) = self.online_backup.clone() {
+            ctx.run_interval(Duration::from_secs(ob.interval), move |act, _ctx| {
+                act.online_backup(&ob);
+            });
+        }
     }
 }
@@ -0,0 +1,83 @@
+// Panics in an operation are caught at the worker, so that one bad request
+// (IE an unexpected unwrap deep in the backend) fails on its own rather than
+// taking the worker thread, and with it the actor, down.
+//
+// Unwinding drops everything the operation held. Backend transactions roll
+// back on drop, so their connection goes back to the pool clean, and our own
+// locks all recover from poisoning.
+
+use backtrace::Backtrace;
+use kanidm_proto::v1::OperationError;
+use std::cell::RefCell;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Once;
+
+static HOOK: Once = Once::new();
+
+thread_local! {
+    static LAST_PANIC: RefCell<Option<String>> = RefCell::new(None);
+}
+
+// The backtrace is only available while the panic hook runs, so stash it for
+// catch_panic to pick up once the stack has unwound. The default hook still
+// runs, so the panic is reported as it always was.
+fn install_hook() {
+    HOOK.call_once(|| {
+        let default = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            let bt = Backtrace::new();
+            LAST_PANIC.with(|p| *p.borrow_mut() = Some(format!("{}\n{:?}", info, bt)));
+            default(info);
+        }));
+    });
+}
+
+// What a handler returns in place of a result when its operation panicked.
+pub trait FromPanic {
+    fn from_panic() -> Self;
+}
+
+impl<T> FromPanic for Result<T, OperationError> {
+    fn from_panic() -> Self {
+        Err(OperationError::Panicked)
+    }
+}
+
+impl FromPanic for () {
+    fn from_panic() -> Self {}
+}
+
+// On panic, the error is the panic message with its backtrace.
+pub fn catch_panic<T, F: FnOnce() -> T>(f: F) -> Result<T, String> {
+    install_hook();
+    panic::catch_unwind(AssertUnwindSafe(f)).map_err(|_| {
+        LAST_PANIC
+            .with(|p| p.borrow_mut().take())
+            .unwrap_or_else(|| "unknown panic".to_string())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::audit::AuditScope;
+    use kanidm_proto::v1::OperationError;
+
+    #[test]
+    fn test_isolated_segment_panic() {
+        let mut audit = AuditScope::new("test_isolated_segment_panic");
+
+        let r: Result<u32, OperationError> = isolated_segment!(&mut audit, || Ok(1));
+        assert_eq!(r, Ok(1));
+
+        let r: Result<u32, OperationError> = isolated_segment!(&mut audit, || {
+            let v: Option<u32> = None;
+            Ok(v.expect("this is a test panic"))
+        });
+        assert_eq!(r, Err(OperationError::Panicked));
+
+        // The worker is still usable afterwards.
+        let r: Result<u32, OperationError> = isolated_segment!(&mut audit, || Ok(2));
+        assert_eq!(r, Ok(2));
+        let () = isolated_segment!(&mut audit, || panic!("this is a test panic"));
+    }
+}
@@ -0,0 +1,347 @@
+// Just enough BER for LDAP. Every tag LDAP uses fits in the one byte, so
+// high tag numbers aren't supported, and we only ever produce the definite,
+// shortest length forms. Values are kept as a tree of TLVs, and the protocol
+// layer in mod.rs picks them apart.
+
+use std::io::{self, Read};
+
+// The bit of a tag that says it holds further TLVs.
+const CONSTRUCTED: u8 = 0x20;
+// How deeply constructed TLVs may nest. Decoding recurses, as does what
+// mod.rs does with a filter, so this bounds the stack a message can use.
+// A search's filter starts at the third level, which leaves it plenty.
+const MAX_DEPTH: usize = 16;
+
+pub const TAG_BOOLEAN: u8 = 0x01;
+pub const TAG_INTEGER: u8 = 0x02;
+pub const TAG_OCTETS: u8 = 0x04;
+pub const TAG_ENUMERATED: u8 = 0x0a;
+pub const TAG_SEQUENCE: u8 = 0x30;
+pub const TAG_SET: u8 = 0x31;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Content {
+    Primitive(Vec<u8>),
+    Constructed(Vec<Tlv>),
+}
+
+// The tag is the whole identifier byte, so includes the constructed bit.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tlv {
+    pub tag: u8,
+    pub content: Content,
+}
+
+fn encode_len(len: usize, out: &mut Vec<u8>) {
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let bytes: Vec<u8> = len
+            .to_be_bytes()
+            .iter()
+            .skip_while(|b| **b == 0)
+            .cloned()
+            .collect();
+        out.push(0x80 | bytes.len() as u8);
+        out.extend(bytes);
+    }
+}
+
+// The length at the start of buf, and how many bytes it took. None if
+// there's not enough of buf to say, Err if it's not a length we accept.
+fn decode_len(buf: &[u8]) -> Result<Option<(usize, usize)>, ()> {
+    let first = match buf.first() {
+        Some(b) => *b,
+        None => return Ok(None),
+    };
+    if first < 0x80 {
+        return Ok(Some((first as usize, 1)));
+    }
+    // 0x80 alone is the indefinite form, which LDAP doesn't allow.
+    let n = (first & 0x7f) as usize;
+    if n == 0 || n > 4 {
+        return Err(());
+    }
+    if buf.len() < 1 + n {
+        return Ok(None);
+    }
+    let len = buf[1..=n]
+        .iter()
+        .fold(0usize, |acc, b| (acc << 8) | *b as usize);
+    Ok(Some((len, 1 + n)))
+}
+
+impl Tlv {
+    pub fn primitive(tag: u8, v: Vec<u8>) -> Self {
+        Tlv {
+            tag: tag,
+            content: Content::Primitive(v),
+        }
+    }
+
+    pub fn constructed(tag: u8, v: Vec<Tlv>) -> Self {
+        Tlv {
+            tag: tag,
+            content: Content::Constructed(v),
+        }
+    }
+
+    pub fn sequence(v: Vec<Tlv>) -> Self {
+        Self::constructed(TAG_SEQUENCE, v)
+    }
+
+    pub fn octets(tag: u8, v: &[u8]) -> Self {
+        Self::primitive(tag, v.to_vec())
+    }
+
+    pub fn string(v: &str) -> Self {
+        Self::octets(TAG_OCTETS, v.as_bytes())
+    }
+
+    // Two's complement, in as few bytes as keep the sign.
+    pub fn integer(tag: u8, v: i64) -> Self {
+        let bytes = v.to_be_bytes();
+        let mut start = 0;
+        while start < 7 {
+            let (b, next) = (bytes[start], bytes[start + 1]);
+            if (b == 0x00 && next & 0x80 == 0) || (b == 0xff && next & 0x80 != 0) {
+                start += 1;
+            } else {
+                break;
+            }
+        }
+        Self::primitive(tag, bytes[start..].to_vec())
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode_into(&mut out);
+        out
+    }
+
+    fn encode_into(&self, out: &mut Vec<u8>) {
+        match &self.content {
+            Content::Primitive(v) => {
+                out.push(self.tag);
+                encode_len(v.len(), out);
+                out.extend_from_slice(v.as_slice());
+            }
+            Content::Constructed(children) => {
+                let mut inner = Vec::new();
+                children.iter().for_each(|c| c.encode_into(&mut inner));
+                out.push(self.tag);
+                encode_len(inner.len(), out);
+                out.extend(inner);
+            }
+        }
+    }
+
+    // One TLV from the start of buf, and how many bytes of buf it took. None
+    // if buf doesn't hold all of it yet.
+    pub fn decode(buf: &[u8]) -> Result<Option<(Tlv, usize)>, ()> {
+        Self::decode_depth(buf, 0)
+    }
+
+    fn decode_depth(buf: &[u8], depth: usize) -> Result<Option<(Tlv, usize)>, ()> {
+        let tag = match buf.first() {
+            Some(t) => *t,
+            None => return Ok(None),
+        };
+        if tag & 0x1f == 0x1f {
+            return Err(());
+        }
+        let (len, lenlen) = match decode_len(&buf[1..])? {
+            Some(l) => l,
+            None => return Ok(None),
+        };
+        let start = 1 + lenlen;
+        if buf.len() - start < len {
+            return Ok(None);
+        }
+        let body = &buf[start..start + len];
+        let tlv = if tag & CONSTRUCTED != 0 {
+            if depth >= MAX_DEPTH {
+                return Err(());
+            }
+            let mut children = Vec::new();
+            let mut rest = body;
+            while !rest.is_empty() {
+                // The body is complete, so a child running off its end is
+                // malformed rather than short.
+                let (child, used) = Self::decode_depth(rest, depth + 1)?.ok_or(())?;
+                children.push(child);
+                rest = &rest[used..];
+            }
+            Self::constructed(tag, children)
+        } else {
+            Self::primitive(tag, body.to_vec())
+        };
+        Ok(Some((tlv, start + len)))
+    }
+
+    // The next TLV from r, of at most max bytes. None if r ended cleanly
+    // before it.
+    pub fn read<R: Read>(r: &mut R, max: usize) -> io::Result<Option<Tlv>> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+        let mut buf = vec![0u8; 2];
+        match r.read(&mut buf[..1])? {
+            0 => return Ok(None),
+            _ => r.read_exact(&mut buf[1..2])?,
+        }
+        // Read the rest of a long form length before we know the body's.
+        if buf[1] & 0x80 != 0 {
+            let n = (buf[1] & 0x7f) as usize;
+            if n == 0 || n > 4 {
+                return Err(invalid("unsupported ber length"));
+            }
+            buf.resize(2 + n, 0);
+            r.read_exact(&mut buf[2..])?;
+        }
+        let (len, lenlen) = match decode_len(&buf[1..]) {
+            Ok(Some(l)) => l,
+            _ => return Err(invalid("unsupported ber length")),
+        };
+        if len > max {
+            return Err(invalid("ber message too large"));
+        }
+        let header = 1 + lenlen;
+        buf.resize(header + len, 0);
+        r.read_exact(&mut buf[header..])?;
+        match Tlv::decode(buf.as_slice()) {
+            Ok(Some((tlv, _))) => Ok(Some(tlv)),
+            _ => Err(invalid("malformed ber")),
+        }
+    }
+
+    pub fn children(&self) -> Option<&[Tlv]> {
+        match &self.content {
+            Content::Constructed(c) => Some(c.as_slice()),
+            Content::Primitive(_) => None,
+        }
+    }
+
+    pub fn bytes(&self) -> Option<&[u8]> {
+        match &self.content {
+            Content::Primitive(v) => Some(v.as_slice()),
+            Content::Constructed(_) => None,
+        }
+    }
+
+    pub fn as_string(&self) -> Option<String> {
+        self.bytes()
+            .and_then(|b| String::from_utf8(b.to_vec()).ok())
+    }
+
+    pub fn as_integer(&self) -> Option<i64> {
+        let b = self.bytes()?;
+        if b.is_empty() || b.len() > 8 {
+            return None;
+        }
+        let init: i64 = if b[0] & 0x80 != 0 { -1 } else { 0 };
+        Some(b.iter().fold(init, |acc, x| (acc << 8) | *x as i64))
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        if self.tag != TAG_BOOLEAN {
+            return None;
+        }
+        match self.bytes() {
+            Some([b]) => Some(*b != 0),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Tlv, MAX_DEPTH, TAG_BOOLEAN, TAG_ENUMERATED, TAG_INTEGER, TAG_SET};
+
+    #[test]
+    fn test_ber_roundtrip() {
+        for i in &[
+            0,
+            1,
+            127,
+            128,
+            255,
+            256,
+            -1,
+            -128,
+            -129,
+            65535,
+            i64::max_value(),
+        ] {
+            let t = Tlv::integer(TAG_INTEGER, *i);
+            assert_eq!(t.as_integer(), Some(*i));
+        }
+        assert_eq!(
+            Tlv::integer(TAG_INTEGER, 128).encode(),
+            vec![0x02, 0x02, 0x00, 0x80]
+        );
+        assert_eq!(
+            Tlv::integer(TAG_INTEGER, -128).encode(),
+            vec![0x02, 0x01, 0x80]
+        );
+
+        let long = "a".repeat(300);
+        let t = Tlv::sequence(vec![
+            Tlv::integer(TAG_INTEGER, 7),
+            Tlv::constructed(
+                TAG_SET,
+                vec![
+                    Tlv::string(long.as_str()),
+                    Tlv::primitive(TAG_BOOLEAN, vec![0xff]),
+                ],
+            ),
+            Tlv::integer(TAG_ENUMERATED, 2),
+        ]);
+        let enc = t.encode();
+        // The long form, in two bytes.
+        assert_eq!(&enc[..4], &[0x30, 0x82, 0x01, 0x3d]);
+        let (dec, used) = Tlv::decode(enc.as_slice()).unwrap().unwrap();
+        assert_eq!(used, enc.len());
+        assert_eq!(dec, t);
+        assert_eq!(
+            dec.children().unwrap()[1].children().unwrap()[0].as_string(),
+            Some(long)
+        );
+
+        // Short, and then read from a stream.
+        assert_eq!(Tlv::decode(&enc[..enc.len() - 1]), Ok(None));
+        let mut r = enc.as_slice();
+        assert_eq!(Tlv::read(&mut r, 1024).unwrap(), Some(t));
+        assert_eq!(Tlv::read(&mut r, 1024).unwrap(), None);
+        assert!(Tlv::read(&mut enc.as_slice(), 16).is_err());
+
+        // Indefinite lengths, and children overrunning their parent.
+        assert!(Tlv::decode(&[0x30, 0x80, 0x00, 0x00]).is_err());
+        assert!(Tlv::decode(&[0x30, 0x02, 0x04, 0x05]).is_err());
+    }
+
+    #[test]
+    fn test_ber_depth() {
+        let nest = |depth: usize| (0..depth).fold(Tlv::string("x"), |t, _| Tlv::sequence(vec![t]));
+        let enc = nest(MAX_DEPTH).encode();
+        assert!(Tlv::decode(enc.as_slice()).unwrap().is_some());
+        let enc = nest(MAX_DEPTH + 1).encode();
+        assert!(Tlv::decode(enc.as_slice()).is_err());
+
+        // Far deeper than would fit the stack, were it all recursed into.
+        // Each level is just a sequence's header, so work the lengths out
+        // from the inside, then write the headers from the outside.
+        let mut lens = vec![0usize];
+        for _ in 0..100_000 {
+            let inner = *lens.last().unwrap();
+            let mut hdr = Vec::new();
+            super::encode_len(inner, &mut hdr);
+            lens.push(1 + hdr.len() + inner);
+        }
+        let mut enc = Vec::new();
+        lens.iter().rev().skip(1).for_each(|l| {
+            enc.push(0x30);
+            super::encode_len(*l, &mut enc);
+        });
+        assert!(Tlv::decode(enc.as_slice()).is_err());
+        assert!(Tlv::read(&mut enc.as_slice(), enc.len()).is_err());
+    }
+}
@@ -0,0 +1,974 @@
+// An LDAPS frontend, for applications that can only look people and groups
+// up over LDAP. It's read only: searches and simple binds are turned into the
+// same search and auth messages the http frontend sends to the read actor, so
+// access controls and limits apply just as they do there, and anything else
+// is refused as unwillingToPerform.
+//
+// Every entry is presented directly beneath the basedn, named by its name if
+// it has one, IE name=admin,dc=example,dc=com, else by its uuid as
+// entryuuid=<uuid>,dc=example,dc=com. A few attributes are renamed to what
+// LDAP clients expect, see ldap_to_attr and entry_to_ldap.
+
+mod ber;
+
+use self::ber::{Tlv, TAG_ENUMERATED, TAG_INTEGER, TAG_SEQUENCE, TAG_SET};
+use crate::actors::v1_read::{AuthMessage, QueryServerReadV1, SearchMessage};
+use crate::config::LdapConfig;
+use futures::Future;
+use kanidm_proto::v1::Entry as ProtoEntry;
+use kanidm_proto::v1::Filter as ProtoFilter;
+use kanidm_proto::v1::{
+    AuthCredential, AuthRequest, AuthState, AuthStep, OperationError, SearchRequest, UserAuthToken,
+};
+use openssl::ssl::SslAcceptor;
+use std::io::Write;
+use std::net::TcpListener;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use uuid::Uuid;
+
+// Larger messages close the connection.
+const LDAP_MAX_MESSAGE: usize = 262144;
+// Idle connections are closed after this many seconds.
+const LDAP_IDLE_TIMEOUT: u64 = 300;
+// Each connection has its own thread, so past this many, new connections are
+// closed as soon as they're accepted.
+const LDAP_MAX_CONNECTIONS: usize = 128;
+
+// Protocol op tags, with the constructed bit where the op is a sequence.
+const OP_BIND_REQUEST: u8 = 0x60;
+const OP_BIND_RESPONSE: u8 = 0x61;
+const OP_UNBIND_REQUEST: u8 = 0x42;
+const OP_SEARCH_REQUEST: u8 = 0x63;
+const OP_SEARCH_RESULT_ENTRY: u8 = 0x64;
+const OP_SEARCH_RESULT_DONE: u8 = 0x65;
+const OP_ABANDON_REQUEST: u8 = 0x50;
+// What we don't do, and the response each gets.
+const OP_UNSUPPORTED: [(u8, u8); 6] = [
+    (0x66, 0x67), // modify
+    (0x68, 0x69), // add
+    (0x4a, 0x6b), // delete
+    (0x6c, 0x6d), // modify dn
+    (0x6e, 0x6f), // compare
+    (0x77, 0x78), // extended
+];
+
+const AUTH_SIMPLE: u8 = 0x80;
+
+const FILTER_AND: u8 = 0xa0;
+const FILTER_OR: u8 = 0xa1;
+const FILTER_NOT: u8 = 0xa2;
+const FILTER_EQUALITY: u8 = 0xa3;
+const FILTER_SUBSTRINGS: u8 = 0xa4;
+const FILTER_GE: u8 = 0xa5;
+const FILTER_LE: u8 = 0xa6;
+const FILTER_PRESENT: u8 = 0x87;
+const FILTER_APPROX: u8 = 0xa8;
+
+// Result codes, from rfc4511 appendix A.
+const RC_SUCCESS: i64 = 0;
+const RC_OPERATIONS_ERROR: i64 = 1;
+const RC_SIZE_LIMIT_EXCEEDED: i64 = 4;
+const RC_AUTH_METHOD_NOT_SUPPORTED: i64 = 7;
+const RC_ADMIN_LIMIT_EXCEEDED: i64 = 11;
+const RC_NO_SUCH_OBJECT: i64 = 32;
+const RC_INVALID_CREDENTIALS: i64 = 49;
+const RC_INSUFFICIENT_ACCESS_RIGHTS: i64 = 50;
+const RC_UNWILLING_TO_PERFORM: i64 = 53;
+const RC_OTHER: i64 = 80;
+
+// Values of these are references, so are presented as DNs.
+const REFERENCE_ATTRS: [&str; 3] = ["member", "memberof", "directmemberof"];
+
+#[derive(Debug, Clone, PartialEq)]
+enum LdapFilter {
+    And(Vec<LdapFilter>),
+    Or(Vec<LdapFilter>),
+    Not(Box<LdapFilter>),
+    Equality(String, String),
+    // attr, initial, any, final
+    Substring(String, Option<String>, Vec<String>, Option<String>),
+    Ge(String, String),
+    Le(String, String),
+    Present(String),
+    Approx(String, String),
+    // Extensible matches, which we can't express.
+    Unsupported,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LdapScope {
+    Base,
+    OneLevel,
+    Subtree,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct LdapSearch {
+    base: String,
+    scope: LdapScope,
+    // Zero is no limit.
+    size_limit: usize,
+    types_only: bool,
+    filter: LdapFilter,
+    attrs: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum LdapOp {
+    // The password is None if it wasn't a simple bind.
+    Bind(String, Option<String>),
+    Unbind,
+    Search(LdapSearch),
+    Abandon,
+    // The tag of the response to refuse with.
+    Unsupported(u8),
+}
+
+// Lowercased, with the spaces around the rdns gone. Escaped commas aren't
+// understood, which only matters for names we'd never have made.
+fn normalise_dn(dn: &str) -> String {
+    dn.split(',')
+        .map(|rdn| rdn.trim().to_lowercase())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn rdn_value(rdn: &str) -> Option<(&str, &str)> {
+    let mut parts = rdn.splitn(2, '=');
+    match (parts.next(), parts.next()) {
+        (Some(a), Some(v)) if !v.is_empty() => Some((a.trim(), v.trim())),
+        _ => None,
+    }
+}
+
+// The kanidm attribute and value naming an entry, for a DN directly beneath
+// the basedn.
+fn dn_to_entry(dn: &str, basedn: &str) -> Option<(String, String)> {
+    let dn = normalise_dn(dn);
+    let rdn = dn.strip_suffix(basedn)?.strip_suffix(',')?;
+    if rdn.contains(',') {
+        return None;
+    }
+    let (attr, value) = rdn_value(rdn)?;
+    match attr {
+        "name" | "uid" | "cn" => Some(("name".to_string(), value.to_string())),
+        "entryuuid" => Some(("uuid".to_string(), value.to_string())),
+        _ => None,
+    }
+}
+
+fn value_to_dn(value: &str, basedn: &str) -> String {
+    // References that can't be resolved to a name come back as the uuid.
+    match Uuid::parse_str(value) {
+        Ok(_) => format!("entryuuid={},{}", value, basedn),
+        Err(_) => format!("name={},{}", value, basedn),
+    }
+}
+
+fn ldap_to_attr(attr: &str) -> String {
+    let attr = attr.to_lowercase();
+    match attr.as_str() {
+        "objectclass" => "class".to_string(),
+        "entryuuid" => "uuid".to_string(),
+        "uid" | "cn" => "name".to_string(),
+        "uniquemember" => "member".to_string(),
+        _ => attr,
+    }
+}
+
+fn equality(attr: &str, value: &str, basedn: &str) -> ProtoFilter {
+    let attr = ldap_to_attr(attr);
+    // Members and such are given as DNs, but we want the name.
+    let value = if REFERENCE_ATTRS.contains(&attr.as_str()) {
+        dn_to_entry(value, basedn)
+            .map(|(_, v)| v)
+            .unwrap_or_else(|| value.to_string())
+    } else {
+        value.to_string()
+    };
+    ProtoFilter::Eq(attr, value)
+}
+
+fn to_proto_filter(f: &LdapFilter, basedn: &str) -> Result<ProtoFilter, ()> {
+    let all = |fs: &[LdapFilter]| -> Result<Vec<ProtoFilter>, ()> {
+        fs.iter().map(|f| to_proto_filter(f, basedn)).collect()
+    };
+    Ok(match f {
+        LdapFilter::And(fs) => ProtoFilter::And(all(fs)?),
+        LdapFilter::Or(fs) => ProtoFilter::Or(all(fs)?),
+        // Our andnot only means something within an and.
+        LdapFilter::Not(f) => ProtoFilter::And(vec![
+            ProtoFilter::Pres("class".to_string()),
+            ProtoFilter::AndNot(Box::new(to_proto_filter(f, basedn)?)),
+        ]),
+        LdapFilter::Equality(a, v) => equality(a, v, basedn),
+        // Each piece only has to be somewhere in the value, so this can
+        // match more than was asked for, but never less.
+        LdapFilter::Substring(a, initial, any, fin) => {
+            let attr = ldap_to_attr(a);
+            let pieces: Vec<_> = initial
+                .iter()
+                .chain(any.iter())
+                .chain(fin.iter())
+                .map(|p| ProtoFilter::Sub(attr.clone(), p.clone()))
+                .collect();
+            if pieces.is_empty() {
+                ProtoFilter::Pres(attr)
+            } else {
+                ProtoFilter::And(pieces)
+            }
+        }
+        LdapFilter::Ge(a, v) => ProtoFilter::Ge(ldap_to_attr(a), v.clone()),
+        LdapFilter::Le(a, v) => ProtoFilter::Le(ldap_to_attr(a), v.clone()),
+        LdapFilter::Present(a) => ProtoFilter::Pres(ldap_to_attr(a)),
+        LdapFilter::Approx(a, v) => ProtoFilter::Fuzzy(ldap_to_attr(a), v.clone()),
+        LdapFilter::Unsupported => return Err(()),
+    })
+}
+
+fn parse_ava(t: &Tlv) -> Option<(String, String)> {
+    match t.children()? {
+        [a, v] => Some((a.as_string()?, v.as_string()?)),
+        _ => None,
+    }
+}
+
+fn parse_filter(t: &Tlv) -> Option<LdapFilter> {
+    let many =
+        |t: &Tlv| -> Option<Vec<LdapFilter>> { t.children()?.iter().map(parse_filter).collect() };
+    Some(match t.tag {
+        FILTER_AND => LdapFilter::And(many(t)?),
+        FILTER_OR => LdapFilter::Or(many(t)?),
+        FILTER_NOT => match t.children()? {
+            [f] => LdapFilter::Not(Box::new(parse_filter(f)?)),
+            _ => return None,
+        },
+        FILTER_EQUALITY => parse_ava(t).map(|(a, v)| LdapFilter::Equality(a, v))?,
+        FILTER_GE => parse_ava(t).map(|(a, v)| LdapFilter::Ge(a, v))?,
+        FILTER_LE => parse_ava(t).map(|(a, v)| LdapFilter::Le(a, v))?,
+        FILTER_APPROX => parse_ava(t).map(|(a, v)| LdapFilter::Approx(a, v))?,
+        FILTER_PRESENT => LdapFilter::Present(t.as_string()?),
+        FILTER_SUBSTRINGS => {
+            let (attr, subs) = match t.children()? {
+                [a, s] => (a.as_string()?, s.children()?),
+                _ => return None,
+            };
+            let (mut initial, mut any, mut fin) = (None, Vec::new(), None);
+            for s in subs {
+                let v = s.as_string()?;
+                match s.tag {
+                    0x80 => initial = Some(v),
+                    0x81 => any.push(v),
+                    0x82 => fin = Some(v),
+                    _ => return None,
+                }
+            }
+            LdapFilter::Substring(attr, initial, any, fin)
+        }
+        0xa9 => LdapFilter::Unsupported,
+        _ => return None,
+    })
+}
+
+fn parse_search(t: &Tlv) -> Option<LdapSearch> {
+    let c = t.children()?;
+    if c.len() != 8 {
+        return None;
+    }
+    let scope = match c[1].as_integer()? {
+        0 => LdapScope::Base,
+        1 => LdapScope::OneLevel,
+        2 => LdapScope::Subtree,
+        _ => return None,
+    };
+    let attrs = c[7]
+        .children()?
+        .iter()
+        .map(|a| a.as_string().map(|s| s.to_lowercase()))
+        .collect::<Option<Vec<_>>>()?;
+    // The deref and time limit are ignored, as we have no aliases, and our
+    // own search limits apply.
+    Some(LdapSearch {
+        base: c[0].as_string()?,
+        scope: scope,
+        size_limit: c[3].as_integer().filter(|s| *s >= 0)? as usize,
+        types_only: c[5].as_bool()?,
+        filter: parse_filter(&c[6])?,
+        attrs: attrs,
+    })
+}
+
+// The message id and op of an LDAPMessage. None if it's malformed, after
+// which we close the connection, as we can't tell what was meant.
+fn parse_message(t: &Tlv) -> Option<(i64, LdapOp)> {
+    if t.tag != TAG_SEQUENCE {
+        return None;
+    }
+    // Controls may follow, but we don't support any, and none were critical
+    // enough for the client to have to know.
+    let (msgid, op) = match t.children()? {
+        [id, op, ..] if id.tag == TAG_INTEGER => (id.as_integer()?, op),
+        _ => return None,
+    };
+    let op = match op.tag {
+        OP_BIND_REQUEST => match op.children()? {
+            [_version, name, auth] => {
+                let password = if auth.tag == AUTH_SIMPLE {
+                    Some(auth.as_string()?)
+                } else {
+                    None
+                };
+                LdapOp::Bind(name.as_string()?, password)
+            }
+            _ => return None,
+        },
+        OP_UNBIND_REQUEST => LdapOp::Unbind,
+        OP_SEARCH_REQUEST => LdapOp::Search(parse_search(op)?),
+        OP_ABANDON_REQUEST => LdapOp::Abandon,
+        tag => match OP_UNSUPPORTED.iter().find(|(req, _)| *req == tag) {
+            Some((_, resp)) => LdapOp::Unsupported(*resp),
+            None => return None,
+        },
+    };
+    Some((msgid, op))
+}
+
+fn result(tag: u8, code: i64, msg: &str) -> Tlv {
+    Tlv::constructed(
+        tag,
+        vec![
+            Tlv::integer(TAG_ENUMERATED, code),
+            Tlv::string(""),
+            Tlv::string(msg),
+        ],
+    )
+}
+
+fn search_entry(dn: &str, attrs: Vec<(String, Vec<String>)>, types_only: bool) -> Tlv {
+    let attrs = attrs
+        .into_iter()
+        .map(|(a, vs)| {
+            let vs = if types_only {
+                Vec::new()
+            } else {
+                vs.iter().map(|v| Tlv::string(v.as_str())).collect()
+            };
+            Tlv::sequence(vec![Tlv::string(a.as_str()), Tlv::constructed(TAG_SET, vs)])
+        })
+        .collect();
+    Tlv::constructed(
+        OP_SEARCH_RESULT_ENTRY,
+        vec![Tlv::string(dn), Tlv::sequence(attrs)],
+    )
+}
+
+// The DN of an entry and its attributes as LDAP names them.
+fn entry_to_ldap(e: &ProtoEntry, basedn: &str) -> (String, Vec<(String, Vec<String>)>) {
+    let first = |a: &str| e.attrs.get(a).and_then(|vs| vs.first());
+    let dn = match (first("name"), first("uuid")) {
+        (Some(n), _) => format!("name={},{}", n, basedn),
+        (None, Some(u)) => format!("entryuuid={},{}", u, basedn),
+        (None, None) => basedn.to_string(),
+    };
+    let mut attrs = Vec::new();
+    for (k, vs) in e.attrs.iter() {
+        match k.as_str() {
+            "class" => attrs.push(("objectclass".to_string(), vs.clone())),
+            "uuid" => attrs.push(("entryuuid".to_string(), vs.clone())),
+            // Most clients look for one of these to show.
+            "name" => {
+                attrs.push(("name".to_string(), vs.clone()));
+                attrs.push(("uid".to_string(), vs.clone()));
+                attrs.push(("cn".to_string(), vs.clone()));
+            }
+            k if REFERENCE_ATTRS.contains(&k) => attrs.push((
+                k.to_string(),
+                vs.iter().map(|v| value_to_dn(v, basedn)).collect(),
+            )),
+            k => attrs.push((k.to_string(), vs.clone())),
+        }
+    }
+    (dn, attrs)
+}
+
+// Empty or * is every attribute, and 1.1 is none. Operational attributes,
+// asked for by +, we don't have.
+fn select_attrs(
+    attrs: Vec<(String, Vec<String>)>,
+    wanted: &[String],
+) -> Vec<(String, Vec<String>)> {
+    if wanted.is_empty() || wanted.iter().any(|w| w == "*") {
+        return attrs;
+    }
+    attrs
+        .into_iter()
+        .filter(|(a, _)| wanted.contains(a))
+        .collect()
+}
+
+struct LdapSession<'a> {
+    config: &'a LdapConfig,
+    server: &'a actix::Addr<QueryServerReadV1>,
+    // Anonymous until bound, once we've needed it.
+    uat: Option<UserAuthToken>,
+}
+
+impl<'a> LdapSession<'a> {
+    fn authenticate(&self, name: &str, cred: AuthCredential) -> Result<UserAuthToken, i64> {
+        let init = AuthMessage::new(
+            AuthRequest {
+                step: AuthStep::Init(name.to_string(), None),
+            },
+            None,
+        );
+        let sessionid = match self.server.send(init).wait() {
+            Ok(Ok(r)) => match r.state {
+                AuthState::Continue(_) => r.sessionid,
+                _ => return Err(RC_INVALID_CREDENTIALS),
+            },
+            // Such as there being no account of that name.
+            Ok(Err(_)) => return Err(RC_INVALID_CREDENTIALS),
+            Err(_) => return Err(RC_OTHER),
+        };
+        let creds = AuthMessage::new(
+            AuthRequest {
+                step: AuthStep::Creds(vec![cred]),
+            },
+            Some(sessionid),
+        );
+        match self.server.send(creds).wait() {
+            Ok(Ok(r)) => match r.state {
+                AuthState::Success(uat) => Ok(uat),
+                _ => Err(RC_INVALID_CREDENTIALS),
+            },
+            Ok(Err(_)) => Err(RC_INVALID_CREDENTIALS),
+            Err(_) => Err(RC_OTHER),
+        }
+    }
+
+    fn bind(&mut self, dn: &str, password: Option<String>) -> Tlv {
+        // A failed bind leaves us anonymous, as rfc4511 4.2.1 asks.
+        self.uat = None;
+        let password = match password {
+            Some(p) => p,
+            None => {
+                return result(
+                    OP_BIND_RESPONSE,
+                    RC_AUTH_METHOD_NOT_SUPPORTED,
+                    "only simple binds are supported",
+                )
+            }
+        };
+        match (dn.is_empty(), password.is_empty()) {
+            (true, true) => return result(OP_BIND_RESPONSE, RC_SUCCESS, ""),
+            // A DN without a password is an "unauthenticated" bind, which
+            // too many clients mistake for a successful one.
+            (false, true) => {
+                return result(
+                    OP_BIND_RESPONSE,
+                    RC_UNWILLING_TO_PERFORM,
+                    "unauthenticated binds are not allowed",
+                )
+            }
+            (true, false) => return result(OP_BIND_RESPONSE, RC_INVALID_CREDENTIALS, ""),
+            (false, false) => {}
+        }
+        // Accept a bare name too, which some clients are configured with.
+        let name = match dn_to_entry(dn, self.config.basedn.as_str()) {
+            Some((_, v)) => v,
+            None if !dn.contains('=') => dn.to_lowercase(),
+            None => return result(OP_BIND_RESPONSE, RC_INVALID_CREDENTIALS, ""),
+        };
+        match self.authenticate(name.as_str(), AuthCredential::Password(password)) {
+            Ok(uat) => {
+                self.uat = Some(uat);
+                result(OP_BIND_RESPONSE, RC_SUCCESS, "")
+            }
+            Err(code) => result(OP_BIND_RESPONSE, code, ""),
+        }
+    }
+
+    fn root_dse(&self, s: &LdapSearch) -> Vec<Tlv> {
+        let attrs = vec![
+            ("objectclass".to_string(), vec!["top".to_string()]),
+            (
+                "namingcontexts".to_string(),
+                vec![self.config.basedn.clone()],
+            ),
+            ("supportedldapversion".to_string(), vec!["3".to_string()]),
+            ("vendorname".to_string(), vec!["Kanidm".to_string()]),
+        ];
+        vec![
+            search_entry("", select_attrs(attrs, &s.attrs), s.types_only),
+            result(OP_SEARCH_RESULT_DONE, RC_SUCCESS, ""),
+        ]
+    }
+
+    fn base_entry(&self, s: &LdapSearch) -> Vec<Tlv> {
+        let dc = self
+            .config
+            .basedn
+            .split(',')
+            .next()
+            .and_then(rdn_value)
+            .map(|(_, v)| v.to_string())
+            .unwrap_or_default();
+        let attrs = vec![
+            (
+                "objectclass".to_string(),
+                vec!["top".to_string(), "domain".to_string()],
+            ),
+            ("dc".to_string(), vec![dc]),
+        ];
+        vec![
+            search_entry(
+                self.config.basedn.as_str(),
+                select_attrs(attrs, &s.attrs),
+                s.types_only,
+            ),
+            result(OP_SEARCH_RESULT_DONE, RC_SUCCESS, ""),
+        ]
+    }
+
+    fn search(&mut self, s: LdapSearch) -> Vec<Tlv> {
+        let basedn = self.config.basedn.as_str();
+        let base = normalise_dn(s.base.as_str());
+        if base.is_empty() && s.scope == LdapScope::Base {
+            return self.root_dse(&s);
+        }
+        let filter = match to_proto_filter(&s.filter, basedn) {
+            Ok(f) => f,
+            Err(_) => {
+                return vec![result(
+                    OP_SEARCH_RESULT_DONE,
+                    RC_UNWILLING_TO_PERFORM,
+                    "extensible matches are not supported",
+                )]
+            }
+        };
+        // Everything is directly beneath the basedn, so below an entry is
+        // nothing, and a subtree of it is just it.
+        let filter = if base == basedn {
+            match s.scope {
+                LdapScope::Base => return self.base_entry(&s),
+                _ => filter,
+            }
+        } else {
+            match (dn_to_entry(base.as_str(), basedn), s.scope) {
+                (Some(_), LdapScope::OneLevel) => {
+                    return vec![result(OP_SEARCH_RESULT_DONE, RC_SUCCESS, "")]
+                }
+                (Some((a, v)), _) => ProtoFilter::And(vec![ProtoFilter::Eq(a, v), filter]),
+                (None, _) => return vec![result(OP_SEARCH_RESULT_DONE, RC_NO_SUCH_OBJECT, "")],
+            }
+        };
+
+        let uat = match self.uat.clone() {
+            Some(uat) => uat,
+            None => match self.authenticate("anonymous", AuthCredential::Anonymous) {
+                Ok(uat) => {
+                    self.uat = Some(uat.clone());
+                    uat
+                }
+                Err(_) => {
+                    return vec![result(
+                        OP_SEARCH_RESULT_DONE,
+                        RC_INSUFFICIENT_ACCESS_RIGHTS,
+                        "anonymous searches are not allowed, bind first",
+                    )]
+                }
+            },
+        };
+
+        let entries = match self
+            .server
+            .send(SearchMessage::new(Some(uat), SearchRequest::new(filter)))
+            .wait()
+        {
+            Ok(Ok(r)) => r.entries,
+            Ok(Err(OperationError::NoMatchingEntries)) => Vec::new(),
+            Ok(Err(OperationError::AccessDenied)) | Ok(Err(OperationError::NotAuthenticated)) => {
+                return vec![result(
+                    OP_SEARCH_RESULT_DONE,
+                    RC_INSUFFICIENT_ACCESS_RIGHTS,
+                    "",
+                )]
+            }
+            Ok(Err(OperationError::ResourceLimit)) => {
+                return vec![result(OP_SEARCH_RESULT_DONE, RC_ADMIN_LIMIT_EXCEEDED, "")]
+            }
+            Ok(Err(e)) => {
+                return vec![result(
+                    OP_SEARCH_RESULT_DONE,
+                    RC_OPERATIONS_ERROR,
+                    format!("{:?}", e).as_str(),
+                )]
+            }
+            Err(_) => return vec![result(OP_SEARCH_RESULT_DONE, RC_OTHER, "")],
+        };
+
+        let truncated = s.size_limit > 0 && entries.len() > s.size_limit;
+        let mut resp: Vec<Tlv> = entries
+            .iter()
+            .take(if truncated {
+                s.size_limit
+            } else {
+                entries.len()
+            })
+            .map(|e| {
+                let (dn, attrs) = entry_to_ldap(e, basedn);
+                search_entry(dn.as_str(), select_attrs(attrs, &s.attrs), s.types_only)
+            })
+            .collect();
+        resp.push(if truncated {
+            result(OP_SEARCH_RESULT_DONE, RC_SIZE_LIMIT_EXCEEDED, "")
+        } else {
+            result(OP_SEARCH_RESULT_DONE, RC_SUCCESS, "")
+        });
+        resp
+    }
+}
+
+fn serve<S: std::io::Read + Write>(
+    config: &LdapConfig,
+    stream: &mut S,
+    server: &actix::Addr<QueryServerReadV1>,
+) -> std::io::Result<()> {
+    let mut session = LdapSession {
+        config: config,
+        server: server,
+        uat: None,
+    };
+    while let Some(msg) = Tlv::read(stream, LDAP_MAX_MESSAGE)? {
+        let (msgid, op) = match parse_message(&msg) {
+            Some(m) => m,
+            None => {
+                debug!("ldap: closing connection after a malformed message");
+                return Ok(());
+            }
+        };
+        let resp = match op {
+            LdapOp::Bind(dn, password) => vec![session.bind(dn.as_str(), password)],
+            LdapOp::Search(s) => session.search(s),
+            LdapOp::Unbind => return Ok(()),
+            // We answer each request before reading the next, so there's
+            // never anything left to abandon.
+            LdapOp::Abandon => Vec::new(),
+            LdapOp::Unsupported(tag) => vec![result(
+                tag,
+                RC_UNWILLING_TO_PERFORM,
+                "this server is read only over ldap",
+            )],
+        };
+        for op in resp {
+            let m = Tlv::sequence(vec![Tlv::integer(TAG_INTEGER, msgid), op]);
+            stream.write_all(m.encode().as_slice())?;
+        }
+        stream.flush()?;
+    }
+    Ok(())
+}
+
+// A connection's place in the count of those open, given up on drop, so
+// that a thread that panics still leaves.
+struct ConnSlot {
+    active: Arc<AtomicUsize>,
+}
+
+impl Drop for ConnSlot {
+    fn drop(&mut self) {
+        self.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Only the accepting thread takes slots, so the count can't pass max.
+fn take_slot(active: &Arc<AtomicUsize>, max: usize) -> Option<ConnSlot> {
+    let slot = ConnSlot {
+        active: active.clone(),
+    };
+    if active.fetch_add(1, Ordering::SeqCst) < max {
+        Some(slot)
+    } else {
+        None
+    }
+}
+
+pub fn start(config: LdapConfig, acceptor: SslAcceptor, server: actix::Addr<QueryServerReadV1>) {
+    let listener = match TcpListener::bind(config.address.as_str()) {
+        Ok(l) => l,
+        Err(e) => {
+            error!("ldap: unable to bind {} -> {:?}", config.address, e);
+            return;
+        }
+    };
+    info!(
+        "ldap: listening on {} for {}",
+        config.address, config.basedn
+    );
+    let config = Arc::new(config);
+    let acceptor = Arc::new(acceptor);
+    let active = Arc::new(AtomicUsize::new(0));
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(s) => s,
+                Err(e) => {
+                    error!("ldap: unable to accept -> {:?}", e);
+                    continue;
+                }
+            };
+            let slot = match take_slot(&active, LDAP_MAX_CONNECTIONS) {
+                Some(s) => s,
+                None => {
+                    error!("ldap: too many connections, closing a new one");
+                    continue;
+                }
+            };
+            let _ = stream.set_read_timeout(Some(Duration::from_secs(LDAP_IDLE_TIMEOUT)));
+            let (config, acceptor, server) = (config.clone(), acceptor.clone(), server.clone());
+            thread::spawn(move || {
+                let _slot = slot;
+                match acceptor.accept(stream) {
+                    Ok(mut tls) => {
+                        if let Err(e) = serve(&config, &mut tls, &server) {
+                            debug!("ldap: connection closed -> {:?}", e);
+                        }
+                    }
+                    Err(e) => debug!("ldap: tls handshake failed -> {:?}", e),
+                }
+            });
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ber::{Tlv, TAG_BOOLEAN, TAG_ENUMERATED, TAG_INTEGER, TAG_OCTETS};
+    use super::{
+        dn_to_entry, entry_to_ldap, parse_message, select_attrs, take_slot, to_proto_filter,
+        LdapFilter, LdapOp, LdapScope,
+    };
+    use kanidm_proto::v1::Entry as ProtoEntry;
+    use kanidm_proto::v1::Filter as ProtoFilter;
+    use std::collections::BTreeMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    const BASEDN: &str = "dc=example,dc=com";
+
+    fn msg(op: Tlv) -> Tlv {
+        Tlv::sequence(vec![Tlv::integer(TAG_INTEGER, 3), op])
+    }
+
+    #[test]
+    fn test_ldap_parse_bind() {
+        // As a client sends it, so we know we agree on the encoding.
+        let raw = [
+            0x30, 0x1a, 0x02, 0x01, 0x01, 0x60, 0x15, 0x02, 0x01, 0x03, 0x04, 0x0a, b'n', b'a',
+            b'm', b'e', b'=', b'a', b'd', b'm', b'i', b'n', 0x80, 0x04, b'p', b'a', b's', b's',
+        ];
+        let (t, _) = Tlv::decode(&raw).unwrap().unwrap();
+        assert_eq!(
+            parse_message(&t),
+            Some((
+                1,
+                LdapOp::Bind("name=admin".to_string(), Some("pass".to_string()))
+            ))
+        );
+
+        // sasl is recognised, but has no password.
+        let t = msg(Tlv::constructed(
+            0x60,
+            vec![
+                Tlv::integer(TAG_INTEGER, 3),
+                Tlv::string(""),
+                Tlv::constructed(0xa3, vec![Tlv::string("EXTERNAL")]),
+            ],
+        ));
+        assert_eq!(
+            parse_message(&t),
+            Some((3, LdapOp::Bind("".to_string(), None)))
+        );
+
+        // Writes get refused with the matching response, and junk is junk.
+        let t = msg(Tlv::constructed(0x68, vec![]));
+        assert_eq!(parse_message(&t), Some((3, LdapOp::Unsupported(0x69))));
+        assert_eq!(parse_message(&msg(Tlv::string("nope"))), None);
+        assert_eq!(parse_message(&Tlv::string("nope")), None);
+    }
+
+    #[test]
+    fn test_ldap_parse_search() {
+        // (&(objectClass=person)(|(uid=ad*in)(!(memberOf=*))))
+        let filter = Tlv::constructed(
+            0xa0,
+            vec![
+                Tlv::constructed(
+                    0xa3,
+                    vec![Tlv::string("objectClass"), Tlv::string("person")],
+                ),
+                Tlv::constructed(
+                    0xa1,
+                    vec![
+                        Tlv::constructed(
+                            0xa4,
+                            vec![
+                                Tlv::string("uid"),
+                                Tlv::sequence(vec![
+                                    Tlv::octets(0x80, b"ad"),
+                                    Tlv::octets(0x82, b"in"),
+                                ]),
+                            ],
+                        ),
+                        Tlv::constructed(0xa2, vec![Tlv::octets(0x87, b"memberOf")]),
+                    ],
+                ),
+            ],
+        );
+        let t = msg(Tlv::constructed(
+            0x63,
+            vec![
+                Tlv::string(BASEDN),
+                Tlv::integer(TAG_ENUMERATED, 2),
+                Tlv::integer(TAG_ENUMERATED, 0),
+                Tlv::integer(TAG_INTEGER, 10),
+                Tlv::integer(TAG_INTEGER, 0),
+                Tlv::primitive(TAG_BOOLEAN, vec![0x00]),
+                filter,
+                Tlv::sequence(vec![Tlv::string("CN"), Tlv::string("memberOf")]),
+            ],
+        ));
+        // Through the wire and back, as the constructed tags matter.
+        let (t, _) = Tlv::decode(t.encode().as_slice()).unwrap().unwrap();
+        let s = match parse_message(&t) {
+            Some((3, LdapOp::Search(s))) => s,
+            r => panic!("{:?}", r),
+        };
+        assert_eq!(s.base, BASEDN);
+        assert_eq!(s.scope, LdapScope::Subtree);
+        assert_eq!(s.size_limit, 10);
+        assert_eq!(s.attrs, vec!["cn".to_string(), "memberof".to_string()]);
+
+        let f = to_proto_filter(&s.filter, BASEDN).unwrap();
+        assert_eq!(
+            f,
+            ProtoFilter::And(vec![
+                ProtoFilter::Eq("class".to_string(), "person".to_string()),
+                ProtoFilter::Or(vec![
+                    ProtoFilter::And(vec![
+                        ProtoFilter::Sub("name".to_string(), "ad".to_string()),
+                        ProtoFilter::Sub("name".to_string(), "in".to_string()),
+                    ]),
+                    ProtoFilter::And(vec![
+                        ProtoFilter::Pres("class".to_string()),
+                        ProtoFilter::AndNot(Box::new(ProtoFilter::Pres("memberof".to_string()))),
+                    ]),
+                ]),
+            ])
+        );
+        // A bare octet string where the filter should be.
+        let bad = msg(Tlv::constructed(
+            0x63,
+            vec![
+                Tlv::string(BASEDN),
+                Tlv::integer(TAG_ENUMERATED, 2),
+                Tlv::integer(TAG_ENUMERATED, 0),
+                Tlv::integer(TAG_INTEGER, 0),
+                Tlv::integer(TAG_INTEGER, 0),
+                Tlv::primitive(TAG_BOOLEAN, vec![0x00]),
+                Tlv::primitive(TAG_OCTETS, b"x".to_vec()),
+                Tlv::sequence(vec![]),
+            ],
+        ));
+        assert_eq!(parse_message(&bad), None);
+    }
+
+    #[test]
+    fn test_ldap_filter_references() {
+        let f = LdapFilter::Equality(
+            "uniqueMember".to_string(),
+            "name=admin, DC=example,dc=com".to_string(),
+        );
+        assert_eq!(
+            to_proto_filter(&f, BASEDN),
+            Ok(ProtoFilter::Eq("member".to_string(), "admin".to_string()))
+        );
+        // Not one of ours, so it's left to not match.
+        let f = LdapFilter::Equality("memberof".to_string(), "cn=x,dc=other".to_string());
+        assert_eq!(
+            to_proto_filter(&f, BASEDN),
+            Ok(ProtoFilter::Eq(
+                "memberof".to_string(),
+                "cn=x,dc=other".to_string()
+            ))
+        );
+        assert!(to_proto_filter(&LdapFilter::Unsupported, BASEDN).is_err());
+    }
+
+    #[test]
+    fn test_ldap_dn_mapping() {
+        assert_eq!(
+            dn_to_entry("uid=Admin,dc=example,dc=com", BASEDN),
+            Some(("name".to_string(), "admin".to_string()))
+        );
+        assert_eq!(
+            dn_to_entry(
+                "entryUUID=00000000-0000-0000-0000-000000000000,dc=example,dc=com",
+                BASEDN
+            ),
+            Some((
+                "uuid".to_string(),
+                "00000000-0000-0000-0000-000000000000".to_string()
+            ))
+        );
+        assert_eq!(dn_to_entry("name=a,ou=x,dc=example,dc=com", BASEDN), None);
+        assert_eq!(dn_to_entry("name=a,dc=other,dc=com", BASEDN), None);
+        assert_eq!(dn_to_entry(BASEDN, BASEDN), None);
+
+        let mut attrs = BTreeMap::new();
+        attrs.insert("name".to_string(), vec!["testgroup".to_string()]);
+        attrs.insert("class".to_string(), vec!["group".to_string()]);
+        attrs.insert(
+            "member".to_string(),
+            vec![
+                "admin".to_string(),
+                "00000000-0000-0000-0000-0000000000ff".to_string(),
+            ],
+        );
+        let (dn, attrs) = entry_to_ldap(&ProtoEntry { attrs: attrs }, BASEDN);
+        assert_eq!(dn, "name=testgroup,dc=example,dc=com");
+        let attrs = select_attrs(attrs, &["objectclass".to_string(), "member".to_string()]);
+        assert_eq!(
+            attrs,
+            vec![
+                ("objectclass".to_string(), vec!["group".to_string()]),
+                (
+                    "member".to_string(),
+                    vec![
+                        "name=admin,dc=example,dc=com".to_string(),
+                        "entryuuid=00000000-0000-0000-0000-0000000000ff,dc=example,dc=com"
+                            .to_string()
+                    ]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_ldap_conn_slots() {
+        let active = Arc::new(AtomicUsize::new(0));
+        let a = take_slot(&active, 2).expect("no slot");
+        let b = take_slot(&active, 2).expect("no slot");
+        // Full, and a refused connection doesn't hold a place.
+        assert!(take_slot(&active, 2).is_none());
+        assert_eq!(active.load(Ordering::SeqCst), 2);
+        drop(a);
+        let c = take_slot(&active, 2).expect("no slot");
+        drop(b);
+        drop(c);
+        assert_eq!(active.load(Ordering::SeqCst), 0);
+    }
+}
@@ -19,21 +19,33 @@ mod async_log;
 #[macro_use]
 mod audit;
 mod be;
+mod commits;
 pub mod constants;
 mod credential;
 mod entry;
 mod event;
 mod filter;
+mod generate;
+mod import;
 mod interval;
+mod isolate;
+mod ldap;
 mod modify;
+mod optrack;
 mod value;
 #[macro_use]
 mod plugins;
 mod access;
 mod actors;
 mod idm;
+mod priority;
+mod psearch;
+mod purgestats;
+mod ratelimit;
+mod repl;
 mod schema;
 mod server;
+mod webui;
 
 pub mod config;
 pub mod core;
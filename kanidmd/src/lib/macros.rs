@@ -2,7 +2,7 @@
 macro_rules! run_test {
     ($test_fn:expr) => {{
         use crate::audit::AuditScope;
-        use crate::be::Backend;
+        use crate::be::{Backend, FILTER_TEST_THRESHOLD};
         use crate::schema::Schema;
         use crate::server::QueryServer;
 
@@ -12,7 +12,7 @@ macro_rules! run_test {
 
         let mut audit = AuditScope::new("run_test");
 
-        let be = match Backend::new(&mut audit, "", 1) {
+        let be = match Backend::new(&mut audit, "", 1, FILTER_TEST_THRESHOLD) {
             Ok(be) => be,
             Err(e) => {
                 debug!("{}", audit);
@@ -84,7 +84,9 @@ macro_rules! filter {
         #[allow(unused_imports)]
         use crate::filter::FC;
         #[allow(unused_imports)]
-        use crate::filter::{f_and, f_andnot, f_eq, f_id, f_or, f_pres, f_self, f_sub};
+        use crate::filter::{
+            f_and, f_andnot, f_eq, f_fuzzy, f_ge, f_id, f_le, f_or, f_pres, f_self, f_sub,
+        };
         Filter::new_ignore_hidden($fc)
     }};
 }
@@ -99,7 +101,9 @@ macro_rules! filter_rec {
         #[allow(unused_imports)]
         use crate::filter::FC;
         #[allow(unused_imports)]
-        use crate::filter::{f_and, f_andnot, f_eq, f_id, f_or, f_pres, f_self, f_sub};
+        use crate::filter::{
+            f_and, f_andnot, f_eq, f_fuzzy, f_ge, f_id, f_le, f_or, f_pres, f_self, f_sub,
+        };
         Filter::new_recycled($fc)
     }};
 }
@@ -114,7 +118,9 @@ macro_rules! filter_all {
         #[allow(unused_imports)]
         use crate::filter::FC;
         #[allow(unused_imports)]
-        use crate::filter::{f_and, f_andnot, f_eq, f_id, f_or, f_pres, f_self, f_sub};
+        use crate::filter::{
+            f_and, f_andnot, f_eq, f_fuzzy, f_ge, f_id, f_le, f_or, f_pres, f_self, f_sub,
+        };
         Filter::new($fc)
     }};
 }
@@ -127,7 +133,7 @@ macro_rules! filter_valid {
         $fc:expr
     ) => {{
         #[allow(unused_imports)]
-        use crate::filter::{f_and, f_andnot, f_eq, f_or, f_pres, f_sub};
+        use crate::filter::{f_and, f_andnot, f_eq, f_fuzzy, f_ge, f_le, f_or, f_pres, f_sub};
         use crate::filter::{Filter, FilterInvalid};
         let f: Filter<FilterInvalid> = Filter::new($fc);
         // Create a resolved filter, via the most unsafe means possible!
@@ -143,7 +149,7 @@ macro_rules! filter_resolved {
         $fc:expr
     ) => {{
         #[allow(unused_imports)]
-        use crate::filter::{f_and, f_andnot, f_eq, f_or, f_pres, f_sub};
+        use crate::filter::{f_and, f_andnot, f_eq, f_fuzzy, f_ge, f_le, f_or, f_pres, f_sub};
         use crate::filter::{Filter, FilterInvalid};
         let f: Filter<FilterInvalid> = Filter::new($fc);
         // Create a resolved filter, via the most unsafe means possible!
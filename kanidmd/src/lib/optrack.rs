@@ -0,0 +1,224 @@
+// Tracking of the operations the server is running right now, so that an admin
+// can see what is keeping the workers busy, who has been busiest recently, and
+// cancel a search that has run away.
+//
+// The read and write workers are sync threads that run one operation to
+// completion at a time, so the operation a thread is running is also kept in
+// a thread local. That lets the backend report which phase it's in, and check
+// for cancellation, without a handle being passed all the way down.
+
+use kanidm_proto::v1::{ActiveOperation, IdentityOperationCount, OperationError, UserAuthToken};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+// Per identity counts cover this long, and then start again.
+const RECENT_WINDOW: Duration = Duration::from_secs(300);
+
+struct OpState {
+    operation: &'static str,
+    phase: Mutex<&'static str>,
+    cancel: AtomicBool,
+}
+
+struct TrackedOp {
+    operation: &'static str,
+    identity: String,
+    start: Instant,
+    state: Arc<OpState>,
+}
+
+struct TrackerState {
+    next_id: u64,
+    active: BTreeMap<u64, TrackedOp>,
+    window_start: Instant,
+    recent: BTreeMap<String, u64>,
+}
+
+#[derive(Clone)]
+pub struct OpTracker {
+    inner: Arc<Mutex<TrackerState>>,
+}
+
+thread_local! {
+    static CURRENT_OP: RefCell<Option<Arc<OpState>>> = RefCell::new(None);
+}
+
+// Held for the duration of an operation, and removes it from the listing on
+// drop.
+pub struct OpGuard {
+    tracker: OpTracker,
+    id: u64,
+}
+
+impl Drop for OpGuard {
+    fn drop(&mut self) {
+        self.tracker.lock().active.remove(&self.id);
+        CURRENT_OP.with(|c| *c.borrow_mut() = None);
+    }
+}
+
+impl OpTracker {
+    pub fn new() -> Self {
+        OpTracker {
+            inner: Arc::new(Mutex::new(TrackerState {
+                next_id: 0,
+                active: BTreeMap::new(),
+                window_start: Instant::now(),
+                recent: BTreeMap::new(),
+            })),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, TrackerState> {
+        // Nothing in here can be left inconsistent by a panic.
+        self.inner
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn begin(&self, operation: &'static str, uat: &Option<UserAuthToken>) -> OpGuard {
+        let identity = match uat {
+            Some(uat) => format!("{} ({})", uat.name, uat.uuid),
+            None => "unauthenticated".to_string(),
+        };
+        let state = Arc::new(OpState {
+            operation: operation,
+            phase: Mutex::new("begin"),
+            cancel: AtomicBool::new(false),
+        });
+
+        let mut inner = self.lock();
+        if inner.window_start.elapsed() > RECENT_WINDOW {
+            inner.recent.clear();
+            inner.window_start = Instant::now();
+        }
+        *inner.recent.entry(identity.clone()).or_insert(0) += 1;
+
+        let id = inner.next_id;
+        inner.next_id += 1;
+        inner.active.insert(
+            id,
+            TrackedOp {
+                operation: operation,
+                identity: identity,
+                start: Instant::now(),
+                state: state.clone(),
+            },
+        );
+
+        CURRENT_OP.with(|c| *c.borrow_mut() = Some(state));
+        OpGuard {
+            tracker: self.clone(),
+            id: id,
+        }
+    }
+
+    pub fn list_active(&self) -> Vec<ActiveOperation> {
+        self.lock()
+            .active
+            .iter()
+            .map(|(id, op)| {
+                let elapsed = op.start.elapsed();
+                ActiveOperation {
+                    id: *id,
+                    operation: op.operation.to_string(),
+                    identity: op.identity.clone(),
+                    elapsed_ms: elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_millis()),
+                    phase: op
+                        .state
+                        .phase
+                        .lock()
+                        .map(|p| p.to_string())
+                        .unwrap_or_else(|_| "unknown".to_string()),
+                }
+            })
+            .collect()
+    }
+
+    pub fn list_recent(&self) -> Vec<IdentityOperationCount> {
+        self.lock()
+            .recent
+            .iter()
+            .map(|(identity, count)| IdentityOperationCount {
+                identity: identity.clone(),
+                count: *count,
+            })
+            .collect()
+    }
+
+    // The operation stops at the next point the backend checks, and fails
+    // with Cancelled. Returns false if it has already finished.
+    pub fn cancel(&self, id: u64) -> bool {
+        match self.lock().active.get(&id) {
+            Some(op) => {
+                op.state.cancel.store(true, Ordering::Relaxed);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+// Record what the operation on this thread is doing. Does nothing outside of
+// a tracked operation, IE in tests or internal tasks.
+pub fn set_phase(phase: &'static str) {
+    CURRENT_OP.with(|c| match c.borrow().as_ref() {
+        Some(state) => {
+            if let Ok(mut p) = state.phase.lock() {
+                *p = phase;
+            }
+        }
+        None => {}
+    })
+}
+
+// Called by the backend at the points where it's safe to give up. A write
+// that is cancelled aborts its txn, so nothing half done is kept.
+pub fn check_cancelled() -> Result<(), OperationError> {
+    CURRENT_OP.with(|c| match c.borrow().as_ref() {
+        Some(state) if state.cancel.load(Ordering::Relaxed) => Err(OperationError::Cancelled),
+        _ => Ok(()),
+    })
+}
+
+// The operation this thread is running, if it's running one.
+pub fn current_operation() -> Option<&'static str> {
+    CURRENT_OP.with(|c| c.borrow().as_ref().map(|state| state.operation))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_cancelled, set_phase, OpTracker};
+    use kanidm_proto::v1::OperationError;
+
+    #[test]
+    fn test_optrack_cancel() {
+        let tracker = OpTracker::new();
+        assert_eq!(check_cancelled(), Ok(()));
+
+        {
+            let _op = tracker.begin("search", &None);
+            set_phase("testing filter");
+            let active = tracker.list_active();
+            assert_eq!(active.len(), 1);
+            assert_eq!(active[0].operation, "search");
+            assert_eq!(active[0].phase, "testing filter");
+
+            assert_eq!(check_cancelled(), Ok(()));
+            assert!(tracker.cancel(active[0].id));
+            assert_eq!(check_cancelled(), Err(OperationError::Cancelled));
+        }
+
+        // Once done, it's gone from the listing but still counted.
+        assert!(tracker.list_active().is_empty());
+        assert!(!tracker.cancel(0));
+        assert_eq!(check_cancelled(), Ok(()));
+        let recent = tracker.list_recent();
+        assert_eq!(recent.len(), 1);
+        assert_eq!(recent[0].identity, "unauthenticated");
+        assert_eq!(recent[0].count, 1);
+    }
+}
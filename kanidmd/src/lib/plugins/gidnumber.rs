@@ -0,0 +1,185 @@
+// Gives posix accounts and groups a gidnumber when they don't bring their own.
+// It's derived from the uuid so that every server in a replicated topology
+// would pick the same one. An account's uid is always its gidnumber, as each
+// posix account is also its own user private group - that way uid's and gid's
+// can never collide. Values below the system range are refused, as a client
+// would otherwise resolve a kanidm name to something like root or wheel.
+use crate::plugins::Plugin;
+
+use crate::audit::AuditScope;
+use crate::entry::{Entry, EntryCommitted, EntryInvalid, EntryNew};
+use crate::event::{CreateEvent, ModifyEvent};
+use crate::server::QueryServerWriteTransaction;
+use crate::value::{PartialValue, Value};
+use kanidm_proto::v1::OperationError;
+
+// Anything lower belongs to the client's local system accounts.
+const GID_SYSTEM_NUMBER_MIN: u32 = 1000;
+// Generated numbers start here, above the range most distributions hand
+// out to local users.
+const GID_GENERATED_MIN: u32 = 65536;
+
+pub struct GidNumber {}
+
+fn apply_gidnumber<STATE>(
+    au: &mut AuditScope,
+    e: &mut Entry<EntryInvalid, STATE>,
+) -> Result<(), OperationError>
+where
+    STATE: Copy,
+{
+    if !e.attribute_value_pres("class", &PartialValue::new_class("posixaccount"))
+        && !e.attribute_value_pres("class", &PartialValue::new_class("posixgroup"))
+    {
+        return Ok(());
+    }
+
+    match e.get_ava_single_uint32("gidnumber") {
+        Some(gid) if gid < GID_SYSTEM_NUMBER_MIN => {
+            audit_log!(au, "gidnumber {} is in the system reserved range", gid);
+            Err(OperationError::InvalidAttribute("gidnumber".to_string()))
+        }
+        Some(_) => Ok(()),
+        None => {
+            let u = match e.get_ava_single("uuid").and_then(|v| v.to_uuid()) {
+                Some(u) => *u,
+                None => return Err(OperationError::InvalidEntryState),
+            };
+            let b = u.as_bytes();
+            let low = u32::from_be_bytes([b[12], b[13], b[14], b[15]]);
+            // Keep clear of the sign bit, some clients still treat gid's as
+            // signed.
+            let gid = (low & 0x7fff_ffff) | GID_GENERATED_MIN;
+            audit_log!(au, "Generated gidnumber {} from uuid {}", gid, u);
+            e.set_avas("gidnumber", vec![Value::new_uint32(gid)]);
+            Ok(())
+        }
+    }
+}
+
+impl Plugin for GidNumber {
+    fn id() -> &'static str {
+        "plugin_gidnumber"
+    }
+
+    fn pre_create_transform(
+        au: &mut AuditScope,
+        _qs: &mut QueryServerWriteTransaction,
+        cand: &mut Vec<Entry<EntryInvalid, EntryNew>>,
+        _ce: &CreateEvent,
+    ) -> Result<(), OperationError> {
+        cand.iter_mut().try_for_each(|e| apply_gidnumber(au, e))
+    }
+
+    fn pre_modify(
+        au: &mut AuditScope,
+        _qs: &mut QueryServerWriteTransaction,
+        cand: &mut Vec<Entry<EntryInvalid, EntryCommitted>>,
+        _me: &ModifyEvent,
+    ) -> Result<(), OperationError> {
+        cand.iter_mut().try_for_each(|e| apply_gidnumber(au, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::audit::AuditScope;
+    use crate::entry::{Entry, EntryInvalid, EntryNew};
+    use crate::server::QueryServerTransaction;
+    use crate::server::QueryServerWriteTransaction;
+    use crate::value::{PartialValue, Value};
+    use kanidm_proto::v1::OperationError;
+
+    static JSON_TEST_GROUP: &'static str = r#"{
+        "valid": null,
+        "state": null,
+        "attrs": {
+            "class": ["object", "group", "posixgroup"],
+            "name": ["testgroup"],
+            "uuid": ["83a0927f-3de1-45ec-bea0-2f7b997ef244"]
+        }
+    }"#;
+
+    fn gidnumber(au: &mut AuditScope, qs: &QueryServerWriteTransaction) -> Option<u32> {
+        let cands = qs
+            .internal_search(
+                au,
+                filter!(f_eq("name", PartialValue::new_iutf8s("testgroup"))),
+            )
+            .expect("Internal search failure");
+        cands[0].get_ava_single_uint32("gidnumber")
+    }
+
+    #[test]
+    fn test_pre_create_gidnumber_generated() {
+        let preload: Vec<Entry<EntryInvalid, EntryNew>> = Vec::new();
+        let create = vec![Entry::unsafe_from_entry_str(JSON_TEST_GROUP)];
+
+        run_create_test!(
+            Ok(()),
+            preload,
+            create,
+            None,
+            |au: &mut AuditScope, qs: &QueryServerWriteTransaction| {
+                // The last 32 bits of the uuid, 0x997ef244, without the
+                // sign bit and lifted into the generated range.
+                assert_eq!(gidnumber(au, qs), Some(0x197ff244));
+            }
+        );
+    }
+
+    #[test]
+    fn test_pre_create_gidnumber_kept() {
+        let preload: Vec<Entry<EntryInvalid, EntryNew>> = Vec::new();
+        let mut e: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(JSON_TEST_GROUP);
+        e.set_avas("gidnumber", vec![Value::new_uint32(2000)]);
+        let create = vec![e];
+
+        run_create_test!(
+            Ok(()),
+            preload,
+            create,
+            None,
+            |au: &mut AuditScope, qs: &QueryServerWriteTransaction| {
+                assert_eq!(gidnumber(au, qs), Some(2000));
+            }
+        );
+    }
+
+    #[test]
+    fn test_pre_create_gidnumber_system_range() {
+        let preload: Vec<Entry<EntryInvalid, EntryNew>> = Vec::new();
+        let mut e: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(JSON_TEST_GROUP);
+        e.set_avas("gidnumber", vec![Value::new_uint32(0)]);
+        let create = vec![e];
+
+        run_create_test!(
+            Err(OperationError::InvalidAttribute("gidnumber".to_string())),
+            preload,
+            create,
+            None,
+            |_, _| {}
+        );
+    }
+
+    #[test]
+    fn test_pre_modify_gidnumber_extend() {
+        let mut e: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(JSON_TEST_GROUP);
+        e.set_avas(
+            "class",
+            vec![Value::new_class("object"), Value::new_class("group")],
+        );
+        let preload = vec![e];
+
+        run_modify_test!(
+            Ok(()),
+            preload,
+            filter!(f_eq("name", PartialValue::new_iutf8s("testgroup"))),
+            modlist!([m_pres("class", &Value::new_class("posixgroup"))]),
+            None,
+            |au: &mut AuditScope, qs: &QueryServerWriteTransaction| {
+                assert!(gidnumber(au, qs).is_some());
+            }
+        );
+    }
+}
@@ -10,7 +10,10 @@ mod macros;
 mod attrunique;
 mod base;
 mod failure;
+mod gidnumber;
 mod memberof;
+mod oauth2;
+mod operational;
 mod protected;
 mod recycle;
 mod refint;
@@ -278,8 +281,15 @@ impl Plugins {
         ce: &CreateEvent,
     ) -> Result<(), OperationError> {
         audit_segment!(au, || {
-            let res =
-                run_pre_create_transform_plugin!(au, qs, cand, ce, base::Base).and_then(|_| {
+            let res = run_pre_create_transform_plugin!(au, qs, cand, ce, base::Base)
+                .and_then(|_| {
+                    run_pre_create_transform_plugin!(au, qs, cand, ce, operational::Operational)
+                })
+                .and_then(|_| run_pre_create_transform_plugin!(au, qs, cand, ce, oauth2::Oauth2))
+                .and_then(|_| {
+                    run_pre_create_transform_plugin!(au, qs, cand, ce, gidnumber::GidNumber)
+                })
+                .and_then(|_| {
                     run_pre_create_transform_plugin!(au, qs, cand, ce, attrunique::AttrUnique)
                 });
 
@@ -314,6 +324,24 @@ impl Plugins {
         })
     }
 
+    // A bulk import checks its own references before it writes anything, so
+    // only memberof is left to run, once, over the groups it created.
+    pub fn run_post_import(
+        au: &mut AuditScope,
+        qs: &mut QueryServerWriteTransaction,
+        cand: &Vec<Entry<EntryValid, EntryCommitted>>,
+    ) -> Result<(), OperationError> {
+        let ce = CreateEvent::new_internal(Vec::new());
+        let ce = &ce;
+        audit_segment!(au, || run_post_create_plugin!(
+            au,
+            qs,
+            cand,
+            ce,
+            memberof::MemberOf
+        ))
+    }
+
     pub fn run_pre_modify(
         au: &mut AuditScope,
         qs: &mut QueryServerWriteTransaction,
@@ -322,13 +350,32 @@ impl Plugins {
     ) -> Result<(), OperationError> {
         audit_segment!(au, || {
             let res = run_pre_modify_plugin!(au, qs, cand, me, protected::Protected)
+                .and_then(|_| run_pre_modify_plugin!(au, qs, cand, me, operational::Operational))
                 .and_then(|_| run_pre_modify_plugin!(au, qs, cand, me, base::Base))
+                .and_then(|_| run_pre_modify_plugin!(au, qs, cand, me, oauth2::Oauth2))
+                .and_then(|_| run_pre_modify_plugin!(au, qs, cand, me, gidnumber::GidNumber))
                 .and_then(|_| run_pre_modify_plugin!(au, qs, cand, me, attrunique::AttrUnique));
 
             res
         })
     }
 
+    // Runs after the modified candidates pass schema validation, see
+    // operational::Operational::stamp_modified.
+    pub fn run_modify_stamp(
+        au: &mut AuditScope,
+        qs: &QueryServerWriteTransaction,
+        pre_cand: &Vec<Entry<EntryValid, EntryCommitted>>,
+        cand: Vec<Entry<EntryValid, EntryCommitted>>,
+    ) -> Result<Vec<Entry<EntryValid, EntryCommitted>>, OperationError> {
+        let mut audit_scope = AuditScope::new(operational::Operational::id());
+        let r = audit_segment!(audit_scope, || {
+            operational::Operational::stamp_modified(&mut audit_scope, qs, pre_cand, cand)
+        });
+        au.append_scope(audit_scope);
+        r
+    }
+
     pub fn run_post_modify(
         au: &mut AuditScope,
         qs: &mut QueryServerWriteTransaction,
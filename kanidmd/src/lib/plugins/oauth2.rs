@@ -0,0 +1,169 @@
+// Fills in what an oauth2 relying party needs but nobody should have to make
+// up by hand: its client secret and token signing key. These are generated
+// after access controls are checked, so they need no create rights, and
+// removing either is how an administrator rotates it. The scope maps are
+// checked here too, as schema only knows they're strings.
+use crate::plugins::Plugin;
+
+use crate::audit::AuditScope;
+use crate::entry::{Entry, EntryCommitted, EntryInvalid, EntryNew};
+use crate::event::{CreateEvent, ModifyEvent};
+use crate::idm::oauth2::{generate_token_key, parse_scope_map};
+use crate::server::QueryServerWriteTransaction;
+use crate::utils::password_from_random;
+use crate::value::{PartialValue, Value};
+use kanidm_proto::v1::OperationError;
+
+pub struct Oauth2 {}
+
+fn fill_resource_server<STATE>(
+    au: &mut AuditScope,
+    e: &mut Entry<EntryInvalid, STATE>,
+) -> Result<(), OperationError>
+where
+    STATE: Copy,
+{
+    if !e.attribute_value_pres("class", &PartialValue::new_class("oauth2_resource_server")) {
+        return Ok(());
+    }
+    let valid_maps = e
+        .get_ava_set_str("oauth2_rs_scope_map")
+        .map(|s| s.into_iter().all(|v| parse_scope_map(v).is_some()))
+        .unwrap_or(true);
+    if !valid_maps {
+        audit_log!(au, "oauth2 scope map isn't \"group: scope ...\"");
+        return Err(OperationError::InvalidAttribute(
+            "oauth2_rs_scope_map".to_string(),
+        ));
+    }
+    if !e.attribute_pres("oauth2_rs_basic_secret") {
+        audit_log!(au, "Generating oauth2 client secret");
+        e.set_avas(
+            "oauth2_rs_basic_secret",
+            vec![Value::new_utf8(password_from_random())],
+        );
+    }
+    if !e.attribute_pres("oauth2_rs_token_key") {
+        audit_log!(au, "Generating oauth2 token signing key");
+        let key = generate_token_key(au)?;
+        e.set_avas("oauth2_rs_token_key", vec![Value::new_utf8(key)]);
+    }
+    Ok(())
+}
+
+impl Plugin for Oauth2 {
+    fn id() -> &'static str {
+        "plugin_oauth2"
+    }
+
+    fn pre_create_transform(
+        au: &mut AuditScope,
+        _qs: &mut QueryServerWriteTransaction,
+        cand: &mut Vec<Entry<EntryInvalid, EntryNew>>,
+        _ce: &CreateEvent,
+    ) -> Result<(), OperationError> {
+        cand.iter_mut()
+            .try_for_each(|e| fill_resource_server(au, e))
+    }
+
+    fn pre_modify(
+        au: &mut AuditScope,
+        _qs: &mut QueryServerWriteTransaction,
+        cand: &mut Vec<Entry<EntryInvalid, EntryCommitted>>,
+        _me: &ModifyEvent,
+    ) -> Result<(), OperationError> {
+        cand.iter_mut()
+            .try_for_each(|e| fill_resource_server(au, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::audit::AuditScope;
+    use crate::entry::{Entry, EntryInvalid, EntryNew};
+    use crate::server::QueryServerTransaction;
+    use crate::server::QueryServerWriteTransaction;
+    use crate::value::{PartialValue, Value};
+    use kanidm_proto::v1::OperationError;
+
+    static JSON_TEST_RS: &'static str = r#"{
+        "valid": null,
+        "state": null,
+        "attrs": {
+            "class": ["object", "oauth2_resource_server"],
+            "name": ["test_app"],
+            "oauth2_rs_origin": ["https://app.example.com/oauth2/callback"],
+            "oauth2_rs_scope_map": ["idm_admins: openid profile"]
+        }
+    }"#;
+
+    fn rs_attr(au: &mut AuditScope, qs: &QueryServerWriteTransaction, attr: &str) -> String {
+        let cands = qs
+            .internal_search(
+                au,
+                filter!(f_eq("name", PartialValue::new_iutf8s("test_app"))),
+            )
+            .expect("Internal search failure");
+        cands[0]
+            .get_ava_single_string(attr)
+            .expect("attribute not generated")
+    }
+
+    #[test]
+    fn test_pre_create_oauth2_generated() {
+        let preload: Vec<Entry<EntryInvalid, EntryNew>> = Vec::new();
+        let create = vec![Entry::unsafe_from_entry_str(JSON_TEST_RS)];
+
+        run_create_test!(
+            Ok(()),
+            preload,
+            create,
+            None,
+            |au: &mut AuditScope, qs: &QueryServerWriteTransaction| {
+                assert_eq!(rs_attr(au, qs, "oauth2_rs_basic_secret").len(), 48);
+                assert!(rs_attr(au, qs, "oauth2_rs_token_key").contains("EC PRIVATE KEY"));
+            }
+        );
+    }
+
+    #[test]
+    fn test_pre_create_oauth2_invalid_scope_map() {
+        let preload: Vec<Entry<EntryInvalid, EntryNew>> = Vec::new();
+        let mut e: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(JSON_TEST_RS);
+        e.set_avas(
+            "oauth2_rs_scope_map",
+            vec![Value::new_utf8s("idm_admins openid")],
+        );
+        let create = vec![e];
+
+        run_create_test!(
+            Err(OperationError::InvalidAttribute(
+                "oauth2_rs_scope_map".to_string()
+            )),
+            preload,
+            create,
+            None,
+            |_, _| {}
+        );
+    }
+
+    #[test]
+    fn test_pre_modify_oauth2_rotate_secret() {
+        let mut e: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(JSON_TEST_RS);
+        e.set_avas("oauth2_rs_basic_secret", vec![Value::new_utf8s("old")]);
+        let preload = vec![e];
+
+        run_modify_test!(
+            Ok(()),
+            preload,
+            filter!(f_eq("name", PartialValue::new_iutf8s("test_app"))),
+            modlist!([m_purge("oauth2_rs_basic_secret")]),
+            None,
+            |au: &mut AuditScope, qs: &QueryServerWriteTransaction| {
+                let secret = rs_attr(au, qs, "oauth2_rs_basic_secret");
+                assert_ne!(secret, "old");
+                assert_eq!(secret.len(), 48);
+            }
+        );
+    }
+}
@@ -0,0 +1,305 @@
+// Operational attributes are maintained by the server, not clients. On create
+// we stamp createtimestamp, modifytimestamp and creatorsname, and on modify we
+// refresh modifytimestamp - but only for entries the modify actually changed.
+//
+// Clients can read these (subject to access controls), but any attempt to
+// modify them is rejected. Internal operations are trusted so that migrations
+// can carry existing values across.
+use crate::plugins::Plugin;
+
+use crate::audit::AuditScope;
+use crate::entry::{Entry, EntryCommitted, EntryInvalid, EntryNew, EntryValid};
+use crate::event::{CreateEvent, EventOrigin, ModifyEvent};
+use crate::modify::Modify;
+use crate::schema::SchemaTransaction;
+use crate::server::{QueryServerTransaction, QueryServerWriteTransaction};
+use crate::value::Value;
+use kanidm_proto::v1::OperationError;
+
+pub struct Operational {}
+
+// Stored as generalizedtime, which sorts correctly as a string.
+fn generalized_time_now() -> Result<Value, OperationError> {
+    time::now_utc()
+        .strftime("%Y%m%d%H%M%SZ")
+        .map(|t| Value::new_utf8(t.to_string()))
+        .map_err(|_| OperationError::InvalidState)
+}
+
+impl Operational {
+    // Unlike the plugin hooks this runs after schema validation, so that the
+    // pre and post entries are both normalised and can be compared directly.
+    // Unchanged entries are passed through untouched, so a no-op modify stays
+    // a no-op in the backend.
+    pub fn stamp_modified(
+        au: &mut AuditScope,
+        qs: &QueryServerWriteTransaction,
+        pre_cand: &Vec<Entry<EntryValid, EntryCommitted>>,
+        cand: Vec<Entry<EntryValid, EntryCommitted>>,
+    ) -> Result<Vec<Entry<EntryValid, EntryCommitted>>, OperationError> {
+        let now = generalized_time_now()?;
+        let schema = qs.get_schema();
+        let mut stamped = 0;
+
+        let res: Result<Vec<_>, _> = cand
+            .into_iter()
+            .zip(pre_cand.iter())
+            .map(|(e, pre)| {
+                if &e == pre {
+                    Ok(e)
+                } else {
+                    stamped += 1;
+                    let mut e = e.invalidate();
+                    e.set_avas("modifytimestamp", vec![now.clone()]);
+                    e.validate(schema).map_err(OperationError::SchemaViolation)
+                }
+            })
+            .collect();
+
+        audit_log!(au, "Stamped modifytimestamp on {} entries", stamped);
+        res
+    }
+}
+
+impl Plugin for Operational {
+    fn id() -> &'static str {
+        "plugin_operational"
+    }
+
+    fn pre_create_transform(
+        au: &mut AuditScope,
+        _qs: &mut QueryServerWriteTransaction,
+        cand: &mut Vec<Entry<EntryInvalid, EntryNew>>,
+        ce: &CreateEvent,
+    ) -> Result<(), OperationError> {
+        let now = generalized_time_now()?;
+        let creator = match &ce.event.origin {
+            EventOrigin::User(e) => Some(Value::new_uuidr(e.get_uuid())),
+            EventOrigin::Internal => None,
+        };
+        let internal = ce.event.is_internal();
+
+        cand.iter_mut().for_each(|e| {
+            // Anything a client supplied is replaced, so these can't be forged.
+            if !internal || !e.attribute_pres("createtimestamp") {
+                e.set_avas("createtimestamp", vec![now.clone()]);
+            }
+            if !internal || !e.attribute_pres("modifytimestamp") {
+                e.set_avas("modifytimestamp", vec![now.clone()]);
+            }
+            if !internal {
+                match &creator {
+                    Some(c) => e.set_avas("creatorsname", vec![c.clone()]),
+                    None => e.purge_ava("creatorsname"),
+                }
+            }
+        });
+
+        audit_log!(
+            au,
+            "Stamped operational attributes on {} entries",
+            cand.len()
+        );
+        Ok(())
+    }
+
+    fn pre_modify(
+        au: &mut AuditScope,
+        qs: &mut QueryServerWriteTransaction,
+        _cand: &mut Vec<Entry<EntryInvalid, EntryCommitted>>,
+        me: &ModifyEvent,
+    ) -> Result<(), OperationError> {
+        if me.event.is_internal() {
+            audit_log!(
+                au,
+                "Internal operation, allowing changes to operational attributes"
+            );
+            return Ok(());
+        }
+
+        let schema = qs.get_schema();
+        let attributes = schema.get_attributes();
+
+        me.modlist.iter().fold(Ok(()), |acc, m| {
+            if acc.is_err() {
+                acc
+            } else {
+                let a = match m {
+                    Modify::Present(a, _) => a,
+                    Modify::Removed(a, _) => a,
+                    Modify::Purged(a) => a,
+                };
+                if attributes.get(a).map(|sa| sa.operational).unwrap_or(false) {
+                    audit_log!(au, "Refusing to modify operational attribute {}", a);
+                    Err(OperationError::InvalidAttribute(a.clone()))
+                } else {
+                    Ok(())
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::constants::JSON_ADMIN_V1;
+    use crate::entry::{Entry, EntryInvalid, EntryNew};
+    use crate::modify::{Modify, ModifyList};
+    use crate::server::{QueryServerTransaction, QueryServerWriteTransaction};
+    use crate::value::{PartialValue, Value};
+    use kanidm_proto::v1::OperationError;
+
+    static JSON_ADMIN_ALLOW_ALL: &'static str = r#"{
+        "valid": null,
+        "state": null,
+        "attrs": {
+            "class": [
+                "object",
+                "access_control_profile",
+                "access_control_modify",
+                "access_control_create",
+                "access_control_delete",
+                "access_control_search"
+            ],
+            "name": ["idm_admins_acp_allow_all_test"],
+            "uuid": ["bb18f746-a409-497d-928c-5455d4aef4f7"],
+            "description": ["Builtin IDM Administrators Access Controls."],
+            "acp_enable": ["true"],
+            "acp_receiver": [
+                "{\"Eq\":[\"uuid\",\"00000000-0000-0000-0000-000000000000\"]}"
+            ],
+            "acp_targetscope": [
+                "{\"Pres\":\"class\"}"
+            ],
+            "acp_search_attr": ["name", "class", "uuid"],
+            "acp_modify_class": [],
+            "acp_modify_removedattr": ["displayname", "modifytimestamp"],
+            "acp_modify_presentattr": ["displayname", "modifytimestamp"],
+            "acp_create_class": ["object", "person"],
+            "acp_create_attr": ["name", "class", "description", "displayname", "createtimestamp"]
+        }
+    }"#;
+
+    static JSON_TESTPERSON: &'static str = r#"{
+        "valid": null,
+        "state": null,
+        "attrs": {
+            "class": ["object", "person"],
+            "name": ["testperson"],
+            "description": ["testperson"],
+            "displayname": ["testperson"]
+        }
+    }"#;
+
+    #[test]
+    fn test_pre_create_stamp() {
+        let acp: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(JSON_ADMIN_ALLOW_ALL);
+        let preload = vec![acp];
+
+        // A client supplied timestamp is replaced with the real one.
+        let mut e: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(JSON_TESTPERSON);
+        e.add_ava("createtimestamp", &Value::new_utf8s("19700101000000Z"));
+        let create = vec![e];
+
+        run_create_test!(
+            Ok(()),
+            preload,
+            create,
+            Some(JSON_ADMIN_V1),
+            |au: &mut AuditScope, qs: &QueryServerWriteTransaction| {
+                let cands = qs
+                    .internal_search(
+                        au,
+                        filter!(f_eq("name", PartialValue::new_iutf8s("testperson"))),
+                    )
+                    .expect("Internal search failure");
+                let ue = cands.first().expect("No cand");
+                let ct = ue
+                    .get_ava_single_string("createtimestamp")
+                    .expect("No createtimestamp");
+                assert!(ct != "19700101000000Z");
+                assert!(ue.get_ava_single_string("modifytimestamp") == Some(ct));
+                assert!(ue.attribute_value_pres(
+                    "creatorsname",
+                    &PartialValue::new_uuids("00000000-0000-0000-0000-000000000000")
+                        .expect("invalid uuid")
+                ));
+            }
+        );
+    }
+
+    #[test]
+    fn test_pre_modify_deny_operational() {
+        let acp: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(JSON_ADMIN_ALLOW_ALL);
+        let e: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(JSON_TESTPERSON);
+        let preload = vec![acp, e];
+
+        // Even though access controls allow it, operational attributes can't be
+        // changed by a client.
+        run_modify_test!(
+            Err(OperationError::InvalidAttribute(
+                "modifytimestamp".to_string()
+            )),
+            preload,
+            filter!(f_eq("name", PartialValue::new_iutf8s("testperson"))),
+            ModifyList::new_list(vec![Modify::Purged("modifytimestamp".to_string())]),
+            Some(JSON_ADMIN_V1),
+            |_, _| {}
+        );
+    }
+
+    #[test]
+    fn test_modify_stamp_only_changed() {
+        // Internal creates may carry their own timestamps, so give the entry
+        // an old one that we can check for.
+        let mut e: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(JSON_TESTPERSON);
+        e.add_ava("modifytimestamp", &Value::new_utf8s("19700101000000Z"));
+        let preload = vec![e.clone()];
+
+        // Asserting a value that's already present changes nothing, so the
+        // timestamp must not move.
+        run_modify_test!(
+            Ok(()),
+            preload,
+            filter!(f_eq("name", PartialValue::new_iutf8s("testperson"))),
+            ModifyList::new_list(vec![Modify::Present(
+                "displayname".to_string(),
+                Value::new_utf8s("testperson")
+            )]),
+            None,
+            |au: &mut AuditScope, qs: &QueryServerWriteTransaction| {
+                let cands = qs
+                    .internal_search(
+                        au,
+                        filter!(f_eq("name", PartialValue::new_iutf8s("testperson"))),
+                    )
+                    .expect("Internal search failure");
+                let ue = cands.first().expect("No cand");
+                assert!(ue.get_ava_single_str("modifytimestamp") == Some("19700101000000Z"));
+            }
+        );
+
+        let preload = vec![e];
+        run_modify_test!(
+            Ok(()),
+            preload,
+            filter!(f_eq("name", PartialValue::new_iutf8s("testperson"))),
+            ModifyList::new_list(vec![Modify::Present(
+                "displayname".to_string(),
+                Value::new_utf8s("changed")
+            )]),
+            None,
+            |au: &mut AuditScope, qs: &QueryServerWriteTransaction| {
+                let cands = qs
+                    .internal_search(
+                        au,
+                        filter!(f_eq("name", PartialValue::new_iutf8s("testperson"))),
+                    )
+                    .expect("Internal search failure");
+                let ue = cands.first().expect("No cand");
+                assert!(ue.attribute_pres("modifytimestamp"));
+                assert!(ue.get_ava_single_str("modifytimestamp") != Some("19700101000000Z"));
+            }
+        );
+    }
+}
@@ -0,0 +1,352 @@
+// Operation scheduling. The query server workers are a fixed pool, so if a
+// bulk import or a long maintenance task is occupying them, logins queue up
+// behind it. Every handler takes a ticket here before it touches the backend,
+// and when slots are contended they are granted by weighted fairness between
+// the classes of operation - each class gets a share of the slots in
+// proportion to its weight, so lower classes are slowed but never starved.
+//
+// Only an op whose worker is waiting here can be ordered, so there must be
+// fewer slots than workers - with a slot each, every worker would just run
+// whatever it took from its mailbox, in the order it arrived.
+
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpPriority {
+    Interactive,
+    Admin,
+    Bulk,
+    Maintenance,
+}
+
+// A create with more entries than this is treated as a bulk operation.
+pub const BULK_CREATE_THRESHOLD: usize = 16;
+
+// The slots for a pool of workers, leaving about half of them to wait.
+pub fn slots_for_workers(workers: usize) -> usize {
+    (workers + 1) / 2
+}
+
+const CLASSES: usize = 4;
+// Lcm of the weights, so the virtual time steps are always whole numbers.
+const VTIME_SCALE: u64 = 8;
+
+impl OpPriority {
+    fn index(self) -> usize {
+        match self {
+            OpPriority::Interactive => 0,
+            OpPriority::Admin => 1,
+            OpPriority::Bulk => 2,
+            OpPriority::Maintenance => 3,
+        }
+    }
+
+    fn weight(self) -> u64 {
+        match self {
+            OpPriority::Interactive => 8,
+            OpPriority::Admin => 4,
+            OpPriority::Bulk => 2,
+            OpPriority::Maintenance => 1,
+        }
+    }
+}
+
+struct SchedState {
+    active: usize,
+    waiting: [usize; CLASSES],
+    // Each class advances its virtual time by scale/weight every time it is
+    // granted a slot. The waiting class with the lowest virtual time goes next.
+    vtime: [u64; CLASSES],
+    last_vtime: u64,
+}
+
+impl SchedState {
+    fn next_class(&self) -> Option<usize> {
+        (0..CLASSES)
+            .filter(|i| self.waiting[*i] > 0)
+            .min_by_key(|i| (self.vtime[*i], *i))
+    }
+}
+
+struct SchedInner {
+    slots: usize,
+    state: Mutex<SchedState>,
+    cv: Condvar,
+}
+
+#[derive(Clone)]
+pub struct OpScheduler {
+    inner: Arc<SchedInner>,
+}
+
+// Held for the duration of an operation, and returns the slot on drop.
+pub struct OpTicket {
+    sched: OpScheduler,
+}
+
+impl Drop for OpTicket {
+    fn drop(&mut self) {
+        let mut state = self.sched.lock();
+        state.active -= 1;
+        self.sched.inner.cv.notify_all();
+    }
+}
+
+impl OpScheduler {
+    pub fn new(slots: usize) -> Self {
+        OpScheduler {
+            inner: Arc::new(SchedInner {
+                slots: if slots == 0 { 1 } else { slots },
+                state: Mutex::new(SchedState {
+                    active: 0,
+                    waiting: [0; CLASSES],
+                    vtime: [0; CLASSES],
+                    last_vtime: 0,
+                }),
+                cv: Condvar::new(),
+            }),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, SchedState> {
+        // A panic while holding this lock can't leave the counters in a state
+        // that is worse than blocking every worker forever.
+        self.inner
+            .state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn acquire(&self, prio: OpPriority) -> OpTicket {
+        let i = prio.index();
+        let mut state = self.lock();
+
+        // A class that has been idle doesn't get to claim the time it was
+        // idle for, else it could monopolise the slots when it returns.
+        if state.waiting[i] == 0 && state.vtime[i] < state.last_vtime {
+            state.vtime[i] = state.last_vtime;
+        }
+        state.waiting[i] += 1;
+
+        while !(state.active < self.inner.slots && state.next_class() == Some(i)) {
+            state = self
+                .inner
+                .cv
+                .wait(state)
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+        }
+
+        state.waiting[i] -= 1;
+        state.active += 1;
+        state.last_vtime = state.vtime[i];
+        state.vtime[i] += VTIME_SCALE / prio.weight();
+        // There may still be free slots for the next class in line.
+        self.inner.cv.notify_all();
+
+        OpTicket {
+            sched: self.clone(),
+        }
+    }
+
+    // Only grants a slot if nothing else is running or waiting, so that
+    // background work can wait for a quiet moment rather than queue.
+    pub fn try_acquire_idle(&self, prio: OpPriority) -> Option<OpTicket> {
+        let i = prio.index();
+        let mut state = self.lock();
+        if state.active > 0 || state.waiting.iter().any(|w| *w > 0) {
+            return None;
+        }
+
+        if state.vtime[i] < state.last_vtime {
+            state.vtime[i] = state.last_vtime;
+        }
+        state.active += 1;
+        state.last_vtime = state.vtime[i];
+        state.vtime[i] += VTIME_SCALE / prio.weight();
+
+        Some(OpTicket {
+            sched: self.clone(),
+        })
+    }
+
+    #[cfg(test)]
+    fn waiting(&self, prio: OpPriority) -> usize {
+        self.lock().waiting[prio.index()]
+    }
+}
+
+// Writes can only run one at a time, and a single worker would take them from
+// its mailbox in order of arrival. Instead several workers take them, and wait
+// here for the one write slot by priority, before a slot shared with the reads.
+#[derive(Clone)]
+pub struct WriteScheduler {
+    writes: OpScheduler,
+    sched: OpScheduler,
+}
+
+pub struct WriteTicket {
+    // Dropped in this order, so the shared slot is returned first.
+    _op: OpTicket,
+    _write: OpTicket,
+}
+
+impl WriteScheduler {
+    pub fn new(sched: OpScheduler) -> Self {
+        WriteScheduler {
+            writes: OpScheduler::new(1),
+            sched: sched,
+        }
+    }
+
+    pub fn acquire(&self, prio: OpPriority) -> WriteTicket {
+        let write = self.writes.acquire(prio);
+        WriteTicket {
+            _op: self.sched.acquire(prio),
+            _write: write,
+        }
+    }
+
+    pub fn try_acquire_idle(&self, prio: OpPriority) -> Option<WriteTicket> {
+        let write = self.writes.try_acquire_idle(prio)?;
+        Some(WriteTicket {
+            _op: self.sched.try_acquire_idle(prio)?,
+            _write: write,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::priority::{slots_for_workers, OpPriority, OpScheduler, WriteScheduler};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    fn wait_for_waiting(sched: &OpScheduler, prio: OpPriority) {
+        while sched.waiting(prio) == 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn test_priority_interactive_first() {
+        let sched = OpScheduler::new(1);
+        let order = Arc::new(Mutex::new(Vec::new()));
+
+        let ticket = sched.acquire(OpPriority::Bulk);
+
+        let spawn = |prio: OpPriority| {
+            let sched = sched.clone();
+            let order = order.clone();
+            thread::spawn(move || {
+                let _t = sched.acquire(prio);
+                order.lock().unwrap().push(prio);
+            })
+        };
+
+        // Maintenance queues first, but interactive must still beat it.
+        let m = spawn(OpPriority::Maintenance);
+        wait_for_waiting(&sched, OpPriority::Maintenance);
+        let a = spawn(OpPriority::Interactive);
+        wait_for_waiting(&sched, OpPriority::Interactive);
+
+        drop(ticket);
+        m.join().unwrap();
+        a.join().unwrap();
+
+        assert_eq!(
+            *order.lock().unwrap(),
+            vec![OpPriority::Interactive, OpPriority::Maintenance]
+        );
+    }
+
+    #[test]
+    fn test_priority_no_starvation() {
+        let sched = OpScheduler::new(1);
+        let order = Arc::new(Mutex::new(Vec::new()));
+
+        let ticket = sched.acquire(OpPriority::Admin);
+
+        // Queue plenty of admin work and one maintenance op. Maintenance has
+        // to be given a slot before the admin queue is drained.
+        let mut handles = Vec::new();
+        for _ in 0..12 {
+            let sched_c = sched.clone();
+            let order_c = order.clone();
+            handles.push(thread::spawn(move || {
+                let _t = sched_c.acquire(OpPriority::Admin);
+                order_c.lock().unwrap().push(OpPriority::Admin);
+            }));
+        }
+        while sched.waiting(OpPriority::Admin) < 12 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        let sched_c = sched.clone();
+        let order_c = order.clone();
+        handles.push(thread::spawn(move || {
+            let _t = sched_c.acquire(OpPriority::Maintenance);
+            order_c.lock().unwrap().push(OpPriority::Maintenance);
+        }));
+        wait_for_waiting(&sched, OpPriority::Maintenance);
+
+        drop(ticket);
+        handles.into_iter().for_each(|h| h.join().unwrap());
+
+        let order = order.lock().unwrap();
+        assert!(order.len() == 13);
+        assert!(order.last() != Some(&OpPriority::Maintenance));
+    }
+
+    #[test]
+    fn test_priority_try_acquire_idle() {
+        let sched = OpScheduler::new(2);
+        let ticket = sched.acquire(OpPriority::Interactive);
+        // There is a free slot, but we aren't idle.
+        assert!(sched.try_acquire_idle(OpPriority::Maintenance).is_none());
+        drop(ticket);
+
+        let idle = sched.try_acquire_idle(OpPriority::Maintenance);
+        assert!(idle.is_some());
+        // Normal work still gets the slot that's left.
+        let _t = sched.acquire(OpPriority::Interactive);
+        assert!(sched.try_acquire_idle(OpPriority::Maintenance).is_none());
+    }
+
+    #[test]
+    fn test_priority_write_waits() {
+        // Four workers, and fewer slots, as the server has.
+        let workers = 4;
+        assert!(slots_for_workers(workers) < workers);
+        let sched = OpScheduler::new(slots_for_workers(workers));
+        let writes = WriteScheduler::new(sched.clone());
+        let order = Arc::new(Mutex::new(Vec::new()));
+
+        let ticket = writes.acquire(OpPriority::Bulk);
+
+        let spawn = |prio: OpPriority| {
+            let writes = writes.clone();
+            let order = order.clone();
+            thread::spawn(move || {
+                let _t = writes.acquire(prio);
+                order.lock().unwrap().push(prio);
+            })
+        };
+
+        // The maintenance write arrived first, and there are shared slots
+        // free, but it still waits behind the interactive write.
+        let m = spawn(OpPriority::Maintenance);
+        wait_for_waiting(&writes.writes, OpPriority::Maintenance);
+        let a = spawn(OpPriority::Interactive);
+        wait_for_waiting(&writes.writes, OpPriority::Interactive);
+        assert!(writes.writes.waiting(OpPriority::Maintenance) == 1);
+
+        drop(ticket);
+        m.join().unwrap();
+        a.join().unwrap();
+
+        assert_eq!(
+            *order.lock().unwrap(),
+            vec![OpPriority::Interactive, OpPriority::Maintenance]
+        );
+    }
+}
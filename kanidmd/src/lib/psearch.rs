@@ -0,0 +1,479 @@
+// Persistent searches, so that a client can be sent the changes to the
+// entries a filter matches as they commit, rather than polling for them.
+//
+// One thread follows the commit events. Each search is first run in full as
+// its owner, then for each commit only over the entries that commit changed,
+// so access controls and search limits apply exactly as to any other search.
+// What a search matched is kept so that an entry leaving it - deleted, changed
+// to no longer match, or no longer visible - can be told as a Delete without
+// saying anything of entries the owner never saw.
+//
+// Commits are followed from before a search is first run, so a change near
+// then may be sent twice, but is never missed. Nothing here lasts past a
+// restart, and a client that has gone away is dropped at the next notice or
+// keepalive.
+
+use futures::sync::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use crate::async_log::EventLog;
+use crate::audit::AuditScope;
+use crate::commits::{ChangeType, CommitEvent};
+use crate::entry::{Entry, EntryCommitted, EntryReduced};
+use crate::event::{Event, SearchEvent};
+use crate::filter::Filter;
+use crate::server::{QueryServer, QueryServerReadTransaction, QueryServerTransaction};
+use crate::value::PartialValue;
+
+use kanidm_proto::v1::Filter as ProtoFilter;
+use kanidm_proto::v1::{OperationError, PersistentSearchNotice, UserAuthToken};
+
+// Each holds a connection open, so there can't be too many.
+pub const PSEARCH_MAX: usize = 256;
+// Often enough that a client's read timeout doesn't end an idle search.
+pub const PSEARCH_KEEPALIVE: Duration = Duration::from_secs(15);
+// How soon a new search begins when nothing is being committed.
+const PSEARCH_POLL: Duration = Duration::from_millis(100);
+
+// None is a keepalive.
+pub type NoticeReceiver = UnboundedReceiver<Option<PersistentSearchNotice>>;
+
+struct PersistentSearch {
+    uat: UserAuthToken,
+    filter: ProtoFilter,
+    // What the search matches, once it has begun.
+    matched: Option<BTreeSet<Uuid>>,
+    tx: UnboundedSender<Option<PersistentSearchNotice>>,
+}
+
+#[derive(Clone)]
+pub struct PersistentSearches {
+    inner: Arc<Mutex<BTreeMap<Uuid, PersistentSearch>>>,
+}
+
+// Run filter as the owner of uat, over only the entries in uuids if given.
+fn search(
+    au: &mut AuditScope,
+    qs: &QueryServerReadTransaction,
+    uat: &UserAuthToken,
+    filter: &ProtoFilter,
+    uuids: Option<&BTreeSet<Uuid>>,
+) -> Result<Vec<Entry<EntryReduced, EntryCommitted>>, OperationError> {
+    let event = Event::from_ro_uat(au, qs, Some(uat.clone()))?;
+    // As with polling for changes.
+    if event.is_anonymous() {
+        audit_log!(au, "anonymous may not follow changes");
+        return Err(OperationError::AccessDenied);
+    }
+    let f = Filter::from_ro(au, filter, qs)?;
+    let f_search = match uuids {
+        Some(uuids) => Filter::join_parts_and(
+            f.clone(),
+            filter_all!(f_or(
+                uuids
+                    .iter()
+                    .map(|u| f_eq("uuid", PartialValue::new_uuidr(u)))
+                    .collect()
+            )),
+        ),
+        None => f.clone(),
+    };
+    let se = SearchEvent {
+        event: event,
+        filter: f_search
+            .to_ignore_hidden()
+            .validate(qs.get_schema())
+            .map_err(|e| OperationError::SchemaViolation(e))?,
+        filter_orig: f
+            .validate(qs.get_schema())
+            .map_err(|e| OperationError::SchemaViolation(e))?,
+        attrs: None,
+        sort: None,
+    };
+    qs.search_ext(au, &se)
+}
+
+// False once the client has gone away.
+fn send(ps: &PersistentSearch, notices: Vec<PersistentSearchNotice>) -> bool {
+    notices
+        .into_iter()
+        .all(|n| ps.tx.unbounded_send(Some(n)).is_ok())
+}
+
+fn end(au: &mut AuditScope, ps: &PersistentSearch, e: OperationError) -> bool {
+    audit_log!(au, "ending persistent search of {} -> {:?}", ps.uat.name, e);
+    let _ = ps.tx.unbounded_send(Some(PersistentSearchNotice::End(e)));
+    false
+}
+
+impl PersistentSearches {
+    pub fn new() -> Self {
+        PersistentSearches {
+            inner: Arc::new(Mutex::new(BTreeMap::new())),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, BTreeMap<Uuid, PersistentSearch>> {
+        // At worst a client is sent a change twice.
+        self.inner
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    // The search begins on the thread, so that it can't miss a commit. Until
+    // then we only know the client is authenticated.
+    pub fn register(
+        &self,
+        uat: Option<UserAuthToken>,
+        filter: ProtoFilter,
+    ) -> Result<NoticeReceiver, OperationError> {
+        let uat = uat.ok_or(OperationError::NotAuthenticated)?;
+        let mut inner = self.lock();
+        inner.retain(|_, ps| !ps.tx.is_closed());
+        if inner.len() >= PSEARCH_MAX {
+            return Err(OperationError::ResourceLimit);
+        }
+        let (tx, rx) = unbounded();
+        inner.insert(
+            Uuid::new_v4(),
+            PersistentSearch {
+                uat: uat,
+                filter: filter,
+                matched: None,
+                tx: tx,
+            },
+        );
+        Ok(rx)
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    fn has_pending(&self) -> bool {
+        self.lock().values().any(|ps| ps.matched.is_none())
+    }
+
+    // Everything a search matches as it begins is an Add.
+    fn begin_pending(&self, au: &mut AuditScope, qs: &QueryServerReadTransaction) {
+        self.lock().retain(|_, ps| {
+            if ps.matched.is_some() {
+                return true;
+            }
+            let found = search(au, qs, &ps.uat, &ps.filter, None).and_then(|entries| {
+                let mut matched = BTreeSet::new();
+                let mut notices = Vec::with_capacity(entries.len());
+                for e in entries {
+                    matched.insert(*e.get_uuid());
+                    notices.push(PersistentSearchNotice::Add(e.into_pe(au, qs)?));
+                }
+                Ok((matched, notices))
+            });
+            match found {
+                Ok((matched, notices)) => {
+                    audit_log!(
+                        au,
+                        "began persistent search of {} matching {}",
+                        ps.uat.name,
+                        matched.len()
+                    );
+                    ps.matched = Some(matched);
+                    send(ps, notices)
+                }
+                Err(e) => end(au, ps, e),
+            }
+        })
+    }
+
+    fn dispatch(&self, au: &mut AuditScope, qs: &QueryServerReadTransaction, ev: &CommitEvent) {
+        // A deleted entry can't match anything, so there's no need to look.
+        let live: BTreeSet<Uuid> = ev
+            .changes
+            .uuids
+            .iter()
+            .filter(|u| ev.changes.types.get(u) != Some(&ChangeType::Deleted))
+            .cloned()
+            .collect();
+
+        self.lock().retain(|_, ps| {
+            let mut matched = match ps.matched.take() {
+                Some(m) => m,
+                None => return true,
+            };
+            let found = if live.is_empty() {
+                Ok(Vec::new())
+            } else {
+                search(au, qs, &ps.uat, &ps.filter, Some(&live))
+            };
+            let notices = found.and_then(|entries| {
+                let mut seen = BTreeSet::new();
+                let mut notices = Vec::new();
+                for e in entries {
+                    let uuid = *e.get_uuid();
+                    seen.insert(uuid);
+                    let pe = e.into_pe(au, qs)?;
+                    if matched.insert(uuid) {
+                        notices.push(PersistentSearchNotice::Add(pe));
+                    } else {
+                        notices.push(PersistentSearchNotice::Modify(pe));
+                    }
+                }
+                for u in ev.changes.uuids.iter() {
+                    if !seen.contains(u) && matched.remove(u) {
+                        notices.push(PersistentSearchNotice::Delete(
+                            u.to_hyphenated_ref().to_string(),
+                        ));
+                    }
+                }
+                Ok(notices)
+            });
+            ps.matched = Some(matched);
+            match notices {
+                Ok(notices) => send(ps, notices),
+                Err(e) => end(au, ps, e),
+            }
+        })
+    }
+
+    fn keepalive(&self) {
+        self.lock()
+            .retain(|_, ps| ps.tx.unbounded_send(None).is_ok())
+    }
+}
+
+pub fn start(qs: QueryServer, searches: PersistentSearches, log: actix::Addr<EventLog>) {
+    // Subscribed before any search can begin.
+    let commits = qs.subscribe_commits();
+    thread::spawn(move || {
+        let mut last_keepalive = Instant::now();
+        loop {
+            let ev = match commits.recv_timeout(PSEARCH_POLL) {
+                Ok(ev) => Some(ev),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            if ev.is_some() || searches.has_pending() {
+                let mut audit = AuditScope::new("persistent_search");
+                match qs.read() {
+                    Ok(qs_read) => {
+                        searches.begin_pending(&mut audit, &qs_read);
+                        if let Some(ev) = ev {
+                            searches.dispatch(&mut audit, &qs_read, &ev);
+                        }
+                    }
+                    Err(e) => {
+                        audit_log!(audit, "Unable to begin txn -> {:?}", e);
+                    }
+                }
+                log.do_send(audit);
+            }
+            if last_keepalive.elapsed() >= PSEARCH_KEEPALIVE {
+                searches.keepalive();
+                last_keepalive = Instant::now();
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PersistentSearches;
+    use crate::audit::AuditScope;
+    use crate::constants::UUID_ADMIN;
+    use crate::entry::{Entry, EntryInvalid, EntryNew};
+    use crate::modify::ModifyList;
+    use crate::server::{QueryServer, QueryServerWriteTransaction};
+    use crate::value::{PartialValue, Value};
+    use futures::{future, Async, Future, Poll, Stream};
+    use kanidm_proto::v1::Filter as ProtoFilter;
+    use kanidm_proto::v1::{OperationError, PersistentSearchNotice, UserAuthToken};
+
+    fn admin_uat() -> UserAuthToken {
+        UserAuthToken {
+            name: "admin".to_string(),
+            displayname: "System Administrator".to_string(),
+            uuid: UUID_ADMIN.to_hyphenated_ref().to_string(),
+            application: None,
+            groups: Vec::new(),
+            claims: Vec::new(),
+            elevated_until: None,
+        }
+    }
+
+    // Whatever the search has been sent so far. Polling needs a task, even
+    // when nothing is waiting.
+    fn notices(rx: &mut super::NoticeReceiver) -> Vec<PersistentSearchNotice> {
+        future::poll_fn(|| -> Poll<Vec<PersistentSearchNotice>, ()> {
+            let mut r = Vec::new();
+            while let Ok(Async::Ready(Some(n))) = rx.poll() {
+                r.extend(n);
+            }
+            Ok(Async::Ready(r))
+        })
+        .wait()
+        .unwrap()
+    }
+
+    fn test_person(name: &str, description: &str) -> Entry<EntryInvalid, EntryNew> {
+        Entry::unsafe_from_entry_str(
+            format!(
+                r#"{{
+                "valid": null,
+                "state": null,
+                "attrs": {{
+                    "class": ["object", "person"],
+                    "name": ["{}"],
+                    "description": ["{}"],
+                    "displayname": ["{}"]
+                }}
+            }}"#,
+                name, description, name
+            )
+            .as_str(),
+        )
+    }
+
+    fn set_description(
+        server_txn: &mut QueryServerWriteTransaction,
+        audit: &mut AuditScope,
+        name: &str,
+        description: &str,
+    ) -> Result<(), OperationError> {
+        server_txn.internal_modify(
+            audit,
+            filter!(f_eq("name", PartialValue::new_iutf8s(name))),
+            ModifyList::new_purge_and_set("description", Value::new_utf8s(description)),
+        )
+    }
+
+    #[test]
+    fn test_psearch_notices() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let searches = PersistentSearches::new();
+            let commits = server.subscribe_commits();
+            let mut rx = searches
+                .register(
+                    Some(admin_uat()),
+                    ProtoFilter::Eq("description".to_string(), "watched".to_string()),
+                )
+                .expect("failed to register");
+            assert_eq!(
+                searches
+                    .register(None, ProtoFilter::Pres("name".to_string()))
+                    .err(),
+                Some(OperationError::NotAuthenticated)
+            );
+
+            // Create one entry that matches, and one that doesn't.
+            let mut server_txn = server.write().expect("Failed to begin txn");
+            assert!(server_txn
+                .internal_create(
+                    audit,
+                    vec![
+                        test_person("testperson1", "watched"),
+                        test_person("testperson2", "ignored")
+                    ]
+                )
+                .is_ok());
+            assert!(server_txn.commit(audit).is_ok());
+
+            {
+                let qs_read = server.read().expect("Failed to begin txn");
+                searches.begin_pending(audit, &qs_read);
+                let ev = commits.try_recv().expect("no commit event");
+                searches.dispatch(audit, &qs_read, &ev);
+            }
+            // Begun after the create, so told of it as it began and again
+            // for the commit.
+            let n = notices(&mut rx);
+            assert_eq!(n.len(), 2);
+            match (&n[0], &n[1]) {
+                (PersistentSearchNotice::Add(a), PersistentSearchNotice::Modify(b)) => {
+                    assert_eq!(a.attrs.get("name"), Some(&vec!["testperson1".to_string()]));
+                    assert_eq!(a.attrs.get("uuid"), b.attrs.get("uuid"));
+                }
+                _ => panic!("unexpected notices {:?}", n),
+            }
+
+            // Moving an entry into and out of the filter is an Add then a
+            // Delete.
+            let mut server_txn = server.write().expect("Failed to begin txn");
+            assert!(set_description(&mut server_txn, audit, "testperson2", "watched").is_ok());
+            assert!(set_description(&mut server_txn, audit, "testperson1", "ignored").is_ok());
+            assert!(server_txn.commit(audit).is_ok());
+            {
+                let qs_read = server.read().expect("Failed to begin txn");
+                let ev = commits.try_recv().expect("no commit event");
+                searches.dispatch(audit, &qs_read, &ev);
+            }
+            let n = notices(&mut rx);
+            assert_eq!(n.len(), 2);
+            let added = match &n[0] {
+                PersistentSearchNotice::Add(e) => e.attrs.get("uuid").unwrap()[0].clone(),
+                _ => panic!("unexpected notices {:?}", n),
+            };
+            match &n[1] {
+                PersistentSearchNotice::Delete(u) => assert!(u != &added),
+                _ => panic!("unexpected notices {:?}", n),
+            }
+
+            // Changes to entries never matched say nothing, and deleting a
+            // matched one is a Delete.
+            let mut server_txn = server.write().expect("Failed to begin txn");
+            assert!(
+                set_description(&mut server_txn, audit, "testperson1", "still ignored").is_ok()
+            );
+            assert!(server_txn.commit(audit).is_ok());
+            let mut server_txn = server.write().expect("Failed to begin txn");
+            assert!(server_txn
+                .internal_delete(
+                    audit,
+                    filter!(f_eq("name", PartialValue::new_iutf8s("testperson2")))
+                )
+                .is_ok());
+            assert!(server_txn.commit(audit).is_ok());
+            {
+                let qs_read = server.read().expect("Failed to begin txn");
+                for ev in commits.try_iter() {
+                    searches.dispatch(audit, &qs_read, &ev);
+                }
+            }
+            let n = notices(&mut rx);
+            assert_eq!(n.len(), 1);
+            match &n[0] {
+                PersistentSearchNotice::Delete(u) => assert_eq!(u, &added),
+                _ => panic!("unexpected notices {:?}", n),
+            }
+
+            // Once the client goes, so does its search.
+            std::mem::drop(rx);
+            searches.keepalive();
+            assert_eq!(searches.len(), 0);
+        })
+    }
+
+    #[test]
+    fn test_psearch_ends_on_error() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let searches = PersistentSearches::new();
+            let mut rx = searches
+                .register(
+                    Some(admin_uat()),
+                    ProtoFilter::Pres("not_an_attribute".to_string()),
+                )
+                .expect("failed to register");
+            let qs_read = server.read().expect("Failed to begin txn");
+            searches.begin_pending(audit, &qs_read);
+            match notices(&mut rx).as_slice() {
+                [PersistentSearchNotice::End(_)] => {}
+                n => panic!("unexpected notices {:?}", n),
+            }
+            assert_eq!(searches.len(), 0);
+        })
+    }
+}
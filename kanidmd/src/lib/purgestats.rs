@@ -0,0 +1,67 @@
+// What the scheduled purges of recycled entries and tombstones have done
+// since we started, so a backlog that the batch limit keeps leaving behind can
+// be seen. As with the pool metrics, these are only kept in memory.
+
+use kanidm_proto::v1::{PurgeRunStats, PurgeStats};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+fn record(s: &mut PurgeRunStats, reaped: usize, pending: bool, now: i64) {
+    s.runs += 1;
+    s.reaped += reaped as u64;
+    s.pending = pending;
+    s.last_run = time::at_utc(time::Timespec::new(now, 0))
+        .strftime("%Y%m%d%H%M%SZ")
+        .map(|t| t.to_string())
+        .ok();
+}
+
+#[derive(Clone)]
+pub struct PurgeMetrics {
+    inner: Arc<Mutex<PurgeStats>>,
+}
+
+impl PurgeMetrics {
+    pub fn new() -> Self {
+        PurgeMetrics {
+            inner: Arc::new(Mutex::new(PurgeStats::default())),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, PurgeStats> {
+        self.inner
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    // now is when the run was, in seconds since the epoch.
+    pub fn recycled(&self, reaped: usize, pending: bool, now: i64) {
+        record(&mut self.lock().recycled, reaped, pending, now)
+    }
+
+    pub fn tombstones(&self, reaped: usize, pending: bool, now: i64) {
+        record(&mut self.lock().tombstones, reaped, pending, now)
+    }
+
+    pub fn to_proto(&self) -> PurgeStats {
+        self.lock().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PurgeMetrics;
+
+    #[test]
+    fn test_purge_metrics() {
+        let m = PurgeMetrics::new();
+        m.recycled(1000, true, 1_577_836_800);
+        m.clone().recycled(10, false, 1_577_840_400);
+        m.tombstones(0, false, 1_577_836_800);
+
+        let s = m.to_proto();
+        assert_eq!((s.recycled.runs, s.recycled.reaped), (2, 1010));
+        assert!(!s.recycled.pending);
+        assert_eq!(s.recycled.last_run, Some("20200101010000Z".to_string()));
+        assert_eq!((s.tombstones.runs, s.tombstones.reaped), (1, 0));
+    }
+}
@@ -0,0 +1,534 @@
+// Per identity rate limiting. Each identity (or source address if the request
+// is anonymous) gets a token bucket per class of operation, and requests are
+// refused before they are ever sent to the query server workers if the bucket
+// is empty.
+//
+// The limits are the ratelimit_search and ratelimit_write of the
+// ratelimit_policy entry, where an account may set its own in their place.
+// They are loaded at startup, and again by a thread following the commits
+// whenever one changes them.
+
+use actix_web::middleware::session::RequestSession;
+use actix_web::middleware::{Middleware, Started};
+use actix_web::{http, HttpRequest, HttpResponse, Result};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::Instant;
+
+use crate::async_log::EventLog;
+use crate::audit::AuditScope;
+use crate::constants::STR_UUID_ANONYMOUS;
+use crate::entry::{Entry, EntryCommitted, EntryValid};
+use crate::server::{QueryServer, QueryServerReadTransaction, QueryServerTransaction};
+use crate::value::PartialValue;
+use kanidm_proto::v1::{OperationError, UserAuthToken};
+
+lazy_static! {
+    static ref PVCLASS_ACCOUNT: PartialValue = PartialValue::new_class("account");
+    static ref PVCLASS_RATELIMIT_POLICY: PartialValue = PartialValue::new_class("ratelimit_policy");
+}
+
+// Once we are tracking this many buckets, we clean out any that have fully
+// refilled, as they are the same as a new bucket, and any no longer limited.
+const BUCKET_PRUNE_THRESHOLD: usize = 8192;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum OpClass {
+    Search,
+    Write,
+}
+
+// A token bucket - capacity is the burst allowed, and per_second is the rate
+// the bucket refills at.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateLimit {
+    pub capacity: u32,
+    pub per_second: u32,
+}
+
+impl RateLimit {
+    pub fn from_str(s: &str) -> Option<Self> {
+        // capacity:per_second
+        let mut parts = s.splitn(2, ':');
+        match (parts.next(), parts.next()) {
+            (Some(c), Some(r)) => match (c.trim().parse(), r.trim().parse()) {
+                (Ok(c), Ok(r)) if c > 0 && r > 0 => Some(RateLimit {
+                    capacity: c,
+                    per_second: r,
+                }),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+// None is unlimited, or for an account, that the server's limit applies.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RateLimitPolicy {
+    pub search: Option<RateLimit>,
+    pub write: Option<RateLimit>,
+}
+
+impl RateLimitPolicy {
+    fn get(&self, class: OpClass) -> Option<&RateLimit> {
+        match class {
+            OpClass::Search => self.search.as_ref(),
+            OpClass::Write => self.write.as_ref(),
+        }
+    }
+
+    // An invalid value is ignored, as if it weren't set.
+    fn from_entry(audit: &mut AuditScope, e: &Entry<EntryValid, EntryCommitted>) -> Self {
+        let mut parse = |attr: &str| -> Option<RateLimit> {
+            let v = e.get_ava_single_str(attr)?;
+            let r = RateLimit::from_str(v);
+            if r.is_none() {
+                audit_log!(audit, "ignoring invalid {} of {:?}", attr, v);
+            }
+            r
+        };
+        RateLimitPolicy {
+            search: parse("ratelimit_search"),
+            write: parse("ratelimit_write"),
+        }
+    }
+}
+
+// The server's policy, and that of each account which sets its own, by uuid.
+#[derive(Debug, Default)]
+struct RateLimits {
+    server: RateLimitPolicy,
+    accounts: BTreeMap<String, RateLimitPolicy>,
+}
+
+impl RateLimits {
+    fn limit_for(&self, key: &str, class: OpClass) -> Option<&RateLimit> {
+        // Anonymous is keyed by address, but is still the one account.
+        let account = if key.starts_with("addr:") {
+            STR_UUID_ANONYMOUS
+        } else {
+            key
+        };
+        self.accounts
+            .get(account)
+            .and_then(|p| p.get(class))
+            .or_else(|| self.server.get(class))
+    }
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: &RateLimit, now: Instant) -> Self {
+        TokenBucket {
+            tokens: limit.capacity as f64,
+            last: now,
+        }
+    }
+
+    fn refill(&mut self, limit: &RateLimit, now: Instant) {
+        let elapsed = now.duration_since(self.last);
+        let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+        self.tokens = (self.tokens + elapsed * limit.per_second as f64).min(limit.capacity as f64);
+        self.last = now;
+    }
+
+    // Take a token, or return how many seconds until one will be available.
+    fn take(&mut self, limit: &RateLimit, now: Instant) -> Result<(), u64> {
+        self.refill(limit, now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            let wait = (1.0 - self.tokens) / limit.per_second as f64;
+            Err(wait.ceil() as u64)
+        }
+    }
+
+    fn is_full(&self, limit: &RateLimit) -> bool {
+        self.tokens >= limit.capacity as f64
+    }
+}
+
+pub struct RateLimiter {
+    limits: RwLock<RateLimits>,
+    buckets: Mutex<BTreeMap<(String, OpClass), TokenBucket>>,
+}
+
+impl RateLimiter {
+    // Unlimited until the limits are loaded.
+    pub fn new() -> Self {
+        RateLimiter {
+            limits: RwLock::new(RateLimits::default()),
+            buckets: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    // The buckets are kept, so a change to the limits doesn't hand everyone
+    // a fresh burst.
+    fn set_limits(&self, limits: RateLimits) -> Result<(), OperationError> {
+        let mut current = self
+            .limits
+            .write()
+            .map_err(|_| OperationError::InvalidState)?;
+        *current = limits;
+        Ok(())
+    }
+
+    pub fn reload(
+        &self,
+        audit: &mut AuditScope,
+        qs: &QueryServerReadTransaction,
+    ) -> Result<(), OperationError> {
+        let filt = filter!(f_eq("class", PVCLASS_RATELIMIT_POLICY.clone()));
+        let res = try_audit!(audit, qs.internal_search(audit, filt));
+        let server = match res.first() {
+            Some(e) => RateLimitPolicy::from_entry(audit, e),
+            None => RateLimitPolicy::default(),
+        };
+
+        let filt = filter!(f_and!([
+            f_eq("class", PVCLASS_ACCOUNT.clone()),
+            f_or!([f_pres("ratelimit_search"), f_pres("ratelimit_write")])
+        ]));
+        let res = try_audit!(audit, qs.internal_search(audit, filt));
+        let accounts = res
+            .iter()
+            .map(|e| {
+                (
+                    e.get_uuid().to_hyphenated_ref().to_string(),
+                    RateLimitPolicy::from_entry(audit, e),
+                )
+            })
+            .collect();
+
+        audit_log!(audit, "rate limits -> {:?}", server);
+        self.set_limits(RateLimits {
+            server: server,
+            accounts: accounts,
+        })
+    }
+
+    pub fn check(&self, key: &str, class: OpClass, now: Instant) -> Result<(), OperationError> {
+        let limits = self
+            .limits
+            .read()
+            .map_err(|_| OperationError::InvalidState)?;
+        let limit = match limits.limit_for(key, class) {
+            Some(l) => l,
+            None => return Ok(()),
+        };
+
+        let mut buckets = self
+            .buckets
+            .lock()
+            .map_err(|_| OperationError::InvalidState)?;
+
+        if buckets.len() > BUCKET_PRUNE_THRESHOLD {
+            let stale: Vec<_> = buckets
+                .iter_mut()
+                .filter_map(|(k, b)| {
+                    // The limit was removed since, so the bucket is no use.
+                    let l = match limits.limit_for(&k.0, k.1) {
+                        Some(l) => l,
+                        None => return Some(k.clone()),
+                    };
+                    b.refill(l, now);
+                    if b.is_full(l) {
+                        Some(k.clone())
+                    } else {
+                        None
+                    }
+                })
+                .collect();
+            stale.iter().for_each(|k| {
+                buckets.remove(k);
+            });
+        }
+
+        buckets
+            .entry((key.to_string(), class))
+            .or_insert_with(|| TokenBucket::new(limit, now))
+            .take(limit, now)
+            .map_err(|wait| OperationError::RateLimited(wait))
+    }
+}
+
+fn reload(qs: &QueryServer, limiter: &RateLimiter, log: &actix::Addr<EventLog>) {
+    let mut audit = AuditScope::new("ratelimit_reload");
+    match qs.read() {
+        Ok(qs_read) => {
+            if let Err(e) = limiter.reload(&mut audit, &qs_read) {
+                audit_log!(audit, "Unable to reload rate limits -> {:?}", e);
+            }
+        }
+        Err(e) => {
+            audit_log!(audit, "Unable to begin txn -> {:?}", e);
+        }
+    }
+    log.do_send(audit);
+}
+
+pub fn start(qs: QueryServer, limiter: Arc<RateLimiter>, log: actix::Addr<EventLog>) {
+    // Subscribed before the first load, so no change can be missed between.
+    let commits = qs.subscribe_commits();
+    reload(&qs, &limiter, &log);
+    thread::spawn(move || {
+        while let Ok(ev) = commits.recv() {
+            if ev.changes.attrs.contains("ratelimit_search")
+                || ev.changes.attrs.contains("ratelimit_write")
+            {
+                reload(&qs, &limiter, &log);
+            }
+        }
+    });
+}
+
+// Work out who we are limiting. Authenticated identities are keyed on their
+// uuid, where anonymous is keyed on where it came from since it is shared.
+fn limit_key<S>(req: &HttpRequest<S>) -> String {
+    let uat = req.session().get::<UserAuthToken>("uat").unwrap_or(None);
+    match uat {
+        Some(ref uat) if uat.uuid != STR_UUID_ANONYMOUS => uat.uuid.clone(),
+        _ => match req.peer_addr() {
+            Some(addr) => format!("addr:{}", addr.ip()),
+            None => "addr:unknown".to_string(),
+        },
+    }
+}
+
+// The POSTs that only read are searches too.
+fn op_class(method: &http::Method, path: &str) -> OpClass {
+    let read_post = match path {
+        "/v1/raw/search"
+        | "/v1/raw/search/query"
+        | "/v1/raw/compare"
+        | "/v1/raw/changes"
+        | "/v1/raw/psearch"
+        | "/v1/raw/explain"
+        | "/v1/raw/delete/preview"
+        | "/v1/repl/supply"
+        | "/v1/admin/verify" => true,
+        _ => {
+            path.starts_with("/v1/auth")
+                || (path.starts_with("/v1/savedsearch/") && path.ends_with("/_search"))
+        }
+    };
+    if method == http::Method::GET || read_post {
+        OpClass::Search
+    } else {
+        OpClass::Write
+    }
+}
+
+// The middleware is created per worker, so the limiter itself is shared.
+pub struct RateLimitMiddleware {
+    limiter: Arc<RateLimiter>,
+}
+
+impl RateLimitMiddleware {
+    pub fn new(limiter: Arc<RateLimiter>) -> Self {
+        RateLimitMiddleware { limiter: limiter }
+    }
+}
+
+impl<S> Middleware<S> for RateLimitMiddleware {
+    fn start(&self, req: &HttpRequest<S>) -> Result<Started> {
+        // Only the api is limited, the ui assets and status are cheap.
+        if !req.path().starts_with("/v1/") {
+            return Ok(Started::Done);
+        }
+
+        let key = limit_key(req);
+        match self
+            .limiter
+            .check(&key, op_class(req.method(), req.path()), Instant::now())
+        {
+            Ok(_) => Ok(Started::Done),
+            Err(OperationError::RateLimited(wait)) => {
+                debug!("rate limited {} for {}s", key, wait);
+                Ok(Started::Response(
+                    HttpResponse::build(http::StatusCode::TOO_MANY_REQUESTS)
+                        .header(http::header::RETRY_AFTER, wait.to_string())
+                        .json(OperationError::RateLimited(wait)),
+                ))
+            }
+            Err(e) => Ok(Started::Response(
+                HttpResponse::InternalServerError().json(e),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::audit::AuditScope;
+    use crate::constants::{STR_UUID_ADMIN, UUID_ADMIN, UUID_RATELIMIT_POLICY};
+    use crate::modify::ModifyList;
+    use crate::ratelimit::{
+        op_class, OpClass, RateLimit, RateLimitPolicy, RateLimiter, RateLimits,
+        BUCKET_PRUNE_THRESHOLD,
+    };
+    use crate::server::QueryServer;
+    use crate::value::{PartialValue, Value};
+    use actix_web::http::Method;
+    use kanidm_proto::v1::OperationError;
+    use std::collections::BTreeMap;
+    use std::time::{Duration, Instant};
+
+    // Step time without sleeping.
+    fn after(now: Instant, ms: u64) -> Instant {
+        now + Duration::from_millis(ms)
+    }
+
+    fn search_limit(capacity: u32) -> RateLimitPolicy {
+        RateLimitPolicy {
+            search: Some(RateLimit {
+                capacity: capacity,
+                per_second: 1,
+            }),
+            write: None,
+        }
+    }
+
+    #[test]
+    fn test_ratelimit_token_bucket() {
+        let limiter = RateLimiter::new();
+        assert!(limiter
+            .set_limits(RateLimits {
+                server: search_limit(2),
+                accounts: BTreeMap::new(),
+            })
+            .is_ok());
+        let now = Instant::now();
+
+        // The burst is allowed, then we are told to come back later.
+        assert!(limiter.check("a", OpClass::Search, now).is_ok());
+        assert!(limiter.check("a", OpClass::Search, now).is_ok());
+        assert_eq!(
+            limiter.check("a", OpClass::Search, now),
+            Err(OperationError::RateLimited(1))
+        );
+        // Other identities have their own bucket.
+        assert!(limiter.check("b", OpClass::Search, now).is_ok());
+        // Writes are not limited in this policy.
+        assert!(limiter.check("a", OpClass::Write, now).is_ok());
+        // After a second, one token has returned.
+        assert!(limiter
+            .check("a", OpClass::Search, after(now, 1000))
+            .is_ok());
+        assert!(limiter
+            .check("a", OpClass::Search, after(now, 1000))
+            .is_err());
+    }
+
+    #[test]
+    fn test_ratelimit_prune_removed() {
+        let limiter = RateLimiter::new();
+        let mut accounts = BTreeMap::new();
+        accounts.insert("a".to_string(), search_limit(1));
+        assert!(limiter
+            .set_limits(RateLimits {
+                server: search_limit(1),
+                accounts: accounts.clone(),
+            })
+            .is_ok());
+        let now = Instant::now();
+
+        // Enough anonymous callers to prune, each with its bucket empty.
+        (0..=BUCKET_PRUNE_THRESHOLD).for_each(|i| {
+            assert!(limiter
+                .check(format!("addr:{}", i).as_str(), OpClass::Search, now)
+                .is_ok());
+        });
+        assert!(limiter.check("a", OpClass::Search, now).is_ok());
+
+        // Anonymous is no longer limited, so only the bucket of a remains.
+        assert!(limiter
+            .set_limits(RateLimits {
+                server: RateLimitPolicy::default(),
+                accounts: accounts,
+            })
+            .is_ok());
+        assert!(limiter.check("a", OpClass::Search, now).is_err());
+        assert_eq!(limiter.buckets.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_ratelimit_op_class() {
+        vec![
+            "/v1/raw/search",
+            "/v1/raw/search/query",
+            "/v1/raw/psearch",
+            "/v1/raw/explain",
+            "/v1/savedsearch/daily/_search",
+            "/v1/auth",
+            "/v1/admin/verify",
+        ]
+        .into_iter()
+        .for_each(|p| assert_eq!(op_class(&Method::POST, p), OpClass::Search, "{}", p));
+        assert_eq!(op_class(&Method::GET, "/v1/self"), OpClass::Search);
+
+        vec!["/v1/raw/create", "/v1/savedsearch", "/v1/admin/reindex"]
+            .into_iter()
+            .for_each(|p| assert_eq!(op_class(&Method::POST, p), OpClass::Write, "{}", p));
+        assert_eq!(
+            op_class(&Method::PUT, "/v1/savedsearch/daily/_attr/filter"),
+            OpClass::Write
+        );
+    }
+
+    #[test]
+    fn test_ratelimit_reload() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let limiter = RateLimiter::new();
+            let now = Instant::now();
+            {
+                let server_txn = server.read().expect("Failed to begin txn");
+                assert!(limiter.reload(audit, &server_txn).is_ok());
+            }
+            // Nothing is limited until the policy says so.
+            for _ in 0..10 {
+                assert!(limiter.check("addr:::1", OpClass::Search, now).is_ok());
+            }
+
+            let mut server_txn = server.write().expect("Failed to begin txn");
+            assert!(server_txn
+                .internal_modify(
+                    audit,
+                    filter!(f_eq(
+                        "uuid",
+                        PartialValue::new_uuidr(&UUID_RATELIMIT_POLICY)
+                    )),
+                    ModifyList::new_purge_and_set("ratelimit_search", Value::new_utf8s("1:1"))
+                )
+                .is_ok());
+            // Admin may search more often than everyone else.
+            assert!(server_txn
+                .internal_modify(
+                    audit,
+                    filter!(f_eq("uuid", PartialValue::new_uuidr(&UUID_ADMIN))),
+                    ModifyList::new_purge_and_set("ratelimit_search", Value::new_utf8s("3:1"))
+                )
+                .is_ok());
+            assert!(server_txn.commit(audit).is_ok());
+
+            {
+                let server_txn = server.read().expect("Failed to begin txn");
+                assert!(limiter.reload(audit, &server_txn).is_ok());
+            }
+            assert!(limiter.check("addr:::2", OpClass::Search, now).is_ok());
+            assert!(limiter.check("addr:::2", OpClass::Search, now).is_err());
+            for _ in 0..3 {
+                assert!(limiter.check(STR_UUID_ADMIN, OpClass::Search, now).is_ok());
+            }
+            assert!(limiter.check(STR_UUID_ADMIN, OpClass::Search, now).is_err());
+        });
+    }
+}
@@ -0,0 +1,122 @@
+// A change identifier says when, and on which server, an attribute last
+// changed. Ordering them by time then server gives every replica the same
+// answer to "which change is newer", without any of them having to ask the
+// others. The server is its domain uuid, which a copy resets when it's set up
+// to run alongside the original.
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Cid {
+    // Nanoseconds since the epoch.
+    pub ts: u64,
+    pub s_uuid: Uuid,
+}
+
+fn now_nanos() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() * 1_000_000_000 + d.subsec_nanos() as u64)
+        .unwrap_or(0)
+}
+
+impl Cid {
+    // Older than any change we made, for values written before we kept CIDs.
+    pub fn zero() -> Self {
+        Cid {
+            ts: 0,
+            s_uuid: Uuid::nil(),
+        }
+    }
+
+    // A CID for a change on s_uuid now. It must come after every CID we have
+    // already seen, ours or a partner's, else a change made here could lose
+    // to one it was made in reply to. So if our clock is behind, we go one
+    // past the latest instead.
+    pub fn new_after(s_uuid: &Uuid, latest: Option<&Cid>) -> Self {
+        let now = now_nanos();
+        let ts = match latest {
+            Some(l) if l.ts >= now => l.ts + 1,
+            _ => now,
+        };
+        Cid {
+            ts: ts,
+            s_uuid: s_uuid.clone(),
+        }
+    }
+}
+
+// Zero padded, so the strings sort as the CIDs do and the db can find the
+// latest for us.
+impl fmt::Display for Cid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:020}-{}", self.ts, self.s_uuid.to_hyphenated_ref())
+    }
+}
+
+impl FromStr for Cid {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, '-');
+        let ts = parts
+            .next()
+            .filter(|t| t.len() == 20)
+            .and_then(|t| t.parse().ok())
+            .ok_or(())?;
+        let s_uuid = parts
+            .next()
+            .and_then(|u| Uuid::parse_str(u).ok())
+            .ok_or(())?;
+        Ok(Cid {
+            ts: ts,
+            s_uuid: s_uuid,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Cid;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_repl_cid_order() {
+        let a = Uuid::parse_str("00000000-0000-0000-0000-00000000000a").unwrap();
+        let b = Uuid::parse_str("00000000-0000-0000-0000-00000000000b").unwrap();
+
+        let c1 = Cid::new_after(&a, None);
+        assert!(c1 > Cid::zero());
+        // Ties on time go to the server, so every replica agrees.
+        let c2 = Cid {
+            ts: c1.ts,
+            s_uuid: b,
+        };
+        assert!(c2 > c1);
+
+        // A clock behind the latest CID we've seen still moves forward.
+        let future = Cid {
+            ts: c1.ts + 1_000_000_000_000,
+            s_uuid: b,
+        };
+        let c3 = Cid::new_after(&a, Some(&future));
+        assert_eq!(c3.ts, future.ts + 1);
+        assert!(c3 > future);
+
+        // The strings sort the same way.
+        let mut cids = vec![c3, c2, Cid::zero(), c1];
+        let mut strs: Vec<String> = cids.iter().map(|c| c.to_string()).collect();
+        cids.sort();
+        strs.sort();
+        let parsed: Vec<Cid> = strs.iter().map(|s| s.parse().unwrap()).collect();
+        assert_eq!(parsed, cids);
+
+        assert!("1-00000000-0000-0000-0000-00000000000a"
+            .parse::<Cid>()
+            .is_err());
+        assert!("00000000000000000001-nope".parse::<Cid>().is_err());
+    }
+}
@@ -0,0 +1,77 @@
+// Pulls from each partner in turn, every interval, and hands what it supplied
+// to the write actor to merge. A partner's cookie only moves on once what it
+// supplied is committed, so a failed merge is pulled again next time. Cookies
+// are only kept in memory, so after a restart each partner is pulled from the
+// start again, which merges to nothing we don't already have.
+
+use crate::actors::v1_write::QueryServerWriteV1;
+use crate::config::ReplConfig;
+use crate::event::ReplApplyEvent;
+use futures::Future;
+use kanidm_client::KanidmClient;
+use std::thread;
+use std::time::Duration;
+
+fn pull(
+    config: &ReplConfig,
+    client: &KanidmClient,
+    partner: &str,
+    cookie: &mut Option<String>,
+    server: &actix::Addr<QueryServerWriteV1>,
+) {
+    // A fresh session each time, rather than noticing when ours expired.
+    let session = client.new_session();
+    let supplied = session
+        .auth_simple_password(config.account.as_str(), config.password.as_str())
+        .and_then(|_| session.repl_supply(cookie.clone()));
+    let supplied = match supplied {
+        Ok(s) => s,
+        Err(e) => {
+            error!("replication: unable to pull from {} -> {:?}", partner, e);
+            return;
+        }
+    };
+    if supplied.entries.is_empty() {
+        *cookie = Some(supplied.cookie);
+        return;
+    }
+
+    let count = supplied.entries.len();
+    match server
+        .send(ReplApplyEvent::new(partner, supplied.entries))
+        .wait()
+    {
+        Ok(Ok(stats)) => {
+            info!(
+                "replication: merged {} entries from {} -> {:?}",
+                count, partner, stats
+            );
+            if stats.conflicts > 0 {
+                error!(
+                    "replication: {} entries from {} are in conflict, see the audit log",
+                    stats.conflicts, partner
+                );
+            }
+            *cookie = Some(supplied.cookie);
+        }
+        Ok(Err(e)) => error!("replication: unable to merge from {} -> {:?}", partner, e),
+        Err(e) => error!("replication: write actor unavailable -> {:?}", e),
+    }
+}
+
+pub fn start(config: ReplConfig, server: actix::Addr<QueryServerWriteV1>) {
+    thread::spawn(move || {
+        let ca = config.ca.as_ref().map(|s| s.as_str());
+        let mut partners: Vec<_> = config
+            .partners
+            .iter()
+            .map(|p| (p.as_str(), KanidmClient::new(p.as_str(), ca), None))
+            .collect();
+        loop {
+            for (partner, client, cookie) in partners.iter_mut() {
+                pull(&config, client, *partner, cookie, &server);
+            }
+            thread::sleep(Duration::from_secs(config.interval));
+        }
+    });
+}
@@ -0,0 +1,371 @@
+// Multi-master replication. Every attribute of every entry carries the CID of
+// the change that last wrote it, which the backend keeps in its changestate
+// table. Each server periodically asks its partners for the entries changed
+// since it last asked (/v1/repl/supply), and merges them in:
+//
+//  * Per attribute, the newer CID wins. Concurrent changes to different
+//    attributes of an entry both survive, and of the same attribute the later
+//    one is kept everywhere.
+//  * A tombstone is final. One from a partner replaces our live entry, and
+//    nothing a partner sends revives one of ours.
+//  * A merge that would break attribute uniqueness, such as two servers
+//    creating the same name at once, is skipped and logged as a conflict. It's
+//    retried when the partner next changes that entry, so renaming either side
+//    resolves it.
+//
+// Merged attributes keep the partner's CIDs, so when the partner pulls from
+// us in turn it finds nothing newer, and changes don't bounce between us.
+//
+// Entries are merged as the partner's plugins left them, so derived values
+// such as memberof are replicated like any other rather than recomputed.
+//
+// A new replica must start from a restore of an existing one, with its domain
+// uuid then reset. Values written before CIDs were kept have none, and are
+// older than any change, so an independently initialised server would have
+// its own newer copy of every builtin entry.
+
+pub mod cid;
+pub mod consumer;
+
+use crate::be::dbentry::DbEntry;
+use crate::commits::changed_attrs;
+use crate::entry::{Entry, EntryCommitted, EntryValid};
+use crate::repl::cid::Cid;
+use crate::value::PartialValue;
+use kanidm_proto::v1::{OperationError, ReplEntry};
+use serde_json;
+use std::collections::{BTreeMap, BTreeSet};
+use uuid::Uuid;
+
+lazy_static! {
+    static ref PVCLASS_TOMBSTONE: PartialValue = PartialValue::new_class("tombstone");
+}
+
+// The attributes a write txn changed, to be given CIDs at commit. None is the
+// txn's own CID, Some one kept from a partner.
+#[derive(Debug, Default, Clone)]
+pub struct ChangeState {
+    changed: BTreeMap<Uuid, BTreeMap<String, Option<Cid>>>,
+    // Entries gone from the db, whose CIDs go with them.
+    removed: BTreeSet<Uuid>,
+}
+
+impl ChangeState {
+    pub fn new() -> Self {
+        ChangeState::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changed.is_empty() && self.removed.is_empty()
+    }
+
+    pub fn record(
+        &mut self,
+        pre: Option<&Entry<EntryValid, EntryCommitted>>,
+        post: Option<&Entry<EntryValid, EntryCommitted>>,
+    ) {
+        match (pre, post) {
+            (Some(pre), Some(post)) => {
+                let attrs = self
+                    .changed
+                    .entry(post.get_uuid().clone())
+                    .or_insert_with(BTreeMap::new);
+                attrs.extend(changed_attrs(pre, post).into_iter().map(|a| (a, None)));
+            }
+            (None, Some(e)) => {
+                self.removed.remove(e.get_uuid());
+                let attrs = self
+                    .changed
+                    .entry(e.get_uuid().clone())
+                    .or_insert_with(BTreeMap::new);
+                attrs.extend(e.get_ava_names().into_iter().map(|a| (a.to_string(), None)));
+            }
+            (Some(e), None) => {
+                self.changed.remove(e.get_uuid());
+                self.removed.insert(e.get_uuid().clone());
+            }
+            (None, None) => {}
+        }
+    }
+
+    // An attribute merged from a partner keeps the partner's CID.
+    pub fn set(&mut self, uuid: &Uuid, attr: &str, cid: &Cid) {
+        self.changed
+            .entry(uuid.clone())
+            .or_insert_with(BTreeMap::new)
+            .insert(attr.to_string(), Some(cid.clone()));
+    }
+
+    // The latest CID kept from a partner, which the txn's own must come after.
+    pub fn latest(&self) -> Option<&Cid> {
+        self.changed
+            .values()
+            .flat_map(|attrs| attrs.values())
+            .filter_map(|c| c.as_ref())
+            .max()
+    }
+
+    // The CID of each changed attribute, and the entries whose CIDs are to be
+    // dropped.
+    pub fn into_rows(self, txn_cid: &Cid) -> (Vec<(Uuid, String, Cid)>, Vec<Uuid>) {
+        let rows = self
+            .changed
+            .into_iter()
+            .flat_map(|(uuid, attrs)| {
+                attrs
+                    .into_iter()
+                    .map(move |(attr, cid)| (uuid.clone(), attr, cid.unwrap_or(*txn_cid)))
+            })
+            .collect();
+        (rows, self.removed.into_iter().collect())
+    }
+}
+
+// What one side holds of an entry, as far as merging it goes.
+#[derive(Debug)]
+pub struct ReplState {
+    pub tombstone: bool,
+    pub attrs: BTreeSet<String>,
+    pub cids: BTreeMap<String, Cid>,
+}
+
+impl ReplState {
+    pub fn new(e: &Entry<EntryValid, EntryCommitted>, cids: BTreeMap<String, Cid>) -> Self {
+        ReplState {
+            tombstone: e.attribute_value_pres("class", &PVCLASS_TOMBSTONE),
+            attrs: e
+                .get_ava_names()
+                .into_iter()
+                .map(|a| a.to_string())
+                .collect(),
+            cids: cids,
+        }
+    }
+
+    // An attribute without one was last changed before we kept CIDs.
+    pub fn cid(&self, attr: &str) -> Cid {
+        self.cids.get(attr).cloned().unwrap_or_else(Cid::zero)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Resolution {
+    // Ours is as new as theirs, or is a tombstone.
+    Keep,
+    // We don't have it yet.
+    Create,
+    // These attributes are replaced with theirs, or removed if they have no
+    // values for them.
+    Take(BTreeSet<String>),
+}
+
+pub fn resolve(ours: Option<&ReplState>, theirs: &ReplState) -> Resolution {
+    let ours = match ours {
+        Some(o) => o,
+        None => return Resolution::Create,
+    };
+    if ours.tombstone {
+        return Resolution::Keep;
+    }
+    let take: BTreeSet<String> = if theirs.tombstone {
+        ours.attrs.union(&theirs.attrs).cloned().collect()
+    } else {
+        ours.attrs
+            .iter()
+            .chain(theirs.attrs.iter())
+            .chain(theirs.cids.keys())
+            .filter(|a| theirs.cid(a) > ours.cid(a))
+            .cloned()
+            .collect()
+    };
+    if take.is_empty() {
+        Resolution::Keep
+    } else {
+        Resolution::Take(take)
+    }
+}
+
+// What became of one entry from a partner.
+#[derive(Debug, PartialEq)]
+pub enum Merged {
+    Applied,
+    Unchanged,
+    // Left as it was, see above.
+    Conflict,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct MergeStats {
+    pub applied: usize,
+    pub unchanged: usize,
+    pub conflicts: usize,
+}
+
+impl MergeStats {
+    pub fn add(&mut self, m: Merged) {
+        match m {
+            Merged::Applied => self.applied += 1,
+            Merged::Unchanged => self.unchanged += 1,
+            Merged::Conflict => self.conflicts += 1,
+        }
+    }
+}
+
+pub fn to_proto(
+    e: &Entry<EntryValid, EntryCommitted>,
+    cids: &BTreeMap<String, Cid>,
+) -> Result<ReplEntry, OperationError> {
+    let entry = serde_json::to_string(&e.into_dbentry()).map_err(|e| {
+        error!("unable to serialise entry for replication -> {:?}", e);
+        OperationError::SerdeJsonError
+    })?;
+    Ok(ReplEntry {
+        entry: entry,
+        cids: cids
+            .iter()
+            .map(|(attr, cid)| (attr.clone(), cid.to_string()))
+            .collect(),
+    })
+}
+
+// The entry isn't checked against our schema here, that's up to the merge.
+pub fn from_proto(
+    re: &ReplEntry,
+) -> Result<(Entry<EntryValid, EntryCommitted>, BTreeMap<String, Cid>), OperationError> {
+    let db_e: DbEntry =
+        serde_json::from_str(re.entry.as_str()).map_err(|_| OperationError::SerdeJsonError)?;
+    let e = Entry::from_dbentry(db_e, 0).map_err(|_| OperationError::InvalidEntryState)?;
+    let cids: Result<BTreeMap<_, _>, _> = re
+        .cids
+        .iter()
+        .map(|(attr, cid)| cid.parse().map(|c| (attr.clone(), c)))
+        .collect();
+    let cids = cids.map_err(|_| OperationError::InvalidEntryState)?;
+    Ok((e, cids))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{from_proto, resolve, to_proto, ChangeState, ReplState, Resolution};
+    use crate::entry::{Entry, EntryInvalid, EntryNew};
+    use crate::repl::cid::Cid;
+    use crate::value::Value;
+    use std::collections::{BTreeMap, BTreeSet};
+    use uuid::Uuid;
+
+    fn state(tombstone: bool, attrs: &[&str], cids: &[(&str, u64)]) -> ReplState {
+        let s_uuid = Uuid::new_v4();
+        ReplState {
+            tombstone: tombstone,
+            attrs: attrs.iter().map(|a| a.to_string()).collect(),
+            cids: cids
+                .iter()
+                .map(|(a, ts)| {
+                    (
+                        a.to_string(),
+                        Cid {
+                            ts: *ts,
+                            s_uuid: s_uuid,
+                        },
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    fn attrs(a: &[&str]) -> BTreeSet<String> {
+        a.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn test_repl_resolve() {
+        let theirs = state(false, &["uuid", "name"], &[("name", 10)]);
+        assert_eq!(resolve(None, &theirs), Resolution::Create);
+
+        // Only what they changed last. Their removal of description is newer
+        // than ours, while our displayname is newer than theirs.
+        let ours = state(
+            false,
+            &["uuid", "name", "description", "displayname"],
+            &[("name", 5), ("description", 2), ("displayname", 20)],
+        );
+        let theirs = state(
+            false,
+            &["uuid", "name", "displayname", "mail"],
+            &[("name", 10), ("description", 3), ("displayname", 15)],
+        );
+        assert_eq!(
+            resolve(Some(&ours), &theirs),
+            Resolution::Take(attrs(&["name", "description"]))
+        );
+        assert_eq!(resolve(Some(&ours), &ours), Resolution::Keep);
+
+        // Values from before CIDs were kept lose to any change.
+        let old = state(false, &["uuid", "name"], &[]);
+        assert_eq!(
+            resolve(Some(&old), &theirs),
+            Resolution::Take(attrs(&["name", "description", "displayname"]))
+        );
+        assert_eq!(resolve(Some(&theirs), &old), Resolution::Keep);
+
+        // Tombstones win whatever the CIDs, and are never revived.
+        let tomb = state(true, &["uuid", "class"], &[("class", 1)]);
+        assert_eq!(
+            resolve(Some(&ours), &tomb),
+            Resolution::Take(attrs(&[
+                "uuid",
+                "class",
+                "name",
+                "description",
+                "displayname"
+            ]))
+        );
+        assert_eq!(resolve(Some(&tomb), &theirs), Resolution::Keep);
+    }
+
+    #[test]
+    fn test_repl_changestate() {
+        let mut e: Entry<EntryInvalid, EntryNew> = Entry::new();
+        e.add_ava(
+            "uuid",
+            &Value::new_uuids("db237e8a-0079-4b8c-8a56-593b22aa44d1").unwrap(),
+        );
+        e.add_ava("name", &Value::new_iutf8s("testperson"));
+        let pre = unsafe { e.clone().to_valid_committed() };
+        e.add_ava("description", &Value::new_utf8s("d"));
+        let post = unsafe { e.to_valid_committed() };
+        let uuid = post.get_uuid().clone();
+
+        let partner = Cid::new_after(&Uuid::new_v4(), None);
+        let mut cs = ChangeState::new();
+        cs.record(Some(&pre), Some(&post));
+        cs.set(&uuid, "name", &partner);
+        assert_eq!(cs.latest(), Some(&partner));
+
+        let txn_cid = Cid::new_after(&Uuid::new_v4(), cs.latest());
+        let (rows, removed) = cs.into_rows(&txn_cid);
+        assert_eq!(
+            rows,
+            vec![
+                (uuid.clone(), "description".to_string(), txn_cid),
+                (uuid.clone(), "name".to_string(), partner),
+            ]
+        );
+        assert!(removed.is_empty());
+
+        let mut cs = ChangeState::new();
+        cs.record(None, Some(&post));
+        cs.record(Some(&post), None);
+        let (rows, removed) = cs.into_rows(&txn_cid);
+        assert!(rows.is_empty());
+        assert_eq!(removed, vec![uuid]);
+
+        // What goes over the wire comes back the same.
+        let mut cids = BTreeMap::new();
+        cids.insert("name".to_string(), partner);
+        let (e, c) = from_proto(&to_proto(&post, &cids).unwrap()).unwrap();
+        assert_eq!(e.get_ava_names(), post.get_ava_names());
+        assert_eq!(e.get_uuid(), post.get_uuid());
+        assert_eq!(c, cids);
+    }
+}
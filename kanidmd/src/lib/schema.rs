@@ -5,6 +5,7 @@ use crate::value::{IndexType, PartialValue, SyntaxType, Value};
 use kanidm_proto::v1::{ConsistencyError, OperationError, SchemaError};
 
 use std::borrow::Borrow;
+use std::collections::BTreeMap;
 use std::collections::BTreeSet;
 use std::collections::HashMap;
 use uuid::Uuid;
@@ -28,11 +29,16 @@ pub struct SchemaAttribute {
     // Is this ... used?
     // class: Vec<String>,
     pub name: String,
+    // Names this attribute had before it was renamed. They are still
+    // accepted from clients, and entries holding them are rewritten to the
+    // current name in the background.
+    pub aliases: Vec<String>,
     pub uuid: Uuid,
-    // Perhaps later add aliases?
     pub description: String,
     pub multivalue: bool,
     pub unique: bool,
+    // Maintained by the server, and not able to be set by clients.
+    pub operational: bool,
     pub index: Vec<IndexType>,
     pub syntax: SyntaxType,
 }
@@ -89,6 +95,15 @@ impl SchemaAttribute {
                     "missing unique".to_string()
                 ))
         );
+        // Older attribute definitions predate this, so it's optional.
+        let operational = value.get_ava_single_bool("operational").unwrap_or(false);
+        let aliases =
+            try_audit!(
+                audit,
+                value.get_ava_opt_string("attributealias").ok_or(
+                    OperationError::InvalidSchemaState("Invalid attributealias".to_string())
+                )
+            );
         // index vec
         // even if empty, it SHOULD be present ... (is that value to put an empty set?)
         // The get_ava_opt_index handles the optional case for us :)
@@ -115,10 +130,12 @@ impl SchemaAttribute {
 
         Ok(SchemaAttribute {
             name: name,
+            aliases: aliases,
             uuid: uuid,
             description: description,
             multivalue: multivalue,
             unique: unique,
+            operational: operational,
             index: index,
             syntax: syntax,
         })
@@ -222,6 +239,14 @@ impl SchemaAttribute {
         }
     }
 
+    fn validate_uint32(&self, v: &Value) -> Result<(), SchemaError> {
+        if v.is_uint32() {
+            Ok(())
+        } else {
+            Err(SchemaError::InvalidAttributeSyntax)
+        }
+    }
+
     fn validate_sshkey(&self, v: &Value) -> Result<(), SchemaError> {
         if v.is_sshkey() {
             Ok(())
@@ -246,6 +271,7 @@ impl SchemaAttribute {
             SyntaxType::CREDENTIAL => v.is_credential(),
             SyntaxType::RADIUS_UTF8STRING => v.is_radius_string(),
             SyntaxType::SSHKEY => v.is_sshkey(),
+            SyntaxType::UINT32 => v.is_uint32(),
         };
         if r {
             Ok(())
@@ -354,6 +380,13 @@ impl SchemaAttribute {
                     acc
                 }
             }),
+            SyntaxType::UINT32 => ava.iter().fold(Ok(()), |acc, v| {
+                if acc.is_ok() {
+                    self.validate_uint32(v)
+                } else {
+                    acc
+                }
+            }),
         }
     }
 }
@@ -449,6 +482,12 @@ pub struct SchemaInner {
     classes: HashMap<String, SchemaClass>,
     attributes: HashMap<String, SchemaAttribute>,
     idxmeta: BTreeSet<(String, IndexType)>,
+    // alias -> current name.
+    aliases: HashMap<String, String>,
+    // Aliases that entries may still be stored under, with the id the
+    // migration has reached. Everything starts pending after a restart, and
+    // the first pass just confirms there is nothing left.
+    pending_aliases: BTreeMap<String, u64>,
 }
 
 pub trait SchemaTransaction {
@@ -464,17 +503,42 @@ pub trait SchemaTransaction {
 
     fn normalise_attr_name(&self, an: &str) -> String {
         // Will duplicate.
-        an.to_lowercase()
+        let an = an.to_lowercase();
+        match self.get_inner().aliases.get(&an) {
+            Some(name) => name.clone(),
+            None => an,
+        }
     }
 
     fn normalise_attr_if_exists(&self, an: &str) -> Option<String> {
-        if self.get_attributes().contains_key(an) {
-            Some(self.normalise_attr_name(an))
+        let an = self.normalise_attr_name(an);
+        if self.get_attributes().contains_key(&an) {
+            Some(an)
         } else {
             None
         }
     }
 
+    // The aliases of attr that entries may still be stored under.
+    fn get_pending_aliases(&self, attr: &str) -> Vec<&String> {
+        let inner = self.get_inner();
+        inner
+            .pending_aliases
+            .keys()
+            .filter(|a| inner.aliases.get(*a).map(|n| n == attr).unwrap_or(false))
+            .collect()
+    }
+
+    // Each pending alias, with its current name and where its migration is up to.
+    fn get_alias_migrations(&self) -> Vec<(String, String, u64)> {
+        let inner = self.get_inner();
+        inner
+            .pending_aliases
+            .iter()
+            .filter_map(|(a, after)| inner.aliases.get(a).map(|n| (a.clone(), n.clone(), *after)))
+            .collect()
+    }
+
     fn get_attributes_unique(&self) -> Vec<String> {
         // This could be improved by caching this set on schema reload!
         self.get_attributes()
@@ -521,6 +585,8 @@ impl SchemaInner {
                 classes: HashMap::new(),
                 attributes: HashMap::new(),
                 idxmeta: BTreeSet::new(),
+                aliases: HashMap::new(),
+                pending_aliases: BTreeMap::new(),
             };
             // Bootstrap in definitions of our own schema types
             // First, add all the needed core attributes for schema parsing
@@ -528,11 +594,13 @@ impl SchemaInner {
                 String::from("class"),
                 SchemaAttribute {
                     name: String::from("class"),
+                    aliases: vec![],
                     uuid: Uuid::parse_str(UUID_SCHEMA_ATTR_CLASS)
                         .expect("unable to parse static uuid"),
                     description: String::from("The set of classes defining an object"),
                     multivalue: true,
                     unique: false,
+                    operational: false,
                     index: vec![IndexType::EQUALITY],
                     syntax: SyntaxType::UTF8STRING_INSENSITIVE,
                 },
@@ -541,6 +609,7 @@ impl SchemaInner {
                 String::from("uuid"),
                 SchemaAttribute {
                     name: String::from("uuid"),
+                    aliases: vec![],
                     uuid: Uuid::parse_str(UUID_SCHEMA_ATTR_UUID)
                         .expect("unable to parse static uuid"),
                     description: String::from("The universal unique id of the object"),
@@ -548,6 +617,7 @@ impl SchemaInner {
                     // Uniqueness is handled by base.rs, not attrunique here due to
                     // needing to check recycled objects too.
                     unique: false,
+                    operational: false,
                     index: vec![IndexType::EQUALITY],
                     syntax: SyntaxType::UUID,
                 },
@@ -556,11 +626,13 @@ impl SchemaInner {
                 String::from("name"),
                 SchemaAttribute {
                     name: String::from("name"),
+                    aliases: vec![],
                     uuid: Uuid::parse_str(UUID_SCHEMA_ATTR_NAME)
                         .expect("unable to parse static uuid"),
                     description: String::from("The shortform name of an object"),
                     multivalue: false,
                     unique: true,
+                    operational: false,
                     index: vec![IndexType::EQUALITY],
                     syntax: SyntaxType::UTF8STRING_INSENSITIVE,
                 },
@@ -569,11 +641,13 @@ impl SchemaInner {
                 String::from("attributename"),
                 SchemaAttribute {
                     name: String::from("attributename"),
+                    aliases: vec![],
                     uuid: Uuid::parse_str(UUID_SCHEMA_ATTR_ATTRIBUTENAME)
                         .expect("unable to parse static uuid"),
                     description: String::from("The name of a schema attribute"),
                     multivalue: false,
                     unique: true,
+                    operational: false,
                     index: vec![IndexType::EQUALITY],
                     syntax: SyntaxType::UTF8STRING_INSENSITIVE,
                 },
@@ -582,11 +656,13 @@ impl SchemaInner {
                 String::from("classname"),
                 SchemaAttribute {
                     name: String::from("classname"),
+                    aliases: vec![],
                     uuid: Uuid::parse_str(UUID_SCHEMA_ATTR_CLASSNAME)
                         .expect("unable to parse static uuid"),
                     description: String::from("The name of a schema class"),
                     multivalue: false,
                     unique: true,
+                    operational: false,
                     index: vec![IndexType::EQUALITY],
                     syntax: SyntaxType::UTF8STRING_INSENSITIVE,
                 },
@@ -595,30 +671,36 @@ impl SchemaInner {
                 String::from("description"),
                 SchemaAttribute {
                     name: String::from("description"),
+                    aliases: vec![],
                     uuid: Uuid::parse_str(UUID_SCHEMA_ATTR_DESCRIPTION)
                         .expect("unable to parse static uuid"),
                     description: String::from("A description of an attribute, object or class"),
                     multivalue: true,
                     unique: false,
+                    operational: false,
                     index: vec![],
                     syntax: SyntaxType::UTF8STRING,
                 },
             );
             s.attributes.insert(String::from("multivalue"), SchemaAttribute {
                 name: String::from("multivalue"),
+                aliases: vec![],
                 uuid: Uuid::parse_str(UUID_SCHEMA_ATTR_MULTIVALUE).expect("unable to parse static uuid"),
                 description: String::from("If true, this attribute is able to store multiple values rather than just a single value."),
                 multivalue: false,
                 unique: false,
+                operational: false,
                 index: vec![],
                 syntax: SyntaxType::BOOLEAN,
             });
             s.attributes.insert(String::from("unique"), SchemaAttribute {
                 name: String::from("unique"),
+                aliases: vec![],
                 uuid: Uuid::parse_str(UUID_SCHEMA_ATTR_UNIQUE).expect("unable to parse static uuid"),
                 description: String::from("If true, this attribute must store a unique value through out the database."),
                 multivalue: false,
                 unique: false,
+                operational: false,
                 index: vec![],
                 syntax: SyntaxType::BOOLEAN,
             });
@@ -626,6 +708,7 @@ impl SchemaInner {
                 String::from("index"),
                 SchemaAttribute {
                     name: String::from("index"),
+                    aliases: vec![],
                     uuid: Uuid::parse_str(UUID_SCHEMA_ATTR_INDEX)
                         .expect("unable to parse static uuid"),
                     description: String::from(
@@ -633,6 +716,7 @@ impl SchemaInner {
                     ),
                     multivalue: true,
                     unique: false,
+                    operational: false,
                     index: vec![],
                     syntax: SyntaxType::INDEX_ID,
                 },
@@ -641,6 +725,7 @@ impl SchemaInner {
                 String::from("syntax"),
                 SchemaAttribute {
                     name: String::from("syntax"),
+                    aliases: vec![],
                     uuid: Uuid::parse_str(UUID_SCHEMA_ATTR_SYNTAX)
                         .expect("unable to parse static uuid"),
                     description: String::from(
@@ -648,6 +733,7 @@ impl SchemaInner {
                     ),
                     multivalue: false,
                     unique: false,
+                    operational: false,
                     index: vec![IndexType::EQUALITY],
                     syntax: SyntaxType::SYNTAX_ID,
                 },
@@ -656,6 +742,7 @@ impl SchemaInner {
                 String::from("systemmay"),
                 SchemaAttribute {
                     name: String::from("systemmay"),
+                    aliases: vec![],
                     uuid: Uuid::parse_str(UUID_SCHEMA_ATTR_SYSTEMMAY)
                         .expect("unable to parse static uuid"),
                     description: String::from(
@@ -663,6 +750,7 @@ impl SchemaInner {
                     ),
                     multivalue: true,
                     unique: false,
+                    operational: false,
                     index: vec![],
                     syntax: SyntaxType::UTF8STRING_INSENSITIVE,
                 },
@@ -671,6 +759,7 @@ impl SchemaInner {
                 String::from("may"),
                 SchemaAttribute {
                     name: String::from("may"),
+                    aliases: vec![],
                     uuid: Uuid::parse_str(UUID_SCHEMA_ATTR_MAY)
                         .expect("unable to parse static uuid"),
                     description: String::from(
@@ -678,6 +767,7 @@ impl SchemaInner {
                     ),
                     multivalue: true,
                     unique: false,
+                    operational: false,
                     index: vec![],
                     syntax: SyntaxType::UTF8STRING_INSENSITIVE,
                 },
@@ -686,6 +776,7 @@ impl SchemaInner {
                 String::from("systemmust"),
                 SchemaAttribute {
                     name: String::from("systemmust"),
+                    aliases: vec![],
                     uuid: Uuid::parse_str(UUID_SCHEMA_ATTR_SYSTEMMUST)
                         .expect("unable to parse static uuid"),
                     description: String::from(
@@ -693,6 +784,7 @@ impl SchemaInner {
                     ),
                     multivalue: true,
                     unique: false,
+                    operational: false,
                     index: vec![],
                     syntax: SyntaxType::UTF8STRING_INSENSITIVE,
                 },
@@ -701,6 +793,7 @@ impl SchemaInner {
                 String::from("must"),
                 SchemaAttribute {
                     name: String::from("must"),
+                    aliases: vec![],
                     uuid: Uuid::parse_str(UUID_SCHEMA_ATTR_MUST)
                         .expect("unable to parse static uuid"),
                     description: String::from(
@@ -708,6 +801,7 @@ impl SchemaInner {
                     ),
                     multivalue: true,
                     unique: false,
+                    operational: false,
                     index: vec![],
                     syntax: SyntaxType::UTF8STRING_INSENSITIVE,
                 },
@@ -718,11 +812,13 @@ impl SchemaInner {
                 String::from("acp_enable"),
                 SchemaAttribute {
                     name: String::from("acp_enable"),
+                    aliases: vec![],
                     uuid: Uuid::parse_str(UUID_SCHEMA_ATTR_ACP_ENABLE)
                         .expect("unable to parse static uuid"),
                     description: String::from("A flag to determine if this ACP is active for application. True is enabled, and enforce. False is checked but not enforced."),
                     multivalue: false,
                 unique: false,
+                operational: false,
                     index: vec![IndexType::EQUALITY],
                     syntax: SyntaxType::BOOLEAN,
                 },
@@ -732,6 +828,7 @@ impl SchemaInner {
                 String::from("acp_receiver"),
                 SchemaAttribute {
                     name: String::from("acp_receiver"),
+                    aliases: vec![],
                     uuid: Uuid::parse_str(UUID_SCHEMA_ATTR_ACP_RECEIVER)
                         .expect("unable to parse static uuid"),
                     description: String::from(
@@ -739,6 +836,7 @@ impl SchemaInner {
                     ),
                     multivalue: false,
                     unique: false,
+                    operational: false,
                     index: vec![IndexType::EQUALITY, IndexType::SUBSTRING],
                     syntax: SyntaxType::JSON_FILTER,
                 },
@@ -747,6 +845,7 @@ impl SchemaInner {
                 String::from("acp_targetscope"),
                 SchemaAttribute {
                     name: String::from("acp_targetscope"),
+                    aliases: vec![],
                     uuid: Uuid::parse_str(UUID_SCHEMA_ATTR_ACP_TARGETSCOPE)
                         .expect("unable to parse static uuid"),
                     description: String::from(
@@ -754,6 +853,7 @@ impl SchemaInner {
                     ),
                     multivalue: false,
                     unique: false,
+                    operational: false,
                     index: vec![IndexType::EQUALITY, IndexType::SUBSTRING],
                     syntax: SyntaxType::JSON_FILTER,
                 },
@@ -762,11 +862,13 @@ impl SchemaInner {
                 String::from("acp_search_attr"),
                 SchemaAttribute {
                     name: String::from("acp_search_attr"),
+                    aliases: vec![],
                     uuid: Uuid::parse_str(UUID_SCHEMA_ATTR_ACP_SEARCH_ATTR)
                         .expect("unable to parse static uuid"),
                     description: String::from("The attributes that may be viewed or searched by the reciever on targetscope."),
                     multivalue: true,
                 unique: false,
+                operational: false,
                     index: vec![IndexType::EQUALITY],
                     syntax: SyntaxType::UTF8STRING_INSENSITIVE,
                 },
@@ -775,6 +877,7 @@ impl SchemaInner {
                 String::from("acp_create_class"),
                 SchemaAttribute {
                     name: String::from("acp_create_class"),
+                    aliases: vec![],
                     uuid: Uuid::parse_str(UUID_SCHEMA_ATTR_ACP_CREATE_CLASS)
                         .expect("unable to parse static uuid"),
                     description: String::from(
@@ -782,6 +885,7 @@ impl SchemaInner {
                     ),
                     multivalue: true,
                     unique: false,
+                    operational: false,
                     index: vec![IndexType::EQUALITY],
                     syntax: SyntaxType::UTF8STRING_INSENSITIVE,
                 },
@@ -790,6 +894,7 @@ impl SchemaInner {
                 String::from("acp_create_attr"),
                 SchemaAttribute {
                     name: String::from("acp_create_attr"),
+                    aliases: vec![],
                     uuid: Uuid::parse_str(UUID_SCHEMA_ATTR_ACP_CREATE_ATTR)
                         .expect("unable to parse static uuid"),
                     description: String::from(
@@ -797,6 +902,7 @@ impl SchemaInner {
                     ),
                     multivalue: true,
                     unique: false,
+                    operational: false,
                     index: vec![IndexType::EQUALITY],
                     syntax: SyntaxType::UTF8STRING_INSENSITIVE,
                 },
@@ -806,11 +912,13 @@ impl SchemaInner {
                 String::from("acp_modify_removedattr"),
                 SchemaAttribute {
                     name: String::from("acp_modify_removedattr"),
+                    aliases: vec![],
                     uuid: Uuid::parse_str(UUID_SCHEMA_ATTR_ACP_MODIFY_REMOVEDATTR)
                         .expect("unable to parse static uuid"),
                     description: String::from("The set of attribute types that could be removed or purged in a modification."),
                     multivalue: true,
                 unique: false,
+                operational: false,
                     index: vec![IndexType::EQUALITY],
                     syntax: SyntaxType::UTF8STRING_INSENSITIVE,
                 },
@@ -819,11 +927,13 @@ impl SchemaInner {
                 String::from("acp_modify_presentattr"),
                 SchemaAttribute {
                     name: String::from("acp_modify_presentattr"),
+                    aliases: vec![],
                     uuid: Uuid::parse_str(UUID_SCHEMA_ATTR_ACP_MODIFY_PRESENTATTR)
                         .expect("unable to parse static uuid"),
                     description: String::from("The set of attribute types that could be added or asserted in a modification."),
                     multivalue: true,
                 unique: false,
+                operational: false,
                     index: vec![IndexType::EQUALITY],
                     syntax: SyntaxType::UTF8STRING_INSENSITIVE,
                 },
@@ -832,11 +942,13 @@ impl SchemaInner {
                 String::from("acp_modify_class"),
                 SchemaAttribute {
                     name: String::from("acp_modify_class"),
+                    aliases: vec![],
                     uuid: Uuid::parse_str(UUID_SCHEMA_ATTR_ACP_MODIFY_CLASS)
                         .expect("unable to parse static uuid"),
                     description: String::from("The set of class values that could be asserted or added to an entry. Only applies to modify::present operations on class."),
                     multivalue: true,
                 unique: false,
+                operational: false,
                     index: vec![IndexType::EQUALITY],
                     syntax: SyntaxType::UTF8STRING_INSENSITIVE,
                 },
@@ -846,11 +958,13 @@ impl SchemaInner {
                 String::from("memberof"),
                 SchemaAttribute {
                     name: String::from("memberof"),
+                    aliases: vec![],
                     uuid: Uuid::parse_str(UUID_SCHEMA_ATTR_MEMBEROF)
                         .expect("unable to parse static uuid"),
                     description: String::from("reverse group membership of the object"),
                     multivalue: true,
                     unique: false,
+                    operational: false,
                     index: vec![IndexType::EQUALITY],
                     syntax: SyntaxType::REFERENCE_UUID,
                 },
@@ -859,11 +973,13 @@ impl SchemaInner {
                 String::from("directmemberof"),
                 SchemaAttribute {
                     name: String::from("directmemberof"),
+                    aliases: vec![],
                     uuid: Uuid::parse_str(UUID_SCHEMA_ATTR_DIRECTMEMBEROF)
                         .expect("unable to parse static uuid"),
                     description: String::from("reverse direct group membership of the object"),
                     multivalue: true,
                     unique: false,
+                    operational: false,
                     index: vec![IndexType::EQUALITY],
                     syntax: SyntaxType::REFERENCE_UUID,
                 },
@@ -872,11 +988,13 @@ impl SchemaInner {
                 String::from("member"),
                 SchemaAttribute {
                     name: String::from("member"),
+                    aliases: vec![],
                     uuid: Uuid::parse_str(UUID_SCHEMA_ATTR_MEMBER)
                         .expect("unable to parse static uuid"),
                     description: String::from("List of members of the group"),
                     multivalue: true,
                     unique: false,
+                    operational: false,
                     index: vec![IndexType::EQUALITY],
                     syntax: SyntaxType::REFERENCE_UUID,
                 },
@@ -886,6 +1004,7 @@ impl SchemaInner {
                 String::from("version"),
                 SchemaAttribute {
                     name: String::from("version"),
+                    aliases: vec![],
                     uuid: Uuid::parse_str(UUID_SCHEMA_ATTR_VERSION)
                         .expect("unable to parse static uuid"),
                     description: String::from(
@@ -893,6 +1012,7 @@ impl SchemaInner {
                     ),
                     multivalue: false,
                     unique: false,
+                    operational: false,
                     index: vec![IndexType::EQUALITY],
                     syntax: SyntaxType::UTF8STRING_INSENSITIVE,
                 },
@@ -902,15 +1022,118 @@ impl SchemaInner {
                 String::from("domain"),
                 SchemaAttribute {
                     name: String::from("domain"),
+                    aliases: vec![],
                     uuid: Uuid::parse_str(UUID_SCHEMA_ATTR_DOMAIN)
                         .expect("unable to parse static uuid"),
                     description: String::from("A DNS Domain name entry."),
                     multivalue: true,
                     unique: false,
+                    operational: false,
                     index: vec![IndexType::EQUALITY],
                     syntax: SyntaxType::UTF8STRING_INSENSITIVE,
                 },
             );
+            s.attributes.insert(String::from("operational"), SchemaAttribute {
+                name: String::from("operational"),
+                aliases: vec![],
+                uuid: Uuid::parse_str(UUID_SCHEMA_ATTR_OPERATIONAL).expect("unable to parse static uuid"),
+                description: String::from("If true, this attribute is maintained by the server and may not be set by clients."),
+                multivalue: false,
+                unique: false,
+                operational: false,
+                index: vec![],
+                syntax: SyntaxType::BOOLEAN,
+            });
+            // Operational attributes, stamped by the server on create and modify.
+            // Timestamps are generalizedtime (YYYYmmddHHMMSSZ) so they sort as strings.
+            s.attributes.insert(
+                String::from("createtimestamp"),
+                SchemaAttribute {
+                    name: String::from("createtimestamp"),
+                    aliases: vec![],
+                    uuid: Uuid::parse_str(UUID_SCHEMA_ATTR_CREATETIMESTAMP)
+                        .expect("unable to parse static uuid"),
+                    description: String::from("The time this entry was created."),
+                    multivalue: false,
+                    unique: false,
+                    operational: true,
+                    index: vec![],
+                    syntax: SyntaxType::UTF8STRING,
+                },
+            );
+            // Ordered, so that sync clients can ask for everything modified
+            // since a point in time.
+            s.attributes.insert(
+                String::from("modifytimestamp"),
+                SchemaAttribute {
+                    name: String::from("modifytimestamp"),
+                    aliases: vec![],
+                    uuid: Uuid::parse_str(UUID_SCHEMA_ATTR_MODIFYTIMESTAMP)
+                        .expect("unable to parse static uuid"),
+                    description: String::from("The time this entry was last changed."),
+                    multivalue: false,
+                    unique: false,
+                    operational: true,
+                    index: vec![
+                        IndexType::EQUALITY,
+                        IndexType::PRESENCE,
+                        IndexType::ORDERING,
+                    ],
+                    syntax: SyntaxType::UTF8STRING,
+                },
+            );
+            s.attributes.insert(
+                String::from("creatorsname"),
+                SchemaAttribute {
+                    name: String::from("creatorsname"),
+                    aliases: vec![],
+                    uuid: Uuid::parse_str(UUID_SCHEMA_ATTR_CREATORSNAME)
+                        .expect("unable to parse static uuid"),
+                    description: String::from(
+                        "The uuid of the account that created this entry. Absent if created internally.",
+                    ),
+                    multivalue: false,
+                    unique: false,
+                    operational: true,
+                    index: vec![],
+                    syntax: SyntaxType::UUID,
+                },
+            );
+            s.attributes.insert(
+                String::from("access_journal"),
+                SchemaAttribute {
+                    name: String::from("access_journal"),
+                    aliases: vec![],
+                    uuid: Uuid::parse_str(UUID_SCHEMA_ATTR_ACCESS_JOURNAL)
+                        .expect("unable to parse static uuid"),
+                    description: String::from(
+                        "If true, every read of this entry is recorded in the access journal.",
+                    ),
+                    multivalue: false,
+                    unique: false,
+                    operational: false,
+                    index: vec![],
+                    syntax: SyntaxType::BOOLEAN,
+                },
+            );
+
+            s.attributes.insert(
+                String::from("attributealias"),
+                SchemaAttribute {
+                    name: String::from("attributealias"),
+                    aliases: vec![],
+                    uuid: Uuid::parse_str(UUID_SCHEMA_ATTR_ATTRIBUTEALIAS)
+                        .expect("unable to parse static uuid"),
+                    description: String::from(
+                        "Previous names of an attribute, accepted until every entry is migrated.",
+                    ),
+                    multivalue: true,
+                    unique: true,
+                    operational: false,
+                    index: vec![],
+                    syntax: SyntaxType::UTF8STRING_INSENSITIVE,
+                },
+            );
 
             s.classes.insert(
                 String::from("attributetype"),
@@ -919,7 +1142,11 @@ impl SchemaInner {
                     uuid: Uuid::parse_str(UUID_SCHEMA_CLASS_ATTRIBUTETYPE)
                         .expect("unable to parse static uuid"),
                     description: String::from("Definition of a schema attribute"),
-                    systemmay: vec![String::from("index")],
+                    systemmay: vec![
+                        String::from("index"),
+                        String::from("operational"),
+                        String::from("attributealias"),
+                    ],
                     may: vec![],
                     systemmust: vec![
                         String::from("class"),
@@ -963,7 +1190,14 @@ impl SchemaInner {
                     description: String::from(
                         "A system created class that all objects must contain",
                     ),
-                    systemmay: vec![String::from("description"), String::from("name")],
+                    systemmay: vec![
+                        String::from("description"),
+                        String::from("name"),
+                        String::from("createtimestamp"),
+                        String::from("modifytimestamp"),
+                        String::from("creatorsname"),
+                        String::from("access_journal"),
+                    ],
                     may: vec![],
                     systemmust: vec![String::from("class"), String::from("uuid")],
                     must: vec![],
@@ -1280,9 +1514,54 @@ impl<'a> SchemaWriteTransaction<'a> {
         // Now update the idxmeta
         self.inner.reload_idxmeta();
 
+        // An alias can't shadow an attribute, or mean two things.
+        let mut aliases = HashMap::new();
+        for a in self.inner.attributes.values() {
+            for alias in a.aliases.iter() {
+                if self.inner.attributes.contains_key(alias)
+                    || aliases.insert(alias.clone(), a.name.clone()).is_some()
+                {
+                    return Err(OperationError::InvalidSchemaState(format!(
+                        "attribute alias {} is already in use",
+                        alias
+                    )));
+                }
+            }
+        }
+        // New aliases (or ones that now point elsewhere) need their entries
+        // migrated, and those already under way carry on where they were.
+        let pending: BTreeMap<_, _> = aliases
+            .iter()
+            .filter_map(|(alias, name)| {
+                if self.inner.aliases.get(alias) != Some(name) {
+                    Some((alias.clone(), 0))
+                } else {
+                    self.inner
+                        .pending_aliases
+                        .get(alias)
+                        .map(|after| (alias.clone(), *after))
+                }
+            })
+            .collect();
+        self.inner.aliases = aliases;
+        self.inner.pending_aliases = pending;
+
         Ok(())
     }
 
+    // Record how far the migration of an alias has got, or with None that
+    // no entry holds it any more.
+    pub fn set_alias_migration(&mut self, alias: &str, after: Option<u64>) {
+        match after {
+            Some(after) => {
+                self.inner.pending_aliases.insert(alias.to_string(), after);
+            }
+            None => {
+                self.inner.pending_aliases.remove(alias);
+            }
+        }
+    }
+
     pub fn update_classes(
         &mut self,
         attributetypes: Vec<SchemaClass>,
@@ -1664,10 +1943,12 @@ mod tests {
         let single_value_string = SchemaAttribute {
             // class: vec![String::from("attributetype")],
             name: String::from("single_value"),
+            aliases: vec![],
             uuid: Uuid::new_v4(),
             description: String::from(""),
             multivalue: false,
             unique: false,
+            operational: false,
             index: vec![IndexType::EQUALITY],
             syntax: SyntaxType::UTF8STRING_INSENSITIVE,
         };
@@ -1686,10 +1967,12 @@ mod tests {
         let multi_value_string = SchemaAttribute {
             // class: vec![String::from("attributetype")],
             name: String::from("mv_string"),
+            aliases: vec![],
             uuid: Uuid::new_v4(),
             description: String::from(""),
             multivalue: true,
             unique: false,
+            operational: false,
             index: vec![IndexType::EQUALITY],
             syntax: SyntaxType::UTF8STRING,
         };
@@ -1703,10 +1986,12 @@ mod tests {
         let multi_value_boolean = SchemaAttribute {
             // class: vec![String::from("attributetype")],
             name: String::from("mv_bool"),
+            aliases: vec![],
             uuid: Uuid::new_v4(),
             description: String::from(""),
             multivalue: true,
             unique: false,
+            operational: false,
             index: vec![IndexType::EQUALITY],
             syntax: SyntaxType::BOOLEAN,
         };
@@ -1726,10 +2011,12 @@ mod tests {
         let single_value_syntax = SchemaAttribute {
             // class: vec![String::from("attributetype")],
             name: String::from("sv_syntax"),
+            aliases: vec![],
             uuid: Uuid::new_v4(),
             description: String::from(""),
             multivalue: false,
             unique: false,
+            operational: false,
             index: vec![IndexType::EQUALITY],
             syntax: SyntaxType::SYNTAX_ID,
         };
@@ -1744,10 +2031,12 @@ mod tests {
         let single_value_index = SchemaAttribute {
             // class: vec![String::from("attributetype")],
             name: String::from("sv_index"),
+            aliases: vec![],
             uuid: Uuid::new_v4(),
             description: String::from(""),
             multivalue: false,
             unique: false,
+            operational: false,
             index: vec![IndexType::EQUALITY],
             syntax: SyntaxType::INDEX_ID,
         };
@@ -2097,6 +2386,72 @@ mod tests {
         println!("{}", audit);
     }
 
+    #[test]
+    fn test_schema_attribute_alias() {
+        let mut audit = AuditScope::new("test_schema_attribute_alias");
+        let schema_outer = Schema::new(&mut audit).expect("failed to create schema");
+        let mut schema = schema_outer.write();
+
+        let mut attrs: Vec<_> = schema.get_attributes().values().cloned().collect();
+        attrs.push(SchemaAttribute {
+            name: String::from("surname"),
+            aliases: vec![String::from("sn")],
+            uuid: Uuid::new_v4(),
+            description: String::from(""),
+            multivalue: false,
+            unique: false,
+            operational: false,
+            index: vec![IndexType::EQUALITY],
+            syntax: SyntaxType::UTF8STRING,
+        });
+        assert!(schema.update_attributes(attrs.clone()).is_ok());
+
+        assert_eq!(schema.normalise_attr_name("SN"), "surname");
+        assert_eq!(
+            schema.normalise_attr_if_exists("sn"),
+            Some("surname".to_string())
+        );
+        let f = filter_all!(f_eq("sn", PartialValue::new_utf8s("smith")));
+        assert_eq!(
+            f.validate(&schema),
+            Ok(unsafe { filter_valid!(f_eq("surname", PartialValue::new_utf8s("smith"))) })
+        );
+        // The alias isn't indexed, only the attribute is.
+        assert!(!schema
+            .get_idxmeta()
+            .contains(&("sn".to_string(), IndexType::EQUALITY)));
+
+        // A new alias is pending until its migration says otherwise, and a
+        // reload keeps that state.
+        assert_eq!(schema.get_pending_aliases("surname"), vec!["sn"]);
+        schema.set_alias_migration("sn", Some(10));
+        assert!(schema.update_attributes(attrs.clone()).is_ok());
+        assert_eq!(
+            schema.get_alias_migrations(),
+            vec![("sn".to_string(), "surname".to_string(), 10)]
+        );
+        schema.set_alias_migration("sn", None);
+        assert!(schema.update_attributes(attrs.clone()).is_ok());
+        assert!(schema.get_pending_aliases("surname").is_empty());
+        assert_eq!(schema.normalise_attr_name("sn"), "surname");
+
+        // An alias can't shadow an existing attribute.
+        let mut attrs_bad = attrs.clone();
+        attrs_bad.push(SchemaAttribute {
+            name: String::from("givenname"),
+            aliases: vec![String::from("class")],
+            uuid: Uuid::new_v4(),
+            description: String::from(""),
+            multivalue: false,
+            unique: false,
+            operational: false,
+            index: vec![],
+            syntax: SyntaxType::UTF8STRING,
+        });
+        assert!(schema.update_attributes(attrs_bad).is_err());
+        println!("{}", audit);
+    }
+
     #[test]
     fn test_schema_filter_normalisation() {
         // Test mixed case attr name
@@ -1,33 +1,51 @@
 // This is really only used for long lived, high level types that need clone
 // that otherwise can't be cloned. Think Mutex.
 // use actix::prelude::*;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::mpsc::Receiver;
 use std::sync::Arc;
+use std::time::Duration;
 use uuid::Uuid;
 
 use crate::audit::AuditScope;
-use crate::be::{Backend, BackendReadTransaction, BackendTransaction, BackendWriteTransaction};
+use crate::be::{
+    Backend, BackendReadTransaction, BackendTransaction, BackendWriteTransaction, Savepoint,
+    SearchLimits,
+};
+use crate::commits::{CommitBroadcast, CommitEvent};
 
 use crate::access::{
     AccessControlCreate, AccessControlDelete, AccessControlModify, AccessControlSearch,
     AccessControls, AccessControlsReadTransaction, AccessControlsTransaction,
-    AccessControlsWriteTransaction,
+    AccessControlsWriteTransaction, AnonymousPolicy,
 };
 // We use so many, we just import them all ...
 use crate::constants::*;
 use crate::entry::{Entry, EntryCommitted, EntryInvalid, EntryNew, EntryReduced, EntryValid};
 use crate::event::{
-    CreateEvent, DeleteEvent, Event, EventOrigin, ExistsEvent, ModifyEvent, ReviveRecycledEvent,
-    SearchEvent,
+    ChangesEvent, CompareEvent, CreateEvent, DeleteEvent, Event, EventOrigin, ExistsEvent,
+    ModifyEvent, ReplSupplyEvent, ReviveRecycledEvent, SearchEvent,
+};
+use crate::filter::{
+    f_and, f_andnot, f_eq, f_or, Filter, FilterInvalid, FilterValid, FilterValidResolved,
 };
-use crate::filter::{Filter, FilterInvalid, FilterValid};
 use crate::modify::{Modify, ModifyInvalid, ModifyList, ModifyValid};
+use crate::optrack;
 use crate::plugins::Plugins;
+use crate::purgestats::PurgeMetrics;
+use crate::repl::cid::Cid;
+use crate::repl::{self, MergeStats, Merged, ReplState, Resolution};
 use crate::schema::{
     Schema, SchemaAttribute, SchemaClass, SchemaReadTransaction, SchemaTransaction,
     SchemaWriteTransaction,
 };
-use crate::value::{PartialValue, SyntaxType, Value};
-use kanidm_proto::v1::{ConsistencyError, OperationError, SchemaError};
+use crate::value::{IndexType, PartialValue, SyntaxType, Value};
+use kanidm_proto::v1::Filter as ProtoFilter;
+use kanidm_proto::v1::{
+    AttrUsage, Branding, ConsistencyError, DbPoolStats, DeleteImpact, MembershipAction,
+    MembershipRequestRecord, OperationError, ReferenceImpact, ReplEntry, ReportRecord, SchemaError,
+    SearchExplain,
+};
 
 lazy_static! {
     static ref PVCLASS_ATTRIBUTETYPE: PartialValue = PartialValue::new_class("attributetype");
@@ -39,7 +57,120 @@ lazy_static! {
     static ref PVCLASS_ACM: PartialValue = PartialValue::new_class("access_control_modify");
     static ref PVCLASS_ACC: PartialValue = PartialValue::new_class("access_control_create");
     static ref PVCLASS_ACP: PartialValue = PartialValue::new_class("access_control_profile");
+    static ref PVCLASS_ANONYMOUS_POLICY: PartialValue = PartialValue::new_class("anonymous_policy");
+    static ref PVCLASS_GROUP: PartialValue = PartialValue::new_class("group");
+    static ref PVCLASS_SAVEDSEARCH: PartialValue = PartialValue::new_class("savedsearch");
     static ref PVACP_ENABLE_TRUE: PartialValue = PartialValue::new_bool(true);
+    static ref PVACCESS_JOURNAL_TRUE: PartialValue = PartialValue::new_bool(true);
+    static ref PVCLASS_MEMBERSHIPREQUEST: PartialValue =
+        PartialValue::new_class("membershiprequest");
+    static ref PVREQUEST_PENDING: PartialValue = PartialValue::new_iutf8s("pending");
+}
+
+fn membership_action_str(action: &MembershipAction) -> &'static str {
+    match action {
+        MembershipAction::Add => "add",
+        MembershipAction::Remove => "remove",
+    }
+}
+
+fn membership_action(e: &Entry<EntryValid, EntryCommitted>) -> Option<MembershipAction> {
+    match e.get_ava_single_str("request_action") {
+        Some("add") => Some(MembershipAction::Add),
+        Some("remove") => Some(MembershipAction::Remove),
+        _ => None,
+    }
+}
+
+// Whether event is one of approvers, or a member of one. The server itself
+// may approve anything.
+fn may_approve(event: &Event, approvers: &BTreeSet<Uuid>) -> bool {
+    match &event.origin {
+        EventOrigin::Internal => true,
+        EventOrigin::User(e) => {
+            approvers.contains(e.get_uuid())
+                || e.get_ava_reference_uuid("memberof")
+                    .map(|mo| mo.into_iter().any(|u| approvers.contains(u)))
+                    .unwrap_or(false)
+        }
+    }
+}
+
+// Whether any term of f asserts one of values.
+fn proto_filter_names(f: &ProtoFilter, values: &[String]) -> bool {
+    match f {
+        ProtoFilter::Eq(_, v)
+        | ProtoFilter::Sub(_, v)
+        | ProtoFilter::Fuzzy(_, v)
+        | ProtoFilter::Ge(_, v)
+        | ProtoFilter::Le(_, v) => values.contains(v),
+        ProtoFilter::Or(l) | ProtoFilter::And(l) => l.iter().any(|f| proto_filter_names(f, values)),
+        ProtoFilter::AndNot(f) => proto_filter_names(f, values),
+        ProtoFilter::Regex(_, _) | ProtoFilter::Pres(_) | ProtoFilter::SelfUUID => false,
+    }
+}
+
+// Replace each ${param} in v with its value from params. A ${ without a
+// closing } is left as written.
+fn saved_search_value(
+    v: &str,
+    params: &BTreeMap<String, String>,
+) -> Result<String, OperationError> {
+    let mut out = String::with_capacity(v.len());
+    let mut rest = v;
+    while let Some(start) = rest.find("${") {
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => break,
+        };
+        let name = &rest[start + 2..end];
+        let value = params
+            .get(name)
+            .ok_or_else(|| OperationError::SavedSearchParamMissing(name.to_string()))?;
+        out.push_str(&rest[..start]);
+        out.push_str(value.as_str());
+        rest = &rest[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+// The filter of a saved search with params filled in to its values.
+fn saved_search_params(
+    f: &ProtoFilter,
+    params: &BTreeMap<String, String>,
+) -> Result<ProtoFilter, OperationError> {
+    let list = |l: &Vec<ProtoFilter>| -> Result<Vec<ProtoFilter>, OperationError> {
+        l.iter().map(|f| saved_search_params(f, params)).collect()
+    };
+    Ok(match f {
+        ProtoFilter::Eq(a, v) => ProtoFilter::Eq(a.clone(), saved_search_value(v, params)?),
+        ProtoFilter::Sub(a, v) => ProtoFilter::Sub(a.clone(), saved_search_value(v, params)?),
+        ProtoFilter::Fuzzy(a, v) => ProtoFilter::Fuzzy(a.clone(), saved_search_value(v, params)?),
+        ProtoFilter::Ge(a, v) => ProtoFilter::Ge(a.clone(), saved_search_value(v, params)?),
+        ProtoFilter::Le(a, v) => ProtoFilter::Le(a.clone(), saved_search_value(v, params)?),
+        ProtoFilter::Regex(a, v) => ProtoFilter::Regex(a.clone(), saved_search_value(v, params)?),
+        ProtoFilter::Or(l) => ProtoFilter::Or(list(l)?),
+        ProtoFilter::And(l) => ProtoFilter::And(list(l)?),
+        ProtoFilter::AndNot(f) => ProtoFilter::AndNot(Box::new(saved_search_params(f, params)?)),
+        ProtoFilter::Pres(_) | ProtoFilter::SelfUUID => f.clone(),
+    })
+}
+
+// As modifytimestamp is stored, which sorts correctly as a string.
+fn generalized_time(secs: i64) -> String {
+    time::at_utc(time::Timespec::new(secs, 0))
+        .strftime("%Y%m%d%H%M%SZ")
+        .map(|t| t.to_string())
+        .unwrap_or_default()
+}
+
+// Whether e was last changed before cutoff. Those from before we stamped
+// modifytimestamp are old enough by now.
+fn changed_before(e: &Entry<EntryValid, EntryCommitted>, cutoff: &str) -> bool {
+    e.get_ava_single_str("modifytimestamp")
+        .map(|t| t < cutoff)
+        .unwrap_or(true)
 }
 
 // This is the core of the server. It implements all
@@ -55,6 +186,24 @@ pub trait QueryServerTransaction {
     type AccessControlsTransactionType: AccessControlsTransaction;
     fn get_accesscontrols(&self) -> &Self::AccessControlsTransactionType;
 
+    fn get_search_limits(&self) -> &SearchLimits;
+
+    // Administering the server itself is for system admins. This is checked
+    // against the caller's entry as it is now, so someone removed from the
+    // group loses access without waiting for their session to end.
+    fn require_system_admin(
+        &self,
+        au: &mut AuditScope,
+        event: &Event,
+    ) -> Result<(), OperationError> {
+        if event.is_internal() || event.is_memberof(&UUID_SYSTEM_ADMINS) {
+            Ok(())
+        } else {
+            audit_log!(au, "denied, not a member of system_admins");
+            Err(OperationError::AccessDenied)
+        }
+    }
+
     fn search_ext(
         &self,
         au: &mut AuditScope,
@@ -66,7 +215,17 @@ pub trait QueryServerTransaction {
          * the end.
          */
         let entries = self.search(au, se)?;
+        self.search_reduce(au, se, entries)
+    }
 
+    // The attributes of entries that se may read, IE the last step of
+    // search_ext.
+    fn search_reduce(
+        &self,
+        au: &mut AuditScope,
+        se: &SearchEvent,
+        entries: Vec<Entry<EntryValid, EntryCommitted>>,
+    ) -> Result<Vec<Entry<EntryReduced, EntryCommitted>>, OperationError> {
         let mut audit_acp = AuditScope::new("access_control_profiles");
         let access = self.get_accesscontrols();
         let acp_res = access.search_filter_entry_attributes(&mut audit_acp, se, entries);
@@ -74,6 +233,21 @@ pub trait QueryServerTransaction {
         // Log and fail if something went wrong.
         let entries_filtered = try_audit!(au, acp_res);
 
+        // Where an entry was sorted by a value the caller can't read, its
+        // place would say something of that value, so those go last in id
+        // order as if they had none.
+        let entries_filtered = match &se.sort {
+            Some(sort) => {
+                let (mut shown, mut hidden): (Vec<_>, Vec<_>) = entries_filtered
+                    .into_iter()
+                    .partition(|e| e.get_ava(sort.attr.as_str()).is_some());
+                hidden.sort_by_key(|e| e.get_id());
+                shown.append(&mut hidden);
+                shown
+            }
+            None => entries_filtered,
+        };
+
         // This is the final entry set that was reduced.
         Ok(entries_filtered)
     }
@@ -93,11 +267,12 @@ pub trait QueryServerTransaction {
         // This normalises and validates in a single step.
         //
         // NOTE: Filters are validated in event conversion.
+        let limits = self.search_limits(au, se)?;
 
         let schema = self.get_schema();
         let idxmeta = schema.get_idxmeta();
         // Now resolve all references and indexes.
-        let vfr = try_audit!(au, se.filter.resolve(&se.event, Some(&idxmeta)));
+        let vfr = try_audit!(au, se.filter.resolve(&se.event, Some(&idxmeta))).with_aliases(schema);
 
         // NOTE: We currently can't build search plugins due to the inability to hand
         // the QS wr/ro to the plugin trait. However, there shouldn't be a need for search
@@ -106,18 +281,71 @@ pub trait QueryServerTransaction {
         let mut audit_be = AuditScope::new("backend_search");
         let res = self
             .get_be_txn()
-            .search(&mut audit_be, &vfr)
-            .map(|r| r)
-            .map_err(|_| OperationError::Backend);
+            .search_limited(&mut audit_be, &vfr, &limits)
+            .map_err(|e| match e {
+                OperationError::ResourceLimit => e,
+                _ => OperationError::Backend,
+            });
         au.append_scope(audit_be);
 
         let res = try_audit!(au, res);
+        let res = match &se.sort {
+            Some(sort) => sort.sort_entries(res),
+            None => res,
+        };
+        self.search_filter(au, se, res)
+    }
+
+    // Before we do anything, check what anonymous is allowed to do. The
+    // original filter is checked, so that anonymous can't probe values of
+    // attributes it is not allowed to read. This is what the search may cost:
+    // the server's limits, with those set on the account in their place.
+    // Searches the server makes itself are never limited.
+    fn search_limits(
+        &self,
+        au: &mut AuditScope,
+        se: &SearchEvent,
+    ) -> Result<SearchLimits, OperationError> {
+        let account = match &se.event.origin {
+            EventOrigin::Internal => return Ok(SearchLimits::unlimited()),
+            EventOrigin::User(e) => e,
+        };
+        let mut parse = |attr: &str| -> Option<usize> {
+            let v = account.get_ava_single_str(attr)?;
+            match v.parse::<usize>() {
+                Ok(v) => Some(v),
+                Err(_) => {
+                    audit_log!(au, "ignoring invalid {} of {:?}", attr, v);
+                    None
+                }
+            }
+        };
+        let overrides = SearchLimits {
+            max_candidates: parse("limit_search_max_candidates"),
+            max_results: parse("limit_search_max_results"),
+            max_time: parse("limit_search_max_time").map(|ms| Duration::from_millis(ms as u64)),
+        };
+        Ok(self.get_search_limits().with_overrides(&overrides))
+    }
+
+    // What the backend found for se, less what se may not see.
+    fn search_filter(
+        &self,
+        au: &mut AuditScope,
+        se: &SearchEvent,
+        mut res: Vec<Entry<EntryValid, EntryCommitted>>,
+    ) -> Result<Vec<Entry<EntryValid, EntryCommitted>>, OperationError> {
+        let schema = self.get_schema();
+        // Anything not yet migrated off an attribute's old name gets the
+        // current one, so access controls and plugins only see that.
+        res.iter_mut().for_each(|e| e.rename_aliases(schema));
 
         // Apply ACP before we let the plugins "have at it".
         // WARNING; for external searches this is NOT the only
         // ACP application. There is a second application to reduce the
         // attribute set on the entries!
         //
+        optrack::set_phase("access controls");
         let mut audit_acp = AuditScope::new("access_control_profiles");
         let access = self.get_accesscontrols();
         let acp_res = access.search_filter_entries(&mut audit_acp, se, res);
@@ -125,6 +353,21 @@ pub trait QueryServerTransaction {
         au.append_scope(audit_acp);
         let acp_res = try_audit!(au, acp_res);
 
+        // Journal what was returned of entries marked for it. Only reads on
+        // behalf of an identity are, not those the server makes itself.
+        if let Some(identity) = se.event.get_uuid() {
+            let identity = identity.to_string();
+            let operation = optrack::current_operation().unwrap_or("search");
+            acp_res
+                .iter()
+                .filter(|e| e.attribute_equality("access_journal", &PVACCESS_JOURNAL_TRUE))
+                .for_each(|e| {
+                    audit_log!(au, "journaling access to {}", e.get_uuid());
+                    self.get_be_txn()
+                        .note_access(e.get_uuid(), identity.as_str(), operation)
+                });
+        }
+
         Ok(acp_res)
     }
 
@@ -133,7 +376,7 @@ pub trait QueryServerTransaction {
 
         let schema = self.get_schema();
         let idxmeta = schema.get_idxmeta();
-        let vfr = try_audit!(au, ee.filter.resolve(&ee.event, Some(&idxmeta)));
+        let vfr = try_audit!(au, ee.filter.resolve(&ee.event, Some(&idxmeta))).with_aliases(schema);
 
         let res = self
             .get_be_txn()
@@ -144,55 +387,313 @@ pub trait QueryServerTransaction {
         res
     }
 
-    // Should this actually be names_to_uuids and we do batches?
-    //  In the initial design "no", we can always write a batched
-    //  interface later.
-    //
-    // The main question is if we need association between the name and
-    // the request uuid - if we do, we need singular. If we don't, we can
-    // just do the batching.
-    //
-    // Filter conversion likely needs 1:1, due to and/or conversions
-    // but create/mod likely doesn't due to the nature of the attributes.
-    //
-    // In the end, singular is the simple and correct option, so lets do
-    // that first, and we can add batched (and cache!) later.
-    //
-    // Remember, we don't care if the name is invalid, because search
-    // will validate/normalise the filter we construct for us. COOL!
-    fn name_to_uuid(&self, audit: &mut AuditScope, name: &str) -> Result<Uuid, OperationError> {
-        // For now this just constructs a filter and searches, but later
-        // we could actually improve this to contact the backend and do
-        // index searches, completely bypassing id2entry.
+    fn compare(&self, au: &mut AuditScope, ce: &CompareEvent) -> Result<bool, OperationError> {
+        // The target must be visible to the caller, and the asserted attribute
+        // must be one they may search on, otherwise compare could be used to
+        // probe values they can't read. filter_orig carries the attribute, so
+        // search applies exactly the same checks a read would.
+        let se = SearchEvent {
+            event: ce.event.clone(),
+            filter: filter!(f_eq("uuid", PartialValue::new_uuid(ce.target.clone())))
+                .to_ignore_hidden()
+                .validate(self.get_schema())
+                .map_err(|e| OperationError::SchemaViolation(e))?,
+            filter_orig: ce.filter_orig.clone(),
+            attrs: None,
+            sort: None,
+        };
+        let res = self.search(au, &se)?;
+        if res.len() == 0 {
+            audit_log!(
+                au,
+                "compare target {:?} not found or not readable",
+                ce.target
+            );
+            return Err(OperationError::NoMatchingEntries);
+        }
 
-        // construct the filter
-        let filt = filter!(f_eq("name", PartialValue::new_iutf8s(name)));
-        audit_log!(audit, "name_to_uuid: name -> {:?}", name);
+        // Now the assertion itself, which the backend can answer from the
+        // equality index if the attribute has one.
+        let schema = self.get_schema();
+        let idxmeta = schema.get_idxmeta();
+        let vfr = try_audit!(au, ce.filter.resolve(&ce.event, Some(&idxmeta))).with_aliases(schema);
+
+        let mut audit_be = AuditScope::new("backend_compare");
+        let res = self
+            .get_be_txn()
+            .exists(&mut audit_be, &vfr)
+            .map_err(|_| OperationError::Backend);
+        au.append_scope(audit_be);
+        res
+    }
 
-        // Internal search - DO NOT SEARCH TOMBSTONES AND RECYCLE
-        // TODO: Should we just search everything? It's a uuid to name ...
-        let res = match self.internal_search(audit, filt) {
-            Ok(e) => e,
-            Err(e) => return Err(e),
+    // Everything that changed after the client's cookie, so that a copy of the
+    // directory can be kept up to date by polling. Returns the entries the
+    // caller may read, the uuids of deleted entries, and the new cookie.
+    fn changes(
+        &self,
+        au: &mut AuditScope,
+        ce: &ChangesEvent,
+    ) -> Result<(Vec<Entry<EntryReduced, EntryCommitted>>, Vec<String>, i64), OperationError> {
+        if ce.event.is_anonymous() {
+            audit_log!(au, "anonymous may not poll for changes");
+            return Err(OperationError::AccessDenied);
+        }
+
+        // Taken in the same txn as the changes, so nothing can fall between
+        // this poll and the next.
+        let marker = self.get_be_txn().get_change_marker();
+        if ce.since > marker {
+            audit_log!(
+                au,
+                "changes cookie {} is ahead of this server at {}",
+                ce.since,
+                marker
+            );
+            return Err(OperationError::InvalidRequestState);
+        }
+
+        let mut audit_be = AuditScope::new("backend_changes");
+        let res = self.get_be_txn().get_changed_since(&mut audit_be, ce.since);
+        au.append_scope(audit_be);
+        let (changed, mut deleted) = try_audit!(au, res);
+
+        // To a client, recycled and tombstoned entries are gone. We only say
+        // which uuids, so there's nothing in them for access controls to hide.
+        let (gone, live): (Vec<_>, Vec<_>) = changed.into_iter().partition(|e| {
+            e.attribute_value_pres("class", &PVCLASS_RECYCLED)
+                || e.attribute_value_pres("class", &PVCLASS_TOMBSTONE)
+        });
+        deleted.extend(
+            gone.iter()
+                .map(|e| e.get_uuid().to_hyphenated_ref().to_string()),
+        );
+
+        // The rest are checked exactly as if they had been searched for.
+        let f_class = filter!(f_pres("class"))
+            .validate(self.get_schema())
+            .map_err(|e| OperationError::SchemaViolation(e))?;
+        let se = SearchEvent {
+            event: ce.event.clone(),
+            filter: f_class.clone(),
+            filter_orig: f_class,
+            attrs: None,
+            sort: None,
         };
+        let mut audit_acp = AuditScope::new("access_control_profiles");
+        let access = self.get_accesscontrols();
+        let acp_res = access
+            .search_filter_entries(&mut audit_acp, &se, live)
+            .and_then(|e| access.search_filter_entry_attributes(&mut audit_acp, &se, e));
+        au.append_scope(audit_acp);
+        let entries = try_audit!(au, acp_res);
 
-        audit_log!(audit, "name_to_uuid: results -- {:?}", res);
+        Ok((entries, deleted, marker))
+    }
 
-        if res.len() == 0 {
-            // If result len == 0, error no such result
-            return Err(OperationError::NoMatchingEntries);
-        } else if res.len() >= 2 {
-            // if result len >= 2, error, invaid entry state.
-            return Err(OperationError::InvalidDBState);
+    // Everything that changed after a replication partner's cookie, as we
+    // store it and with the CID of each attribute, and the new cookie. This
+    // is the whole of every entry, credentials and all, so only system
+    // admins may ask.
+    fn repl_supply(
+        &self,
+        au: &mut AuditScope,
+        re: &ReplSupplyEvent,
+    ) -> Result<(Vec<ReplEntry>, i64), OperationError> {
+        self.require_system_admin(au, &re.event)?;
+
+        let marker = self.get_be_txn().get_change_marker();
+        if re.since > marker {
+            audit_log!(
+                au,
+                "replication cookie {} is ahead of this server at {}",
+                re.since,
+                marker
+            );
+            return Err(OperationError::InvalidRequestState);
         }
 
-        // error should never be triggered due to the len checks above.
-        let e = res.first().ok_or(OperationError::NoMatchingEntries)?;
-        // Get the uuid from the entry. Again, check it exists, and only one.
-        let uuid_res: Uuid = e.get_uuid().clone();
+        let mut audit_be = AuditScope::new("backend_repl_supply");
+        let res = self.get_be_txn().get_changed_since(&mut audit_be, re.since);
+        au.append_scope(audit_be);
+        // Entries removed outright were purged tombstones, which each server
+        // purges for itself.
+        let (changed, _) = try_audit!(au, res);
 
-        audit_log!(audit, "name_to_uuid: uuid <- {:?}", uuid_res);
+        let entries: Result<Vec<_>, _> = changed
+            .iter()
+            .map(|e| {
+                self.get_be_txn()
+                    .get_changestate(au, e.get_uuid())
+                    .and_then(|cids| repl::to_proto(e, &cids))
+            })
+            .collect();
+        Ok((try_audit!(au, entries), marker))
+    }
+
+    // Explaining a search reports the sizes of candidate sets before access
+    // controls are applied, so it's limited to system admins.
+    fn explain_filter(
+        &self,
+        au: &mut AuditScope,
+        se: &SearchEvent,
+    ) -> Result<Filter<FilterValidResolved>, OperationError> {
+        self.require_system_admin(au, &se.event)?;
+
+        let schema = self.get_schema();
+        let idxmeta = schema.get_idxmeta();
+        let vfr = try_audit!(au, se.filter.resolve(&se.event, Some(&idxmeta))).with_aliases(schema);
+        Ok(vfr)
+    }
+
+    // Explain how the backend would resolve this search, and what it matches.
+    fn explain(
+        &self,
+        au: &mut AuditScope,
+        se: &SearchEvent,
+    ) -> Result<SearchExplain, OperationError> {
+        let vfr = self.explain_filter(au, se)?;
+
+        let mut audit_be = AuditScope::new("backend_explain");
+        let res = self
+            .get_be_txn()
+            .explain(&mut audit_be, &vfr)
+            .map_err(|_| OperationError::Backend);
+        au.append_scope(audit_be);
+        res
+    }
+
+    // What deleting the entries se matches would break, as far as the caller
+    // can see. Auth sessions are the idm server's to count, so are left at 0.
+    fn delete_preview(
+        &self,
+        au: &mut AuditScope,
+        se: &SearchEvent,
+    ) -> Result<Vec<DeleteImpact>, OperationError> {
+        let targets = self.search(au, se)?;
+        if targets.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let schema = self.get_schema();
+        let ref_types = schema.get_reference_types();
+        // Everything referring to any target - the refint filter for deleting
+        // them, so the reference indexes answer it.
+        let filt = filter!(FC::Or(
+            targets
+                .iter()
+                .map(|t| ref_types.values().map(move |r_type| {
+                    f_eq(r_type.name.as_str(), PartialValue::new_refer(*t.get_uuid()))
+                }))
+                .flatten()
+                .collect(),
+        ));
+        let referrers = self.impersonate_search(au, filt.clone(), filt, &se.event)?;
+        let acp_filt = filter!(f_eq("class", PVCLASS_ACP.clone()));
+        let acps = self.impersonate_search(au, acp_filt.clone(), acp_filt, &se.event)?;
+
+        let impacts = targets
+            .iter()
+            .map(|t| {
+                let uuid = t.get_uuid();
+                let name = t.get_ava_single_string("name");
+                let references: Vec<ReferenceImpact> = referrers
+                    .iter()
+                    .flat_map(|r| {
+                        ref_types
+                            .keys()
+                            .filter(move |attr| {
+                                r.get_ava_reference_uuid(attr.as_str())
+                                    .map(|us| us.contains(&uuid))
+                                    .unwrap_or(false)
+                            })
+                            .map(move |attr| ReferenceImpact {
+                                uuid: r.get_uuid().to_hyphenated_ref().to_string(),
+                                attr: attr.to_string(),
+                            })
+                    })
+                    .collect();
+                let memberships_lost = references
+                    .iter()
+                    .filter(|r| r.attr == "member")
+                    .map(|r| r.uuid.clone())
+                    .collect();
+                // The receiver and target scope are filters, so may name the
+                // entry rather than refer to it.
+                let mut idents = vec![uuid.to_hyphenated_ref().to_string()];
+                idents.extend(name.clone());
+                let access_profiles = acps
+                    .iter()
+                    .filter(|acp| {
+                        ["acp_receiver", "acp_targetscope"].iter().any(|attr| {
+                            acp.get_ava_single_protofilter(attr)
+                                .map(|f| proto_filter_names(&f, idents.as_slice()))
+                                .unwrap_or(false)
+                        })
+                    })
+                    .map(|acp| acp.get_uuid().to_hyphenated_ref().to_string())
+                    .collect();
+                DeleteImpact {
+                    uuid: uuid.to_hyphenated_ref().to_string(),
+                    name: name,
+                    references: references,
+                    memberships_lost: memberships_lost,
+                    access_profiles: access_profiles,
+                    auth_sessions: 0,
+                }
+            })
+            .collect();
+        Ok(impacts)
+    }
+
+    // How many entries hold attr, or every attribute in schema if None. Those
+    // without a presence index are listed without a count, as finding it would
+    // mean loading every entry.
+    fn attr_usage(
+        &self,
+        au: &mut AuditScope,
+        attr: Option<&str>,
+    ) -> Result<Vec<AttrUsage>, OperationError> {
+        let schema = self.get_schema();
+        let mut attrs: Vec<String> = match attr {
+            Some(a) => {
+                let a_norm = schema.normalise_attr_name(a);
+                if !schema.get_attributes().contains_key(&a_norm) {
+                    return Err(OperationError::InvalidAttributeName(a_norm));
+                }
+                vec![a_norm]
+            }
+            None => schema.get_attributes().keys().cloned().collect(),
+        };
+        attrs.sort();
+
+        let be_txn = self.get_be_txn();
+        attrs
+            .into_iter()
+            .map(|a| {
+                be_txn.count_attr_usage(au, &a).map(|entries| AttrUsage {
+                    attr: a,
+                    entries: entries,
+                })
+            })
+            .collect()
+    }
 
+    // Should this actually be names_to_uuids and we do batches?
+    //  In the initial design "no", we can always write a batched
+    //  interface later.
+    //
+    // These read name2uuid and uuid2name from the backend, which only hold
+    // live entries, so like an internal search they never find tombstones
+    // or recycled entries. Remember, we don't care if the name is invalid,
+    // it simply won't be there.
+    fn name_to_uuid(&self, audit: &mut AuditScope, name: &str) -> Result<Uuid, OperationError> {
+        audit_log!(audit, "name_to_uuid: name -> {:?}", name);
+        let uuid_res = self
+            .get_be_txn()
+            .name2uuid(audit, name)?
+            .ok_or(OperationError::NoMatchingEntries)?;
+        audit_log!(audit, "name_to_uuid: uuid <- {:?}", uuid_res);
         Ok(uuid_res)
     }
 
@@ -201,49 +702,152 @@ pub trait QueryServerTransaction {
         audit: &mut AuditScope,
         uuid: &Uuid,
     ) -> Result<Option<Value>, OperationError> {
-        // construct the filter
-        let filt = filter!(f_eq("uuid", PartialValue::new_uuidr(uuid)));
         audit_log!(audit, "uuid_to_name: uuid -> {:?}", uuid);
+        // No entry, or no name, which for some types is valid, IE schema.
+        let name_res = self
+            .get_be_txn()
+            .uuid2name(audit, uuid)?
+            .map(|n| Value::new_iutf8s(n.as_str()));
+        audit_log!(audit, "uuid_to_name: name <- {:?}", name_res);
+        Ok(name_res)
+    }
 
-        // Internal search - DO NOT SEARCH TOMBSTONES AND RECYCLE
-        let res = match self.internal_search(audit, filt) {
-            Ok(e) => e,
-            Err(e) => return Err(e),
-        };
-
-        audit_log!(audit, "uuid_to_name: results -- {:?}", res);
+    // The uuid and stored filter of the saved search called name. The saved
+    // search is read as event, so the caller must be able to see its filter.
+    fn saved_search_lookup(
+        &self,
+        audit: &mut AuditScope,
+        event: &Event,
+        name: &str,
+    ) -> Result<(Uuid, ProtoFilter), OperationError> {
+        let f = filter!(f_and!([
+            f_eq("class", PVCLASS_SAVEDSEARCH.clone()),
+            f_eq("name", PartialValue::new_iutf8s(name))
+        ]));
+        let f_valid = f
+            .clone()
+            .to_ignore_hidden()
+            .validate(self.get_schema())
+            .map_err(OperationError::SchemaViolation)?;
+        let f_orig = f
+            .validate(self.get_schema())
+            .map_err(OperationError::SchemaViolation)?;
+        let se = SearchEvent::new_impersonate(event, f_valid, f_orig);
+        let res = self.search_ext(audit, &se)?;
+        let e = res.first().ok_or(OperationError::NoMatchingEntries)?;
+        // Being able to see the entry isn't enough to run it.
+        let filter = e
+            .get_ava_single_protofilter("savedsearch_filter")
+            .ok_or(OperationError::AccessDenied)?;
+        audit_log!(audit, "saved_search_lookup: {} -> {:?}", name, filter);
+        Ok((e.get_uuid().clone(), filter))
+    }
 
-        if res.len() == 0 {
-            // If result len == 0, error no such result
-            audit_log!(audit, "uuid_to_name: name, no such entry <- Ok(None)");
-            return Ok(None);
-        } else if res.len() >= 2 {
-            // if result len >= 2, error, invaid entry state.
-            return Err(OperationError::InvalidDBState);
-        }
+    // The filter of the saved search called name, ready to search with. The
+    // search it describes is subject to the caller's access as any other.
+    fn saved_search_filter(
+        &self,
+        audit: &mut AuditScope,
+        event: &Event,
+        name: &str,
+        params: &BTreeMap<String, String>,
+    ) -> Result<ProtoFilter, OperationError> {
+        let (_, filter) = self.saved_search_lookup(audit, event, name)?;
+        saved_search_params(&filter, params)
+    }
 
-        // fine for 0/1 case, but check len for >= 2 to eliminate that case.
-        let e = res.first().ok_or(OperationError::NoMatchingEntries)?;
-        // Get the uuid from the entry. Again, check it exists, and only one.
-        let name_res = match e.get_ava(&String::from("name")) {
-            Some(vas) => match vas.first() {
-                Some(u) => (*u).clone(),
-                // Name is in an invalid state in the db
-                None => return Err(OperationError::InvalidEntryState),
-            },
-            None => {
-                // No attr name, some types this is valid, IE schema.
-                // return Err(OperationError::InvalidEntryState),
-                return Ok(None);
-            }
-        };
+    // Who may approve membership requests for group. Only the group decides
+    // this, so a group without approvers can't have requests made of it.
+    fn membership_approvers(
+        &self,
+        audit: &mut AuditScope,
+        group: &Uuid,
+    ) -> Result<BTreeSet<Uuid>, OperationError> {
+        let mut groups = self.internal_search(
+            audit,
+            filter!(f_and!([
+                f_eq("class", PVCLASS_GROUP.clone()),
+                f_eq("uuid", PartialValue::new_uuid(*group))
+            ])),
+        )?;
+        let g = groups.pop().ok_or(OperationError::NoMatchingEntries)?;
+        Ok(g.get_ava_reference_uuid("membership_approver")
+            .map(|us| us.into_iter().cloned().collect())
+            .unwrap_or_else(BTreeSet::new))
+    }
 
-        audit_log!(audit, "uuid_to_name: name <- {:?}", name_res);
+    // A uuid as its name if it has one, for showing to people.
+    fn uuid_display(&self, audit: &mut AuditScope, u: &Uuid) -> Result<String, OperationError> {
+        Ok(self
+            .uuid_to_name(audit, u)?
+            .map(|v| v.to_proto_string_clone())
+            .unwrap_or_else(|| u.to_hyphenated_ref().to_string()))
+    }
 
-        // Make sure it's the right type ... (debug only)
-        debug_assert!(name_res.is_insensitive_utf8());
+    // The pending membership requests event made, and those it may decide.
+    // Who may decide one is set on its group rather than by an access
+    // profile, so these are found internally and then checked.
+    fn membership_requests(
+        &self,
+        audit: &mut AuditScope,
+        event: &Event,
+    ) -> Result<Vec<MembershipRequestRecord>, OperationError> {
+        let requests = self.internal_search(
+            audit,
+            filter!(f_and!([
+                f_eq("class", PVCLASS_MEMBERSHIPREQUEST.clone()),
+                f_eq("request_state", PVREQUEST_PENDING.clone())
+            ])),
+        )?;
+        let mut records = Vec::new();
+        for r in requests {
+            let (group, member, action, requester) = match (
+                r.get_ava_single_uuid("request_group"),
+                r.get_ava_single_uuid("request_member"),
+                membership_action(&r),
+                r.get_ava_single_uuid("request_requester"),
+            ) {
+                (Some(g), Some(m), Some(a), Some(q)) => (*g, *m, a, *q),
+                _ => {
+                    audit_log!(audit, "membership request {:?} is malformed", r.get_uuid());
+                    continue;
+                }
+            };
+            if event.get_uuid() != Some(&requester) {
+                // The group may have gone since the request was made.
+                let approvers = match self.membership_approvers(audit, &group) {
+                    Ok(a) => a,
+                    Err(OperationError::NoMatchingEntries) => continue,
+                    Err(e) => return Err(e),
+                };
+                if !may_approve(event, &approvers) {
+                    continue;
+                }
+            }
+            records.push(MembershipRequestRecord {
+                uuid: r.get_uuid().to_hyphenated_ref().to_string(),
+                group: self.uuid_display(audit, &group)?,
+                member: self.uuid_display(audit, &member)?,
+                action: action,
+                state: "pending".to_string(),
+                requester: self.uuid_display(audit, &requester)?,
+                approver: None,
+            });
+        }
+        Ok(records)
+    }
 
-        Ok(Some(name_res))
+    // How the domain presents itself. This is public, so it's read internally
+    // for anyone who asks, but only the attributes meant to be shown.
+    fn branding(&self, audit: &mut AuditScope) -> Result<Branding, OperationError> {
+        let e = self.internal_search_uuid(audit, &UUID_DOMAIN_BRANDING)?;
+        Ok(Branding {
+            display_name: e
+                .get_ava_single_string("displayname")
+                .ok_or(OperationError::InvalidEntryState)?,
+            logo: e.get_ava_single_string("branding_logo"),
+            login_banner: e.get_ava_single_string("login_banner"),
+        })
     }
 
     // From internal, generate an exists event and dispatch
@@ -457,6 +1061,8 @@ pub trait QueryServerTransaction {
                     SyntaxType::CREDENTIAL => Err(OperationError::InvalidAttribute("Credentials can not be supplied through modification - please use the IDM api".to_string())),
                     SyntaxType::RADIUS_UTF8STRING => Err(OperationError::InvalidAttribute("Radius secrets can not be supplied through modification - please use the IDM api".to_string())),
                     SyntaxType::SSHKEY => Err(OperationError::InvalidAttribute("SSH public keys can not be supplied through modification - please use the IDM api".to_string())),
+                    SyntaxType::UINT32 => Value::new_uint32_str(value.as_str())
+                        .ok_or(OperationError::InvalidAttribute("Invalid uint32 syntax".to_string())),
                 }
             }
             None => {
@@ -531,6 +1137,9 @@ pub trait QueryServerTransaction {
                     SyntaxType::CREDENTIAL => Ok(PartialValue::new_credential_tag(value.as_str())),
                     SyntaxType::RADIUS_UTF8STRING => Ok(PartialValue::new_radius_string()),
                     SyntaxType::SSHKEY => Ok(PartialValue::new_sshkey_tag_s(value.as_str())),
+                    SyntaxType::UINT32 => PartialValue::new_uint32_str(value.as_str()).ok_or(
+                        OperationError::InvalidAttribute("Invalid uint32 syntax".to_string()),
+                    ),
                 }
             }
             None => {
@@ -571,6 +1180,7 @@ pub struct QueryServerReadTransaction {
     // type, maybe others?
     schema: SchemaReadTransaction,
     accesscontrols: AccessControlsReadTransaction,
+    search_limits: Arc<SearchLimits>,
 }
 
 // Actually conduct a search request
@@ -594,9 +1204,69 @@ impl QueryServerTransaction for QueryServerReadTransaction {
     fn get_accesscontrols(&self) -> &AccessControlsReadTransaction {
         &self.accesscontrols
     }
+
+    fn get_search_limits(&self) -> &SearchLimits {
+        &self.search_limits
+    }
 }
 
 impl QueryServerReadTransaction {
+    // As search_ext, a page at a time. Each page has access controls applied
+    // as it is read, so a page may hold fewer than page_size entries, and
+    // the search is done only once next_cursor is None.
+    pub fn search_page_ext(
+        &self,
+        au: &mut AuditScope,
+        se: &SearchEvent,
+        page_size: usize,
+        cursor: Option<&str>,
+    ) -> Result<(Vec<Entry<EntryReduced, EntryCommitted>>, Option<String>), OperationError> {
+        audit_log!(au, "paged search: filter -> {:?}", se.filter);
+        let limits = self.search_limits(au, se)?;
+
+        let schema = self.get_schema();
+        let idxmeta = schema.get_idxmeta();
+        let vfr = try_audit!(au, se.filter.resolve(&se.event, Some(&idxmeta))).with_aliases(schema);
+
+        let mut audit_be = AuditScope::new("backend_search_page");
+        let res = self
+            .be_txn
+            .search_page(
+                &mut audit_be,
+                &vfr,
+                se.sort.as_ref(),
+                se.event.get_uuid(),
+                page_size,
+                cursor,
+                &limits,
+            )
+            .map_err(|e| match e {
+                OperationError::ResourceLimit | OperationError::InvalidSearchCursor => e,
+                _ => OperationError::Backend,
+            });
+        au.append_scope(audit_be);
+
+        let (res, next_cursor) = try_audit!(au, res);
+        let entries = self.search_filter(au, se, res)?;
+        self.search_reduce(au, se, entries)
+            .map(|entries| (entries, next_cursor))
+    }
+
+    // The most recent runs of the report of the saved search called name,
+    // newest first. As with running it, the caller must be able to see its
+    // filter.
+    pub fn reports(
+        &self,
+        audit: &mut AuditScope,
+        event: &Event,
+        name: &str,
+        limit: usize,
+    ) -> Result<Vec<ReportRecord>, OperationError> {
+        let (uuid, _) = self.saved_search_lookup(audit, event, name)?;
+        self.be_txn
+            .reports(audit, uuid.to_hyphenated_ref().to_string().as_str(), limit)
+    }
+
     // Verify the data content of the server is as expected. This will probably
     // call various functions for validation, including possibly plugin
     // verifications.
@@ -606,7 +1276,7 @@ impl QueryServerReadTransaction {
         // If we fail after backend, we need to return NOW because we can't
         // assert any other faith in the DB states.
         //  * backend
-        let be_errs = self.get_be_txn().verify();
+        let be_errs = self.get_be_txn().verify(&mut audit);
 
         if be_errs.len() != 0 {
             au.append_scope(audit);
@@ -650,6 +1320,8 @@ pub struct QueryServerWriteTransaction<'a> {
     be_txn: BackendWriteTransaction,
     schema: SchemaWriteTransaction<'a>,
     accesscontrols: AccessControlsWriteTransaction<'a>,
+    search_limits: Arc<SearchLimits>,
+    commits: CommitBroadcast,
     // We store a set of flags that indicate we need a reload of
     // schema or acp, which is tested by checking the classes of the
     // changing content.
@@ -675,6 +1347,10 @@ impl<'a> QueryServerTransaction for QueryServerWriteTransaction<'a> {
     fn get_accesscontrols(&self) -> &AccessControlsWriteTransaction<'a> {
         &self.accesscontrols
     }
+
+    fn get_search_limits(&self) -> &SearchLimits {
+        &self.search_limits
+    }
 }
 
 #[derive(Clone)]
@@ -683,6 +1359,9 @@ pub struct QueryServer {
     be: Backend,
     schema: Arc<Schema>,
     accesscontrols: Arc<AccessControls>,
+    search_limits: Arc<SearchLimits>,
+    commits: CommitBroadcast,
+    purge_metrics: PurgeMetrics,
 }
 
 impl QueryServer {
@@ -692,23 +1371,80 @@ impl QueryServer {
             be: be,
             schema: Arc::new(schema),
             accesscontrols: Arc::new(AccessControls::new()),
+            search_limits: Arc::new(SearchLimits::unlimited()),
+            commits: CommitBroadcast::new(),
+            purge_metrics: PurgeMetrics::new(),
         }
     }
 
-    pub fn read(&self) -> QueryServerReadTransaction {
-        QueryServerReadTransaction {
-            be_txn: self.be.read(),
-            schema: self.schema.read(),
-            accesscontrols: self.accesscontrols.read(),
-        }
+    // The write actor records each purge here once it has committed.
+    pub fn purge_metrics(&self) -> &PurgeMetrics {
+        &self.purge_metrics
     }
 
-    pub fn write(&self) -> QueryServerWriteTransaction {
+    // A commit event is sent for every write that changes something, from
+    // now on. See commits.rs for who should subscribe.
+    pub fn subscribe_commits(&self) -> Receiver<Arc<CommitEvent>> {
+        self.commits.subscribe()
+    }
+
+    // This must be set before the server is cloned to the workers.
+    pub fn set_search_limits(&mut self, limits: SearchLimits) {
+        self.search_limits = Arc::new(limits);
+    }
+
+    // Unlike the above, this can be changed at any time. See
+    // Backend::set_read_only.
+    pub fn set_read_only(&self, read_only: bool) {
+        self.be.set_read_only(read_only)
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.be.is_read_only()
+    }
+
+    // See Backend::set_primary.
+    pub fn set_primary(&self, primary: Option<String>) {
+        self.be.set_primary(primary)
+    }
+
+    pub fn get_primary(&self) -> Option<String> {
+        self.be.get_primary()
+    }
+
+    pub fn pool_stats(&self) -> DbPoolStats {
+        self.be.pool_stats()
+    }
+
+    // Cheap enough to check on an interval, as it doesn't touch the database.
+    pub fn alias_migrations_pending(&self) -> bool {
+        !self.schema.read().get_alias_migrations().is_empty()
+    }
+
+    pub fn read(&self) -> Result<QueryServerReadTransaction, OperationError> {
+        Ok(QueryServerReadTransaction {
+            be_txn: self.be.read()?,
+            schema: self.schema.read(),
+            accesscontrols: self.accesscontrols.read(),
+            search_limits: self.search_limits.clone(),
+        })
+    }
+
+    pub fn write(&self) -> Result<QueryServerWriteTransaction, OperationError> {
+        if let Some(primary) = self.be.get_primary() {
+            return Err(OperationError::ReadReplica(primary));
+        }
+        self.write_replicated()
+    }
+
+    // See Backend::write_replicated.
+    pub fn write_replicated(&self) -> Result<QueryServerWriteTransaction, OperationError> {
         // Feed the current schema index metadata to the be write transaction.
         let schema_write = self.schema.write();
         let idxmeta = schema_write.get_idxmeta();
+        let be_txn = self.be.write_replicated(idxmeta)?;
 
-        QueryServerWriteTransaction {
+        Ok(QueryServerWriteTransaction {
             // I think this is *not* needed, because commit is mut self which should
             // take ownership of the value, and cause the commit to "only be run
             // once".
@@ -716,12 +1452,52 @@ impl QueryServer {
             // The commited flag is however used for abort-specific code in drop
             // which today I don't think we have ... yet.
             committed: false,
-            be_txn: self.be.write(idxmeta),
+            be_txn: be_txn,
             schema: schema_write,
             accesscontrols: self.accesscontrols.write(),
+            search_limits: self.search_limits.clone(),
+            commits: self.commits.clone(),
             changed_schema: false,
             changed_acp: false,
-        }
+        })
+    }
+
+    // Mark that we still own the database.
+    pub fn heartbeat(&self, audit: &mut AuditScope) -> Result<(), OperationError> {
+        self.be.heartbeat(audit)
+    }
+
+    pub fn analyze(&self, audit: &mut AuditScope) -> Result<usize, OperationError> {
+        self.be.analyze(audit)
+    }
+
+    // The schema write lock is held throughout, as it is by a write txn, so
+    // that no write runs alongside. Returns the db size before and after.
+    pub fn vacuum(&self, audit: &mut AuditScope) -> Result<(u64, u64), OperationError> {
+        let _schema_write = self.schema.write();
+        self.be
+            .write(BTreeSet::new())
+            .and_then(|be_txn| be_txn.vacuum(audit))
+    }
+
+    pub fn flush_idx_usage(&self, audit: &mut AuditScope) -> Result<(), OperationError> {
+        self.be.flush_idx_usage(audit)
+    }
+
+    pub fn flush_slow_queries(&self, audit: &mut AuditScope) -> Result<(), OperationError> {
+        self.be.flush_slow_queries(audit)
+    }
+
+    pub fn flush_access_journal(&self, audit: &mut AuditScope) -> Result<(), OperationError> {
+        self.be.flush_access_journal(audit)
+    }
+
+    pub fn warm_up(
+        &self,
+        audit: &mut AuditScope,
+        limit: usize,
+    ) -> Result<(usize, usize), OperationError> {
+        self.be.warm_up(audit, limit)
     }
 
     pub(crate) fn initialise_helper(&self, audit: &mut AuditScope) -> Result<(), OperationError> {
@@ -736,7 +1512,7 @@ impl QueryServer {
         // reloading to occur, which causes the idxmeta to update, and allows validation
         // of the schema in the subsequent steps as we proceed.
 
-        let reindex_write_1 = self.write();
+        let reindex_write_1 = self.write()?;
         reindex_write_1
             .upgrade_reindex(audit, 1)
             .and_then(|_| reindex_write_1.commit(audit))?;
@@ -750,35 +1526,73 @@ impl QueryServer {
         // the schema to tell us what's indexed), but because we have the in
         // mem schema that defines how schema is structuded, and this is all
         // marked "system", then we won't have an issue here.
-        let mut ts_write_1 = self.write();
+        let mut ts_write_1 = self.write()?;
         ts_write_1
             .initialise_schema_core(audit)
             .and_then(|_| ts_write_1.commit(audit))?;
 
-        let mut ts_write_2 = self.write();
+        let mut ts_write_2 = self.write()?;
         ts_write_2
             .initialise_schema_idm(audit)
             .and_then(|_| ts_write_2.commit(audit))?;
 
-        // reindex and set to version 2
-        let reindex_write_2 = self.write();
+        // reindex and set to version 5 - substring indexes hold trigrams
+        // from 3 on, and name2uuid and uuid2name are filled from 4 on, so
+        // earlier ones are empty. From 5 on every entry in id2entry has a
+        // format header, and reindexing rewrites those without one.
+        let reindex_write_2 = self.write()?;
         reindex_write_2
-            .upgrade_reindex(audit, 2)
+            .upgrade_reindex(audit, 5)
             .and_then(|_| reindex_write_2.commit(audit))?;
 
-        let mut ts_write_3 = self.write();
+        let mut ts_write_3 = self.write()?;
         ts_write_3
             .initialise_idm(audit)
             .and_then(|_| ts_write_3.commit(audit))
     }
 
     pub fn verify(&self, au: &mut AuditScope) -> Vec<Result<(), ConsistencyError>> {
-        let r_txn = self.read();
-        r_txn.verify(au)
+        match self.read() {
+            Ok(r_txn) => r_txn.verify(au),
+            Err(e) => {
+                audit_log!(au, "Unable to begin verify txn -> {:?}", e);
+                vec![Err(ConsistencyError::QueryServerSearchFailure)]
+            }
+        }
     }
 }
 
 impl<'a> QueryServerWriteTransaction<'a> {
+    // Add the entries a write changed to the changelog, under who made it.
+    fn note_changes(
+        &self,
+        event: Option<&Event>,
+        entries: &[Entry<EntryValid, EntryCommitted>],
+        operation: &str,
+    ) {
+        let identity = match event.and_then(|e| e.get_uuid()) {
+            Some(u) => u.to_string(),
+            None => "internal".to_string(),
+        };
+        entries.iter().for_each(|e| {
+            self.be_txn
+                .note_change(e.get_uuid(), identity.as_str(), operation)
+        });
+    }
+
+    // Undo what a failed operation wrote since savepoint, and pass on why it
+    // failed. Should even that fail the txn can't be trusted, and that is
+    // the error given instead.
+    fn rollback_op<T>(
+        &self,
+        au: &mut AuditScope,
+        savepoint: Savepoint,
+        e: OperationError,
+    ) -> Result<T, OperationError> {
+        try_audit!(au, self.be_txn.rollback_to_savepoint(au, savepoint));
+        Err(e)
+    }
+
     pub fn create(&mut self, au: &mut AuditScope, ce: &CreateEvent) -> Result<(), OperationError> {
         // The create event is a raw, read only representation of the request
         // that was made to us, including information about the identity
@@ -869,13 +1683,24 @@ impl<'a> QueryServerWriteTransaction<'a> {
 
         let _ = try_audit!(au, plug_pre_res, "Create operation failed (plugin), {:?}");
 
+        // From here on a failure undoes only this create, and leaves the rest
+        // of the txn as it was.
+        let savepoint = try_audit!(au, self.be_txn.savepoint(au));
+
         let mut audit_be = AuditScope::new("backend_create");
         // We may change from ce.entries later to something else?
         let res = self.be_txn.create(&mut audit_be, norm_cand).map_err(|e| e);
 
         au.append_scope(audit_be);
 
-        let commit_cand = try_audit!(au, res);
+        let commit_cand = match res {
+            Ok(c) => c,
+            Err(e) => {
+                audit_log!(au, "Create operation failed (backend), {:?}", e);
+                return self.rollback_op(au, savepoint, e);
+            }
+        };
+        self.note_changes(Some(&ce.event), &commit_cand, "create");
         // Run any post plugins
 
         let mut audit_plugin_post = AuditScope::new("plugin_post_create");
@@ -883,14 +1708,11 @@ impl<'a> QueryServerWriteTransaction<'a> {
             Plugins::run_post_create(&mut audit_plugin_post, self, &commit_cand, ce);
         au.append_scope(audit_plugin_post);
 
-        if plug_post_res.is_err() {
-            audit_log!(
-                au,
-                "Create operation failed (post plugin), {:?}",
-                plug_post_res
-            );
-            return plug_post_res;
+        if let Err(e) = plug_post_res {
+            audit_log!(au, "Create operation failed (post plugin), {:?}", e);
+            return self.rollback_op(au, savepoint, e);
         }
+        try_audit!(au, self.be_txn.release_savepoint(au, savepoint));
 
         // We have finished all plugs and now have a successful operation - flag if
         // schema or acp requires reload.
@@ -907,6 +1729,7 @@ impl<'a> QueryServerWriteTransaction<'a> {
                 acc
             } else {
                 e.attribute_value_pres("class", &PVCLASS_ACP)
+                    || e.attribute_value_pres("class", &PVCLASS_ANONYMOUS_POLICY)
             }
         });
         audit_log!(
@@ -1007,6 +1830,15 @@ impl<'a> QueryServerWriteTransaction<'a> {
             Err(e) => return Err(OperationError::SchemaViolation(e)),
         };
 
+        // Recycling is a change too, so sync clients need to see it.
+        let del_cand = try_audit!(
+            au,
+            Plugins::run_modify_stamp(au, self, &pre_candidates, del_cand)
+        );
+
+        // As in create, a failure from here only undoes this delete.
+        let savepoint = try_audit!(au, self.be_txn.savepoint(au));
+
         let mut audit_be = AuditScope::new("backend_modify");
 
         let res = self
@@ -1014,21 +1846,22 @@ impl<'a> QueryServerWriteTransaction<'a> {
             .modify(&mut audit_be, &pre_candidates, &del_cand);
         au.append_scope(audit_be);
 
-        if res.is_err() {
-            // be_txn is dropped, ie aborted here.
-            audit_log!(au, "Delete operation failed (backend), {:?}", res);
-            return res;
+        if let Err(e) = res {
+            audit_log!(au, "Delete operation failed (backend), {:?}", e);
+            return self.rollback_op(au, savepoint, e);
         }
+        self.note_changes(Some(&de.event), &del_cand, "delete");
 
         // Post delete plugs
         let mut audit_plugin_post = AuditScope::new("plugin_post_delete");
         let plug_post_res = Plugins::run_post_delete(&mut audit_plugin_post, self, &del_cand, de);
         au.append_scope(audit_plugin_post);
 
-        if plug_post_res.is_err() {
-            audit_log!(au, "Delete operation failed (plugin), {:?}", plug_post_res);
-            return plug_post_res;
+        if let Err(e) = plug_post_res {
+            audit_log!(au, "Delete operation failed (plugin), {:?}", e);
+            return self.rollback_op(au, savepoint, e);
         }
+        try_audit!(au, self.be_txn.release_savepoint(au, savepoint));
 
         // We have finished all plugs and now have a successful operation - flag if
         // schema or acp requires reload.
@@ -1045,6 +1878,7 @@ impl<'a> QueryServerWriteTransaction<'a> {
                 acc
             } else {
                 e.attribute_value_pres("class", &PVCLASS_ACP)
+                    || e.attribute_value_pres("class", &PVCLASS_ANONYMOUS_POLICY)
             }
         });
         audit_log!(
@@ -1059,19 +1893,32 @@ impl<'a> QueryServerWriteTransaction<'a> {
         res
     }
 
-    pub fn purge_tombstones(&self, au: &mut AuditScope) -> Result<(), OperationError> {
-        // delete everything that is a tombstone.
-
+    // Delete at most limit of the tombstones older than TOMBSTONE_MAX_AGE, as
+    // of now in seconds since the epoch. Returns how many were deleted, and
+    // whether any were left for next time.
+    pub fn purge_tombstones(
+        &self,
+        au: &mut AuditScope,
+        now: i64,
+        limit: usize,
+    ) -> Result<(usize, bool), OperationError> {
         // Search for tombstones
         let ts =
             match self.internal_search(au, filter_all!(f_eq("class", PVCLASS_TOMBSTONE.clone()))) {
                 Ok(r) => r,
                 Err(e) => return Err(e),
             };
+        let cutoff = generalized_time(now - TOMBSTONE_MAX_AGE);
+        let mut ts: Vec<_> = ts
+            .into_iter()
+            .filter(|e| changed_before(e, cutoff.as_str()))
+            .collect();
+        let pending = ts.len() > limit;
+        ts.truncate(limit);
 
         if ts.len() == 0 {
             audit_log!(au, "No Tombstones present - purge operation success");
-            return Ok(());
+            return Ok((0, false));
         }
 
         // TODO #68: Has an appropriate amount of time/condition past (ie replication events?)
@@ -1088,30 +1935,48 @@ impl<'a> QueryServerWriteTransaction<'a> {
         if res.is_err() {
             // be_txn is dropped, ie aborted here.
             audit_log!(au, "Tombstone purge operation failed (backend), {:?}", res);
-            return res;
+            return res.map(|_| (0, false));
         }
+        self.note_changes(None, &ts, "purge_tombstone");
 
         // Send result
         audit_log!(au, "Tombstone purge operation success");
-        res
+        Ok((ts.len(), pending))
     }
 
-    pub fn purge_recycled(&self, au: &mut AuditScope) -> Result<(), OperationError> {
-        // Send everything that is recycled to tombstone
+    // Make a tombstone of at most limit of the entries recycled longer than
+    // RECYCLEBIN_MAX_AGE ago, as of now in seconds since the epoch. Returns
+    // as purge_tombstones does.
+    pub fn purge_recycled(
+        &self,
+        au: &mut AuditScope,
+        now: i64,
+        limit: usize,
+    ) -> Result<(usize, bool), OperationError> {
         // Search all recycled
         let rc =
             match self.internal_search(au, filter_all!(f_eq("class", PVCLASS_RECYCLED.clone()))) {
                 Ok(r) => r,
                 Err(e) => return Err(e),
             };
+        let cutoff = generalized_time(now - RECYCLEBIN_MAX_AGE);
+        let mut rc: Vec<_> = rc
+            .into_iter()
+            .filter(|e| changed_before(e, cutoff.as_str()))
+            .collect();
+        let pending = rc.len() > limit;
+        rc.truncate(limit);
 
         if rc.len() == 0 {
             audit_log!(au, "No recycled present - purge operation success");
-            return Ok(());
+            return Ok((0, false));
         }
 
-        // Modify them to strip all avas except uuid
+        // Modify them to strip all avas except uuid, then stamp them so the
+        // tombstone's age starts from now.
         let tombstone_cand = rc.iter().map(|e| e.to_tombstone()).collect();
+        let tombstone_cand =
+            try_audit!(au, Plugins::run_modify_stamp(au, self, &rc, tombstone_cand));
 
         // Backend Modify
         let mut audit_be = AuditScope::new("backend_modify");
@@ -1122,12 +1987,252 @@ impl<'a> QueryServerWriteTransaction<'a> {
         if res.is_err() {
             // be_txn is dropped, ie aborted here.
             audit_log!(au, "Purge recycled operation failed (backend), {:?}", res);
-            return res;
+            return res.map(|_| (0, false));
         }
+        self.note_changes(None, &rc, "purge_recycled");
 
         // return
         audit_log!(au, "Purge recycled operation success");
-        res
+        Ok((rc.len(), pending))
+    }
+
+    // Merge what a replication partner supplied, by the rules in repl/mod.rs.
+    // Schema comes first, so the rest are checked against the schema they
+    // were written with. This writes to the backend directly: the entries
+    // are as the partner's plugins left them, and ours would stamp them as a
+    // new change of our own that then bounced back to the partner.
+    pub fn repl_apply(
+        &mut self,
+        au: &mut AuditScope,
+        entries: &[ReplEntry],
+    ) -> Result<MergeStats, OperationError> {
+        let incoming: Result<Vec<_>, _> = entries.iter().map(repl::from_proto).collect();
+        let incoming = try_audit!(au, incoming);
+        let (schema_ents, rest): (Vec<_>, Vec<_>) = incoming.into_iter().partition(|(e, _)| {
+            e.attribute_value_pres("class", &PVCLASS_CLASSTYPE)
+                || e.attribute_value_pres("class", &PVCLASS_ATTRIBUTETYPE)
+        });
+
+        let mut stats = MergeStats::default();
+        for (e, cids) in schema_ents {
+            let m = self.repl_merge(au, e, cids)?;
+            if m == Merged::Applied {
+                self.changed_schema = true;
+            }
+            stats.add(m);
+        }
+        if self.changed_schema {
+            self.reload_schema(au)?;
+        }
+        for (e, cids) in rest {
+            let m = self.repl_merge(au, e, cids)?;
+            stats.add(m);
+        }
+        audit_log!(au, "replication merged {:?}", stats);
+        Ok(stats)
+    }
+
+    fn repl_merge(
+        &mut self,
+        au: &mut AuditScope,
+        theirs_e: Entry<EntryValid, EntryCommitted>,
+        cids: BTreeMap<String, Cid>,
+    ) -> Result<Merged, OperationError> {
+        let uuid = theirs_e.get_uuid().clone();
+        let theirs = ReplState::new(&theirs_e, cids);
+        let ours_e = self
+            .internal_search(
+                au,
+                filter_all!(f_eq("uuid", PartialValue::new_uuidr(&uuid))),
+            )?
+            .pop();
+        let ours = match &ours_e {
+            Some(e) => Some(ReplState::new(e, self.be_txn.get_changestate(au, &uuid)?)),
+            None => None,
+        };
+
+        let post = match (repl::resolve(ours.as_ref(), &theirs), ours_e) {
+            (Resolution::Keep, _) => return Ok(Merged::Unchanged),
+            (Resolution::Create, _) => {
+                let mut e: Entry<EntryInvalid, EntryNew> = Entry::new();
+                theirs_e
+                    .avas()
+                    .for_each(|(attr, vs)| vs.iter().for_each(|v| e.add_ava(attr, v)));
+                if self.repl_unique_conflict(au, &e)? {
+                    audit_log!(au, "replication conflict, {} would not be unique", uuid);
+                    return Ok(Merged::Conflict);
+                }
+                let e = match e.validate(self.get_schema()) {
+                    Ok(e) => e,
+                    Err(err) => {
+                        audit_log!(au, "replication conflict, {} is invalid {:?}", uuid, err);
+                        return Ok(Merged::Conflict);
+                    }
+                };
+                let mut audit_be = AuditScope::new("backend_create");
+                let res = self.be_txn.create(&mut audit_be, vec![e]);
+                au.append_scope(audit_be);
+                let created = try_audit!(au, res);
+                // Including those they removed, so we know when that was.
+                for attr in theirs.attrs.iter().chain(theirs.cids.keys()) {
+                    self.be_txn
+                        .set_changestate(&uuid, attr.as_str(), &theirs.cid(attr));
+                }
+                created
+            }
+            (Resolution::Take(take), Some(pre)) => {
+                let mut post = pre.clone().invalidate();
+                for attr in take.iter() {
+                    match theirs_e.get_ava(attr.as_str()) {
+                        Some(vs) => post.set_avas(attr.as_str(), vs.into_iter().cloned().collect()),
+                        None => post.purge_ava(attr.as_str()),
+                    }
+                }
+                if self.repl_unique_conflict(au, &post)? {
+                    audit_log!(au, "replication conflict, {} would not be unique", uuid);
+                    return Ok(Merged::Conflict);
+                }
+                let post = match post.validate(self.get_schema()) {
+                    Ok(e) => e,
+                    Err(err) => {
+                        audit_log!(au, "replication conflict, {} is invalid {:?}", uuid, err);
+                        return Ok(Merged::Conflict);
+                    }
+                };
+                let pre = vec![pre];
+                let post = vec![post];
+                let mut audit_be = AuditScope::new("backend_modify");
+                let res = self.be_txn.modify(&mut audit_be, &pre, &post);
+                au.append_scope(audit_be);
+                try_audit!(au, res);
+                for attr in take.iter() {
+                    self.be_txn
+                        .set_changestate(&uuid, attr.as_str(), &theirs.cid(attr));
+                }
+                post
+            }
+            // resolve only takes from an entry we have.
+            (Resolution::Take(_), None) => return Err(OperationError::InvalidState),
+        };
+
+        self.note_changes(None, &post, "replicate");
+        self.changed_acp = self.changed_acp
+            || post.iter().any(|e| {
+                e.attribute_value_pres("class", &PVCLASS_ACP)
+                    || e.attribute_value_pres("class", &PVCLASS_ANONYMOUS_POLICY)
+            });
+        Ok(Merged::Applied)
+    }
+
+    // Whether a live entry other than this one already holds any of its
+    // unique values. Recycled and tombstoned entries hold theirs to no one.
+    fn repl_unique_conflict<STATE>(
+        &self,
+        au: &mut AuditScope,
+        e: &Entry<EntryInvalid, STATE>,
+    ) -> Result<bool, OperationError> {
+        if e.attribute_value_pres("class", &PVCLASS_RECYCLED)
+            || e.attribute_value_pres("class", &PVCLASS_TOMBSTONE)
+        {
+            return Ok(false);
+        }
+        let uuid = match e.get_ava_single("uuid") {
+            Some(v) => v.to_partialvalue(),
+            None => return Err(OperationError::InvalidEntryState),
+        };
+        let unique = self.get_schema().get_attributes_unique();
+        let terms: Vec<_> = unique
+            .iter()
+            .flat_map(|attr| {
+                let uuid = uuid.clone();
+                e.get_ava(attr.as_str())
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(move |v| {
+                        f_and(vec![
+                            f_eq(attr.as_str(), v.to_partialvalue()),
+                            f_andnot(f_eq("uuid", uuid.clone())),
+                        ])
+                    })
+            })
+            .collect();
+        if terms.is_empty() {
+            return Ok(false);
+        }
+        self.internal_exists(au, filter!(f_or(terms)))
+    }
+
+    // Run each report that is due, keeping how many entries its saved search
+    // matched as of now, in seconds since the epoch. Reports run as the
+    // server with no params, so a saved search with placeholders is skipped.
+    // Only the count is kept, which the indexes can often give without
+    // loading a single entry.
+    pub fn run_reports(&self, au: &mut AuditScope, now: i64) -> Result<(), OperationError> {
+        let candidates = self.internal_search(
+            au,
+            filter!(f_and!([
+                f_eq("class", PVCLASS_SAVEDSEARCH.clone()),
+                f_pres("report_interval")
+            ])),
+        )?;
+
+        let schema = self.get_schema();
+        let idxmeta = schema.get_idxmeta();
+        let event = Event::from_internal();
+        let no_params = BTreeMap::new();
+        let time = time::at_utc(time::Timespec::new(now, 0))
+            .strftime("%Y%m%d%H%M%SZ")
+            .map(|t| t.to_string())
+            .unwrap_or_default();
+
+        for e in candidates {
+            let name = e.get_ava_single_str("name").unwrap_or("");
+            let report = e.get_uuid().to_hyphenated_ref().to_string();
+
+            let interval = match e
+                .get_ava_single_str("report_interval")
+                .and_then(|i| i.parse::<i64>().ok())
+            {
+                Some(i) if i > 0 => i,
+                _ => {
+                    audit_log!(au, "report {} has an invalid interval, skipping", name);
+                    continue;
+                }
+            };
+            if let Some(last) = self.be_txn.report_last_run(au, report.as_str())? {
+                if last + interval > now {
+                    continue;
+                }
+            }
+
+            // The filter may name attributes that have since left the schema,
+            // which shouldn't stop the other reports.
+            let filt = match e
+                .get_ava_single_protofilter("savedsearch_filter")
+                .ok_or(OperationError::InvalidState)
+                .and_then(|f| saved_search_params(&f, &no_params))
+                .and_then(|f| Filter::from_rw(au, &f, self))
+                .and_then(|f| {
+                    f.to_ignore_hidden()
+                        .validate(schema)
+                        .map_err(OperationError::SchemaViolation)
+                })
+                .and_then(|f| f.resolve(&event, Some(&idxmeta)))
+                .map(|f| f.with_aliases(schema))
+            {
+                Ok(f) => f,
+                Err(err) => {
+                    audit_log!(au, "report {} can't be run, skipping: {:?}", name, err);
+                    continue;
+                }
+            };
+
+            let (count, allids) = self.be_txn.count(au, &filt)?;
+            self.be_txn
+                .write_report(au, report.as_str(), now, time.as_str(), count, allids)?;
+            info!("report {}: {} entries (allids {})", name, count, allids);
+        }
+        Ok(())
     }
 
     // Should this take a revive event?
@@ -1156,8 +2261,83 @@ impl<'a> QueryServerWriteTransaction<'a> {
                 .map_err(|e| OperationError::SchemaViolation(e))
         );
 
+        // As they were in the recycle bin, where directmemberof was kept so
+        // that we can put them back in their groups.
+        let revived = try_audit!(
+            au,
+            self.impersonate_search_valid(au, re.filter.clone(), re.filter.clone(), &re.event)
+        );
+
         // Now impersonate the modify
-        self.impersonate_modify_valid(au, re.filter.clone(), re.filter.clone(), m_valid, &re.event)
+        try_audit!(
+            au,
+            self.impersonate_modify_valid(
+                au,
+                re.filter.clone(),
+                re.filter.clone(),
+                m_valid,
+                &re.event
+            )
+        );
+
+        // memberof is rebuilt from the groups once they have us back.
+        let ref_types: Vec<String> = self
+            .get_schema()
+            .get_reference_types()
+            .keys()
+            .filter(|a| a.as_str() != "memberof" && a.as_str() != "directmemberof")
+            .map(|a| (*a).clone())
+            .collect();
+        for e in revived.iter() {
+            let uuid = e.get_uuid().clone();
+
+            // Anything we referred to that was deleted while we were in the
+            // bin, refint has already removed from everything live.
+            let mut dangling = Vec::new();
+            for attr in ref_types.iter() {
+                for u in e.get_ava_reference_uuid(attr).unwrap_or_default() {
+                    if !self
+                        .internal_exists(au, filter!(f_eq("uuid", PartialValue::new_uuidr(u))))?
+                    {
+                        dangling.push(Modify::Removed(attr.clone(), PartialValue::new_refer_r(u)));
+                    }
+                }
+            }
+            if !dangling.is_empty() {
+                audit_log!(
+                    au,
+                    "revive: removing {} dangling references",
+                    dangling.len()
+                );
+                self.internal_modify(
+                    au,
+                    filter!(f_eq("uuid", PartialValue::new_uuidr(&uuid))),
+                    ModifyList::new_list(dangling),
+                )?;
+            }
+
+            let mut groups = Vec::new();
+            for g in e
+                .get_ava_reference_uuid("directmemberof")
+                .unwrap_or_default()
+            {
+                if self.internal_exists(au, filter!(f_eq("uuid", PartialValue::new_uuidr(g))))? {
+                    groups.push(f_eq("uuid", PartialValue::new_uuidr(g)));
+                }
+            }
+            if !groups.is_empty() {
+                audit_log!(au, "revive: restoring {} memberships", groups.len());
+                self.internal_modify(
+                    au,
+                    filter!(f_or(groups)),
+                    ModifyList::new_list(vec![Modify::Present(
+                        "member".to_string(),
+                        Value::new_refer(uuid),
+                    )]),
+                )?;
+            }
+        }
+        Ok(())
     }
 
     pub fn modify(&mut self, au: &mut AuditScope, me: &ModifyEvent) -> Result<(), OperationError> {
@@ -1267,6 +2447,15 @@ impl<'a> QueryServerWriteTransaction<'a> {
             Err(e) => return Err(OperationError::SchemaViolation(e)),
         };
 
+        // Refresh modifytimestamp on the entries that actually changed.
+        let norm_cand = try_audit!(
+            au,
+            Plugins::run_modify_stamp(au, self, &pre_candidates, norm_cand)
+        );
+
+        // As in create, a failure from here only undoes this modify.
+        let savepoint = try_audit!(au, self.be_txn.savepoint(au));
+
         // Backend Modify
         let mut audit_be = AuditScope::new("backend_modify");
 
@@ -1275,11 +2464,11 @@ impl<'a> QueryServerWriteTransaction<'a> {
             .modify(&mut audit_be, &pre_candidates, &norm_cand);
         au.append_scope(audit_be);
 
-        if res.is_err() {
-            // be_txn is dropped, ie aborted here.
-            audit_log!(au, "Modify operation failed (backend), {:?}", res);
-            return res;
+        if let Err(e) = res {
+            audit_log!(au, "Modify operation failed (backend), {:?}", e);
+            return self.rollback_op(au, savepoint, e);
         }
+        self.note_changes(Some(&me.event), &norm_cand, "modify");
 
         // Post Plugins
         //
@@ -1295,10 +2484,11 @@ impl<'a> QueryServerWriteTransaction<'a> {
         );
         au.append_scope(audit_plugin_post);
 
-        if plug_post_res.is_err() {
-            audit_log!(au, "Modify operation failed (plugin), {:?}", plug_post_res);
-            return plug_post_res;
+        if let Err(e) = plug_post_res {
+            audit_log!(au, "Modify operation failed (plugin), {:?}", e);
+            return self.rollback_op(au, savepoint, e);
         }
+        try_audit!(au, self.be_txn.release_savepoint(au, savepoint));
 
         // We have finished all plugs and now have a successful operation - flag if
         // schema or acp requires reload. Remember, this is a modify, so we need to check
@@ -1323,6 +2513,7 @@ impl<'a> QueryServerWriteTransaction<'a> {
                     acc
                 } else {
                     e.attribute_value_pres("class", &PVCLASS_ACP)
+                        || e.attribute_value_pres("class", &PVCLASS_ANONYMOUS_POLICY)
                 }
             });
         audit_log!(
@@ -1355,6 +2546,66 @@ impl<'a> QueryServerWriteTransaction<'a> {
         res
     }
 
+    // As internal_create, for crate::import, which has already checked that
+    // the references are sound and the unique values are unique within the
+    // import. So the entries aren't indexed, and the post plugins aren't run,
+    // until Plugins::run_post_import once everything is in and reindexed.
+    // Schema and access controls must go through create, as they need a
+    // reload.
+    pub fn import_create(
+        &mut self,
+        au: &mut AuditScope,
+        entries: Vec<Entry<EntryInvalid, EntryNew>>,
+    ) -> Result<Vec<Entry<EntryValid, EntryCommitted>>, OperationError> {
+        let ce = CreateEvent::new_internal(Vec::new());
+        let mut candidates = entries;
+
+        let mut audit_plugin_pre_transform = AuditScope::new("plugin_pre_create_transform");
+        let plug_pre_transform_res = Plugins::run_pre_create_transform(
+            &mut audit_plugin_pre_transform,
+            self,
+            &mut candidates,
+            &ce,
+        );
+        au.append_scope(audit_plugin_pre_transform);
+        let _ = try_audit!(
+            au,
+            plug_pre_transform_res,
+            "Import failed (pre_transform plugin), {:?}"
+        );
+
+        let res: Result<Vec<Entry<EntryValid, EntryNew>>, OperationError> = candidates
+            .into_iter()
+            .map(|e| {
+                e.validate(&self.schema)
+                    .map_err(|er| OperationError::SchemaViolation(er))
+            })
+            .collect();
+        let norm_cand: Vec<Entry<_, _>> = try_audit!(au, res);
+
+        if norm_cand.iter().any(|e| {
+            e.attribute_value_pres("class", &PVCLASS_CLASSTYPE)
+                || e.attribute_value_pres("class", &PVCLASS_ATTRIBUTETYPE)
+                || e.attribute_value_pres("class", &PVCLASS_ACP)
+        }) {
+            audit_log!(au, "Import failed, schema and acps can't be imported");
+            return Err(OperationError::InvalidRequestState);
+        }
+
+        let mut audit_plugin_pre = AuditScope::new("plugin_pre_create");
+        let plug_pre_res = Plugins::run_pre_create(&mut audit_plugin_pre, self, &norm_cand, &ce);
+        au.append_scope(audit_plugin_pre);
+        let _ = try_audit!(au, plug_pre_res, "Import failed (plugin), {:?}");
+
+        let mut audit_be = AuditScope::new("backend_create");
+        let res = self.be_txn.create_ext(&mut audit_be, norm_cand, false);
+        au.append_scope(audit_be);
+
+        let commit_cand = try_audit!(au, res);
+        self.note_changes(None, &commit_cand, "import");
+        Ok(commit_cand)
+    }
+
     pub fn internal_delete(
         &mut self,
         audit: &mut AuditScope,
@@ -1433,46 +2684,317 @@ impl<'a> QueryServerWriteTransaction<'a> {
         self.impersonate_modify_valid(audit, f_valid, f_intent_valid, m_valid, event)
     }
 
-    // internal server operation types.
-    // These just wrap the fn create/search etc, but they allow
-    // creating the needed create event with the correct internal flags
-    // and markers. They act as though they have the highest level privilege
-    // IE there are no access control checks.
-
-    pub fn internal_exists_or_create(
-        &self,
-        _e: Entry<EntryValid, EntryNew>,
-    ) -> Result<(), OperationError> {
-        // If the thing exists, stop.
-        // if not, create from Entry.
-        unimplemented!()
-    }
-
-    pub fn internal_migrate_or_create_str(
+    // Add and remove many members of a group at once. The group is loaded once,
+    // only the members that actually change are put in the modlist, and it's
+    // applied as a single modify, so memberof and the changelog see one write
+    // rather than one per member. Returns how many were added and removed.
+    pub fn modify_members(
         &mut self,
         audit: &mut AuditScope,
-        e_str: &str,
-    ) -> Result<(), OperationError> {
-        let res = audit_segment!(audit, || Entry::from_proto_entry_str(audit, e_str, self)
-            .and_then(|e: Entry<EntryInvalid, EntryNew>| {
-                let schema = self.get_schema();
-                e.validate(schema)
-                    .map_err(|e| OperationError::SchemaViolation(e))
-            })
-            .and_then(
-                |e: Entry<EntryValid, EntryNew>| self.internal_migrate_or_create(audit, e)
-            ));
-        audit_log!(audit, "internal_migrate_or_create_str -> result {:?}", res);
-        assert!(res.is_ok());
-        res
+        event: &Event,
+        group: &Uuid,
+        add: &BTreeSet<Uuid>,
+        remove: &BTreeSet<Uuid>,
+    ) -> Result<(usize, usize), OperationError> {
+        if add.intersection(remove).next().is_some() {
+            audit_log!(audit, "modify_members: uuid both added and removed");
+            return Err(OperationError::InvalidRequestState);
+        }
+
+        let filt = filter!(f_and!([
+            f_eq("class", PVCLASS_GROUP.clone()),
+            f_eq("uuid", PartialValue::new_uuid(group.clone()))
+        ]));
+        let mut groups = self.impersonate_search(audit, filt.clone(), filt.clone(), event)?;
+        let group_entry = match groups.pop() {
+            Some(g) => g,
+            None => return Err(OperationError::NoMatchingEntries),
+        };
+        let current: BTreeSet<Uuid> = group_entry
+            .get_ava_reference_uuid("member")
+            .map(|us| us.into_iter().cloned().collect())
+            .unwrap_or_else(BTreeSet::new);
+
+        let to_add: Vec<Uuid> = add.difference(&current).cloned().collect();
+        let to_remove: Vec<Uuid> = remove.intersection(&current).cloned().collect();
+        audit_log!(
+            audit,
+            "modify_members: {} to add, {} to remove",
+            to_add.len(),
+            to_remove.len()
+        );
+        if to_add.is_empty() && to_remove.is_empty() {
+            return Ok((0, 0));
+        }
+
+        let modlist =
+            ModifyList::new_list(
+                to_add
+                    .iter()
+                    .map(|u| Modify::Present("member".to_string(), Value::new_refer(*u)))
+                    .chain(to_remove.iter().map(|u| {
+                        Modify::Removed("member".to_string(), PartialValue::new_refer(*u))
+                    }))
+                    .collect(),
+            );
+        self.impersonate_modify(audit, filt.clone(), filt, modlist, event)
+            .map(|_| (to_add.len(), to_remove.len()))
     }
 
-    pub fn internal_migrate_or_create(
+    // Ask, as event, for member to be added to or removed from group. The
+    // request waits for one of the group's approvers, and its uuid is
+    // returned so the requester can follow it.
+    pub fn request_membership(
         &mut self,
         audit: &mut AuditScope,
-        e: Entry<EntryValid, EntryNew>,
-    ) -> Result<(), OperationError> {
-        // if the thing exists, ensure the set of attributes on
+        event: &Event,
+        group: &Uuid,
+        member: &Uuid,
+        action: MembershipAction,
+    ) -> Result<Uuid, OperationError> {
+        let requester = match event.get_uuid() {
+            Some(u) if !event.is_anonymous() => *u,
+            _ => {
+                audit_log!(audit, "request_membership: requester must be authenticated");
+                return Err(OperationError::AccessDenied);
+            }
+        };
+        if self.membership_approvers(audit, group)?.is_empty() {
+            audit_log!(audit, "request_membership: {:?} has no approvers", group);
+            return Err(OperationError::InvalidRequestState);
+        }
+        if !self.internal_exists(
+            audit,
+            filter!(f_eq("uuid", PartialValue::new_uuid(*member))),
+        )? {
+            return Err(OperationError::NoMatchingEntries);
+        }
+
+        // Nothing to ask for if the member is already as asked, or if it's
+        // been asked for already.
+        let is_member = self.internal_exists(
+            audit,
+            filter!(f_and!([
+                f_eq("uuid", PartialValue::new_uuid(*group)),
+                f_eq("member", PartialValue::new_refer(*member))
+            ])),
+        )?;
+        let pending = self.internal_exists(
+            audit,
+            filter!(f_and!([
+                f_eq("class", PVCLASS_MEMBERSHIPREQUEST.clone()),
+                f_eq("request_state", PVREQUEST_PENDING.clone()),
+                f_eq("request_group", PartialValue::new_uuid(*group)),
+                f_eq("request_member", PartialValue::new_uuid(*member))
+            ])),
+        )?;
+        let redundant = match action {
+            MembershipAction::Add => is_member,
+            MembershipAction::Remove => !is_member,
+        };
+        if redundant || pending {
+            audit_log!(
+                audit,
+                "request_membership: nothing to request, member {}, pending {}",
+                is_member,
+                pending
+            );
+            return Err(OperationError::InvalidRequestState);
+        }
+
+        let uuid = Uuid::new_v4();
+        let mut e: Entry<EntryInvalid, EntryNew> = Entry::new();
+        e.add_ava("class", &Value::new_class("object"));
+        e.add_ava("class", &Value::new_class("membershiprequest"));
+        e.add_ava("uuid", &Value::new_uuid(uuid));
+        e.add_ava("request_group", &Value::new_uuid(*group));
+        e.add_ava("request_member", &Value::new_uuid(*member));
+        e.add_ava(
+            "request_action",
+            &Value::new_iutf8s(membership_action_str(&action)),
+        );
+        e.add_ava("request_state", &Value::new_iutf8s("pending"));
+        e.add_ava("request_requester", &Value::new_uuid(requester));
+        self.internal_create(audit, vec![e])?;
+
+        // The create is internal, so note who it was really for.
+        self.be_txn
+            .note_change(&uuid, requester.to_string().as_str(), "membership_request");
+        audit_log!(
+            audit,
+            "request_membership: {:?} asks to {} {:?} in {:?}",
+            requester,
+            membership_action_str(&action),
+            member,
+            group
+        );
+        Ok(uuid)
+    }
+
+    // Approve or reject a pending membership request, as event. An approved
+    // request's change is made in the same txn as its decision, and both are
+    // noted against the approver. Nobody may decide their own request.
+    pub fn decide_membership_request(
+        &mut self,
+        audit: &mut AuditScope,
+        event: &Event,
+        request: &Uuid,
+        approve: bool,
+    ) -> Result<(), OperationError> {
+        let filt = filter!(f_and!([
+            f_eq("class", PVCLASS_MEMBERSHIPREQUEST.clone()),
+            f_eq("uuid", PartialValue::new_uuid(*request))
+        ]));
+        let r = self
+            .internal_search(audit, filt.clone())?
+            .pop()
+            .ok_or(OperationError::NoMatchingEntries)?;
+        if !r.attribute_value_pres("request_state", &PVREQUEST_PENDING) {
+            audit_log!(audit, "decide_membership_request: already decided");
+            return Err(OperationError::InvalidRequestState);
+        }
+        let (group, member, action, requester) = match (
+            r.get_ava_single_uuid("request_group"),
+            r.get_ava_single_uuid("request_member"),
+            membership_action(&r),
+            r.get_ava_single_uuid("request_requester"),
+        ) {
+            (Some(g), Some(m), Some(a), Some(q)) => (*g, *m, a, *q),
+            _ => return Err(OperationError::InvalidEntryState),
+        };
+
+        let approvers = self.membership_approvers(audit, &group)?;
+        if !may_approve(event, &approvers) || event.get_uuid() == Some(&requester) {
+            audit_log!(audit, "decide_membership_request: not an approver");
+            return Err(OperationError::AccessDenied);
+        }
+
+        if approve {
+            let change = match action {
+                MembershipAction::Add => {
+                    Modify::Present("member".to_string(), Value::new_refer(member))
+                }
+                MembershipAction::Remove => {
+                    Modify::Removed("member".to_string(), PartialValue::new_refer(member))
+                }
+            };
+            self.internal_modify(
+                audit,
+                filter!(f_and!([
+                    f_eq("class", PVCLASS_GROUP.clone()),
+                    f_eq("uuid", PartialValue::new_uuid(group))
+                ])),
+                ModifyList::new_list(vec![change]),
+            )?;
+        }
+
+        let (state, operation) = if approve {
+            ("approved", "membership_approve")
+        } else {
+            ("rejected", "membership_reject")
+        };
+        let mut decision = vec![
+            Modify::Purged("request_state".to_string()),
+            Modify::Present("request_state".to_string(), Value::new_iutf8s(state)),
+        ];
+        if let Some(u) = event.get_uuid() {
+            decision.push(Modify::Present(
+                "request_approver".to_string(),
+                Value::new_uuid(*u),
+            ));
+        }
+        self.internal_modify(audit, filt, ModifyList::new_list(decision))?;
+
+        // Both writes were internal, so note who made them.
+        let identity = event
+            .get_uuid()
+            .map(|u| u.to_string())
+            .unwrap_or_else(|| "internal".to_string());
+        if approve {
+            self.be_txn
+                .note_change(&group, identity.as_str(), operation);
+        }
+        self.be_txn
+            .note_change(request, identity.as_str(), operation);
+        audit_log!(
+            audit,
+            "decide_membership_request: {:?} {} by {}",
+            request,
+            state,
+            identity
+        );
+        Ok(())
+    }
+
+    // internal server operation types.
+    // These just wrap the fn create/search etc, but they allow
+    // creating the needed create event with the correct internal flags
+    // and markers. They act as though they have the highest level privilege
+    // IE there are no access control checks.
+
+    pub fn internal_exists_or_create(
+        &mut self,
+        audit: &mut AuditScope,
+        e: Entry<EntryValid, EntryNew>,
+    ) -> Result<(), OperationError> {
+        // If the thing exists, stop.
+        // if not, create from Entry.
+        let filt = match e.filter_from_attrs(&vec![String::from("uuid")]) {
+            Some(f) => f,
+            None => return Err(OperationError::FilterGeneration),
+        };
+
+        if self.internal_exists(audit, filt)? {
+            Ok(())
+        } else {
+            self.internal_create(audit, vec![e.invalidate()])
+        }
+    }
+
+    pub fn internal_exists_or_create_str(
+        &mut self,
+        audit: &mut AuditScope,
+        e_str: &str,
+    ) -> Result<(), OperationError> {
+        let res = audit_segment!(audit, || Entry::from_proto_entry_str(audit, e_str, self)
+            .and_then(|e: Entry<EntryInvalid, EntryNew>| {
+                let schema = self.get_schema();
+                e.validate(schema)
+                    .map_err(|e| OperationError::SchemaViolation(e))
+            })
+            .and_then(
+                |e: Entry<EntryValid, EntryNew>| self.internal_exists_or_create(audit, e)
+            ));
+        audit_log!(audit, "internal_exists_or_create_str -> result {:?}", res);
+        assert!(res.is_ok());
+        res
+    }
+
+    pub fn internal_migrate_or_create_str(
+        &mut self,
+        audit: &mut AuditScope,
+        e_str: &str,
+    ) -> Result<(), OperationError> {
+        let res = audit_segment!(audit, || Entry::from_proto_entry_str(audit, e_str, self)
+            .and_then(|e: Entry<EntryInvalid, EntryNew>| {
+                let schema = self.get_schema();
+                e.validate(schema)
+                    .map_err(|e| OperationError::SchemaViolation(e))
+            })
+            .and_then(
+                |e: Entry<EntryValid, EntryNew>| self.internal_migrate_or_create(audit, e)
+            ));
+        audit_log!(audit, "internal_migrate_or_create_str -> result {:?}", res);
+        assert!(res.is_ok());
+        res
+    }
+
+    pub fn internal_migrate_or_create(
+        &mut self,
+        audit: &mut AuditScope,
+        e: Entry<EntryValid, EntryNew>,
+    ) -> Result<(), OperationError> {
+        // if the thing exists, ensure the set of attributes on
         // Entry A match and are present (but don't delete multivalue, or extended
         // attributes in the situation.
         // If not exist, create from Entry B
@@ -1603,6 +3125,43 @@ impl<'a> QueryServerWriteTransaction<'a> {
             JSON_SCHEMA_CLASS_PERSON,
             JSON_SCHEMA_CLASS_GROUP,
             JSON_SCHEMA_CLASS_ACCOUNT,
+            JSON_SCHEMA_ATTR_SAVEDSEARCH_FILTER,
+            JSON_SCHEMA_ATTR_REPORT_INTERVAL,
+            JSON_SCHEMA_CLASS_SAVEDSEARCH,
+            JSON_SCHEMA_ATTR_MEMBERSHIP_APPROVER,
+            JSON_SCHEMA_ATTR_REQUEST_GROUP,
+            JSON_SCHEMA_ATTR_REQUEST_MEMBER,
+            JSON_SCHEMA_ATTR_REQUEST_ACTION,
+            JSON_SCHEMA_ATTR_REQUEST_STATE,
+            JSON_SCHEMA_ATTR_REQUEST_REQUESTER,
+            JSON_SCHEMA_ATTR_REQUEST_APPROVER,
+            JSON_SCHEMA_CLASS_MEMBERSHIPREQUEST,
+            JSON_SCHEMA_ATTR_BRANDING_LOGO,
+            JSON_SCHEMA_ATTR_LOGIN_BANNER,
+            JSON_SCHEMA_CLASS_DOMAIN_BRANDING,
+            JSON_SCHEMA_ATTR_TEMPLATE_SUBJECT,
+            JSON_SCHEMA_ATTR_TEMPLATE_BODY,
+            JSON_SCHEMA_CLASS_NOTIFICATION_TEMPLATE,
+            JSON_SCHEMA_ATTR_LIMIT_SEARCH_MAX_RESULTS,
+            JSON_SCHEMA_ATTR_LIMIT_SEARCH_MAX_CANDIDATES,
+            JSON_SCHEMA_ATTR_LIMIT_SEARCH_MAX_TIME,
+            JSON_SCHEMA_ATTR_OAUTH2_RS_ORIGIN,
+            JSON_SCHEMA_ATTR_OAUTH2_RS_SCOPE_MAP,
+            JSON_SCHEMA_ATTR_OAUTH2_RS_BASIC_SECRET,
+            JSON_SCHEMA_ATTR_OAUTH2_RS_TOKEN_KEY,
+            JSON_SCHEMA_CLASS_OAUTH2_RESOURCE_SERVER,
+            JSON_SCHEMA_ATTR_RADIUS_VLAN,
+            JSON_SCHEMA_ATTR_GIDNUMBER,
+            JSON_SCHEMA_ATTR_LOGINSHELL,
+            JSON_SCHEMA_ATTR_HOMEDIRECTORY,
+            JSON_SCHEMA_CLASS_POSIXACCOUNT,
+            JSON_SCHEMA_CLASS_POSIXGROUP,
+            JSON_SCHEMA_ATTR_ANONYMOUS_ALLOW_SEARCH,
+            JSON_SCHEMA_ATTR_ANONYMOUS_ATTR_ALLOW,
+            JSON_SCHEMA_CLASS_ANONYMOUS_POLICY,
+            JSON_SCHEMA_ATTR_RATELIMIT_SEARCH,
+            JSON_SCHEMA_ATTR_RATELIMIT_WRITE,
+            JSON_SCHEMA_CLASS_RATELIMIT_POLICY,
         ];
 
         let mut audit_si = AuditScope::new("start_initialise_schema_idm");
@@ -1700,6 +3259,18 @@ impl<'a> QueryServerWriteTransaction<'a> {
             JSON_IDM_ACP_SCHEMA_WRITE_ATTRS_PRIV_V1,
             JSON_IDM_ACP_SCHEMA_WRITE_CLASSES_PRIV_V1,
             JSON_IDM_ACP_ACP_MANAGE_PRIV_V1,
+            JSON_IDM_ACP_SAVEDSEARCH_MANAGE_V1,
+            JSON_IDM_ACP_MEMBERSHIPREQUEST_READ_V1,
+            JSON_IDM_ACP_BRANDING_READ_V1,
+            JSON_IDM_ACP_BRANDING_MANAGE_V1,
+            JSON_IDM_ACP_NOTIFICATION_TEMPLATE_MANAGE_V1,
+            JSON_IDM_ACP_OAUTH2_MANAGE_V1,
+            JSON_IDM_ACP_UNIX_READ_V1,
+            JSON_IDM_ACP_UNIX_MANAGE_V1,
+            JSON_IDM_ACP_ANONYMOUS_POLICY_MANAGE_V1,
+            JSON_IDM_ACP_RATELIMIT_POLICY_MANAGE_V1,
+            // Built in reports.
+            JSON_IDM_REPORT_UNUSED_GROUPS_V1,
         ];
 
         let res: Result<(), _> = idm_entries
@@ -1713,6 +3284,23 @@ impl<'a> QueryServerWriteTransaction<'a> {
             return res;
         }
 
+        // These are the defaults, and are changed at runtime, so they must
+        // never be put back.
+        let mut audit_an = AuditScope::new("start_idm_defaults");
+        let res = self
+            .internal_exists_or_create_str(&mut audit_an, JSON_DOMAIN_BRANDING_V1)
+            .and_then(|_| {
+                self.internal_exists_or_create_str(&mut audit_an, JSON_ANONYMOUS_POLICY_V1)
+            })
+            .and_then(|_| {
+                self.internal_exists_or_create_str(&mut audit_an, JSON_RATELIMIT_POLICY_V1)
+            });
+        audit.append_scope(audit_an);
+        assert!(res.is_ok());
+        if res.is_err() {
+            return res;
+        }
+
         Ok(())
     }
 
@@ -1828,15 +3416,64 @@ impl<'a> QueryServerWriteTransaction<'a> {
         let delete_acps = try_audit!(audit, delete_acps);
 
         try_audit!(audit, self.accesscontrols.update_delete(delete_acps));
+        // Update the anonymous policy. It's created with the idm entries, so
+        // before then anonymous is only limited by the acps.
+        let filt = filter!(f_eq("class", PVCLASS_ANONYMOUS_POLICY.clone()));
+        let res = try_audit!(audit, self.internal_search(audit, filt));
+        let policy = match res.first() {
+            Some(e) => try_audit!(audit, AnonymousPolicy::try_from(audit, e)),
+            None => AnonymousPolicy::new(),
+        };
+        try_audit!(audit, self.accesscontrols.update_anonymous(policy));
         // Alternately, we just get ACP class, and just let acctrl work it out ...
         Ok(())
     }
 
-    pub fn reindex(&self, audit: &mut AuditScope) -> Result<(), OperationError> {
+    pub fn reindex(&self, audit: &mut AuditScope, progress: bool) -> Result<(), OperationError> {
         // initiate a be reindex here. This could have been from first run checking
         // the versions, or it could just be from the cli where an admin needs to do an
-        // indexing.
-        self.be_txn.reindex(audit)
+        // indexing - in which case they'll want progress.
+        self.be_txn.reindex_ext(audit, progress)
+    }
+
+    // Much faster than reindex when only a few indexes are damaged.
+    pub fn repair_idxs(
+        &self,
+        audit: &mut AuditScope,
+    ) -> Result<Vec<(String, IndexType)>, OperationError> {
+        self.be_txn.repair_idxs(audit)
+    }
+
+    // Rewrite up to limit entries still stored under an attribute's old
+    // name. Each call carries on from where the last stopped, and once an
+    // alias has nothing left it stops being matched in filters. Returns how
+    // many entries were rewritten.
+    pub fn migrate_aliases(
+        &mut self,
+        audit: &mut AuditScope,
+        limit: usize,
+    ) -> Result<usize, OperationError> {
+        let mut done = 0;
+        for (alias, name, after) in self.schema.get_alias_migrations() {
+            if done >= limit {
+                break;
+            }
+            let (n, next) = self.be_txn.rename_attr(
+                audit,
+                alias.as_str(),
+                name.as_str(),
+                after,
+                limit - done,
+            )?;
+            done += n;
+            if next.is_none() {
+                self.be_txn
+                    .finish_rename(audit, alias.as_str(), name.as_str())?;
+                audit_log!(audit, "attribute {} has been migrated to {}", alias, name);
+            }
+            self.schema.set_alias_migration(alias.as_str(), next);
+        }
+        Ok(done)
     }
 
     pub(crate) fn upgrade_reindex(
@@ -1854,6 +3491,17 @@ impl<'a> QueryServerWriteTransaction<'a> {
         // Reload the schema from qs.
         if self.changed_schema {
             self.reload_schema(audit)?;
+            // Build any index the schema has just gained, so it's correct
+            // from the moment it's committed.
+            let new_idxs: Vec<_> = self
+                .schema
+                .get_idxmeta()
+                .difference(self.be_txn.get_idxmeta())
+                .cloned()
+                .collect();
+            for (attr, itype) in new_idxs.iter() {
+                self.be_txn.reindex_attr(audit, attr, itype)?;
+            }
         }
         // Determine if we need to update access control profiles
         // based on any modifications that have occured.
@@ -1869,6 +3517,8 @@ impl<'a> QueryServerWriteTransaction<'a> {
             be_txn,
             schema,
             accesscontrols,
+            search_limits: _,
+            commits,
             changed_schema: _,
             changed_acp: _,
         } = self;
@@ -1880,9 +3530,14 @@ impl<'a> QueryServerWriteTransaction<'a> {
         if r.len() == 0 {
             // Schema has been validated, so we can go ahead and commit it with the be
             // because both are consistent.
+            let changes = be_txn.take_changes();
             schema
                 .commit()
                 .and_then(|_| accesscontrols.commit().and_then(|_| be_txn.commit(audit)))
+                .map(|_| {
+                    let generation = commits.publish(changes);
+                    audit_log!(audit, "Committed generation {}", generation);
+                })
         } else {
             Err(OperationError::ConsistencyError(r))
         }
@@ -1892,61 +3547,751 @@ impl<'a> QueryServerWriteTransaction<'a> {
 
 // Auth requests? How do we structure these ...
 
-#[cfg(test)]
-mod tests {
-    use crate::constants::{JSON_ADMIN_V1, UUID_ADMIN};
-    use crate::credential::Credential;
-    use crate::entry::{Entry, EntryInvalid, EntryNew};
-    use crate::event::{CreateEvent, DeleteEvent, ModifyEvent, ReviveRecycledEvent, SearchEvent};
-    use crate::modify::{Modify, ModifyList};
-    use crate::server::QueryServerTransaction;
-    use crate::value::{PartialValue, Value};
-    use kanidm_proto::v1::{OperationError, SchemaError};
-    use uuid::Uuid;
+#[cfg(test)]
+mod tests {
+    use crate::be::SearchLimits;
+    use crate::commits::ChangeType;
+    use crate::constants::{
+        JSON_ADMIN_V1, JSON_ANONYMOUS_V1, PURGE_BATCH, RECYCLEBIN_MAX_AGE, TOMBSTONE_MAX_AGE,
+        UUID_ADMIN, UUID_ANONYMOUS, UUID_ANONYMOUS_POLICY, UUID_SYSTEM_ADMINS,
+    };
+    use crate::credential::Credential;
+    use crate::entry::{Entry, EntryInvalid, EntryNew};
+    use crate::event::{
+        ChangesEvent, CompareEvent, CreateEvent, DeleteEvent, Event, ModifyEvent,
+        ReviveRecycledEvent, SearchEvent,
+    };
+    use crate::modify::{Modify, ModifyList};
+    use crate::server::{QueryServerTransaction, QueryServerWriteTransaction};
+    use crate::value::{PartialValue, Value};
+    use kanidm_proto::v1::Filter as ProtoFilter;
+    use kanidm_proto::v1::{ExplainIdl, MembershipAction, OperationError, SchemaError};
+    use std::collections::{BTreeMap, BTreeSet};
+    use uuid::Uuid;
+
+    #[test]
+    fn test_qs_create_user() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let mut server_txn = server.write().expect("Failed to begin txn");
+            let filt = filter!(f_eq("name", PartialValue::new_iutf8s("testperson")));
+            let admin = server_txn
+                .internal_search_uuid(audit, &UUID_ADMIN)
+                .expect("failed");
+
+            let se1 = unsafe { SearchEvent::new_impersonate_entry(admin.clone(), filt.clone()) };
+            let se2 = unsafe { SearchEvent::new_impersonate_entry(admin, filt) };
+
+            let e: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "person"],
+                    "name": ["testperson"],
+                    "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63930"],
+                    "description": ["testperson"],
+                    "displayname": ["testperson"]
+                }
+            }"#,
+            );
+
+            let ce = CreateEvent::new_internal(vec![e.clone()]);
+
+            let r1 = server_txn.search(audit, &se1).expect("search failure");
+            assert!(r1.len() == 0);
+
+            let cr = server_txn.create(audit, &ce);
+            assert!(cr.is_ok());
+
+            let r2 = server_txn.search(audit, &se2).expect("search failure");
+            println!("--> {:?}", r2);
+            assert!(r2.len() == 1);
+
+            // The operational attributes were stamped by the server, so
+            // carry those over to what we expect.
+            let mut e = e;
+            ["createtimestamp", "modifytimestamp"].iter().for_each(|a| {
+                let v = r2[0]
+                    .get_ava_single(a)
+                    .expect("operational attribute missing");
+                e.add_ava(a, v);
+            });
+            let expected = unsafe { vec![e.to_valid_committed()] };
+
+            assert_eq!(r2, expected);
+
+            assert!(server_txn.commit(audit).is_ok());
+        });
+    }
+
+    #[test]
+    fn test_qs_create_post_plugin_rollback() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let mut server_txn = server.write().expect("Failed to begin txn");
+            let e1: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "person"],
+                    "name": ["testperson1"],
+                    "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63930"],
+                    "description": ["testperson"],
+                    "displayname": ["testperson1"]
+                }
+            }"#,
+            );
+            // refint fails this after the backend has written it.
+            let e2: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "group"],
+                    "name": ["testgroup1"],
+                    "uuid": ["d2b496bd-8493-47b7-8142-f568b5cf47ee"],
+                    "member": ["a3e1c4a0-5b1a-4f3e-9d0c-2d8a3b9b6e11"]
+                }
+            }"#,
+            );
+            assert!(server_txn.internal_create(audit, vec![e1]).is_ok());
+            assert!(server_txn.internal_create(audit, vec![e2]).is_err());
+
+            // Only the failed create was undone, and the txn is still good.
+            assert!(server_txn
+                .internal_exists(
+                    audit,
+                    filter!(f_eq("name", PartialValue::new_iutf8s("testperson1")))
+                )
+                .expect("exists failed"));
+            assert!(!server_txn
+                .internal_exists(
+                    audit,
+                    filter_all!(f_eq("name", PartialValue::new_iutf8s("testgroup1")))
+                )
+                .expect("exists failed"));
+            assert!(server_txn.commit(audit).is_ok());
+        })
+    }
+
+    #[test]
+    fn test_qs_explain_access() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let server_txn = server.read().expect("Failed to begin txn");
+            // filter_all, as the hidden exclusion would leave the one candidate
+            // under the threshold, and so partial.
+            let filt = filter_all!(f_eq("name", PartialValue::new_iutf8s("admin")));
+            let admin = server_txn
+                .internal_search_uuid(audit, &UUID_ADMIN)
+                .expect("failed");
+            let anon = server_txn
+                .internal_search_uuid(audit, &UUID_ANONYMOUS)
+                .expect("failed");
+
+            // Admin is in system_admins, so may see the plan.
+            let se_admin = unsafe { SearchEvent::new_impersonate_entry(admin, filt.clone()) };
+            let ex = server_txn
+                .explain(audit, &se_admin)
+                .expect("explain failed");
+            assert_eq!(ex.result, ExplainIdl::Indexed(1));
+            assert_eq!(ex.matched, 1);
+
+            let se_anon = unsafe { SearchEvent::new_impersonate_entry(anon, filt) };
+            assert_eq!(
+                server_txn.explain(audit, &se_anon),
+                Err(OperationError::AccessDenied)
+            );
+        });
+    }
+
+    #[test]
+    fn test_qs_modifytimestamp_ordering() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let server_txn = server.read().expect("Failed to begin txn");
+            let admin = server_txn
+                .internal_search_uuid(audit, &UUID_ADMIN)
+                .expect("failed");
+
+            // Every entry was stamped after the epoch, and none before it.
+            // filter_all, so the range is the whole of what's resolved.
+            let f_ge = filter_all!(f_ge(
+                "modifytimestamp",
+                PartialValue::new_utf8s("19700101000000Z")
+            ));
+            let se = unsafe { SearchEvent::new_impersonate_entry(admin.clone(), f_ge) };
+            let ex = server_txn.explain(audit, &se).expect("explain failed");
+            match ex.result {
+                ExplainIdl::Indexed(n) => assert!(n > 0 && n == ex.matched),
+                r => panic!("not indexed -> {:?}", r),
+            }
+
+            let f_le = filter_all!(f_le(
+                "modifytimestamp",
+                PartialValue::new_utf8s("19700101000000Z")
+            ));
+            let se = unsafe { SearchEvent::new_impersonate_entry(admin, f_le) };
+            let ex = server_txn.explain(audit, &se).expect("explain failed");
+            assert_eq!(ex.result, ExplainIdl::Indexed(0));
+        });
+    }
+
+    #[test]
+    fn test_qs_require_system_admin() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let mut server_txn = server.write().expect("Failed to begin txn");
+            assert!(server_txn
+                .require_system_admin(audit, &Event::from_internal())
+                .is_ok());
+
+            let admin = server_txn
+                .internal_search_uuid(audit, &UUID_ADMIN)
+                .expect("failed");
+            let anon = server_txn
+                .internal_search_uuid(audit, &UUID_ANONYMOUS)
+                .expect("failed");
+            assert!(server_txn
+                .require_system_admin(audit, &Event::from_impersonate_entry(admin))
+                .is_ok());
+            assert_eq!(
+                server_txn.require_system_admin(audit, &Event::from_impersonate_entry(anon)),
+                Err(OperationError::AccessDenied)
+            );
+
+            // Once admin leaves the group, their entry no longer passes.
+            let modlist = ModifyList::new_list(vec![Modify::Removed(
+                "member".to_string(),
+                PartialValue::new_refer_r(&UUID_ADMIN),
+            )]);
+            assert!(server_txn
+                .internal_modify(
+                    audit,
+                    filter!(f_eq("uuid", PartialValue::new_uuidr(&UUID_SYSTEM_ADMINS))),
+                    modlist
+                )
+                .is_ok());
+            let admin = server_txn
+                .internal_search_uuid(audit, &UUID_ADMIN)
+                .expect("failed");
+            assert_eq!(
+                server_txn.require_system_admin(audit, &Event::from_impersonate_entry(admin)),
+                Err(OperationError::AccessDenied)
+            );
+        });
+    }
+
+    #[test]
+    fn test_qs_attr_usage() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let server_txn = server.read().expect("Failed to begin txn");
+            // Every entry is stamped, and the stamp is presence indexed.
+            let usage = server_txn
+                .attr_usage(audit, Some("ModifyTimestamp"))
+                .expect("failed");
+            assert_eq!(usage.len(), 1);
+            assert_eq!(usage[0].attr, "modifytimestamp");
+            assert!(usage[0].entries.unwrap_or(0) > 0);
+
+            let usage = server_txn.attr_usage(audit, Some("name")).expect("failed");
+            assert_eq!(usage[0].entries, None);
+
+            assert_eq!(
+                server_txn.attr_usage(audit, Some("nonexist")),
+                Err(OperationError::InvalidAttributeName("nonexist".to_string()))
+            );
+
+            let usage = server_txn.attr_usage(audit, None).expect("failed");
+            assert!(usage.windows(2).all(|w| w[0].attr < w[1].attr));
+            assert!(usage.iter().any(|u| u.attr == "modifytimestamp"));
+        });
+    }
+
+    #[test]
+    fn test_qs_delete_preview() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let server_txn = server.read().expect("Failed to begin txn");
+            let sys_admins = "00000000-0000-0000-0000-000000000019".to_string();
+
+            let se = unsafe {
+                SearchEvent::new_internal_invalid(filter!(f_eq(
+                    "name",
+                    PartialValue::new_iutf8s("system_admins")
+                )))
+            };
+            let impacts = server_txn
+                .delete_preview(audit, &se)
+                .expect("preview failed");
+            assert_eq!(impacts.len(), 1);
+            let impact = &impacts[0];
+            assert_eq!(impact.uuid, sys_admins);
+            assert_eq!(impact.name, Some("system_admins".to_string()));
+            assert!(!impact.memberships_lost.is_empty());
+            assert!(impact
+                .references
+                .iter()
+                .any(|r| r.uuid == impact.memberships_lost[0] && r.attr == "member"));
+            // Named in the receiver of the recycle bin acp.
+            assert!(impact
+                .access_profiles
+                .contains(&"00000000-0000-0000-0000-ffffff000002".to_string()));
+            assert_eq!(impact.auth_sessions, 0);
+
+            // Admin loses its membership of system_admins.
+            let se = unsafe {
+                SearchEvent::new_internal_invalid(filter!(f_eq(
+                    "uuid",
+                    PartialValue::new_uuid(UUID_ADMIN.clone())
+                )))
+            };
+            let impacts = server_txn
+                .delete_preview(audit, &se)
+                .expect("preview failed");
+            assert_eq!(impacts.len(), 1);
+            assert!(impacts[0].memberships_lost.contains(&sys_admins));
+
+            let se = unsafe {
+                SearchEvent::new_internal_invalid(filter!(f_eq(
+                    "name",
+                    PartialValue::new_iutf8s("nonexist")
+                )))
+            };
+            assert_eq!(server_txn.delete_preview(audit, &se), Ok(Vec::new()));
+        });
+    }
+
+    #[test]
+    fn test_qs_modify_members() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let mut server_txn = server.write().expect("Failed to begin txn");
+            let g_uuid = Uuid::parse_str("d1a8e6e5-5d1d-4d2a-9a0a-6c3a4c8e0a01").unwrap();
+            let p_uuids: Vec<Uuid> = (1..=3)
+                .map(|i| {
+                    Uuid::parse_str(&format!("d1a8e6e5-5d1d-4d2a-9a0a-6c3a4c8e0b0{}", i)).unwrap()
+                })
+                .collect();
+
+            let mut entries: Vec<Entry<EntryInvalid, EntryNew>> = p_uuids
+                .iter()
+                .enumerate()
+                .map(|(i, u)| {
+                    Entry::unsafe_from_entry_str(&format!(
+                        r#"{{
+                        "valid": null,
+                        "state": null,
+                        "attrs": {{
+                            "class": ["object", "person"],
+                            "name": ["testperson{}"],
+                            "uuid": ["{}"],
+                            "description": ["testperson"],
+                            "displayname": ["testperson"]
+                        }}
+                    }}"#,
+                        i, u
+                    ))
+                })
+                .collect();
+            entries.push(Entry::unsafe_from_entry_str(&format!(
+                r#"{{
+                "valid": null,
+                "state": null,
+                "attrs": {{
+                    "class": ["object", "group"],
+                    "name": ["testgroup"],
+                    "uuid": ["{}"],
+                    "member": ["{}"]
+                }}
+            }}"#,
+                g_uuid, p_uuids[0]
+            )));
+            let ce = CreateEvent::new_internal(entries);
+            assert!(server_txn.create(audit, &ce).is_ok());
+
+            let event = Event::from_internal();
+            let members = |server_txn: &QueryServerWriteTransaction, audit: &mut AuditScope| {
+                let g = server_txn
+                    .internal_search_uuid(audit, &g_uuid)
+                    .expect("failed");
+                g.get_ava_reference_uuid("member")
+                    .map(|us| us.into_iter().cloned().collect::<BTreeSet<Uuid>>())
+                    .unwrap_or_else(BTreeSet::new)
+            };
+
+            // The existing member is skipped, the rest added in one modify.
+            let add: BTreeSet<Uuid> = p_uuids.iter().cloned().collect();
+            assert_eq!(
+                server_txn.modify_members(audit, &event, &g_uuid, &add, &BTreeSet::new()),
+                Ok((2, 0))
+            );
+            assert_eq!(members(&server_txn, audit), add);
+            let p = server_txn
+                .internal_search_uuid(audit, &p_uuids[2])
+                .expect("failed");
+            assert!(p.attribute_value_pres("memberof", &PartialValue::new_refer(g_uuid)));
+
+            // Removing a non member does nothing.
+            let mut remove = BTreeSet::new();
+            remove.insert(p_uuids[1]);
+            remove.insert(Uuid::new_v4());
+            assert_eq!(
+                server_txn.modify_members(audit, &event, &g_uuid, &BTreeSet::new(), &remove),
+                Ok((0, 1))
+            );
+            let p = server_txn
+                .internal_search_uuid(audit, &p_uuids[1])
+                .expect("failed");
+            assert!(!p.attribute_value_pres("memberof", &PartialValue::new_refer(g_uuid)));
+            assert_eq!(members(&server_txn, audit).len(), 2);
+
+            // Both adding and removing the same member is ambiguous.
+            assert_eq!(
+                server_txn.modify_members(audit, &event, &g_uuid, &add, &remove),
+                Err(OperationError::InvalidRequestState)
+            );
+            // Nothing to change, so no write at all.
+            assert_eq!(
+                server_txn.modify_members(audit, &event, &g_uuid, &BTreeSet::new(), &remove),
+                Ok((0, 0))
+            );
+
+            // Only groups can have their members changed.
+            assert_eq!(
+                server_txn.modify_members(audit, &event, &p_uuids[0], &add, &BTreeSet::new()),
+                Err(OperationError::NoMatchingEntries)
+            );
+            assert!(server_txn.commit(audit).is_ok());
+        });
+    }
+
+    #[test]
+    fn test_qs_changes() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let tuuid = Uuid::parse_str("cc8e95b4-c24f-4d68-ba54-8bed76f63930").unwrap();
+            let e: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "person"],
+                    "name": ["testperson"],
+                    "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63930"],
+                    "description": ["testperson"],
+                    "displayname": ["testperson"]
+                }
+            }"#,
+            );
+            let filt = filter!(f_eq("name", PartialValue::new_iutf8s("testperson")));
+
+            let server_txn = server.read().expect("Failed to begin txn");
+            let admin = server_txn
+                .internal_search_uuid(audit, &UUID_ADMIN)
+                .expect("failed");
+            let anon = server_txn
+                .internal_search_uuid(audit, &UUID_ANONYMOUS)
+                .expect("failed");
+
+            // With no cookie, everything so far.
+            let ce = unsafe { ChangesEvent::new_impersonate_entry(admin.clone(), 0) };
+            let (entries, _, c1) = server_txn.changes(audit, &ce).expect("changes failed");
+            assert!(entries.len() > 0);
+            assert!(entries.iter().all(|e| e.get_uuid() != &tuuid));
+
+            // A cookie from the future, and anonymous, are refused.
+            let ce = unsafe { ChangesEvent::new_impersonate_entry(admin.clone(), c1 + 1) };
+            assert_eq!(
+                server_txn.changes(audit, &ce),
+                Err(OperationError::InvalidRequestState)
+            );
+            let ce = unsafe { ChangesEvent::new_impersonate_entry(anon, 0) };
+            assert_eq!(
+                server_txn.changes(audit, &ce),
+                Err(OperationError::AccessDenied)
+            );
+            std::mem::drop(server_txn);
+
+            let mut server_txn = server.write().expect("Failed to begin txn");
+            assert!(server_txn.internal_create(audit, vec![e]).is_ok());
+            assert!(server_txn.commit(audit).is_ok());
+
+            // Only the new entry has changed.
+            let server_txn = server.read().expect("Failed to begin txn");
+            let ce = unsafe { ChangesEvent::new_impersonate_entry(admin.clone(), c1) };
+            let (entries, deleted, c2) = server_txn.changes(audit, &ce).expect("changes failed");
+            assert!(c2 > c1);
+            assert_eq!(entries.len(), 1);
+            assert_eq!(entries[0].get_uuid(), &tuuid);
+            assert!(deleted.is_empty());
+            std::mem::drop(server_txn);
+
+            let mut server_txn = server.write().expect("Failed to begin txn");
+            assert!(server_txn.internal_delete(audit, filt).is_ok());
+            assert!(server_txn.commit(audit).is_ok());
+
+            // Once recycled, it's reported as deleted.
+            let server_txn = server.read().expect("Failed to begin txn");
+            let ce = unsafe { ChangesEvent::new_impersonate_entry(admin, c2) };
+            let (entries, deleted, _) = server_txn.changes(audit, &ce).expect("changes failed");
+            assert!(entries.iter().all(|e| e.get_uuid() != &tuuid));
+            assert_eq!(deleted, vec![tuuid.to_hyphenated_ref().to_string()]);
+        });
+    }
+
+    #[test]
+    fn test_qs_compare() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let server_txn = server.read().expect("Failed to begin txn");
+            let admin = server_txn
+                .internal_search_uuid(audit, &UUID_ADMIN)
+                .expect("failed");
+            let anon = server_txn
+                .internal_search_uuid(audit, &UUID_ANONYMOUS)
+                .expect("failed");
+
+            let ce = unsafe {
+                CompareEvent::new_impersonate_entry(
+                    admin.clone(),
+                    UUID_ADMIN.clone(),
+                    "name",
+                    PartialValue::new_iutf8s("admin"),
+                )
+            };
+            assert_eq!(server_txn.compare(audit, &ce), Ok(true));
+
+            let ce = unsafe {
+                CompareEvent::new_impersonate_entry(
+                    admin,
+                    UUID_ADMIN.clone(),
+                    "name",
+                    PartialValue::new_iutf8s("claire"),
+                )
+            };
+            assert_eq!(server_txn.compare(audit, &ce), Ok(false));
+
+            // Anonymous can't read legalname on admin, so can't ask about it.
+            let ce = unsafe {
+                CompareEvent::new_impersonate_entry(
+                    anon,
+                    UUID_ADMIN.clone(),
+                    "legalname",
+                    PartialValue::new_utf8s("System Administrator"),
+                )
+            };
+            assert_eq!(
+                server_txn.compare(audit, &ce),
+                Err(OperationError::NoMatchingEntries)
+            );
+        });
+    }
+
+    #[test]
+    fn test_qs_anonymous_policy() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            {
+                let mut server_txn = server.write().expect("Failed to begin txn");
+                let modlist = ModifyList::new_list(vec![
+                    Modify::Present(
+                        "anonymous_attr_allow".to_string(),
+                        Value::new_iutf8s("name"),
+                    ),
+                    Modify::Present(
+                        "anonymous_attr_allow".to_string(),
+                        Value::new_iutf8s("class"),
+                    ),
+                ]);
+                assert!(server_txn
+                    .internal_modify(
+                        audit,
+                        filter!(f_eq(
+                            "uuid",
+                            PartialValue::new_uuidr(&UUID_ANONYMOUS_POLICY)
+                        )),
+                        modlist
+                    )
+                    .is_ok());
+                // How many results anonymous may have is its own limit.
+                let modlist = ModifyList::new_list(vec![Modify::Present(
+                    "limit_search_max_results".to_string(),
+                    Value::new_utf8s("1"),
+                )]);
+                assert!(server_txn
+                    .internal_modify(
+                        audit,
+                        filter!(f_eq("uuid", PartialValue::new_uuidr(&UUID_ANONYMOUS))),
+                        modlist
+                    )
+                    .is_ok());
+                assert!(server_txn.commit(audit).is_ok());
+            }
+
+            {
+                let server_txn = server.read().expect("Failed to begin txn");
+                let anon = server_txn
+                    .internal_search_uuid(audit, &UUID_ANONYMOUS)
+                    .expect("failed");
+                // Anonymous can read admin, but only the allowed attrs.
+                let se = unsafe {
+                    SearchEvent::new_impersonate_entry(
+                        anon.clone(),
+                        filter!(f_eq("name", PartialValue::new_iutf8s("admin"))),
+                    )
+                };
+                let r = server_txn.search_ext(audit, &se).expect("search failure");
+                assert!(r.len() == 1);
+                let e = r.first().unwrap();
+                assert!(e.attribute_pres("name"));
+                assert!(!e.attribute_pres("displayname"));
+
+                // Filtering on an attribute outside the allow list matches
+                // nothing, as if the acps didn't allow it.
+                let se = unsafe {
+                    SearchEvent::new_impersonate_entry(
+                        anon.clone(),
+                        filter!(f_eq(
+                            "displayname",
+                            PartialValue::new_utf8s("System Administrator")
+                        )),
+                    )
+                };
+                assert!(server_txn.search_ext(audit, &se).unwrap().len() == 0);
+
+                // Too many results.
+                let se = unsafe {
+                    SearchEvent::new_impersonate_entry(anon.clone(), filter!(f_pres("name")))
+                };
+                assert_eq!(
+                    server_txn.search_ext(audit, &se),
+                    Err(OperationError::ResourceLimit)
+                );
+
+                // Other identities are unaffected.
+                let admin = server_txn
+                    .internal_search_uuid(audit, &UUID_ADMIN)
+                    .expect("failed");
+                let se =
+                    unsafe { SearchEvent::new_impersonate_entry(admin, filter!(f_pres("name"))) };
+                assert!(server_txn.search_ext(audit, &se).unwrap().len() > 1);
+            }
+
+            {
+                let mut server_txn = server.write().expect("Failed to begin txn");
+                let modlist = ModifyList::new_list(vec![
+                    Modify::Purged("anonymous_allow_search".to_string()),
+                    Modify::Present("anonymous_allow_search".to_string(), Value::new_bool(false)),
+                ]);
+                assert!(server_txn
+                    .internal_modify(
+                        audit,
+                        filter!(f_eq(
+                            "uuid",
+                            PartialValue::new_uuidr(&UUID_ANONYMOUS_POLICY)
+                        )),
+                        modlist
+                    )
+                    .is_ok());
+                assert!(server_txn.commit(audit).is_ok());
+            }
+
+            let server_txn = server.read().expect("Failed to begin txn");
+            let anon = server_txn
+                .internal_search_uuid(audit, &UUID_ANONYMOUS)
+                .expect("failed");
+            let se = unsafe {
+                SearchEvent::new_impersonate_entry(
+                    anon,
+                    filter!(f_eq("name", PartialValue::new_iutf8s("admin"))),
+                )
+            };
+            assert_eq!(
+                server_txn.search_ext(audit, &se),
+                Err(OperationError::AccessDenied)
+            );
+        });
+    }
 
     #[test]
-    fn test_qs_create_user() {
+    fn test_qs_search_attrs() {
         run_test!(|server: &QueryServer, audit: &mut AuditScope| {
-            let mut server_txn = server.write();
-            let filt = filter!(f_eq("name", PartialValue::new_iutf8s("testperson")));
+            let server_txn = server.read().expect("Failed to begin txn");
             let admin = server_txn
                 .internal_search_uuid(audit, &UUID_ADMIN)
                 .expect("failed");
+            let mut se = unsafe {
+                SearchEvent::new_impersonate_entry(
+                    admin,
+                    filter!(f_eq("name", PartialValue::new_iutf8s("admin"))),
+                )
+            };
+            let mut attrs = BTreeSet::new();
+            attrs.insert("name".to_string());
+            // Requested, but not readable.
+            attrs.insert("radius_secret".to_string());
+            se.attrs = Some(attrs);
+
+            let r = server_txn.search_ext(audit, &se).expect("search failure");
+            assert!(r.len() == 1);
+            let e = r.first().unwrap();
+            assert!(e.attribute_pres("name"));
+            assert!(!e.attribute_pres("radius_secret"));
+            assert!(!e.attribute_pres("class"));
+            assert!(!e.attribute_pres("displayname"));
+        });
+    }
 
-            let se1 = unsafe { SearchEvent::new_impersonate_entry(admin.clone(), filt.clone()) };
-            let se2 = unsafe { SearchEvent::new_impersonate_entry(admin, filt) };
-
-            let e: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
-                r#"{
-                "valid": null,
-                "state": null,
-                "attrs": {
-                    "class": ["object", "person"],
-                    "name": ["testperson"],
-                    "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63930"],
-                    "description": ["testperson"],
-                    "displayname": ["testperson"]
-                }
-            }"#,
-            );
-
-            let ce = CreateEvent::new_internal(vec![e.clone()]);
-
-            let r1 = server_txn.search(audit, &se1).expect("search failure");
-            assert!(r1.len() == 0);
-
-            let cr = server_txn.create(audit, &ce);
-            assert!(cr.is_ok());
+    #[test]
+    fn test_qs_search_limits() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let mut server = server.clone();
+            server.set_search_limits(SearchLimits {
+                max_results: Some(1),
+                ..SearchLimits::unlimited()
+            });
 
-            let r2 = server_txn.search(audit, &se2).expect("search failure");
-            println!("--> {:?}", r2);
-            assert!(r2.len() == 1);
+            {
+                // Admin may see more than everyone else.
+                let mut server_txn = server.write().expect("Failed to begin txn");
+                let modlist = ModifyList::new_list(vec![Modify::Present(
+                    "limit_search_max_results".to_string(),
+                    Value::new_utf8s("1000"),
+                )]);
+                assert!(server_txn
+                    .internal_modify(
+                        audit,
+                        filter!(f_eq("uuid", PartialValue::new_uuidr(&UUID_ADMIN))),
+                        modlist
+                    )
+                    .is_ok());
+                assert!(server_txn.commit(audit).is_ok());
+            }
 
-            let expected = unsafe { vec![e.to_valid_committed()] };
+            let server_txn = server.read().expect("Failed to begin txn");
+            let se = unsafe {
+                SearchEvent::new_impersonate_entry_ser(JSON_ANONYMOUS_V1, filter!(f_pres("name")))
+            };
+            assert_eq!(
+                server_txn.search_ext(audit, &se),
+                Err(OperationError::ResourceLimit)
+            );
+            // Under the limit is fine.
+            let se = unsafe {
+                SearchEvent::new_impersonate_entry_ser(
+                    JSON_ANONYMOUS_V1,
+                    filter!(f_eq("name", PartialValue::new_iutf8s("admin"))),
+                )
+            };
+            assert!(server_txn.search_ext(audit, &se).unwrap().len() == 1);
 
-            assert_eq!(r2, expected);
+            let admin = server_txn
+                .internal_search_uuid(audit, &UUID_ADMIN)
+                .expect("failed");
+            let se = unsafe { SearchEvent::new_impersonate_entry(admin, filter!(f_pres("name"))) };
+            assert!(server_txn.search_ext(audit, &se).unwrap().len() > 1);
 
-            assert!(server_txn.commit(audit).is_ok());
+            // The server's own searches aren't limited.
+            assert!(
+                server_txn
+                    .internal_search(audit, filter!(f_pres("name")))
+                    .unwrap()
+                    .len()
+                    > 1
+            );
         });
     }
 
@@ -1955,23 +4300,23 @@ mod tests {
         run_test!(|server: &QueryServer, audit: &mut AuditScope| {
             {
                 // Setup and abort.
-                let mut server_txn = server.write();
+                let mut server_txn = server.write().expect("Failed to begin txn");
                 assert!(server_txn.initialise_schema_core(audit).is_ok());
             }
             {
-                let mut server_txn = server.write();
+                let mut server_txn = server.write().expect("Failed to begin txn");
                 assert!(server_txn.initialise_schema_core(audit).is_ok());
                 assert!(server_txn.initialise_schema_core(audit).is_ok());
                 assert!(server_txn.commit(audit).is_ok());
             }
             {
                 // Now do it again in a new txn, but abort
-                let mut server_txn = server.write();
+                let mut server_txn = server.write().expect("Failed to begin txn");
                 assert!(server_txn.initialise_schema_core(audit).is_ok());
             }
             {
                 // Now do it again in a new txn.
-                let mut server_txn = server.write();
+                let mut server_txn = server.write().expect("Failed to begin txn");
                 assert!(server_txn.initialise_schema_core(audit).is_ok());
                 assert!(server_txn.commit(audit).is_ok());
             }
@@ -1982,7 +4327,7 @@ mod tests {
     fn test_qs_modify() {
         run_test!(|server: &QueryServer, audit: &mut AuditScope| {
             // Create an object
-            let mut server_txn = server.write();
+            let mut server_txn = server.write().expect("Failed to begin txn");
 
             let e1: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
                 r#"{
@@ -2111,7 +4456,7 @@ mod tests {
         // Test modifying an entry and adding an extra class, that would cause the entry
         // to no longer conform to schema.
         run_test!(|server: &QueryServer, audit: &mut AuditScope| {
-            let mut server_txn = server.write();
+            let mut server_txn = server.write().expect("Failed to begin txn");
 
             let e1: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
                 r#"{
@@ -2187,7 +4532,7 @@ mod tests {
     fn test_qs_delete() {
         run_test!(|server: &QueryServer, audit: &mut AuditScope| {
             // Create
-            let mut server_txn = server.write();
+            let mut server_txn = server.write().expect("Failed to begin txn");
 
             let e1: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
                 r#"{
@@ -2275,7 +4620,7 @@ mod tests {
     #[test]
     fn test_qs_tombstone() {
         run_test!(|server: &QueryServer, audit: &mut AuditScope| {
-            let mut server_txn = server.write();
+            let mut server_txn = server.write().expect("Failed to begin txn");
             let admin = server_txn
                 .internal_search_uuid(audit, &UUID_ADMIN)
                 .expect("failed");
@@ -2335,7 +4680,11 @@ mod tests {
             assert!(r2.len() == 1);
 
             // Now purge
-            assert!(server_txn.purge_tombstones(audit).is_ok());
+            let now = time::now_utc().to_timespec().sec;
+            assert_eq!(
+                server_txn.purge_tombstones(audit, now + TOMBSTONE_MAX_AGE + 1, PURGE_BATCH),
+                Ok((1, false))
+            );
 
             // Assert it's gone
             // Internal search should not see it.
@@ -2351,7 +4700,7 @@ mod tests {
     #[test]
     fn test_qs_recycle_simple() {
         run_test!(|server: &QueryServer, audit: &mut AuditScope| {
-            let mut server_txn = server.write();
+            let mut server_txn = server.write().expect("Failed to begin txn");
             let admin = server_txn
                 .internal_search_uuid(audit, &UUID_ADMIN)
                 .expect("failed");
@@ -2451,7 +4800,11 @@ mod tests {
             assert!(server_txn.revive_recycled(audit, &rre_rc).is_ok());
 
             //  purge to tombstone
-            assert!(server_txn.purge_recycled(audit).is_ok());
+            let now = time::now_utc().to_timespec().sec;
+            assert_eq!(
+                server_txn.purge_recycled(audit, now + RECYCLEBIN_MAX_AGE + 1, PURGE_BATCH),
+                Ok((1, false))
+            );
 
             // Should be no recycled objects.
             let r3 = server_txn
@@ -2475,12 +4828,244 @@ mod tests {
         })
     }
 
+    #[test]
+    fn test_qs_revive_restores_references() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let mut server_txn = server.write().expect("Failed to begin txn");
+            let admin = server_txn
+                .internal_search_uuid(audit, &UUID_ADMIN)
+                .expect("failed");
+            let p_uuid = Uuid::parse_str("cc8e95b4-c24f-4d68-ba54-8bed76f63930").unwrap();
+            let g_uuid = Uuid::parse_str("cc8e95b4-c24f-4d68-ba54-8bed76f63931").unwrap();
+            let o_uuid = Uuid::parse_str("cc8e95b4-c24f-4d68-ba54-8bed76f63932").unwrap();
+            let entries = vec![
+                Entry::unsafe_from_entry_str(&format!(
+                    r#"{{
+                    "valid": null,
+                    "state": null,
+                    "attrs": {{
+                        "class": ["object", "person"],
+                        "name": ["testperson1"],
+                        "uuid": ["{}"],
+                        "description": ["testperson"],
+                        "displayname": ["testperson1"]
+                    }}
+                }}"#,
+                    p_uuid
+                )),
+                Entry::unsafe_from_entry_str(&format!(
+                    r#"{{
+                    "valid": null,
+                    "state": null,
+                    "attrs": {{
+                        "class": ["object", "group"],
+                        "name": ["testgroup"],
+                        "uuid": ["{}"],
+                        "member": ["{}"]
+                    }}
+                }}"#,
+                    g_uuid, p_uuid
+                )),
+                // A member of the group, which is gone by the time it's revived.
+                Entry::unsafe_from_entry_str(&format!(
+                    r#"{{
+                    "valid": null,
+                    "state": null,
+                    "attrs": {{
+                        "class": ["object", "group"],
+                        "name": ["testgroup2"],
+                        "uuid": ["{}"]
+                    }}
+                }}"#,
+                    o_uuid
+                )),
+            ];
+            assert!(server_txn.internal_create(audit, entries).is_ok());
+
+            assert!(server_txn
+                .internal_delete(
+                    audit,
+                    filter!(f_eq("uuid", PartialValue::new_uuidr(&p_uuid)))
+                )
+                .is_ok());
+            assert!(server_txn
+                .internal_modify(
+                    audit,
+                    filter!(f_eq("uuid", PartialValue::new_uuidr(&g_uuid))),
+                    ModifyList::new_list(vec![Modify::Present(
+                        "member".to_string(),
+                        Value::new_refer(o_uuid),
+                    )])
+                )
+                .is_ok());
+            assert!(server_txn
+                .internal_delete(
+                    audit,
+                    filter!(f_eq("uuid", PartialValue::new_uuidr(&g_uuid)))
+                )
+                .is_ok());
+            assert!(server_txn
+                .internal_delete(
+                    audit,
+                    filter!(f_eq("uuid", PartialValue::new_uuidr(&o_uuid)))
+                )
+                .is_ok());
+            // The group comes back without either member, since both were
+            // deleted.
+            let rre = unsafe {
+                ReviveRecycledEvent::new_impersonate_entry(
+                    admin.clone(),
+                    filter_all!(f_eq("uuid", PartialValue::new_uuidr(&g_uuid))),
+                )
+            };
+            assert!(server_txn.revive_recycled(audit, &rre).is_ok());
+            let g = server_txn
+                .internal_search_uuid(audit, &g_uuid)
+                .expect("failed");
+            assert!(!g.attribute_value_pres("member", &PartialValue::new_refer(o_uuid)));
+            assert!(!g.attribute_value_pres("member", &PartialValue::new_refer(p_uuid)));
+
+            // testperson1 goes back in the group it was in.
+            let rre = unsafe {
+                ReviveRecycledEvent::new_impersonate_entry(
+                    admin,
+                    filter_all!(f_eq("uuid", PartialValue::new_uuidr(&p_uuid))),
+                )
+            };
+            assert!(server_txn.revive_recycled(audit, &rre).is_ok());
+            let g = server_txn
+                .internal_search_uuid(audit, &g_uuid)
+                .expect("failed");
+            assert!(g.attribute_value_pres("member", &PartialValue::new_refer(p_uuid)));
+            let p = server_txn
+                .internal_search_uuid(audit, &p_uuid)
+                .expect("failed");
+            assert!(p.attribute_value_pres("memberof", &PartialValue::new_refer(g_uuid)));
+
+            assert!(server_txn.commit(audit).is_ok());
+        })
+    }
+
+    #[test]
+    fn test_qs_purge_retention() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let mut server_txn = server.write().expect("Failed to begin txn");
+            let e1: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "person"],
+                    "name": ["testperson1"],
+                    "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63930"],
+                    "description": ["testperson"],
+                    "displayname": ["testperson1"]
+                }
+            }"#,
+            );
+            assert!(server_txn.internal_create(audit, vec![e1]).is_ok());
+            assert!(server_txn
+                .internal_delete(
+                    audit,
+                    filter!(f_eq("name", PartialValue::new_iutf8s("testperson1")))
+                )
+                .is_ok());
+
+            let filt_i_rc = filter_all!(f_eq("class", PartialValue::new_class("recycled")));
+            let filt_i_ts = filter_all!(f_eq("class", PartialValue::new_class("tombstone")));
+            macro_rules! count {
+                ($f:expr) => {
+                    server_txn
+                        .internal_search(audit, $f.clone())
+                        .expect("internal search failed")
+                        .len()
+                };
+            }
+
+            // Still in the recycle bin until it's old enough.
+            let now = time::now_utc().to_timespec().sec;
+            assert_eq!(
+                server_txn.purge_recycled(audit, now, PURGE_BATCH),
+                Ok((0, false))
+            );
+            assert_eq!(count!(filt_i_rc), 1);
+
+            let later = now + RECYCLEBIN_MAX_AGE + 1;
+            assert_eq!(
+                server_txn.purge_recycled(audit, later, PURGE_BATCH),
+                Ok((1, false))
+            );
+            assert_eq!(count!(filt_i_rc), 0);
+            assert_eq!(count!(filt_i_ts), 1);
+
+            // The tombstone is kept from when it was made, not deleted.
+            assert_eq!(
+                server_txn.purge_tombstones(audit, later, PURGE_BATCH),
+                Ok((0, false))
+            );
+            assert_eq!(count!(filt_i_ts), 1);
+            assert_eq!(
+                server_txn.purge_tombstones(audit, now + TOMBSTONE_MAX_AGE + 60, PURGE_BATCH),
+                Ok((1, false))
+            );
+            assert_eq!(count!(filt_i_ts), 0);
+
+            assert!(server_txn.commit(audit).is_ok());
+        })
+    }
+
+    #[test]
+    fn test_qs_purge_batch() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let mut server_txn = server.write().expect("Failed to begin txn");
+            let names = ["testperson1", "testperson2", "testperson3"];
+            let entries: Vec<Entry<EntryInvalid, EntryNew>> = names
+                .iter()
+                .map(|n| {
+                    let mut e: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+                        r#"{
+                        "valid": null,
+                        "state": null,
+                        "attrs": {
+                            "class": ["object", "person"],
+                            "description": ["testperson"],
+                            "displayname": ["testperson"]
+                        }
+                    }"#,
+                    );
+                    e.add_ava("name", &Value::new_iutf8s(n));
+                    e
+                })
+                .collect();
+            assert!(server_txn.internal_create(audit, entries).is_ok());
+            assert!(server_txn
+                .internal_delete(
+                    audit,
+                    filter!(f_eq("class", PartialValue::new_class("person")))
+                )
+                .is_ok());
+
+            // Each run reaps no more than it's allowed, and says so if it
+            // left some behind.
+            let later = time::now_utc().to_timespec().sec + RECYCLEBIN_MAX_AGE + 1;
+            assert_eq!(server_txn.purge_recycled(audit, later, 2), Ok((2, true)));
+            assert_eq!(server_txn.purge_recycled(audit, later, 2), Ok((1, false)));
+            assert_eq!(server_txn.purge_recycled(audit, later, 2), Ok((0, false)));
+
+            let later = later + TOMBSTONE_MAX_AGE + 60;
+            assert_eq!(server_txn.purge_tombstones(audit, later, 2), Ok((2, true)));
+            assert_eq!(server_txn.purge_tombstones(audit, later, 2), Ok((1, false)));
+
+            assert!(server_txn.commit(audit).is_ok());
+        })
+    }
+
     // The delete test above should be unaffected by recycle anyway
     #[test]
     fn test_qs_recycle_advanced() {
         run_test!(|server: &QueryServer, audit: &mut AuditScope| {
             // Create items
-            let mut server_txn = server.write();
+            let mut server_txn = server.write().expect("Failed to begin txn");
             let admin = server_txn
                 .internal_search_uuid(audit, &UUID_ADMIN)
                 .expect("failed");
@@ -2528,7 +5113,7 @@ mod tests {
     #[test]
     fn test_qs_name_to_uuid() {
         run_test!(|server: &QueryServer, audit: &mut AuditScope| {
-            let mut server_txn = server.write();
+            let mut server_txn = server.write().expect("Failed to begin txn");
 
             let e1: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
                 r#"{
@@ -2565,7 +5150,7 @@ mod tests {
     #[test]
     fn test_qs_uuid_to_name() {
         run_test!(|server: &QueryServer, audit: &mut AuditScope| {
-            let mut server_txn = server.write();
+            let mut server_txn = server.write().expect("Failed to begin txn");
 
             let e1: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
                 r#"{
@@ -2606,10 +5191,299 @@ mod tests {
         })
     }
 
+    #[test]
+    fn test_qs_saved_search() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let mut server_txn = server.write().expect("Failed to begin txn");
+            let e1: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "savedsearch"],
+                    "name": ["people_named"],
+                    "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63930"],
+                    "savedsearch_filter": ["{\"And\": [{\"Eq\": [\"class\", \"person\"]}, {\"Sub\": [\"name\", \"${first}_${last}\"]}]}"]
+                }
+            }"#,
+            );
+            let ce = CreateEvent::new_internal(vec![e1]);
+            assert!(server_txn.create(audit, &ce).is_ok());
+
+            let event = Event::from_internal();
+            let mut params = BTreeMap::new();
+            params.insert("first".to_string(), "claire".to_string());
+            assert_eq!(
+                server_txn.saved_search_filter(audit, &event, "people_named", &params),
+                Err(OperationError::SavedSearchParamMissing("last".to_string()))
+            );
+            params.insert("last".to_string(), "${first}".to_string());
+            // Substituted values aren't substituted again.
+            assert_eq!(
+                server_txn.saved_search_filter(audit, &event, "People_Named", &params),
+                Ok(ProtoFilter::And(vec![
+                    ProtoFilter::Eq("class".to_string(), "person".to_string()),
+                    ProtoFilter::Sub("name".to_string(), "claire_${first}".to_string()),
+                ]))
+            );
+            assert_eq!(
+                server_txn.saved_search_filter(audit, &event, "not_saved", &params),
+                Err(OperationError::NoMatchingEntries)
+            );
+
+            // Anonymous can see the saved search is there, but not what it is.
+            let anon = unsafe { Event::from_impersonate_entry_ser(JSON_ANONYMOUS_V1) };
+            assert_eq!(
+                server_txn.saved_search_filter(audit, &anon, "people_named", &params),
+                Err(OperationError::AccessDenied)
+            );
+        })
+    }
+
+    #[test]
+    fn test_qs_run_reports() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let mut server_txn = server.write().expect("Failed to begin txn");
+            let mk_search = |name: &str, uuid: &str, filter: &str| {
+                let mut e: Entry<EntryInvalid, EntryNew> = Entry::new();
+                e.add_ava("class", &Value::new_class("object"));
+                e.add_ava("class", &Value::new_class("savedsearch"));
+                e.add_ava("name", &Value::new_iutf8s(name));
+                e.add_ava("uuid", &Value::new_uuids(uuid).unwrap());
+                e.add_ava(
+                    "savedsearch_filter",
+                    &Value::new_json_filter(filter).unwrap(),
+                );
+                e.add_ava("report_interval", &Value::new_utf8s("100"));
+                e
+            };
+            let mk_group = |name: &str| {
+                let mut e: Entry<EntryInvalid, EntryNew> = Entry::new();
+                e.add_ava("class", &Value::new_class("object"));
+                e.add_ava("class", &Value::new_class("group"));
+                e.add_ava("name", &Value::new_iutf8s(name));
+                e
+            };
+            let ce = CreateEvent::new_internal(vec![
+                mk_search(
+                    "test_groups",
+                    "cc8e95b4-c24f-4d68-ba54-8bed76f63930",
+                    "{\"And\": [{\"Eq\": [\"class\", \"group\"]}, {\"Sub\": [\"name\", \"testgroup\"]}]}",
+                ),
+                // Reports have no params to give, so this one never runs.
+                mk_search(
+                    "test_param",
+                    "2dc2fe4f-b1c4-4f8a-a5ac-3f9a1a2c5c1e",
+                    "{\"Eq\": [\"name\", \"${name}\"]}",
+                ),
+                mk_group("testgroup1"),
+            ]);
+            assert!(server_txn.create(audit, &ce).is_ok());
+
+            assert!(server_txn.run_reports(audit, 1000).is_ok());
+            // Not due yet.
+            assert!(server_txn.run_reports(audit, 1050).is_ok());
+            let ce = CreateEvent::new_internal(vec![mk_group("testgroup2")]);
+            assert!(server_txn.create(audit, &ce).is_ok());
+            assert!(server_txn.run_reports(audit, 1100).is_ok());
+            assert!(server_txn.commit(audit).is_ok());
+
+            let server_txn = server.read().expect("Failed to begin txn");
+            let event = Event::from_internal();
+            let reports = server_txn
+                .reports(audit, &event, "test_groups", 10)
+                .expect("No reports");
+            assert_eq!(reports.len(), 2);
+            assert_eq!(reports[0].time, "19700101001820Z");
+            assert_eq!((reports[0].count, reports[1].count), (2, 1));
+            assert_eq!(
+                server_txn
+                    .reports(audit, &event, "test_param", 10)
+                    .map(|r| r.len()),
+                Ok(0)
+            );
+
+            // As with running it, the filter must be readable.
+            let anon = unsafe { Event::from_impersonate_entry_ser(JSON_ANONYMOUS_V1) };
+            assert_eq!(
+                server_txn.reports(audit, &anon, "test_groups", 10),
+                Err(OperationError::AccessDenied)
+            );
+        })
+    }
+
+    #[test]
+    fn test_qs_membership_request() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let mut server_txn = server.write().expect("Failed to begin txn");
+            let u_req = Uuid::parse_str("cc8e95b4-c24f-4d68-ba54-8bed76f63930").unwrap();
+            let u_appr = Uuid::parse_str("d2b496bd-8493-47b7-8142-f568b5cf47ee").unwrap();
+            let u_appr_group = Uuid::parse_str("a1b2ff21-05b2-4a7e-8f0c-1de4b0a1e3c2").unwrap();
+            let u_group = Uuid::parse_str("5f4b5a6d-1f2e-4a3b-9c8d-7e6f5a4b3c2d").unwrap();
+            let mk_person = |name: &str, u: &Uuid| {
+                let mut e: Entry<EntryInvalid, EntryNew> = Entry::new();
+                e.add_ava("class", &Value::new_class("object"));
+                e.add_ava("class", &Value::new_class("person"));
+                e.add_ava("name", &Value::new_iutf8s(name));
+                e.add_ava("uuid", &Value::new_uuid(*u));
+                e.add_ava("displayname", &Value::new_utf8s(name));
+                e
+            };
+            let mut appr_group: Entry<EntryInvalid, EntryNew> = Entry::new();
+            appr_group.add_ava("class", &Value::new_class("object"));
+            appr_group.add_ava("class", &Value::new_class("group"));
+            appr_group.add_ava("name", &Value::new_iutf8s("testapprovers"));
+            appr_group.add_ava("uuid", &Value::new_uuid(u_appr_group));
+            appr_group.add_ava("member", &Value::new_refer(u_appr));
+            let mut group: Entry<EntryInvalid, EntryNew> = Entry::new();
+            group.add_ava("class", &Value::new_class("object"));
+            group.add_ava("class", &Value::new_class("group"));
+            group.add_ava("name", &Value::new_iutf8s("testgroup"));
+            group.add_ava("uuid", &Value::new_uuid(u_group));
+            group.add_ava("membership_approver", &Value::new_refer(u_appr_group));
+            let ce = CreateEvent::new_internal(vec![
+                mk_person("testrequester", &u_req),
+                mk_person("testapprover", &u_appr),
+                appr_group,
+                group,
+            ]);
+            assert!(server_txn.create(audit, &ce).is_ok());
+
+            let as_user =
+                |server_txn: &QueryServerWriteTransaction, audit: &mut AuditScope, u: &Uuid| {
+                    let e = server_txn
+                        .internal_search(audit, filter!(f_eq("uuid", PartialValue::new_uuid(*u))))
+                        .expect("search failed")
+                        .pop()
+                        .expect("no entry");
+                    Event::from_impersonate_entry(e)
+                };
+            let requester = as_user(&server_txn, audit, &u_req);
+            let approver = as_user(&server_txn, audit, &u_appr);
+            let anon = unsafe { Event::from_impersonate_entry_ser(JSON_ANONYMOUS_V1) };
+
+            // Only authenticated users may ask, and only for groups with approvers.
+            assert_eq!(
+                server_txn.request_membership(
+                    audit,
+                    &anon,
+                    &u_group,
+                    &u_req,
+                    MembershipAction::Add
+                ),
+                Err(OperationError::AccessDenied)
+            );
+            assert_eq!(
+                server_txn.request_membership(
+                    audit,
+                    &requester,
+                    &u_appr_group,
+                    &u_req,
+                    MembershipAction::Add
+                ),
+                Err(OperationError::InvalidRequestState)
+            );
+            assert_eq!(
+                server_txn.request_membership(
+                    audit,
+                    &requester,
+                    &u_group,
+                    &u_req,
+                    MembershipAction::Remove
+                ),
+                Err(OperationError::InvalidRequestState)
+            );
+            let r1 = server_txn
+                .request_membership(audit, &requester, &u_group, &u_req, MembershipAction::Add)
+                .expect("request failed");
+            assert_eq!(
+                server_txn.request_membership(
+                    audit,
+                    &requester,
+                    &u_group,
+                    &u_req,
+                    MembershipAction::Add
+                ),
+                Err(OperationError::InvalidRequestState)
+            );
+
+            // Both parties see it, but the requester can't decide it.
+            assert_eq!(
+                server_txn
+                    .membership_requests(audit, &requester)
+                    .map(|r| r.len()),
+                Ok(1)
+            );
+            let pending = server_txn
+                .membership_requests(audit, &approver)
+                .expect("no requests");
+            assert_eq!(pending.len(), 1);
+            assert_eq!(pending[0].group, "testgroup");
+            assert_eq!(pending[0].requester, "testrequester");
+            assert_eq!(
+                server_txn.decide_membership_request(audit, &requester, &r1, true),
+                Err(OperationError::AccessDenied)
+            );
+
+            assert!(server_txn
+                .decide_membership_request(audit, &approver, &r1, true)
+                .is_ok());
+            assert!(server_txn
+                .internal_exists(
+                    audit,
+                    filter!(f_and!([
+                        f_eq("uuid", PartialValue::new_uuid(u_group)),
+                        f_eq("member", PartialValue::new_refer(u_req))
+                    ]))
+                )
+                .unwrap());
+            assert_eq!(
+                server_txn.decide_membership_request(audit, &approver, &r1, false),
+                Err(OperationError::InvalidRequestState)
+            );
+            assert_eq!(
+                server_txn
+                    .membership_requests(audit, &approver)
+                    .map(|r| r.len()),
+                Ok(0)
+            );
+
+            // A rejected removal leaves the member in place.
+            let r2 = server_txn
+                .request_membership(
+                    audit,
+                    &requester,
+                    &u_group,
+                    &u_req,
+                    MembershipAction::Remove,
+                )
+                .expect("request failed");
+            assert!(server_txn
+                .decide_membership_request(audit, &approver, &r2, false)
+                .is_ok());
+            assert!(server_txn
+                .internal_exists(
+                    audit,
+                    filter!(f_and!([
+                        f_eq("uuid", PartialValue::new_uuid(r2)),
+                        f_eq("request_state", PartialValue::new_iutf8s("rejected"))
+                    ]))
+                )
+                .unwrap());
+            assert!(server_txn
+                .internal_exists(
+                    audit,
+                    filter!(f_eq("member", PartialValue::new_refer(u_req)))
+                )
+                .unwrap());
+            assert!(server_txn.commit(audit).is_ok());
+        })
+    }
+
     #[test]
     fn test_qs_clone_value() {
         run_test!(|server: &QueryServer, audit: &mut AuditScope| {
-            let mut server_txn = server.write();
+            let mut server_txn = server.write().expect("Failed to begin txn");
             let e1: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
                 r#"{
                 "valid": null,
@@ -2660,7 +5534,7 @@ mod tests {
     #[test]
     fn test_qs_resolve_value() {
         run_test!(|server: &QueryServer, audit: &mut AuditScope| {
-            let mut server_txn = server.write();
+            let mut server_txn = server.write().expect("Failed to begin txn");
             let e1: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
                 r#"{
                 "valid": null,
@@ -2742,7 +5616,7 @@ mod tests {
             }"#,
             );
 
-            let mut server_txn = server.write();
+            let mut server_txn = server.write().expect("Failed to begin txn");
             // Add a new class.
             let ce_class = CreateEvent::new_internal(vec![e_cd.clone()]);
             assert!(server_txn.create(audit, &ce_class).is_ok());
@@ -2754,7 +5628,7 @@ mod tests {
             server_txn.commit(audit).expect("should not fail");
 
             // Start a new write
-            let mut server_txn = server.write();
+            let mut server_txn = server.write().expect("Failed to begin txn");
             // Add the class to an object
             // should work
             let ce_work = CreateEvent::new_internal(vec![e1.clone()]);
@@ -2764,7 +5638,7 @@ mod tests {
             server_txn.commit(audit).expect("should not fail");
 
             // Start a new write
-            let mut server_txn = server.write();
+            let mut server_txn = server.write().expect("Failed to begin txn");
             // delete the class
             let de_class = unsafe {
                 DeleteEvent::new_internal_invalid(filter!(f_eq(
@@ -2777,7 +5651,7 @@ mod tests {
             server_txn.commit(audit).expect("should not fail");
 
             // Start a new write
-            let mut server_txn = server.write();
+            let mut server_txn = server.write().expect("Failed to begin txn");
             // Trying to add now should fail
             let ce_fail = CreateEvent::new_internal(vec![e1.clone()]);
             assert!(server_txn.create(audit, &ce_fail).is_err());
@@ -2829,7 +5703,7 @@ mod tests {
             }"#,
             );
 
-            let mut server_txn = server.write();
+            let mut server_txn = server.write().expect("Failed to begin txn");
             // Add a new attribute.
             let ce_attr = CreateEvent::new_internal(vec![e_ad.clone()]);
             assert!(server_txn.create(audit, &ce_attr).is_ok());
@@ -2841,7 +5715,7 @@ mod tests {
             server_txn.commit(audit).expect("should not fail");
 
             // Start a new write
-            let mut server_txn = server.write();
+            let mut server_txn = server.write().expect("Failed to begin txn");
             // Add the attr to an object
             // should work
             let ce_work = CreateEvent::new_internal(vec![e1.clone()]);
@@ -2851,7 +5725,7 @@ mod tests {
             server_txn.commit(audit).expect("should not fail");
 
             // Start a new write
-            let mut server_txn = server.write();
+            let mut server_txn = server.write().expect("Failed to begin txn");
             // delete the attr
             let de_attr = unsafe {
                 DeleteEvent::new_internal_invalid(filter!(f_eq(
@@ -2864,7 +5738,7 @@ mod tests {
             server_txn.commit(audit).expect("should not fail");
 
             // Start a new write
-            let mut server_txn = server.write();
+            let mut server_txn = server.write().expect("Failed to begin txn");
             // Trying to add now should fail
             let ce_fail = CreateEvent::new_internal(vec![e1.clone()]);
             assert!(server_txn.create(audit, &ce_fail).is_err());
@@ -2902,7 +5776,7 @@ mod tests {
                 }
             }"#,
             );
-            let mut server_txn = server.write();
+            let mut server_txn = server.write().expect("Failed to begin txn");
             // Add the entry. Today we have no syntax to take simple str to a credential
             // but honestly, that's probably okay :)
             let ce = CreateEvent::new_internal(vec![e1]);
@@ -2943,12 +5817,115 @@ mod tests {
         })
     }
 
+    #[test]
+    fn test_qs_commit_events() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let commits = server.subscribe_commits();
+            let uuid = Uuid::parse_str("cc8e95b4-c24f-4d68-ba54-8bed76f63930").unwrap();
+            let e1: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "person"],
+                    "name": ["testperson1"],
+                    "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63930"],
+                    "description": ["testperson"],
+                    "displayname": ["testperson1"]
+                }
+            }"#,
+            );
+            let mut server_txn = server.write().expect("Failed to begin txn");
+            assert!(server_txn.internal_create(audit, vec![e1]).is_ok());
+            assert!(server_txn.commit(audit).is_ok());
+
+            let ev1 = commits.try_recv().expect("no commit event");
+            assert!(ev1.changes.uuids.contains(&uuid));
+            assert!(ev1.changes.attrs.contains("name"));
+            assert_eq!(ev1.changes.types.get(&uuid), Some(&ChangeType::Created));
+
+            fn modify(
+                server_txn: &mut crate::server::QueryServerWriteTransaction,
+                audit: &mut crate::audit::AuditScope,
+            ) -> Result<(), OperationError> {
+                server_txn.internal_modify(
+                    audit,
+                    filter!(f_eq("name", PartialValue::new_iutf8s("testperson1"))),
+                    ModifyList::new_purge_and_set("description", Value::new_utf8s("changed")),
+                )
+            }
+            let mut server_txn = server.write().expect("Failed to begin txn");
+            assert!(modify(&mut server_txn, audit).is_ok());
+            assert!(server_txn.commit(audit).is_ok());
+
+            let ev2 = commits.try_recv().expect("no commit event");
+            assert_eq!(ev2.generation, ev1.generation + 1);
+            assert!(ev2.changes.uuids.contains(&uuid));
+            assert!(ev2.changes.attrs.contains("description"));
+            assert!(!ev2.changes.attrs.contains("name"));
+            assert_eq!(ev2.changes.types.get(&uuid), Some(&ChangeType::Modified));
+
+            // Nothing is sent for a txn that is aborted.
+            let mut server_txn = server.write().expect("Failed to begin txn");
+            assert!(modify(&mut server_txn, audit).is_ok());
+            std::mem::drop(server_txn);
+            assert!(commits.try_recv().is_err());
+
+            // Recycling is a delete, as far as anyone following is concerned.
+            let mut server_txn = server.write().expect("Failed to begin txn");
+            assert!(server_txn
+                .internal_delete(
+                    audit,
+                    filter!(f_eq("name", PartialValue::new_iutf8s("testperson1")))
+                )
+                .is_ok());
+            assert!(server_txn.commit(audit).is_ok());
+            let ev3 = commits.try_recv().expect("no commit event");
+            assert_eq!(ev3.changes.types.get(&uuid), Some(&ChangeType::Deleted));
+        })
+    }
+
+    #[test]
+    fn test_qs_branding_kept() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let mut server_txn = server.write().expect("Failed to begin txn");
+            let b = server_txn.branding(audit).expect("no branding");
+            assert_eq!(b.display_name, "Kanidm");
+            assert!(b.login_banner.is_none());
+
+            assert!(server_txn
+                .internal_modify(
+                    audit,
+                    filter!(f_eq(
+                        "uuid",
+                        PartialValue::new_uuid(UUID_DOMAIN_BRANDING.clone())
+                    )),
+                    ModifyList::new_list(vec![
+                        Modify::Purged("displayname".to_string()),
+                        Modify::Present("displayname".to_string(), Value::new_utf8s("Example")),
+                        Modify::Present(
+                            "login_banner".to_string(),
+                            Value::new_utf8s("Authorised use only")
+                        ),
+                    ]),
+                )
+                .is_ok());
+            // As on the next start up, which must not put the defaults back.
+            assert!(server_txn.initialise_idm(audit).is_ok());
+
+            let b = server_txn.branding(audit).expect("no branding");
+            assert_eq!(b.display_name, "Example");
+            assert_eq!(b.login_banner, Some("Authorised use only".to_string()));
+            assert!(server_txn.commit(audit).is_ok());
+        })
+    }
+
     /*
     #[test]
     fn test_qs_schema_dump_attrs() {
         run_test!(|server: &QueryServer, _audit: &mut AuditScope| {
             use crate::schema::SchemaTransaction;
-            let server_txn = server.write();
+            let server_txn = server.write().expect("Failed to begin txn");
             let schema = server_txn.get_schema();
 
             for k in schema.get_attributes().keys() {
@@ -3,6 +3,7 @@ use crate::credential::Credential;
 use kanidm_proto::v1::Filter as ProtoFilter;
 
 use std::borrow::Borrow;
+use std::collections::BTreeSet;
 use std::convert::TryFrom;
 use std::str::FromStr;
 use uuid::Uuid;
@@ -15,6 +16,10 @@ pub enum IndexType {
     EQUALITY,
     PRESENCE,
     SUBSTRING,
+    // Keyed as equality, but searched by range for ge and le.
+    ORDERING,
+    // Keyed by how each word sounds, for the fuzzy filter.
+    FUZZY,
 }
 
 impl TryFrom<&str> for IndexType {
@@ -26,6 +31,8 @@ impl TryFrom<&str> for IndexType {
             "EQUALITY" => Ok(IndexType::EQUALITY),
             "PRESENCE" => Ok(IndexType::PRESENCE),
             "SUBSTRING" => Ok(IndexType::SUBSTRING),
+            "ORDERING" => Ok(IndexType::ORDERING),
+            "FUZZY" => Ok(IndexType::FUZZY),
             _ => Err(()),
         }
     }
@@ -39,6 +46,8 @@ impl TryFrom<usize> for IndexType {
             0 => Ok(IndexType::EQUALITY),
             1 => Ok(IndexType::PRESENCE),
             2 => Ok(IndexType::SUBSTRING),
+            3 => Ok(IndexType::ORDERING),
+            4 => Ok(IndexType::FUZZY),
             _ => Err(()),
         }
     }
@@ -50,6 +59,19 @@ impl IndexType {
             IndexType::EQUALITY => "eq",
             IndexType::PRESENCE => "pres",
             IndexType::SUBSTRING => "sub",
+            IndexType::ORDERING => "ord",
+            IndexType::FUZZY => "fuzzy",
+        }
+    }
+
+    pub fn from_idx_str(s: &str) -> Option<Self> {
+        match s {
+            "eq" => Some(IndexType::EQUALITY),
+            "pres" => Some(IndexType::PRESENCE),
+            "sub" => Some(IndexType::SUBSTRING),
+            "ord" => Some(IndexType::ORDERING),
+            "fuzzy" => Some(IndexType::FUZZY),
+            _ => None,
         }
     }
 
@@ -58,6 +80,8 @@ impl IndexType {
             IndexType::EQUALITY => "EQUALITY",
             IndexType::PRESENCE => "PRESENCE",
             IndexType::SUBSTRING => "SUBSTRING",
+            IndexType::ORDERING => "ORDERING",
+            IndexType::FUZZY => "FUZZY",
         })
     }
 
@@ -66,6 +90,8 @@ impl IndexType {
             IndexType::EQUALITY => 0,
             IndexType::PRESENCE => 1,
             IndexType::SUBSTRING => 2,
+            IndexType::ORDERING => 3,
+            IndexType::FUZZY => 4,
         }
     }
 }
@@ -86,6 +112,7 @@ pub enum SyntaxType {
     CREDENTIAL,
     RADIUS_UTF8STRING,
     SSHKEY,
+    UINT32,
 }
 
 impl TryFrom<&str> for SyntaxType {
@@ -105,6 +132,7 @@ impl TryFrom<&str> for SyntaxType {
             "CREDENTIAL" => Ok(SyntaxType::CREDENTIAL),
             "RADIUS_UTF8STRING" => Ok(SyntaxType::RADIUS_UTF8STRING),
             "SSHKEY" => Ok(SyntaxType::SSHKEY),
+            "UINT32" => Ok(SyntaxType::UINT32),
             _ => Err(()),
         }
     }
@@ -126,6 +154,7 @@ impl TryFrom<usize> for SyntaxType {
             8 => Ok(SyntaxType::CREDENTIAL),
             9 => Ok(SyntaxType::RADIUS_UTF8STRING),
             10 => Ok(SyntaxType::SSHKEY),
+            11 => Ok(SyntaxType::UINT32),
             _ => Err(()),
         }
     }
@@ -145,6 +174,7 @@ impl SyntaxType {
             SyntaxType::CREDENTIAL => "CREDENTIAL",
             SyntaxType::RADIUS_UTF8STRING => "RADIUS_UTF8STRING",
             SyntaxType::SSHKEY => "SSHKEY",
+            SyntaxType::UINT32 => "UINT32",
         })
     }
 
@@ -161,6 +191,7 @@ impl SyntaxType {
             SyntaxType::CREDENTIAL => 8,
             SyntaxType::RADIUS_UTF8STRING => 9,
             SyntaxType::SSHKEY => 10,
+            SyntaxType::UINT32 => 11,
         }
     }
 }
@@ -188,6 +219,7 @@ pub enum PartialValue {
     Cred(String),
     SshKey(String),
     RadiusCred,
+    Uint32(u32),
 }
 
 impl PartialValue {
@@ -369,6 +401,21 @@ impl PartialValue {
         }
     }
 
+    pub fn new_uint32(u: u32) -> Self {
+        PartialValue::Uint32(u)
+    }
+
+    pub fn new_uint32_str(u: &str) -> Option<Self> {
+        u32::from_str(u).ok().map(PartialValue::Uint32)
+    }
+
+    pub fn is_uint32(&self) -> bool {
+        match self {
+            PartialValue::Uint32(_) => true,
+            _ => false,
+        }
+    }
+
     pub fn to_str(&self) -> Option<&str> {
         match self {
             PartialValue::Utf8(s) => Some(s.as_str()),
@@ -403,12 +450,89 @@ impl PartialValue {
             // This will never match as we never index radius creds! See generate_idx_eq_keys
             PartialValue::RadiusCred => "_".to_string(),
             PartialValue::SshKey(tag) => tag.to_string(),
+            // Padded so that the keys sort in numeric order.
+            PartialValue::Uint32(u) => format!("{:010}", u),
+        }
+    }
+
+    // The trigrams an entry must have in the substring index to contain this.
+    // Shorter than a trigram can't be resolved by the index, so gives none.
+    pub fn get_idx_sub_keys(&self) -> Vec<String> {
+        match &self {
+            PartialValue::Utf8(s) | PartialValue::Iutf8(s) => trigrams(s.as_str()),
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn get_idx_fuzzy_keys(&self) -> Vec<String> {
+        match &self {
+            PartialValue::Utf8(s) | PartialValue::Iutf8(s) => phonetic_keys(s.as_str()),
+            _ => Vec::new(),
         }
     }
+}
 
-    pub fn get_idx_sub_key(&self) -> String {
-        unimplemented!();
+// The soundex code of each word in s, in order and without repeats, so that
+// "jon smyth" and "John Smith" both give J500 and S530. Soundex is cruder than
+// the metaphones, but is simple to get right, and for names close enough.
+fn phonetic_keys(s: &str) -> Vec<String> {
+    let mut seen = BTreeSet::new();
+    s.split(|c: char| !c.is_alphanumeric())
+        .filter_map(soundex)
+        .filter(|k| seen.insert(k.clone()))
+        .collect()
+}
+
+// None if word has no ascii letters to go on.
+fn soundex(word: &str) -> Option<String> {
+    let code = |c: char| match c {
+        'B' | 'F' | 'P' | 'V' => Some('1'),
+        'C' | 'G' | 'J' | 'K' | 'Q' | 'S' | 'X' | 'Z' => Some('2'),
+        'D' | 'T' => Some('3'),
+        'L' => Some('4'),
+        'M' | 'N' => Some('5'),
+        'R' => Some('6'),
+        // Vowels separate letters that would otherwise be coded once.
+        _ => None,
+    };
+    let mut letters = word
+        .chars()
+        .filter(|c| c.is_ascii_alphabetic())
+        .map(|c| c.to_ascii_uppercase());
+    let first = letters.next()?;
+    let mut key = first.to_string();
+    let mut prev = code(first);
+    for c in letters {
+        if key.len() == 4 {
+            break;
+        }
+        // H and W don't separate, so the code either side is still one.
+        if c == 'H' || c == 'W' {
+            continue;
+        }
+        let cc = code(c);
+        if let Some(d) = cc {
+            if cc != prev {
+                key.push(d);
+            }
+        }
+        prev = cc;
+    }
+    while key.len() < 4 {
+        key.push('0');
     }
+    Some(key)
+}
+
+// Every run of three characters in s, in order and without repeats.
+fn trigrams(s: &str) -> Vec<String> {
+    let chars: Vec<char> = s.chars().collect();
+    let mut seen = BTreeSet::new();
+    chars
+        .windows(3)
+        .map(|w| w.iter().collect::<String>())
+        .filter(|t| seen.insert(t.clone()))
+        .collect()
 }
 
 #[derive(Clone, Debug)]
@@ -769,6 +893,27 @@ impl Value {
         }
     }
 
+    pub fn new_uint32(u: u32) -> Self {
+        Value {
+            pv: PartialValue::new_uint32(u),
+            data: None,
+        }
+    }
+
+    pub fn new_uint32_str(u: &str) -> Option<Self> {
+        Some(Value {
+            pv: PartialValue::new_uint32_str(u)?,
+            data: None,
+        })
+    }
+
+    pub fn is_uint32(&self) -> bool {
+        match &self.pv {
+            PartialValue::Uint32(_) => true,
+            _ => false,
+        }
+    }
+
     pub fn contains(&self, s: &PartialValue) -> bool {
         self.pv.contains(s)
     }
@@ -801,11 +946,19 @@ impl Value {
                 data: None,
             }),
             DbValueV1::SY(us) => Ok(Value {
-                pv: PartialValue::Syntax(SyntaxType::try_from(us)?),
+                pv: PartialValue::Syntax(
+                    usize::try_from(us)
+                        .map_err(|_| ())
+                        .and_then(SyntaxType::try_from)?,
+                ),
                 data: None,
             }),
             DbValueV1::IN(us) => Ok(Value {
-                pv: PartialValue::Index(IndexType::try_from(us)?),
+                pv: PartialValue::Index(
+                    usize::try_from(us)
+                        .map_err(|_| ())
+                        .and_then(IndexType::try_from)?,
+                ),
                 data: None,
             }),
             DbValueV1::RF(u) => Ok(Value {
@@ -834,6 +987,12 @@ impl Value {
                 pv: PartialValue::SshKey(ts.t),
                 data: Some(DataValue::SshKey(ts.d)),
             }),
+            DbValueV1::UI(u) => Ok(Value {
+                pv: PartialValue::Uint32(u),
+                data: None,
+            }),
+            // The backend reads these in from id2blob, so one here is corrupt.
+            DbValueV1::XB(_) => Err(()),
         }
     }
 
@@ -844,8 +1003,8 @@ impl Value {
             PartialValue::Iutf8(s) => DbValueV1::I8(s.clone()),
             PartialValue::Uuid(u) => DbValueV1::UU(u.clone()),
             PartialValue::Bool(b) => DbValueV1::BO(b.clone()),
-            PartialValue::Syntax(syn) => DbValueV1::SY(syn.to_usize()),
-            PartialValue::Index(it) => DbValueV1::IN(it.to_usize()),
+            PartialValue::Syntax(syn) => DbValueV1::SY(syn.to_usize() as u64),
+            PartialValue::Index(it) => DbValueV1::IN(it.to_usize() as u64),
             PartialValue::Refer(u) => DbValueV1::RF(u.clone()),
             PartialValue::JsonFilt(s) => DbValueV1::JF(
                 serde_json::to_string(s)
@@ -890,6 +1049,7 @@ impl Value {
                     d: sk,
                 })
             }
+            PartialValue::Uint32(u) => DbValueV1::UI(*u),
         }
     }
 
@@ -943,6 +1103,13 @@ impl Value {
         }
     }
 
+    pub fn to_uint32(&self) -> Option<u32> {
+        match self.pv {
+            PartialValue::Uint32(u) => Some(u),
+            _ => None,
+        }
+    }
+
     pub fn to_bool(&self) -> Option<bool> {
         match self.pv {
             // *v is to invoke a copy, but this is cheap af
@@ -977,6 +1144,7 @@ impl Value {
             }
             PartialValue::SshKey(tag) => tag.to_string(),
             PartialValue::RadiusCred => "radius".to_string(),
+            PartialValue::Uint32(u) => u.to_string(),
         }
     }
 
@@ -1024,6 +1192,22 @@ impl Value {
             PartialValue::Cred(tag) => vec![tag.to_string()],
             PartialValue::SshKey(tag) => vec![tag.to_string()],
             PartialValue::RadiusCred => vec![],
+            PartialValue::Uint32(_) => vec![self.pv.get_idx_eq_key()],
+        }
+    }
+
+    // Only strings are substring indexed.
+    pub fn generate_idx_sub_keys(&self) -> Vec<String> {
+        match &self.pv {
+            PartialValue::Utf8(s) | PartialValue::Iutf8(s) => trigrams(s.as_str()),
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn generate_idx_fuzzy_keys(&self) -> Vec<String> {
+        match &self.pv {
+            PartialValue::Utf8(s) | PartialValue::Iutf8(s) => phonetic_keys(s.as_str()),
+            _ => Vec::new(),
         }
     }
 }
@@ -1051,6 +1235,12 @@ mod tests {
 
         let r4 = IndexType::try_from("thaoeusaneuh");
         assert_eq!(r4, Err(()));
+
+        let r5 = IndexType::try_from("ORDERING");
+        assert_eq!(r5, Ok(IndexType::ORDERING));
+
+        let r6 = IndexType::try_from("fuzzy");
+        assert_eq!(r6, Ok(IndexType::FUZZY));
     }
 
     #[test]
@@ -1072,6 +1262,28 @@ mod tests {
 
         let r6 = SyntaxType::try_from("zzzzantheou");
         assert_eq!(r6, Err(()));
+
+        let r7 = SyntaxType::try_from("UINT32");
+        assert_eq!(r7, Ok(SyntaxType::UINT32));
+        assert_eq!(SyntaxType::try_from(11), Ok(SyntaxType::UINT32));
+    }
+
+    #[test]
+    fn test_value_uint32() {
+        assert_eq!(Value::new_uint32_str("4094"), Some(Value::new_uint32(4094)));
+        assert!(Value::new_uint32_str("-1").is_none());
+        assert!(Value::new_uint32_str("4294967296").is_none());
+        assert_eq!(Value::new_uint32(10).to_uint32(), Some(10));
+        // Index keys order as the numbers do, for Ge, Le and sorting.
+        assert_eq!(
+            Value::new_uint32(9).generate_idx_eq_keys(),
+            vec!["0000000009".to_string()]
+        );
+        assert!(
+            PartialValue::new_uint32(9).get_idx_eq_key()
+                < PartialValue::new_uint32(10).get_idx_eq_key()
+        );
+        assert_eq!(Value::new_uint32(42).to_proto_string_clone(), "42");
     }
 
     /*
@@ -1111,6 +1323,40 @@ mod tests {
         assert!(r5.is_ok());
     }
 
+    #[test]
+    fn test_value_idx_sub_keys() {
+        let v = Value::new_iutf8s("Banana");
+        assert_eq!(v.generate_idx_sub_keys(), vec!["ban", "ana", "nan"]);
+        assert_eq!(
+            PartialValue::new_iutf8s("NAN").get_idx_sub_keys(),
+            vec!["nan"]
+        );
+        // Too short, or not a string, can't be indexed.
+        assert!(PartialValue::new_iutf8s("an").get_idx_sub_keys().is_empty());
+        assert!(Value::new_bool(true).generate_idx_sub_keys().is_empty());
+    }
+
+    #[test]
+    fn test_value_idx_fuzzy_keys() {
+        let v = Value::new_utf8s("John Smith");
+        assert_eq!(v.generate_idx_fuzzy_keys(), vec!["J500", "S530"]);
+        assert_eq!(
+            PartialValue::new_utf8s("jon smyth").get_idx_fuzzy_keys(),
+            v.generate_idx_fuzzy_keys()
+        );
+        // Letters coded the same either side of h or w are coded once, but not
+        // either side of a vowel.
+        assert_eq!(soundex("Ashcraft"), Some("A261".to_string()));
+        assert_eq!(soundex("Tymczak"), Some("T522".to_string()));
+        assert_eq!(soundex("Pfister"), Some("P236".to_string()));
+        // Repeated words are one key, and words without letters none.
+        assert_eq!(
+            PartialValue::new_utf8s("smith-smyth 42").get_idx_fuzzy_keys(),
+            vec!["S530"]
+        );
+        assert!(Value::new_bool(true).generate_idx_fuzzy_keys().is_empty());
+    }
+
     #[test]
     fn test_schema_normalise_uuid() {
         let sa = SchemaAttribute {
@@ -0,0 +1,46 @@
+// The admin web ui. The assets are compiled into the server so that there is
+// nothing extra to deploy - the ui is just another client of the rest api, and
+// core.rs routes /ui to the content here.
+
+static UI_INDEX: &'static str = include_str!("../../static/index.html");
+static UI_JS: &'static str = include_str!("../../static/admin.js");
+static UI_CSS: &'static str = include_str!("../../static/admin.css");
+
+pub struct UiAsset {
+    pub content_type: &'static str,
+    pub body: &'static str,
+}
+
+pub fn ui_asset(name: &str) -> Option<UiAsset> {
+    match name {
+        "" | "index.html" => Some(UiAsset {
+            content_type: "text/html; charset=utf-8",
+            body: UI_INDEX,
+        }),
+        "admin.js" => Some(UiAsset {
+            content_type: "application/javascript; charset=utf-8",
+            body: UI_JS,
+        }),
+        "admin.css" => Some(UiAsset {
+            content_type: "text/css; charset=utf-8",
+            body: UI_CSS,
+        }),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::webui::ui_asset;
+
+    #[test]
+    fn test_webui_assets() {
+        assert!(ui_asset("").is_some());
+        assert!(ui_asset("index.html").is_some());
+        assert!(ui_asset("admin.js").is_some());
+        assert!(ui_asset("admin.css").is_some());
+        // Don't allow anything else to escape.
+        assert!(ui_asset("../Cargo.toml").is_none());
+        assert!(ui_asset("admin").is_none());
+    }
+}
@@ -9,10 +9,15 @@ extern crate structopt;
 #[macro_use]
 extern crate log;
 
-use kanidm::config::Configuration;
+use kanidm::config::{
+    BackupCompression, BackupFormat, BackupKey, Configuration, DbKeySource, ExportSpec,
+    GenerateConfig, GroupSizeDistribution, LogRedaction,
+};
 use kanidm::core::{
-    backup_server_core, create_server_core, recover_account_core, reset_sid_core,
-    restore_server_core, verify_server_core,
+    backup_server_core, copy_server_core, create_server_core, export_server_core,
+    generate_server_core, import_ldif_server_core, import_server_core, recover_account_core,
+    repair_indexes_core, replay_server_core, reset_sid_core, restore_server_core,
+    vacuum_server_core, verify_changelog_core, verify_server_core,
 };
 
 use std::path::PathBuf;
@@ -24,6 +29,11 @@ struct CommonOpt {
     debug: bool,
     #[structopt(parse(from_os_str), short = "D", long = "db_path")]
     db_path: PathBuf,
+    // Needs a build with the sqlcipher feature.
+    #[structopt(parse(from_os_str), long = "db_key_file")]
+    db_key_file: Option<PathBuf>,
+    #[structopt(long = "db_key_command")]
+    db_key_command: Option<String>,
 }
 
 #[derive(Debug, StructOpt)]
@@ -38,6 +48,77 @@ struct ServerOpt {
     domain: String,
     #[structopt(short = "b", long = "bindaddr")]
     bind: Option<String>,
+    #[structopt(long = "log_redaction")]
+    log_redaction: Option<String>,
+    #[structopt(parse(from_os_str), long = "access_log")]
+    access_log: Option<PathBuf>,
+    #[structopt(long = "search_max_candidates")]
+    search_max_candidates: Option<usize>,
+    #[structopt(long = "search_max_results")]
+    search_max_results: Option<usize>,
+    #[structopt(long = "search_max_time_ms")]
+    search_max_time_ms: Option<u64>,
+    #[structopt(parse(from_os_str), long = "backup_path")]
+    backup_path: Option<PathBuf>,
+    #[structopt(long = "backup_interval", default_value = "86400")]
+    backup_interval: u64,
+    #[structopt(long = "backup_versions", default_value = "7")]
+    backup_versions: usize,
+    #[structopt(long = "warmup")]
+    warmup: Option<usize>,
+    #[structopt(long = "filter_test_threshold")]
+    filter_test_threshold: Option<usize>,
+    // In milliseconds.
+    #[structopt(long = "slow_query_threshold")]
+    slow_query_threshold: Option<u64>,
+    #[structopt(parse(from_os_str), long = "changelog_key_file")]
+    changelog_key_file: Option<PathBuf>,
+    // Capture backend writes for this long, for `replay`.
+    #[structopt(parse(from_os_str), long = "capture_path")]
+    capture_path: Option<PathBuf>,
+    #[structopt(long = "capture_secs", default_value = "3600")]
+    capture_secs: u64,
+    // The origin of another server of this domain to pull from, once per
+    // partner.
+    #[structopt(long = "repl_partner")]
+    repl_partners: Vec<String>,
+    // Run as a read replica of this server, serving only reads and auths, and
+    // referring writes to it.
+    #[structopt(long = "repl_primary")]
+    repl_primary: Option<String>,
+    #[structopt(long = "repl_account")]
+    repl_account: Option<String>,
+    #[structopt(parse(from_os_str), long = "repl_password_file")]
+    repl_password_file: Option<PathBuf>,
+    #[structopt(parse(from_os_str), long = "repl_ca")]
+    repl_ca: Option<PathBuf>,
+    // Seconds between pulls.
+    #[structopt(long = "repl_interval", default_value = "60")]
+    repl_interval: u64,
+    // Serve LDAPS, for search and simple bind only, on this address.
+    #[structopt(long = "ldapbindaddr")]
+    ldap_address: Option<String>,
+    // Defaults to the domain's components, IE dc=example,dc=com
+    #[structopt(long = "ldap_basedn")]
+    ldap_basedn: Option<String>,
+    // Where clients reach us, and so the OAuth2 issuer. Defaults to
+    // https://<domain>
+    #[structopt(long = "origin")]
+    origin: Option<String>,
+    // true or false. Changing this rewrites every entry at startup.
+    #[structopt(long = "entry_compression")]
+    entry_compression: Option<bool>,
+    // Sqlite tuning, see sqlite's docs for each pragma.
+    #[structopt(long = "db_synchronous")]
+    db_synchronous: Option<String>,
+    #[structopt(long = "db_cache_size")]
+    db_cache_size: Option<i64>,
+    #[structopt(long = "db_mmap_size")]
+    db_mmap_size: Option<i64>,
+    #[structopt(long = "db_wal_autocheckpoint")]
+    db_wal_autocheckpoint: Option<i64>,
+    #[structopt(long = "db_busy_timeout")]
+    db_busy_timeout: Option<i64>,
     #[structopt(flatten)]
     commonopts: CommonOpt,
 }
@@ -46,6 +127,34 @@ struct ServerOpt {
 struct BackupOpt {
     #[structopt(parse(from_os_str))]
     path: PathBuf,
+    #[structopt(short = "z", long = "compression", default_value = "none")]
+    compression: String,
+    // ldif is for other tools, and can't be restored.
+    #[structopt(short = "f", long = "format", default_value = "json")]
+    format: String,
+    #[structopt(long = "key_file", parse(from_os_str))]
+    key_file: Option<PathBuf>,
+    #[structopt(long = "passphrase")]
+    passphrase: bool,
+    // Only what changed since the last backup.
+    #[structopt(short = "i", long = "incremental")]
+    incremental: bool,
+    #[structopt(flatten)]
+    commonopts: CommonOpt,
+}
+
+#[derive(Debug, StructOpt)]
+struct ExportOpt {
+    // A directory, that gets one csv per class.
+    #[structopt(parse(from_os_str))]
+    path: PathBuf,
+    #[structopt(short = "c", long = "class")]
+    class: Vec<String>,
+    #[structopt(short = "a", long = "attr")]
+    attr: Vec<String>,
+    // attr=rule, where rule is as for log_redaction.
+    #[structopt(short = "r", long = "redact")]
+    redact: Vec<String>,
     #[structopt(flatten)]
     commonopts: CommonOpt,
 }
@@ -54,6 +163,88 @@ struct BackupOpt {
 struct RestoreOpt {
     #[structopt(parse(from_os_str))]
     path: PathBuf,
+    #[structopt(short = "b", long = "batch_size", default_value = "1024")]
+    batch_size: usize,
+    // Incremental backups to apply after path, oldest first.
+    #[structopt(short = "i", long = "incremental", parse(from_os_str))]
+    incremental: Vec<PathBuf>,
+    #[structopt(long = "key_file", parse(from_os_str))]
+    key_file: Option<PathBuf>,
+    #[structopt(long = "passphrase")]
+    passphrase: bool,
+    #[structopt(flatten)]
+    commonopts: CommonOpt,
+}
+
+#[derive(Debug, StructOpt)]
+struct ImportOpt {
+    // One json entry per line, as the create api takes them.
+    #[structopt(parse(from_os_str))]
+    path: PathBuf,
+    #[structopt(short = "b", long = "batch_size", default_value = "1000")]
+    batch_size: usize,
+    #[structopt(flatten)]
+    commonopts: CommonOpt,
+}
+
+#[derive(Debug, StructOpt)]
+struct ImportLdifOpt {
+    #[structopt(parse(from_os_str))]
+    path: PathBuf,
+    #[structopt(flatten)]
+    commonopts: CommonOpt,
+}
+
+#[derive(Debug, StructOpt)]
+struct CopyOpt {
+    #[structopt(parse(from_os_str))]
+    path: PathBuf,
+    #[structopt(long = "reid")]
+    reid: bool,
+    #[structopt(long = "new_server_id")]
+    new_server_id: bool,
+    // The key for the copy, if it differs from the source, IE to encrypt a
+    // database that wasn't before.
+    #[structopt(parse(from_os_str), long = "dst_db_key_file")]
+    dst_db_key_file: Option<PathBuf>,
+    #[structopt(long = "dst_db_key_command")]
+    dst_db_key_command: Option<String>,
+    #[structopt(flatten)]
+    commonopts: CommonOpt,
+}
+
+#[derive(Debug, StructOpt)]
+struct GenerateOpt {
+    #[structopt(short = "a", long = "accounts", default_value = "1000")]
+    accounts: usize,
+    #[structopt(short = "g", long = "groups", default_value = "100")]
+    groups: usize,
+    #[structopt(short = "m", long = "max_members", default_value = "500")]
+    max_members: usize,
+    #[structopt(long = "distribution", default_value = "zipf")]
+    distribution: String,
+    #[structopt(short = "s", long = "seed", default_value = "0")]
+    seed: u64,
+    #[structopt(flatten)]
+    commonopts: CommonOpt,
+}
+
+#[derive(Debug, StructOpt)]
+struct VerifyChangelogOpt {
+    // Without this only the hash chain is checked.
+    #[structopt(parse(from_os_str), long = "key_file")]
+    key_file: Option<PathBuf>,
+    #[structopt(flatten)]
+    commonopts: CommonOpt,
+}
+
+#[derive(Debug, StructOpt)]
+struct ReplayOpt {
+    #[structopt(parse(from_os_str))]
+    path: PathBuf,
+    // Stop after this txn, leaving the database as it was then.
+    #[structopt(long = "until")]
+    until: Option<u64>,
     #[structopt(flatten)]
     commonopts: CommonOpt,
 }
@@ -74,8 +265,26 @@ enum Opt {
     Backup(BackupOpt),
     #[structopt(name = "restore")]
     Restore(RestoreOpt),
+    #[structopt(name = "export")]
+    Export(ExportOpt),
+    #[structopt(name = "import")]
+    Import(ImportOpt),
+    #[structopt(name = "import_ldif")]
+    ImportLdif(ImportLdifOpt),
+    #[structopt(name = "copy")]
+    Copy(CopyOpt),
+    #[structopt(name = "generate")]
+    Generate(GenerateOpt),
     #[structopt(name = "verify")]
     Verify(CommonOpt),
+    #[structopt(name = "verify_changelog")]
+    VerifyChangelog(VerifyChangelogOpt),
+    #[structopt(name = "repair_indexes")]
+    RepairIndexes(CommonOpt),
+    #[structopt(name = "vacuum")]
+    Vacuum(CommonOpt),
+    #[structopt(name = "replay")]
+    Replay(ReplayOpt),
     #[structopt(name = "recover_account")]
     RecoverAccount(RecoverAccountOpt),
     #[structopt(name = "reset_server_id")]
@@ -86,14 +295,76 @@ impl Opt {
     fn debug(&self) -> bool {
         match self {
             Opt::Server(sopt) => sopt.commonopts.debug,
-            Opt::Verify(sopt) | Opt::ResetServerId(sopt) => sopt.debug,
+            Opt::Verify(sopt)
+            | Opt::RepairIndexes(sopt)
+            | Opt::Vacuum(sopt)
+            | Opt::ResetServerId(sopt) => sopt.debug,
             Opt::Backup(bopt) => bopt.commonopts.debug,
             Opt::Restore(ropt) => ropt.commonopts.debug,
+            Opt::Export(eopt) => eopt.commonopts.debug,
+            Opt::Import(iopt) => iopt.commonopts.debug,
+            Opt::ImportLdif(iopt) => iopt.commonopts.debug,
+            Opt::Copy(copt) => copt.commonopts.debug,
+            Opt::Generate(gopt) => gopt.commonopts.debug,
             Opt::RecoverAccount(ropt) => ropt.commonopts.debug,
+            Opt::VerifyChangelog(vopt) => vopt.commonopts.debug,
+            Opt::Replay(ropt) => ropt.commonopts.debug,
+        }
+    }
+}
+
+// Backups are encrypted with either a key file or a passphrase we prompt for,
+// so that the passphrase doesn't end up in shell history.
+fn backup_key(key_file: &Option<PathBuf>, passphrase: bool) -> Option<BackupKey> {
+    match (key_file, passphrase) {
+        (None, false) => None,
+        (Some(p), false) => match BackupKey::from_key_file(p) {
+            Some(k) => Some(k),
+            None => {
+                error!("Invalid key file - must contain 32 bytes as hex");
+                std::process::exit(1);
+            }
+        },
+        (None, true) => {
+            let p = rpassword::prompt_password_stderr("backup passphrase: ").unwrap();
+            Some(BackupKey::Passphrase(p))
+        }
+        (Some(_), true) => {
+            error!("Only one of key_file or passphrase may be given");
+            std::process::exit(1);
         }
     }
 }
 
+fn export_spec(eopt: &ExportOpt) -> ExportSpec {
+    if eopt.class.is_empty() {
+        error!("At least one class must be given");
+        std::process::exit(1);
+    }
+    let redact = eopt
+        .redact
+        .iter()
+        .map(|r| {
+            let mut parts = r.splitn(2, '=');
+            match (parts.next(), parts.next().and_then(LogRedaction::from_str)) {
+                (Some(a), Some(lr)) => (a.to_lowercase(), lr),
+                _ => {
+                    error!(
+                        "Invalid redaction {} - must be attr=clear, hash or truncate[=N]",
+                        r
+                    );
+                    std::process::exit(1);
+                }
+            }
+        })
+        .collect();
+    ExportSpec {
+        classes: eopt.class.iter().map(|c| c.to_lowercase()).collect(),
+        attrs: eopt.attr.iter().map(|a| a.to_lowercase()).collect(),
+        redact: redact,
+    }
+}
+
 fn main() {
     // Read cli args, determine if we should backup/restore
     let opt = Opt::from_args();
@@ -116,9 +387,48 @@ fn main() {
             info!("Running in server mode ...");
 
             config.update_db_path(&sopt.commonopts.db_path);
+            config.update_db_key(
+                &sopt.commonopts.db_key_file,
+                &sopt.commonopts.db_key_command,
+            );
             config.update_tls(&sopt.ca_path, &sopt.cert_path, &sopt.key_path);
             config.update_bind(&sopt.bind);
+            config.update_log_redaction(&sopt.log_redaction);
+            config.update_access_log(&sopt.access_log);
+            config.update_search_limits(
+                &sopt.search_max_candidates,
+                &sopt.search_max_results,
+                &sopt.search_max_time_ms,
+            );
+            config.update_online_backup(
+                &sopt.backup_path,
+                sopt.backup_interval,
+                sopt.backup_versions,
+            );
+            config.update_warmup(&sopt.warmup);
+            config.update_filter_test_threshold(&sopt.filter_test_threshold);
+            config.update_slow_query_threshold(&sopt.slow_query_threshold);
+            config.update_changelog_key(&sopt.changelog_key_file);
+            config.entry_compression = sopt.entry_compression;
+            config.update_db_pragmas(
+                &sopt.db_synchronous,
+                &sopt.db_cache_size,
+                &sopt.db_mmap_size,
+                &sopt.db_wal_autocheckpoint,
+                &sopt.db_busy_timeout,
+            );
+            config.update_capture(&sopt.capture_path, sopt.capture_secs);
+            config.update_replication(
+                &sopt.repl_partners,
+                &sopt.repl_primary,
+                &sopt.repl_account,
+                &sopt.repl_password_file,
+                &sopt.repl_ca,
+                sopt.repl_interval,
+            );
             config.domain = sopt.domain.clone();
+            config.update_origin(&sopt.origin);
+            config.update_ldap(&sopt.ldap_address, &sopt.ldap_basedn);
 
             let sys = actix::System::new("kanidm-server");
             create_server_core(config);
@@ -128,6 +438,10 @@ fn main() {
             info!("Running in backup mode ...");
 
             config.update_db_path(&bopt.commonopts.db_path);
+            config.update_db_key(
+                &bopt.commonopts.db_key_file,
+                &bopt.commonopts.db_key_command,
+            );
 
             let p = match bopt.path.to_str() {
                 Some(p) => p,
@@ -136,12 +450,35 @@ fn main() {
                     std::process::exit(1);
                 }
             };
-            backup_server_core(config, p);
+            let compression = match BackupCompression::from_str(bopt.compression.as_str()) {
+                Some(c) => c,
+                None => {
+                    error!("Invalid compression - must be none, gzip or zstd");
+                    std::process::exit(1);
+                }
+            };
+            let format = match BackupFormat::from_str(bopt.format.as_str()) {
+                Some(f) => f,
+                None => {
+                    error!("Invalid format - must be json or ldif");
+                    std::process::exit(1);
+                }
+            };
+            if format == BackupFormat::Ldif && bopt.incremental {
+                error!("Incremental backups can only be written as json");
+                std::process::exit(1);
+            }
+            let key = backup_key(&bopt.key_file, bopt.passphrase);
+            backup_server_core(config, p, compression, format, key, bopt.incremental);
         }
         Opt::Restore(ropt) => {
             info!("Running in restore mode ...");
 
             config.update_db_path(&ropt.commonopts.db_path);
+            config.update_db_key(
+                &ropt.commonopts.db_key_file,
+                &ropt.commonopts.db_key_command,
+            );
 
             let p = match ropt.path.to_str() {
                 Some(p) => p,
@@ -150,19 +487,185 @@ fn main() {
                     std::process::exit(1);
                 }
             };
-            restore_server_core(config, p);
+            let incremental: Vec<&str> = ropt
+                .incremental
+                .iter()
+                .map(|i| match i.to_str() {
+                    Some(i) => i,
+                    None => {
+                        error!("Invalid incremental backup path");
+                        std::process::exit(1);
+                    }
+                })
+                .collect();
+            let key = backup_key(&ropt.key_file, ropt.passphrase);
+            restore_server_core(config, p, incremental.as_slice(), ropt.batch_size, key);
+        }
+        Opt::Export(eopt) => {
+            info!("Running in export mode ...");
+
+            config.update_db_path(&eopt.commonopts.db_path);
+            config.update_db_key(
+                &eopt.commonopts.db_key_file,
+                &eopt.commonopts.db_key_command,
+            );
+
+            let p = match eopt.path.to_str() {
+                Some(p) => p,
+                None => {
+                    error!("Invalid export path");
+                    std::process::exit(1);
+                }
+            };
+            let spec = export_spec(&eopt);
+            export_server_core(config, p, spec);
+        }
+        Opt::Import(iopt) => {
+            info!("Running in import mode ...");
+
+            config.update_db_path(&iopt.commonopts.db_path);
+            config.update_db_key(
+                &iopt.commonopts.db_key_file,
+                &iopt.commonopts.db_key_command,
+            );
+
+            let p = match iopt.path.to_str() {
+                Some(p) => p,
+                None => {
+                    error!("Invalid import path");
+                    std::process::exit(1);
+                }
+            };
+            if iopt.batch_size == 0 {
+                error!("Invalid batch size - must be at least 1");
+                std::process::exit(1);
+            }
+            import_server_core(config, p, iopt.batch_size);
+        }
+        Opt::ImportLdif(iopt) => {
+            info!("Running in import mode ...");
+
+            config.update_db_path(&iopt.commonopts.db_path);
+            config.update_db_key(
+                &iopt.commonopts.db_key_file,
+                &iopt.commonopts.db_key_command,
+            );
+
+            let p = match iopt.path.to_str() {
+                Some(p) => p,
+                None => {
+                    error!("Invalid import path");
+                    std::process::exit(1);
+                }
+            };
+            import_ldif_server_core(config, p);
+        }
+        Opt::Copy(copt) => {
+            info!("Running in copy mode ...");
+
+            config.update_db_path(&copt.commonopts.db_path);
+            config.update_db_key(
+                &copt.commonopts.db_key_file,
+                &copt.commonopts.db_key_command,
+            );
+
+            let p = match copt.path.to_str() {
+                Some(p) => p,
+                None => {
+                    error!("Invalid copy path");
+                    std::process::exit(1);
+                }
+            };
+            // The copy keeps the source's key unless given one of its own.
+            let dst_key = DbKeySource::from_opts(&copt.dst_db_key_file, &copt.dst_db_key_command)
+                .or_else(|| config.db_key.clone());
+            copy_server_core(config, p, dst_key, copt.reid, copt.new_server_id);
+        }
+        Opt::Generate(gopt) => {
+            info!("Running in generate mode ...");
+
+            config.update_db_path(&gopt.commonopts.db_path);
+            config.update_db_key(
+                &gopt.commonopts.db_key_file,
+                &gopt.commonopts.db_key_command,
+            );
+
+            let distribution = match GroupSizeDistribution::from_str(gopt.distribution.as_str()) {
+                Some(d) => d,
+                None => {
+                    error!("Invalid distribution - must be uniform or zipf");
+                    std::process::exit(1);
+                }
+            };
+            generate_server_core(
+                config,
+                GenerateConfig {
+                    accounts: gopt.accounts,
+                    groups: gopt.groups,
+                    max_members: gopt.max_members,
+                    distribution: distribution,
+                    seed: gopt.seed,
+                },
+            );
         }
         Opt::Verify(vopt) => {
             info!("Running in restore mode ...");
 
             config.update_db_path(&vopt.db_path);
+            config.update_db_key(&vopt.db_key_file, &vopt.db_key_command);
             verify_server_core(config);
         }
+        Opt::VerifyChangelog(vopt) => {
+            info!("Running in changelog verify mode ...");
+
+            config.update_db_path(&vopt.commonopts.db_path);
+            config.update_db_key(
+                &vopt.commonopts.db_key_file,
+                &vopt.commonopts.db_key_command,
+            );
+            config.update_changelog_key(&vopt.key_file);
+            verify_changelog_core(config);
+        }
+        Opt::Replay(ropt) => {
+            info!("Running in replay mode ...");
+
+            config.update_db_path(&ropt.commonopts.db_path);
+            config.update_db_key(
+                &ropt.commonopts.db_key_file,
+                &ropt.commonopts.db_key_command,
+            );
+            let p = match ropt.path.to_str() {
+                Some(p) => p,
+                None => {
+                    error!("Invalid capture path");
+                    std::process::exit(1);
+                }
+            };
+            replay_server_core(config, p, ropt.until);
+        }
+        Opt::RepairIndexes(ropt) => {
+            info!("Running in index repair mode ...");
+
+            config.update_db_path(&ropt.db_path);
+            config.update_db_key(&ropt.db_key_file, &ropt.db_key_command);
+            repair_indexes_core(config);
+        }
+        Opt::Vacuum(vopt) => {
+            info!("Running in vacuum mode ...");
+
+            config.update_db_path(&vopt.db_path);
+            config.update_db_key(&vopt.db_key_file, &vopt.db_key_command);
+            vacuum_server_core(config);
+        }
         Opt::RecoverAccount(raopt) => {
             info!("Running account recovery ...");
 
             let password = rpassword::prompt_password_stderr("new password: ").unwrap();
             config.update_db_path(&raopt.commonopts.db_path);
+            config.update_db_key(
+                &raopt.commonopts.db_key_file,
+                &raopt.commonopts.db_key_command,
+            );
 
             recover_account_core(config, raopt.name, password);
         }
@@ -170,6 +673,7 @@ fn main() {
             info!("Resetting server id. THIS MAY BREAK REPLICATION");
 
             config.update_db_path(&vopt.db_path);
+            config.update_db_key(&vopt.db_key_file, &vopt.db_key_command);
             reset_sid_core(config);
         }
     }
@@ -0,0 +1,421 @@
+// The kanidm admin ui. This is intentionally plain js with no build step so
+// that it can be embedded into the server binary. Every view here is driven by
+// the same json apis that the cli and client library consume, so the ui never
+// has more rights than the session cookie that the server issued to it.
+"use strict";
+
+var views = ["login", "status", "backend", "accounts", "groups", "schema", "search", "self", "entry"];
+
+function show_error(e) {
+    document.getElementById("error").textContent = e ? String(e) : "";
+}
+
+function api(method, path, body) {
+    var opts = {
+        method: method,
+        credentials: "same-origin",
+        headers: { "Content-Type": "application/json" }
+    };
+    if (body !== undefined) {
+        opts.body = JSON.stringify(body);
+    }
+    return fetch(path, opts).then(function (r) {
+        return r.text().then(function (t) {
+            var v = t.length > 0 ? JSON.parse(t) : null;
+            if (r.status === 401) {
+                show_view("login");
+                throw "Not authenticated";
+            }
+            if (!r.ok) {
+                throw JSON.stringify(v);
+            }
+            return v;
+        });
+    });
+}
+
+function show_view(name) {
+    views.forEach(function (v) {
+        document.getElementById(v).classList.toggle("active", v === name);
+    });
+    show_error(null);
+}
+
+function clear(node) {
+    while (node.firstChild) {
+        node.removeChild(node.firstChild);
+    }
+}
+
+function attr_first(entry, attr) {
+    var v = entry.attrs[attr];
+    return v && v.length > 0 ? v[0] : "";
+}
+
+// Schema entries are addressed by attributename/classname rather than name, so
+// work out the rest path and id to use for an entry in a listing.
+function entry_target(entry, kind) {
+    var classes = entry.attrs["class"] || [];
+    if (kind === "schema") {
+        if (classes.indexOf("attributetype") >= 0) {
+            return ["schema/attributetype", attr_first(entry, "attributename")];
+        }
+        return ["schema/classtype", attr_first(entry, "classname")];
+    }
+    return [kind, attr_first(entry, "uuid")];
+}
+
+// Render a list of protocol entries as a table of name/uuid/class, where each
+// row links to the detailed entry view.
+function render_entry_table(node, entries, kind) {
+    clear(node);
+    var table = document.createElement("table");
+    var head = table.insertRow();
+    ["name", "uuid", "class"].forEach(function (h) {
+        var th = document.createElement("th");
+        th.textContent = h;
+        head.appendChild(th);
+    });
+    (entries || []).forEach(function (e) {
+        var row = table.insertRow();
+        var name_cell = row.insertCell();
+        var link = document.createElement("a");
+        link.className = "entry-link";
+        var target = entry_target(e, kind);
+        link.textContent = attr_first(e, "name") || target[1];
+        link.onclick = function () {
+            show_entry(target[0], target[1]);
+        };
+        name_cell.appendChild(link);
+        row.insertCell().textContent = attr_first(e, "uuid");
+        row.insertCell().textContent = (e.attrs["class"] || []).join(", ");
+    });
+    node.appendChild(table);
+}
+
+function render_entry_detail(node, entry) {
+    clear(node);
+    if (entry === null) {
+        node.textContent = "No such entry";
+        return;
+    }
+    var table = document.createElement("table");
+    Object.keys(entry.attrs).sort().forEach(function (a) {
+        var row = table.insertRow();
+        row.insertCell().textContent = a;
+        row.insertCell().textContent = entry.attrs[a].join("\n");
+    });
+    node.appendChild(table);
+}
+
+function action_button(label, fn) {
+    var b = document.createElement("button");
+    b.type = "button";
+    b.textContent = label;
+    b.onclick = function () {
+        fn().catch(show_error);
+    };
+    return b;
+}
+
+// Entry maintenance actions. These are the same operations the cli exposes,
+// and access controls are applied by the server for the current session.
+function show_entry(kind, id) {
+    var base = "/v1/" + kind + "/" + encodeURIComponent(id);
+    return api("GET", base).then(function (entry) {
+        show_view("entry");
+        document.getElementById("entry-title").textContent =
+            kind + ": " + id;
+        render_entry_detail(document.getElementById("entry-detail"), entry);
+        var actions = document.getElementById("entry-actions");
+        clear(actions);
+        if (kind === "group") {
+            actions.appendChild(action_button("Add members", function () {
+                var m = prompt("Members to add (comma separated)");
+                if (!m) { return Promise.resolve(); }
+                return api("POST", base + "/_attr/member", m.split(",").map(function (s) {
+                    return s.trim();
+                })).then(function () { return show_entry(kind, id); });
+            }));
+            actions.appendChild(action_button("Purge members", function () {
+                if (!confirm("Remove all members of this group?")) { return Promise.resolve(); }
+                return api("DELETE", base + "/_attr/member").then(function () {
+                    return show_entry(kind, id);
+                });
+            }));
+        }
+        if (kind === "account") {
+            actions.appendChild(action_button("Regenerate radius secret", function () {
+                return api("POST", base + "/_radius").then(function (s) {
+                    alert("New radius secret: " + s);
+                });
+            }));
+        }
+        if (kind === "account" || kind === "group") {
+            actions.appendChild(action_button("Delete", function () {
+                if (!confirm("Delete " + id + "?")) { return Promise.resolve(); }
+                return api("DELETE", base).then(function () {
+                    return refresh(kind + "s");
+                });
+            }));
+        }
+    }).catch(show_error);
+}
+
+function refresh_status() {
+    return api("GET", "/status").then(function (s) {
+        var table = document.getElementById("status-table");
+        clear(table);
+        var row = table.insertRow();
+        row.insertCell().textContent = "backend";
+        row.insertCell().textContent = s.online ? "online" : "unavailable";
+        var rorow = table.insertRow();
+        rorow.insertCell().textContent = "writes";
+        rorow.insertCell().textContent = s.read_only ? "refused, read only" : "allowed";
+        s.warnings.forEach(function (w) {
+            var wrow = table.insertRow();
+            wrow.insertCell().textContent = "warning";
+            wrow.insertCell().textContent = w;
+        });
+    });
+}
+
+// A table of records, one column for each of columns.
+function render_records(node, records, columns) {
+    clear(node);
+    var table = document.createElement("table");
+    var head = table.insertRow();
+    columns.forEach(function (c) {
+        var th = document.createElement("th");
+        th.textContent = c;
+        head.appendChild(th);
+    });
+    (records || []).forEach(function (r) {
+        var row = table.insertRow();
+        columns.forEach(function (c) {
+            var v = r[c];
+            row.insertCell().textContent = v === null || v === undefined ? "" : String(v);
+        });
+    });
+    node.appendChild(table);
+}
+
+function txn_stats(t) {
+    return t.count + " (" + t.ms_total + "ms total, " + t.ms_max + "ms max)";
+}
+
+// Maintenance runs on the server while it keeps serving, and the result is
+// shown once it's done. Reindex and vacuum hold up writes until then.
+function backend_action(label, warning, path, done) {
+    return action_button(label, function () {
+        if (warning && !confirm(warning)) { return Promise.resolve(); }
+        var msg = document.getElementById("backend-msg");
+        msg.textContent = label + " running ...";
+        return api("POST", path).then(function (r) {
+            msg.textContent = done(r);
+        }, function (e) {
+            msg.textContent = "";
+            throw e;
+        });
+    });
+}
+
+function refresh_backend() {
+    var actions = document.getElementById("backend-actions");
+    clear(actions);
+    actions.appendChild(backend_action("Verify", null, "/v1/admin/verify", function (errs) {
+        return errs.length === 0 ? "Verify found no errors" :
+            "Verify found errors: " + JSON.stringify(errs);
+    }));
+    actions.appendChild(backend_action("Reindex",
+        "Rebuild every index? Writes wait until this is done.",
+        "/v1/admin/reindex", function () {
+            return "Reindex complete";
+        }));
+    actions.appendChild(backend_action("Vacuum",
+        "Compact the database? Writes wait until this is done.",
+        "/v1/admin/vacuum", function (v) {
+            return "Vacuum complete, " + v.before + " -> " + v.after + " bytes";
+        }));
+    return Promise.all([
+        api("GET", "/status").then(function (s) {
+            var label = s.read_only ? "Allow writes" : "Make read only";
+            actions.appendChild(action_button(label, function () {
+                return api("PUT", "/v1/admin/read_only", !s.read_only).then(function () {
+                    return refresh_backend();
+                });
+            }));
+        }),
+        api("GET", "/v1/admin/db_pool_stats").then(function (p) {
+            var table = document.getElementById("backend-pool");
+            clear(table);
+            [
+                ["connections", p.connections + " of " + p.max_size + ", " +
+                    p.idle_connections + " idle"],
+                ["waits", txn_stats(p.waits)],
+                ["exhausted", p.exhausted],
+                ["read txns", txn_stats(p.read_txns)],
+                ["write txns", txn_stats(p.write_txns)],
+                ["commits", p.commits],
+                ["rollbacks", p.rollbacks]
+            ].forEach(function (kv) {
+                var row = table.insertRow();
+                row.insertCell().textContent = kv[0];
+                row.insertCell().textContent = String(kv[1]);
+            });
+        }),
+        api("GET", "/v1/admin/purge_stats").then(function (p) {
+            p.recycled.purge = "recycled";
+            p.tombstones.purge = "tombstones";
+            render_records(document.getElementById("backend-purges"),
+                [p.recycled, p.tombstones],
+                ["purge", "runs", "reaped", "pending", "last_run"]);
+        }),
+        api("GET", "/v1/admin/index_stats").then(function (is) {
+            render_records(document.getElementById("backend-indexes"), is,
+                ["attr", "itype", "keys", "avg_idl", "max_idl", "allids"]);
+        }),
+        api("GET", "/v1/admin/write_stats").then(function (ws) {
+            render_records(document.getElementById("backend-writes"), ws,
+                ["time", "entries", "id2entry_bytes", "idx_slots", "idx_bytes",
+                    "changelog_records"]);
+        }),
+        api("GET", "/v1/admin/slow_queries").then(function (sq) {
+            render_records(document.getElementById("backend-slow"), sq,
+                ["time", "filter", "duration_ms", "candidates", "results", "allids"]);
+        })
+    ]);
+}
+
+// Every entry of the class, in name order.
+function list_sorted(cls) {
+    var req = { filter: { Eq: ["class", cls] }, sort: { attr: "name" } };
+    return api("POST", "/v1/raw/search", req).then(function (sr) {
+        return sr.entries;
+    });
+}
+
+function refresh(view) {
+    show_view(view);
+    var p;
+    switch (view) {
+        case "status":
+            p = refresh_status();
+            break;
+        case "backend":
+            p = refresh_backend();
+            break;
+        case "accounts":
+            p = list_sorted("account").then(function (es) {
+                render_entry_table(document.getElementById("accounts-list"), es, "account");
+            });
+            break;
+        case "groups":
+            p = list_sorted("group").then(function (es) {
+                render_entry_table(document.getElementById("groups-list"), es, "group");
+            });
+            break;
+        case "schema":
+            p = api("GET", "/v1/schema").then(function (es) {
+                render_entry_table(document.getElementById("schema-list"), es, "schema");
+            });
+            break;
+        case "self":
+            p = api("GET", "/v1/self").then(function (wr) {
+                render_entry_detail(document.getElementById("self-entry"), wr.youare);
+            });
+            break;
+        default:
+            p = Promise.resolve();
+    }
+    return p.catch(show_error);
+}
+
+function update_whoami() {
+    return api("GET", "/v1/self").then(function (wr) {
+        document.getElementById("whoami").textContent = wr.uat.name;
+    }).catch(function () {
+        document.getElementById("whoami").textContent = "";
+    });
+}
+
+// The auth exchange is the same two step init/creds flow that the client
+// library uses. The session cookie carries the resulting token.
+function login(name, creds) {
+    var msg = document.getElementById("login-msg");
+    msg.textContent = "";
+    return api("POST", "/v1/auth", { step: { Init: [name, null] } }).then(function () {
+        return api("POST", "/v1/auth", { step: { Creds: creds } });
+    }).then(function (ar) {
+        if (ar.state.Success !== undefined) {
+            update_whoami();
+            return refresh("status");
+        }
+        msg.textContent = "Authentication failed";
+    }).catch(function (e) {
+        msg.textContent = "Authentication failed: " + e;
+    });
+}
+
+document.getElementById("login-form").onsubmit = function (ev) {
+    ev.preventDefault();
+    var pw = document.getElementById("login-password");
+    login(document.getElementById("login-name").value, [{ Password: pw.value }]);
+    pw.value = "";
+};
+
+document.getElementById("login-anonymous").onclick = function () {
+    login("anonymous", ["Anonymous"]);
+};
+
+document.getElementById("search-form").onsubmit = function (ev) {
+    ev.preventDefault();
+    var filter;
+    try {
+        filter = JSON.parse(document.getElementById("search-filter").value);
+    } catch (e) {
+        show_error("Invalid filter json: " + e);
+        return;
+    }
+    api("POST", "/v1/raw/search", { filter: filter }).then(function (sr) {
+        var node = document.getElementById("search-results");
+        clear(node);
+        sr.entries.forEach(function (e) {
+            var d = document.createElement("div");
+            render_entry_detail(d, e);
+            node.appendChild(d);
+        });
+    }).catch(show_error);
+};
+
+Array.prototype.forEach.call(document.querySelectorAll("[data-action]"), function (b) {
+    b.onclick = function () {
+        refresh(b.getAttribute("data-action").replace("refresh-", ""));
+    };
+});
+
+window.onhashchange = function () {
+    refresh(window.location.hash.replace("#", "") || "status");
+};
+
+// Public, so this is shown before anyone has logged in.
+function update_branding() {
+    return api("GET", "/v1/branding").then(function (b) {
+        document.title = b.display_name + " Administration";
+        document.getElementById("brand-name").textContent = b.display_name;
+        var logo = document.getElementById("brand-logo");
+        logo.hidden = !b.logo;
+        if (b.logo) {
+            logo.src = b.logo;
+        }
+        var banner = document.getElementById("login-banner");
+        banner.hidden = !b.login_banner;
+        banner.textContent = b.login_banner || "";
+    }).catch(function () {});
+}
+
+update_branding();
+
+update_whoami().then(function () {
+    window.onhashchange();
+});